@@ -0,0 +1,528 @@
+use std::ops::{Add, Mul};
+
+use cgmath::{InnerSpace, Matrix4, Quaternion, Vector2, Vector3};
+use tracing::warn;
+
+use crate::error::{Error, Result};
+use crate::scene_data::{NodeTransform, SceneData};
+
+/// How values between two keyframes are found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Hold each keyframe's value until the next
+    Step,
+    Linear,
+
+    /// Hermite spline through the keyframes, with each keyframe storing its in-tangent, value
+    /// and out-tangent in that order
+    CubicSpline,
+}
+
+/// The keyframe values of a channel, one per keyframe or three for cubic splines
+#[derive(Clone, Debug, PartialEq)]
+pub enum Keyframes {
+    Translation(Vec<Vector3<f32>>),
+    Rotation(Vec<Quaternion<f32>>),
+    Scale(Vec<Vector3<f32>>),
+}
+
+/// Animates one part of one node's transform
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationChannel {
+    /// Index in to `SceneData::nodes`
+    pub node: usize,
+
+    /// Time of each keyframe in seconds, in increasing order
+    pub times: Vec<f32>,
+    pub keyframes: Keyframes,
+    pub interpolation: Interpolation,
+}
+
+/// A single named animation of a scene's nodes, eg. a walk cycle
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationClip {
+    pub name: Option<String>,
+    pub channels: Vec<AnimationChannel>,
+
+    /// Time of the last keyframe of any channel, in seconds
+    pub duration: f32,
+}
+
+impl AnimationClip {
+    pub fn from_gltf(animation: &gltf::Animation, buffers: &[gltf::buffer::Data]) -> Result<Self> {
+        let mut channels = Vec::new();
+        for channel in animation.channels() {
+            let reader = channel.reader(|buff| Some(&buffers[buff.index()]));
+            let times: Vec<f32> = reader
+                .read_inputs()
+                .ok_or(Error::InvalidAsset("Animation channel has no keyframe times"))?
+                .collect();
+            let outputs = reader
+                .read_outputs()
+                .ok_or(Error::InvalidAsset("Animation channel has no keyframe values"))?;
+
+            let keyframes = match outputs {
+                gltf::animation::util::ReadOutputs::Translations(values) => {
+                    Keyframes::Translation(values.map(Vector3::from).collect())
+                }
+                gltf::animation::util::ReadOutputs::Rotations(values) => Keyframes::Rotation(
+                    values
+                        .into_f32()
+                        .map(|[x, y, z, w]| Quaternion::new(w, x, y, z))
+                        .collect(),
+                ),
+                gltf::animation::util::ReadOutputs::Scales(values) => {
+                    Keyframes::Scale(values.map(Vector3::from).collect())
+                }
+                gltf::animation::util::ReadOutputs::MorphTargetWeights(_) => {
+                    warn!("Morph target animations aren't supported, skipping channel");
+                    continue;
+                }
+            };
+
+            let interpolation = match channel.sampler().interpolation() {
+                gltf::animation::Interpolation::Step => Interpolation::Step,
+                gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+            };
+
+            let values_per_keyframe = match interpolation {
+                Interpolation::CubicSpline => 3,
+                _ => 1,
+            };
+            if times.is_empty() || keyframes.len() != times.len() * values_per_keyframe {
+                return Err(Error::InvalidAsset(
+                    "Animation channel has mismatched keyframe times and values",
+                ));
+            }
+
+            channels.push(AnimationChannel {
+                node: channel.target().node().index(),
+                times,
+                keyframes,
+                interpolation,
+            });
+        }
+
+        let duration = channels
+            .iter()
+            .filter_map(|channel| channel.times.last())
+            .fold(0.0, |duration: f32, &time| duration.max(time));
+
+        Ok(Self {
+            name: animation.name().map(str::to_owned),
+            channels,
+            duration,
+        })
+    }
+
+    /// Overwrite the animated parts of `pose`, a local transform per node, with their values at
+    /// the given time
+    pub fn sample(&self, time: f32, pose: &mut [NodeTransform]) {
+        for channel in &self.channels {
+            let transform = &mut pose[channel.node];
+            match &channel.keyframes {
+                Keyframes::Translation(values) => {
+                    transform.translation = sample(channel, values, time, |a, b, t| a + (b - a) * t)
+                }
+                Keyframes::Rotation(values) => {
+                    transform.rotation = sample(channel, values, time, Quaternion::slerp)
+                        .normalize()
+                }
+                Keyframes::Scale(values) => {
+                    transform.scale = sample(channel, values, time, |a, b, t| a + (b - a) * t)
+                }
+            }
+        }
+    }
+}
+
+impl Keyframes {
+    fn len(&self) -> usize {
+        match self {
+            Keyframes::Translation(values) | Keyframes::Scale(values) => values.len(),
+            Keyframes::Rotation(values) => values.len(),
+        }
+    }
+}
+
+/// Find a channel's value at the given time, holding the first/last keyframe outside of its range
+fn sample<T>(channel: &AnimationChannel, values: &[T], time: f32, lerp: fn(T, T, f32) -> T) -> T
+where
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let times = &channel.times;
+    let value = |keyframe: usize| match channel.interpolation {
+        Interpolation::CubicSpline => values[keyframe * 3 + 1],
+        _ => values[keyframe],
+    };
+
+    // Index of the first keyframe after `time`
+    let next = times.partition_point(|&keyframe_time| keyframe_time <= time);
+    if next == 0 {
+        return value(0);
+    } else if next == times.len() {
+        return value(times.len() - 1);
+    }
+
+    let prev = next - 1;
+    let span = times[next] - times[prev];
+    let t = (time - times[prev]) / span;
+    match channel.interpolation {
+        Interpolation::Step => value(prev),
+        Interpolation::Linear => lerp(value(prev), value(next), t),
+        Interpolation::CubicSpline => {
+            let out_tangent = values[prev * 3 + 2] * span;
+            let in_tangent = values[next * 3] * span;
+            let t2 = t * t;
+            let t3 = t2 * t;
+            value(prev) * (2.0 * t3 - 3.0 * t2 + 1.0)
+                + out_tangent * (t3 - 2.0 * t2 + t)
+                + value(next) * (-2.0 * t3 + 3.0 * t2)
+                + in_tangent * (t3 - t2)
+        }
+    }
+}
+
+/// Plays one of a scene's animations, evaluating the pose of every node each tick
+pub struct AnimationPlayer {
+    /// Index in to `SceneData::animations`
+    clip: usize,
+
+    /// Seconds since the start of the clip
+    time: f32,
+
+    /// Multiplies the time passed to `tick`
+    pub speed: f32,
+
+    /// Whether to start over after the last keyframe, rather than holding it
+    pub looping: bool,
+
+    /// Scene space transform of every node, as of the last tick
+    node_transforms: Vec<Matrix4<f32>>,
+
+    /// Local transform of every node as of the last tick, and as of the tick before that
+    pose: Vec<NodeTransform>,
+    previous_pose: Vec<NodeTransform>,
+}
+
+impl AnimationPlayer {
+    pub fn new(scene: &SceneData, clip: usize) -> Self {
+        let mut player = Self {
+            clip,
+            time: 0.0,
+            speed: 1.0,
+            looping: true,
+            node_transforms: Vec::new(),
+            pose: Vec::new(),
+            previous_pose: Vec::new(),
+        };
+        player.tick(scene, 0.0);
+        // There's no earlier pose to come from
+        player.previous_pose = player.pose.clone();
+        player
+    }
+
+    /// Advance the clip by `dt` seconds and evaluate the resulting pose
+    pub fn tick(&mut self, scene: &SceneData, dt: f32) {
+        let clip = &scene.animations[self.clip];
+
+        self.time += dt * self.speed;
+        if self.looping && clip.duration > 0.0 {
+            self.time = self.time.rem_euclid(clip.duration);
+        } else {
+            self.time = self.time.clamp(0.0, clip.duration);
+        }
+
+        let mut pose = scene.rest_pose();
+        clip.sample(self.time, &mut pose);
+        self.node_transforms = scene.node_transforms(&pose);
+        self.previous_pose = std::mem::replace(&mut self.pose, pose);
+    }
+
+    /// Scene space transforms of every node in the current pose
+    pub fn node_transforms(&self) -> &[Matrix4<f32>] {
+        &self.node_transforms
+    }
+
+    /// Scene space transforms of every node `alpha` of the way from the pose before the last
+    /// tick to the current one, for drawing frames that fall between ticks
+    ///
+    /// Each node's local transform is interpolated before they're composed, so that rotating
+    /// joints sweep round rather than cutting the corner.
+    pub fn interpolated_node_transforms(&self, scene: &SceneData, alpha: f32) -> Vec<Matrix4<f32>> {
+        let pose: Vec<_> = self
+            .previous_pose
+            .iter()
+            .zip(&self.pose)
+            .map(|(previous, current)| previous.lerp(current, alpha))
+            .collect();
+        scene.node_transforms(&pose)
+    }
+}
+
+/// What an `AnimatedSprite` does once it reaches its last frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlipbookMode {
+    /// Hold the last frame
+    Once,
+
+    /// Start over from the first frame
+    Loop,
+
+    /// Play back down to the first frame, then up again
+    PingPong,
+}
+
+/// Steps through a grid of equally sized frames in an atlas at a fixed rate, giving the atlas
+/// rect of the current frame for a sprite to draw with
+///
+/// Frames are numbered left to right, then top to bottom.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimatedSprite {
+    /// Atlas x/y coordinate of the top-left corner of the grid
+    pub grid_pos: Vector2<f32>,
+
+    /// Size of the whole grid in the atlas
+    pub grid_size: Vector2<f32>,
+    columns: u32,
+    rows: u32,
+
+    /// Frames actually used, which may leave cells at the end of the last row empty
+    frame_count: u32,
+
+    /// Frames per second
+    pub fps: f32,
+    pub mode: FlipbookMode,
+
+    /// Seconds since the first frame was shown
+    time: f32,
+}
+
+impl AnimatedSprite {
+    /// Play every cell of a grid covering the whole atlas
+    pub fn new(columns: u32, rows: u32, fps: f32, mode: FlipbookMode) -> Self {
+        assert!(columns > 0 && rows > 0, "Flipbook grid must have at least one cell");
+        Self {
+            grid_pos: Vector2::new(0.0, 0.0),
+            grid_size: Vector2::new(1.0, 1.0),
+            columns,
+            rows,
+            frame_count: columns * rows,
+            fps,
+            mode,
+            time: 0.0,
+        }
+    }
+
+    /// Only play the first `frame_count` cells of the grid
+    pub fn with_frame_count(mut self, frame_count: u32) -> Self {
+        self.frame_count = frame_count.clamp(1, self.columns * self.rows);
+        self
+    }
+
+    /// Only play a grid covering the given rect of the atlas
+    pub fn with_grid_rect(mut self, pos: Vector2<f32>, size: Vector2<f32>) -> Self {
+        self.grid_pos = pos;
+        self.grid_size = size;
+        self
+    }
+
+    pub fn tick(&mut self, dt: f32) {
+        self.time += dt;
+
+        // Wrap repeating animations back round a whole cycle, so the time never grows large
+        // enough to lose precision
+        let cycle_frames = match self.mode {
+            FlipbookMode::Once => return,
+            FlipbookMode::Loop => self.frame_count,
+            FlipbookMode::PingPong => 2 * (self.frame_count - 1),
+        };
+        if cycle_frames > 0 && self.fps > 0.0 {
+            self.time = self.time.rem_euclid(cycle_frames as f32 / self.fps);
+        }
+    }
+
+    /// Go back to the first frame
+    pub fn restart(&mut self) {
+        self.time = 0.0;
+    }
+
+    /// Whether a `FlipbookMode::Once` animation has reached its last frame. Never the case for
+    /// the repeating modes.
+    pub fn is_finished(&self) -> bool {
+        self.mode == FlipbookMode::Once && self.steps() >= self.frame_count as u64 - 1
+    }
+
+    /// Frames stepped through since the start, ignoring the mode
+    fn steps(&self) -> u64 {
+        (self.time * self.fps).max(0.0) as u64
+    }
+
+    /// Index of the frame currently showing
+    pub fn frame(&self) -> u32 {
+        let steps = self.steps();
+        let last = self.frame_count as u64 - 1;
+        let frame = match self.mode {
+            FlipbookMode::Once => steps.min(last),
+            FlipbookMode::Loop => steps % self.frame_count as u64,
+            // Up and back down without repeating either end, eg. 0 1 2 1 0 1 2 ...
+            FlipbookMode::PingPong if last == 0 => 0,
+            FlipbookMode::PingPong => {
+                let step = steps % (2 * last);
+                if step <= last {
+                    step
+                } else {
+                    2 * last - step
+                }
+            }
+        };
+        frame as u32
+    }
+
+    /// Atlas x/y coordinate of the top-left corner of the current frame, and its size, for a
+    /// sprite's `atlas_pos` and `atlas_size`
+    pub fn atlas_rect(&self) -> (Vector2<f32>, Vector2<f32>) {
+        let frame = self.frame();
+        let size = Vector2::new(
+            self.grid_size.x / self.columns as f32,
+            self.grid_size.y / self.rows as f32,
+        );
+        let pos = Vector2::new(
+            self.grid_pos.x + (frame % self.columns) as f32 * size.x,
+            self.grid_pos.y + (frame / self.columns) as f32 * size.y,
+        );
+        (pos, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene_data::SceneNode;
+
+    fn translation_channel(interpolation: Interpolation, values: Vec<f32>) -> AnimationChannel {
+        AnimationChannel {
+            node: 0,
+            times: vec![1.0, 3.0],
+            keyframes: Keyframes::Translation(
+                values.into_iter().map(|x| Vector3::new(x, 0.0, 0.0)).collect(),
+            ),
+            interpolation,
+        }
+    }
+
+    fn sample_x(channel: AnimationChannel, time: f32) -> f32 {
+        let clip = AnimationClip {
+            name: None,
+            channels: vec![channel],
+            duration: 3.0,
+        };
+        let mut pose = vec![NodeTransform::default()];
+        clip.sample(time, &mut pose);
+        pose[0].translation.x
+    }
+
+    #[test]
+    fn test_sample_interpolation() {
+        let linear = || translation_channel(Interpolation::Linear, vec![2.0, 4.0]);
+        assert_eq!(sample_x(linear(), 0.0), 2.0);
+        assert_eq!(sample_x(linear(), 2.0), 3.0);
+        assert_eq!(sample_x(linear(), 5.0), 4.0);
+
+        let step = translation_channel(Interpolation::Step, vec![2.0, 4.0]);
+        assert_eq!(sample_x(step, 2.9), 2.0);
+
+        // Flat tangents ease in and out, but still pass through the midpoint half way
+        let spline = || {
+            translation_channel(Interpolation::CubicSpline, vec![0.0, 2.0, 0.0, 0.0, 4.0, 0.0])
+        };
+        assert_eq!(sample_x(spline(), 2.0), 3.0);
+        assert!(sample_x(spline(), 1.5) < 2.5);
+    }
+
+    #[test]
+    fn test_player_interpolates_between_ticks() {
+        let node = SceneNode {
+            local_transform: NodeTransform::default(),
+            mesh: None,
+            skin: None,
+            children: Vec::new(),
+        };
+        let scene = SceneData {
+            meshes: Vec::new(),
+            nodes: vec![node],
+            skins: Vec::new(),
+            animations: vec![AnimationClip {
+                name: None,
+                channels: vec![translation_channel(Interpolation::Linear, vec![2.0, 4.0])],
+                duration: 3.0,
+            }],
+            roots: vec![0],
+        };
+        let x = |transforms: Vec<Matrix4<f32>>| transforms[0].w.x;
+
+        // Nothing to interpolate from before the first tick
+        let mut player = AnimationPlayer::new(&scene, 0);
+        assert_eq!(x(player.interpolated_node_transforms(&scene, 0.5)), 2.0);
+
+        player.looping = false;
+        player.tick(&scene, 1.0);
+        player.tick(&scene, 1.0);
+        assert_eq!(x(player.interpolated_node_transforms(&scene, 0.0)), 2.0);
+        assert_eq!(x(player.interpolated_node_transforms(&scene, 0.5)), 2.5);
+        assert_eq!(x(player.interpolated_node_transforms(&scene, 1.0)), 3.0);
+    }
+
+    #[test]
+    fn test_rotation_stays_normalized() {
+        let clip = AnimationClip {
+            name: None,
+            channels: vec![AnimationChannel {
+                node: 0,
+                times: vec![0.0, 1.0],
+                keyframes: Keyframes::Rotation(vec![
+                    Quaternion::new(1.0, 0.0, 0.0, 0.0),
+                    Quaternion::new(0.0, 0.0, 0.0, 1.0),
+                ]),
+                interpolation: Interpolation::Linear,
+            }],
+            duration: 1.0,
+        };
+        let mut pose = vec![NodeTransform::default()];
+        clip.sample(0.5, &mut pose);
+        assert!((pose[0].rotation.magnitude() - 1.0).abs() < 1e-5);
+    }
+
+    fn frames(mut sprite: AnimatedSprite, count: usize) -> Vec<u32> {
+        (0..count)
+            .map(|_| {
+                let frame = sprite.frame();
+                sprite.tick(0.1);
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_flipbook_modes() {
+        let sprite = |mode| AnimatedSprite::new(2, 2, 10.0, mode).with_frame_count(3);
+        assert_eq!(frames(sprite(FlipbookMode::Once), 5), vec![0, 1, 2, 2, 2]);
+        assert_eq!(frames(sprite(FlipbookMode::Loop), 5), vec![0, 1, 2, 0, 1]);
+        assert_eq!(frames(sprite(FlipbookMode::PingPong), 7), vec![0, 1, 2, 1, 0, 1, 2]);
+
+        let mut once = sprite(FlipbookMode::Once);
+        once.tick(0.25);
+        assert!(once.is_finished());
+        assert!(!sprite(FlipbookMode::Loop).is_finished());
+    }
+
+    #[test]
+    fn test_flipbook_atlas_rect() {
+        let mut sprite = AnimatedSprite::new(4, 2, 1.0, FlipbookMode::Loop)
+            .with_grid_rect(Vector2::new(0.5, 0.0), Vector2::new(0.5, 0.5));
+        sprite.tick(5.5);
+        assert_eq!(sprite.frame(), 5);
+        assert_eq!(sprite.atlas_rect(), (Vector2::new(0.625, 0.25), Vector2::new(0.125, 0.25)));
+    }
+}
@@ -1,89 +1,842 @@
-use std::time::Duration;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use cgmath::{Angle, Deg, InnerSpace, Matrix4, Point3, Quaternion, Rad, SquareMatrix, Vector3};
+use cgmath::{
+    Angle, Deg, InnerSpace, Matrix4, Point3, Rad, SquareMatrix, Vector2, Vector3, Vector4,
+};
+use tracing::{info, warn};
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::MouseButton;
 
-use crate::camera::Camera;
-use crate::input_manager::{InputManager, KeyState, LogicalEvent, LogicalKey};
+use crate::animation::AnimatedSprite;
+use crate::asset_loader::{LoadedScene, SceneHandle};
+use crate::calibration::CalibrationScreen;
+use crate::camera::{Camera, FovAnimation, InvalidClipPlanes, OrbitCamera};
+use crate::collision::Sphere;
+use crate::config::{AccessibilityConfig, Config};
+use crate::debug_gui::{DebugGui, DebugGuiOutput};
+use crate::display::WindowMode;
+use crate::text_field::{Clipboard, TextField};
+use crate::ui_interaction::{UiEvent, UiInteraction, UiRegionId};
+use crate::mesh_gen;
+use crate::loading_indicator;
+use crate::input_manager::{
+    InputManager, KeyState, LogicalAxis, LogicalEvent, LogicalKey, TextInputEvent,
+};
+use crate::model_data::{MaterialData, MaterialFactors, ModelData};
+use crate::picking::Ray;
+use crate::quality::QualityPreset;
 use crate::renderer::{
-    frame_packet::{FramePacket, FramePacketModel, InstanceData, FramePacketSprites, SpriteInstanceData},
-    ModelId, AtlasId,
+    frame_packet::{
+        Anchor, DebugLine, DepthOfField, DirectionalLight, Fog, FogMode, FramePacket,
+        FramePacketSplitView, FramePacketSprites, FramePacketView, InstanceStyle, Light, PointLight,
+        SpotLight, TextRun, UiSprite, ViewportRect,
+    },
+    AtlasId, ColorDeficiency, ColorFilter, FrameStats, LodModel, MaterialId, ModelId,
+    OutputCalibration, PresentMode, SceneModel, SkyboxId,
+};
+use crate::stats::Stats;
+use crate::world::{
+    Entity, LodRef, MaterialRef, ModelRef, SceneInstance, Selected, Spinner, Transform, World,
 };
 
-struct AppObject {
-    model: ModelId,
-    scale: f32,
-    pos: Point3<f32>,
-    angle: Quaternion<f32>,
+/// Size of the render target the rear view camera is drawn in to, in pixels
+pub const REAR_VIEW_SIZE: [u32; 2] = [480, 270];
+
+/// Region of the rear view in the overlay, which is enlarged and shrunk again by clicking it
+const REAR_VIEW_REGION: UiRegionId = UiRegionId(0);
+
+/// Distance between each of the extra scenes lined up beside the object, in meters
+const EXTRA_SCENE_SPACING: f32 = 3.0;
+
+/// How far in front of the fly camera its focus point is, in meters
+const FOCUS_DISTANCE: f32 = 3.0;
+
+/// How long zooming the fly camera's field of view takes to ease to each new target, in seconds
+const FOV_ZOOM_DURATION: f32 = 0.15;
+
+/// Radius of the sphere around the fly camera that collides with the scene, in meters. Kept
+/// well clear of the near plane so that models never get clipped by it.
+const CAMERA_COLLISION_RADIUS: f32 = 0.3;
+
+/// How long the object flashes for after one of its parts is picked, in seconds
+const PICK_FLASH_DURATION: f32 = 0.4;
+
+/// Base color multiplier of every other ground tile, to make a checkerboard of the one material
+const GROUND_CHECKER_TINT: f32 = 0.7;
+
+/// Every color filter the debug GUI cycles through, with its name
+const COLOR_FILTERS: [(ColorFilter, &str); 7] = [
+    (ColorFilter::None, "None"),
+    (ColorFilter::Simulate(ColorDeficiency::Protanopia), "Protanopia"),
+    (ColorFilter::Simulate(ColorDeficiency::Deuteranopia), "Deuteranopia"),
+    (ColorFilter::Simulate(ColorDeficiency::Tritanopia), "Tritanopia"),
+    (ColorFilter::Daltonize(ColorDeficiency::Protanopia), "Daltonize protanopia"),
+    (ColorFilter::Daltonize(ColorDeficiency::Deuteranopia), "Daltonize deuteranopia"),
+    (ColorFilter::Daltonize(ColorDeficiency::Tritanopia), "Daltonize tritanopia"),
+];
+
+/// Which style of control the user has over the main camera
+#[derive(Clone)]
+enum CameraController {
+    /// Free flying, looking around with the mouse and moving relative to the view direction
+    Fly(Camera),
+
+    /// Circling a target point, with looking around orbiting the target and moving
+    /// forward/backward zooming in/out
+    Orbit(OrbitCamera),
 }
 
-impl AppObject {
-    fn rotate(&mut self, angle: impl Into<Rad<f32>>, axis: Vector3<f32>) {
-        let angle = angle.into() / 2.0;
-        let s = angle.sin();
-        let c = angle.cos();
-        let rotation = Quaternion::new(c, axis.x * s, axis.y * s, axis.z * s);
+impl CameraController {
+    fn view(&self) -> Matrix4<f32> {
+        match self {
+            CameraController::Fly(camera) => camera.view(),
+            CameraController::Orbit(camera) => camera.view(),
+        }
+    }
+
+    fn proj(&self, aspect_ratio: f32) -> Matrix4<f32> {
+        match self {
+            CameraController::Fly(camera) => camera.proj(aspect_ratio),
+            CameraController::Orbit(camera) => camera.proj(aspect_ratio),
+        }
+    }
+
+    fn near_clip(&self) -> f32 {
+        match self {
+            CameraController::Fly(camera) => camera.near_clip,
+            CameraController::Orbit(camera) => camera.near_clip,
+        }
+    }
+
+    fn reverse_z(&self) -> bool {
+        match self {
+            CameraController::Fly(camera) => camera.reverse_z,
+            CameraController::Orbit(camera) => camera.reverse_z,
+        }
+    }
+
+    fn set_clip_planes(&mut self, near: f32, far: f32) -> Result<(), InvalidClipPlanes> {
+        match self {
+            CameraController::Fly(camera) => camera.set_clip_planes(near, far),
+            CameraController::Orbit(camera) => camera.set_clip_planes(near, far),
+        }
+    }
+
+    fn vertical_fov(&self) -> Rad<f32> {
+        match self {
+            CameraController::Fly(camera) => camera.vertical_fov,
+            CameraController::Orbit(camera) => camera.vertical_fov,
+        }
+    }
+
+    fn set_vertical_fov(&mut self, fov: Rad<f32>) {
+        match self {
+            CameraController::Fly(camera) => camera.set_vertical_fov(fov),
+            CameraController::Orbit(camera) => camera.set_vertical_fov(fov),
+        }
+    }
+
+    /// Turn the view, to the right/upwards for positive angles
+    fn pan(&mut self, horizontal: Rad<f32>, vertical: Rad<f32>) {
+        match self {
+            CameraController::Fly(camera) => {
+                camera.pan_horizonal(horizontal);
+                camera.pan_vertical(vertical);
+            }
+            // Turning the view right means moving clockwise around the target, and looking up
+            // means dropping below it
+            CameraController::Orbit(camera) => {
+                camera.orbit_horizontal(-horizontal);
+                camera.orbit_vertical(-vertical);
+            }
+        }
+    }
+
+    /// Move with the given camera relative velocity (see `App::camera_velocity`) for `dt`
+    /// seconds
+    fn apply_velocity(&mut self, velocity: Vector3<f32>, dt: f32) {
+        match self {
+            CameraController::Fly(camera) => {
+                let strafe_dir = camera.direction.cross([0.0, 0.0, 1.0].into()).normalize();
+                let strafe: Vector3<f32> = strafe_dir * velocity.x;
+                let forward: Vector3<f32> = velocity.y * camera.direction;
+                let up: Vector3<f32> = velocity.z * Vector3::new(0.0, 0.0, 1.0);
+                camera.location += (strafe + forward + up) * dt;
+            }
+            // Sideways/vertical movement is along the orbit, at the same speed as flying
+            CameraController::Orbit(camera) => {
+                camera.orbit_horizontal(Rad(velocity.x * dt / camera.distance));
+                camera.orbit_vertical(Rad(velocity.z * dt / camera.distance));
+                camera.zoom(velocity.y * dt);
+            }
+        }
+    }
 
-        self.angle = (rotation * self.angle).normalize();
+    /// This camera `alpha` of the way along its movement from `previous`, which is how it was at
+    /// the start of the last tick
+    ///
+    /// The fly camera only has its location and field of view interpolated, as the mouse turns it
+    /// between ticks and looking around should never lag behind. If the controller was switched
+    /// during the tick there's nothing to interpolate from.
+    fn interpolated(&self, previous: &CameraController, alpha: f32) -> Self {
+        let lerp = |from: f32, to: f32| from + (to - from) * alpha;
+        match (self, previous) {
+            (CameraController::Fly(camera), CameraController::Fly(previous)) => {
+                CameraController::Fly(Camera {
+                    location: previous.location + (camera.location - previous.location) * alpha,
+                    vertical_fov: Rad(lerp(previous.vertical_fov.0, camera.vertical_fov.0)),
+                    ..camera.clone()
+                })
+            }
+            (CameraController::Orbit(camera), CameraController::Orbit(previous)) => {
+                CameraController::Orbit(OrbitCamera {
+                    target: previous.target + (camera.target - previous.target) * alpha,
+                    distance: lerp(previous.distance, camera.distance),
+                    // The shortest way round, in case the yaw wrapped during the tick
+                    yaw: previous.yaw + (camera.yaw - previous.yaw).normalize_signed() * alpha,
+                    pitch: Rad(lerp(previous.pitch.0, camera.pitch.0)),
+                    vertical_fov: Rad(lerp(previous.vertical_fov.0, camera.vertical_fov.0)),
+                    ..camera.clone()
+                })
+            }
+            _ => self.clone(),
+        }
     }
 
-    /// Generates a matrix that transforms this objects model space into world space
-    fn model_matrix(&self) -> Matrix4<f32> {
-        Matrix4::from_translation(Vector3::new(self.pos.x, self.pos.y, self.pos.z))
-            * Matrix4::from(self.angle)
-            * Matrix4::from_scale(self.scale)
+    /// The point the camera is looking at, ie. the orbit target or a little way in front of the
+    /// fly camera
+    fn focus_point(&self) -> Point3<f32> {
+        match self {
+            CameraController::Fly(camera) => camera.location + camera.direction * FOCUS_DISTANCE,
+            CameraController::Orbit(camera) => camera.target,
+        }
     }
 
-    /// Generates a matrix that transforms normals from this objects model space to the given view
-    /// space
-    fn normal_matrix(&self, view: Matrix4<f32>) -> Matrix4<f32> {
-        let model_view = view * self.model_matrix();
-        let mut normal = model_view
-            .invert()
-            .expect("Model-View matrix had a zero determinant");
-        normal.transpose_self();
-        normal
+    /// Switch to the other kind of controller without moving the camera, orbiting around the
+    /// given target if switching to orbit mode
+    fn toggled(&self, orbit_target: Point3<f32>) -> Self {
+        match self {
+            CameraController::Fly(camera) => {
+                let mut orbit = OrbitCamera::looking_at(orbit_target, camera.location);
+                orbit.near_clip = camera.near_clip;
+                orbit.far_clip = camera.far_clip;
+                orbit.vertical_fov = camera.vertical_fov;
+                orbit.reverse_z = camera.reverse_z;
+                CameraController::Orbit(orbit)
+            }
+            CameraController::Orbit(orbit) => CameraController::Fly(Camera {
+                location: orbit.location(),
+                direction: orbit.direction(),
+                near_clip: orbit.near_clip,
+                far_clip: orbit.far_clip,
+                vertical_fov: orbit.vertical_fov,
+                reverse_z: orbit.reverse_z,
+            }),
+        }
     }
 }
 
+/// Width of each square tile in the ground grid
+const GROUND_TILE_SIZE: f32 = 2.0;
+
+/// How many tiles there are along each side of the ground grid
+const GROUND_TILES: u32 = 10;
+
+/// The single tile that's instanced to make up the ground
+pub fn ground_tile_model() -> ModelData {
+    let mut model = mesh_gen::plane(GROUND_TILE_SIZE, GROUND_TILE_SIZE, 1);
+    model.material.factors.base_color = [0.3, 0.3, 0.3, 1.0];
+    model.material.factors.roughness = 0.8;
+    model
+}
+
+/// Radius of the spheres in the row showing off LOD switching
+const LOD_SPHERE_RADIUS: f32 = 0.5;
+
+/// Every level of the LOD sphere, paired with the smallest fraction of the screen it's drawn at
+pub fn lod_sphere_models() -> Vec<(ModelData, f32)> {
+    [(64, 0.25), (24, 0.08), (8, 0.0)]
+        .iter()
+        .map(|&(segments, min_screen_size)| {
+            let mut model = mesh_gen::uv_sphere(LOD_SPHERE_RADIUS, segments, segments / 2);
+            model.material.factors.base_color = [0.8, 0.5, 0.2, 1.0];
+            (model, min_screen_size)
+        })
+        .collect()
+}
+
+/// Every other LOD sphere is drawn with this instead of its own material, sharing the meshes
+pub fn lod_sphere_alt_material() -> MaterialData {
+    MaterialData::from_factors(MaterialFactors {
+        base_color: [0.2, 0.4, 0.8, 1.0],
+        metallic: 1.0,
+        roughness: 0.3,
+        ..MaterialFactors::default()
+    })
+}
+
+/// Everything the app draws with that has to be loaded or uploaded before it starts
+pub struct AppAssets {
+    pub object_scene: SceneHandle,
+
+    /// Shown in a row beside the object, eg. extra models given on the command line
+    pub extra_scenes: Vec<SceneHandle>,
+
+    pub ui_atlas: AtlasId,
+    pub calibration_atlas: AtlasId,
+
+    /// Uploaded from `loading_indicator::flipbook_image`
+    pub loading_atlas: AtlasId,
+    pub skybox: SkyboxId,
+
+    /// Render target of `REAR_VIEW_SIZE` that the rear view camera is drawn in to
+    pub rear_view: AtlasId,
+
+    /// Uploaded from `ground_tile_model`
+    pub ground_tile: ModelId,
+
+    /// Uploaded from `lod_sphere_models`
+    pub lod_sphere: LodModel,
+
+    /// Uploaded from `lod_sphere_alt_material`
+    pub lod_sphere_alt_material: MaterialId,
+}
+
 pub struct App {
     input_manager: InputManager,
-    main_camera: Camera,
+    main_camera: CameraController,
+
+    /// `main_camera` as of the start of the last tick, for drawing frames that fall between ticks
+    previous_camera: CameraController,
 
-    /// Camera velocity relative to the camera
+    /// Direction of the held movement keys relative to the camera, which is scaled by
+    /// `camera_speed` to give its velocity
     ///
     /// The Z component of this vector is straight up in world space
     /// The Y component is in the direction the camera is facing
     /// The X component points right out of the camera (camera.dir cross world up)
     camera_velocity: Vector3<f32>,
 
-    object: AppObject,
+    /// Latest analog movement input, in the same space as `camera_velocity` with magnitude <= 1
+    analog_movement: Vector2<f32>,
+
+    /// Top speed of the main camera, in meters per second
+    camera_speed: f32,
+
+    /// Latest analog camera pan input, as horizontal/vertical rates with magnitude <= 1
+    analog_pan: Vector2<f32>,
+
+    /// Field of view the main camera starts with, and returns to when the zoom is reset
+    default_fov: Rad<f32>,
+
+    /// Set while the main camera's field of view is easing towards a new zoom level
+    fov_animation: Option<FovAnimation>,
+
+    /// Seconds left of the object's flash after a part of it was picked
+    pick_flash: f32,
+
+    world: World,
+
+    /// The spinning object in the middle of the scene, drawn with a `SceneInstance`
+    object: Entity,
+
+    /// Objects shown alongside `object`, which aren't selectable
+    extra_objects: Vec<Entity>,
 
     ui_atlas: AtlasId,
+
+    /// Shown in the corner of the screen while the object is still loading
+    loading_atlas: AtlasId,
+    loading_animation: AnimatedSprite,
+
+    skybox: SkyboxId,
+    rear_view: AtlasId,
+
+    /// Size of the window's drawable area
+    screen_size: PhysicalSize<u32>,
+
+    /// Physical pixels per logical pixel on the monitor the window is currently on
+    scale_factor: f64,
+
+    calibration_screen: CalibrationScreen,
+
+    /// Path of a scene to load, typed in to the debug GUI
+    scene_path_field: TextField,
+
+    /// Whether typed text is going to `scene_path_field`, rather than acting as controls
+    scene_path_focused: bool,
+    clipboard: Clipboard,
+
+    /// Scene submitted through `scene_path_field` that hasn't been picked up for loading yet
+    requested_scene: Option<PathBuf>,
+
+    quality: QualityPreset,
+
+    /// Set when the quality preset has changed but hasn't been picked up by the renderer yet
+    quality_changed: bool,
+
+    present_mode: PresentMode,
+
+    /// Like `quality_changed`, for `present_mode`
+    present_mode_changed: bool,
+
+    window_mode: WindowMode,
+
+    /// Like `quality_changed`, for `window_mode`
+    window_mode_changed: bool,
+
+    /// Whether the scene is drawn in wireframe
+    wireframe: bool,
+
+    /// Like `quality_changed`, for `wireframe`
+    wireframe_changed: bool,
+
+    /// Adjustable in the debug GUI
+    accessibility: AccessibilityConfig,
+
+    /// Like `quality_changed`, for `accessibility`
+    accessibility_changed: bool,
+
+    /// Whether to draw axes and light markers over the scene
+    debug_lines_visible: bool,
+
+    /// Whether to draw a second camera beside the main one, toggled from the debug GUI
+    split_screen: bool,
+
+    /// Whether the fly camera is pushed out of the scene's models each tick
+    camera_collision: bool,
+
+    /// Pairs of entities whose bounds overlapped at the end of the last tick, which are drawn
+    /// with the debug lines
+    overlapping: Vec<(Entity, Entity)>,
+
+    /// Intensity of the sun, and power of the point and spot lights, all adjustable in the debug
+    /// GUI
+    sun_intensity: f32,
+    point_light_power: f32,
+    spot_light_power: f32,
+
+    /// Density of the exponential squared fog, adjustable in the debug GUI. Zero draws no fog.
+    fog_density: f32,
+
+    /// Whether the scene is blurred away from `focus_point`, adjustable in the debug GUI
+    depth_of_field: bool,
+
+    /// Blur radius of the far distance, as a fraction of the screen's height
+    dof_aperture: f32,
+
+    /// World space point that the depth of field focuses on, where the object was last picked.
+    /// The object's origin is focused on while nothing's picked.
+    focus_point: Option<Point3<f32>>,
+
+    debug_gui: DebugGui,
+
+    /// Hit tests the cursor against the overlay's clickable sprites
+    ui_interaction: UiInteraction,
+    rear_view_enlarged: bool,
+
+    /// What the debug GUI laid out on the last tick, drawn by every frame until the next
+    debug_gui_output: DebugGuiOutput,
+
+    /// Whether the mouse is controlling the camera, rather than moving a cursor around the UI
+    cursor_grabbed: bool,
+
+    /// Like `quality_changed`, for `cursor_grabbed`
+    cursor_grab_changed: bool,
+
+    stats: Stats,
 }
 
 impl App {
-    pub fn new(model: ModelId, ui_atlas: AtlasId) -> Self {
-        let mut object = AppObject {
-            model,
-            scale: 0.4,
-            pos: [0.0, 0.0, -1.0].into(),
-            angle: [1.0, 0.0, 0.0, 0.0].into(),
+    pub fn new(
+        assets: AppAssets,
+        screen_size: PhysicalSize<u32>,
+        scale_factor: f64,
+        config: &Config,
+    ) -> Self {
+        let quality = config.graphics_quality;
+
+        let mut world = World::default();
+        let object =
+            Self::spawn_object(&mut world, assets.object_scene, Point3::new(0.0, 0.0, -1.0));
+        let extra_objects = assets
+            .extra_scenes
+            .iter()
+            .enumerate()
+            .map(|(i, &scene)| {
+                let position = Point3::new(0.0, EXTRA_SCENE_SPACING * (i + 1) as f32, -1.0);
+                Self::spawn_object(&mut world, scene, position)
+            })
+            .collect();
+        Self::spawn_ground(&mut world, assets.ground_tile, Point3::new(0.0, 0.0, -1.0));
+        Self::spawn_lod_spheres(&mut world, assets.lod_sphere, assets.lod_sphere_alt_material);
+
+        let mut camera = Camera {
+            location: [2.0, 2.0, 0.0].into(),
+            direction: Vector3::new(-1.0, -1.0, 0.0).normalize(),
+            far_clip: quality.draw_distance(),
+            reverse_z: config.reverse_z,
+            ..Camera::default()
         };
-        object.rotate(Deg(90.0), [1.0, 0.0, 0.0].into());
+        camera.set_vertical_fov(Deg(config.controls.field_of_view));
+        let default_fov = camera.vertical_fov;
+        let main_camera = CameraController::Fly(camera);
 
         Self {
-            input_manager: InputManager::new(),
-            main_camera: Camera {
-                location: [2.0, 2.0, 0.0].into(),
-                direction: Vector3::new(-1.0, -1.0, 0.0).normalize(),
-                ..Camera::default()
-            },
+            input_manager: InputManager::new(
+                config.key_bindings.clone(),
+                config.controls.mouse_sensitivity,
+            ),
+            previous_camera: main_camera.clone(),
+            main_camera,
             camera_velocity: [0.0, 0.0, 0.0].into(),
+            analog_movement: [0.0, 0.0].into(),
+            camera_speed: config.controls.move_speed,
+            analog_pan: [0.0, 0.0].into(),
+            default_fov,
+            fov_animation: None,
+            pick_flash: 0.0,
+            world,
             object,
-            ui_atlas,
+            extra_objects,
+            ui_atlas: assets.ui_atlas,
+            loading_atlas: assets.loading_atlas,
+            loading_animation: loading_indicator::animation(),
+            skybox: assets.skybox,
+            rear_view: assets.rear_view,
+            screen_size,
+            scale_factor,
+            calibration_screen: CalibrationScreen::new(assets.calibration_atlas),
+            scene_path_field: TextField::new(),
+            scene_path_focused: false,
+            clipboard: Clipboard::new(),
+            requested_scene: None,
+            quality,
+            quality_changed: false,
+            present_mode: config.present_mode,
+            present_mode_changed: false,
+            window_mode: config.display.window_mode,
+            window_mode_changed: false,
+            wireframe: false,
+            wireframe_changed: false,
+            accessibility: config.accessibility,
+            accessibility_changed: false,
+            debug_lines_visible: false,
+            split_screen: false,
+            camera_collision: config.controls.camera_collision,
+            overlapping: Vec::new(),
+            sun_intensity: 0.8,
+            point_light_power: 5.0,
+            spot_light_power: 4.0,
+            fog_density: 0.0,
+            depth_of_field: false,
+            dof_aperture: DepthOfField::default().aperture,
+            focus_point: None,
+            debug_gui: DebugGui::new(screen_size, scale_factor),
+            ui_interaction: UiInteraction::new(screen_size, scale_factor),
+            rear_view_enlarged: false,
+            debug_gui_output: DebugGuiOutput::default(),
+            cursor_grabbed: false,
+            cursor_grab_changed: false,
+            stats: Stats::default(),
+        }
+    }
+
+    /// A row of spheres heading away from the object, each further one drawn with less detail
+    /// and alternating between two materials
+    fn spawn_lod_spheres(world: &mut World, sphere: LodModel, alt_material: MaterialId) {
+        let sphere = Rc::new(sphere);
+        for i in 0..8 {
+            let position = Point3::new(-4.0 * i as f32, -3.0, LOD_SPHERE_RADIUS - 1.0);
+            let entity = world.spawn();
+            world.transforms.insert(entity, Transform::at(position));
+            world.lod_models.insert(entity, LodRef::new(sphere.clone()));
+            if i % 2 == 1 {
+                world.materials.insert(entity, MaterialRef(alt_material));
+            }
+        }
+    }
+
+    fn spawn_object(world: &mut World, scene: SceneHandle, position: Point3<f32>) -> Entity {
+        let mut transform = Transform {
+            scale: 0.4,
+            ..Transform::at(position)
+        };
+        transform.rotate(Deg(90.0), Vector3::unit_x());
+
+        let object = world.spawn();
+        world.transforms.insert(object, transform);
+        world.scenes.insert(object, SceneInstance::new(scene));
+        world.spinners.insert(object, Spinner {
+            axis: Vector3::unit_z(),
+            speed: Deg(100.0).into(),
+        });
+        object
+    }
+
+    /// A grid of tiles centered on the given point, each its own entity
+    fn spawn_ground(world: &mut World, tile: ModelId, center: Point3<f32>) {
+        let half_width = GROUND_TILE_SIZE * GROUND_TILES as f32 / 2.0;
+        for j in 0..GROUND_TILES {
+            for i in 0..GROUND_TILES {
+                let offset = Vector3::new(
+                    (i as f32 + 0.5) * GROUND_TILE_SIZE - half_width,
+                    (j as f32 + 0.5) * GROUND_TILE_SIZE - half_width,
+                    0.0,
+                );
+                let entity = world.spawn();
+                world.transforms.insert(entity, Transform::at(center + offset));
+                world.models.insert(entity, ModelRef(tile));
+                if (i + j) % 2 == 1 {
+                    let t = GROUND_CHECKER_TINT;
+                    world.styles.insert(entity, InstanceStyle {
+                        tint: Vector4::new(t, t, t, 1.0),
+                        ..InstanceStyle::default()
+                    });
+                }
+            }
+        }
+    }
+
+    /// Show a scene the user picked, by dropping it on to the window or typing its path in to
+    /// the debug GUI, at the camera's focus point
+    ///
+    /// The scene replaces the object and everything shown alongside it, unless Shift is held in
+    /// which case it's shown alongside them.
+    pub fn drop_scene(&mut self, scene: SceneHandle) {
+        let position = self.main_camera.focus_point();
+        if self.input_manager.modifiers().shift() {
+            let object = Self::spawn_object(&mut self.world, scene, position);
+            self.extra_objects.push(object);
+        } else {
+            for object in self.extra_objects.drain(..) {
+                self.world.despawn(object);
+            }
+            self.world.despawn(self.object);
+            self.object = Self::spawn_object(&mut self.world, scene, position);
+        }
+    }
+
+    /// Start drawing a scene that has finished loading, if it's one the app is waiting on
+    pub fn scene_loaded(&mut self, scene: LoadedScene) {
+        if self.world.scene_loaded(scene) {
+            self.world.selections.remove(self.object);
+        }
+    }
+
+    /// Where the object is in the world, interpolated by `alpha` (see
+    /// `World::interpolated_transform`)
+    fn object_transform(&self, alpha: f32) -> Transform {
+        self.world
+            .interpolated_transform(self.object, alpha)
+            .unwrap_or_default()
+    }
+
+    /// The object's parts, and each one's model matrix interpolated by `alpha`
+    fn object_parts(&self, alpha: f32) -> Vec<(&SceneModel, Matrix4<f32>)> {
+        let object_matrix = self.object_transform(alpha).matrix();
+        match self.world.scenes.get(self.object) {
+            Some(scene) => {
+                let pose = scene.interpolated_pose(alpha);
+                scene
+                    .parts
+                    .iter()
+                    .map(|part| (part, object_matrix * scene.posed_part(part, &pose).0))
+                    .collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Should be called whenever the window grabs or releases the cursor
+    pub fn set_cursor_grabbed(&mut self, grabbed: bool) {
+        self.cursor_grabbed = grabbed;
+        self.input_manager.set_cursor_grabbed(grabbed);
+    }
+
+    /// Whether the window should have the cursor grabbed
+    pub fn cursor_grabbed(&self) -> bool {
+        self.cursor_grabbed
+    }
+
+    /// Returns whether the window should grab the cursor if that has been toggled since this was
+    /// last called
+    pub fn take_cursor_grab_change(&mut self) -> Option<bool> {
+        if std::mem::take(&mut self.cursor_grab_changed) {
+            Some(self.cursor_grabbed)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_quality(&mut self, quality: QualityPreset) {
+        self.quality = quality;
+        self.quality_changed = true;
+        let near_clip = self.main_camera.near_clip();
+        if let Err(e) = self.main_camera.set_clip_planes(near_clip, quality.draw_distance()) {
+            warn!("Keeping the current draw distance: {}", e);
         }
     }
 
+    /// Set the main camera's clip planes, leaving them unchanged if they're invalid
+    #[allow(unused)]
+    pub fn set_clip_planes(&mut self, near: f32, far: f32) -> Result<(), InvalidClipPlanes> {
+        self.main_camera.set_clip_planes(near, far)
+    }
+
+    /// Immediately set the main camera's vertical field of view, stopping any zoom in progress
+    #[allow(unused)]
+    pub fn set_vertical_fov(&mut self, fov: impl Into<Rad<f32>>) {
+        self.fov_animation = None;
+        self.main_camera.set_vertical_fov(fov.into());
+    }
+
+    /// Ease the main camera's field of view towards the given one
+    fn animate_fov(&mut self, target: Rad<f32>) {
+        let current = self.main_camera.vertical_fov();
+        self.fov_animation = Some(FovAnimation::new(current, target, FOV_ZOOM_DURATION));
+    }
+
+    /// Zoom in for positive amounts, by narrowing the field of view when flying or moving toward
+    /// the target when orbiting
+    fn zoom(&mut self, amount: f32) {
+        match &mut self.main_camera {
+            CameraController::Fly(camera) => {
+                // Steps build on the target of any zoom still in progress, so that scrolling
+                // quickly isn't slowed down by the easing
+                let from = match &self.fov_animation {
+                    Some(animation) => animation.target(),
+                    None => camera.vertical_fov,
+                };
+                self.animate_fov(from - Rad::from(Deg(5.0)) * amount);
+            }
+            // Proportional to the distance so that each step feels the same from near or far
+            CameraController::Orbit(camera) => camera.zoom(0.1 * amount * camera.distance),
+        }
+    }
+
+    /// Returns the new quality preset if it has changed since this was last called
+    pub fn take_quality_change(&mut self) -> Option<QualityPreset> {
+        if std::mem::take(&mut self.quality_changed) {
+            Some(self.quality)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the new present mode if it has changed since this was last called
+    pub fn take_present_mode_change(&mut self) -> Option<PresentMode> {
+        if std::mem::take(&mut self.present_mode_changed) {
+            Some(self.present_mode)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the new window mode if it has changed since this was last called
+    pub fn take_window_mode_change(&mut self) -> Option<WindowMode> {
+        if std::mem::take(&mut self.window_mode_changed) {
+            Some(self.window_mode)
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether wireframe should be enabled if it has been toggled since this was last
+    /// called
+    pub fn take_wireframe_change(&mut self) -> Option<bool> {
+        if std::mem::take(&mut self.wireframe_changed) {
+            Some(self.wireframe)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the new accessibility settings if they've changed since this was last called
+    pub fn take_accessibility_change(&mut self) -> Option<AccessibilityConfig> {
+        if std::mem::take(&mut self.accessibility_changed) {
+            Some(self.accessibility)
+        } else {
+            None
+        }
+    }
+
+    /// Pick up the renderer's statistics for the frame it last drew
+    pub fn record_frame_stats(&mut self, frame_stats: &FrameStats) {
+        self.stats.record_frame(frame_stats);
+    }
+
+    /// Returns the path of a scene the user has asked to load since this was last called
+    pub fn take_scene_request(&mut self) -> Option<PathBuf> {
+        self.requested_scene.take()
+    }
+
+    /// Start or stop sending typed text to the scene path field instead of treating key presses
+    /// as controls
+    fn set_scene_path_focused(&mut self, focused: bool) {
+        self.scene_path_focused = focused;
+        self.input_manager.set_text_input_enabled(focused);
+    }
+
+    /// Whether key presses are going to a text field, so shouldn't act as shortcuts either
+    pub fn text_input_active(&self) -> bool {
+        self.scene_path_focused
+    }
+
+    /// Physical pixels from the top-left of the window to where the IME should open its
+    /// candidate window, while a text field is focused
+    ///
+    /// `text_width` gives the physical pixel width of UI text drawn at a pixel height.
+    pub fn ime_position(
+        &self,
+        text_width: impl Fn(&str, f32) -> f32,
+    ) -> Option<PhysicalPosition<f32>> {
+        self.debug_gui_output.ime_position(self.scale_factor as f32, text_width)
+    }
+
+    fn handle_text_input_event(&mut self, event: TextInputEvent) {
+        if !self.scene_path_focused {
+            return;
+        }
+        if event == TextInputEvent::Cancel {
+            self.set_scene_path_focused(false);
+            return;
+        }
+
+        if let Some(submitted) = self.scene_path_field.apply(event, &mut self.clipboard) {
+            let path = submitted.trim();
+            if !path.is_empty() {
+                self.requested_scene = Some(PathBuf::from(path));
+            }
+            self.set_scene_path_focused(false);
+        }
+    }
+
+    /// Update the size/DPI of the window that the UI is laid out for
+    pub fn set_screen_metrics(&mut self, screen_size: PhysicalSize<u32>, scale_factor: f64) {
+        self.screen_size = screen_size;
+        self.scale_factor = scale_factor;
+        self.debug_gui.set_screen_metrics(screen_size, scale_factor);
+        self.ui_interaction.set_screen_metrics(screen_size, scale_factor);
+    }
+
+    /// Converts a size in logical pixels to a size in clip space, so that UI elements stay the
+    /// same apparent size regardless of the monitor's DPI
+    fn logical_to_clip_size(&self, logical_size: Vector2<f32>) -> Vector2<f32> {
+        let physical_size = logical_size * self.scale_factor as f32;
+        Vector2::new(
+            2.0 * physical_size.x / self.screen_size.width as f32,
+            2.0 * physical_size.y / self.screen_size.height as f32,
+        )
+    }
+
+    /// The display calibration the renderer should apply to its output
+    pub fn output_calibration(&self) -> OutputCalibration {
+        self.calibration_screen.calibration
+    }
+
     pub fn handle_event(&mut self, event: &winit::event::Event<()>) {
         self.input_manager.update(event);
         while let Some(logical_event) = self.input_manager.poll_logical_event() {
@@ -92,15 +845,18 @@ impl App {
     }
 
     fn handle_logical_event(&mut self, event: LogicalEvent) {
+        if self.debug_gui.handle_event(&event) || self.ui_interaction.handle_event(&event) {
+            return;
+        }
+
         match event {
             LogicalEvent::MouseMovement { x, y } => {
                 const MOUSE_SCALING: f32 = 1.0 / 1024.0;
-                self.main_camera.pan_horizonal(Rad(x * MOUSE_SCALING));
 
                 // A negative vertical delta is the mouse moving toward the top of the screen.
                 // Invert it so that the mouse moving upwards is a positive vertical pan (looking
                 // more up)
-                self.main_camera.pan_vertical(Rad(-y * MOUSE_SCALING));
+                self.main_camera.pan(Rad(x * MOUSE_SCALING), Rad(-y * MOUSE_SCALING));
             }
             LogicalEvent::Key {
                 logical_key,
@@ -108,13 +864,136 @@ impl App {
             } => {
                 self.handle_key_event(logical_key, new_state);
             }
+            LogicalEvent::MouseButton {
+                button: MouseButton::Middle,
+                new_state: KeyState::Down,
+            } => self.animate_fov(self.default_fov),
+            LogicalEvent::MouseButton { .. } => (),
+            LogicalEvent::Scroll { lines } => self.zoom(lines),
+            LogicalEvent::Axis { axis, value } => match axis {
+                LogicalAxis::Strafe => self.analog_movement.x = value,
+                LogicalAxis::Move => self.analog_movement.y = value,
+                LogicalAxis::PanHorizontal => self.analog_pan.x = value,
+                LogicalAxis::PanVertical => self.analog_pan.y = value,
+            },
+            LogicalEvent::Text(text_event) => self.handle_text_input_event(text_event),
+            LogicalEvent::Click { position } => self.pick(position),
+            LogicalEvent::CursorMoved { .. } => (),
+        }
+    }
+
+    /// Select the part of the object under the given position in the window, or under the center
+    /// of the window if there's no position
+    fn pick(&mut self, position: Option<PhysicalPosition<f64>>) {
+        let width = self.screen_size.width as f32;
+        let height = self.screen_size.height as f32;
+        if width == 0.0 || height == 0.0 {
+            return;
+        }
+
+        let ndc = match position {
+            Some(position) => Vector2::new(
+                2.0 * position.x as f32 / width - 1.0,
+                1.0 - 2.0 * position.y as f32 / height,
+            ),
+            None => Vector2::new(0.0, 0.0),
+        };
+        let proj = self.main_camera.proj(width / height);
+        let ray = Ray::from_screen(ndc, self.main_camera.view(), proj);
+
+        // Only the object's parts are selectable, but anything else can still be in the way
+        match self.world.raycast_parts(&ray) {
+            Some((entity, part, distance)) if entity == self.object => {
+                info!("Picked part {} at {:.2}m", part, distance);
+                self.focus_point = Some(ray.origin + ray.direction * distance);
+                self.world.selections.insert(self.object, Selected { part: Some(part) });
+                self.pick_flash = PICK_FLASH_DURATION;
+            }
+            _ => {
+                info!("Picked nothing");
+                self.focus_point = None;
+                self.world.selections.remove(self.object);
+            }
         }
     }
 
     fn handle_key_event(&mut self, key: LogicalKey, new_state: KeyState) {
+        let calibration = &mut self.calibration_screen;
+        match (key, new_state) {
+            (LogicalKey::ToggleCalibrationScreen, KeyState::Down) => {
+                calibration.visible = !calibration.visible
+            }
+            (LogicalKey::ToggleCameraMode, KeyState::Down) => {
+                self.main_camera = self.main_camera.toggled(self.object_transform(1.0).position);
+            }
+            (LogicalKey::CycleQualityPreset, KeyState::Down) => {
+                self.set_quality(self.quality.next());
+                info!("Graphics quality: {:?}", self.quality);
+            }
+            (LogicalKey::ToggleVsync, KeyState::Down) => {
+                self.present_mode = self.present_mode.vsync_toggled();
+                self.present_mode_changed = true;
+                info!("Present mode: {:?}", self.present_mode);
+            }
+            (LogicalKey::CycleWindowMode, KeyState::Down) => {
+                self.window_mode = self.window_mode.next();
+                self.window_mode_changed = true;
+                info!("Window mode: {:?}", self.window_mode);
+            }
+            (LogicalKey::ToggleStats, KeyState::Down) => self.stats.visible = !self.stats.visible,
+            (LogicalKey::ToggleWireframe, KeyState::Down) => {
+                self.wireframe = !self.wireframe;
+                self.wireframe_changed = true;
+            }
+            (LogicalKey::ToggleDebugLines, KeyState::Down) => {
+                self.debug_lines_visible = !self.debug_lines_visible
+            }
+            (LogicalKey::ToggleCursor, KeyState::Down) => {
+                self.set_cursor_grabbed(!self.cursor_grabbed);
+                self.cursor_grab_changed = true;
+            }
+            (LogicalKey::ToggleDebugGui, KeyState::Down) => {
+                self.debug_gui.visible = !self.debug_gui.visible;
+                // The GUI is used with the cursor, so free it up straight away
+                if self.debug_gui.visible && self.cursor_grabbed {
+                    self.set_cursor_grabbed(false);
+                    self.cursor_grab_changed = true;
+                }
+            }
+            (LogicalKey::ExposureDown, KeyState::Down) if calibration.visible => {
+                calibration.adjust_exposure(-0.05)
+            }
+            (LogicalKey::ExposureUp, KeyState::Down) if calibration.visible => {
+                calibration.adjust_exposure(0.05)
+            }
+            (LogicalKey::BrightnessDown, KeyState::Down) if calibration.visible => {
+                calibration.adjust_brightness(-0.002)
+            }
+            (LogicalKey::BrightnessUp, KeyState::Down) if calibration.visible => {
+                calibration.adjust_brightness(0.002)
+            }
+            (LogicalKey::GammaDown, KeyState::Down) if calibration.visible => {
+                calibration.adjust_gamma(-0.05)
+            }
+            (LogicalKey::GammaUp, KeyState::Down) if calibration.visible => {
+                calibration.adjust_gamma(0.05)
+            }
+            (LogicalKey::ZoomIn, KeyState::Down) => self.zoom(1.0),
+            (LogicalKey::ZoomOut, KeyState::Down) => self.zoom(-1.0),
+            (LogicalKey::MoveForward, _)
+            | (LogicalKey::StrafeLeft, _)
+            | (LogicalKey::MoveBackward, _)
+            | (LogicalKey::StrafeRight, _)
+            | (LogicalKey::MoveUp, _)
+            | (LogicalKey::MoveDown, _) => self.handle_movement_key_event(key, new_state),
+            _ => (),
+        }
+    }
+
+    fn handle_movement_key_event(&mut self, key: LogicalKey, new_state: KeyState) {
         let multiplier: f32 = match new_state {
-            KeyState::Down => 10.0,
-            KeyState::Up => -10.0,
+            KeyState::Down => 1.0,
+            KeyState::Up => -1.0,
         };
 
         let base_vel: Vector3<f32> = match key {
@@ -124,57 +1003,393 @@ impl App {
             LogicalKey::StrafeRight => [1.0, 0.0, 0.0],
             LogicalKey::MoveUp => [0.0, 0.0, 1.0],
             LogicalKey::MoveDown => [0.0, 0.0, -1.0],
+            _ => return,
         }
         .into();
 
         self.camera_velocity += multiplier * base_vel;
     }
 
-    // Generates the world space camera velocity from the camera space first person velocity.
-    fn world_camera_vel(&self) -> Vector3<f32> {
-        let strafe_dir = self
-            .main_camera
-            .direction
-            .cross([0.0, 0.0, 1.0].into())
-            .normalize();
-        let strafe: Vector3<f32> = strafe_dir * self.camera_velocity.x;
-        let forward: Vector3<f32> = self.camera_velocity.y * self.main_camera.direction;
-        let up: Vector3<f32> = self.camera_velocity.z * Vector3::new(0.0, 0.0, 1.0);
-        strafe + forward + up
+    /// The camera relative velocity from both the movement keys and sticks
+    fn camera_relative_vel(&self) -> Vector3<f32> {
+        // Sticks move at the same top speed as the movement keys
+        self.camera_speed * (self.camera_velocity + self.analog_movement.extend(0.0))
     }
 
     /// Allow the given amount of time to pass
+    ///
+    /// Should be called with a fixed `dt`, so that the simulation runs the same however fast
+    /// frames are drawn.
     pub fn tick(&mut self, dt: Duration) {
+        let tick_start = Instant::now();
+        self.previous_camera = self.main_camera.clone();
+        self.input_manager.poll_gamepads();
+        while let Some(logical_event) = self.input_manager.poll_logical_event() {
+            self.handle_logical_event(logical_event);
+        }
+
         let dt = dt.as_secs_f32();
-        self.object.rotate(Deg(100.0) * dt, [0.0, 0.0, 1.0].into());
-        self.main_camera.location += self.world_camera_vel() * dt;
+
+        // Stick positions are rates rather than deltas, so have to be integrated every tick
+        const ANALOG_PAN_SPEED: f32 = 2.5;
+        self.main_camera.pan(
+            Rad(self.analog_pan.x * ANALOG_PAN_SPEED * dt),
+            Rad(self.analog_pan.y * ANALOG_PAN_SPEED * dt),
+        );
+
+        if let Some(animation) = &mut self.fov_animation {
+            self.main_camera.set_vertical_fov(animation.tick(dt));
+            if animation.is_finished() {
+                self.fov_animation = None;
+            }
+        }
+
+        self.pick_flash = (self.pick_flash - dt).max(0.0);
+        if self.pick_flash > 0.0 {
+            // Brightened and glowing, fading back to normal over the flash
+            let t = self.pick_flash / PICK_FLASH_DURATION;
+            let tint = 1.0 + t;
+            self.world.styles.insert(self.object, InstanceStyle {
+                tint: Vector4::new(tint, tint, tint, 1.0),
+                material_scale: Vector4::new(1.0 + 4.0 * t, 1.0, 1.0, 1.0),
+            });
+        } else {
+            self.world.styles.remove(self.object);
+        }
+
+        self.loading_animation.tick(dt);
+        self.world.tick(dt);
+        self.main_camera.apply_velocity(self.camera_relative_vel(), dt);
+        if let CameraController::Fly(camera) = &mut self.main_camera {
+            if self.camera_collision {
+                let sphere = Sphere {
+                    center: camera.location,
+                    radius: CAMERA_COLLISION_RADIUS,
+                };
+                camera.location = self.world.push_out(sphere).center;
+            }
+        }
+        self.overlapping = self.world.overlapping_entities();
+
+        let aspect_ratio = self.screen_size.width as f32 / self.screen_size.height.max(1) as f32;
+        self.world.select_lods(self.main_camera.view(), self.main_camera.proj(aspect_ratio));
+
+        self.update_debug_gui();
+        self.update_ui_interaction();
+        self.stats.record_tick(tick_start.elapsed());
+    }
+
+    /// Register this tick's clickable sprites, and act on what the cursor has done to them
+    fn update_ui_interaction(&mut self) {
+        self.ui_interaction.begin_regions();
+        self.ui_interaction.add_ui_sprite(REAR_VIEW_REGION, self.rear_view_sprite());
+        self.ui_interaction.end_regions();
+
+        // Hovering is picked up by `rear_view_sprite` as it's drawn, so only clicks matter here
+        while let Some(event) = self.ui_interaction.poll_event() {
+            if event == UiEvent::Clicked(REAR_VIEW_REGION) {
+                self.rear_view_enlarged = !self.rear_view_enlarged;
+            }
+        }
     }
 
-    pub fn generate_frame_packet(&self, aspect_ratio: f32) -> FramePacket {
-        let view = self.main_camera.view();
-        let proj = self.main_camera.proj(aspect_ratio);
+    /// The rear view as it's shown in the overlay, at the top middle of the screen like a rear
+    /// view mirror
+    fn rear_view_sprite(&self) -> UiSprite {
+        let scale = if self.rear_view_enlarged { 2.0 } else { 1.0 };
+        // Brightened while the cursor is over it, to show that it can be clicked
+        let hovered = self.ui_interaction.hovered() == Some(REAR_VIEW_REGION);
+        let brightness = if hovered { 1.3 } else { 1.0 };
+        UiSprite {
+            anchor: Anchor::Top,
+            offset: [0.0, 16.0].into(),
+            size: [320.0 * scale, 180.0 * scale].into(),
+            atlas_pos: [0.0, 0.0].into(),
+            atlas_size: [1.0, 1.0].into(),
+            rotation: 0.0,
+            tint: [brightness, brightness, brightness, 1.0].into(),
+            nine_slice: None,
+            layer: 0,
+        }
+    }
+
+    /// Lay out the debug GUI for this tick, applying any changes made through it
+    fn update_debug_gui(&mut self) {
+        if !self.debug_gui.visible {
+            self.debug_gui_output = DebugGuiOutput::default();
+            if self.scene_path_focused {
+                self.set_scene_path_focused(false);
+            }
+            return;
+        }
+
+        let mut gui = self.debug_gui.frame();
+        gui.heading("Camera");
+        gui.slider("Speed", &mut self.camera_speed, 1.0..=50.0);
+        gui.checkbox("Split screen", &mut self.split_screen);
+        gui.checkbox("Collide with scene", &mut self.camera_collision);
+
+        gui.heading("Lights");
+        gui.slider("Sun intensity", &mut self.sun_intensity, 0.0..=4.0);
+        gui.slider("Fog density", &mut self.fog_density, 0.0..=0.2);
+        gui.slider("Point light", &mut self.point_light_power, 0.0..=20.0);
+        gui.slider("Spot light", &mut self.spot_light_power, 0.0..=20.0);
+
+        gui.heading("Renderer");
+        if gui.checkbox("Wireframe", &mut self.wireframe) {
+            self.wireframe_changed = true;
+        }
+        gui.checkbox("Debug lines", &mut self.debug_lines_visible);
+        let mut vsync = self.present_mode != PresentMode::Immediate;
+        if gui.checkbox("VSync", &mut vsync) {
+            self.present_mode = self.present_mode.vsync_toggled();
+            self.present_mode_changed = true;
+        }
+        let calibration = &mut self.calibration_screen.calibration;
+        gui.slider("Exposure", &mut calibration.exposure, 0.1..=4.0);
+        gui.checkbox("Depth of field", &mut self.depth_of_field);
+        gui.slider("Aperture", &mut self.dof_aperture, 0.0..=0.05);
+
+        gui.heading("Accessibility");
+        let accessibility = &mut self.accessibility;
+        let mut changed =
+            gui.cycle("Color filter", &mut accessibility.color_filter, &COLOR_FILTERS);
+        changed |= gui.checkbox("High contrast UI", &mut accessibility.high_contrast_ui);
+        self.accessibility_changed |= changed;
+
+        gui.heading("Assets");
+        let clicked = gui.text_field(
+            "Load scene (Enter to load)",
+            &self.scene_path_field,
+            self.scene_path_focused,
+        );
+
+        self.debug_gui_output = gui.finish();
+        if clicked {
+            self.set_scene_path_focused(true);
+        }
+    }
+
+    /// Everything to draw for a frame `alpha` of the way from the last tick to the next one,
+    /// which is interpolated from the last two ticks
+    pub fn generate_frame_packet(&self, aspect_ratio: f32, alpha: f32) -> FramePacket {
+        let camera = self.main_camera.interpolated(&self.previous_camera, alpha);
+        let view = camera.view();
+        let proj = camera.proj(aspect_ratio);
+
+        // Just up and to the right of the middle of the screen
+        let mut overlay_sprites = vec![FramePacketSprites {
+            atlas_id: self.ui_atlas,
+            sprites: Vec::new(),
+            ui_sprites: vec![UiSprite {
+                anchor: Anchor::Center,
+                offset: [43.0, -43.0].into(),
+                size: [86.0, 86.0].into(),
+                atlas_pos: [0.0, 0.0].into(),
+                atlas_size: [1.0, 1.0].into(),
+                rotation: 0.0,
+                tint: [1.0, 1.0, 1.0, 1.0].into(),
+                nine_slice: None,
+                layer: 0,
+            }],
+        }];
+
+        overlay_sprites.push(FramePacketSprites {
+            atlas_id: self.rear_view,
+            sprites: Vec::new(),
+            ui_sprites: vec![self.rear_view_sprite()],
+        });
+        if self.world.scenes.get(self.object).is_none() {
+            let (atlas_pos, atlas_size) = self.loading_animation.atlas_rect();
+            overlay_sprites.push(FramePacketSprites {
+                atlas_id: self.loading_atlas,
+                sprites: Vec::new(),
+                ui_sprites: vec![UiSprite {
+                    anchor: Anchor::BottomRight,
+                    offset: [-16.0, -16.0].into(),
+                    size: [48.0, 48.0].into(),
+                    atlas_pos,
+                    atlas_size,
+                    rotation: 0.0,
+                    tint: [1.0, 1.0, 1.0, 1.0].into(),
+                    nine_slice: None,
+                    layer: 0,
+                }],
+            });
+        }
+        overlay_sprites.extend(self.calibration_screen.sprites(aspect_ratio));
+
+        let mut status_text = format!("Quality: {:?}", self.quality);
+        if self.stats.visible {
+            status_text.push('\n');
+            status_text.push_str(&self.stats.text());
+        }
+        let mut overlay_text = vec![TextRun {
+            text: status_text,
+            screen_pos: Vector2::new(-1.0, 1.0) + self.logical_to_clip_size([16.0, -16.0].into()),
+            size: 20.0 * self.scale_factor as f32,
+            color: [1.0, 1.0, 1.0, 0.9].into(),
+        }];
+        overlay_text.extend(
+            self.debug_gui_output.text_runs(self.screen_size, self.scale_factor as f32),
+        );
+
+        let models = self.world.frame_packet_models(view, alpha);
+
+        // Turned around to look behind the main camera
+        let rear_view = Matrix4::from_angle_y(Deg(180.0)) * view;
+        let views = vec![FramePacketView {
+            target: self.rear_view,
+            view: rear_view,
+            proj: camera.proj(REAR_VIEW_SIZE[0] as f32 / REAR_VIEW_SIZE[1] as f32),
+            models: self.world.frame_packet_models(rear_view, alpha),
+        }];
+
+        let lights = vec![
+            PointLight {
+                position: [1.0, 4.0, 3.0].into(),
+                color: [1.0, 1.0, 1.0].into(),
+                power: self.point_light_power,
+            }
+            .into(),
+            SpotLight {
+                position: [-3.0, -3.0, 4.0].into(),
+                direction: [3.0, 3.0, -4.0].into(),
+                color: [0.4, 0.6, 1.0].into(),
+                power: self.spot_light_power,
+                inner_angle: Deg(10.0).into(),
+                outer_angle: Deg(20.0).into(),
+            }
+            .into(),
+        ];
+
+        let mut debug_lines = if self.debug_lines_visible {
+            Self::debug_lines(self.object_transform(alpha).matrix(), &lights)
+        } else {
+            Vec::new()
+        };
+
+        // The renderer outlines the selected part, its bounds are what it was picked by
+        let selection = self.world.selections.get(self.object);
+        let selected_part = selection.and_then(|selected| selected.part);
+        if let Some(part) = selected_part.filter(|_| self.debug_lines_visible) {
+            let parts = self.object_parts(alpha);
+            if let Some((part, matrix)) = parts.get(part) {
+                let bounds = part.pick_mesh.bounds.transformed(*matrix);
+                let color = [1.0, 0.8, 0.0, 1.0].into();
+                debug_lines.extend(DebugLine::aabb(bounds.min, bounds.max, color));
+            }
+        }
+        if self.debug_lines_visible {
+            // Every part of each entity that overlaps another
+            let overlapping = |&(entity, _): &(Entity, _)| {
+                self.overlapping.iter().any(|&(a, b)| a == entity || b == entity)
+            };
+            for (_, bounds) in self.world.part_bounds().filter(overlapping) {
+                let color = [1.0, 0.1, 0.1, 1.0].into();
+                debug_lines.extend(DebugLine::aabb(bounds.min, bounds.max, color));
+            }
+        }
 
         FramePacket {
             view,
             proj,
-            models: vec![FramePacketModel {
-                model_id: self.object.model,
-                instances: vec![InstanceData {
-                    model_matrix: self.object.model_matrix(),
-                    normal_matrix: self.object.normal_matrix(view),
-                }],
-            }],
-            overlay_sprites: vec![FramePacketSprites {
-                atlas_id: self.ui_atlas,
-                sprites: vec![
-                    SpriteInstanceData {
-                        screen_pos: [0.09, 0.16].into(),
-                        screen_size: [-0.09, -0.16].into(),
-                        atlas_pos: [0.0, 0.0].into(),
-                        atlas_size: [1.0, 1.0].into(),
-                    }
-                ]
-            }]
+            models,
+            lights,
+            directional_light: Some(DirectionalLight {
+                direction: [-0.4, -0.2, -1.0].into(),
+                color: [1.0, 0.95, 0.85].into(),
+                intensity: self.sun_intensity,
+            }),
+            fog: (self.fog_density > 0.0).then_some(Fog {
+                color: [0.6, 0.65, 0.7].into(),
+                mode: FogMode::ExponentialSquared {
+                    density: self.fog_density,
+                },
+            }),
+            clip_plane: None,
+            depth_of_field: self.depth_of_field.then(|| {
+                let focus_point = self.focus_point.unwrap_or(self.object_transform(alpha).position);
+                DepthOfField {
+                    // Distance along the view direction, rather than straight line distance
+                    focus_distance: -(view * focus_point.to_homogeneous()).z,
+                    aperture: self.dof_aperture,
+                    ..DepthOfField::default()
+                }
+            }),
+            decals: Vec::new(),
+            overlay_sprites,
+            overlay_text,
+            debug_lines,
+            debug_gui: self.debug_gui_output.rects.clone(),
+            ui_scale: self.scale_factor as f32,
+            skybox: Some(self.skybox),
+            terrain: None,
+            water: None,
+            views,
+            split_views: self.split_views(&camera, aspect_ratio, alpha),
+        }
+    }
+
+    /// The main camera on the left half of the screen, and the object seen from above and behind
+    /// on the right half, if split screen is enabled
+    fn split_views(
+        &self,
+        camera: &CameraController,
+        aspect_ratio: f32,
+        alpha: f32,
+    ) -> Vec<FramePacketSplitView> {
+        if !self.split_screen {
+            return Vec::new();
+        }
+
+        let columns = ViewportRect::columns(2);
+        let target = self.object_transform(alpha).position;
+        let location = target + Vector3::new(-3.0, -3.0, 4.0);
+        let overhead = Camera {
+            location,
+            direction: (target - location).normalize(),
+            far_clip: self.quality.draw_distance(),
+            reverse_z: camera.reverse_z(),
+            ..Camera::default()
+        };
+
+        let views = [
+            (columns[0], camera.view(), camera.proj(columns[0].aspect_ratio(aspect_ratio))),
+            (columns[1], overhead.view(), overhead.proj(columns[1].aspect_ratio(aspect_ratio))),
+        ];
+        views
+            .iter()
+            .map(|&(viewport, view, proj)| FramePacketSplitView {
+                viewport,
+                view,
+                proj,
+                models: self.world.frame_packet_models(view, alpha),
+            })
+            .collect()
+    }
+
+    /// Axes at the origin and on the object, and a marker on each light
+    fn debug_lines(object_matrix: Matrix4<f32>, lights: &[Light]) -> Vec<DebugLine> {
+        let mut lines = DebugLine::axes(Matrix4::identity(), 1.0);
+        lines.extend(DebugLine::axes(object_matrix, 0.5));
+
+        let marker = |position: Point3<f32>, color: Vector3<f32>| {
+            let half_size = Vector3::new(0.1, 0.1, 0.1);
+            DebugLine::aabb(position - half_size, position + half_size, color.extend(1.0))
+        };
+        for light in lights {
+            match light {
+                Light::Point(light) => lines.extend(marker(light.position, light.color)),
+                Light::Spot(light) => {
+                    lines.extend(marker(light.position, light.color));
+                    let end = light.position + light.direction.normalize();
+                    lines.push(DebugLine::new(light.position, end, light.color.extend(1.0)));
+                }
+                Light::Directional(_) => (),
+            }
         }
+
+        lines
     }
 }
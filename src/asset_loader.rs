@@ -0,0 +1,152 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Instant;
+
+use tracing::{debug, warn, Instrument};
+
+use crate::{
+    error::{Error, Result},
+    ktx2,
+    renderer::{AtlasId, Renderer, SceneModel},
+    scene_data::SceneData,
+};
+
+/// Stands in for a scene requested from an `AssetLoader` until it has loaded and its models are
+/// known
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SceneHandle(usize);
+
+/// A scene that has finished loading and been uploaded to the renderer
+pub struct LoadedScene {
+    pub handle: SceneHandle,
+    pub models: Vec<SceneModel>,
+
+    /// What's left of the scene once its meshes are uploaded, for animating `models`
+    pub scene: SceneData,
+}
+
+/// The CPU side result of a single load job
+enum LoadResult {
+    Scene(SceneHandle, Result<SceneData>),
+    Atlas(AtlasId, Result<image::RgbaImage>),
+}
+
+/// Loads assets from disk on tokio tasks, so that the app can keep drawing while they load
+///
+/// Every load hands back a handle straight away. Finished jobs queue up until `poll` uploads
+/// them to the renderer, which is expected to happen once a frame.
+pub struct AssetLoader {
+    sender: mpsc::Sender<LoadResult>,
+    receiver: mpsc::Receiver<LoadResult>,
+    next_scene_handle: SceneHandle,
+
+    /// Scenes whose failure to load is only logged, rather than returned from `poll`
+    optional_scenes: HashSet<SceneHandle>,
+}
+
+impl Default for AssetLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AssetLoader {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            sender,
+            receiver,
+            next_scene_handle: SceneHandle(0),
+            optional_scenes: HashSet::new(),
+        }
+    }
+
+    /// Run a load job on a tokio task, inside a span naming the file it loads so that anything
+    /// logged while loading says which asset it's about
+    fn spawn<F, Fut>(&self, path: PathBuf, job: F)
+    where
+        F: FnOnce(PathBuf) -> Fut,
+        Fut: std::future::Future<Output = LoadResult> + Send + 'static,
+    {
+        let sender = self.sender.clone();
+        let span = tracing::info_span!("load", path = %path.display());
+        let job = job(path);
+        tokio::spawn(
+            async move {
+                let start = Instant::now();
+                let result = job.await;
+                debug!("Finished in {:.2?}", start.elapsed());
+
+                // Only fails if the loader has been dropped, in which case nobody wants the result
+                let _ = sender.send(result);
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Start loading a GLTF or OBJ scene, whose models are reported by `poll` once it's uploaded
+    pub fn load_scene(&mut self, path: impl Into<PathBuf>) -> SceneHandle {
+        let handle = self.next_scene_handle;
+        self.next_scene_handle = SceneHandle(handle.0 + 1);
+
+        self.spawn(path.into(), move |path| async move {
+            LoadResult::Scene(handle, SceneData::load(path).await)
+        });
+        handle
+    }
+
+    /// Like `load_scene`, but the app carries on without the scene if it fails to load, eg. for
+    /// files the user picks at runtime
+    pub fn load_optional_scene(&mut self, path: impl Into<PathBuf>) -> SceneHandle {
+        let handle = self.load_scene(path);
+        self.optional_scenes.insert(handle);
+        handle
+    }
+
+    /// Start loading an image file as a sprite atlas
+    ///
+    /// The returned atlas can be drawn with immediately, its sprites are skipped until it loads.
+    pub fn load_atlas(&mut self, renderer: &mut Renderer, path: impl Into<PathBuf>) -> AtlasId {
+        let atlas_id = renderer.reserve_atlas_id();
+
+        self.spawn(path.into(), move |path| async move {
+            LoadResult::Atlas(atlas_id, load_image(path).await)
+        });
+        atlas_id
+    }
+
+    /// Upload everything that has finished loading since the last poll, returning the scenes
+    /// that are now ready to draw
+    pub fn poll(&mut self, renderer: &mut Renderer) -> Result<Vec<LoadedScene>> {
+        let mut loaded_scenes = Vec::new();
+        while let Ok(result) = self.receiver.try_recv() {
+            match result {
+                LoadResult::Scene(handle, scene) => {
+                    let optional = self.optional_scenes.remove(&handle);
+                    let uploaded = scene.and_then(|mut scene| {
+                        let models = renderer.upload_scene(&mut scene)?;
+                        Ok(LoadedScene {
+                            handle,
+                            models,
+                            scene,
+                        })
+                    });
+                    match uploaded {
+                        Ok(loaded) => loaded_scenes.push(loaded),
+                        Err(e) if optional => warn!("Failed to load scene: {}", e),
+                        Err(e) => return Err(e),
+                    }
+                }
+                LoadResult::Atlas(atlas_id, image) => renderer.fill_atlas(atlas_id, image?)?,
+            }
+        }
+
+        Ok(loaded_scenes)
+    }
+}
+
+async fn load_image(path: PathBuf) -> Result<image::RgbaImage> {
+    let data = tokio::fs::read(&path).await.map_err(Error::io(&path))?;
+    ktx2::decode_image(&data)
+}
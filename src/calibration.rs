@@ -0,0 +1,163 @@
+use crate::renderer::{
+    frame_packet::{FramePacketSprites, SpriteInstanceData},
+    AtlasId, OutputCalibration,
+};
+
+/// Width/height in texels of a single patch in the calibration atlas
+const CELL_SIZE: u32 = 8;
+
+/// Output values of the near-black patches, out of 255
+const BLACK_LEVELS: [u8; 8] = [0, 2, 4, 6, 8, 10, 12, 16];
+
+/// Output values of the near-white patches, out of 255
+const WHITE_LEVELS: [u8; 8] = [239, 243, 245, 247, 249, 251, 253, 255];
+
+/// A solid patch that matches the average brightness of the striped patch on a correctly
+/// calibrated 2.2 gamma display, ie. 0.5^(1 / 2.2)
+const MID_GREY: u8 = 186;
+
+// Atlas cell indices of the non-level patches
+const STRIPES_CELL: u32 = (BLACK_LEVELS.len() + WHITE_LEVELS.len()) as u32;
+const MID_GREY_CELL: u32 = STRIPES_CELL + 1;
+const CELL_COUNT: u32 = MID_GREY_CELL + 1;
+
+/// Sprite layer of the calibration screen, above the rest of the UI it covers
+const CALIBRATION_LAYER: i32 = 100;
+
+/// Generates the atlas of test patches shown on the calibration screen
+///
+/// Atlas textures are decoded from sRGB when sampled and the overlay encodes back to sRGB when
+/// writing, so the stored values come out on screen unchanged.
+pub fn pattern_image() -> image::RgbaImage {
+    let mut image = image::RgbaImage::new(CELL_SIZE * CELL_COUNT, CELL_SIZE);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let cell = x / CELL_SIZE;
+        let output = if cell < BLACK_LEVELS.len() as u32 {
+            BLACK_LEVELS[cell as usize]
+        } else if cell < STRIPES_CELL {
+            WHITE_LEVELS[cell as usize - BLACK_LEVELS.len()]
+        } else if cell == STRIPES_CELL {
+            // Alternating full black/white rows, which average to 50% linear brightness
+            if y % 2 == 0 {
+                255
+            } else {
+                0
+            }
+        } else {
+            MID_GREY
+        };
+
+        *pixel = image::Rgba([output, output, output, 255]);
+    }
+
+    image
+}
+
+/// A full screen set of test patterns for adjusting the output calibration by eye.
+///
+/// - Brightness should be set so that the second near-black patch is only just visible
+/// - Exposure should be set so that the second to last near-white patch is only just
+///   distinguishable from the last one
+/// - Gamma should be set so that the striped patch matches the solid grey one when viewed from a
+///   distance
+pub struct CalibrationScreen {
+    atlas: AtlasId,
+    pub visible: bool,
+    pub calibration: OutputCalibration,
+}
+
+impl CalibrationScreen {
+    pub fn new(atlas: AtlasId) -> Self {
+        Self {
+            atlas,
+            visible: false,
+            calibration: OutputCalibration::default(),
+        }
+    }
+
+    pub fn adjust_exposure(&mut self, delta: f32) {
+        self.calibration.exposure = (self.calibration.exposure + delta).clamp(0.1, 4.0);
+    }
+
+    pub fn adjust_brightness(&mut self, delta: f32) {
+        self.calibration.brightness = (self.calibration.brightness + delta).clamp(-0.1, 0.1);
+    }
+
+    pub fn adjust_gamma(&mut self, delta: f32) {
+        self.calibration.gamma = (self.calibration.gamma + delta).clamp(1.0, 3.0);
+    }
+
+    /// Atlas coordinates for the given cell, inset by half a texel so that the linear filtering
+    /// doesn't pull in the neighbouring patches
+    fn cell_atlas_rect(cell: u32) -> (cgmath::Vector2<f32>, cgmath::Vector2<f32>) {
+        let atlas_width = (CELL_SIZE * CELL_COUNT) as f32;
+        let half_texel_x = 0.5 / atlas_width;
+        let half_texel_y = 0.5 / CELL_SIZE as f32;
+        let pos = [(cell * CELL_SIZE) as f32 / atlas_width + half_texel_x, half_texel_y];
+        let size = [
+            CELL_SIZE as f32 / atlas_width - 2.0 * half_texel_x,
+            1.0 - 2.0 * half_texel_y,
+        ];
+        (pos.into(), size.into())
+    }
+
+    /// Generate the sprites for the calibration screen, or nothing if it isn't visible
+    pub fn sprites(&self, aspect_ratio: f32) -> Option<FramePacketSprites> {
+        if !self.visible {
+            return None;
+        }
+
+        // Square patches, laid out in clip space
+        let patch_width = 0.16;
+        let patch_height = patch_width * aspect_ratio;
+        let gap = 0.02;
+        let patch = |cell: u32, x: f32, y: f32, scale: f32| {
+            let (atlas_pos, atlas_size) = Self::cell_atlas_rect(cell);
+            SpriteInstanceData {
+                screen_pos: [x, y].into(),
+                screen_size: [patch_width * scale, patch_height * scale].into(),
+                atlas_pos,
+                atlas_size,
+                rotation: 0.0,
+                tint: [1.0, 1.0, 1.0, 1.0].into(),
+                slice_atlas: [0.0; 4].into(),
+                slice_screen: [0.0; 4].into(),
+                layer: CALIBRATION_LAYER,
+            }
+        };
+
+        let row_width = BLACK_LEVELS.len() as f32 * (patch_width + gap) - gap;
+        let left = -row_width / 2.0;
+
+        // Black backdrop over the whole of the scene
+        let (atlas_pos, atlas_size) = Self::cell_atlas_rect(0);
+        let mut sprites = vec![SpriteInstanceData {
+            screen_pos: [-1.0, -1.0].into(),
+            screen_size: [2.0, 2.0].into(),
+            atlas_pos,
+            atlas_size,
+            rotation: 0.0,
+            tint: [1.0, 1.0, 1.0, 1.0].into(),
+            slice_atlas: [0.0; 4].into(),
+            slice_screen: [0.0; 4].into(),
+            layer: CALIBRATION_LAYER,
+        }];
+
+        for i in 0..BLACK_LEVELS.len() {
+            let x = left + i as f32 * (patch_width + gap);
+            sprites.push(patch(i as u32, x, 0.5, 1.0));
+            sprites.push(patch((BLACK_LEVELS.len() + i) as u32, x, 0.1, 1.0));
+        }
+
+        // The stripes are sampled with linear filtering, so draw them large to keep most of each
+        // stripe crisp
+        sprites.push(patch(STRIPES_CELL, -2.0 * patch_width, -0.8, 1.5));
+        sprites.push(patch(MID_GREY_CELL, 0.5 * patch_width, -0.8, 1.5));
+
+        Some(FramePacketSprites {
+            atlas_id: self.atlas,
+            sprites,
+            ui_sprites: Vec::new(),
+        })
+    }
+}
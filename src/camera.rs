@@ -1,5 +1,50 @@
-use cgmath::{Deg, InnerSpace, Matrix3, Matrix4, Point3, Rad, Vector3};
+use cgmath::{Angle, Deg, InnerSpace, Matrix3, Matrix4, Point3, Rad, Vector3, Vector4};
+use thiserror::Error;
 
+/// Bounds for every camera's vertical field of view, 20 and 110 degrees
+pub const MIN_FOV: Rad<f32> = Rad(std::f32::consts::PI / 9.0);
+pub const MAX_FOV: Rad<f32> = Rad(std::f32::consts::PI * 11.0 / 18.0);
+
+/// Clip planes that a perspective projection can't be built from
+#[derive(Debug, Error, PartialEq)]
+#[error("Invalid clip planes: near ({near}) must be positive and less than far ({far})")]
+pub struct InvalidClipPlanes {
+    pub near: f32,
+    pub far: f32,
+}
+
+fn clamp_fov(fov: Rad<f32>) -> Rad<f32> {
+    Rad(fov.0.clamp(MIN_FOV.0, MAX_FOV.0))
+}
+
+fn check_clip_planes(near: f32, far: f32) -> Result<(), InvalidClipPlanes> {
+    if near > 0.0 && near < far {
+        Ok(())
+    } else {
+        Err(InvalidClipPlanes { near, far })
+    }
+}
+
+/// A perspective projection with depth reversed, so the near plane is at 1, and the far plane
+/// pushed out to infinity at 0
+///
+/// Floating point depth is most precise near 0, which reversing spends on the distance where a
+/// standard projection has the least precision, so there's no far plane to trade it off against.
+pub fn reversed_infinite_perspective<A: Into<Rad<f32>>>(
+    fovy: A,
+    aspect: f32,
+    near: f32,
+) -> Matrix4<f32> {
+    let f = Rad::cot(fovy.into() / 2.0);
+    Matrix4::from_cols(
+        Vector4::new(f / aspect, 0.0, 0.0, 0.0),
+        Vector4::new(0.0, f, 0.0, 0.0),
+        Vector4::new(0.0, 0.0, 0.0, -1.0),
+        Vector4::new(0.0, 0.0, near, 0.0),
+    )
+}
+
+#[derive(Clone)]
 pub struct Camera {
     /// Position of this camera in world coordinates
     pub location: Point3<f32>,
@@ -14,6 +59,10 @@ pub struct Camera {
     pub far_clip: f32,
 
     pub vertical_fov: Rad<f32>,
+
+    /// Whether to project with reversed depth and no far plane, which must match the renderer's
+    /// `reverse_z`. `far_clip` is ignored when this is set.
+    pub reverse_z: bool,
 }
 
 impl Default for Camera {
@@ -24,6 +73,7 @@ impl Default for Camera {
             near_clip: 0.1,
             far_clip: 1000.0,
             vertical_fov: Deg(90.0).into(),
+            reverse_z: false,
         }
     }
 }
@@ -36,6 +86,10 @@ impl Camera {
 
     /// Generate a matrix that transforms view space into Vulkan screenspace coordinates
     pub fn proj(&self, aspect_ratio: f32) -> Matrix4<f32> {
+        if self.reverse_z {
+            return reversed_infinite_perspective(self.vertical_fov, aspect_ratio, self.near_clip);
+        }
+
         // OPENGL_SCREENSPACE_TO_VULKAN *
         cgmath::perspective(
             self.vertical_fov,
@@ -45,6 +99,19 @@ impl Camera {
         )
     }
 
+    /// Set the vertical field of view, clamped to between `MIN_FOV` and `MAX_FOV`
+    pub fn set_vertical_fov<A: Into<Rad<f32>>>(&mut self, fov: A) {
+        self.vertical_fov = clamp_fov(fov.into());
+    }
+
+    /// Set both clip planes, leaving them unchanged if the far plane isn't beyond the near one
+    pub fn set_clip_planes(&mut self, near: f32, far: f32) -> Result<(), InvalidClipPlanes> {
+        check_clip_planes(near, far)?;
+        self.near_clip = near;
+        self.far_clip = far;
+        Ok(())
+    }
+
     /// Pan this camera left/right
     pub fn pan_horizonal<A: Into<Rad<f32>>>(&mut self, angle: A) {
         let rot_matrix = Matrix3::from_axis_angle([0.0, 0.0, 1.0].into(), Rad(0.0) - angle.into());
@@ -86,6 +153,175 @@ impl Camera {
     }
 }
 
+/// A camera that circles a target point, always looking straight at it
+#[derive(Clone)]
+pub struct OrbitCamera {
+    /// The point this camera orbits and looks at, in world coordinates
+    pub target: Point3<f32>,
+
+    /// Distance from the target to the camera
+    pub distance: f32,
+
+    /// Angle around the world Z axis of the camera's position relative to the target, where zero
+    /// is along +X
+    pub yaw: Rad<f32>,
+
+    /// Angle of the camera's position above the target's horizontal plane
+    pub pitch: Rad<f32>,
+
+    /// Near clipping plane for the perspective projection
+    pub near_clip: f32,
+
+    /// Far clipping plane for the perspective projection
+    pub far_clip: f32,
+
+    pub vertical_fov: Rad<f32>,
+
+    /// See `Camera::reverse_z`
+    pub reverse_z: bool,
+}
+
+impl OrbitCamera {
+    /// Stops just short of straight up/down, where the view's up vector is undefined
+    const MAX_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+
+    /// Stops the camera from zooming in to, or through, the target
+    const MIN_DISTANCE: f32 = 0.1;
+
+    /// An orbit around the given target that starts out at the given location
+    pub fn looking_at(target: Point3<f32>, location: Point3<f32>) -> Self {
+        let offset = location - target;
+        let distance = offset.magnitude().max(Self::MIN_DISTANCE);
+        let defaults = Camera::default();
+
+        let mut camera = Self {
+            target,
+            distance,
+            yaw: Rad(offset.y.atan2(offset.x)),
+            pitch: Rad((offset.z / distance).asin()),
+            near_clip: defaults.near_clip,
+            far_clip: defaults.far_clip,
+            vertical_fov: defaults.vertical_fov,
+            reverse_z: defaults.reverse_z,
+        };
+        camera.orbit_vertical(Rad(0.0));
+        camera
+    }
+
+    /// Position of this camera in world coordinates
+    pub fn location(&self) -> Point3<f32> {
+        let horizontal = self.pitch.cos();
+        let offset = Vector3::new(
+            horizontal * self.yaw.cos(),
+            horizontal * self.yaw.sin(),
+            self.pitch.sin(),
+        );
+        self.target + self.distance * offset
+    }
+
+    /// A unit vector in the direction this camera is facing
+    pub fn direction(&self) -> Vector3<f32> {
+        (self.target - self.location()).normalize()
+    }
+
+    /// Generate a matrix that transforms world space into this camera's view space
+    pub fn view(&self) -> Matrix4<f32> {
+        Matrix4::look_at(self.location(), self.target, [0.0, 0.0, 1.0].into())
+    }
+
+    /// Generate a matrix that transforms view space into Vulkan screenspace coordinates
+    pub fn proj(&self, aspect_ratio: f32) -> Matrix4<f32> {
+        if self.reverse_z {
+            return reversed_infinite_perspective(self.vertical_fov, aspect_ratio, self.near_clip);
+        }
+
+        cgmath::perspective(
+            self.vertical_fov,
+            aspect_ratio,
+            self.near_clip,
+            self.far_clip,
+        )
+    }
+
+    /// Set the vertical field of view, clamped to between `MIN_FOV` and `MAX_FOV`
+    pub fn set_vertical_fov<A: Into<Rad<f32>>>(&mut self, fov: A) {
+        self.vertical_fov = clamp_fov(fov.into());
+    }
+
+    /// Set both clip planes, leaving them unchanged if the far plane isn't beyond the near one
+    pub fn set_clip_planes(&mut self, near: f32, far: f32) -> Result<(), InvalidClipPlanes> {
+        check_clip_planes(near, far)?;
+        self.near_clip = near;
+        self.far_clip = far;
+        Ok(())
+    }
+
+    /// Move around the target, counter-clockwise when viewed from above for a positive angle
+    pub fn orbit_horizontal<A: Into<Rad<f32>>>(&mut self, angle: A) {
+        self.yaw = (self.yaw + angle.into()).normalize();
+    }
+
+    /// Move over the target, upwards for a positive angle
+    ///
+    /// Clamps the orbit to just short of straight above/below the target.
+    pub fn orbit_vertical<A: Into<Rad<f32>>>(&mut self, angle: A) {
+        let pitch = self.pitch + angle.into();
+        self.pitch = Rad(pitch.0.clamp(-Self::MAX_PITCH, Self::MAX_PITCH));
+    }
+
+    /// Move toward the target for a positive distance, or away from it for a negative one
+    pub fn zoom(&mut self, distance: f32) {
+        self.distance = (self.distance - distance).max(Self::MIN_DISTANCE);
+    }
+}
+
+/// Eases a field of view towards a target over a fixed time, so that zooming doesn't jump
+pub struct FovAnimation {
+    from: Rad<f32>,
+    to: Rad<f32>,
+
+    /// In seconds
+    duration: f32,
+    elapsed: f32,
+}
+
+impl FovAnimation {
+    /// The target is clamped to between `MIN_FOV` and `MAX_FOV`
+    pub fn new(from: Rad<f32>, to: Rad<f32>, duration: f32) -> Self {
+        Self {
+            from,
+            to: clamp_fov(to),
+            duration,
+            elapsed: 0.0,
+        }
+    }
+
+    /// The field of view this will end up at
+    pub fn target(&self) -> Rad<f32> {
+        self.to
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+
+    /// Advance by `dt` seconds, returning the field of view at the new time
+    pub fn tick(&mut self, dt: f32) -> Rad<f32> {
+        self.elapsed = (self.elapsed + dt).min(self.duration);
+        let t = if self.duration > 0.0 {
+            self.elapsed / self.duration
+        } else {
+            1.0
+        };
+        self.from + (self.to - self.from) * ease_out_cubic(t)
+    }
+}
+
+/// Starts fast and slows down towards the end, for 0 <= t <= 1
+fn ease_out_cubic(t: f32) -> f32 {
+    1.0 - (1.0 - t).powi(3)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -141,4 +377,75 @@ mod tests {
         assert_ulps_eq!(camera.direction.magnitude(), 1.0);
         assert_relative_eq!(camera.direction, [0.0, 0.0, -1.0].into(), epsilon = 0.01);
     }
+
+    #[test]
+    fn test_orbit_camera_looking_at() {
+        let target = Point3::new(1.0, 2.0, 3.0);
+        let location = Point3::new(4.0, 6.0, 3.0);
+        let camera = OrbitCamera::looking_at(target, location);
+        assert_relative_eq!(camera.distance, 5.0);
+        assert_relative_eq!(camera.location(), location, epsilon = 1e-5);
+        assert_relative_eq!(camera.direction(), Vector3::new(-0.6, -0.8, 0.0), epsilon = 1e-5);
+    }
+
+    #[test]
+    fn test_orbit_camera_limits() {
+        let mut camera = OrbitCamera::looking_at([0.0, 0.0, 0.0].into(), [1.0, 0.0, 0.0].into());
+
+        camera.orbit_vertical(Deg(180.0));
+        assert_relative_eq!(camera.direction(), [0.0, 0.0, -1.0].into(), epsilon = 0.01);
+        camera.orbit_vertical(Deg(-360.0));
+        assert_relative_eq!(camera.direction(), [0.0, 0.0, 1.0].into(), epsilon = 0.01);
+
+        camera.zoom(10.0);
+        assert!(camera.distance > 0.0);
+    }
+
+    #[test]
+    fn test_camera_setters() {
+        let mut camera = Camera::default();
+
+        camera.set_vertical_fov(Deg(170.0));
+        assert_eq!(camera.vertical_fov, MAX_FOV);
+        camera.set_vertical_fov(Deg(1.0));
+        assert_eq!(camera.vertical_fov, MIN_FOV);
+
+        assert!(camera.set_clip_planes(0.5, 50.0).is_ok());
+        assert_eq!((camera.near_clip, camera.far_clip), (0.5, 50.0));
+        assert_eq!(
+            camera.set_clip_planes(10.0, 5.0),
+            Err(InvalidClipPlanes { near: 10.0, far: 5.0 })
+        );
+        assert!(camera.set_clip_planes(0.0, 5.0).is_err());
+        assert_eq!((camera.near_clip, camera.far_clip), (0.5, 50.0));
+    }
+
+    #[test]
+    fn test_reversed_infinite_perspective() {
+        let proj = reversed_infinite_perspective(Deg(90.0), 1.0, 0.5);
+        let depth = |distance: f32| {
+            let clip = proj * Vector3::new(0.0, 0.0, -distance).extend(1.0);
+            clip.z / clip.w
+        };
+
+        assert_relative_eq!(depth(0.5), 1.0);
+        assert_relative_eq!(depth(1.0), 0.5);
+        assert!(depth(1e6) > 0.0 && depth(1e6) < 1e-6);
+
+        let clip = proj * Vector3::new(1.0, 0.0, -1.0).extend(1.0);
+        assert_relative_eq!(clip.x / clip.w, 1.0);
+    }
+
+    #[test]
+    fn test_fov_animation() {
+        let from = Rad::from(Deg(90.0));
+        let mut animation = FovAnimation::new(from, Deg(60.0).into(), 0.2);
+
+        let halfway = animation.tick(0.1);
+        assert!(halfway < from && halfway > animation.target());
+        assert!(!animation.is_finished());
+
+        assert_relative_eq!(animation.tick(1.0), Rad::from(Deg(60.0)));
+        assert!(animation.is_finished());
+    }
 }
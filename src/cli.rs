@@ -0,0 +1,166 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::display::WindowMode;
+use crate::error::{Error, Result};
+use crate::renderer::{AaMode, BackendPreference, RenderQuality};
+
+pub const USAGE: &str = "\
+Usage: wgpu-test [OPTIONS] [MODEL]...
+
+Views each GLTF MODEL, or the configured object scene if none are given.
+
+Options:
+    --atlas PATH        Image to draw UI sprites from
+    --backend NAME      One of auto, vulkan, metal, dx12, dx11 or gl
+    --windowed          Open in a window
+    --fullscreen        Open in a borderless window covering the monitor
+    --exclusive         Open fullscreen at the configured video mode
+    --msaa SAMPLES      Anti-alias with MSAA, 0 or 1 to disable it, whatever the quality
+    -h, --help          Print this message";
+
+/// Options given on the command line, which take precedence over the config file for one run
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    /// The first replaces the configured object scene, the rest are shown alongside it
+    pub models: Vec<PathBuf>,
+
+    pub atlas: Option<PathBuf>,
+    pub backend: Option<BackendPreference>,
+    pub window_mode: Option<WindowMode>,
+    pub msaa_samples: Option<u32>,
+
+    /// Whether to print `USAGE` rather than run
+    pub help: bool,
+}
+
+impl Args {
+    /// Parse the arguments following the program name
+    ///
+    /// Paths are kept as the OS gave them, so only option names and their other values have to
+    /// be valid Unicode.
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |option: &str| {
+                args.next()
+                    .ok_or_else(|| Error::InvalidArgument(format!("{} expects a value", option)))
+            };
+            let text = |option: &str, value: OsString| {
+                value.into_string().map_err(|value| {
+                    Error::InvalidArgument(format!("Invalid {} value {:?}", option, value))
+                })
+            };
+
+            match arg.to_str() {
+                Some(option @ "--atlas") => parsed.atlas = Some(value(option)?.into()),
+                Some(option @ "--backend") => {
+                    let backend = text(option, value(option)?)?.parse().map_err(|e| {
+                        Error::InvalidArgument(format!("Unknown backend, {}", e))
+                    })?;
+                    parsed.backend = Some(backend);
+                }
+                Some("--windowed") => parsed.window_mode = Some(WindowMode::Windowed),
+                Some("--fullscreen") => parsed.window_mode = Some(WindowMode::Borderless),
+                Some("--exclusive") => parsed.window_mode = Some(WindowMode::Exclusive),
+                Some(option @ "--msaa") => {
+                    let samples = text(option, value(option)?)?;
+                    let samples = samples.parse().map_err(|_| {
+                        Error::InvalidArgument(format!("Invalid MSAA sample count {}", samples))
+                    })?;
+                    parsed.msaa_samples = Some(samples);
+                }
+                Some("-h" | "--help") => parsed.help = true,
+                Some(option) if option.starts_with('-') => {
+                    return Err(Error::InvalidArgument(format!("Unknown option {}", option)))
+                }
+                _ => parsed.models.push(arg.into()),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Override the settings given on the command line
+    ///
+    /// Only the first model is applied, see `extra_models` for the rest.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(model) = self.models.first() {
+            config.assets.object_scene = model.clone();
+        }
+        if let Some(atlas) = &self.atlas {
+            config.assets.ui_atlas = atlas.clone();
+        }
+        if let Some(backend) = self.backend {
+            config.backend = backend;
+        }
+        if let Some(window_mode) = self.window_mode {
+            config.display.window_mode = window_mode;
+        }
+    }
+
+    /// Override the settings of a quality preset given on the command line, which hold for every
+    /// preset switched to during the run
+    pub fn apply_quality(&self, quality: &mut RenderQuality) {
+        if let Some(msaa_samples) = self.msaa_samples {
+            quality.aa_mode = AaMode::Msaa;
+            quality.msaa_samples = msaa_samples;
+        }
+    }
+
+    /// The models to show alongside the object scene
+    pub fn extra_models(&self) -> &[PathBuf] {
+        self.models.get(1..).unwrap_or(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quality::QualityPreset;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&["a.glb", "--backend", "VULKAN", "--msaa", "4", "b.glb", "--fullscreen"])
+            .unwrap();
+        assert_eq!(args.models, vec![PathBuf::from("a.glb"), PathBuf::from("b.glb")]);
+        assert_eq!(args.extra_models(), &[PathBuf::from("b.glb")]);
+        assert_eq!(args.backend, Some(BackendPreference::Vulkan));
+        assert_eq!(args.msaa_samples, Some(4));
+        assert_eq!(args.window_mode, Some(WindowMode::Borderless));
+
+        let mut config = Config::default();
+        args.apply(&mut config);
+        assert_eq!(config.assets.object_scene, PathBuf::from("a.glb"));
+        let mut quality = QualityPreset::Low.render_quality();
+        args.apply_quality(&mut quality);
+        assert_eq!(quality.aa_mode, AaMode::Msaa);
+        assert_eq!(quality.msaa_samples, 4);
+
+        assert_eq!(parse(&[]).unwrap(), Args::default());
+        assert!(parse(&["--msaa"]).is_err());
+        assert!(parse(&["--msaa", "lots"]).is_err());
+        assert!(parse(&["--backend", "glide"]).is_err());
+        assert!(parse(&["--frobnicate"]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_non_unicode_args() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let not_unicode = || OsString::from_vec(b"caf\xe9.glb".to_vec());
+        let args = Args::parse(vec![not_unicode()]).unwrap();
+        assert_eq!(args.models, vec![PathBuf::from(not_unicode())]);
+        let args = Args::parse(vec![OsString::from("--atlas"), not_unicode()]).unwrap();
+        assert_eq!(args.atlas, Some(PathBuf::from(not_unicode())));
+
+        assert!(Args::parse(vec![OsString::from("--msaa"), not_unicode()]).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, SquareMatrix, Vector3};
+
+use crate::picking::{Aabb, PickMesh};
+
+/// Most contacts `push_out` resolves one after another before giving up
+const MAX_PUSH_OUT_STEPS: usize = 4;
+
+/// How far and in which direction to move a shape so that it stops overlapping a triangle
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Contact {
+    /// Unit length
+    pub normal: Vector3<f32>,
+    pub depth: f32,
+}
+
+/// A convex shape that can be tested against triangles
+pub trait Collider: Sized {
+    /// Box around the whole shape
+    fn bounds(&self) -> Aabb;
+
+    /// How to move the shape out of the triangle, if they overlap at all
+    fn contact(&self, triangle: [Point3<f32>; 3]) -> Option<Contact>;
+
+    fn translated(&self, offset: Vector3<f32>) -> Self;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl Collider for Sphere {
+    fn bounds(&self) -> Aabb {
+        let extent = Vector3::new(self.radius, self.radius, self.radius);
+        Aabb {
+            min: self.center - extent,
+            max: self.center + extent,
+        }
+    }
+
+    fn contact(&self, triangle: [Point3<f32>; 3]) -> Option<Contact> {
+        let offset = self.center - closest_point_on_triangle(self.center, triangle);
+        let distance = offset.magnitude();
+        if distance >= self.radius {
+            return None;
+        }
+
+        // With the center right on the triangle, either side is as good as the other
+        let normal = if distance > f32::EPSILON {
+            offset / distance
+        } else {
+            triangle_normal(triangle)?
+        };
+        Some(Contact {
+            normal,
+            depth: self.radius - distance,
+        })
+    }
+
+    fn translated(&self, offset: Vector3<f32>) -> Self {
+        Self {
+            center: self.center + offset,
+            ..*self
+        }
+    }
+}
+
+/// Every point within `radius` of the segment from `start` to `end`
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(unused)]
+pub struct Capsule {
+    pub start: Point3<f32>,
+    pub end: Point3<f32>,
+    pub radius: f32,
+}
+
+impl Collider for Capsule {
+    fn bounds(&self) -> Aabb {
+        let start = Sphere {
+            center: self.start,
+            radius: self.radius,
+        };
+        let end = Sphere {
+            center: self.end,
+            ..start
+        };
+        start.bounds().union(&end.bounds())
+    }
+
+    /// Tests the sphere along the segment nearest the triangle, found from where the segment's
+    /// line crosses the triangle's plane
+    fn contact(&self, triangle: [Point3<f32>; 3]) -> Option<Contact> {
+        let direction = self.end - self.start;
+        let reference = match triangle_normal(triangle) {
+            Some(normal) if normal.dot(direction).abs() > f32::EPSILON => {
+                let t = normal.dot(triangle[0] - self.start) / normal.dot(direction);
+                closest_point_on_triangle(self.start + direction * t, triangle)
+            }
+            // Parallel to the triangle, so start from the middle of it
+            _ => Point3::centroid(&triangle),
+        };
+        let sphere = Sphere {
+            center: closest_point_on_segment(reference, self.start, self.end),
+            radius: self.radius,
+        };
+        sphere.contact(triangle)
+    }
+
+    fn translated(&self, offset: Vector3<f32>) -> Self {
+        Self {
+            start: self.start + offset,
+            end: self.end + offset,
+            ..*self
+        }
+    }
+}
+
+impl Collider for Aabb {
+    fn bounds(&self) -> Aabb {
+        *self
+    }
+
+    /// Separating axis test, with the contact along whichever axis they overlap least on
+    fn contact(&self, triangle: [Point3<f32>; 3]) -> Option<Contact> {
+        let center = self.center().to_vec();
+        let half_extent = (self.max - self.min) / 2.0;
+        let edges = [
+            triangle[1] - triangle[0],
+            triangle[2] - triangle[1],
+            triangle[0] - triangle[2],
+        ];
+        let box_axes = [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()];
+
+        let mut axes = box_axes.to_vec();
+        axes.push(edges[0].cross(edges[1]));
+        for box_axis in box_axes {
+            axes.extend(edges.iter().map(|edge| box_axis.cross(*edge)));
+        }
+
+        let mut nearest: Option<Contact> = None;
+        for axis in axes {
+            let length = axis.magnitude();
+            if length <= f32::EPSILON {
+                // Parallel edges give no axis to separate along
+                continue;
+            }
+            let axis = axis / length;
+
+            let radius = half_extent.x * axis.x.abs()
+                + half_extent.y * axis.y.abs()
+                + half_extent.z * axis.z.abs();
+            let box_min = center.dot(axis) - radius;
+            let box_max = center.dot(axis) + radius;
+            let projected = triangle.map(|corner| corner.to_vec().dot(axis));
+            let triangle_min = projected.iter().copied().fold(f32::INFINITY, f32::min);
+            let triangle_max = projected.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+
+            let (depth, normal) = if box_max - triangle_min < triangle_max - box_min {
+                (box_max - triangle_min, -axis)
+            } else {
+                (triangle_max - box_min, axis)
+            };
+            if depth <= 0.0 {
+                return None;
+            }
+            if nearest.is_none_or(|nearest| depth < nearest.depth) {
+                nearest = Some(Contact { normal, depth });
+            }
+        }
+        nearest
+    }
+
+    fn translated(&self, offset: Vector3<f32>) -> Self {
+        Self {
+            min: self.min + offset,
+            max: self.max + offset,
+        }
+    }
+}
+
+/// Every contact between a world space shape and a mesh, given the mesh's model to world
+/// transform
+pub fn mesh_contacts(
+    collider: &impl Collider,
+    mesh: &PickMesh,
+    transform: Matrix4<f32>,
+) -> Vec<Contact> {
+    let Some(inv_transform) = transform.invert() else {
+        return Vec::new();
+    };
+    let to_world = |point: Point3<f32>| {
+        Point3::from_homogeneous(transform * point.to_homogeneous())
+    };
+    mesh.triangles_near(&collider.bounds().transformed(inv_transform))
+        .filter_map(|triangle| collider.contact(triangle.map(to_world)))
+        .collect()
+}
+
+/// Move a shape out of whatever it overlaps, given every contact it has wherever it's moved to
+///
+/// The deepest contact is resolved first, a few times over, so a shape squeezed between surfaces
+/// may still overlap some of them.
+pub fn push_out<C: Collider>(collider: C, mut contacts: impl FnMut(&C) -> Vec<Contact>) -> C {
+    let mut collider = collider;
+    for _ in 0..MAX_PUSH_OUT_STEPS {
+        let deepest = contacts(&collider)
+            .into_iter()
+            .max_by(|a, b| a.depth.total_cmp(&b.depth));
+        match deepest {
+            Some(contact) => collider = collider.translated(contact.normal * contact.depth),
+            None => break,
+        }
+    }
+    collider
+}
+
+/// Unit normal of the triangle, by the right hand rule, or None if it has no area
+fn triangle_normal([a, b, c]: [Point3<f32>; 3]) -> Option<Vector3<f32>> {
+    let normal = (b - a).cross(c - a);
+    let length = normal.magnitude();
+    if length > f32::EPSILON {
+        Some(normal / length)
+    } else {
+        None
+    }
+}
+
+#[allow(unused)]
+fn closest_point_on_segment(
+    point: Point3<f32>,
+    start: Point3<f32>,
+    end: Point3<f32>,
+) -> Point3<f32> {
+    let direction = end - start;
+    let length2 = direction.magnitude2();
+    if length2 <= f32::EPSILON {
+        return start;
+    }
+    let t = (point - start).dot(direction) / length2;
+    start + direction * t.clamp(0.0, 1.0)
+}
+
+/// By the regions of the triangle's corners and edges, from Real-Time Collision Detection
+fn closest_point_on_triangle(point: Point3<f32>, [a, b, c]: [Point3<f32>; 3]) -> Point3<f32> {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = point - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+
+    let bp = point - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = point - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denom = 1.0 / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2x2 square on the XY plane, facing up
+    fn floor() -> PickMesh {
+        let positions = vec![
+            Point3::new(-1.0, -1.0, 0.0),
+            Point3::new(1.0, -1.0, 0.0),
+            Point3::new(1.0, 1.0, 0.0),
+            Point3::new(-1.0, 1.0, 0.0),
+        ];
+        PickMesh::from_triangles(positions, vec![0, 1, 2, 0, 2, 3])
+    }
+
+    fn assert_contact(contact: Option<Contact>, normal: Vector3<f32>, depth: f32) {
+        let contact = contact.expect("Expected a contact");
+        assert!((contact.normal - normal).magnitude() < 1e-5, "{:?}", contact);
+        assert!((contact.depth - depth).abs() < 1e-5, "{:?}", contact);
+    }
+
+    #[test]
+    fn test_closest_point_on_triangle() {
+        let triangle = [
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(2.0, 0.0, 0.0),
+            Point3::new(0.0, 2.0, 0.0),
+        ];
+        let closest = |x, y, z| closest_point_on_triangle(Point3::new(x, y, z), triangle);
+        assert_eq!(closest(0.5, 0.5, 3.0), Point3::new(0.5, 0.5, 0.0));
+        assert_eq!(closest(-1.0, -1.0, 0.0), Point3::new(0.0, 0.0, 0.0));
+        assert_eq!(closest(1.0, -1.0, 1.0), Point3::new(1.0, 0.0, 0.0));
+        assert_eq!(closest(2.0, 2.0, 0.0), Point3::new(1.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn test_shapes_against_triangle() {
+        let triangle = [
+            Point3::new(-1.0, -1.0, 0.0),
+            Point3::new(1.0, -1.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ];
+        let up = Vector3::unit_z();
+
+        let sphere = Sphere {
+            center: Point3::new(0.0, 0.0, 0.25),
+            radius: 0.5,
+        };
+        assert_contact(sphere.contact(triangle), up, 0.25);
+        assert_eq!(sphere.translated(up).contact(triangle), None);
+
+        // Lying just above the triangle, along the X axis
+        let capsule = Capsule {
+            start: Point3::new(-5.0, 0.0, 0.1),
+            end: Point3::new(5.0, 0.0, 0.1),
+            radius: 0.25,
+        };
+        assert_contact(capsule.contact(triangle), up, 0.15);
+
+        // Standing upright with its bottom end in the triangle
+        let capsule = Capsule {
+            start: Point3::new(0.0, 0.0, -0.1),
+            end: Point3::new(0.0, 0.0, 2.0),
+            radius: 0.25,
+        };
+        assert!(capsule.contact(triangle).is_some());
+
+        let aabb = Aabb {
+            min: Point3::new(-0.5, -0.5, -0.1),
+            max: Point3::new(0.5, 0.5, 1.0),
+        };
+        assert_contact(aabb.contact(triangle), up, 0.1);
+        assert_eq!(aabb.translated(Vector3::new(3.0, 0.0, 0.0)).contact(triangle), None);
+    }
+
+    #[test]
+    fn test_push_out_of_mesh() {
+        let mesh = floor();
+        let transform = Matrix4::from_translation(Vector3::new(0.0, 0.0, 2.0));
+        let sphere = Sphere {
+            center: Point3::new(0.5, -0.5, 1.9),
+            radius: 0.5,
+        };
+        assert_eq!(mesh_contacts(&sphere, &mesh, transform).len(), 1);
+
+        let pushed = push_out(sphere, |sphere| mesh_contacts(sphere, &mesh, transform));
+        assert!((pushed.center - Point3::new(0.5, -0.5, 1.5)).magnitude() < 1e-5);
+        assert!(mesh_contacts(&pushed, &mesh, transform).is_empty());
+    }
+}
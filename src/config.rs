@@ -0,0 +1,233 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::display::DisplayConfig;
+use crate::key_bindings::KeyBindings;
+use crate::logging::LogLevel;
+use crate::quality::QualityPreset;
+use crate::renderer::{
+    BackendPreference, ColorFilter, PresentMode, RenderPath, RenderQuality,
+    RendererConfig, TextureStreamingConfig, Tonemapper, TransparencyMode, DEFAULT_UI_FONT_PATH,
+};
+
+/// Where user settings are persisted between runs
+pub const CONFIG_PATH: &str = "./config.toml";
+
+/// User settings that persist between runs
+///
+/// Missing fields take their default value, so a config file written by an older version still
+/// loads.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Also decides how edges are anti-aliased, the shadow map size and which post effects are
+    /// drawn
+    pub graphics_quality: QualityPreset,
+
+    /// Only takes effect on the next run, the renderer can't switch paths once it's created
+    pub render_path: RenderPath,
+
+    /// Graphics API to draw with, which also needs a restart. The `WGPU_BACKEND` environment
+    /// variable takes precedence over this.
+    pub backend: BackendPreference,
+
+    /// Whether to draw the scene's depth before shading it, which also needs a restart
+    pub depth_prepass: bool,
+
+    /// Whether to wait for vertical blank before presenting each frame, ie. vsync
+    pub present_mode: PresentMode,
+    pub tonemapper: Tonemapper,
+
+    /// The `WGPU_TEST_LOG` environment variable takes precedence over this
+    pub log_level: LogLevel,
+
+    /// Whether to frustum cull models with many instances on the GPU, which also needs a restart
+    pub gpu_culling: bool,
+
+    /// How transparent surfaces are blended, which also needs a restart
+    pub transparency: TransparencyMode,
+
+    /// Whether to reverse the depth buffer, so that distant surfaces don't z-fight, which also
+    /// needs a restart
+    pub reverse_z: bool,
+
+    /// Stream material textures in as they're seen up close, left out to upload them in full.
+    /// This also needs a restart.
+    pub texture_streaming: Option<TextureStreamingConfig>,
+
+    pub display: DisplayConfig,
+    pub controls: ControlsConfig,
+    pub accessibility: AccessibilityConfig,
+
+    /// Only take effect on the next run
+    pub assets: AssetPaths,
+
+    /// Tables have to come after every plain value when serialized to TOML, so this stays last
+    pub key_bindings: KeyBindings,
+}
+
+/// How the camera responds to the user
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControlsConfig {
+    /// The main camera's vertical field of view in degrees, which middle clicking returns to after
+    /// zooming
+    pub field_of_view: f32,
+
+    /// Scales how far the camera turns as the mouse moves, where 1.0 turns it a radian for every
+    /// 1024 pixels
+    pub mouse_sensitivity: f32,
+
+    /// How fast the camera flies, in meters per second
+    pub move_speed: f32,
+
+    /// Whether the fly camera is stopped by the scene's models rather than flying through them
+    pub camera_collision: bool,
+}
+
+impl Default for ControlsConfig {
+    fn default() -> Self {
+        Self {
+            field_of_view: 90.0,
+            mouse_sensitivity: 1.0,
+            move_speed: 10.0,
+            camera_collision: false,
+        }
+    }
+}
+
+/// Adjustments for users who have trouble telling colors or UI elements apart
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessibilityConfig {
+    /// Whether the UI overlay is drawn with solid, high contrast colors
+    pub high_contrast_ui: bool,
+
+    /// Filter applied to the final image, including the UI overlay. This serializes as a table,
+    /// so it has to come after the plain values.
+    pub color_filter: ColorFilter,
+}
+
+/// Files the app loads its assets from, relative to the working directory
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AssetPaths {
+    /// GLTF scene shown in the middle of the world
+    pub object_scene: PathBuf,
+
+    /// Image holding every sprite drawn in the overlay
+    pub ui_atlas: PathBuf,
+
+    /// TrueType font for UI text
+    pub ui_font: PathBuf,
+}
+
+impl Default for AssetPaths {
+    fn default() -> Self {
+        Self {
+            object_scene: PathBuf::from("./AntiqueCamera.glb"),
+            ui_atlas: PathBuf::from("./atlas.png"),
+            ui_font: PathBuf::from(DEFAULT_UI_FONT_PATH),
+        }
+    }
+}
+
+impl Config {
+    /// The settings that the renderer is created with, starting out at the given quality
+    pub fn renderer_config(&self, quality: &RenderQuality) -> RendererConfig {
+        RendererConfig {
+            render_path: self.render_path,
+            aa_mode: quality.aa_mode,
+            msaa_samples: quality.msaa_samples,
+            backend: self.backend,
+            present_mode: self.present_mode,
+            depth_prepass: self.depth_prepass,
+            ssao: quality.ssao,
+            motion_blur: quality.motion_blur,
+            shadow_map_size: quality.shadow_map_size,
+            texture_streaming: self.texture_streaming,
+            gpu_culling: self.gpu_culling,
+            transparency: self.transparency,
+            reverse_z: self.reverse_z,
+            ui_font: self.assets.ui_font.clone(),
+        }
+    }
+
+    /// Load the config from the given path, falling back to the defaults if it doesn't exist or
+    /// can't be parsed
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!("Failed to read config file {}: {}", path.display(), e);
+                return Self::default();
+            }
+        };
+
+        match toml::from_str(&text) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Failed to parse config file {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let text = match toml::to_string(self) {
+            Ok(text) => text,
+            Err(e) => {
+                error!("Failed to serialize config: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = std::fs::write(path, text) {
+            warn!("Failed to write config file {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::WindowMode;
+    use crate::renderer::ColorDeficiency;
+
+    #[test]
+    fn test_config_round_trip() {
+        let config = Config {
+            graphics_quality: QualityPreset::Ultra,
+            ..Config::default()
+        };
+        let text = toml::to_string(&config).unwrap();
+        assert!(text.starts_with("graphics_quality = \"ultra\"\n"));
+        assert_eq!(toml::from_str::<Config>(&text).unwrap(), config);
+        assert_eq!(toml::from_str::<Config>("").unwrap(), Config::default());
+
+        let config = Config {
+            texture_streaming: Some(TextureStreamingConfig::default()),
+            accessibility: AccessibilityConfig {
+                high_contrast_ui: true,
+                color_filter: ColorFilter::Daltonize(ColorDeficiency::Tritanopia),
+            },
+            display: DisplayConfig {
+                window_mode: WindowMode::Exclusive,
+                resolution: Some([2560, 1440]),
+                ..DisplayConfig::default()
+            },
+            ..Config::default()
+        };
+        let text = toml::to_string(&config).unwrap();
+        assert_eq!(toml::from_str::<Config>(&text).unwrap(), config);
+
+        // Fields missing from a table keep their defaults too
+        let config = toml::from_str::<Config>("[controls]\nfield_of_view = 70.0\n").unwrap();
+        assert_eq!(config.controls.field_of_view, 70.0);
+        assert_eq!(config.controls.move_speed, ControlsConfig::default().move_speed);
+    }
+}
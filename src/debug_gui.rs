@@ -0,0 +1,515 @@
+use std::ops::RangeInclusive;
+
+use cgmath::{Vector2, Vector4};
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::MouseButton;
+
+use crate::input_manager::{KeyState, LogicalEvent};
+use crate::text_field::TextField;
+use crate::renderer::frame_packet::{GuiRect, TextRun};
+
+/// Logical pixels between the panel and the top-right corner of the screen
+const MARGIN: f32 = 16.0;
+const PANEL_WIDTH: f32 = 300.0;
+
+/// Space between the edge of the panel and its widgets, and between a control and its label
+const PADDING: f32 = 8.0;
+
+const ROW_HEIGHT: f32 = 24.0;
+const SLIDER_WIDTH: f32 = 120.0;
+const SLIDER_HEIGHT: f32 = 14.0;
+const CHECKBOX_SIZE: f32 = 16.0;
+
+/// Line height of the labels, in logical pixels
+const TEXT_SIZE: f32 = 16.0;
+
+const PANEL_COLOR: [f32; 4] = [0.05, 0.05, 0.08, 0.8];
+const CONTROL_COLOR: [f32; 4] = [0.25, 0.25, 0.3, 1.0];
+const ACCENT_COLOR: [f32; 4] = [0.3, 0.6, 1.0, 1.0];
+const TEXT_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const HEADING_COLOR: [f32; 4] = [0.6, 0.75, 1.0, 1.0];
+
+fn contains(pos: Vector2<f32>, size: Vector2<f32>, point: Vector2<f32>) -> bool {
+    point.x >= pos.x && point.y >= pos.y && point.x < pos.x + size.x && point.y < pos.y + size.y
+}
+
+/// A line of the GUI's text
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuiLabel {
+    /// Logical pixels from the top-left of the screen to the top-left of the text
+    pub pos: Vector2<f32>,
+    pub text: String,
+    pub color: Vector4<f32>,
+}
+
+/// Where the focused text field's caret is drawn
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuiCaret {
+    /// Logical pixels from the top-left of the screen to the bottom-left of the field's text
+    pub pos: Vector2<f32>,
+
+    /// The field's text up to the caret, which the caret is drawn after
+    pub text_before: String,
+}
+
+/// Everything to draw for one frame of the GUI, back to front
+#[derive(Default)]
+pub struct DebugGuiOutput {
+    pub rects: Vec<GuiRect>,
+    pub labels: Vec<GuiLabel>,
+
+    /// The focused text field's caret, if there is one
+    pub caret: Option<GuiCaret>,
+}
+
+impl DebugGuiOutput {
+    /// The labels as text runs for a screen of the given size
+    pub fn text_runs(&self, screen_size: PhysicalSize<u32>, scale_factor: f32) -> Vec<TextRun> {
+        let width = screen_size.width as f32;
+        let height = screen_size.height as f32;
+        self.labels
+            .iter()
+            .map(|label| {
+                let pos = label.pos * scale_factor;
+                TextRun {
+                    text: label.text.clone(),
+                    screen_pos: [2.0 * pos.x / width - 1.0, 1.0 - 2.0 * pos.y / height].into(),
+                    size: TEXT_SIZE * scale_factor,
+                    color: label.color,
+                }
+            })
+            .collect()
+    }
+
+    /// Physical pixels from the top-left of the screen to just below the focused text field's
+    /// caret, for the IME to open its candidate window at
+    ///
+    /// `text_width` gives the physical pixel width of a string drawn at a pixel height.
+    pub fn ime_position(
+        &self,
+        scale_factor: f32,
+        text_width: impl Fn(&str, f32) -> f32,
+    ) -> Option<PhysicalPosition<f32>> {
+        self.caret.as_ref().map(|caret| {
+            let pos = caret.pos * scale_factor;
+            let x = text_width(&caret.text_before, TEXT_SIZE * scale_factor);
+            PhysicalPosition::new(pos.x + x, pos.y)
+        })
+    }
+}
+
+/// A panel of widgets for tweaking settings while the app runs
+///
+/// The widgets are immediate mode: they're declared afresh each frame through a
+/// `DebugGuiFrame`, which applies any mouse input to the values they're given as it goes. Only
+/// the mouse state and the widget being dragged persist between frames.
+pub struct DebugGui {
+    pub visible: bool,
+
+    /// Physical pixels per logical pixel
+    scale_factor: f32,
+
+    /// In logical pixels
+    screen_width: f32,
+
+    /// In logical pixels, or None while the cursor is outside the window or grabbed
+    cursor: Option<Vector2<f32>>,
+
+    /// Whether the left button is held after being pressed over the panel
+    mouse_down: bool,
+
+    /// Set when the left button is pressed over the panel, until a widget takes the press
+    pressed: bool,
+
+    /// Label of the slider being dragged
+    active: Option<String>,
+
+    /// Size of the panel as of the last frame, for telling whether clicks land on it
+    panel_size: Vector2<f32>,
+}
+
+impl DebugGui {
+    pub fn new(screen_size: PhysicalSize<u32>, scale_factor: f64) -> Self {
+        Self {
+            visible: false,
+            scale_factor: scale_factor as f32,
+            screen_width: screen_size.width as f32 / scale_factor as f32,
+            cursor: None,
+            mouse_down: false,
+            pressed: false,
+            active: None,
+            panel_size: [0.0, 0.0].into(),
+        }
+    }
+
+    /// Update the size/DPI of the window the GUI is laid out in
+    pub fn set_screen_metrics(&mut self, screen_size: PhysicalSize<u32>, scale_factor: f64) {
+        self.scale_factor = scale_factor as f32;
+        self.screen_width = screen_size.width as f32 / self.scale_factor;
+    }
+
+    /// Logical pixels from the top-left of the screen to the top-left of the panel
+    fn panel_pos(&self) -> Vector2<f32> {
+        Vector2::new(self.screen_width - PANEL_WIDTH - MARGIN, MARGIN)
+    }
+
+    fn is_over_panel(&self) -> bool {
+        let panel_pos = self.panel_pos();
+        self.visible
+            && self
+                .cursor
+                .is_some_and(|cursor| contains(panel_pos, self.panel_size, cursor))
+    }
+
+    /// Pick up the mouse's movement and buttons, returning whether the event was meant for the
+    /// GUI and so shouldn't also act on the scene
+    pub fn handle_event(&mut self, event: &LogicalEvent) -> bool {
+        match event {
+            LogicalEvent::CursorMoved { position } => {
+                self.cursor = position
+                    .map(|position| Vector2::new(position.x as f32, position.y as f32))
+                    .map(|position| position / self.scale_factor);
+                false
+            }
+            LogicalEvent::MouseButton {
+                button: MouseButton::Left,
+                new_state: KeyState::Down,
+            } if self.is_over_panel() => {
+                self.mouse_down = true;
+                self.pressed = true;
+                true
+            }
+            // Releases only count if the press did, so a drag can end anywhere
+            LogicalEvent::MouseButton {
+                button: MouseButton::Left,
+                new_state: KeyState::Up,
+            } => std::mem::take(&mut self.mouse_down),
+            LogicalEvent::Click { .. } | LogicalEvent::Scroll { .. } => self.is_over_panel(),
+            _ => false,
+        }
+    }
+
+    /// Start declaring this frame's widgets
+    pub fn frame(&mut self) -> DebugGuiFrame<'_> {
+        let panel_pos = self.panel_pos();
+        DebugGuiFrame {
+            gui: self,
+            panel_pos,
+            next_row: panel_pos.y + PADDING,
+            output: DebugGuiOutput::default(),
+        }
+    }
+}
+
+/// One frame's worth of widgets, stacked in rows down the panel in the order they're declared
+pub struct DebugGuiFrame<'a> {
+    gui: &'a mut DebugGui,
+    panel_pos: Vector2<f32>,
+
+    /// Logical y coordinate of the top of the next row
+    next_row: f32,
+
+    output: DebugGuiOutput,
+}
+
+impl DebugGuiFrame<'_> {
+    /// Top-left of a new row, below the last one
+    fn row(&mut self) -> Vector2<f32> {
+        let pos = Vector2::new(self.panel_pos.x + PADDING, self.next_row);
+        self.next_row += ROW_HEIGHT;
+        pos
+    }
+
+    fn rect(&mut self, pos: Vector2<f32>, size: Vector2<f32>, color: [f32; 4]) {
+        self.output.rects.push(GuiRect {
+            pos,
+            size,
+            color: color.into(),
+        });
+    }
+
+    /// Text starting `x` logical pixels across the row at `row`, centered vertically in it
+    fn text(&mut self, row: Vector2<f32>, x: f32, text: String, color: [f32; 4]) {
+        self.output.labels.push(GuiLabel {
+            pos: [row.x + x, row.y + (ROW_HEIGHT - TEXT_SIZE) / 2.0].into(),
+            text,
+            color: color.into(),
+        });
+    }
+
+    /// Whether the press waiting to be handled landed in the given rect, taking it if so
+    fn take_press(&mut self, pos: Vector2<f32>, size: Vector2<f32>) -> bool {
+        let hit = self.gui.pressed && self.gui.cursor.is_some_and(|c| contains(pos, size, c));
+        if hit {
+            self.gui.pressed = false;
+        }
+        hit
+    }
+
+    /// A line of text introducing the widgets after it
+    pub fn heading(&mut self, text: &str) {
+        let row = self.row();
+        self.text(row, 0.0, text.to_owned(), HEADING_COLOR);
+    }
+
+    /// A box that toggles `value` when clicked, returning whether it did this frame
+    pub fn checkbox(&mut self, label: &str, value: &mut bool) -> bool {
+        let row = self.row();
+        let box_pos = row + Vector2::new(0.0, (ROW_HEIGHT - CHECKBOX_SIZE) / 2.0);
+        let box_size = Vector2::new(CHECKBOX_SIZE, CHECKBOX_SIZE);
+
+        // The label is part of the target too, as it's the bigger of the two
+        let clicked = self.take_press(row, [PANEL_WIDTH - 2.0 * PADDING, ROW_HEIGHT].into());
+        if clicked {
+            *value = !*value;
+        }
+
+        self.rect(box_pos, box_size, CONTROL_COLOR);
+        if *value {
+            let inset = Vector2::new(3.0, 3.0);
+            self.rect(box_pos + inset, box_size - 2.0 * inset, ACCENT_COLOR);
+        }
+        self.text(row, CHECKBOX_SIZE + PADDING, label.to_owned(), TEXT_COLOR);
+        clicked
+    }
+
+    /// A track that sets `value` to wherever in `range` it's clicked or dragged to, returning
+    /// whether `value` changed this frame
+    pub fn slider(&mut self, label: &str, value: &mut f32, range: RangeInclusive<f32>) -> bool {
+        let row = self.row();
+        let track_pos = row + Vector2::new(0.0, (ROW_HEIGHT - SLIDER_HEIGHT) / 2.0);
+        let track_size = Vector2::new(SLIDER_WIDTH, SLIDER_HEIGHT);
+
+        let pressed = self.take_press(row, [SLIDER_WIDTH, ROW_HEIGHT].into());
+        if pressed {
+            self.gui.active = Some(label.to_owned());
+        }
+
+        // A press counts even if it was released before this frame, but a drag ends with the
+        // release
+        let mut changed = false;
+        let dragging =
+            self.gui.active.as_deref() == Some(label) && (pressed || self.gui.mouse_down);
+        if let (true, Some(cursor)) = (dragging, self.gui.cursor) {
+            let t = ((cursor.x - track_pos.x) / SLIDER_WIDTH).clamp(0.0, 1.0);
+            let new_value = range.start() + t * (range.end() - range.start());
+            changed = new_value != *value;
+            *value = new_value;
+        }
+
+        let t = ((*value - range.start()) / (range.end() - range.start())).clamp(0.0, 1.0);
+        self.rect(track_pos, track_size, CONTROL_COLOR);
+        self.rect(track_pos, [t * SLIDER_WIDTH, SLIDER_HEIGHT].into(), ACCENT_COLOR);
+        self.text(row, SLIDER_WIDTH + PADDING, format!("{}: {:.2}", label, value), TEXT_COLOR);
+        changed
+    }
+
+    /// A button showing which of `options` `value` is, that steps it on to the next one when
+    /// clicked, returning whether it did this frame
+    pub fn cycle<T: Copy + PartialEq>(
+        &mut self,
+        label: &str,
+        value: &mut T,
+        options: &[(T, &str)],
+    ) -> bool {
+        let row = self.row();
+        let button_pos = row + Vector2::new(0.0, (ROW_HEIGHT - SLIDER_HEIGHT) / 2.0);
+
+        let current = options.iter().position(|(option, _)| option == value);
+        let clicked = self.take_press(row, [SLIDER_WIDTH, ROW_HEIGHT].into());
+        if clicked {
+            // A value that isn't one of the options starts over from the first
+            let next = current.map_or(0, |i| (i + 1) % options.len());
+            *value = options[next].0;
+        }
+
+        self.rect(button_pos, [SLIDER_WIDTH, SLIDER_HEIGHT].into(), CONTROL_COLOR);
+        let name = options
+            .iter()
+            .find(|(option, _)| option == value)
+            .map_or("?", |(_, name)| name);
+        self.text(row, SLIDER_WIDTH + PADDING, format!("{}: {}", label, name), TEXT_COLOR);
+        clicked
+    }
+
+    /// A box showing `field`'s text, with its cursor while it's `focused`, returning whether it
+    /// was clicked this frame
+    ///
+    /// The label goes in a row of its own above the box, so that the box can span the panel.
+    pub fn text_field(&mut self, label: &str, field: &TextField, focused: bool) -> bool {
+        let label_row = self.row();
+        self.text(label_row, 0.0, label.to_owned(), TEXT_COLOR);
+
+        let row = self.row();
+        let box_size = Vector2::new(PANEL_WIDTH - 2.0 * PADDING, ROW_HEIGHT - 4.0);
+        let clicked = self.take_press(row, box_size);
+
+        self.rect(row, box_size, CONTROL_COLOR);
+        if focused {
+            let inset = Vector2::new(0.0, box_size.y - 2.0);
+            self.rect(row + inset, [box_size.x, 2.0].into(), ACCENT_COLOR);
+        }
+        let (before, after) = field.text().split_at(field.cursor());
+        let text = if focused {
+            self.output.caret = Some(GuiCaret {
+                pos: [row.x + PADDING, row.y + (ROW_HEIGHT + TEXT_SIZE) / 2.0].into(),
+                text_before: before.to_owned(),
+            });
+            format!("{}|{}", before, after)
+        } else {
+            field.text().to_owned()
+        };
+        self.text(row, PADDING, text, TEXT_COLOR);
+        clicked
+    }
+
+    /// Finish the frame, returning what to draw for it
+    pub fn finish(mut self) -> DebugGuiOutput {
+        let panel_size = Vector2::new(PANEL_WIDTH, self.next_row + PADDING - self.panel_pos.y);
+        self.output.rects.insert(0, GuiRect {
+            pos: self.panel_pos,
+            size: panel_size,
+            color: PANEL_COLOR.into(),
+        });
+
+        self.gui.panel_size = panel_size;
+        // A press that missed every widget just goes to the panel
+        self.gui.pressed = false;
+        if !self.gui.mouse_down {
+            self.gui.active = None;
+        }
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use winit::dpi::PhysicalPosition;
+
+    fn move_cursor(gui: &mut DebugGui, x: f64, y: f64) {
+        gui.handle_event(&LogicalEvent::CursorMoved {
+            position: Some(PhysicalPosition::new(x, y)),
+        });
+    }
+
+    fn set_mouse_button(gui: &mut DebugGui, new_state: KeyState) -> bool {
+        gui.handle_event(&LogicalEvent::MouseButton {
+            button: MouseButton::Left,
+            new_state,
+        })
+    }
+
+    /// One slider from 0 to 10 and one checkbox
+    fn draw(gui: &mut DebugGui, speed: &mut f32, enabled: &mut bool) {
+        let mut frame = gui.frame();
+        frame.slider("Speed", speed, 0.0..=10.0);
+        frame.checkbox("Enabled", enabled);
+        frame.finish();
+    }
+
+    #[test]
+    fn test_slider_and_checkbox() {
+        let mut gui = DebugGui::new(PhysicalSize::new(1600, 1200), 2.0);
+        gui.visible = true;
+        let mut speed = 0.0;
+        let mut enabled = false;
+        draw(&mut gui, &mut speed, &mut enabled);
+
+        // Halfway along the slider's track, in the first row
+        let x = 800.0 - MARGIN - PANEL_WIDTH + PADDING + SLIDER_WIDTH / 2.0;
+        let y = MARGIN + PADDING + ROW_HEIGHT / 2.0;
+        move_cursor(&mut gui, 2.0 * x as f64, 2.0 * y as f64);
+        assert!(set_mouse_button(&mut gui, KeyState::Down));
+        draw(&mut gui, &mut speed, &mut enabled);
+        assert_eq!(speed, 5.0);
+
+        // Dragging keeps going while the button is held, even off the panel
+        move_cursor(&mut gui, 3000.0, 0.0);
+        draw(&mut gui, &mut speed, &mut enabled);
+        assert_eq!(speed, 10.0);
+        assert!(set_mouse_button(&mut gui, KeyState::Up));
+
+        // In the second row
+        move_cursor(&mut gui, 2.0 * x as f64, 2.0 * (y + ROW_HEIGHT) as f64);
+        assert!(set_mouse_button(&mut gui, KeyState::Down));
+        assert!(set_mouse_button(&mut gui, KeyState::Up));
+        draw(&mut gui, &mut speed, &mut enabled);
+        assert!(enabled);
+        assert_eq!(speed, 10.0);
+    }
+
+    #[test]
+    fn test_cycle() {
+        let mut gui = DebugGui::new(PhysicalSize::new(800, 600), 1.0);
+        gui.visible = true;
+        let options = [(1, "One"), (2, "Two"), (3, "Three")];
+        let mut value = 3;
+        let draw = |gui: &mut DebugGui, value: &mut i32| {
+            let mut frame = gui.frame();
+            let clicked = frame.cycle("Number", value, &options);
+            (clicked, frame.finish())
+        };
+        draw(&mut gui, &mut value);
+
+        let x = 800.0 - MARGIN - PANEL_WIDTH + PADDING + SLIDER_WIDTH / 2.0;
+        let y = MARGIN + PADDING + ROW_HEIGHT / 2.0;
+        move_cursor(&mut gui, x as f64, y as f64);
+        assert!(set_mouse_button(&mut gui, KeyState::Down));
+        assert!(set_mouse_button(&mut gui, KeyState::Up));
+        let (clicked, output) = draw(&mut gui, &mut value);
+        assert!(clicked);
+        assert_eq!(value, 1);
+        assert_eq!(output.labels[0].text, "Number: One");
+
+        let (clicked, _) = draw(&mut gui, &mut value);
+        assert!(!clicked);
+        assert_eq!(value, 1);
+    }
+
+    #[test]
+    fn test_text_field() {
+        let mut gui = DebugGui::new(PhysicalSize::new(800, 600), 1.0);
+        gui.visible = true;
+        let mut field = TextField::new();
+        field.insert("scene.glb");
+        let draw = |gui: &mut DebugGui, field: &TextField, focused: bool| {
+            let mut frame = gui.frame();
+            let clicked = frame.text_field("Path", field, focused);
+            (clicked, frame.finish())
+        };
+
+        let (clicked, output) = draw(&mut gui, &field, false);
+        assert!(!clicked);
+        assert_eq!(output.labels[1].text, "scene.glb");
+        assert_eq!(output.ime_position(1.0, |_, _| 0.0), None);
+
+        // In the second row, which holds the box
+        let x = 800.0 - MARGIN - PANEL_WIDTH + PADDING + 200.0;
+        let y = MARGIN + PADDING + 1.5 * ROW_HEIGHT;
+        move_cursor(&mut gui, x as f64, y as f64);
+        assert!(set_mouse_button(&mut gui, KeyState::Down));
+        assert!(set_mouse_button(&mut gui, KeyState::Up));
+        let (clicked, output) = draw(&mut gui, &field, true);
+        assert!(clicked);
+        assert_eq!(output.labels[1].text, "scene.glb|");
+
+        // The IME goes below the text, after everything up to the caret
+        let text_width = |text: &str, size: f32| text.len() as f32 * size;
+        let label = &output.labels[1];
+        let position = output.ime_position(2.0, text_width).unwrap();
+        assert_eq!(position.x, 2.0 * label.pos.x + 9.0 * 2.0 * TEXT_SIZE);
+        assert_eq!(position.y, 2.0 * (label.pos.y + TEXT_SIZE));
+    }
+
+    #[test]
+    fn test_clicks_off_the_panel_pass_through() {
+        let mut gui = DebugGui::new(PhysicalSize::new(800, 600), 1.0);
+        gui.visible = true;
+        draw(&mut gui, &mut 0.0, &mut false);
+
+        move_cursor(&mut gui, 100.0, 100.0);
+        assert!(!set_mouse_button(&mut gui, KeyState::Down));
+        assert!(!gui.handle_event(&LogicalEvent::Click { position: None }));
+    }
+}
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use winit::dpi::PhysicalSize;
+use winit::monitor::{MonitorHandle, VideoMode};
+use winit::window::{Fullscreen, Window};
+
+/// How the window is shown
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WindowMode {
+    #[default]
+    Windowed,
+
+    /// A window covering the whole monitor, at the monitor's current video mode
+    Borderless,
+
+    /// Takes over the monitor, switching it to the configured video mode
+    Exclusive,
+}
+
+impl WindowMode {
+    pub fn next(self) -> Self {
+        match self {
+            WindowMode::Windowed => WindowMode::Borderless,
+            WindowMode::Borderless => WindowMode::Exclusive,
+            WindowMode::Exclusive => WindowMode::Windowed,
+        }
+    }
+}
+
+/// How big the window is, and which monitor and video mode it goes fullscreen on
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    pub window_mode: WindowMode,
+
+    /// Width and height the window opens at, in physical pixels
+    pub window_size: [u32; 2],
+
+    /// Index in to the available monitors, or the monitor the window is currently on if unset
+    pub monitor: Option<usize>,
+
+    /// Width and height for exclusive fullscreen, or the monitor's largest if unset
+    pub resolution: Option<[u32; 2]>,
+
+    /// Refresh rate in Hz for exclusive fullscreen, or the highest available at the resolution if
+    /// unset
+    pub refresh_rate: Option<u16>,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            window_mode: WindowMode::default(),
+            window_size: [1920, 1080],
+            monitor: None,
+            resolution: None,
+            refresh_rate: None,
+        }
+    }
+}
+
+impl DisplayConfig {
+    pub fn window_size(&self) -> PhysicalSize<u32> {
+        let [width, height] = self.window_size;
+        PhysicalSize::new(width, height)
+    }
+
+    /// What to pass to `Window::set_fullscreen` to show the window in the configured mode
+    ///
+    /// Falls back to borderless if the monitor has no video mode matching the configured one.
+    pub fn fullscreen(&self, window: &Window) -> Option<Fullscreen> {
+        if self.window_mode == WindowMode::Windowed {
+            return None;
+        }
+
+        let monitor = self.monitor(window);
+        if self.window_mode == WindowMode::Exclusive {
+            let modes: Vec<VideoMode> = monitor.video_modes().collect();
+            let summaries: Vec<_> = modes
+                .iter()
+                .map(|mode| (mode.size(), mode.refresh_rate(), mode.bit_depth()))
+                .collect();
+            match best_video_mode(&summaries, self.resolution, self.refresh_rate) {
+                Some(i) => return Some(Fullscreen::Exclusive(modes[i].clone())),
+                None => warn!(
+                    "No video mode matches {:?} at {:?}Hz, using borderless instead",
+                    self.resolution, self.refresh_rate
+                ),
+            }
+        }
+
+        Some(Fullscreen::Borderless(monitor))
+    }
+
+    fn monitor(&self, window: &Window) -> MonitorHandle {
+        let index = match self.monitor {
+            Some(index) => index,
+            None => return window.current_monitor(),
+        };
+        match window.available_monitors().nth(index) {
+            Some(monitor) => monitor,
+            None => {
+                warn!("There is no monitor {}, using the current one", index);
+                window.current_monitor()
+            }
+        }
+    }
+}
+
+/// Index of the video mode, given as its size, refresh rate and bit depth, that best matches the
+/// requested resolution and refresh rate
+///
+/// Whatever isn't requested is as high as possible, preferring resolution over refresh rate over
+/// bit depth.
+fn best_video_mode(
+    modes: &[(PhysicalSize<u32>, u16, u16)],
+    resolution: Option<[u32; 2]>,
+    refresh_rate: Option<u16>,
+) -> Option<usize> {
+    modes
+        .iter()
+        .enumerate()
+        .filter(|(_, (size, rate, _))| {
+            resolution.is_none_or(|[w, h]| size.width == w && size.height == h)
+                && refresh_rate.is_none_or(|r| *rate == r)
+        })
+        .max_by_key(|(_, (size, rate, depth))| (size.width * size.height, *rate, *depth))
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_best_video_mode() {
+        let modes = [
+            (PhysicalSize::new(1920, 1080), 60, 32),
+            (PhysicalSize::new(1920, 1080), 144, 32),
+            (PhysicalSize::new(2560, 1440), 60, 32),
+            (PhysicalSize::new(1280, 720), 144, 24),
+            (PhysicalSize::new(1280, 720), 144, 32),
+        ];
+        assert_eq!(best_video_mode(&modes, None, None), Some(2));
+        assert_eq!(best_video_mode(&modes, Some([1920, 1080]), None), Some(1));
+        assert_eq!(best_video_mode(&modes, None, Some(144)), Some(1));
+        assert_eq!(best_video_mode(&modes, Some([1280, 720]), Some(144)), Some(4));
+        assert_eq!(best_video_mode(&modes, Some([800, 600]), None), None);
+    }
+}
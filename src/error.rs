@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+/// Everything that can go wrong loading assets or drawing them
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Failed to read {}: {source}", .path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse GLTF file: {0}")]
+    Gltf(#[from] gltf::Error),
+
+    #[error("Failed to decode image: {0}")]
+    Image(#[from] image::ImageError),
+
+    #[error("Failed to compile shader {}: {source}", .path.display())]
+    ShaderCompilation {
+        path: PathBuf,
+        source: shaderc::Error,
+    },
+
+    /// An asset was loaded fine, but doesn't contain something that's needed
+    #[error("Invalid asset: {0}")]
+    InvalidAsset(&'static str),
+
+    #[error("Invalid command line argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("Failed to create window: {0}")]
+    Window(#[from] winit::error::OsError),
+
+    #[error("GPU error: {0}")]
+    Gpu(&'static str),
+
+    /// The app asked the renderer to draw something it doesn't know about
+    #[error("Invalid frame packet: {0}")]
+    InvalidFramePacket(&'static str),
+}
+
+impl Error {
+    /// For use with `map_err`, to attach the path being read to an I/O error
+    pub fn io(path: impl Into<PathBuf>) -> impl FnOnce(std::io::Error) -> Self {
+        let path = path.into();
+        move |source| Error::Io { path, source }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
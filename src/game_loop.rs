@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use tracing::warn;
+
+/// Steps the simulation at a fixed rate, however often frames are drawn
+///
+/// Wall clock time is fed in with `advance`, which says how many whole ticks are due. Whatever is
+/// left over is how far the current time is in to the next tick, which frames use to interpolate
+/// between the last two ticks.
+pub struct GameLoop {
+    timestep: Duration,
+
+    /// Time that has passed but hasn't been ticked yet, always less than `timestep` between calls
+    /// to `advance`
+    accumulator: Duration,
+}
+
+impl GameLoop {
+    /// After a long stall (eg. the window being dragged) the simulation skips ahead rather than
+    /// running enough ticks to catch up, which would only make the next frame later still
+    const MAX_TICKS_PER_ADVANCE: u32 = 10;
+
+    pub fn new(timestep: Duration) -> Self {
+        Self {
+            timestep,
+            accumulator: Duration::from_secs(0),
+        }
+    }
+
+    /// The simulated time that passes in every tick
+    pub fn timestep(&self) -> Duration {
+        self.timestep
+    }
+
+    /// Let the given wall clock time pass, returning how many ticks to run to catch up with it
+    pub fn advance(&mut self, elapsed: Duration) -> u32 {
+        self.accumulator += elapsed;
+
+        let mut ticks = 0;
+        while self.accumulator >= self.timestep {
+            self.accumulator -= self.timestep;
+            ticks += 1;
+        }
+
+        if ticks > Self::MAX_TICKS_PER_ADVANCE {
+            warn!("Simulation fell {} ticks behind, skipping ahead", ticks);
+            ticks = Self::MAX_TICKS_PER_ADVANCE;
+        }
+        ticks
+    }
+
+    /// How far the current time is between the last tick and the next one, from 0 to 1
+    pub fn alpha(&self) -> f32 {
+        self.accumulator.as_secs_f32() / self.timestep.as_secs_f32()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_game_loop_accumulates() {
+        let mut game_loop = GameLoop::new(Duration::from_millis(10));
+
+        assert_eq!(game_loop.advance(Duration::from_millis(4)), 0);
+        assert_relative_eq!(game_loop.alpha(), 0.4, epsilon = 1e-5);
+
+        assert_eq!(game_loop.advance(Duration::from_millis(21)), 2);
+        assert_relative_eq!(game_loop.alpha(), 0.5, epsilon = 1e-5);
+    }
+
+    #[test]
+    fn test_game_loop_skips_ahead_after_stall() {
+        let mut game_loop = GameLoop::new(Duration::from_millis(10));
+        assert_eq!(game_loop.advance(Duration::from_secs(5)), GameLoop::MAX_TICKS_PER_ADVANCE);
+        assert!(game_loop.alpha() < 1.0);
+    }
+}
@@ -1,8 +1,15 @@
 use std::collections::{HashMap, VecDeque};
 
-use scancode::Scancode;
-use winit::event::{DeviceEvent, ElementState, Event, KeyboardInput};
+use tracing::warn;
+use winit::dpi::PhysicalPosition;
+use winit::event::{
+    DeviceEvent, ElementState, Event, KeyboardInput, ModifiersState, MouseButton,
+    MouseScrollDelta, VirtualKeyCode, WindowEvent,
+};
 
+use crate::key_bindings::KeyBindings;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LogicalKey {
     MoveForward,
     MoveBackward,
@@ -10,26 +17,60 @@ pub enum LogicalKey {
     StrafeRight,
     MoveUp,
     MoveDown,
+    ToggleCalibrationScreen,
+    CycleQualityPreset,
+    ToggleCameraMode,
+    ToggleVsync,
+    ToggleStats,
+    ToggleWireframe,
+    ToggleDebugLines,
+    ExposureDown,
+    ExposureUp,
+    BrightnessDown,
+    BrightnessUp,
+    GammaDown,
+    GammaUp,
+    ZoomIn,
+    ZoomOut,
+    ToggleCursor,
+    CycleWindowMode,
+    ToggleDebugGui,
 }
 
 impl LogicalKey {
-    // Effectively hardcode the key bindings for now
-    // TODO: Configurable key bindings
-    fn from_scancode(scancode: u32) -> Option<Self> {
-        let scancode = match Scancode::new(scancode as u8) {
-            Some(scancode) => scancode,
-            None => return None,
-        };
+    pub const ALL: [LogicalKey; 24] = [
+        LogicalKey::MoveForward,
+        LogicalKey::MoveBackward,
+        LogicalKey::StrafeLeft,
+        LogicalKey::StrafeRight,
+        LogicalKey::MoveUp,
+        LogicalKey::MoveDown,
+        LogicalKey::ToggleCalibrationScreen,
+        LogicalKey::CycleQualityPreset,
+        LogicalKey::ToggleCameraMode,
+        LogicalKey::ToggleVsync,
+        LogicalKey::ToggleStats,
+        LogicalKey::ToggleWireframe,
+        LogicalKey::ToggleDebugLines,
+        LogicalKey::ExposureDown,
+        LogicalKey::ExposureUp,
+        LogicalKey::BrightnessDown,
+        LogicalKey::BrightnessUp,
+        LogicalKey::GammaDown,
+        LogicalKey::GammaUp,
+        LogicalKey::ZoomIn,
+        LogicalKey::ZoomOut,
+        LogicalKey::ToggleCursor,
+        LogicalKey::CycleWindowMode,
+        LogicalKey::ToggleDebugGui,
+    ];
 
-        Some(match scancode {
-            Scancode::W => LogicalKey::MoveForward,
-            Scancode::A => LogicalKey::StrafeLeft,
-            Scancode::S => LogicalKey::MoveBackward,
-            Scancode::D => LogicalKey::StrafeRight,
-            Scancode::Space => LogicalKey::MoveUp,
-            Scancode::LeftControl => LogicalKey::MoveDown,
-            _ => return None,
-        })
+    /// Parse the name used for this key in config files, which matches the variant name
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|key| format!("{:?}", key) == name)
     }
 }
 
@@ -39,26 +80,197 @@ pub enum KeyState {
     Down,
 }
 
+/// An edit to whichever text field currently has focus
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextInputEvent {
+    /// A committed character, either typed directly or the result of an IME composition
+    Insert(char),
+    Backspace,
+    Delete,
+    CursorLeft,
+    CursorRight,
+    Home,
+    End,
+    Copy,
+    Cut,
+    Paste,
+    Submit,
+
+    /// Leave the field without submitting it
+    Cancel,
+}
+
+/// A continuous input, eg. a gamepad stick, whose value is in the range -1.0..=1.0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogicalAxis {
+    /// Positive is to the right
+    Strafe,
+    /// Positive is forwards
+    Move,
+    /// Positive turns the camera to the right
+    PanHorizontal,
+    /// Positive turns the camera up
+    PanVertical,
+}
+
 pub enum LogicalEvent {
     Key {
         new_state: KeyState,
         logical_key: LogicalKey,
     },
-    /// Represents a relative movement of the mouse in pixels, where X is right and Y is down.
+    /// The new position of an analog input. Sent whenever it changes, so the last value received
+    /// holds until the next one.
+    Axis { axis: LogicalAxis, value: f32 },
+    /// Represents a relative movement of the mouse in pixels multiplied by the mouse sensitivity,
+    /// where X is right and Y is down. Only generated while the cursor is grabbed and the window
+    /// has focus.
     MouseMovement { x: f32, y: f32 },
+    MouseButton {
+        new_state: KeyState,
+        button: MouseButton,
+    },
+    /// A vertical scroll in lines, positive when scrolling up/away from the user
+    Scroll { lines: f32 },
+    /// Only generated while text input is enabled
+    Text(TextInputEvent),
+    /// A press of the left mouse button, at the cursor's position within the window, or None if
+    /// the cursor is grabbed and so implicitly at the center of the window
+    Click { position: Option<PhysicalPosition<f64>> },
+    /// The cursor's new position within the window, or None once it's left the window or been
+    /// grabbed
+    CursorMoved { position: Option<PhysicalPosition<f64>> },
 }
 
+/// How far a touchpad has to scroll to count as one line of a mouse wheel
+const PIXELS_PER_SCROLL_LINE: f32 = 20.0;
+
 pub struct InputManager {
     // Maps hardware scancode to current pressed state
     key_states: HashMap<u32, KeyState>,
+    mouse_button_states: HashMap<MouseButton, KeyState>,
     logical_events: VecDeque<LogicalEvent>,
+    key_bindings: KeyBindings,
+
+    /// Multiplies every mouse movement
+    mouse_sensitivity: f32,
+
+    /// While enabled, key presses go to the focused text field rather than generating logical key
+    /// events
+    text_input_enabled: bool,
+    modifiers: ModifiersState,
+
+    /// Last known position of the cursor within the window, None while it's outside the window
+    cursor_position: Option<PhysicalPosition<f64>>,
+
+    /// Whether the window has grabbed the cursor, in which case only its movement matters
+    cursor_grabbed: bool,
+
+    /// Whether the window has keyboard focus. Device events keep arriving without it, but they're
+    /// meant for whichever window does have it.
+    focused: bool,
+
+    /// None if gamepad support couldn't be initialized on this platform
+    gamepads: Option<gilrs::Gilrs>,
+}
+
+/// The fixed mapping from gamepad buttons to logical keys
+fn gamepad_button_logical_key(button: gilrs::Button) -> Option<LogicalKey> {
+    use gilrs::Button;
+    Some(match button {
+        Button::RightTrigger => LogicalKey::MoveUp,
+        Button::LeftTrigger => LogicalKey::MoveDown,
+        Button::Start => LogicalKey::ToggleCalibrationScreen,
+        Button::Select => LogicalKey::CycleQualityPreset,
+        Button::North => LogicalKey::ToggleCameraMode,
+        Button::DPadUp => LogicalKey::ExposureUp,
+        Button::DPadDown => LogicalKey::ExposureDown,
+        Button::DPadLeft => LogicalKey::GammaDown,
+        Button::DPadRight => LogicalKey::GammaUp,
+        _ => return None,
+    })
+}
+
+/// The fixed mapping from gamepad sticks to logical axes
+fn gamepad_logical_axis(axis: gilrs::Axis) -> Option<LogicalAxis> {
+    use gilrs::Axis;
+    Some(match axis {
+        Axis::LeftStickX => LogicalAxis::Strafe,
+        Axis::LeftStickY => LogicalAxis::Move,
+        Axis::RightStickX => LogicalAxis::PanHorizontal,
+        Axis::RightStickY => LogicalAxis::PanVertical,
+        _ => return None,
+    })
 }
 
 impl InputManager {
-    pub fn new() -> Self {
+    pub fn new(key_bindings: KeyBindings, mouse_sensitivity: f32) -> Self {
         Self {
             key_states: HashMap::new(),
+            mouse_button_states: HashMap::new(),
             logical_events: VecDeque::new(),
+            key_bindings,
+            mouse_sensitivity,
+            text_input_enabled: false,
+            modifiers: ModifiersState::empty(),
+            cursor_position: None,
+            cursor_grabbed: false,
+            focused: true,
+            gamepads: match gilrs::Gilrs::new() {
+                Ok(gilrs) => Some(gilrs),
+                Err(e) => {
+                    warn!("Gamepad support unavailable: {}", e);
+                    None
+                }
+            },
+        }
+    }
+
+    /// The bindings used to turn key presses in to logical keys, which can be changed at any time
+    #[allow(unused)]
+    pub fn key_bindings_mut(&mut self) -> &mut KeyBindings {
+        &mut self.key_bindings
+    }
+
+    /// Which modifier keys are currently held
+    pub fn modifiers(&self) -> ModifiersState {
+        self.modifiers
+    }
+
+    pub fn set_text_input_enabled(&mut self, enabled: bool) {
+        self.text_input_enabled = enabled;
+    }
+
+    /// Should be kept in sync with the window, so that clicks are reported at the right place and
+    /// the mouse only turns the camera while it's grabbed
+    pub fn set_cursor_grabbed(&mut self, grabbed: bool) {
+        self.cursor_grabbed = grabbed;
+        if grabbed {
+            self.logical_events.push_back(LogicalEvent::CursorMoved { position: None });
+        }
+    }
+
+    fn handle_focus_change(&mut self, focused: bool) {
+        self.focused = focused;
+        if focused {
+            return;
+        }
+
+        // Anything held now will be released while some other window has focus, where it can't
+        // be seen, so release it straight away instead
+        let held: Vec<u32> = self
+            .key_states
+            .iter()
+            .filter(|(_, &state)| state == KeyState::Down)
+            .map(|(&scancode, _)| scancode)
+            .collect();
+        for scancode in held {
+            self.key_states.insert(scancode, KeyState::Up);
+            if let Some(logical_key) = self.key_bindings.logical_key(scancode) {
+                self.logical_events.push_back(LogicalEvent::Key {
+                    new_state: KeyState::Up,
+                    logical_key,
+                });
+            }
         }
     }
 
@@ -75,7 +287,14 @@ impl InputManager {
         }
 
         *tracked_state = new_state;
-        if let Some(logical_key) = LogicalKey::from_scancode(ki.scancode) {
+
+        // Releases still have to get through, otherwise a key held while a text field gained focus
+        // would never be seen as released
+        if self.text_input_enabled && new_state == KeyState::Down {
+            return;
+        }
+
+        if let Some(logical_key) = self.key_bindings.logical_key(ki.scancode) {
             self.logical_events.push_back(LogicalEvent::Key {
                 new_state,
                 logical_key,
@@ -83,12 +302,62 @@ impl InputManager {
         }
     }
 
+    /// Whether the given mouse button is currently held down
+    #[allow(unused)]
+    pub fn is_mouse_button_down(&self, button: MouseButton) -> bool {
+        self.mouse_button_states.get(&button) == Some(&KeyState::Down)
+    }
+
+    fn handle_mouse_input(&mut self, button: MouseButton, state: ElementState) {
+        let tracked_state = self.mouse_button_states.entry(button).or_insert(KeyState::Up);
+
+        let new_state = match state {
+            ElementState::Pressed => KeyState::Down,
+            ElementState::Released => KeyState::Up,
+        };
+
+        if *tracked_state == new_state {
+            return;
+        }
+
+        *tracked_state = new_state;
+        self.logical_events.push_back(LogicalEvent::MouseButton { new_state, button });
+
+        if button == MouseButton::Left && new_state == KeyState::Down {
+            let position = if self.cursor_grabbed {
+                None
+            } else {
+                match self.cursor_position {
+                    Some(position) => Some(position),
+                    // Can't tell where a click landed before the cursor has moved in the window
+                    None => return,
+                }
+            };
+            self.logical_events.push_back(LogicalEvent::Click { position });
+        }
+    }
+
+    fn handle_mouse_wheel(&mut self, delta: MouseScrollDelta) {
+        let lines = match delta {
+            MouseScrollDelta::LineDelta(_, y) => y,
+            MouseScrollDelta::PixelDelta(position) => position.y as f32 / PIXELS_PER_SCROLL_LINE,
+        };
+
+        if lines != 0.0 {
+            self.logical_events.push_back(LogicalEvent::Scroll { lines });
+        }
+    }
+
     fn handle_device_event(&mut self, event: &DeviceEvent) {
+        if !self.focused {
+            return;
+        }
+
         match event {
-            DeviceEvent::MouseMotion { delta } => {
+            DeviceEvent::MouseMotion { delta } if self.cursor_grabbed => {
                 self.logical_events.push_back(LogicalEvent::MouseMovement {
-                    x: delta.0 as f32,
-                    y: delta.1 as f32,
+                    x: delta.0 as f32 * self.mouse_sensitivity,
+                    y: delta.1 as f32 * self.mouse_sensitivity,
                 });
             }
             DeviceEvent::Key(ki) => self.handle_keyboard_input(ki),
@@ -96,10 +365,115 @@ impl InputManager {
         }
     }
 
+    fn handle_text_key(&mut self, keycode: VirtualKeyCode) {
+        let ctrl = self.modifiers.ctrl();
+        let text_event = match keycode {
+            VirtualKeyCode::Back => TextInputEvent::Backspace,
+            VirtualKeyCode::Delete => TextInputEvent::Delete,
+            VirtualKeyCode::Left => TextInputEvent::CursorLeft,
+            VirtualKeyCode::Right => TextInputEvent::CursorRight,
+            VirtualKeyCode::Home => TextInputEvent::Home,
+            VirtualKeyCode::End => TextInputEvent::End,
+            VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => TextInputEvent::Submit,
+            VirtualKeyCode::Escape => TextInputEvent::Cancel,
+            VirtualKeyCode::C if ctrl => TextInputEvent::Copy,
+            VirtualKeyCode::X if ctrl => TextInputEvent::Cut,
+            VirtualKeyCode::V if ctrl => TextInputEvent::Paste,
+            _ => return,
+        };
+        self.logical_events.push_back(LogicalEvent::Text(text_event));
+    }
+
+    fn handle_window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::ModifiersChanged(modifiers) => self.modifiers = *modifiers,
+            WindowEvent::Focused(focused) => self.handle_focus_change(*focused),
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor_position = Some(*position);
+                if !self.cursor_grabbed {
+                    self.logical_events.push_back(LogicalEvent::CursorMoved {
+                        position: Some(*position),
+                    });
+                }
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.cursor_position = None;
+                self.logical_events.push_back(LogicalEvent::CursorMoved { position: None });
+            }
+            WindowEvent::MouseInput { button, state, .. } => {
+                self.handle_mouse_input(*button, *state)
+            }
+            WindowEvent::MouseWheel { delta, .. } => self.handle_mouse_wheel(*delta),
+            // Control characters (backspace, enter, ctrl+<key>, ...) are handled from the raw key
+            // presses instead
+            WindowEvent::ReceivedCharacter(c)
+                if self.text_input_enabled && !c.is_control() && !self.modifiers.ctrl() =>
+            {
+                self.logical_events
+                    .push_back(LogicalEvent::Text(TextInputEvent::Insert(*c)));
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(keycode),
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } if self.text_input_enabled => self.handle_text_key(*keycode),
+            _ => (),
+        }
+    }
+
+    /// Queue logical events for anything that's happened on a gamepad since this was last called
+    pub fn poll_gamepads(&mut self) {
+        let gamepads = match self.gamepads.as_mut() {
+            Some(gamepads) => gamepads,
+            None => return,
+        };
+
+        while let Some(gilrs::Event { event, .. }) = gamepads.next_event() {
+            let logical_event = match event {
+                gilrs::EventType::ButtonPressed(button, _) => {
+                    gamepad_button_logical_key(button).map(|logical_key| LogicalEvent::Key {
+                        new_state: KeyState::Down,
+                        logical_key,
+                    })
+                }
+                gilrs::EventType::ButtonReleased(button, _) => {
+                    gamepad_button_logical_key(button).map(|logical_key| LogicalEvent::Key {
+                        new_state: KeyState::Up,
+                        logical_key,
+                    })
+                }
+                gilrs::EventType::AxisChanged(axis, value, _) => gamepad_logical_axis(axis)
+                    .map(|axis| LogicalEvent::Axis {
+                        axis,
+                        value: value.clamp(-1.0, 1.0),
+                    }),
+                _ => None,
+            };
+
+            // Keep the gamepad out of text fields, same as the keyboard's logical keys
+            let is_key_down = matches!(
+                logical_event,
+                Some(LogicalEvent::Key { new_state: KeyState::Down, .. })
+            );
+            if self.text_input_enabled && is_key_down {
+                continue;
+            }
+
+            if let Some(logical_event) = logical_event {
+                self.logical_events.push_back(logical_event);
+            }
+        }
+    }
+
     /// Update the internal state of this InputManager, potentially queuing more logical events
     pub fn update(&mut self, event: &Event<()>) {
         match event {
             Event::DeviceEvent { event, .. } => self.handle_device_event(event),
+            Event::WindowEvent { event, .. } => self.handle_window_event(event),
             _ => (),
         }
     }
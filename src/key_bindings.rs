@@ -0,0 +1,147 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+
+use scancode::Scancode;
+use serde::{Deserialize, Serialize};
+
+use crate::input_manager::LogicalKey;
+
+/// Maps physical keys to the logical keys they trigger
+///
+/// Serialized as a table of logical key to key name, eg. `MoveForward = "W"`. Any logical key
+/// missing from the table keeps its default binding.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "BTreeMap<String, String>", into = "BTreeMap<String, String>")]
+pub struct KeyBindings {
+    /// Keyed by USB HID usage id, ie. `Scancode as u8`, as Scancode isn't hashable
+    bindings: HashMap<u8, LogicalKey>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        let mut bindings = Self {
+            bindings: HashMap::new(),
+        };
+
+        for &(scancode, key) in &[
+            (Scancode::W, LogicalKey::MoveForward),
+            (Scancode::A, LogicalKey::StrafeLeft),
+            (Scancode::S, LogicalKey::MoveBackward),
+            (Scancode::D, LogicalKey::StrafeRight),
+            (Scancode::Space, LogicalKey::MoveUp),
+            (Scancode::LeftControl, LogicalKey::MoveDown),
+            (Scancode::F1, LogicalKey::ToggleCalibrationScreen),
+            (Scancode::F2, LogicalKey::CycleQualityPreset),
+            (Scancode::F3, LogicalKey::ToggleCameraMode),
+            (Scancode::F4, LogicalKey::ToggleVsync),
+            (Scancode::F5, LogicalKey::ToggleStats),
+            (Scancode::F6, LogicalKey::ToggleWireframe),
+            (Scancode::F7, LogicalKey::ToggleDebugLines),
+            (Scancode::F8, LogicalKey::ToggleDebugGui),
+            (Scancode::F11, LogicalKey::CycleWindowMode),
+            (Scancode::Comma, LogicalKey::ExposureDown),
+            (Scancode::Period, LogicalKey::ExposureUp),
+            (Scancode::LeftBracket, LogicalKey::BrightnessDown),
+            (Scancode::RightBracket, LogicalKey::BrightnessUp),
+            (Scancode::Minus, LogicalKey::GammaDown),
+            (Scancode::Equals, LogicalKey::GammaUp),
+            (Scancode::PageUp, LogicalKey::ZoomIn),
+            (Scancode::PageDown, LogicalKey::ZoomOut),
+            (Scancode::Tab, LogicalKey::ToggleCursor),
+        ] {
+            bindings.bind(scancode, key);
+        }
+
+        bindings
+    }
+}
+
+impl KeyBindings {
+    /// The logical key bound to a hardware scancode, as reported by winit
+    pub fn logical_key(&self, hardware_scancode: u32) -> Option<LogicalKey> {
+        let scancode = Scancode::new(hardware_scancode as u8)?;
+        self.bindings.get(&(scancode as u8)).copied()
+    }
+
+    /// Make the given physical key trigger the given logical key, replacing whatever it was
+    /// bound to before. Other keys bound to the same logical key are unaffected.
+    pub fn bind(&mut self, scancode: Scancode, key: LogicalKey) {
+        self.bindings.insert(scancode as u8, key);
+    }
+
+    /// Replace every existing binding for the given logical key with a single physical key
+    pub fn rebind(&mut self, key: LogicalKey, scancode: Scancode) {
+        self.bindings.retain(|_, bound| *bound != key);
+        self.bind(scancode, key);
+    }
+
+    #[allow(unused)]
+    pub fn unbind(&mut self, scancode: Scancode) {
+        self.bindings.remove(&(scancode as u8));
+    }
+}
+
+/// Every key that exists on this platform's keyboard map, in a consistent order
+fn all_scancodes() -> impl Iterator<Item = Scancode> {
+    (0..=u8::MAX).filter_map(Scancode::new)
+}
+
+fn parse_key_name(name: &str) -> Option<Scancode> {
+    all_scancodes().find(|scancode| format!("{:?}", scancode) == name)
+}
+
+impl TryFrom<BTreeMap<String, String>> for KeyBindings {
+    type Error = String;
+
+    fn try_from(table: BTreeMap<String, String>) -> Result<Self, Self::Error> {
+        let mut bindings = Self::default();
+        for (key, name) in table {
+            let key = LogicalKey::from_name(&key)
+                .ok_or_else(|| format!("Unknown logical key \"{}\"", key))?;
+            let scancode =
+                parse_key_name(&name).ok_or_else(|| format!("Unknown key name \"{}\"", name))?;
+            bindings.rebind(key, scancode);
+        }
+        Ok(bindings)
+    }
+}
+
+impl From<KeyBindings> for BTreeMap<String, String> {
+    fn from(bindings: KeyBindings) -> Self {
+        // Only one key per logical key fits in the table, so take the first in scancode order
+        let mut table = BTreeMap::new();
+        for scancode in all_scancodes() {
+            if let Some(&key) = bindings.bindings.get(&(scancode as u8)) {
+                table
+                    .entry(format!("{:?}", key))
+                    .or_insert_with(|| format!("{:?}", scancode));
+            }
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_bindings_round_trip() {
+        let mut bindings = KeyBindings::default();
+        bindings.rebind(LogicalKey::MoveForward, Scancode::Up);
+
+        let text = toml::to_string(&bindings).unwrap();
+        assert!(text.contains("MoveForward = \"Up\""));
+        assert_eq!(toml::from_str::<KeyBindings>(&text).unwrap(), bindings);
+    }
+
+    #[test]
+    fn test_key_bindings_partial_table_keeps_defaults() {
+        let bindings: KeyBindings = toml::from_str("StrafeLeft = \"Q\"").unwrap();
+        let mut expected = KeyBindings::default();
+        expected.rebind(LogicalKey::StrafeLeft, Scancode::Q);
+        assert_eq!(bindings, expected);
+
+        assert!(toml::from_str::<KeyBindings>("StrafeLeft = \"NotAKey\"").is_err());
+    }
+}
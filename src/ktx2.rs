@@ -0,0 +1,141 @@
+use std::convert::TryInto;
+
+use crate::error::{Error, Result};
+
+/// The 12 bytes every KTX2 file starts with
+const IDENTIFIER: [u8; 12] = [
+    0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n',
+];
+
+/// Size of the identifier, header and index, after which the level index starts
+const LEVEL_INDEX_OFFSET: usize = 80;
+
+/// The `VkFormat`s of the only texel layouts that can be loaded
+const VK_FORMAT_R8G8B8A8_UNORM: u32 = 37;
+const VK_FORMAT_R8G8B8A8_SRGB: u32 = 43;
+
+/// Decode a texture that's either a KTX2 file or in any format the image crate understands
+pub fn decode_image(data: &[u8]) -> Result<image::RgbaImage> {
+    if data.starts_with(&IDENTIFIER) {
+        decode(data)
+    } else {
+        Ok(image::load_from_memory(data)?.to_rgba8())
+    }
+}
+
+/// Read the base mip level of a KTX2 file holding a single 2D RGBA8 image
+///
+/// The version of wgpu in use has no compressed texture formats, so block compressed data
+/// can't be uploaded as is, and there's no transcoder for Basis Universal payloads. Both are
+/// rejected rather than decoded on the CPU, which would lose the point of compressing them.
+pub fn decode(data: &[u8]) -> Result<image::RgbaImage> {
+    if !data.starts_with(&IDENTIFIER) {
+        return Err(Error::InvalidAsset("Not a KTX2 file"));
+    }
+
+    let vk_format = read_u32(data, 12)?;
+    let width = read_u32(data, 20)?;
+    let height = read_u32(data, 24)?;
+    let depth = read_u32(data, 28)?;
+    let layer_count = read_u32(data, 32)?;
+    let face_count = read_u32(data, 36)?;
+    let supercompression_scheme = read_u32(data, 44)?;
+
+    if supercompression_scheme != 0 {
+        return Err(Error::InvalidAsset("Supercompressed KTX2 textures aren't supported"));
+    }
+    match vk_format {
+        VK_FORMAT_R8G8B8A8_UNORM | VK_FORMAT_R8G8B8A8_SRGB => (),
+        // Basis Universal's UASTC is stored with an undefined format
+        0 => return Err(Error::InvalidAsset("Basis Universal KTX2 textures aren't supported")),
+        _ => return Err(Error::InvalidAsset("KTX2 texture isn't uncompressed RGBA8")),
+    }
+    if depth > 1 || layer_count > 1 || face_count != 1 {
+        return Err(Error::InvalidAsset("KTX2 texture isn't a single 2D image"));
+    }
+
+    // Levels are listed from the base level down, regardless of how they're laid out in the file
+    let level_offset = read_u64(data, LEVEL_INDEX_OFFSET)? as usize;
+    let level_length = read_u64(data, LEVEL_INDEX_OFFSET + 8)? as usize;
+    let expected_length = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|texels| texels.checked_mul(4))
+        .ok_or(Error::InvalidAsset("KTX2 texture is too large"))?;
+    if level_length != expected_length {
+        return Err(Error::InvalidAsset("KTX2 base level is the wrong size for its dimensions"));
+    }
+    let level_end = level_offset
+        .checked_add(level_length)
+        .ok_or(Error::InvalidAsset("KTX2 base level is out of bounds"))?;
+    let level = data
+        .get(level_offset..level_end)
+        .ok_or(Error::InvalidAsset("KTX2 base level is out of bounds"))?;
+
+    image::RgbaImage::from_raw(width, height, level.to_vec())
+        .ok_or(Error::InvalidAsset("KTX2 base level is the wrong size for its dimensions"))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or(Error::InvalidAsset("KTX2 header is truncated"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    let bytes = data
+        .get(offset..offset + 8)
+        .ok_or(Error::InvalidAsset("KTX2 header is truncated"))?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A KTX2 file with a single level of the given texels straight after the level index
+    fn ktx2_file(vk_format: u32, width: u32, height: u32, texels: &[u8]) -> Vec<u8> {
+        // Just the one 24 byte entry in the level index
+        let level_offset = (LEVEL_INDEX_OFFSET + 24) as u64;
+        let mut data = IDENTIFIER.to_vec();
+        for field in &[vk_format, 1, width, height, 0, 0, 1, 1, 0] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        // No data format descriptor, key/value data or supercompression global data
+        data.extend_from_slice(&[0; 32]);
+        for field in &[level_offset, texels.len() as u64, texels.len() as u64] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(texels);
+        data
+    }
+
+    #[test]
+    fn test_decode_rgba8() {
+        let texels = [255, 0, 0, 255, 0, 255, 0, 128];
+        let data = ktx2_file(VK_FORMAT_R8G8B8A8_SRGB, 2, 1, &texels);
+
+        let image = decode_image(&data).unwrap();
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(image.get_pixel(1, 0), &image::Rgba([0, 255, 0, 128]));
+    }
+
+    #[test]
+    fn test_decode_rejects_unsupported() {
+        // BC7, and a level too small for its dimensions
+        assert!(decode(&ktx2_file(145, 4, 4, &[0; 16])).is_err());
+        assert!(decode(&ktx2_file(VK_FORMAT_R8G8B8A8_UNORM, 2, 2, &[0; 8])).is_err());
+        assert!(decode(&IDENTIFIER).is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_overflowing_sizes() {
+        let data = ktx2_file(VK_FORMAT_R8G8B8A8_UNORM, u32::MAX, u32::MAX, &[0; 4]);
+        assert!(matches!(decode(&data), Err(Error::InvalidAsset(_))));
+
+        // A level that would wrap around past the end of the address space
+        let mut data = ktx2_file(VK_FORMAT_R8G8B8A8_UNORM, 1, 1, &[0; 4]);
+        data[LEVEL_INDEX_OFFSET..LEVEL_INDEX_OFFSET + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(decode(&data), Err(Error::InvalidAsset(_))));
+    }
+}
@@ -0,0 +1,35 @@
+//! A forward and deferred renderer on wgpu, along with the camera, input and asset loading it's
+//! driven with
+//!
+//! A `renderer::Renderer` is made with a `renderer::RendererBuilder`, and has models, materials,
+//! atlases and skyboxes uploaded to it, each of which is referred to afterwards by a typed handle
+//! such as `renderer::ModelId`. Every frame is then described by a
+//! `renderer::frame_packet::FramePacket` of what to draw with which handles, and drawn with
+//! `Renderer::draw_frame`.
+
+#[cfg(test)]
+#[macro_use]
+extern crate cgmath;
+
+pub mod animation;
+pub mod asset_loader;
+pub mod camera;
+pub mod collision;
+pub mod error;
+pub mod game_loop;
+pub mod input_manager;
+pub mod key_bindings;
+mod ktx2;
+pub mod mesh_gen;
+mod mesh_opt;
+pub mod model_data;
+mod obj;
+pub mod picking;
+pub mod quality;
+pub mod renderer;
+pub mod scene_data;
+mod shader_cache;
+mod shader_watcher;
+pub mod spatial;
+pub mod terrain;
+pub mod vertex;
@@ -0,0 +1,47 @@
+use crate::animation::{AnimatedSprite, FlipbookMode};
+
+/// Width/height in texels of a single frame in the loading indicator atlas
+const FRAME_SIZE: u32 = 32;
+
+const COLUMNS: u32 = 4;
+const ROWS: u32 = 2;
+
+/// Frames per second of the loading indicator
+const FPS: f32 = 12.0;
+
+/// Generates the loading indicator's flipbook, with a bright dot a further eighth of the way
+/// around a dim ring in each frame
+pub fn flipbook_image() -> image::RgbaImage {
+    let mut image = image::RgbaImage::new(FRAME_SIZE * COLUMNS, FRAME_SIZE * ROWS);
+    let frame_count = COLUMNS * ROWS;
+    let center = FRAME_SIZE as f32 / 2.0;
+    let ring_radius = FRAME_SIZE as f32 * 0.35;
+    let dot_radius = FRAME_SIZE as f32 * 0.12;
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let frame = (y / FRAME_SIZE) * COLUMNS + x / FRAME_SIZE;
+        let px = (x % FRAME_SIZE) as f32 + 0.5 - center;
+        let py = (y % FRAME_SIZE) as f32 + 0.5 - center;
+
+        let angle = frame as f32 / frame_count as f32 * std::f32::consts::TAU;
+        let (dot_x, dot_y) = (ring_radius * angle.sin(), -ring_radius * angle.cos());
+        let to_dot = ((px - dot_x).powi(2) + (py - dot_y).powi(2)).sqrt();
+        let to_ring = ((px * px + py * py).sqrt() - ring_radius).abs();
+
+        let alpha = if to_dot <= dot_radius {
+            255
+        } else if to_ring <= 1.5 {
+            64
+        } else {
+            0
+        };
+        *pixel = image::Rgba([255, 255, 255, alpha]);
+    }
+
+    image
+}
+
+/// The flipbook to play over the atlas from `flipbook_image`
+pub fn animation() -> AnimatedSprite {
+    AnimatedSprite::new(COLUMNS, ROWS, FPS, FlipbookMode::Loop)
+}
@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
+
+/// Environment variable holding tracing filter directives, eg. `debug` or
+/// `wgpu_test::renderer=trace`, which take precedence over the configured log level
+pub const LOG_ENV_VAR: &str = "WGPU_TEST_LOG";
+
+/// The least severe events that get logged
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Changes the log level after logging has started, eg. once the config has been loaded
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+
+    /// Whether the filter came from `LOG_ENV_VAR`, in which case the configured level is ignored
+    from_env: bool,
+}
+
+impl LogHandle {
+    pub fn set_level(&self, level: LogLevel) {
+        if self.from_env {
+            return;
+        }
+        if let Err(e) = self.filter.reload(level_filter(level)) {
+            tracing::warn!("Failed to change the log level: {}", e);
+        }
+    }
+}
+
+fn level_filter(level: LogLevel) -> EnvFilter {
+    EnvFilter::default().add_directive(LevelFilter::from(level).into())
+}
+
+/// Start printing log events to stdout, at the default level until `LogHandle::set_level` is
+/// called
+///
+/// Must only be called once.
+pub fn init() -> LogHandle {
+    let mut env_error = None;
+    let env_filter = match std::env::var(LOG_ENV_VAR) {
+        Ok(directives) => match EnvFilter::try_new(&directives) {
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                env_error = Some(e);
+                None
+            }
+        },
+        Err(_) => None,
+    };
+    let from_env = env_filter.is_some();
+
+    let filter = env_filter.unwrap_or_else(|| level_filter(LogLevel::default()));
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_target(false))
+        .init();
+
+    // Can't be logged until the subscriber exists
+    if let Some(e) = env_error {
+        tracing::warn!("Ignoring {}: {}", LOG_ENV_VAR, e);
+    }
+
+    LogHandle {
+        filter: handle,
+        from_env,
+    }
+}
@@ -1,83 +1,225 @@
-#[cfg(test)]
-#[macro_use]
-extern crate cgmath;
-
 use winit::{
     dpi::PhysicalSize,
     event::{self, Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{Window, WindowBuilder},
 };
-use tokio::fs::File;
-use tokio::prelude::*;
 
+// The app's own modules, on top of the library
 mod app;
-mod camera;
-mod input_manager;
-mod model_data;
-mod renderer;
-mod shader_cache;
-mod vertex;
-
-use app::App;
-use model_data::ModelData;
-use renderer::Renderer;
+mod calibration;
+mod cli;
+mod config;
+mod debug_gui;
+mod display;
+mod loading_indicator;
+mod logging;
+mod sky;
+mod stats;
+mod text_field;
+mod ui_interaction;
+mod world;
+
+// Imported at the root so that the app's modules find the library's under `crate::`
+use wgpu_test::{
+    animation, asset_loader, camera, collision, error, game_loop, input_manager, key_bindings,
+    mesh_gen, model_data, picking, quality, renderer, scene_data, spatial,
+};
+
+use app::{App, AppAssets};
+use asset_loader::AssetLoader;
+use config::{AssetPaths, Config, CONFIG_PATH};
+use error::{Error, Result};
+use game_loop::GameLoop;
+use renderer::{Renderer, RendererBuilder};
+use scene_data::SceneData;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
-use vertex::Vertex;
+use tracing::{error, info, warn};
+
+/// How often the app is ticked, independent of the frame rate
+const TICK_RATE: f32 = 120.0;
+
+/// Frames are drawn no more often than this, even without vsync
+const MAX_FRAME_RATE: f32 = 200.0;
+
+/// Environment variable that, when set, has the rolling frame profile logged every
+/// `PROFILE_LOG_INTERVAL`
+const PROFILE_ENV_VAR: &str = "WGPU_TEST_PROFILE";
+const PROFILE_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Report an error that the app can't continue after, and quit
+fn exit_with_error(error: Error) -> ! {
+    error!("{}", error);
+    std::process::exit(1);
+}
+
+/// Grab and hide the cursor so that the mouse controls the camera, or release it for the UI
+fn set_cursor_grab(window: &Window, grabbed: bool) {
+    if let Err(e) = window.set_cursor_grab(grabbed) {
+        warn!("Failed to change cursor grab: {}", e);
+    }
+    window.set_cursor_visible(!grabbed);
+}
+
+/// Start loading everything the app draws
+///
+/// The generated assets are uploaded straight away, the rest are left to stream in through the
+/// asset loader.
+fn load_assets(
+    renderer: &mut Renderer,
+    asset_loader: &mut AssetLoader,
+    paths: &AssetPaths,
+    extra_models: &[PathBuf],
+) -> Result<AppAssets> {
+    let object_scene = asset_loader.load_scene(&paths.object_scene);
+    let extra_scenes = extra_models
+        .iter()
+        .map(|path| asset_loader.load_scene(path))
+        .collect();
+    let ui_atlas = asset_loader.load_atlas(renderer, &paths.ui_atlas);
+
+    let calibration_atlas = renderer.upload_atlas(calibration::pattern_image())?;
+    let loading_atlas = renderer.upload_atlas(loading_indicator::flipbook_image())?;
+
+    let skybox = renderer.upload_skybox(sky::gradient_faces(256))?;
+
+    let ground_tile = renderer.upload_model(app::ground_tile_model())?;
+    let lod_sphere = renderer.upload_model_lods(app::lod_sphere_models())?;
+    let lod_sphere_alt_material = renderer.upload_material(&app::lod_sphere_alt_material())?;
+
+    let [width, height] = app::REAR_VIEW_SIZE;
+    let rear_view = renderer.create_render_target(PhysicalSize::new(width, height))?;
+
+    Ok(AppAssets {
+        object_scene,
+        extra_scenes,
+        ui_atlas,
+        calibration_atlas,
+        loading_atlas,
+        skybox,
+        rear_view,
+        ground_tile,
+        lod_sphere,
+        lod_sphere_alt_material,
+    })
+}
+
+/// Start loading a scene the user picked at runtime, for the app to show once it's loaded
+fn load_user_scene(app: &mut App, asset_loader: &mut AssetLoader, path: &Path) {
+    if SceneData::is_supported(path) {
+        app.drop_scene(asset_loader.load_optional_scene(path));
+    } else {
+        warn!("Can't load {}, expected a .glb, .gltf or .obj file", path.display());
+    }
+}
 
 #[tokio::main]
 async fn main() {
+    let args = match cli::Args::parse(std::env::args_os().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+
+    let logging = logging::init();
+    let mut config = Config::load(CONFIG_PATH);
+    logging.set_level(config.log_level);
+
+    // Command line options only apply to this run, so are kept out of the config that's saved
+    let mut startup_config = config.clone();
+    args.apply(&mut startup_config);
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new()
-        .with_resizable(false)
-        .with_inner_size(PhysicalSize {
-            width: 1920,
-            height: 1080,
-        })
+        .with_inner_size(startup_config.display.window_size())
         .with_title("wgpu-test")
         .build(&event_loop)
-        .unwrap();
+        .unwrap_or_else(|e| exit_with_error(e.into()));
+    window.set_fullscreen(startup_config.display.fullscreen(&window));
 
-    window.set_cursor_grab(true).expect("Failed to grab cursor");
-    window.set_cursor_visible(false);
+    let mut quality = config.graphics_quality.render_quality();
+    args.apply_quality(&mut quality);
+    let builder = RendererBuilder::from_config(startup_config.renderer_config(&quality));
+    let mut renderer = match builder.build(&window).await {
+        Ok(renderer) => renderer,
+        Err(e) => exit_with_error(e),
+    };
+    if let Err(e) = renderer.apply_quality(&quality) {
+        warn!("Failed to apply graphics quality: {}", e);
+    }
+    renderer.set_tonemapper(config.tonemapper);
+    renderer.set_color_filter(config.accessibility.color_filter);
+    renderer.set_high_contrast_ui(config.accessibility.high_contrast_ui);
 
-    let mut renderer = Renderer::new(&window).await;
+    let mut asset_loader = AssetLoader::new();
+    let assets = match load_assets(
+        &mut renderer,
+        &mut asset_loader,
+        &startup_config.assets,
+        args.extra_models(),
+    ) {
+        Ok(assets) => assets,
+        Err(e) => exit_with_error(e),
+    };
 
-    let model_id = renderer.upload_model(
-        ModelData::load_gltf("./AntiqueCamera.glb")
-            .await
-            .expect("Failed to load model from disk"),
+    let mut app = App::new(
+        assets,
+        window.inner_size(),
+        window.scale_factor(),
+        &startup_config,
     );
+    set_cursor_grab(&window, true);
+    app.set_cursor_grabbed(true);
 
-    let atlas_id;
-    {
-        let mut atlas_file = File::open("./atlas.png")
-            .await
-            .expect("Failed to open atlas file");
-        let mut atlas_data = Vec::new();
-        atlas_file.read_to_end(&mut atlas_data)
-            .await
-            .expect("Failed to read atlas file");
-        let atlas_data = image::load_from_memory(&atlas_data)
-            .expect("Failed to parse atlas file");
-        atlas_id = renderer.upload_atlas(atlas_data.to_rgba());
-    }
-
-    let mut app = App::new(model_id, atlas_id);
-
+    let mut game_loop = GameLoop::new(Duration::from_secs_f32(1.0 / TICK_RATE));
     let mut last_update_inst = Instant::now();
+    let mut last_redraw_inst = Instant::now();
+    let log_profile = std::env::var_os(PROFILE_ENV_VAR).is_some();
+    let mut last_profile_log_inst = Instant::now();
+    let mut frame_index: u64 = 0;
+    let mut last_ime_position = None;
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::WaitUntil(Instant::now() + Duration::from_millis(1));
 
         match event {
             Event::MainEventsCleared => {
-                if last_update_inst.elapsed() > Duration::from_secs_f32(1.0 / 200.0) {
-                    app.tick(last_update_inst.elapsed());
-                    last_update_inst = Instant::now();
+                let now = Instant::now();
+                for _ in 0..game_loop.advance(now - last_update_inst) {
+                    let tick_start = Instant::now();
+                    app.tick(game_loop.timestep());
+                    renderer.record_cpu_scope("tick", tick_start.elapsed());
+                }
+                last_update_inst = now;
+
+                if let Some(grabbed) = app.take_cursor_grab_change() {
+                    set_cursor_grab(&window, grabbed);
+                }
+
+                let ime_position = app.ime_position(|text, size| renderer.text_width(text, size));
+                if ime_position != last_ime_position {
+                    if let Some(position) = ime_position {
+                        window.set_ime_position(position);
+                    }
+                    last_ime_position = ime_position;
+                }
+
+                if now - last_redraw_inst > Duration::from_secs_f32(1.0 / MAX_FRAME_RATE) {
+                    last_redraw_inst = now;
                     window.request_redraw();
                 }
             }
-            Event::WindowEvent { event, .. } => match event {
+            Event::WindowEvent {
+                event: ref window_event,
+                ..
+            } => match window_event {
+                // Escape cancels typing in to a text field instead, which the app handles
                 WindowEvent::KeyboardInput {
                     input:
                         event::KeyboardInput {
@@ -86,15 +228,116 @@ async fn main() {
                             ..
                         },
                     ..
+                } if !app.text_input_active() => {
+                    *control_flow = ControlFlow::Exit;
                 }
-                | WindowEvent::CloseRequested => {
+                WindowEvent::CloseRequested => {
                     *control_flow = ControlFlow::Exit;
                 }
-                _ => (),
+                WindowEvent::Focused(focused) => {
+                    // Some platforms drop the grab along with focus, so take it back
+                    if *focused && app.cursor_grabbed() {
+                        set_cursor_grab(&window, true);
+                    }
+                    app.handle_event(&event);
+                }
+                WindowEvent::DroppedFile(path) => {
+                    load_user_scene(&mut app, &mut asset_loader, path);
+                }
+                WindowEvent::Resized(size) => {
+                    renderer.resize(*size);
+                    app.set_screen_metrics(*size, window.scale_factor());
+                }
+                WindowEvent::ScaleFactorChanged {
+                    scale_factor,
+                    new_inner_size,
+                } => {
+                    // Accept the size suggested by the OS, which keeps the window the same
+                    // logical size on the new monitor
+                    renderer.resize(**new_inner_size);
+                    app.set_screen_metrics(**new_inner_size, *scale_factor);
+                }
+                _ => app.handle_event(&event),
             },
+            Event::LoopDestroyed => renderer.save_pipeline_cache(),
             event::Event::RedrawRequested(_) => {
-                let frame_packet = app.generate_frame_packet(renderer.aspect_ratio());
-                renderer.draw_frame(&frame_packet);
+                let _frame_span = tracing::info_span!("frame", index = frame_index).entered();
+                frame_index += 1;
+
+                if let Some(window_mode) = app.take_window_mode_change() {
+                    config.display.window_mode = window_mode;
+                    window.set_fullscreen(config.display.fullscreen(&window));
+                    config.save(CONFIG_PATH);
+                }
+
+                // Nothing to draw in to while minimized
+                let size = window.inner_size();
+                if size.width == 0 || size.height == 0 {
+                    return;
+                }
+
+                // Not every platform sends a resize event when switching in and out of
+                // fullscreen, so make sure the renderer has caught up. This does nothing if it has.
+                renderer.resize(size);
+                app.set_screen_metrics(size, window.scale_factor());
+
+                if let Some(preset) = app.take_quality_change() {
+                    let mut quality = preset.render_quality();
+                    args.apply_quality(&mut quality);
+                    if let Err(e) = renderer.apply_quality(&quality) {
+                        warn!("Failed to apply graphics quality: {}", e);
+                    }
+                    config.graphics_quality = preset;
+                    config.save(CONFIG_PATH);
+                }
+                if let Some(wireframe) = app.take_wireframe_change() {
+                    if let Err(e) = renderer.set_wireframe(wireframe) {
+                        warn!("Failed to switch wireframe mode: {}", e);
+                    }
+                }
+                if let Some(present_mode) = app.take_present_mode_change() {
+                    renderer.set_present_mode(present_mode);
+                    config.present_mode = present_mode;
+                    config.save(CONFIG_PATH);
+                }
+                if let Some(path) = app.take_scene_request() {
+                    load_user_scene(&mut app, &mut asset_loader, &path);
+                }
+                if let Some(accessibility) = app.take_accessibility_change() {
+                    renderer.set_color_filter(accessibility.color_filter);
+                    renderer.set_high_contrast_ui(accessibility.high_contrast_ui);
+                    config.accessibility = accessibility;
+                    config.save(CONFIG_PATH);
+                }
+
+                match asset_loader.poll(&mut renderer) {
+                    Ok(loaded_scenes) => {
+                        for scene in loaded_scenes {
+                            app.scene_loaded(scene);
+                        }
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                }
+
+                let frame_packet_start = Instant::now();
+                let frame_packet =
+                    app.generate_frame_packet(renderer.aspect_ratio(), game_loop.alpha());
+                renderer.record_cpu_scope("frame packet", frame_packet_start.elapsed());
+                renderer.set_output_calibration(app.output_calibration());
+                if let Err(e) = renderer.draw_frame(&frame_packet) {
+                    error!("{}", e);
+                    *control_flow = ControlFlow::Exit;
+                }
+                app.record_frame_stats(renderer.frame_stats());
+
+                if log_profile && last_profile_log_inst.elapsed() > PROFILE_LOG_INTERVAL {
+                    last_profile_log_inst = Instant::now();
+                    info!("Frame profile:\n{}", renderer.rolling_frame_profile());
+                }
             }
             _ => app.handle_event(&event),
         }
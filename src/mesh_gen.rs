@@ -0,0 +1,261 @@
+use std::f32::consts::PI;
+
+use cgmath::Vector3;
+
+use crate::model_data::{MaterialData, MaterialFactors, ModelData};
+use crate::vertex::Vertex;
+
+/// Accumulates the vertices and triangles of a generated mesh
+#[derive(Default)]
+struct MeshBuilder {
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+}
+
+impl MeshBuilder {
+    fn vertex(&mut self, position: Vector3<f32>, normal: Vector3<f32>, texcoord: [f32; 2]) -> u32 {
+        self.vertices.push(Vertex {
+            position: position.into(),
+            normal: normal.into(),
+            texcoord,
+            lightmap_texcoord: texcoord,
+            color: Vertex::WHITE,
+            joints: [0; 4],
+            weights: [0.0; 4],
+        });
+        (self.vertices.len() - 1) as u32
+    }
+
+    /// Two triangles covering a quad whose corners are given counter-clockwise from the front
+    fn quad(&mut self, a: u32, b: u32, c: u32, d: u32) {
+        self.indices.extend_from_slice(&[a, b, c, a, c, d]);
+    }
+
+    /// Sweep rows of vertices around the Z axis, each given as its angle down from +Z and its
+    /// offset along Z, stitching neighbouring rows together
+    ///
+    /// The first and last rows are taken to be poles, where the triangles that would collapse to
+    /// nothing are left out.
+    fn lathe(&mut self, radius: f32, segments: u32, rows: &[(f32, f32)]) {
+        let segments = segments.max(3);
+        let first = self.vertices.len() as u32;
+        for (row, &(theta, z_offset)) in rows.iter().enumerate() {
+            for segment in 0..=segments {
+                let phi = 2.0 * PI * segment as f32 / segments as f32;
+                let normal =
+                    Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                let position = normal * radius + Vector3::new(0.0, 0.0, z_offset);
+                let texcoord = [
+                    segment as f32 / segments as f32,
+                    row as f32 / (rows.len() - 1) as f32,
+                ];
+                self.vertex(position, normal, texcoord);
+            }
+        }
+
+        let row_len = segments + 1;
+        let last_band = rows.len() as u32 - 2;
+        for band in 0..=last_band {
+            for segment in 0..segments {
+                let a = first + band * row_len + segment;
+                let b = a + row_len;
+                let c = b + 1;
+                let d = a + 1;
+                if band != last_band {
+                    self.indices.extend_from_slice(&[a, b, c]);
+                }
+                if band != 0 {
+                    self.indices.extend_from_slice(&[a, c, d]);
+                }
+            }
+        }
+    }
+
+    /// A plain white, fully rough and non-metallic model, for the caller to change the material of
+    fn build(self) -> ModelData {
+        ModelData {
+            vertices: self.vertices,
+            indices: self.indices,
+            material: MaterialData::from_factors(MaterialFactors {
+                metallic: 0.0,
+                roughness: 1.0,
+                ..MaterialFactors::default()
+            }),
+            skinned: false,
+        }
+    }
+}
+
+// Every shape is centered on the origin with +Z up, matching the world, and texcoords covering
+// the whole texture once.
+
+/// A flat rectangle in the XY plane facing +Z, split in to a grid of `subdivisions` squares along
+/// each side
+pub fn plane(width: f32, depth: f32, subdivisions: u32) -> ModelData {
+    let subdivisions = subdivisions.max(1);
+    let mut builder = MeshBuilder::default();
+    for j in 0..=subdivisions {
+        for i in 0..=subdivisions {
+            let u = i as f32 / subdivisions as f32;
+            let v = j as f32 / subdivisions as f32;
+            let position = Vector3::new((u - 0.5) * width, (v - 0.5) * depth, 0.0);
+            builder.vertex(position, Vector3::unit_z(), [u, 1.0 - v]);
+        }
+    }
+
+    let row_len = subdivisions + 1;
+    for j in 0..subdivisions {
+        for i in 0..subdivisions {
+            let a = j * row_len + i;
+            builder.quad(a, a + 1, a + row_len + 1, a + row_len);
+        }
+    }
+
+    builder.build()
+}
+
+/// An axis aligned cube, with separate vertices for each face so that its edges stay sharp
+#[allow(unused)]
+pub fn cube(size: f32) -> ModelData {
+    let half_size = size / 2.0;
+    let (x, y, z) = (Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z());
+
+    // Each face's normal, and its right and up directions when looking at it
+    let faces = [(x, y, z), (-x, -y, z), (y, -x, z), (-y, x, z), (z, x, y), (-z, x, -y)];
+
+    let mut builder = MeshBuilder::default();
+    for &(normal, right, up) in &faces {
+        let corner = |u: f32, v: f32| (normal + right * u + up * v) * half_size;
+        let a = builder.vertex(corner(-1.0, -1.0), normal, [0.0, 1.0]);
+        let b = builder.vertex(corner(1.0, -1.0), normal, [1.0, 1.0]);
+        let c = builder.vertex(corner(1.0, 1.0), normal, [1.0, 0.0]);
+        let d = builder.vertex(corner(-1.0, 1.0), normal, [0.0, 0.0]);
+        builder.quad(a, b, c, d);
+    }
+
+    builder.build()
+}
+
+/// A sphere made of `segments` slices around the Z axis and `rings` bands from pole to pole
+#[allow(unused)]
+pub fn uv_sphere(radius: f32, segments: u32, rings: u32) -> ModelData {
+    let rings = rings.max(2);
+    let rows: Vec<_> = (0..=rings).map(|ring| (PI * ring as f32 / rings as f32, 0.0)).collect();
+
+    let mut builder = MeshBuilder::default();
+    builder.lathe(radius, segments, &rows);
+    builder.build()
+}
+
+/// A closed cylinder of the given height along the Z axis
+#[allow(unused)]
+pub fn cylinder(radius: f32, height: f32, segments: u32) -> ModelData {
+    let segments = segments.max(3);
+    let half_height = height / 2.0;
+    let mut builder = MeshBuilder::default();
+
+    // Sides
+    for segment in 0..=segments {
+        let phi = 2.0 * PI * segment as f32 / segments as f32;
+        let normal = Vector3::new(phi.cos(), phi.sin(), 0.0);
+        let u = segment as f32 / segments as f32;
+        builder.vertex(normal * radius - Vector3::unit_z() * half_height, normal, [u, 1.0]);
+        builder.vertex(normal * radius + Vector3::unit_z() * half_height, normal, [u, 0.0]);
+    }
+    for segment in 0..segments {
+        let bottom = segment * 2;
+        builder.quad(bottom, bottom + 2, bottom + 3, bottom + 1);
+    }
+
+    // Caps, each a fan around its center
+    for &side in &[1.0f32, -1.0] {
+        let normal = Vector3::unit_z() * side;
+        let center = builder.vertex(normal * half_height, normal, [0.5, 0.5]);
+        for segment in 0..=segments {
+            let phi = 2.0 * PI * segment as f32 / segments as f32;
+            let (sin, cos) = phi.sin_cos();
+            let position = Vector3::new(cos * radius, sin * radius, side * half_height);
+            builder.vertex(position, normal, [0.5 + 0.5 * cos, 0.5 - 0.5 * sin * side]);
+        }
+        for segment in 0..segments {
+            let (a, b) = (center + 1 + segment, center + 2 + segment);
+            let triangle = if side > 0.0 { [center, a, b] } else { [center, b, a] };
+            builder.indices.extend_from_slice(&triangle);
+        }
+    }
+
+    builder.build()
+}
+
+/// A cylinder of the given length along the Z axis, capped with hemispheres of `rings` bands each
+///
+/// The overall length is `length + 2 * radius`.
+#[allow(unused)]
+pub fn capsule(radius: f32, length: f32, segments: u32, rings: u32) -> ModelData {
+    let rings = rings.max(1);
+    let half_length = length / 2.0;
+    let hemisphere = |ring: u32| PI / 2.0 * ring as f32 / rings as f32;
+
+    // The top hemisphere's last row and the bottom hemisphere's first are both on the equator, so
+    // the band between them forms the sides
+    let rows: Vec<_> = (0..=rings)
+        .map(|ring| (hemisphere(ring), half_length))
+        .chain((0..=rings).map(|ring| (PI / 2.0 + hemisphere(ring), -half_length)))
+        .collect();
+
+    let mut builder = MeshBuilder::default();
+    builder.lathe(radius, segments, &rows);
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+
+    /// Every normal is unit length, and every triangle winds counter-clockwise when seen from the
+    /// side its vertices' normals point to
+    fn assert_well_formed(model: &ModelData) {
+        assert!(!model.indices.is_empty());
+        assert_eq!(model.indices.len() % 3, 0);
+
+        for vertex in &model.vertices {
+            assert!((Vector3::from(vertex.normal).magnitude() - 1.0).abs() < 1e-5);
+        }
+
+        for triangle in model.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| &model.vertices[triangle[i] as usize]);
+            let face_normal = (Vector3::from(b.position) - Vector3::from(a.position))
+                .cross(Vector3::from(c.position) - Vector3::from(a.position));
+            let vertex_normals =
+                Vector3::from(a.normal) + Vector3::from(b.normal) + Vector3::from(c.normal);
+            assert!(face_normal.dot(vertex_normals) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_shapes_are_well_formed() {
+        assert_well_formed(&plane(2.0, 3.0, 4));
+        assert_well_formed(&cube(1.0));
+        assert_well_formed(&uv_sphere(1.0, 12, 6));
+        assert_well_formed(&cylinder(0.5, 2.0, 12));
+        assert_well_formed(&capsule(0.5, 1.0, 12, 4));
+    }
+
+    #[test]
+    fn test_normals_point_outwards() {
+        let sphere = uv_sphere(2.0, 8, 4);
+        for vertex in &sphere.vertices {
+            let position = Vector3::from(vertex.position);
+            assert!((position.magnitude() - 2.0).abs() < 1e-5);
+            assert!((position / 2.0 - Vector3::from(vertex.normal)).magnitude() < 1e-5);
+        }
+
+        let capsule = capsule(0.5, 1.0, 8, 3);
+        for vertex in &capsule.vertices {
+            let position = Vector3::from(vertex.position);
+            assert!(position.z.abs() <= 1.0 + 1e-5);
+            assert!(position.dot(Vector3::from(vertex.normal)) > 0.0);
+        }
+    }
+}
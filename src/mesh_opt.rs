@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::vertex::Vertex;
+
+/// Entries in the simulated post-transform cache that `optimize_vertex_cache` orders triangles
+/// for. Larger than most hardware's, as orders tuned for a larger cache still do well on smaller
+/// ones but not the other way around.
+const OPTIMIZE_CACHE_SIZE: usize = 32;
+
+/// Entries in the FIFO cache that `MeshStats` counts misses of, typical of real hardware
+const STATS_CACHE_SIZE: usize = 16;
+
+/// How a mesh changed when it was optimized
+///
+/// Misses are of a simulated post-transform vertex cache, so dividing them by the triangle count
+/// gives the average cache miss ratio (ACMR). That's 3 at worst, and 0.5 is about the best a
+/// regular grid can do. Stats for several meshes can be summed to report them together.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MeshStats {
+    pub triangles: usize,
+    pub vertices_before: usize,
+    pub vertices_after: usize,
+    pub cache_misses_before: usize,
+    pub cache_misses_after: usize,
+}
+
+impl MeshStats {
+    fn acmr(&self, cache_misses: usize) -> f32 {
+        cache_misses as f32 / self.triangles.max(1) as f32
+    }
+}
+
+impl std::ops::AddAssign for MeshStats {
+    fn add_assign(&mut self, other: Self) {
+        self.triangles += other.triangles;
+        self.vertices_before += other.vertices_before;
+        self.vertices_after += other.vertices_after;
+        self.cache_misses_before += other.cache_misses_before;
+        self.cache_misses_after += other.cache_misses_after;
+    }
+}
+
+impl fmt::Display for MeshStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} triangles, {} -> {} vertices, ACMR {:.2} -> {:.2}",
+            self.triangles,
+            self.vertices_before,
+            self.vertices_after,
+            self.acmr(self.cache_misses_before),
+            self.acmr(self.cache_misses_after),
+        )
+    }
+}
+
+/// Merge bitwise identical vertices, leaving the rest in the order the indices first use them
+///
+/// Vertices that no triangle uses are dropped along the way.
+pub fn deduplicate_vertices(vertices: &[Vertex], indices: &[u32]) -> (Vec<Vertex>, Vec<u32>) {
+    let mut unique_vertices = Vec::new();
+    let mut remap: HashMap<&[u8], u32> = HashMap::new();
+
+    let new_indices = indices
+        .iter()
+        .map(|&index| {
+            let vertex = &vertices[index as usize];
+            *remap.entry(bytemuck::bytes_of(vertex)).or_insert_with(|| {
+                unique_vertices.push(*vertex);
+                unique_vertices.len() as u32 - 1
+            })
+        })
+        .collect();
+
+    (unique_vertices, new_indices)
+}
+
+/// Round every attribute to the precision a compact vertex format would hold, so that vertices
+/// differing by less than that are merged by `deduplicate_vertices`
+///
+/// The vertex layout stays as 32 bit floats, so this doesn't make the vertices any smaller.
+/// Positions are rounded to a tenth of a millimetre, normals and colors to 8 bits and both
+/// texcoord sets to 16 bits. Joints and weights are left alone, as skinning is too sensitive to
+/// them.
+pub fn quantize_vertices(vertices: &mut [Vertex]) {
+    fn round(value: f32, steps: f32) -> f32 {
+        (value * steps).round() / steps
+    }
+
+    for vertex in vertices {
+        for x in &mut vertex.position {
+            *x = round(*x, 10_000.0);
+        }
+        for x in &mut vertex.normal {
+            *x = round(*x, 127.0);
+        }
+        for x in vertex.texcoord.iter_mut().chain(&mut vertex.lightmap_texcoord) {
+            *x = round(*x, 65_535.0);
+        }
+        for x in &mut vertex.color {
+            *x = round(*x, 255.0);
+        }
+    }
+}
+
+/// Reorder triangles so that consecutive ones share as many vertices as possible, letting the
+/// GPU reuse more of the vertex shader's results
+///
+/// This is Tom Forsyth's linear-speed vertex cache optimisation. Each triangle's winding is
+/// kept as is.
+pub fn optimize_vertex_cache(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+
+    // Triangles that each vertex is part of and haven't been output yet
+    let mut vertex_triangles: Vec<Vec<usize>> = vec![Vec::new(); vertex_count];
+    for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+        for &index in corners {
+            vertex_triangles[index as usize].push(triangle);
+        }
+    }
+
+    let mut vertex_score: Vec<f32> = (0..vertex_count)
+        .map(|vertex| forsyth_score(None, vertex_triangles[vertex].len()))
+        .collect();
+    let triangle_score = |vertex_score: &[f32], triangle: usize| -> f32 {
+        indices[3 * triangle..3 * triangle + 3]
+            .iter()
+            .map(|&index| vertex_score[index as usize])
+            .sum()
+    };
+    let mut triangle_added = vec![false; triangle_count];
+
+    let mut cache: Vec<u32> = Vec::with_capacity(OPTIMIZE_CACHE_SIZE + 3);
+    let mut output = Vec::with_capacity(indices.len());
+
+    // The next triangle to output, from those touching the cache, or None to search them all
+    let mut best_triangle: Option<usize> = None;
+    let mut search_from = 0;
+    for _ in 0..triangle_count {
+        let triangle = match best_triangle {
+            Some(triangle) => triangle,
+            None => {
+                // Nothing in the cache has triangles left, so start afresh from the best of the
+                // rest. Triangles before `search_from` have all been added already.
+                while triangle_added[search_from] {
+                    search_from += 1;
+                }
+                (search_from..triangle_count)
+                    .filter(|&triangle| !triangle_added[triangle])
+                    .max_by(|&a, &b| {
+                        triangle_score(&vertex_score, a)
+                            .partial_cmp(&triangle_score(&vertex_score, b))
+                            .unwrap()
+                    })
+                    .unwrap()
+            }
+        };
+
+        triangle_added[triangle] = true;
+        let corners = &indices[3 * triangle..3 * triangle + 3];
+        output.extend_from_slice(corners);
+
+        // The triangle's vertices move to the front of the cache, pushing the rest back
+        for &index in corners {
+            vertex_triangles[index as usize].retain(|&other| other != triangle);
+        }
+        let mut new_cache: Vec<u32> = corners.to_vec();
+        new_cache.extend(cache.iter().copied().filter(|index| !corners.contains(index)));
+        for (position, &index) in new_cache.iter().enumerate() {
+            let position = Some(position).filter(|&position| position < OPTIMIZE_CACHE_SIZE);
+            vertex_score[index as usize] =
+                forsyth_score(position, vertex_triangles[index as usize].len());
+        }
+        new_cache.truncate(OPTIMIZE_CACHE_SIZE);
+        cache = new_cache;
+
+        best_triangle = cache
+            .iter()
+            .flat_map(|&index| vertex_triangles[index as usize].iter().copied())
+            .max_by(|&a, &b| {
+                triangle_score(&vertex_score, a)
+                    .partial_cmp(&triangle_score(&vertex_score, b))
+                    .unwrap()
+            });
+    }
+
+    output
+}
+
+/// How much adding a triangle using the vertex is worth, given where it is in the cache and how
+/// many triangles still need it
+fn forsyth_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32 {
+    const CACHE_DECAY_POWER: f32 = 1.5;
+    const LAST_TRIANGLE_SCORE: f32 = 0.75;
+    const VALENCE_BOOST_SCALE: f32 = 2.0;
+    const VALENCE_BOOST_POWER: f32 = 0.5;
+
+    if remaining_triangles == 0 {
+        return -1.0;
+    }
+
+    let cache_score = match cache_position {
+        // The last triangle's vertices are scored the same, so that the order it was output in
+        // doesn't favour one of its edges
+        Some(position) if position < 3 => LAST_TRIANGLE_SCORE,
+        Some(position) => {
+            let scale = 1.0 / (OPTIMIZE_CACHE_SIZE - 3) as f32;
+            (1.0 - (position - 3) as f32 * scale).powf(CACHE_DECAY_POWER)
+        }
+        None => 0.0,
+    };
+
+    // Vertices with few triangles left are worth finishing off, so they can leave the cache
+    let valence_boost =
+        VALENCE_BOOST_SCALE * (remaining_triangles as f32).powf(-VALENCE_BOOST_POWER);
+
+    cache_score + valence_boost
+}
+
+/// How many vertices a FIFO post-transform cache of `STATS_CACHE_SIZE` would have to shade
+/// drawing the indices
+pub fn cache_misses(indices: &[u32]) -> usize {
+    let mut cache = std::collections::VecDeque::with_capacity(STATS_CACHE_SIZE);
+    let mut misses = 0;
+    for &index in indices {
+        if !cache.contains(&index) {
+            misses += 1;
+            if cache.len() == STATS_CACHE_SIZE {
+                cache.pop_front();
+            }
+            cache.push_back(index);
+        }
+    }
+    misses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh_gen;
+
+    /// Each triangle as its indices rotated to start from the smallest, sorted, for comparing
+    /// triangle lists regardless of order
+    fn normalized_triangles(indices: &[u32]) -> Vec<[u32; 3]> {
+        let mut triangles: Vec<_> = indices
+            .chunks_exact(3)
+            .map(|t| {
+                let start = (0..3).min_by_key(|&i| t[i]).unwrap();
+                [t[start], t[(start + 1) % 3], t[(start + 2) % 3]]
+            })
+            .collect();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn test_deduplicate_vertices() {
+        let cube = mesh_gen::cube(1.0);
+        let mut vertices = cube.vertices.clone();
+        vertices.extend_from_slice(&cube.vertices);
+        let offset = cube.vertices.len() as u32;
+        let indices: Vec<u32> = cube.indices.iter().map(|&index| index + offset).collect();
+
+        let (deduplicated, remapped) = deduplicate_vertices(&vertices, &indices);
+        assert_eq!(deduplicated.len(), cube.vertices.len());
+        for (&old, &new) in indices.iter().zip(&remapped) {
+            assert_eq!(vertices[old as usize].position, deduplicated[new as usize].position);
+        }
+    }
+
+    #[test]
+    fn test_optimize_vertex_cache() {
+        let plane = mesh_gen::plane(1.0, 1.0, 32);
+        // Shuffle the triangles, so that the original order doesn't already do well
+        let triangle_count = plane.indices.len() / 3;
+        let mut shuffled = Vec::new();
+        for triangle in (0..triangle_count).map(|i| i * 7919 % triangle_count) {
+            shuffled.extend_from_slice(&plane.indices[3 * triangle..3 * triangle + 3]);
+        }
+
+        let optimized = optimize_vertex_cache(&shuffled, plane.vertices.len());
+        assert_eq!(normalized_triangles(&optimized), normalized_triangles(&shuffled));
+
+        let triangles = (optimized.len() / 3) as f32;
+        assert!(cache_misses(&optimized) as f32 / triangles < 1.0);
+        assert!(cache_misses(&optimized) < cache_misses(&shuffled));
+    }
+}
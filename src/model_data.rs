@@ -1,109 +1,464 @@
 use std::path::Path;
 use tokio::fs::File;
 use tokio::prelude::*;
+use tracing::{info, warn};
 
-use super::Vertex;
+use crate::error::{Error, Result};
+use crate::ktx2;
+use crate::mesh_opt::{self, MeshStats};
+use crate::obj;
+use crate::vertex::Vertex;
+
+/// How a material's base color alpha is used, following glTF's alphaMode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// Alpha is ignored and the surface fully covers whatever is behind it
+    #[default]
+    Opaque,
+
+    /// The surface is opaque wherever its alpha is at least `MaterialFactors::alpha_cutoff`, and
+    /// missing everywhere else
+    Mask,
+
+    /// The surface is blended over whatever is behind it by its alpha
+    Blend,
+}
+
+/// The scalar parts of a glTF metallic-roughness material
+///
+/// Each factor multiplies the matching texture, or stands in for it when the model doesn't have
+/// one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaterialFactors {
+    /// Linear RGBA
+    pub base_color: [f32; 4],
+    pub metallic: f32,
+    pub roughness: f32,
+
+    /// Scales the X/Y of the tangent space normals read from the normal texture
+    pub normal_scale: f32,
+
+    /// How much of the occlusion texture to apply, from 0 (none) to 1 (all)
+    pub occlusion_strength: f32,
+
+    /// Linear RGB
+    pub emissive: [f32; 3],
+
+    pub alpha_mode: AlphaMode,
+
+    /// Alpha below which an `AlphaMode::Mask` surface is cut out
+    pub alpha_cutoff: f32,
+}
+
+impl Default for MaterialFactors {
+    /// The defaults from the glTF spec
+    fn default() -> Self {
+        Self {
+            base_color: [1.0, 1.0, 1.0, 1.0],
+            metallic: 1.0,
+            roughness: 1.0,
+            normal_scale: 1.0,
+            occlusion_strength: 1.0,
+            emissive: [0.0, 0.0, 0.0],
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+        }
+    }
+}
+
+/// The textures and factors of a single material on the CPU
+///
+/// Every model comes with one, but they can also be uploaded on their own to draw any model with.
+pub struct MaterialData {
+    /// sRGB encoded base color
+    pub base_color_texture: image::RgbaImage,
+
+    /// Roughness in the green channel and metalness in the blue channel
+    pub metallic_roughness_texture: Option<image::RgbaImage>,
+
+    /// Tangent space normals
+    pub normal_texture: Option<image::RgbaImage>,
+
+    /// Ambient occlusion in the red channel
+    pub occlusion_texture: Option<image::RgbaImage>,
+
+    /// sRGB encoded emitted color
+    pub emissive_texture: Option<image::RgbaImage>,
+
+    /// sRGB encoded light baked on to the surface, sampled with the vertices' second texcoord
+    /// set. Replaces the flat ambient light when present.
+    pub lightmap_texture: Option<image::RgbaImage>,
+
+    pub factors: MaterialFactors,
+}
+
+impl MaterialData {
+    /// An untextured material, colored by its factors alone
+    pub fn from_factors(factors: MaterialFactors) -> Self {
+        Self {
+            base_color_texture: image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4])),
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+            lightmap_texture: None,
+            factors,
+        }
+    }
+}
 
 /// Represents the data for a single model on the CPU
 pub struct ModelData {
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
-    pub texture: image::RgbaImage,
+
+    pub material: MaterialData,
+
+    /// Whether the vertices have joints and weights to be deformed by a skin
+    pub skinned: bool,
 }
 
 impl ModelData {
-    // TODO: Proper error type
     /// Load a model from a GLTF file.
     ///
-    /// The file must contain only a single mesh, made from a single primitive.
-    pub async fn load_gltf<P: AsRef<Path>>(path: P) -> Result<Self, &'static str> {
+    /// The file must contain only a single mesh, made from a single primitive. Use
+    /// `SceneData::load_gltf` for anything more complex.
+    #[allow(unused)]
+    pub async fn load_gltf<P: AsRef<Path>>(path: P) -> Result<Self> {
         let path = path.as_ref();
 
         let mut file_content = Vec::new();
         {
-            let mut file = File::open(path)
-                .await
-                .map_err(|_| "Failed to open model file")?;
+            let mut file = File::open(path).await.map_err(Error::io(path))?;
 
             file.read_to_end(&mut file_content)
                 .await
-                .map_err(|_| "Failed to read model data")?;
+                .map_err(Error::io(path))?;
         }
 
-        let (doc, buffers, images) =
-            gltf::import_slice(&file_content).map_err(|_| "Failed to parse GLTF file")?;
+        let (doc, buffers, images) = gltf::import_slice(&file_content)?;
 
         if doc.meshes().len() < 1 {
-            return Err("Expected a GLTF file with at least one mesh");
+            return Err(Error::InvalidAsset("Expected a GLTF file with at least one mesh"));
         } else if doc.meshes().len() > 1 {
-            println!("WARN: GLTF file has multiple meshes, only loading the first")
+            warn!("GLTF file has multiple meshes, only loading the first")
         }
         let mesh = doc.meshes().next().unwrap();
 
         if mesh.primitives().len() < 1 {
-            return Err("Expected a GLTF mesh with at least one primitive");
+            return Err(Error::InvalidAsset("Expected a GLTF mesh with at least one primitive"));
         } else if mesh.primitives().len() > 1 {
-            println!("WARN: mesh has multiple primitives, only loading the first")
+            warn!("mesh has multiple primitives, only loading the first")
         }
         let primitive = mesh.primitives().next().unwrap();
 
+        let mut model = Self::from_gltf_primitive(&primitive, &buffers, &images)?;
+        let stats = model.optimize(false);
+        info!("Optimized {}: {}", path.display(), stats);
+        Ok(model)
+    }
+
+    /// Load a model from a Wavefront OBJ file, along with the diffuse color and texture of its
+    /// material from any MTL file it references
+    ///
+    /// OBJ materials aren't physically based, so the model is shaded as fully rough and
+    /// non-metallic.
+    pub async fn load_obj<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let source = tokio::fs::read_to_string(path).await.map_err(Error::io(path))?;
+        let mesh = obj::parse_obj(&source)?;
+
+        // Material and texture paths are relative to the file that names them
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut material = None;
+        if let Some(name) = &mesh.material {
+            for lib in &mesh.material_libs {
+                let lib_path = dir.join(lib);
+                let source =
+                    tokio::fs::read_to_string(&lib_path).await.map_err(Error::io(&lib_path))?;
+                let found = obj::parse_mtl(&source)?.into_iter().find(|m| &m.name == name);
+                if let Some(found) = found {
+                    material = Some((found, lib_path));
+                    break;
+                }
+            }
+            if material.is_none() {
+                warn!("OBJ material {} not found, using a plain white material", name);
+            }
+        }
+
+        let texture_path = material.as_ref().and_then(|(material, lib_path)| {
+            let texture = material.diffuse_texture.as_ref()?;
+            Some(lib_path.parent().unwrap_or_else(|| Path::new("")).join(texture))
+        });
+        let base_color_texture = match texture_path {
+            Some(texture_path) => {
+                let data = tokio::fs::read(&texture_path)
+                    .await
+                    .map_err(Error::io(&texture_path))?;
+                ktx2::decode_image(&data)?
+            }
+            None => image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 255, 255, 255])),
+        };
+
+        let base_color = match &material {
+            Some((material, _)) => {
+                let [r, g, b] = material.diffuse;
+                [r, g, b, material.dissolve]
+            }
+            None => [1.0; 4],
+        };
+
+        let mut model = Self {
+            vertices: mesh.vertices,
+            indices: mesh.indices,
+            material: MaterialData {
+                base_color_texture,
+                ..MaterialData::from_factors(MaterialFactors {
+                    base_color,
+                    metallic: 0.0,
+                    roughness: 1.0,
+                    alpha_mode: if base_color[3] < 1.0 {
+                        AlphaMode::Blend
+                    } else {
+                        AlphaMode::Opaque
+                    },
+                    ..MaterialFactors::default()
+                })
+            },
+            skinned: false,
+        };
+        let stats = model.optimize(false);
+        info!("Optimized {}: {}", path.display(), stats);
+        Ok(model)
+    }
+
+    /// Extract a single primitive, along with its material, from an imported GLTF
+    /// document
+    pub fn from_gltf_primitive(
+        primitive: &gltf::Primitive,
+        buffers: &[gltf::buffer::Data],
+        images: &[gltf::image::Data],
+    ) -> Result<Self> {
+        warn_unsupported_gltf_features(primitive);
+
         let reader = primitive.reader(|buff| Some(&buffers[buff.index()]));
         let position_iter = reader
             .read_positions()
-            .ok_or("Mesh vertices have no position data")?;
+            .ok_or(Error::InvalidAsset("Mesh vertices have no position data"))?;
         let normal_iter = reader
             .read_normals()
-            .ok_or("Mesh vertices have no normal data")?;
+            .ok_or(Error::InvalidAsset("Mesh vertices have no normal data"))?;
+        // Texcoords are only needed to sample a base color texture, so untextured primitives
+        // can do without them
         let texcoord_iter = reader
             .read_tex_coords(0)
-            .ok_or("Mesh vertices have no texcoord data")?
-            .into_f32();
+            .map(|texcoords| texcoords.into_f32())
+            .into_iter()
+            .flatten()
+            .chain(std::iter::repeat([0.0, 0.0]));
+        // Primitives without a second set are lightmapped with the first
+        let lightmap_texcoord_iter = reader
+            .read_tex_coords(1)
+            .map(|texcoords| texcoords.into_f32().map(Some))
+            .into_iter()
+            .flatten()
+            .chain(std::iter::repeat(None));
+
+        // COLOR_0 may be RGB or RGBA in any of the normalized integer formats, which the reader
+        // converts to linear float RGBA
+        let color_iter = reader
+            .read_colors(0)
+            .map(|colors| colors.into_rgba_f32())
+            .into_iter()
+            .flatten()
+            .chain(std::iter::repeat(Vertex::WHITE));
+
+        // Only skinned primitives have joints and weights
+        let joints = reader.read_joints(0);
+        let weights = reader.read_weights(0);
+        let skinned = joints.is_some() && weights.is_some();
+        let joint_iter = joints
+            .map(|joints| joints.into_u16())
+            .into_iter()
+            .flatten()
+            .map(|[a, b, c, d]| [a as u32, b as u32, c as u32, d as u32])
+            .chain(std::iter::repeat([0; 4]));
+        let weight_iter = weights
+            .map(|weights| weights.into_f32())
+            .into_iter()
+            .flatten()
+            .chain(std::iter::repeat([0.0; 4]));
 
         let mut vertices = Vec::new();
-        for ((position, normal), texcoord) in position_iter.zip(normal_iter).zip(texcoord_iter) {
+        let attribute_iter = position_iter
+            .zip(normal_iter)
+            .zip(texcoord_iter.zip(lightmap_texcoord_iter).zip(color_iter))
+            .zip(joint_iter.zip(weight_iter));
+        for (((position, normal), ((texcoord, lightmap_texcoord), color)), (joints, weights)) in
+            attribute_iter
+        {
             vertices.push(Vertex {
                 position,
                 normal,
                 texcoord,
-                color: [0.5, 0.5, 0.5, 1.0],
+                lightmap_texcoord: lightmap_texcoord.unwrap_or(texcoord),
+                color,
+                joints,
+                weights,
             })
         }
 
         let indices = reader
             .read_indices()
-            .ok_or("Mesh doesn't have vertex index data")?
+            .ok_or(Error::InvalidAsset("Mesh doesn't have vertex index data"))?
             .into_u32()
             .collect();
 
-        let pbr_material = primitive.material().pbr_metallic_roughness();
-        let base_color_texture = match pbr_material.base_color_texture() {
-            Some(texture_info) => &images[texture_info.texture().index()],
-            None => return Err("Primitive material doesn't have a pbr base color"),
+        let material = primitive.material();
+        let pbr_material = material.pbr_metallic_roughness();
+        let texture_image = |texture: gltf::texture::Texture| {
+            gltf_image_to_rgba(&images[texture.source().index()])
         };
-        let base_color_texture = match base_color_texture.format {
-            gltf::image::Format::R8G8B8 => {
-                let rgb = image::RgbImage::from_raw(
-                    base_color_texture.width,
-                    base_color_texture.height,
-                    base_color_texture.pixels.clone(),
-                )
-                .ok_or("GLTF texture didn't have sufficient pixel data to fill its width*height")?;
-
-                image::DynamicImage::ImageRgb8(rgb).into_rgba()
-            }
-            gltf::image::Format::R8G8B8A8 => image::RgbaImage::from_raw(
-                base_color_texture.width,
-                base_color_texture.height,
-                base_color_texture.pixels.clone(),
-            )
-            .ok_or("GLTF texture didn't have sufficient pixel data to fill its width*height")?,
-            _ => return Err("Primitive base color texture has an unsupported pixel format"),
+
+        // Untextured materials just have a flat color, so stand in a single white texel for the
+        // base color factor to multiply
+        let base_color_texture = match pbr_material.base_color_texture() {
+            Some(info) => texture_image(info.texture())?,
+            None => image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 255, 255, 255])),
         };
+        let metallic_roughness_texture = pbr_material
+            .metallic_roughness_texture()
+            .map(|info| texture_image(info.texture()))
+            .transpose()?;
+        let normal_texture = material
+            .normal_texture()
+            .map(|normal| texture_image(normal.texture()))
+            .transpose()?;
+        let occlusion_texture = material
+            .occlusion_texture()
+            .map(|occlusion| texture_image(occlusion.texture()))
+            .transpose()?;
+        let emissive_texture = material
+            .emissive_texture()
+            .map(|info| texture_image(info.texture()))
+            .transpose()?;
 
         Ok(Self {
             vertices,
             indices,
-            texture: base_color_texture,
+            material: MaterialData {
+                base_color_texture,
+                metallic_roughness_texture,
+                normal_texture,
+                occlusion_texture,
+                emissive_texture,
+                // glTF has no notion of lightmaps, they have to be given separately
+                lightmap_texture: None,
+                factors: MaterialFactors {
+                    base_color: pbr_material.base_color_factor(),
+                    metallic: pbr_material.metallic_factor(),
+                    roughness: pbr_material.roughness_factor(),
+                    normal_scale: material.normal_texture().map_or(1.0, |normal| normal.scale()),
+                    occlusion_strength: material
+                        .occlusion_texture()
+                        .map_or(1.0, |occlusion| occlusion.strength()),
+                    emissive: material.emissive_factor(),
+                    alpha_mode: match material.alpha_mode() {
+                        gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                        gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+                        gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+                    },
+                    alpha_cutoff: material.alpha_cutoff(),
+                },
+            },
+            skinned,
         })
     }
+
+    /// Merge duplicate vertices and reorder the triangles and vertices for the GPU to draw them
+    /// faster, returning how much was gained
+    ///
+    /// With `quantize` set, attributes are first rounded to the precision a compact vertex
+    /// format would hold, merging vertices that only differ by less than that.
+    pub fn optimize(&mut self, quantize: bool) -> MeshStats {
+        let vertices_before = self.vertices.len();
+        let cache_misses_before = mesh_opt::cache_misses(&self.indices);
+
+        if quantize {
+            mesh_opt::quantize_vertices(&mut self.vertices);
+        }
+        let (vertices, indices) = mesh_opt::deduplicate_vertices(&self.vertices, &self.indices);
+        let indices = mesh_opt::optimize_vertex_cache(&indices, vertices.len());
+
+        // Laying the vertices out in the order they're first used helps the pre-transform cache
+        let (vertices, indices) = mesh_opt::deduplicate_vertices(&vertices, &indices);
+        self.vertices = vertices;
+        self.indices = indices;
+
+        MeshStats {
+            triangles: self.indices.len() / 3,
+            vertices_before,
+            vertices_after: self.vertices.len(),
+            cache_misses_before,
+            cache_misses_after: mesh_opt::cache_misses(&self.indices),
+        }
+    }
+}
+
+/// Warn about anything in a GLTF primitive that loads fine but isn't drawn as the file intends
+fn warn_unsupported_gltf_features(primitive: &gltf::Primitive) {
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        warn!("{:?} primitives aren't supported, drawing as triangles", primitive.mode());
+    }
+    if primitive.morph_targets().len() > 0 {
+        warn!("Morph targets aren't supported, ignoring them");
+    }
+
+    let material = primitive.material();
+    let pbr_material = material.pbr_metallic_roughness();
+    let tex_coords = [
+        pbr_material.base_color_texture().map(|info| info.tex_coord()),
+        pbr_material.metallic_roughness_texture().map(|info| info.tex_coord()),
+        material.normal_texture().map(|normal| normal.tex_coord()),
+        material.occlusion_texture().map(|occlusion| occlusion.tex_coord()),
+        material.emissive_texture().map(|info| info.tex_coord()),
+    ];
+    if tex_coords.iter().flatten().any(|&set| set != 0) {
+        warn!("Only the first texture coordinate set is supported, sampling every texture with it");
+    }
+}
+
+/// Convert an image decoded by the gltf crate in to RGBA
+///
+/// Single channel images are copied in to each of red, green and blue.
+fn gltf_image_to_rgba(data: &gltf::image::Data) -> Result<image::RgbaImage> {
+    let insufficient_pixels = || {
+        Error::InvalidAsset(
+            "GLTF texture didn't have sufficient pixel data to fill its width*height",
+        )
+    };
+    let dynamic_image = match data.format {
+        gltf::image::Format::R8 => image::GrayImage::from_raw(
+            data.width,
+            data.height,
+            data.pixels.clone(),
+        )
+        .map(image::DynamicImage::ImageLuma8),
+        gltf::image::Format::R8G8B8 => image::RgbImage::from_raw(
+            data.width,
+            data.height,
+            data.pixels.clone(),
+        )
+        .map(image::DynamicImage::ImageRgb8),
+        gltf::image::Format::R8G8B8A8 => image::RgbaImage::from_raw(
+            data.width,
+            data.height,
+            data.pixels.clone(),
+        )
+        .map(image::DynamicImage::ImageRgba8),
+        _ => return Err(Error::InvalidAsset("GLTF texture has an unsupported pixel format")),
+    };
+
+    Ok(dynamic_image.ok_or_else(insufficient_pixels)?.into_rgba8())
 }
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::str::SplitWhitespace;
+
+use cgmath::{InnerSpace, Vector3};
+use tracing::warn;
+
+use crate::error::{Error, Result};
+use crate::vertex::Vertex;
+
+/// The geometry of a Wavefront OBJ file, triangulated and with a single index per vertex
+pub struct ObjMesh {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+
+    /// Paths of the MTL files named by `mtllib`, relative to the OBJ file
+    pub material_libs: Vec<String>,
+
+    /// Name of the material the first face uses, if any
+    pub material: Option<String>,
+}
+
+/// The parts of an MTL material that map on to `MaterialFactors`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjMaterial {
+    pub name: String,
+
+    /// `Kd`, linear RGB
+    pub diffuse: [f32; 3],
+
+    /// `d`, or one minus `Tr`
+    pub dissolve: f32,
+
+    /// `map_Kd`, relative to the MTL file
+    pub diffuse_texture: Option<String>,
+}
+
+/// A face corner, as indices in to the position, texcoord and normal lists
+type Corner = (usize, Option<usize>, Option<usize>);
+
+/// Parse an OBJ file, fanning polygons out in to triangles and merging corners that share the same
+/// position, texcoord and normal in to one vertex
+///
+/// Vertices without a normal get the average of their faces' normals, and those without a
+/// texcoord get zero. Points, lines, groups and smoothing groups are ignored.
+pub fn parse_obj(source: &str) -> Result<ObjMesh> {
+    let mut positions = Vec::new();
+    let mut texcoords = Vec::new();
+    let mut normals = Vec::new();
+    let mut material_libs = Vec::new();
+    let mut material: Option<String> = None;
+    let mut warned_materials = false;
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    let mut vertex_indices: HashMap<Corner, u32> = HashMap::new();
+
+    // Vertices that need their normal computed from the faces they're part of
+    let mut missing_normals = Vec::new();
+
+    for line in source.lines() {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => positions.push(parse_floats::<3>(&mut tokens)?),
+            Some("vt") => {
+                let [u, v] = parse_floats::<2>(&mut tokens)?;
+                // OBJ puts the origin at the bottom left of the texture, wgpu at the top left
+                texcoords.push([u, 1.0 - v]);
+            }
+            Some("vn") => normals.push(parse_floats::<3>(&mut tokens)?),
+            Some("mtllib") => material_libs.extend(tokens.map(str::to_owned)),
+            Some("usemtl") => {
+                let name = tokens.next().map(str::to_owned);
+                if material.is_none() {
+                    material = name;
+                } else if name != material && !warned_materials {
+                    warn!("OBJ file uses multiple materials, only applying the first");
+                    warned_materials = true;
+                }
+            }
+            Some("f") => {
+                let mut face = Vec::new();
+                for token in tokens {
+                    let corner = parse_corner(token, &positions, &texcoords, &normals)?;
+                    let index = *vertex_indices.entry(corner).or_insert_with(|| {
+                        let (position, texcoord, normal) = corner;
+                        if normal.is_none() {
+                            missing_normals.push(vertices.len());
+                        }
+                        let texcoord = texcoord.map_or([0.0; 2], |texcoord| texcoords[texcoord]);
+                        vertices.push(Vertex {
+                            position: positions[position],
+                            normal: normal.map_or([0.0; 3], |normal| normals[normal]),
+                            texcoord,
+                            lightmap_texcoord: texcoord,
+                            color: Vertex::WHITE,
+                            joints: [0; 4],
+                            weights: [0.0; 4],
+                        });
+                        (vertices.len() - 1) as u32
+                    });
+                    face.push(index);
+                }
+
+                if face.len() < 3 {
+                    return Err(Error::InvalidAsset("OBJ face has fewer than three corners"));
+                }
+                for i in 1..face.len() - 1 {
+                    indices.extend_from_slice(&[face[0], face[i], face[i + 1]]);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if !missing_normals.is_empty() {
+        compute_normals(&mut vertices, &indices, &missing_normals);
+    }
+
+    Ok(ObjMesh {
+        vertices,
+        indices,
+        material_libs,
+        material,
+    })
+}
+
+/// Parse every material in an MTL file
+pub fn parse_mtl(source: &str) -> Result<Vec<ObjMaterial>> {
+    let mut materials: Vec<ObjMaterial> = Vec::new();
+    for line in source.lines() {
+        let mut tokens = line.split_whitespace();
+        let keyword = tokens.next();
+        if keyword == Some("newmtl") {
+            materials.push(ObjMaterial {
+                name: tokens.next().unwrap_or_default().to_owned(),
+                diffuse: [1.0; 3],
+                dissolve: 1.0,
+                diffuse_texture: None,
+            });
+            continue;
+        }
+
+        let material = match materials.last_mut() {
+            Some(material) => material,
+            None => continue,
+        };
+        match keyword {
+            Some("Kd") => material.diffuse = parse_floats::<3>(&mut tokens)?,
+            Some("d") => material.dissolve = parse_floats::<1>(&mut tokens)?[0],
+            Some("Tr") => material.dissolve = 1.0 - parse_floats::<1>(&mut tokens)?[0],
+            Some("map_Kd") => {
+                // Any texture options, eg. `-s 1 1 1`, come before the file name
+                material.diffuse_texture = tokens.last().map(str::to_owned);
+            }
+            _ => (),
+        }
+    }
+
+    Ok(materials)
+}
+
+fn parse_floats<const N: usize>(tokens: &mut SplitWhitespace) -> Result<[f32; N]> {
+    let mut values = [0.0; N];
+    for value in &mut values {
+        *value = tokens
+            .next()
+            .and_then(|token| token.parse().ok())
+            .ok_or(Error::InvalidAsset("OBJ file has a missing or malformed number"))?;
+    }
+    Ok(values)
+}
+
+/// Parse a face corner, eg. `1`, `1/2`, `1//3` or `1/2/3`
+fn parse_corner(
+    token: &str,
+    positions: &[[f32; 3]],
+    texcoords: &[[f32; 2]],
+    normals: &[[f32; 3]],
+) -> Result<Corner> {
+    let mut parts = token.split('/');
+    let position = parts.next().and_then(|index| resolve_index(index, positions.len()));
+    let position = position.ok_or(Error::InvalidAsset("OBJ face has an invalid position index"))?;
+
+    let mut optional_index = |count, error| match parts.next() {
+        None | Some("") => Ok(None),
+        Some(index) => resolve_index(index, count)
+            .map(Some)
+            .ok_or(Error::InvalidAsset(error)),
+    };
+    let texcoord = optional_index(texcoords.len(), "OBJ face has an invalid texcoord index")?;
+    let normal = optional_index(normals.len(), "OBJ face has an invalid normal index")?;
+
+    Ok((position, texcoord, normal))
+}
+
+/// Turn a one based index, or a negative one counting back from the end, in to a zero based one
+fn resolve_index(index: &str, count: usize) -> Option<usize> {
+    let index: isize = index.parse().ok()?;
+    let resolved = if index < 0 {
+        count as isize + index
+    } else {
+        index - 1
+    };
+    (0..count as isize).contains(&resolved).then_some(resolved as usize)
+}
+
+/// Set the normals of the given vertices to the area weighted average of their triangles' normals
+fn compute_normals(vertices: &mut [Vertex], indices: &[u32], missing: &[usize]) {
+    let mut sums = vec![Vector3::new(0.0, 0.0, 0.0); vertices.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|i| Vector3::from(vertices[triangle[i] as usize].position));
+        // Not normalized, so that bigger triangles count for more
+        let normal = (b - a).cross(c - a);
+        for &index in triangle {
+            sums[index as usize] += normal;
+        }
+    }
+
+    for &vertex in missing {
+        let sum = sums[vertex];
+        if sum.magnitude2() > 0.0 {
+            vertices[vertex].normal = sum.normalize().into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quad_is_triangulated_and_deduplicated() {
+        let mesh = parse_obj(
+            "
+            mtllib quad.mtl
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0
+            vt 0 0
+            vt 1 1
+            vn 0 0 1
+            usemtl red
+            f 1/1/1 2/1/1 3/2/1 -1/2/-1
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.vertices[3].position, [0.0, 1.0, 0.0]);
+        assert_eq!(mesh.vertices[3].texcoord, [1.0, 0.0]);
+        assert_eq!(mesh.material_libs, vec!["quad.mtl".to_owned()]);
+        assert_eq!(mesh.material.as_deref(), Some("red"));
+    }
+
+    #[test]
+    fn test_missing_normals_are_computed() {
+        let mesh = parse_obj("v 0 0 0\nv 1 0 0\nv 0 0 -1\nf 1 2 3\nf 1 2 3").unwrap();
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_eq!(mesh.indices.len(), 6);
+        for vertex in &mesh.vertices {
+            assert_eq!(vertex.normal, [0.0, 1.0, 0.0]);
+        }
+
+        assert!(parse_obj("v 0 0 0\nf 1 2 3").is_err());
+    }
+
+    #[test]
+    fn test_parse_mtl() {
+        let materials = parse_mtl(
+            "
+            newmtl red
+            Kd 1 0 0
+            Tr 0.25
+            map_Kd -s 2 2 1 red.png
+            newmtl plain
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(materials, vec![
+            ObjMaterial {
+                name: "red".to_owned(),
+                diffuse: [1.0, 0.0, 0.0],
+                dissolve: 0.75,
+                diffuse_texture: Some("red.png".to_owned()),
+            },
+            ObjMaterial {
+                name: "plain".to_owned(),
+                diffuse: [1.0; 3],
+                dissolve: 1.0,
+                diffuse_texture: None,
+            },
+        ]);
+    }
+}
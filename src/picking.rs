@@ -0,0 +1,321 @@
+use cgmath::{
+    EuclideanSpace, InnerSpace, Matrix4, MetricSpace, Point3, SquareMatrix, Vector2, Vector3,
+};
+
+use crate::model_data::ModelData;
+use crate::spatial::{Bvh, ObjectBvh};
+
+/// A half-line in some space, along which hits are measured in multiples of `direction`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    /// The world space ray through a point on the screen, in normalized device coordinates with
+    /// Y up, starting on the near plane
+    pub fn from_screen(ndc: Vector2<f32>, view: Matrix4<f32>, proj: Matrix4<f32>) -> Self {
+        let inv_view = view.invert().unwrap_or_else(Matrix4::identity);
+        let inv_view_proj = (proj * view).invert().unwrap_or_else(Matrix4::identity);
+        let unproject = |z| {
+            let point = inv_view_proj * ndc.extend(z).extend(1.0);
+            Point3::from_vec(point.truncate() / point.w)
+        };
+
+        // `cgmath::perspective` maps the near and far planes to -1 and 1, whereas a reversed
+        // projection has its near plane at 1 and puts -1 behind the camera. Either way the near
+        // plane is the nearest of the two in front of the camera.
+        let eye = Point3::from_homogeneous(inv_view * Point3::origin().to_homogeneous());
+        let forward = (inv_view * -Vector3::unit_z().extend(0.0)).truncate();
+        let origin = [unproject(-1.0), unproject(1.0)]
+            .iter()
+            .copied()
+            .filter(|point| (point - eye).dot(forward) > 0.0)
+            .min_by(|a, b| a.distance2(eye).total_cmp(&b.distance2(eye)))
+            .unwrap_or(eye);
+        Self {
+            origin,
+            direction: (origin - eye).normalize(),
+        }
+    }
+
+    /// The same ray in another space
+    ///
+    /// The direction isn't renormalized, so distances along the result are in the same units as
+    /// along this ray.
+    pub fn transformed(&self, transform: Matrix4<f32>) -> Self {
+        Self {
+            origin: Point3::from_homogeneous(transform * self.origin.to_homogeneous()),
+            direction: (transform * self.direction.extend(0.0)).truncate(),
+        }
+    }
+
+    /// Distance to where the ray enters the box, or zero if it starts inside it
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let mut near = 0.0f32;
+        let mut far = f32::INFINITY;
+        for axis in 0..3 {
+            // Dividing by a zero component gives infinities that still compare correctly
+            let inv_direction = 1.0 / self.direction[axis];
+            let t0 = (aabb.min[axis] - self.origin[axis]) * inv_direction;
+            let t1 = (aabb.max[axis] - self.origin[axis]) * inv_direction;
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+
+        if near <= far {
+            Some(near)
+        } else {
+            None
+        }
+    }
+
+    /// Distance to where the ray hits a triangle from either side, by Möller-Trumbore
+    pub fn intersect_triangle(&self, [a, b, c]: [Point3<f32>; 3]) -> Option<f32> {
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = self.direction.cross(edge2);
+        let det = edge1.dot(p);
+        if det.abs() < f32::EPSILON {
+            // Parallel to the triangle's plane
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = self.origin - a;
+        let u = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = s.cross(edge1);
+        let v = self.direction.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let distance = edge2.dot(q) * inv_det;
+        if distance >= 0.0 {
+            Some(distance)
+        } else {
+            None
+        }
+    }
+}
+
+/// An axis aligned bounding box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    /// The smallest box around every given point, or None if there aren't any
+    pub fn from_points(points: impl IntoIterator<Item = Point3<f32>>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Self { min: first, max: first }, |aabb, point| Self {
+            min: Point3::new(
+                aabb.min.x.min(point.x),
+                aabb.min.y.min(point.y),
+                aabb.min.z.min(point.z),
+            ),
+            max: Point3::new(
+                aabb.max.x.max(point.x),
+                aabb.max.y.max(point.y),
+                aabb.max.z.max(point.z),
+            ),
+        }))
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    /// The smallest box around both this one and another
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: Point3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Point3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
+    }
+
+    /// Whether the boxes share any space, including just touching
+    pub fn overlaps(&self, other: &Aabb) -> bool {
+        (0..3).all(|axis| self.min[axis] <= other.max[axis] && other.min[axis] <= self.max[axis])
+    }
+
+    /// The box around this one's corners once transformed
+    pub fn transformed(&self, transform: Matrix4<f32>) -> Self {
+        let corners = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            let corner = Point3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            Point3::from_homogeneous(transform * corner.to_homogeneous())
+        });
+        Self::from_points(corners).unwrap()
+    }
+}
+
+/// CPU side copy of a model's triangles for picking against, with a BVH over them
+///
+/// Skinned models are kept in their bind pose, so only pick accurately while near it.
+pub struct PickMesh {
+    pub bounds: Aabb,
+    positions: Vec<Point3<f32>>,
+    indices: Vec<u32>,
+    triangles: Bvh,
+}
+
+impl PickMesh {
+    pub fn new(data: &ModelData) -> Self {
+        let positions = data.vertices.iter().map(|vertex| vertex.position.into()).collect();
+        Self::from_triangles(positions, data.indices.clone())
+    }
+
+    /// Three indices in to `positions` for each triangle
+    pub fn from_triangles(positions: Vec<Point3<f32>>, indices: Vec<u32>) -> Self {
+        let triangle_bounds: Vec<_> = indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                let corners = triangle.iter().map(|&i| positions[i as usize]);
+                Aabb::from_points(corners).unwrap()
+            })
+            .collect();
+        let triangles = Bvh::build(&triangle_bounds);
+        let bounds = triangles.bounds().unwrap_or(Aabb {
+            min: Point3::origin(),
+            max: Point3::origin(),
+        });
+        Self {
+            bounds,
+            positions,
+            indices,
+            triangles,
+        }
+    }
+
+    fn triangle(&self, triangle: usize) -> [Point3<f32>; 3] {
+        let corner = |i: usize| self.positions[self.indices[3 * triangle + i] as usize];
+        [corner(0), corner(1), corner(2)]
+    }
+
+    /// Every triangle in the parts of the mesh that overlap a model space box, along with some
+    /// near them
+    pub fn triangles_near(&self, aabb: &Aabb) -> impl Iterator<Item = [Point3<f32>; 3]> + '_ {
+        let triangles = self.triangles.overlapping(aabb);
+        triangles.into_iter().map(move |triangle| self.triangle(triangle))
+    }
+
+    /// Distance along a model space ray to the nearest triangle it hits
+    pub fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let (_, distance) = self
+            .triangles
+            .raycast(ray, |triangle| ray.intersect_triangle(self.triangle(triangle)))?;
+        Some(distance)
+    }
+}
+
+/// Find the nearest of the objects in a BVH that a world space ray hits, given each object's
+/// mesh and model to world transform, or None to skip it
+///
+/// Returns the object that was hit and the world space distance to the hit.
+pub fn pick<'a, 'm, K: PartialEq>(
+    ray: &Ray,
+    objects: &'a ObjectBvh<K>,
+    mut mesh: impl FnMut(&K) -> Option<(&'m PickMesh, Matrix4<f32>)>,
+) -> Option<(&'a K, f32)> {
+    objects.raycast(ray, |key| {
+        let (mesh, transform) = mesh(key)?;
+        mesh.intersect(&ray.transformed(transform.invert()?))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::reversed_infinite_perspective;
+
+    fn unit_quad() -> PickMesh {
+        let positions = vec![
+            Point3::new(-1.0, -1.0, 0.0),
+            Point3::new(1.0, -1.0, 0.0),
+            Point3::new(1.0, 1.0, 0.0),
+            Point3::new(-1.0, 1.0, 0.0),
+        ];
+        PickMesh::from_triangles(positions, vec![0, 1, 2, 0, 2, 3])
+    }
+
+    #[test]
+    fn test_screen_center_ray_follows_camera() {
+        let eye = Point3::new(0.0, 0.0, 5.0);
+        let view = Matrix4::look_at(eye, Point3::origin(), Vector3::unit_y());
+        let proj = cgmath::perspective(cgmath::Deg(60.0), 1.5, 0.1, 100.0);
+
+        let ray = Ray::from_screen(Vector2::new(0.0, 0.0), view, proj);
+        assert!((ray.origin - Point3::new(0.0, 0.0, 4.9)).magnitude() < 1e-3);
+        assert!((ray.direction - Vector3::new(0.0, 0.0, -1.0)).magnitude() < 1e-4);
+
+        let proj = reversed_infinite_perspective(cgmath::Deg(60.0), 1.5, 0.1);
+        let ray = Ray::from_screen(Vector2::new(0.0, 0.0), view, proj);
+        assert!((ray.origin - Point3::new(0.0, 0.0, 4.9)).magnitude() < 1e-3);
+        assert!((ray.direction - Vector3::new(0.0, 0.0, -1.0)).magnitude() < 1e-4);
+    }
+
+    #[test]
+    fn test_pick_nearest() {
+        let ray = Ray {
+            origin: Point3::new(0.5, 0.5, 10.0),
+            direction: Vector3::new(0.0, 0.0, -1.0),
+        };
+        let quad = unit_quad();
+        let models = [
+            (&quad, Matrix4::from_translation(Vector3::new(0.0, 0.0, -2.0))),
+            (&quad, Matrix4::from_translation(Vector3::new(0.0, 0.0, 3.0))),
+            // Scaled up and off to the side, so the ray misses it
+            (
+                &quad,
+                Matrix4::from_translation(Vector3::new(5.0, 0.0, 5.0)) * Matrix4::from_scale(2.0),
+            ),
+        ];
+
+        let mut objects = ObjectBvh::default();
+        let bounds = models.iter().map(|(mesh, transform)| mesh.bounds.transformed(*transform));
+        objects.update(bounds.enumerate());
+
+        let (&hit, distance) = pick(&ray, &objects, |&i| Some(models[i])).unwrap();
+        assert_eq!(hit, 1);
+        assert!((distance - 7.0).abs() < 1e-5);
+
+        let miss = Ray {
+            origin: Point3::new(1.5, 0.0, 10.0),
+            direction: Vector3::new(0.0, 0.0, -1.0),
+        };
+        assert_eq!(pick(&miss, &objects, |&i| Some(models[i])), None);
+    }
+
+    #[test]
+    fn test_aabb_from_inside() {
+        let ray = Ray {
+            origin: Point3::origin(),
+            direction: Vector3::new(1.0, 0.0, 0.0),
+        };
+        assert_eq!(unit_quad().bounds.transformed(Matrix4::from_scale(2.0)), Aabb {
+            min: Point3::new(-2.0, -2.0, 0.0),
+            max: Point3::new(2.0, 2.0, 0.0),
+        });
+        assert_eq!(ray.intersect_aabb(&unit_quad().bounds), Some(0.0));
+    }
+}
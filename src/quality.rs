@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+use crate::renderer::{AaMode, MotionBlurConfig, RenderQuality, SsaoConfig, UpscaleFilter};
+
+/// A named set of graphics settings, trading image quality for performance
+///
+/// Presets are always applied as a whole, there is no way to override a single setting within
+/// one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QualityPreset {
+    Low,
+    Medium,
+    #[default]
+    High,
+    Ultra,
+}
+
+impl QualityPreset {
+    /// The next preset up, wrapping around from the highest to the lowest
+    pub fn next(self) -> Self {
+        match self {
+            QualityPreset::Low => QualityPreset::Medium,
+            QualityPreset::Medium => QualityPreset::High,
+            QualityPreset::High => QualityPreset::Ultra,
+            QualityPreset::Ultra => QualityPreset::Low,
+        }
+    }
+
+    /// The renderer side settings for this preset
+    ///
+    /// MSAA can't be drawn along with SSAO or motion blur, so the presets with those anti-alias
+    /// with TAA instead.
+    pub fn render_quality(self) -> RenderQuality {
+        match self {
+            QualityPreset::Low => RenderQuality {
+                render_scale: 0.75,
+                dynamic_resolution: true,
+                upscale_filter: UpscaleFilter::Bilinear,
+                texture_filter: wgpu::FilterMode::Nearest,
+                anisotropy: 1,
+                shadow_map_size: 1024,
+                aa_mode: AaMode::Fxaa,
+                msaa_samples: 0,
+                ssao: None,
+                motion_blur: None,
+            },
+            QualityPreset::Medium => RenderQuality {
+                render_scale: 1.0,
+                dynamic_resolution: true,
+                upscale_filter: UpscaleFilter::default(),
+                texture_filter: wgpu::FilterMode::Linear,
+                anisotropy: 4,
+                shadow_map_size: 2048,
+                aa_mode: AaMode::Msaa,
+                msaa_samples: 2,
+                ssao: None,
+                motion_blur: None,
+            },
+            QualityPreset::High => RenderQuality {
+                render_scale: 1.0,
+                dynamic_resolution: false,
+                upscale_filter: UpscaleFilter::default(),
+                texture_filter: wgpu::FilterMode::Linear,
+                anisotropy: 8,
+                shadow_map_size: 2048,
+                aa_mode: AaMode::Taa,
+                msaa_samples: 0,
+                ssao: Some(SsaoConfig::default()),
+                motion_blur: None,
+            },
+            QualityPreset::Ultra => RenderQuality {
+                render_scale: 1.5,
+                dynamic_resolution: false,
+                upscale_filter: UpscaleFilter::default(),
+                texture_filter: wgpu::FilterMode::Linear,
+                anisotropy: 16,
+                shadow_map_size: 4096,
+                aa_mode: AaMode::Taa,
+                msaa_samples: 0,
+                ssao: Some(SsaoConfig::default()),
+                motion_blur: Some(MotionBlurConfig::default()),
+            },
+        }
+    }
+
+    /// How far from the camera, in world units, geometry is still drawn
+    pub fn draw_distance(self) -> f32 {
+        match self {
+            QualityPreset::Low => 150.0,
+            QualityPreset::Medium => 400.0,
+            QualityPreset::High => 1000.0,
+            QualityPreset::Ultra => 2000.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preset_cycle_visits_all() {
+        let mut preset = QualityPreset::Low;
+        let mut seen = vec![preset];
+        for _ in 0..3 {
+            preset = preset.next();
+            seen.push(preset);
+        }
+        assert_eq!(
+            seen,
+            vec![QualityPreset::Low, QualityPreset::Medium, QualityPreset::High, QualityPreset::Ultra]
+        );
+        assert_eq!(preset.next(), QualityPreset::Low);
+    }
+
+    #[test]
+    fn test_presets_never_lower_quality() {
+        let qualities: Vec<_> = [
+            QualityPreset::Low,
+            QualityPreset::Medium,
+            QualityPreset::High,
+            QualityPreset::Ultra,
+        ]
+        .iter()
+        .map(|preset| preset.render_quality())
+        .collect();
+        for pair in qualities.windows(2) {
+            assert!(pair[1].shadow_map_size >= pair[0].shadow_map_size);
+            assert!(pair[1].anisotropy >= pair[0].anisotropy);
+            assert!(pair[1].ssao.is_some() || pair[0].ssao.is_none());
+            assert!(pair[1].motion_blur.is_some() || pair[0].motion_blur.is_none());
+        }
+        assert!(qualities[3].ssao.is_some() && qualities[3].motion_blur.is_some());
+    }
+
+    #[test]
+    fn test_presets_only_ask_for_what_their_aa_mode_draws() {
+        for preset in [
+            QualityPreset::Low,
+            QualityPreset::Medium,
+            QualityPreset::High,
+            QualityPreset::Ultra,
+        ] {
+            let quality = preset.render_quality();
+            match quality.aa_mode {
+                // MSAA takes the place of the post effects
+                AaMode::Msaa => {
+                    assert!(quality.ssao.is_none(), "{:?}", preset);
+                    assert!(quality.motion_blur.is_none(), "{:?}", preset);
+                }
+                // MSAA wouldn't be drawn at all
+                AaMode::Fxaa | AaMode::Taa => assert!(quality.msaa_samples <= 1, "{:?}", preset),
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use cgmath::Vector2;
+use tracing::warn;
+
+use super::{AtlasId, Renderer};
+use crate::error::{Error, Result};
+
+/// Largest width/height an atlas is allowed to grow to
+const MAX_ATLAS_SIZE: u32 = 8192;
+
+/// Narrowest an atlas is made, which keeps its rows a multiple of 256 bytes for the upload
+const MIN_ATLAS_WIDTH: u32 = 64;
+
+/// Texels of each image's edge repeated around it, so that filtering at the edge of a sprite
+/// doesn't pick up its neighbours
+const PADDING: u32 = 1;
+
+/// Where a single image ended up in an atlas, in the same normalized coordinates as
+/// `SpriteInstanceData::atlas_pos` and `atlas_size`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AtlasRegion {
+    pub pos: Vector2<f32>,
+    pub size: Vector2<f32>,
+}
+
+/// Where each image ended up in an atlas, keyed by the name it was added with
+pub type AtlasRegions = HashMap<String, AtlasRegion>;
+
+/// Rectangles placed by `pack`
+struct Packing {
+    width: u32,
+    height: u32,
+
+    /// Top left corner of each rectangle
+    positions: Vec<(u32, u32)>,
+}
+
+/// Composes named images in to a single sprite atlas at runtime
+#[derive(Default)]
+pub struct AtlasBuilder {
+    images: Vec<(String, image::RgbaImage)>,
+}
+
+impl AtlasBuilder {
+    #[allow(unused)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an image to the atlas, to be looked up by `name` once it's built
+    #[allow(unused)]
+    pub fn add(&mut self, name: impl Into<String>, image: image::RgbaImage) -> &mut Self {
+        self.images.push((name.into(), image));
+        self
+    }
+
+    /// Pack every image added so far in to a single atlas image
+    pub fn build(&self) -> Result<(image::RgbaImage, AtlasRegions)> {
+        if self.images.iter().any(|(_, image)| image.width() == 0 || image.height() == 0) {
+            return Err(Error::InvalidAsset("Atlas image is empty"));
+        }
+
+        let sizes: Vec<_> = self
+            .images
+            .iter()
+            .map(|(_, image)| (image.width() + 2 * PADDING, image.height() + 2 * PADDING))
+            .collect();
+        let Packing {
+            width: atlas_width,
+            height: atlas_height,
+            positions,
+        } = pack(&sizes).ok_or(Error::InvalidAsset("Sprite images don't fit in a single atlas"))?;
+
+        let mut atlas = image::RgbaImage::new(atlas_width, atlas_height);
+        let mut regions = HashMap::new();
+        for ((name, image), (x, y)) in self.images.iter().zip(positions) {
+            // Copy the padding too, clamping to the image's edge
+            let (width, height) = image.dimensions();
+            for padded_y in 0..height + 2 * PADDING {
+                for padded_x in 0..width + 2 * PADDING {
+                    let source_x = padded_x.saturating_sub(PADDING).min(width - 1);
+                    let source_y = padded_y.saturating_sub(PADDING).min(height - 1);
+                    let texel = *image.get_pixel(source_x, source_y);
+                    atlas.put_pixel(x + padded_x, y + padded_y, texel);
+                }
+            }
+
+            let region = AtlasRegion {
+                pos: Vector2::new(
+                    (x + PADDING) as f32 / atlas_width as f32,
+                    (y + PADDING) as f32 / atlas_height as f32,
+                ),
+                size: Vector2::new(
+                    width as f32 / atlas_width as f32,
+                    height as f32 / atlas_height as f32,
+                ),
+            };
+            if regions.insert(name.clone(), region).is_some() {
+                warn!("Atlas has multiple images named {}, using the last", name);
+            }
+        }
+
+        Ok((atlas, regions))
+    }
+
+    /// Pack the atlas and upload it, returning it along with the region of each named image
+    #[allow(unused)]
+    pub fn upload(&self, renderer: &mut Renderer) -> Result<(AtlasId, AtlasRegions)> {
+        let (atlas, regions) = self.build()?;
+        Ok((renderer.upload_atlas(atlas)?, regions))
+    }
+}
+
+/// Place rectangles of the given sizes on shelves in an atlas as small as can be found
+///
+/// Returns None if they can't fit in `MAX_ATLAS_SIZE`.
+fn pack(sizes: &[(u32, u32)]) -> Option<Packing> {
+    if sizes.is_empty() {
+        return None;
+    }
+
+    // Tallest first, so that each shelf wastes as little space as possible above its shorter
+    // rectangles
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(sizes[i].1));
+
+    let area: u64 = sizes.iter().map(|&(w, h)| w as u64 * h as u64).sum();
+    let widest = sizes.iter().map(|&(w, _)| w).max().unwrap_or(0);
+    let mut atlas_width = ((area as f64).sqrt().ceil() as u32)
+        .max(widest)
+        .max(MIN_ATLAS_WIDTH)
+        .next_power_of_two();
+
+    while atlas_width <= MAX_ATLAS_SIZE {
+        let mut positions = vec![(0, 0); sizes.len()];
+        let (mut x, mut shelf_y, mut shelf_height) = (0, 0, 0);
+        for &i in &order {
+            let (width, height) = sizes[i];
+            if x + width > atlas_width {
+                x = 0;
+                shelf_y += shelf_height;
+                shelf_height = 0;
+            }
+            positions[i] = (x, shelf_y);
+            x += width;
+            shelf_height = shelf_height.max(height);
+        }
+
+        let atlas_height = shelf_y + shelf_height;
+        if atlas_height <= MAX_ATLAS_SIZE {
+            return Some(Packing {
+                width: atlas_width,
+                height: atlas_height,
+                positions,
+            });
+        }
+        atlas_width *= 2;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_without_overlap() {
+        let sizes = [(30, 40), (64, 10), (20, 20), (20, 20), (10, 50), (70, 5)];
+        let Packing {
+            width,
+            height,
+            positions,
+        } = pack(&sizes).unwrap();
+        assert!(width.is_power_of_two() && width >= MIN_ATLAS_WIDTH);
+
+        let rects: Vec<_> = sizes
+            .iter()
+            .zip(&positions)
+            .map(|(&(w, h), &(x, y))| (x, y, x + w, y + h))
+            .collect();
+        for (i, a) in rects.iter().enumerate() {
+            assert!(a.2 <= width && a.3 <= height);
+            for b in &rects[i + 1..] {
+                assert!(a.2 <= b.0 || b.2 <= a.0 || a.3 <= b.1 || b.3 <= a.1);
+            }
+        }
+
+        assert!(pack(&[(MAX_ATLAS_SIZE + 1, 1)]).is_none());
+    }
+
+    #[test]
+    fn test_build_regions() {
+        let red = image::RgbaImage::from_pixel(4, 2, image::Rgba([255, 0, 0, 255]));
+        let (atlas, regions) = AtlasBuilder::new().add("red", red).build().unwrap();
+
+        let region = regions["red"];
+        assert_eq!(region.size.x * atlas.width() as f32, 4.0);
+        assert_eq!(region.size.y * atlas.height() as f32, 2.0);
+
+        // The padding around the image repeats its edge
+        assert_eq!(atlas.get_pixel(0, 0), &image::Rgba([255, 0, 0, 255]));
+        assert_eq!(atlas.get_pixel(5, 3), &image::Rgba([255, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,91 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Environment variable that overrides the configured backend, eg. `WGPU_BACKEND=dx12`
+pub const BACKEND_ENV_VAR: &str = "WGPU_BACKEND";
+
+/// Which graphics API the renderer should draw with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendPreference {
+    /// Whichever of Vulkan, Metal or DX12 the platform has, falling back to DX11 or GL
+    #[default]
+    Auto,
+    Vulkan,
+    Metal,
+    Dx12,
+    Dx11,
+    Gl,
+}
+
+impl BackendPreference {
+    /// Sets of backends to request an adapter from, in the order they should be tried
+    ///
+    /// A specific backend is tried first, but still falls back to the automatic choices rather
+    /// than leaving the app unable to start.
+    pub fn candidates(self) -> Vec<wgpu::BackendBit> {
+        let specific = match self {
+            BackendPreference::Auto => None,
+            BackendPreference::Vulkan => Some(wgpu::BackendBit::VULKAN),
+            BackendPreference::Metal => Some(wgpu::BackendBit::METAL),
+            BackendPreference::Dx12 => Some(wgpu::BackendBit::DX12),
+            BackendPreference::Dx11 => Some(wgpu::BackendBit::DX11),
+            BackendPreference::Gl => Some(wgpu::BackendBit::GL),
+        };
+
+        specific
+            .into_iter()
+            .chain([wgpu::BackendBit::PRIMARY, wgpu::BackendBit::SECONDARY])
+            .collect()
+    }
+
+    /// The preference set by `BACKEND_ENV_VAR` if there is one, otherwise `configured`
+    pub fn with_env_override(configured: Self) -> Self {
+        match std::env::var(BACKEND_ENV_VAR) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                warn!("Ignoring {}: {}", BACKEND_ENV_VAR, e);
+                configured
+            }),
+            Err(_) => configured,
+        }
+    }
+}
+
+impl FromStr for BackendPreference {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(BackendPreference::Auto),
+            "vulkan" => Ok(BackendPreference::Vulkan),
+            "metal" => Ok(BackendPreference::Metal),
+            "dx12" => Ok(BackendPreference::Dx12),
+            "dx11" => Ok(BackendPreference::Dx11),
+            "gl" => Ok(BackendPreference::Gl),
+            _ => Err("expected one of auto, vulkan, metal, dx12, dx11 or gl"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates_fall_back_to_auto() {
+        assert_eq!(BackendPreference::Auto.candidates(), vec![
+            wgpu::BackendBit::PRIMARY,
+            wgpu::BackendBit::SECONDARY,
+        ]);
+        assert_eq!(BackendPreference::Dx12.candidates()[0], wgpu::BackendBit::DX12);
+        assert_eq!(BackendPreference::Dx12.candidates().len(), 3);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("DX12".parse::<BackendPreference>().unwrap(), BackendPreference::Dx12);
+        assert!("directx".parse::<BackendPreference>().is_err());
+    }
+}
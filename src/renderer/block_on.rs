@@ -0,0 +1,41 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+use std::thread::{self, Thread};
+
+/// Wakes the thread blocked on a future by unparking it
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Drive a future to completion on the current thread, parking it while the future waits
+///
+/// tokio refuses to block on a future from within its own runtime, which the event loop runs
+/// inside of. This is only for the renderer's own futures, which wait on nothing but file reads
+/// that tokio runs on its blocking pool, so there's no risk of them waiting on this thread.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Arc::new(ThreadWaker(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_block_on_file_read_inside_runtime() {
+        let source = block_on(tokio::fs::read_to_string("./src/renderer/shaders/shader.frag"));
+        assert!(source.unwrap().starts_with("#version 450"));
+    }
+}
@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+
+use winit::dpi::PhysicalSize;
+
+use crate::error::Result;
+use super::{
+    AaMode, BackendPreference, MotionBlurConfig, PresentMode, RenderPath, Renderer,
+    RendererConfig, SsaoConfig, TextureStreamingConfig, TransparencyMode,
+};
+
+/// Creates a `Renderer`, starting from the defaults of `RendererConfig` and overriding only the
+/// settings that are given
+///
+/// Every setting here is fixed once the renderer is built, apart from the present mode, which
+/// can be changed later with `Renderer::set_present_mode`.
+#[derive(Clone, Debug, Default)]
+pub struct RendererBuilder {
+    config: RendererConfig,
+}
+
+impl RendererBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from a whole config, eg. one loaded from disk
+    pub fn from_config(config: RendererConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn render_path(mut self, render_path: RenderPath) -> Self {
+        self.config.render_path = render_path;
+        self
+    }
+
+    pub fn aa_mode(mut self, aa_mode: AaMode) -> Self {
+        self.config.aa_mode = aa_mode;
+        self
+    }
+
+    /// MSAA samples per pixel, 0 or 1 to disable it, for `AaMode::Msaa`. The closest count the
+    /// adapter supports is used instead.
+    pub fn msaa_samples(mut self, msaa_samples: u32) -> Self {
+        self.config.msaa_samples = msaa_samples;
+        self
+    }
+
+    pub fn backend(mut self, backend: BackendPreference) -> Self {
+        self.config.backend = backend;
+        self
+    }
+
+    pub fn present_mode(mut self, present_mode: PresentMode) -> Self {
+        self.config.present_mode = present_mode;
+        self
+    }
+
+    pub fn depth_prepass(mut self, depth_prepass: bool) -> Self {
+        self.config.depth_prepass = depth_prepass;
+        self
+    }
+
+    pub fn ssao(mut self, ssao: Option<SsaoConfig>) -> Self {
+        self.config.ssao = ssao;
+        self
+    }
+
+    pub fn motion_blur(mut self, motion_blur: Option<MotionBlurConfig>) -> Self {
+        self.config.motion_blur = motion_blur;
+        self
+    }
+
+    pub fn shadow_map_size(mut self, shadow_map_size: u32) -> Self {
+        self.config.shadow_map_size = shadow_map_size;
+        self
+    }
+
+    pub fn gpu_culling(mut self, gpu_culling: bool) -> Self {
+        self.config.gpu_culling = gpu_culling;
+        self
+    }
+
+    pub fn transparency(mut self, transparency: TransparencyMode) -> Self {
+        self.config.transparency = transparency;
+        self
+    }
+
+    /// Cameras drawn with must set `reverse_z` to match
+    pub fn reverse_z(mut self, reverse_z: bool) -> Self {
+        self.config.reverse_z = reverse_z;
+        self
+    }
+
+    pub fn texture_streaming(mut self, texture_streaming: Option<TextureStreamingConfig>) -> Self {
+        self.config.texture_streaming = texture_streaming;
+        self
+    }
+
+    pub fn ui_font(mut self, ui_font: impl Into<PathBuf>) -> Self {
+        self.config.ui_font = ui_font.into();
+        self
+    }
+
+    /// The config the renderer will be built with
+    pub fn config(&self) -> &RendererConfig {
+        &self.config
+    }
+
+    /// Build a renderer that draws to the window
+    pub async fn build(&self, window: &winit::window::Window) -> Result<Renderer> {
+        Renderer::new(window, &self.config).await
+    }
+
+    /// Build a renderer that draws to an offscreen texture of the given size, for reading frames
+    /// back with `Renderer::render_to_image`
+    pub async fn build_headless(&self, size: PhysicalSize<u32>) -> Result<Renderer> {
+        Renderer::new_headless(size, &self.config).await
+    }
+}
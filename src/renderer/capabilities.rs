@@ -0,0 +1,122 @@
+use tracing::warn;
+
+/// What the adapter being drawn with can do, which optional features are gated on
+///
+/// The version of wgpu in use can't be asked about an adapter's features or limits, only which
+/// backend it's on, so these are what every adapter on that backend can be relied on for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdapterCapabilities {
+    pub backend: wgpu::Backend,
+
+    /// Largest width or height of a 2D texture
+    pub max_texture_size: u32,
+
+    /// MSAA sample counts the backend's API guarantees for the scene's color and depth formats,
+    /// in increasing order
+    pub sample_counts: &'static [u32],
+
+    /// Whether the device can be asked for anisotropic filtering
+    pub anisotropic_filtering: bool,
+
+    /// Whether block compressed textures can be uploaded as they are. Never the case until wgpu
+    /// has compressed texture formats, so compressed assets are rejected.
+    pub compressed_textures: bool,
+}
+
+impl AdapterCapabilities {
+    pub fn for_adapter(info: &wgpu::AdapterInfo) -> Self {
+        Self::for_backend(info.backend)
+    }
+
+    fn for_backend(backend: wgpu::Backend) -> Self {
+        let max_texture_size = match backend {
+            wgpu::Backend::Dx12 | wgpu::Backend::Dx11 => 16384,
+            wgpu::Backend::Metal => 8192,
+            wgpu::Backend::Vulkan => 4096,
+            _ => 2048,
+        };
+        let sample_counts: &[u32] = match backend {
+            wgpu::Backend::Dx12 => &[1, 2, 4, 8],
+            wgpu::Backend::Metal => &[1, 2, 4],
+            _ => &[1, 4],
+        };
+
+        Self {
+            backend,
+            max_texture_size,
+            sample_counts,
+            anisotropic_filtering: !matches!(
+                backend,
+                wgpu::Backend::Gl | wgpu::Backend::BrowserWebGpu | wgpu::Backend::Empty
+            ),
+            compressed_textures: false,
+        }
+    }
+
+    /// Pick the largest supported MSAA sample count that's no more than the one asked for
+    ///
+    /// Anything below 1 is taken to mean MSAA is disabled.
+    pub fn validate_sample_count(&self, requested: u32) -> u32 {
+        let supported = self
+            .sample_counts
+            .iter()
+            .copied()
+            .filter(|&count| count <= requested.max(1))
+            .max()
+            .unwrap_or(1);
+
+        if supported != requested.max(1) {
+            warn!(
+                "{} MSAA samples isn't supported on {:?}, using {}",
+                requested, self.backend, supported
+            );
+        }
+        supported
+    }
+
+    /// Shrink `size` to fit in a texture, keeping its aspect ratio
+    pub fn fit_texture_size(
+        &self,
+        size: winit::dpi::PhysicalSize<u32>,
+    ) -> winit::dpi::PhysicalSize<u32> {
+        let largest = size.width.max(size.height);
+        if largest <= self.max_texture_size {
+            return size;
+        }
+
+        let scale = |dimension: u32| {
+            let scaled = dimension as u64 * self.max_texture_size as u64 / largest as u64;
+            (scaled as u32).max(1)
+        };
+        winit::dpi::PhysicalSize::new(scale(size.width), scale(size.height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_sample_count() {
+        let vulkan = AdapterCapabilities::for_backend(wgpu::Backend::Vulkan);
+        assert_eq!(vulkan.validate_sample_count(0), 1);
+        assert_eq!(vulkan.validate_sample_count(1), 1);
+        assert_eq!(vulkan.validate_sample_count(2), 1);
+        assert_eq!(vulkan.validate_sample_count(8), 4);
+        let dx12 = AdapterCapabilities::for_backend(wgpu::Backend::Dx12);
+        assert_eq!(dx12.validate_sample_count(8), 8);
+        let metal = AdapterCapabilities::for_backend(wgpu::Backend::Metal);
+        assert_eq!(metal.validate_sample_count(3), 2);
+    }
+
+    #[test]
+    fn test_fit_texture_size() {
+        let vulkan = AdapterCapabilities::for_backend(wgpu::Backend::Vulkan);
+        let fit = |width, height| {
+            vulkan.fit_texture_size(winit::dpi::PhysicalSize::new(width, height))
+        };
+        assert_eq!(fit(1920, 1080), winit::dpi::PhysicalSize::new(1920, 1080));
+        assert_eq!(fit(7680, 4320), winit::dpi::PhysicalSize::new(4096, 2304));
+        assert_eq!(fit(2160, 8192), winit::dpi::PhysicalSize::new(1080, 4096));
+    }
+}
@@ -0,0 +1,188 @@
+use std::rc::Rc;
+
+use super::resource_cache::{ComputePipelineDesc, ResourceCache};
+use crate::{error::Result, shader_cache::ShaderCache};
+
+/// GPU simulation work, eg. particles, cloth or culling, that runs ahead of each frame's
+/// rendering
+pub trait ComputeWorkload {
+    /// Record this frame's dispatches
+    ///
+    /// Anything written here is visible to the rendering of the same frame, as wgpu orders
+    /// resource accesses between submissions on a queue.
+    fn encode(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder);
+}
+
+/// Submits compute workloads separately from, and before, the frame's raster work.
+///
+/// wgpu only exposes a single queue, so there is no true async compute yet. Submitting early is
+/// the next best thing: the dispatches are queued up behind the previous frame's raster work
+/// while the CPU is still waiting for a swapchain image, rather than after it has recorded the
+/// whole frame. Should wgpu gain separate compute queues, only this type needs to change.
+#[derive(Default)]
+pub struct ComputeScheduler {
+    workloads: Vec<Box<dyn ComputeWorkload>>,
+}
+
+impl ComputeScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, workload: Box<dyn ComputeWorkload>) {
+        self.workloads.push(workload);
+    }
+
+    /// Record and submit every workload's dispatches for this frame
+    pub fn submit(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if self.workloads.is_empty() {
+            return;
+        }
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Compute workload encoder"),
+        });
+        for workload in &mut self.workloads {
+            workload.encode(device, &mut encoder);
+        }
+        queue.submit(&[encoder.finish()]);
+    }
+}
+
+/// Describes a `ComputePass`
+pub struct ComputePassDesc<'a> {
+    pub label: &'a str,
+
+    /// GLSL compute shader, compiled with `defines`
+    pub shader_path: &'a str,
+    pub defines: &'a [(&'a str, Option<&'a str>)],
+
+    /// The types of each bind group's bindings, numbered from zero in order. Every binding is
+    /// only visible to the compute stage.
+    pub bind_groups: &'a [&'a [wgpu::BindingType]],
+
+    /// Must match the shader's `local_size_x/y/z`
+    pub workgroup_size: [u32; 3],
+}
+
+/// A compute shader along with the layouts of the resources it binds, ready to be dispatched
+/// from a `ComputeWorkload` or a render stage
+pub struct ComputePass {
+    pipeline: Rc<wgpu::ComputePipeline>,
+    bind_group_layouts: Vec<Rc<wgpu::BindGroupLayout>>,
+    workgroup_size: [u32; 3],
+}
+
+impl ComputePass {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        desc: &ComputePassDesc<'_>,
+    ) -> Result<Self> {
+        let spirv = ShaderCache::new()
+            .get_shader_with_defines(desc.shader_path, shaderc::ShaderKind::Compute, desc.defines)
+            .await?;
+
+        let bind_group_layouts: Vec<_> = desc
+            .bind_groups
+            .iter()
+            .map(|types| {
+                let entries: Vec<_> = types
+                    .iter()
+                    .enumerate()
+                    .map(|(binding, &ty)| wgpu::BindGroupLayoutEntry {
+                        binding: binding as u32,
+                        visibility: wgpu::ShaderStage::COMPUTE,
+                        ty,
+                    })
+                    .collect();
+                resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                    bindings: &entries,
+                    label: Some(desc.label),
+                })
+            })
+            .collect();
+
+        let layouts: Vec<&wgpu::BindGroupLayout> =
+            bind_group_layouts.iter().map(|layout| &**layout).collect();
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &layouts,
+        });
+        let pipeline = resources.compute_pipeline(device, &ComputePipelineDesc {
+            layout: &pipeline_layout,
+            compute_shader: &spirv,
+        });
+
+        Ok(Self {
+            pipeline,
+            bind_group_layouts,
+            workgroup_size: desc.workgroup_size,
+        })
+    }
+
+    /// Create a bind group for bind group `index`, with `resources` bound in binding order
+    pub fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        index: usize,
+        resources: Vec<wgpu::BindingResource>,
+    ) -> wgpu::BindGroup {
+        let bindings: Vec<_> = resources
+            .into_iter()
+            .enumerate()
+            .map(|(binding, resource)| wgpu::Binding {
+                binding: binding as u32,
+                resource,
+            })
+            .collect();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layouts[index],
+            bindings: &bindings,
+            label: None,
+        })
+    }
+
+    /// Record a dispatch of enough workgroups to cover `invocations` in each dimension
+    ///
+    /// Workgroups are only dispatched whole, so the shader has to skip any invocations past the
+    /// end of its data.
+    pub fn dispatch(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        bind_groups: &[&wgpu::BindGroup],
+        invocations: [u32; 3],
+    ) {
+        let [x, y, z] = workgroup_count(invocations, self.workgroup_size);
+        if x == 0 || y == 0 || z == 0 {
+            return;
+        }
+
+        let mut cpass = encoder.begin_compute_pass();
+        cpass.set_pipeline(&self.pipeline);
+        for (index, bind_group) in bind_groups.iter().enumerate() {
+            cpass.set_bind_group(index as u32, bind_group, &[]);
+        }
+        cpass.dispatch(x, y, z);
+    }
+}
+
+/// Workgroups needed in each dimension to cover `invocations`, rounding up
+fn workgroup_count(invocations: [u32; 3], workgroup_size: [u32; 3]) -> [u32; 3] {
+    let mut count = [0; 3];
+    for i in 0..3 {
+        count[i] = invocations[i].div_ceil(workgroup_size[i]);
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workgroup_count() {
+        assert_eq!(workgroup_count([1000, 1, 1], [64, 1, 1]), [16, 1, 1]);
+        assert_eq!(workgroup_count([64, 8, 1], [64, 4, 1]), [1, 2, 1]);
+        assert_eq!(workgroup_count([0, 1, 1], [64, 1, 1]), [0, 1, 1]);
+    }
+}
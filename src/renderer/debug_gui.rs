@@ -0,0 +1,202 @@
+use std::rc::Rc;
+
+use crate::error::Result;
+use crate::shader_cache::ShaderCache;
+use super::{
+    frame_packet::GuiRect,
+    frames_in_flight::FrameSlot,
+    instance_buffer::InstanceBuffer,
+    render_target::ColorTarget,
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    staging_belt::StagingBelt,
+    Renderer,
+};
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct GuiRectInstance {
+    /// Clip space top-left corner
+    screen_pos: [f32; 2],
+
+    /// Clip space size, negative in y to go down the screen
+    screen_size: [f32; 2],
+
+    /// Non-premultiplied RGBA
+    color: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for GuiRectInstance {}
+unsafe impl bytemuck::Zeroable for GuiRectInstance {}
+
+impl GuiRectInstance {
+    /// Place a rectangle laid out in logical pixels on an output of the given size
+    fn new(rect: &GuiRect, output_size: winit::dpi::PhysicalSize<u32>, ui_scale: f32) -> Self {
+        let width = output_size.width as f32;
+        let height = output_size.height as f32;
+        let pos = rect.pos * ui_scale;
+        let size = rect.size * ui_scale;
+        Self {
+            screen_pos: [2.0 * pos.x / width - 1.0, 1.0 - 2.0 * pos.y / height],
+            screen_size: [2.0 * size.x / width, -2.0 * size.y / height],
+            color: rect.color.into(),
+        }
+    }
+
+    fn vertex_buffer_descriptor<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float2,
+                    offset: 0,
+                    shader_location: 0,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float2,
+                    offset: 2 * 4,
+                    shader_location: 1,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: 4 * 4,
+                    shader_location: 2,
+                },
+            ],
+        }
+    }
+}
+
+/// Represents a render stage that draws the debug GUI's rectangles over the composited frame
+///
+/// The GUI's labels are plain text runs, left to the text stage.
+pub struct DebugGuiStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    instances: InstanceBuffer<GuiRectInstance>,
+    instance_count: u32,
+}
+
+impl DebugGuiStage {
+    pub async fn new(device: &wgpu::Device, resources: &mut ResourceCache) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader("./src/renderer/shaders/debug_gui.vert", shaderc::ShaderKind::Vertex)
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader("./src/renderer/shaders/debug_gui.frag", shaderc::ShaderKind::Fragment)
+            .await?;
+
+        let render_pipeline_layout =
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[],
+            });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &render_pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleStrip,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: ColorTarget::LDR_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[GuiRectInstance::vertex_buffer_descriptor()],
+            },
+            sample_count: 1,
+        });
+
+        Ok(Self {
+            pipeline,
+            instances: InstanceBuffer::new(device, "Debug GUI stage instance buffer"),
+            instance_count: 0,
+        })
+    }
+
+    /// Record uploading this frame's rectangles, laid out for an output of `output_size`
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: FrameSlot,
+        rects: &[GuiRect],
+        output_size: winit::dpi::PhysicalSize<u32>,
+        ui_scale: f32,
+    ) {
+        let instances: Vec<_> = rects
+            .iter()
+            .map(|rect| GuiRectInstance::new(rect, output_size, ui_scale))
+            .collect();
+        self.instance_count = instances.len() as u32;
+        if instances.is_empty() {
+            return;
+        }
+
+        let instances = std::iter::once(&instances[..]);
+        self.instances.update(device, staging_belt, encoder, frame, instances);
+    }
+
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+    ) {
+        if self.instance_count == 0 {
+            return;
+        }
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: output,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Load,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_vertex_buffer(0, self.instances.buffer(), 0, 0);
+        rpass.draw(0..4, 0..self.instance_count);
+        renderer.draw_counter.record(self.instance_count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rect_to_clip_space() {
+        let rect = GuiRect {
+            pos: [20.0, 10.0].into(),
+            size: [100.0, 50.0].into(),
+            color: [1.0, 0.0, 0.0, 0.5].into(),
+        };
+        let instance = GuiRectInstance::new(&rect, winit::dpi::PhysicalSize::new(400, 200), 2.0);
+        assert_eq!(instance, GuiRectInstance {
+            screen_pos: [-0.8, 0.8],
+            screen_size: [1.0, -1.0],
+            color: [1.0, 0.0, 0.0, 0.5],
+        });
+    }
+}
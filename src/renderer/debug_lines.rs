@@ -0,0 +1,270 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use cgmath::Matrix4;
+
+use crate::error::Result;
+use crate::shader_cache::ShaderCache;
+use super::{
+    frame_packet::{DebugLine, FramePacket},
+    frames_in_flight::FrameSlot,
+    instance_buffer::InstanceBuffer,
+    render_target::RenderTarget,
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    staging_belt::StagingBelt,
+    Renderer,
+};
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DebugVertex {
+    /// World space
+    position: [f32; 3],
+
+    /// Linear RGBA
+    color: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for DebugVertex {}
+unsafe impl bytemuck::Zeroable for DebugVertex {}
+
+impl DebugVertex {
+    fn vertex_buffer_descriptor<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float3,
+                    offset: 0,
+                    shader_location: 0,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: 3 * 4,
+                    shader_location: 1,
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct DebugLinesUniformData {
+    view_proj: Matrix4<f32>,
+}
+
+unsafe impl bytemuck::Pod for DebugLinesUniformData {}
+unsafe impl bytemuck::Zeroable for DebugLinesUniformData {}
+
+/// The index buffer to draw a triangle list's edges as a line list with, listing each edge once
+/// however many triangles share it
+pub fn wireframe_indices(triangle_indices: &[u32]) -> Vec<u32> {
+    let mut edges = HashSet::new();
+    let mut indices = Vec::new();
+    for triangle in triangle_indices.chunks_exact(3) {
+        for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+            let (a, b) = (triangle[a], triangle[b]);
+            if edges.insert((a.min(b), a.max(b))) {
+                indices.extend_from_slice(&[a, b]);
+            }
+        }
+    }
+    indices
+}
+
+/// Represents a render stage that draws the frame packet's debug lines over the scene, depth
+/// tested against it so that they sit in the world rather than on top of it
+pub struct DebugLinesStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    uniform_bind_group: wgpu::BindGroup,
+    uniform_buff: wgpu::Buffer,
+
+    /// Two vertices per line. Instance buffers are just growable vertex buffers, so this reuses
+    /// one even though it's stepped per vertex.
+    vertices: InstanceBuffer<DebugVertex>,
+    vertex_count: u32,
+}
+
+impl DebugLinesStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        target: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/debug_lines.vert",
+                shaderc::ShaderKind::Vertex,
+            )
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/debug_lines.frag",
+                shaderc::ShaderKind::Fragment,
+            )
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<DebugLinesUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Debug lines stage uniform buffer"),
+        });
+
+        let uniform_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                }],
+                label: Some("Debug lines stage uniform buffer layout"),
+            });
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            bindings: &[wgpu::Binding {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &uniform_buff,
+                    range: 0..std::mem::size_of::<DebugLinesUniformData>() as wgpu::BufferAddress,
+                },
+            }],
+            label: Some("Debug lines stage uniform bind group"),
+        });
+
+        let render_pipeline_layout =
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&uniform_bind_group_layout],
+            });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &render_pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::LineList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: RenderTarget::COLOR_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            // Lines don't write depth, so that overlapping ones don't hide each other
+            depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                format: RenderTarget::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: target.depth_order.compare(wgpu::CompareFunction::LessEqual),
+                stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
+                stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
+                stencil_read_mask: 0,
+                stencil_write_mask: 0,
+            }),
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[DebugVertex::vertex_buffer_descriptor()],
+            },
+            sample_count: target.sample_count,
+        });
+
+        Ok(Self {
+            pipeline,
+            uniform_bind_group,
+            uniform_buff,
+            vertices: InstanceBuffer::new(device, "Debug lines stage vertex buffer"),
+            vertex_count: 0,
+        })
+    }
+
+    /// Record uploading this frame's lines and camera
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: FrameSlot,
+        frame_packet: &FramePacket,
+    ) {
+        let vertex = |position: cgmath::Point3<f32>, line: &DebugLine| DebugVertex {
+            position: position.into(),
+            color: line.color.into(),
+        };
+        let vertices: Vec<_> = frame_packet
+            .debug_lines
+            .iter()
+            .flat_map(|line| [vertex(line.start, line), vertex(line.end, line)])
+            .collect();
+        self.vertex_count = vertices.len() as u32;
+        if vertices.is_empty() {
+            return;
+        }
+
+        let batches = std::iter::once(&vertices[..]);
+        self.vertices.update(device, staging_belt, encoder, frame, batches);
+        staging_belt.write(
+            device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[DebugLinesUniformData {
+                view_proj: frame_packet.proj * frame_packet.view,
+            }]),
+        );
+    }
+
+    /// Draws on top of the scene that's already in the target, so has to come after it
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &RenderTarget,
+        viewport_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        if self.vertex_count == 0 {
+            return;
+        }
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[target.color_attachment(wgpu::LoadOp::Load)],
+            depth_stencil_attachment: Some(target.depth_attachment(wgpu::LoadOp::Load)),
+        });
+
+        rpass.set_viewport(
+            0.0,
+            0.0,
+            viewport_size.width as f32,
+            viewport_size.height as f32,
+            0.0,
+            1.0,
+        );
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        rpass.set_vertex_buffer(0, self.vertices.buffer(), 0, 0);
+        rpass.draw(0..self.vertex_count, 0..1);
+        renderer.draw_counter.record(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wireframe_indices_share_edges() {
+        // Two triangles making a quad, sharing the 0-2 diagonal
+        let indices = wireframe_indices(&[0, 1, 2, 0, 2, 3]);
+        assert_eq!(indices, vec![0, 1, 1, 2, 2, 0, 2, 3, 3, 0]);
+    }
+}
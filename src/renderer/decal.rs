@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use cgmath::{Matrix4, SquareMatrix, Vector2};
+
+use crate::{
+    error::{Error, Result},
+    shader_cache::ShaderCache,
+};
+use super::{
+    frame_packet::{Decal, FramePacket, FramePacketDecals},
+    frames_in_flight::FrameSlot,
+    instance_buffer::InstanceBuffer,
+    render_target::RenderTarget,
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    staging_belt::StagingBelt,
+    AtlasId, Renderer,
+};
+
+const DECAL_VERTEX_SHADER: &str = "./src/renderer/shaders/decal.vert";
+const DECAL_FRAGMENT_SHADER: &str = "./src/renderer/shaders/decal.frag";
+
+/// Vertices drawn for each decal's box, see decal.vert
+const BOX_VERTICES: u32 = 36;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DecalInstanceData {
+    /// Transforms the unit cube in to the decal's box in world space
+    transform: Matrix4<f32>,
+
+    /// Transforms world space in to the decal's unit cube
+    inv_transform: Matrix4<f32>,
+
+    /// Atlas x/y of the top-left corner of the texture in xy, and its size in zw
+    atlas_rect: [f32; 4],
+
+    tint: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for DecalInstanceData {}
+unsafe impl bytemuck::Zeroable for DecalInstanceData {}
+
+impl DecalInstanceData {
+    /// None if the decal's box is flattened to nothing, in which case there's nothing to draw
+    fn new(decal: &Decal) -> Option<Self> {
+        Some(Self {
+            transform: decal.transform,
+            inv_transform: decal.transform.invert()?,
+            atlas_rect: [
+                decal.atlas_pos.x,
+                decal.atlas_pos.y,
+                decal.atlas_size.x,
+                decal.atlas_size.y,
+            ],
+            tint: decal.tint.into(),
+        })
+    }
+
+    fn vertex_buffer_descriptor<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        const FLOAT_SIZE: wgpu::BufferAddress = 4;
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: 0,
+                    shader_location: 0,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4,
+                    shader_location: 1,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 2,
+                    shader_location: 2,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 3,
+                    shader_location: 3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 4,
+                    shader_location: 4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 5,
+                    shader_location: 5,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 6,
+                    shader_location: 6,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 7,
+                    shader_location: 7,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 8,
+                    shader_location: 8,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 9,
+                    shader_location: 9,
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct DecalUniformData {
+    view_proj: Matrix4<f32>,
+
+    /// Transforms this frame's unjittered clip space back in to world space
+    inv_view_proj: Matrix4<f32>,
+
+    /// Size of the rendered region in xy, zw are unused
+    region: [f32; 4],
+
+    /// Clip space offset the scene was drawn with in xy, zw are unused
+    jitter: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for DecalUniformData {}
+unsafe impl bytemuck::Zeroable for DecalUniformData {}
+
+/// A run of consecutive instances drawn from the same atlas
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DecalBatch {
+    atlas_id: AtlasId,
+    count: usize,
+}
+
+/// Every decal in the frame that has something to draw, with one batch for each set of them
+fn collect_instances(
+    decal_sets: &[FramePacketDecals],
+) -> (Vec<DecalInstanceData>, Vec<DecalBatch>) {
+    let mut instances = Vec::new();
+    let mut batches = Vec::new();
+    for set in decal_sets {
+        let first = instances.len();
+        instances.extend(set.decals.iter().filter_map(DecalInstanceData::new));
+        batches.push(DecalBatch {
+            atlas_id: set.atlas_id,
+            count: instances.len() - first,
+        });
+    }
+    (instances, batches)
+}
+
+/// Represents a render stage that projects decals on to the opaque scene
+///
+/// Each decal draws the back faces of its box, so that it still covers the pixels it projects on
+/// to with the camera inside it, and finds the surface behind every pixel from the scene's depth.
+/// Anything outside the box is discarded, and the texture fades out on surfaces turned away from
+/// the box's z axis. Decals are blended over the lit scene, so they don't pick up any lighting of
+/// their own.
+///
+/// The scene's depth can't be read under MSAA, so the stage isn't created then.
+pub struct DecalRenderStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    uniform_bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    uniform_buff: wgpu::Buffer,
+    depth_sampler: Rc<wgpu::Sampler>,
+    texture_bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    texture_bind_groups: HashMap<AtlasId, wgpu::BindGroup>,
+    texture_sampler: Rc<wgpu::Sampler>,
+    instances: InstanceBuffer<DecalInstanceData>,
+
+    /// How this frame's instances are split between atlases, in draw order
+    batches: Vec<DecalBatch>,
+}
+
+impl DecalRenderStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        target: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(DECAL_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader_with_defines(
+                DECAL_FRAGMENT_SHADER,
+                shaderc::ShaderKind::Fragment,
+                target.depth_order.shader_defines(),
+            )
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<DecalUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Decal stage uniform buffer"),
+        });
+
+        let uniform_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Decal stage uniform bind group layout"),
+            });
+
+        let texture_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Decal stage texture bind group layout"),
+            });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&uniform_bind_group_layout, &texture_bind_group_layout],
+        });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            // Only the back faces, which are there to cover the box's pixels whether or not the
+            // camera is inside it
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::Front,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: RenderTarget::COLOR_FORMAT,
+                // The scene's alpha is left as it was
+                alpha_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::Zero,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                color_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            // The depth is read rather than tested against, so it can't be attached as well
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[DecalInstanceData::vertex_buffer_descriptor()],
+            },
+            sample_count: 1,
+        });
+
+        let depth_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        let texture_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        Ok(Self {
+            pipeline,
+            uniform_bind_group_layout,
+            uniform_buff,
+            depth_sampler,
+            texture_bind_group_layout,
+            texture_bind_groups: HashMap::new(),
+            texture_sampler,
+            instances: InstanceBuffer::new(device, "Decal stage instance buffer"),
+            batches: Vec::new(),
+        })
+    }
+
+    /// Make decals with the given atlas id sample the given texture, eg. a `GpuAtlas`'s
+    pub fn add_atlas(
+        &mut self,
+        device: &wgpu::Device,
+        atlas_id: AtlasId,
+        texture: &wgpu::TextureView,
+    ) {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.texture_bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(texture),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.texture_sampler),
+                },
+            ],
+            label: Some("Decal atlas bind group"),
+        });
+
+        self.texture_bind_groups.insert(atlas_id, bind_group);
+    }
+
+    /// Record uploading this frame's decals
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: FrameSlot,
+        decal_sets: &[FramePacketDecals],
+    ) {
+        let (instances, batches) = collect_instances(decal_sets);
+        let instances = std::iter::once(&instances[..]);
+        self.instances.update(device, staging_belt, encoder, frame, instances);
+        self.batches = batches;
+    }
+
+    /// Blend the decals over the top-left `region` pixels of the scene target, which was drawn
+    /// with its projection offset by `jitter` in clip space
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        jitter: Vector2<f32>,
+        encoder: &mut wgpu::CommandEncoder,
+        region: winit::dpi::PhysicalSize<u32>,
+    ) -> Result<()> {
+        if self.batches.iter().all(|batch| batch.count == 0) {
+            return Ok(());
+        }
+
+        let view_proj = frame_packet.proj * frame_packet.view;
+        let inv_view_proj = view_proj
+            .invert()
+            .ok_or(Error::InvalidFramePacket("View-Projection matrix had a zero determinant"))?;
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[DecalUniformData {
+                view_proj,
+                inv_view_proj,
+                region: [region.width as f32, region.height as f32, 0.0, 0.0],
+                jitter: [jitter.x, jitter.y, 0.0, 0.0],
+            }]),
+        );
+
+        let target = &renderer.scene_target;
+        let uniform_bind_group = renderer.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.uniform_bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &self.uniform_buff,
+                        range: 0..std::mem::size_of::<DecalUniformData>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&target.depth_view),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&self.depth_sampler),
+                },
+            ],
+            label: Some("Decal stage uniform bind group"),
+        });
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &target.color_view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Load,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        // Keeps each fragment lined up with the depth texels it reads
+        rpass.set_viewport(0.0, 0.0, region.width as f32, region.height as f32, 0.0, 1.0);
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &uniform_bind_group, &[]);
+
+        let mut first_instance = 0;
+        for batch in &self.batches {
+            if batch.count == 0 || renderer.pending_atlases.contains(&batch.atlas_id) {
+                // Still loading, so there's nothing to draw these decals with yet
+                first_instance += batch.count;
+                continue;
+            }
+
+            let bind_group = self
+                .texture_bind_groups
+                .get(&batch.atlas_id)
+                .ok_or(Error::InvalidFramePacket("Decal atlas with unknown id"))?;
+            rpass.set_bind_group(1, bind_group, &[]);
+            rpass.set_vertex_buffer(
+                0,
+                self.instances.buffer(),
+                InstanceBuffer::<DecalInstanceData>::offset(first_instance),
+                0,
+            );
+            rpass.draw(0..BOX_VERTICES, 0..(batch.count as u32));
+            renderer.draw_counter.record(batch.count as u32);
+            first_instance += batch.count;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decal(scale: f32) -> Decal {
+        Decal {
+            transform: Matrix4::from_scale(scale),
+            atlas_pos: Vector2::new(0.0, 0.0),
+            atlas_size: Vector2::new(1.0, 1.0),
+            tint: [1.0; 4].into(),
+        }
+    }
+
+    #[test]
+    fn test_collect_instances() {
+        let decal_sets = [
+            FramePacketDecals {
+                atlas_id: AtlasId(3),
+                decals: vec![decal(1.0), decal(0.0), decal(2.0)],
+            },
+            FramePacketDecals {
+                atlas_id: AtlasId(1),
+                decals: vec![decal(0.0)],
+            },
+            FramePacketDecals {
+                atlas_id: AtlasId(3),
+                decals: vec![decal(0.5)],
+            },
+        ];
+
+        // Flattened boxes are left out, while their sets keep a batch in place
+        let (instances, batches) = collect_instances(&decal_sets);
+        let scales: Vec<_> = instances.iter().map(|instance| instance.transform.x.x).collect();
+        assert_eq!(scales, vec![1.0, 2.0, 0.5]);
+        assert_eq!(batches, vec![
+            DecalBatch { atlas_id: AtlasId(3), count: 2 },
+            DecalBatch { atlas_id: AtlasId(1), count: 0 },
+            DecalBatch { atlas_id: AtlasId(3), count: 1 },
+        ]);
+        assert_eq!(instances[1].inv_transform, Matrix4::from_scale(0.5));
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use cgmath::{Matrix4, SquareMatrix};
+
+use crate::{
+    error::Result,
+    shader_cache::ShaderCache,
+    vertex::Vertex,
+};
+use super::{
+    frame_packet::{FramePacket, InstanceData},
+    index_buffer::INDEX_FORMATS,
+    render_target::{ColorTarget, RenderTarget},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    shadow, ForwardRenderStage, Renderer, FORWARD_VERTEX_SHADER,
+};
+
+const GBUFFER_FRAGMENT_SHADER: &str = "./src/renderer/shaders/gbuffer.frag";
+const LIGHTING_VERTEX_SHADER: &str = "./src/renderer/shaders/fullscreen.vert";
+const LIGHTING_FRAGMENT_SHADER: &str = "./src/renderer/shaders/deferred_lighting.frag";
+
+/// The per-pixel surface attributes written by the geometry pass, for the lighting pass to shade
+///
+/// Depth isn't stored here, the geometry pass writes it to the scene render target's depth
+/// texture so that everything reading scene depth works the same under either render path.
+#[allow(unused)]
+pub struct GBuffer {
+    pub size: winit::dpi::PhysicalSize<u32>,
+
+    /// Base color, before any lighting
+    pub albedo: ColorTarget,
+
+    /// View space unit normal
+    pub normal: ColorTarget,
+
+    /// Specular strength in r, Blinn-Phong exponent scaled to 0..1 in g
+    pub material: ColorTarget,
+
+    /// Linear HDR light given off by the surface, added on top of its lighting
+    pub emissive: ColorTarget,
+}
+
+impl GBuffer {
+    pub const ALBEDO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+    pub const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    pub const MATERIAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+    pub const EMISSIVE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    pub fn new(device: &wgpu::Device, size: winit::dpi::PhysicalSize<u32>) -> Self {
+        Self {
+            size,
+            albedo: ColorTarget::new(device, size, Self::ALBEDO_FORMAT, "G-buffer albedo texture"),
+            normal: ColorTarget::new(device, size, Self::NORMAL_FORMAT, "G-buffer normal texture"),
+            material: ColorTarget::new(
+                device,
+                size,
+                Self::MATERIAL_FORMAT,
+                "G-buffer material texture",
+            ),
+            emissive: ColorTarget::new(
+                device,
+                size,
+                Self::EMISSIVE_FORMAT,
+                "G-buffer emissive texture",
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct DeferredUniformData {
+    /// Transforms clip space back in to view space, to reconstruct positions from depth
+    inv_proj: Matrix4<f32>,
+
+    /// Transforms view space in to the shadow map's clip space
+    view_to_light: Matrix4<f32>,
+}
+
+unsafe impl bytemuck::Pod for DeferredUniformData {}
+unsafe impl bytemuck::Zeroable for DeferredUniformData {}
+
+/// Represents a render stage that draws instanced 3d geometry in to a G-buffer, then lights every
+/// pixel of it in a single fullscreen pass
+///
+/// This is an alternative to `ForwardRenderStage`, whose uniforms, per-model bind groups,
+/// instances and lights it draws with rather than keeping its own copies.
+pub struct DeferredRenderStage {
+    /// One for each index format models can have
+    geometry_pipelines: HashMap<wgpu::IndexFormat, Rc<wgpu::RenderPipeline>>,
+    lighting_pipeline: Rc<wgpu::RenderPipeline>,
+
+    gbuffer: GBuffer,
+    gbuffer_bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    gbuffer_bind_group: wgpu::BindGroup,
+    gbuffer_sampler: Rc<wgpu::Sampler>,
+    uniform_buff: wgpu::Buffer,
+}
+
+impl DeferredRenderStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        forward: &ForwardRenderStage,
+        target: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let geometry_vs_spirv = shader_cache
+            .get_shader(FORWARD_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let geometry_fs_spirv = shader_cache
+            .get_shader(GBUFFER_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+        let lighting_vs_spirv = shader_cache
+            .get_shader(LIGHTING_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let lighting_defines = [
+            forward.lights.kind().shader_defines(),
+            target.depth_order.shader_defines(),
+        ]
+        .concat();
+        let lighting_fs_spirv = shader_cache
+            .get_shader_with_defines(
+                LIGHTING_FRAGMENT_SHADER,
+                shaderc::ShaderKind::Fragment,
+                &lighting_defines,
+            )
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<DeferredUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Deferred stage uniform buffer"),
+        });
+
+        let gbuffer_texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+            },
+        };
+        let gbuffer_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    gbuffer_texture_entry(1),
+                    gbuffer_texture_entry(2),
+                    gbuffer_texture_entry(3),
+                    gbuffer_texture_entry(4),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                    gbuffer_texture_entry(6),
+                ],
+                label: Some("Deferred stage G-buffer bind group layout"),
+            });
+
+        // The lighting pass reads exact texels, so the filtering here never comes in to play
+        let gbuffer_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let geometry_pipeline_layout =
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[
+                    &forward.uniform_bind_group_layout,
+                    &forward.texture_bind_group_layout,
+                ],
+            });
+
+        let lighting_pipeline_layout =
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[
+                    &forward.uniform_bind_group_layout,
+                    &gbuffer_bind_group_layout,
+                    forward.lights.bind_group_layout(),
+                ],
+            });
+
+        let gbuffer_color_state = |format| wgpu::ColorStateDescriptor {
+            format,
+            alpha_blend: wgpu::BlendDescriptor::REPLACE,
+            color_blend: wgpu::BlendDescriptor::REPLACE,
+            write_mask: wgpu::ColorWrite::ALL,
+        };
+        let mut create_geometry_pipeline = |index_format| {
+            resources.render_pipeline(device, &RenderPipelineDesc {
+                layout: &geometry_pipeline_layout,
+                vertex_shader: &geometry_vs_spirv,
+                fragment_shader: Some(&geometry_fs_spirv),
+                rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: wgpu::CullMode::Back,
+                    depth_bias: 0,
+                    depth_bias_slope_scale: 0.0,
+                    depth_bias_clamp: 0.0,
+                }),
+                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+                color_states: &[
+                    gbuffer_color_state(GBuffer::ALBEDO_FORMAT),
+                    gbuffer_color_state(GBuffer::NORMAL_FORMAT),
+                    gbuffer_color_state(GBuffer::MATERIAL_FORMAT),
+                    gbuffer_color_state(GBuffer::EMISSIVE_FORMAT),
+                ],
+                depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                    format: RenderTarget::DEPTH_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: target.depth_order.compare(wgpu::CompareFunction::Less),
+                    stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
+                    stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
+                    stencil_read_mask: 0,
+                    stencil_write_mask: 0,
+                }),
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format,
+                    vertex_buffers: &[
+                        Vertex::vertex_buffer_descriptor(),
+                        InstanceData::vertex_buffer_descriptor(),
+                    ],
+                },
+                sample_count: 1,
+            })
+        };
+        let geometry_pipelines = INDEX_FORMATS
+            .iter()
+            .map(|&index_format| (index_format, create_geometry_pipeline(index_format)))
+            .collect();
+
+        let lighting_pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &lighting_pipeline_layout,
+            vertex_shader: &lighting_vs_spirv,
+            fragment_shader: Some(&lighting_fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: RenderTarget::COLOR_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+        });
+
+        let gbuffer = GBuffer::new(device, target.size);
+        let gbuffer_bind_group = Self::create_gbuffer_bind_group(
+            device,
+            &gbuffer_bind_group_layout,
+            &gbuffer_sampler,
+            &uniform_buff,
+            &gbuffer,
+            target,
+        );
+
+        Ok(Self {
+            geometry_pipelines,
+            lighting_pipeline,
+            gbuffer,
+            gbuffer_bind_group_layout,
+            gbuffer_bind_group,
+            gbuffer_sampler,
+            uniform_buff,
+        })
+    }
+
+    fn create_gbuffer_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        uniform_buff: &wgpu::Buffer,
+        gbuffer: &GBuffer,
+        target: &RenderTarget,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: uniform_buff,
+                        range: 0..std::mem::size_of::<DeferredUniformData>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&gbuffer.albedo.view),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&gbuffer.normal.view),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&gbuffer.material.view),
+                },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&target.depth_view),
+                },
+                wgpu::Binding {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::Binding {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&gbuffer.emissive.view),
+                },
+            ],
+            label: Some("Deferred stage G-buffer bind group"),
+        })
+    }
+
+    /// Reallocate the G-buffer to match a new scene render target
+    pub fn set_target(&mut self, device: &wgpu::Device, target: &RenderTarget) {
+        self.gbuffer = GBuffer::new(device, target.size);
+        self.gbuffer_bind_group = Self::create_gbuffer_bind_group(
+            device,
+            &self.gbuffer_bind_group_layout,
+            &self.gbuffer_sampler,
+            &self.uniform_buff,
+            &self.gbuffer,
+            target,
+        );
+    }
+
+    /// Expects the forward stage's uniforms, instances and lights to already be up to date for
+    /// this frame
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        encoder: &mut wgpu::CommandEncoder,
+        viewport_size: winit::dpi::PhysicalSize<u32>,
+    ) -> Result<()> {
+        let forward = &renderer.forward_render_stage;
+        let target = &renderer.scene_target;
+
+        let inv_view = frame_packet.view.invert().unwrap_or_else(Matrix4::identity);
+        let view_to_light = match frame_packet.directional_light {
+            Some(light) => shadow::light_view_proj(&light, frame_packet.view) * inv_view,
+            None => Matrix4::identity(),
+        };
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[DeferredUniformData {
+                inv_proj: frame_packet.proj.invert().unwrap_or_else(Matrix4::identity),
+                view_to_light,
+            }]),
+        );
+
+        {
+            let gbuffer_attachment = |attachment| wgpu::RenderPassColorAttachmentDescriptor {
+                attachment,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::TRANSPARENT,
+            };
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    gbuffer_attachment(&self.gbuffer.albedo.view),
+                    gbuffer_attachment(&self.gbuffer.normal.view),
+                    gbuffer_attachment(&self.gbuffer.material.view),
+                    gbuffer_attachment(&self.gbuffer.emissive.view),
+                ],
+                depth_stencil_attachment: Some(target.depth_attachment(wgpu::LoadOp::Clear)),
+            });
+
+            rpass.set_viewport(
+                0.0,
+                0.0,
+                viewport_size.width as f32,
+                viewport_size.height as f32,
+                0.0,
+                1.0,
+            );
+            rpass.set_bind_group(0, forward.uniform_bind_group(), &[]);
+
+            let mut first_instance = 0;
+            for (i, model) in frame_packet.models.iter().enumerate() {
+                let (model_data, texture_bind_group, features) = forward.resolve(renderer, model)?;
+
+                // Blended over the lit scene afterwards by the forward stage
+                if forward.is_transparent(features) {
+                    first_instance += model.instances.len();
+                    continue;
+                }
+
+                rpass.set_pipeline(&self.geometry_pipelines[&model_data.indices.format]);
+                rpass.set_bind_group(1, texture_bind_group, &[]);
+                rpass.set_vertex_buffer(0, &model_data.vertex_buff, 0, 0);
+                rpass.set_index_buffer(&model_data.indices.buffer, 0, 0);
+                forward.draw_instances(
+                    renderer,
+                    &mut rpass,
+                    i,
+                    first_instance,
+                    model.instances.len(),
+                    model_data.indices.count,
+                );
+                first_instance += model.instances.len();
+            }
+        }
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &target.color_view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_viewport(
+            0.0,
+            0.0,
+            viewport_size.width as f32,
+            viewport_size.height as f32,
+            0.0,
+            1.0,
+        );
+        rpass.set_pipeline(&self.lighting_pipeline);
+        rpass.set_bind_group(0, forward.uniform_bind_group(), &[]);
+        rpass.set_bind_group(1, &self.gbuffer_bind_group, &[]);
+        rpass.set_bind_group(2, forward.lights.bind_group(), &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+
+        Ok(())
+    }
+}
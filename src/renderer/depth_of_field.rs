@@ -0,0 +1,361 @@
+use std::rc::Rc;
+
+use cgmath::{Matrix4, SquareMatrix};
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    frame_packet::DepthOfField,
+    render_graph::TransientDesc,
+    render_target::{ColorTarget, RenderTarget},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+const FULLSCREEN_VERTEX_SHADER: &str = "./src/renderer/shaders/fullscreen.vert";
+const COC_FRAGMENT_SHADER: &str = "./src/renderer/shaders/dof_coc.frag";
+const BLUR_FRAGMENT_SHADER: &str = "./src/renderer/shaders/dof_blur.frag";
+const COMPOSITE_FRAGMENT_SHADER: &str = "./src/renderer/shaders/dof_composite.frag";
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct DepthOfFieldUniformData {
+    /// Transforms clip space back in to view space, to find distances from depth
+    inv_proj: Matrix4<f32>,
+
+    /// Focus distance in meters, then aperture and max blur radius in pixels, w is unused
+    params: [f32; 4],
+
+    /// Size of the rendered region of the scene in xy, zw are unused
+    region: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for DepthOfFieldUniformData {}
+unsafe impl bytemuck::Zeroable for DepthOfFieldUniformData {}
+
+impl DepthOfFieldUniformData {
+    fn new(
+        depth_of_field: &DepthOfField,
+        proj: Matrix4<f32>,
+        region: winit::dpi::PhysicalSize<u32>,
+    ) -> Self {
+        // Sizes are given relative to the output's height, so the blur looks the same at any
+        // resolution
+        let height = region.height as f32;
+        Self {
+            inv_proj: proj.invert().unwrap_or_else(Matrix4::identity),
+            params: [
+                depth_of_field.focus_distance,
+                depth_of_field.aperture * height,
+                depth_of_field.max_blur * height,
+                0.0,
+            ],
+            region: [region.width as f32, height, 0.0, 0.0],
+        }
+    }
+}
+
+/// Represents a render stage that blurs the HDR scene away from a focus plane, before it's
+/// tonemapped
+///
+/// The circle of confusion is worked out for each pixel from the scene's depth and stored in
+/// the alpha of a copy of the scene, which is then blurred horizontally and vertically, and
+/// finally blended with the sharp copy by how blurred each pixel should be. Every pass reads
+/// and writes render graph transients of `transient_desc`, so this stage owns no textures of its
+/// own and binds them afresh each frame.
+///
+/// The scene's depth can't be read under MSAA, so the stage isn't created then.
+pub struct DepthOfFieldStage {
+    coc_pipeline: Rc<wgpu::RenderPipeline>,
+    horizontal_blur_pipeline: Rc<wgpu::RenderPipeline>,
+    vertical_blur_pipeline: Rc<wgpu::RenderPipeline>,
+    composite_pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    sampler: Rc<wgpu::Sampler>,
+    uniform_buff: wgpu::Buffer,
+}
+
+impl DepthOfFieldStage {
+    pub async fn new(device: &wgpu::Device, resources: &mut ResourceCache) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(FULLSCREEN_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let coc_fs_spirv = shader_cache
+            .get_shader(COC_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+        let horizontal_blur_fs_spirv = shader_cache
+            .get_shader(BLUR_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+        let vertical_blur_fs_spirv = shader_cache
+            .get_shader_with_defines(
+                BLUR_FRAGMENT_SHADER,
+                shaderc::ShaderKind::Fragment,
+                &[("VERTICAL", None)],
+            )
+            .await?;
+        let composite_fs_spirv = shader_cache
+            .get_shader(COMPOSITE_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<DepthOfFieldUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Depth of field stage uniform buffer"),
+        });
+
+        // Every pass reads up to two textures, the scene and its depth for the first pass, and
+        // the sharp and blurred copies for the composite. The blurs bind their source twice.
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+            },
+        };
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    texture_entry(1),
+                    texture_entry(2),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Depth of field stage bind group layout"),
+            });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&bind_group_layout],
+        });
+
+        let mut create_pipeline = |fs_spirv: &[u32]| {
+            resources.render_pipeline(device, &RenderPipelineDesc {
+                layout: &pipeline_layout,
+                vertex_shader: &vs_spirv,
+                fragment_shader: Some(fs_spirv),
+                rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: wgpu::CullMode::None,
+                    depth_bias: 0,
+                    depth_bias_slope_scale: 0.0,
+                    depth_bias_clamp: 0.0,
+                }),
+                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+                color_states: &[wgpu::ColorStateDescriptor {
+                    format: RenderTarget::COLOR_FORMAT,
+                    alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                    color_blend: wgpu::BlendDescriptor::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+                depth_stencil_state: None,
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format: wgpu::IndexFormat::Uint32,
+                    vertex_buffers: &[],
+                },
+                sample_count: 1,
+            })
+        };
+        let coc_pipeline = create_pipeline(&coc_fs_spirv);
+        let horizontal_blur_pipeline = create_pipeline(&horizontal_blur_fs_spirv);
+        let vertical_blur_pipeline = create_pipeline(&vertical_blur_fs_spirv);
+        let composite_pipeline = create_pipeline(&composite_fs_spirv);
+
+        // Every pass reads exact texels, so the filtering here never comes in to play
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        Ok(Self {
+            coc_pipeline,
+            horizontal_blur_pipeline,
+            vertical_blur_pipeline,
+            composite_pipeline,
+            bind_group_layout,
+            sampler,
+            uniform_buff,
+        })
+    }
+
+    /// Description of every texture passed between this stage's passes as render graph
+    /// transients, and of its output, for the given scene render target
+    pub fn transient_desc(scene: &RenderTarget) -> TransientDesc {
+        TransientDesc {
+            size: scene.size,
+            format: RenderTarget::COLOR_FORMAT,
+        }
+    }
+
+    /// Record one fullscreen pass over the top-left `region` pixels of `output`
+    #[allow(clippy::too_many_arguments)]
+    fn draw_pass(
+        &self,
+        renderer: &Renderer,
+        pipeline: &wgpu::RenderPipeline,
+        first: &wgpu::TextureView,
+        second: &wgpu::TextureView,
+        output: &ColorTarget,
+        region: winit::dpi::PhysicalSize<u32>,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let bind_group = renderer.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &self.uniform_buff,
+                        range: 0..std::mem::size_of::<DepthOfFieldUniformData>()
+                            as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(first),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(second),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+            label: Some("Depth of field stage bind group"),
+        });
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &output.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        // Keeps each fragment lined up with the texels it reads
+        rpass.set_viewport(0.0, 0.0, region.width as f32, region.height as f32, 0.0, 1.0);
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, &bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+    }
+
+    /// Copy the top-left `region` pixels of the scene in to `output`, with each pixel's circle of
+    /// confusion in alpha
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_coc(
+        &self,
+        renderer: &Renderer,
+        depth_of_field: &DepthOfField,
+        proj: Matrix4<f32>,
+        scene: &RenderTarget,
+        output: &ColorTarget,
+        region: winit::dpi::PhysicalSize<u32>,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[DepthOfFieldUniformData::new(depth_of_field, proj, region)]),
+        );
+
+        let pipeline = &self.coc_pipeline;
+        let (color, depth) = (&scene.color_view, &scene.depth_view);
+        self.draw_pass(renderer, pipeline, color, depth, output, region, encoder);
+    }
+
+    /// Blur the output of `draw_coc`, or of the other blur direction, along one axis
+    pub fn draw_blur(
+        &self,
+        renderer: &Renderer,
+        vertical: bool,
+        source: &ColorTarget,
+        output: &ColorTarget,
+        region: winit::dpi::PhysicalSize<u32>,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let pipeline = if vertical {
+            &self.vertical_blur_pipeline
+        } else {
+            &self.horizontal_blur_pipeline
+        };
+        let source = &source.view;
+        self.draw_pass(renderer, pipeline, source, source, output, region, encoder);
+    }
+
+    /// Blend the sharp output of `draw_coc` with the blurred copy, by how blurred each pixel
+    /// should be
+    pub fn draw_composite(
+        &self,
+        renderer: &Renderer,
+        sharp: &ColorTarget,
+        blurred: &ColorTarget,
+        output: &ColorTarget,
+        region: winit::dpi::PhysicalSize<u32>,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let pipeline = &self.composite_pipeline;
+        let (sharp, blurred) = (&sharp.view, &blurred.view);
+        self.draw_pass(renderer, pipeline, sharp, blurred, output, region, encoder);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{AbsDiffEq, Deg, PerspectiveFov};
+    use winit::dpi::PhysicalSize;
+
+    use super::*;
+
+    #[test]
+    fn test_uniform_data() {
+        let depth_of_field = DepthOfField {
+            focus_distance: 5.0,
+            aperture: 0.01,
+            max_blur: 0.02,
+        };
+        let proj: Matrix4<f32> = PerspectiveFov {
+            fovy: Deg(60.0).into(),
+            aspect: 2.0,
+            near: 0.1,
+            far: 100.0,
+        }
+        .into();
+
+        let data = |proj, width, height| {
+            DepthOfFieldUniformData::new(&depth_of_field, proj, PhysicalSize::new(width, height))
+        };
+
+        // Blur sizes scale with the region's height, not the width
+        let wide = data(proj, 800, 400);
+        assert_eq!(wide.params, [5.0, 4.0, 8.0, 0.0]);
+        assert_eq!(wide.region, [800.0, 400.0, 0.0, 0.0]);
+        assert!((wide.inv_proj * proj).abs_diff_eq(&Matrix4::identity(), 1e-5));
+        assert_eq!(data(proj, 800, 800).params, [5.0, 8.0, 16.0, 0.0]);
+
+        // A degenerate projection falls back to the identity rather than NaNs
+        assert_eq!(data(Matrix4::from_scale(0.0), 1, 1).inv_proj, Matrix4::identity());
+    }
+}
@@ -0,0 +1,158 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use tracing::warn;
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+
+/// Rows of a texture to buffer copy have to start on a multiple of this many bytes
+pub const COPY_ROW_ALIGNMENT: wgpu::BufferAddress = 256;
+
+type MapFuture =
+    Pin<Box<dyn Future<Output = Result<wgpu::BufferReadMapping, wgpu::BufferAsyncErr>>>>;
+
+/// The result of a `Renderer::sample_depth` call, which becomes available a frame or two later
+#[derive(Clone)]
+pub struct DepthSample {
+    value: Rc<Cell<Option<f32>>>,
+}
+
+impl DepthSample {
+    /// The sampled value from the depth buffer, in the range 0 (near plane) to 1 (far plane or
+    /// nothing drawn), or the other way round with reversed depth. None if the readback hasn't
+    /// completed yet.
+    #[allow(unused)]
+    pub fn try_get(&self) -> Option<f32> {
+        self.value.get()
+    }
+}
+
+struct PendingSample {
+    /// Output pixel that was asked for
+    position: PhysicalPosition<u32>,
+    result: DepthSample,
+}
+
+struct InFlightSample {
+    /// Kept alive until the mapping completes
+    _buffer: wgpu::Buffer,
+    mapping: MapFuture,
+    result: DepthSample,
+}
+
+/// Copies single texels out of the scene depth buffer back to the CPU without stalling.
+///
+/// Requested samples are copied out after the next frame's scene has been drawn, and the results
+/// are picked up whenever the GPU gets around to finishing that frame.
+#[derive(Default)]
+pub struct DepthReadback {
+    pending: Vec<PendingSample>,
+    in_flight: Vec<InFlightSample>,
+}
+
+impl DepthReadback {
+    pub fn request(&mut self, position: PhysicalPosition<u32>) -> DepthSample {
+        let result = DepthSample {
+            value: Rc::new(Cell::new(None)),
+        };
+        self.pending.push(PendingSample {
+            position,
+            result: result.clone(),
+        });
+        result
+    }
+
+    /// Record copies for all pending samples out of the freshly drawn depth buffer.
+    ///
+    /// The scene occupies the top left `scene_size` of the depth texture, and is stretched over
+    /// `output_size` on screen. Returns the buffers to map once the encoder has been submitted.
+    pub fn record_copies(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        depth_texture: &wgpu::Texture,
+        scene_size: PhysicalSize<u32>,
+        output_size: PhysicalSize<u32>,
+    ) -> Vec<(wgpu::Buffer, DepthSample)> {
+        self.pending
+            .drain(..)
+            .map(|sample| {
+                let scale = |pos: u32, scene: u32, output: u32| {
+                    ((pos as u64 * scene as u64 / output.max(1) as u64) as u32).min(scene - 1)
+                };
+                let x = scale(sample.position.x, scene_size.width, output_size.width);
+                let y = scale(sample.position.y, scene_size.height, output_size.height);
+
+                let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                    size: COPY_ROW_ALIGNMENT,
+                    usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+                    label: Some("Depth readback buffer"),
+                });
+                encoder.copy_texture_to_buffer(
+                    wgpu::TextureCopyView {
+                        texture: depth_texture,
+                        mip_level: 0,
+                        array_layer: 0,
+                        origin: wgpu::Origin3d { x, y, z: 0 },
+                    },
+                    wgpu::BufferCopyView {
+                        buffer: &buffer,
+                        offset: 0,
+                        bytes_per_row: COPY_ROW_ALIGNMENT as u32,
+                        rows_per_image: 1,
+                    },
+                    wgpu::Extent3d {
+                        width: 1,
+                        height: 1,
+                        depth: 1,
+                    },
+                );
+
+                (buffer, sample.result)
+            })
+            .collect()
+    }
+
+    /// Drop every pending request without reading anything back, leaving their samples empty
+    pub fn discard_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Start mapping the buffers returned by `record_copies`, which must have been submitted
+    pub fn map_copies(&mut self, copies: Vec<(wgpu::Buffer, DepthSample)>) {
+        for (buffer, result) in copies {
+            let mapping = Box::pin(buffer.map_read(0, std::mem::size_of::<f32>() as u64));
+            self.in_flight.push(InFlightSample {
+                _buffer: buffer,
+                mapping,
+                result,
+            });
+        }
+    }
+
+    /// Collect the results of any readbacks that have finished, without blocking
+    pub fn poll(&mut self, device: &wgpu::Device) {
+        if self.in_flight.is_empty() {
+            return;
+        }
+
+        device.poll(wgpu::Maintain::Poll);
+
+        let mut cx = Context::from_waker(Waker::noop());
+        self.in_flight.retain_mut(|sample| match sample.mapping.as_mut().poll(&mut cx) {
+            Poll::Pending => true,
+            Poll::Ready(Ok(mapping)) => {
+                let bytes = mapping.as_slice();
+                let value = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                sample.result.value.set(Some(value));
+                false
+            }
+            Poll::Ready(Err(_)) => {
+                warn!("Failed to map depth readback buffer");
+                false
+            }
+        });
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use winit::dpi::PhysicalSize;
+
+/// Number of recent frame times that are averaged before making a scaling decision
+const FRAME_HISTORY_LEN: usize = 16;
+
+/// Largest supported render scale. Supersampling beyond this is prohibitively expensive, and the
+/// downsampling filter only takes enough taps to cover this much.
+pub const MAX_RENDER_SCALE: f32 = 4.0;
+
+/// Chooses the internal resolution that the 3D scene is rendered at from recent frame times.
+///
+/// wgpu doesn't expose GPU timestamps, so the frame time used here is the wall clock time between
+/// consecutive frames. Once the renderer is GPU bound this is dominated by the time spent waiting
+/// on the GPU, which is what we want to react to.
+pub struct DynamicResolution {
+    /// Whether the scale should be adjusted at all. When disabled the scale is pinned at
+    /// `max_scale`.
+    pub enabled: bool,
+
+    /// The frame time that the scaling is trying to hit
+    pub target_frame_time: Duration,
+
+    /// Lower bound for the fraction of the output resolution rendered in each dimension
+    pub min_scale: f32,
+
+    /// Upper bound for the fraction of the output resolution rendered in each dimension
+    ///
+    /// Values above 1.0 supersample the scene. The scene render target has to be allocated large
+    /// enough to hold this, so change it with `Renderer::set_render_scale` rather than directly.
+    pub max_scale: f32,
+
+    scale: f32,
+    frame_times: VecDeque<Duration>,
+}
+
+impl Default for DynamicResolution {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            target_frame_time: Duration::from_secs_f32(1.0 / 60.0),
+            min_scale: 0.5,
+            max_scale: 1.0,
+            scale: 1.0,
+            frame_times: VecDeque::with_capacity(FRAME_HISTORY_LEN),
+        }
+    }
+}
+
+impl DynamicResolution {
+    /// The current fraction of the output resolution rendered in each dimension
+    #[allow(unused)]
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Record how long the most recent frame took, potentially updating the current scale
+    pub fn record_frame_time(&mut self, frame_time: Duration) {
+        if !self.enabled {
+            self.scale = self.max_scale;
+            self.frame_times.clear();
+            return;
+        }
+
+        if self.frame_times.len() == FRAME_HISTORY_LEN {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(frame_time);
+
+        // Wait for a full window of samples so that a single slow frame (eg. a shader compile or
+        // asset upload) doesn't immediately drop the resolution.
+        if self.frame_times.len() < FRAME_HISTORY_LEN {
+            return;
+        }
+
+        let average = self.frame_times.iter().sum::<Duration>() / FRAME_HISTORY_LEN as u32;
+        let load = average.as_secs_f32() / self.target_frame_time.as_secs_f32();
+
+        // The cost of rendering the scene is roughly proportional to the number of pixels, which
+        // goes with the square of the scale. Leave some headroom either side of the target so that
+        // the scale doesn't oscillate when sitting right on the budget.
+        let new_scale = if load > 1.05 {
+            self.scale * (1.0 / load).sqrt()
+        } else if load < 0.85 {
+            // Grow slowly, overshooting is more noticeable than being a little soft
+            self.scale * 1.02
+        } else {
+            return;
+        };
+
+        self.scale = new_scale.max(self.min_scale).min(self.max_scale);
+
+        // Judge the next change only on frames rendered at the new scale
+        self.frame_times.clear();
+    }
+
+    /// The size of the region the scene should be rendered to for the given output size
+    pub fn scaled_size(&self, output_size: PhysicalSize<u32>) -> PhysicalSize<u32> {
+        scale_size(output_size, self.scale)
+    }
+
+    /// The largest region the scene could be rendered to for the given output size
+    pub fn max_scaled_size(&self, output_size: PhysicalSize<u32>) -> PhysicalSize<u32> {
+        scale_size(output_size, self.max_scale)
+    }
+}
+
+fn scale_size(size: PhysicalSize<u32>, scale: f32) -> PhysicalSize<u32> {
+    PhysicalSize {
+        width: ((size.width as f32 * scale).round() as u32).max(1),
+        height: ((size.height as f32 * scale).round() as u32).max(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(dynres: &mut DynamicResolution, frame_time: Duration, frames: usize) {
+        for _ in 0..frames {
+            dynres.record_frame_time(frame_time);
+        }
+    }
+
+    #[test]
+    fn test_scale_drops_when_over_budget() {
+        let mut dynres = DynamicResolution::default();
+        feed(&mut dynres, Duration::from_millis(33), FRAME_HISTORY_LEN);
+        assert!(dynres.scale() < 1.0);
+        assert!(dynres.scale() >= dynres.min_scale);
+
+        feed(&mut dynres, Duration::from_millis(100), FRAME_HISTORY_LEN * 10);
+        assert_ulps_eq!(dynres.scale(), dynres.min_scale);
+    }
+
+    #[test]
+    fn test_scale_recovers_when_under_budget() {
+        let mut dynres = DynamicResolution::default();
+        feed(&mut dynres, Duration::from_millis(100), FRAME_HISTORY_LEN * 10);
+        assert_ulps_eq!(dynres.scale(), dynres.min_scale);
+
+        feed(&mut dynres, Duration::from_millis(5), FRAME_HISTORY_LEN * 100);
+        assert_ulps_eq!(dynres.scale(), dynres.max_scale);
+    }
+
+    #[test]
+    fn test_disabled_pins_max_scale() {
+        let mut dynres = DynamicResolution::default();
+        feed(&mut dynres, Duration::from_millis(100), FRAME_HISTORY_LEN * 10);
+        dynres.enabled = false;
+        dynres.record_frame_time(Duration::from_millis(100));
+        assert_ulps_eq!(dynres.scale(), dynres.max_scale);
+    }
+
+    #[test]
+    fn test_scaled_size() {
+        let dynres = DynamicResolution::default();
+        let size = dynres.scaled_size(PhysicalSize {
+            width: 1920,
+            height: 1080,
+        });
+        assert_eq!(size.width, 1920);
+        assert_eq!(size.height, 1080);
+    }
+
+    #[test]
+    fn test_supersampled_size() {
+        let dynres = DynamicResolution {
+            enabled: false,
+            max_scale: 2.0,
+            ..DynamicResolution::default()
+        };
+        let output_size = PhysicalSize {
+            width: 1920,
+            height: 1080,
+        };
+        assert_eq!(dynres.max_scaled_size(output_size), PhysicalSize::new(3840, 2160));
+    }
+}
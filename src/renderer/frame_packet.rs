@@ -1,5 +1,11 @@
-use super::{AtlasId, ModelId};
+use cgmath::{
+    ElementWise, EuclideanSpace, InnerSpace, Matrix4, Point3, SquareMatrix, Vector3, Vector4,
+    Zero,
+};
 
+use super::{AtlasId, MaterialId, ModelId, SkyboxId, TerrainId};
+
+#[repr(C)]
 #[derive(Clone, Copy)]
 pub struct InstanceData {
     /// Transforms positions from model space to world space
@@ -7,12 +13,31 @@ pub struct InstanceData {
 
     /// Transforms normals from model space to view space
     pub normal_matrix: cgmath::Matrix4<f32>,
+
+    pub style: InstanceStyle,
 }
 
 unsafe impl bytemuck::Pod for InstanceData {}
 unsafe impl bytemuck::Zeroable for InstanceData {}
 
 impl InstanceData {
+    /// An unstyled instance, with the normal matrix for drawing it from the given view
+    pub fn new(model_matrix: Matrix4<f32>, view: Matrix4<f32>) -> Self {
+        Self::try_new(model_matrix, view).expect("Model-View matrix had a zero determinant")
+    }
+
+    /// Like `new`, but None if the model and view together can't be inverted for the normal
+    /// matrix, eg. if the model is scaled to nothing along an axis
+    pub fn try_new(model_matrix: Matrix4<f32>, view: Matrix4<f32>) -> Option<Self> {
+        let mut normal_matrix = (view * model_matrix).invert()?;
+        normal_matrix.transpose_self();
+        Some(Self {
+            model_matrix,
+            normal_matrix,
+            style: InstanceStyle::default(),
+        })
+    }
+
     pub fn vertex_buffer_descriptor<'a>() -> wgpu::VertexBufferDescriptor<'a> {
         const FLOAT_SIZE: wgpu::BufferAddress = 4;
         wgpu::VertexBufferDescriptor {
@@ -59,14 +84,101 @@ impl InstanceData {
                     offset: FLOAT_SIZE * 4 * 7,
                     shader_location: 11,
                 },
+                // Locations 12 and 13 are the vertex joints and weights
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 8,
+                    shader_location: 14,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 9,
+                    shader_location: 15,
+                },
+            ],
+        }
+    }
+
+    /// Like `vertex_buffer_descriptor`, but leaving out the normal matrix so that locations 8 to
+    /// 11 are free for the previous model matrix when drawing motion vectors
+    pub fn motion_vector_buffer_descriptor<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        const FLOAT_SIZE: wgpu::BufferAddress = 4;
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: 0,
+                    shader_location: 4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4,
+                    shader_location: 5,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 2,
+                    shader_location: 6,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 3,
+                    shader_location: 7,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 8,
+                    shader_location: 14,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 9,
+                    shader_location: 15,
+                },
             ],
         }
     }
 }
 
+/// Varies how a single instance is shaded, without it needing a material of its own
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstanceStyle {
+    /// Linear RGBA multiplier of the material's base color, including the alpha that's tested
+    /// against its cutoff
+    pub tint: Vector4<f32>,
+
+    /// Multipliers of the material's emissive strength, metalness, roughness and occlusion
+    /// strength, in that order
+    pub material_scale: Vector4<f32>,
+}
+
+impl Default for InstanceStyle {
+    fn default() -> Self {
+        Self {
+            tint: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            material_scale: Vector4::new(1.0, 1.0, 1.0, 1.0),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct FramePacketModel {
     pub model_id: ModelId,
+
+    /// Material to draw the model with instead of the one it was uploaded with, if any
+    pub material_id: Option<MaterialId>,
+
     pub instances: Vec<InstanceData>,
+
+    /// Matrix for each joint of a skinned model, in the order its vertices refer to them, shared
+    /// by every instance. Empty for models that aren't skinned.
+    pub joint_matrices: Vec<cgmath::Matrix4<f32>>,
+
+    /// Indices in to `instances` of the ones outlined as selected, in ascending order
+    pub selected: Vec<usize>,
 }
 
 #[repr(C)]
@@ -83,6 +195,24 @@ pub struct SpriteInstanceData {
 
     /// The size of the sprite in the atlas
     pub atlas_size: cgmath::Vector2<f32>,
+
+    /// Counter-clockwise rotation about the sprite's center, in radians
+    pub rotation: f32,
+
+    /// Non-premultiplied RGBA multiplier for the atlas texels
+    pub tint: cgmath::Vector4<f32>,
+
+    /// Nine-slice borders as (left, top, right, bottom) fractions of the atlas rect. The corners
+    /// are drawn unstretched, the edges stretch along their length, and the middle stretches both
+    /// ways. All zero for a plain sprite.
+    pub slice_atlas: cgmath::Vector4<f32>,
+
+    /// The same borders as fractions of the sprite's size on screen
+    pub slice_screen: cgmath::Vector4<f32>,
+
+    /// Sprites on higher layers are drawn over those on lower ones, regardless of which
+    /// `FramePacketSprites` they're in. Sprites on the same layer are drawn in packet order.
+    pub layer: i32,
 }
 
 unsafe impl bytemuck::Pod for SpriteInstanceData {}
@@ -114,15 +244,602 @@ impl SpriteInstanceData {
                     offset: 6 * 4,
                     shader_location: 3,
                 },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float,
+                    offset: 8 * 4,
+                    shader_location: 4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: 9 * 4,
+                    shader_location: 5,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: 13 * 4,
+                    shader_location: 6,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: 17 * 4,
+                    shader_location: 7,
+                },
             ],
         }
     }
 }
 
+/// The point on the screen a `UiSprite` is positioned relative to, which is also the point on the
+/// sprite that ends up there
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(unused)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// How far across and down the screen (or sprite) the anchor is, from 0 to 1
+    fn fraction(self) -> cgmath::Vector2<f32> {
+        let (x, y) = match self {
+            Anchor::TopLeft => (0.0, 0.0),
+            Anchor::Top => (0.5, 0.0),
+            Anchor::TopRight => (1.0, 0.0),
+            Anchor::Left => (0.0, 0.5),
+            Anchor::Center => (0.5, 0.5),
+            Anchor::Right => (1.0, 0.5),
+            Anchor::BottomLeft => (0.0, 1.0),
+            Anchor::Bottom => (0.5, 1.0),
+            Anchor::BottomRight => (1.0, 1.0),
+        };
+        cgmath::Vector2::new(x, y)
+    }
+}
+
+/// Borders of a `UiSprite` that keep their size however big the sprite is drawn, so that a small
+/// panel texture can be stretched to any size without distorting its frame
+#[derive(Clone, Copy, Debug)]
+#[allow(unused)]
+pub struct NineSlice {
+    /// (left, top, right, bottom) borders as fractions of the atlas rect
+    pub atlas_insets: cgmath::Vector4<f32>,
+
+    /// (left, top, right, bottom) borders in logical pixels
+    pub insets: cgmath::Vector4<f32>,
+}
+
+/// A sprite laid out in logical pixels, which the sprite overlay places for whatever size the
+/// output is when it's drawn
+#[derive(Clone, Copy, Debug)]
+pub struct UiSprite {
+    pub anchor: Anchor,
+
+    /// Logical pixels from the anchor point on the screen to the same point on the sprite, with
+    /// +x to the right and +y down
+    pub offset: cgmath::Vector2<f32>,
+
+    /// Size in logical pixels
+    pub size: cgmath::Vector2<f32>,
+
+    pub atlas_pos: cgmath::Vector2<f32>,
+    pub atlas_size: cgmath::Vector2<f32>,
+    pub rotation: f32,
+    pub tint: cgmath::Vector4<f32>,
+    pub nine_slice: Option<NineSlice>,
+    pub layer: i32,
+}
+
+impl UiSprite {
+    /// Where this sprite ends up in clip space on an output of the given size, with `ui_scale`
+    /// physical pixels to each logical one
+    pub fn to_clip_space(
+        self,
+        output_size: winit::dpi::PhysicalSize<u32>,
+        ui_scale: f32,
+    ) -> SpriteInstanceData {
+        let output = cgmath::Vector2::new(output_size.width as f32, output_size.height as f32);
+        let anchor = self.anchor.fraction();
+        let size = self.size * ui_scale;
+        let top_left = anchor.mul_element_wise(output) + self.offset * ui_scale
+            - anchor.mul_element_wise(size);
+
+        let (slice_atlas, slice_screen) = match self.nine_slice {
+            Some(nine_slice) => {
+                let insets = nine_slice.insets * ui_scale;
+                let mut slice_screen = Vector4::new(
+                    insets.x / size.x,
+                    insets.y / size.y,
+                    insets.z / size.x,
+                    insets.w / size.y,
+                );
+                // Shrink the borders of a sprite too small to fit them, rather than overlap them
+                let across = slice_screen.x + slice_screen.z;
+                if across > 1.0 {
+                    slice_screen.x /= across;
+                    slice_screen.z /= across;
+                }
+                let down = slice_screen.y + slice_screen.w;
+                if down > 1.0 {
+                    slice_screen.y /= down;
+                    slice_screen.w /= down;
+                }
+                (nine_slice.atlas_insets, slice_screen)
+            }
+            None => (Vector4::zero(), Vector4::zero()),
+        };
+
+        SpriteInstanceData {
+            screen_pos: [2.0 * top_left.x / output.x - 1.0, 1.0 - 2.0 * top_left.y / output.y]
+                .into(),
+            // Clip space y goes up where the layout's goes down
+            screen_size: [2.0 * size.x / output.x, -2.0 * size.y / output.y].into(),
+            atlas_pos: self.atlas_pos,
+            atlas_size: self.atlas_size,
+            rotation: self.rotation,
+            tint: self.tint,
+            slice_atlas,
+            slice_screen,
+            layer: self.layer,
+        }
+    }
+}
 
 pub struct FramePacketSprites {
     pub atlas_id: AtlasId,
+
+    /// Already placed in clip space
     pub sprites: Vec<SpriteInstanceData>,
+
+    /// Laid out relative to the edges of the screen, so they keep their size and shape whatever
+    /// the output's size
+    pub ui_sprites: Vec<UiSprite>,
+}
+
+/// A texture projected on to whatever opaque surfaces lie inside a box, eg. a bullet hole or a
+/// road marking
+#[derive(Clone, Copy, Debug)]
+pub struct Decal {
+    /// Transforms the unit cube centred on the origin in to the decal's box in world space. The
+    /// texture is projected along the box's z axis, with its top towards +y.
+    pub transform: cgmath::Matrix4<f32>,
+
+    /// The atlas x/y coordinate of the top-left corner of the decal's texture
+    pub atlas_pos: cgmath::Vector2<f32>,
+
+    /// The size of the decal's texture in the atlas
+    pub atlas_size: cgmath::Vector2<f32>,
+
+    /// Linear RGBA multiplier for the atlas texels
+    pub tint: cgmath::Vector4<f32>,
+}
+
+pub struct FramePacketDecals {
+    pub atlas_id: AtlasId,
+    pub decals: Vec<Decal>,
+}
+
+/// A solid rectangle of the debug GUI
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GuiRect {
+    /// Logical pixels from the top-left of the screen to the top-left of the rectangle
+    pub pos: cgmath::Vector2<f32>,
+
+    /// Size in logical pixels
+    pub size: cgmath::Vector2<f32>,
+
+    /// Non-premultiplied RGBA color
+    pub color: cgmath::Vector4<f32>,
+}
+
+/// A string of text to draw over the top of everything else
+#[derive(Clone, Debug)]
+pub struct TextRun {
+    pub text: String,
+
+    /// The clip space x/y coordinate of the top-left corner of the first line
+    pub screen_pos: cgmath::Vector2<f32>,
+
+    /// Line height in physical pixels
+    pub size: f32,
+
+    /// Non-premultiplied RGBA color
+    pub color: cgmath::Vector4<f32>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct GlyphInstanceData {
+    /// The clip space x/y coordinate of the top-left corner of this glyph
+    pub screen_pos: cgmath::Vector2<f32>,
+
+    /// The clip space size of the this glyph
+    pub screen_size: cgmath::Vector2<f32>,
+
+    /// The glyph atlas x/y coordinate of the top-left corner of this glyph
+    pub atlas_pos: cgmath::Vector2<f32>,
+
+    /// The size of the glyph in the glyph atlas
+    pub atlas_size: cgmath::Vector2<f32>,
+
+    pub color: cgmath::Vector4<f32>,
+}
+
+unsafe impl bytemuck::Pod for GlyphInstanceData {}
+unsafe impl bytemuck::Zeroable for GlyphInstanceData {}
+
+impl GlyphInstanceData {
+    pub fn vertex_buffer_descriptor<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float2,
+                    offset: 0,
+                    shader_location: 0,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float2,
+                    offset: 2 * 4,
+                    shader_location: 1,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float2,
+                    offset: 4 * 4,
+                    shader_location: 2,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float2,
+                    offset: 6 * 4,
+                    shader_location: 3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: 8 * 4,
+                    shader_location: 4,
+                },
+            ],
+        }
+    }
+}
+
+/// A single unlit line in the scene for debugging, hidden behind anything in front of it
+#[derive(Clone, Copy, Debug)]
+pub struct DebugLine {
+    /// World space
+    pub start: Point3<f32>,
+    pub end: Point3<f32>,
+
+    /// Linear RGBA
+    pub color: Vector4<f32>,
+}
+
+impl DebugLine {
+    pub fn new(start: Point3<f32>, end: Point3<f32>, color: Vector4<f32>) -> Self {
+        Self { start, end, color }
+    }
+
+    /// The edges of an axis aligned box
+    pub fn aabb(min: Point3<f32>, max: Point3<f32>, color: Vector4<f32>) -> Vec<Self> {
+        let corners = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            Point3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        });
+        Self::box_edges(corners, color)
+    }
+
+    /// Red, green and blue lines along the X, Y and Z axes of a transform, from its origin
+    pub fn axes(transform: Matrix4<f32>, length: f32) -> Vec<Self> {
+        let origin = Point3::from_vec(transform.w.truncate());
+        [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()]
+            .iter()
+            .map(|&axis| {
+                let end = origin + (transform * axis.extend(0.0)).truncate().normalize() * length;
+                Self::new(origin, end, axis.extend(1.0))
+            })
+            .collect()
+    }
+
+    /// The edges of the volume a view-projection matrix maps on to clip space, with the depth
+    /// range of `cgmath::perspective`
+    #[allow(unused)]
+    pub fn frustum(view_proj: Matrix4<f32>, color: Vector4<f32>) -> Vec<Self> {
+        let inv_view_proj = view_proj.invert().unwrap_or_else(Matrix4::identity);
+        let corners = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            let sign = |bit| if i & bit == 0 { -1.0 } else { 1.0 };
+            let corner = inv_view_proj * Vector4::new(sign(1), sign(2), sign(4), 1.0);
+            Point3::from_vec(corner.truncate() / corner.w)
+        });
+        Self::box_edges(corners, color)
+    }
+
+    /// A line along each normal, from the position it belongs to
+    #[allow(unused)]
+    pub fn normals(
+        vertices: impl IntoIterator<Item = (Point3<f32>, Vector3<f32>)>,
+        length: f32,
+        color: Vector4<f32>,
+    ) -> Vec<Self> {
+        vertices
+            .into_iter()
+            .map(|(position, normal)| Self::new(position, position + normal * length, color))
+            .collect()
+    }
+
+    /// Join each of a box's corners, indexed by a bit per axis, to its three neighbours
+    fn box_edges(corners: [Point3<f32>; 8], color: Vector4<f32>) -> Vec<Self> {
+        let mut edges = Vec::with_capacity(12);
+        for i in 0..8 {
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    edges.push(Self::new(corners[i], corners[i | bit], color));
+                }
+            }
+        }
+        edges
+    }
+}
+
+/// An omnidirectional light whose power falls off linearly with distance
+#[derive(Clone, Copy, Debug)]
+pub struct PointLight {
+    /// World space position
+    pub position: cgmath::Point3<f32>,
+
+    /// Linear RGB color
+    pub color: cgmath::Vector3<f32>,
+
+    pub power: f32,
+}
+
+/// A point light that only shines within a cone
+#[derive(Clone, Copy, Debug)]
+pub struct SpotLight {
+    /// World space position
+    pub position: cgmath::Point3<f32>,
+
+    /// World space direction of the cone's axis
+    pub direction: cgmath::Vector3<f32>,
+
+    /// Linear RGB color
+    pub color: cgmath::Vector3<f32>,
+
+    pub power: f32,
+
+    /// Angle from the cone's axis at which the light starts to fade out
+    pub inner_angle: cgmath::Rad<f32>,
+
+    /// Angle from the cone's axis beyond which there is no light at all
+    pub outer_angle: cgmath::Rad<f32>,
+}
+
+/// A light infinitely far away, eg. the sun
+#[derive(Clone, Copy, Debug)]
+pub struct DirectionalLight {
+    /// World space direction the light travels in
+    pub direction: cgmath::Vector3<f32>,
+
+    /// Linear RGB color
+    pub color: cgmath::Vector3<f32>,
+
+    pub intensity: f32,
+}
+
+/// How quickly fog thickens with distance from the camera
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FogMode {
+    /// None before `start` meters, thickening evenly to completely hide everything past `end`
+    #[allow(unused)]
+    Linear { start: f32, end: f32 },
+
+    /// Hides `1 - e^(-density * distance)` of each surface
+    #[allow(unused)]
+    Exponential { density: f32 },
+
+    /// Hides `1 - e^(-(density * distance)^2)` of each surface, which stays clearer near the
+    /// camera than `Exponential` before thickening more sharply
+    ExponentialSquared { density: f32 },
+}
+
+/// Distance fog that models are blended toward
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fog {
+    /// Linear RGB color
+    pub color: cgmath::Vector3<f32>,
+    pub mode: FogMode,
+}
+
+impl Fog {
+    /// Start and end distances, density and mode, as the forward shader reads them
+    pub fn params(&self) -> Vector4<f32> {
+        match self.mode {
+            FogMode::Linear { start, end } => Vector4::new(start, end, 0.0, 0.0),
+            FogMode::Exponential { density } => Vector4::new(0.0, 0.0, density, 1.0),
+            FogMode::ExponentialSquared { density } => Vector4::new(0.0, 0.0, density, 2.0),
+        }
+    }
+}
+
+/// Blurs the scene away from a plane of perfect focus, like a camera lens with a wide aperture
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DepthOfField {
+    /// Distance from the camera to the plane that's in perfect focus, in meters
+    pub focus_distance: f32,
+
+    /// Blur radius of anything infinitely far away, as a fraction of the output's height. Closer
+    /// to the focus plane than that, the blur falls off the way a lens's circle of confusion
+    /// does.
+    pub aperture: f32,
+
+    /// Largest blur radius, as a fraction of the output's height, which anything in front of
+    /// the focus plane is clamped to as it approaches the camera
+    pub max_blur: f32,
+}
+
+impl Default for DepthOfField {
+    fn default() -> Self {
+        Self {
+            focus_distance: 3.0,
+            aperture: 0.01,
+            max_blur: 0.02,
+        }
+    }
+}
+
+/// A flat rectangle of water facing +Z, which reflects the scene above it and can show the scene
+/// below through its surface
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Water {
+    /// World space height of the surface
+    pub height: f32,
+
+    /// World space corners of the surface's rectangle in the XY plane
+    pub min: cgmath::Point2<f32>,
+    pub max: cgmath::Point2<f32>,
+
+    /// Linear RGB color that the scene below fades to with depth. Without refraction the water
+    /// is this color all the way down.
+    pub color: cgmath::Vector3<f32>,
+
+    /// How quickly the scene below fades to `color`, per meter of water it's seen through
+    pub density: f32,
+
+    /// World units covered by each repeat of the waves
+    pub wave_scale: f32,
+
+    /// How far the waves bend the reflection and refraction, as a fraction of the screen
+    pub distortion: f32,
+
+    /// Seconds since any fixed point, which the waves scroll with
+    pub time: f32,
+
+    /// Whether the scene below the surface is drawn to be seen through it
+    pub refraction: bool,
+}
+
+impl Default for Water {
+    fn default() -> Self {
+        Self {
+            height: 0.0,
+            min: cgmath::Point2::new(-50.0, -50.0),
+            max: cgmath::Point2::new(50.0, 50.0),
+            color: cgmath::Vector3::new(0.01, 0.05, 0.06),
+            density: 0.5,
+            wave_scale: 4.0,
+            distortion: 0.02,
+            time: 0.0,
+            refraction: true,
+        }
+    }
+}
+
+impl Water {
+    /// The world space plane of the surface, facing up out of the water
+    pub fn plane(&self) -> Vector4<f32> {
+        Vector4::new(0.0, 0.0, 1.0, -self.height)
+    }
+
+    /// Mirrors world space positions about the surface
+    pub fn reflection(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(Vector3::new(0.0, 0.0, self.height))
+            * Matrix4::from_nonuniform_scale(1.0, 1.0, -1.0)
+            * Matrix4::from_translation(Vector3::new(0.0, 0.0, -self.height))
+    }
+}
+
+/// Any of the kinds of light that can be put in a frame packet's light list
+#[derive(Clone, Copy, Debug)]
+pub enum Light {
+    Point(PointLight),
+    Spot(SpotLight),
+    Directional(DirectionalLight),
+}
+
+impl From<PointLight> for Light {
+    fn from(light: PointLight) -> Self {
+        Light::Point(light)
+    }
+}
+
+impl From<SpotLight> for Light {
+    fn from(light: SpotLight) -> Self {
+        Light::Spot(light)
+    }
+}
+
+impl From<DirectionalLight> for Light {
+    fn from(light: DirectionalLight) -> Self {
+        Light::Directional(light)
+    }
+}
+
+/// The scene drawn from a secondary camera in to a render target, eg. for a rear view mirror
+///
+/// Lights, shadows and the skybox are shared with the main view. The target can then be shown
+/// anywhere in the frame with sprites.
+pub struct FramePacketView {
+    /// From `Renderer::create_render_target`
+    pub target: AtlasId,
+
+    pub view: cgmath::Matrix4<f32>,
+    pub proj: cgmath::Matrix4<f32>,
+
+    /// Like `FramePacket::models`, but with normal matrices for this view
+    pub models: Vec<FramePacketModel>,
+}
+
+/// A region of the output as fractions of its size, measured from its top left corner
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewportRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl ViewportRect {
+    /// `count` equal width columns covering the whole output, from left to right
+    pub fn columns(count: usize) -> Vec<Self> {
+        let width = 1.0 / count as f32;
+        (0..count)
+            .map(|i| ViewportRect {
+                x: i as f32 * width,
+                y: 0.0,
+                width,
+                height: 1.0,
+            })
+            .collect()
+    }
+
+    /// Width over height of this region of an output with the given aspect ratio
+    pub fn aspect_ratio(self, output_aspect_ratio: f32) -> f32 {
+        output_aspect_ratio * self.width / self.height
+    }
+}
+
+/// One of several cameras drawn side by side in place of the main view, eg. for split-screen
+///
+/// Lights, shadows and the skybox are shared with the main view, as with `FramePacketView`.
+pub struct FramePacketSplitView {
+    pub viewport: ViewportRect,
+
+    pub view: cgmath::Matrix4<f32>,
+
+    /// Should be built with `ViewportRect::aspect_ratio`, so that the view isn't stretched
+    pub proj: cgmath::Matrix4<f32>,
+
+    /// Like `FramePacket::models`, but with normal matrices for this view
+    pub models: Vec<FramePacketModel>,
 }
 
 /// Desribes a frame for the renderer to draw in its entirity
@@ -130,5 +847,289 @@ pub struct FramePacket {
     pub view: cgmath::Matrix4<f32>,
     pub proj: cgmath::Matrix4<f32>,
     pub models: Vec<FramePacketModel>,
+    pub lights: Vec<Light>,
+
+    /// The one directional light that casts shadows. Any directional lights in `lights` don't.
+    pub directional_light: Option<DirectionalLight>,
+
+    /// Only drawn by the forward render path
+    pub fog: Option<Fog>,
+
+    /// World space plane that models and the terrain are cut off at, with anything on the side
+    /// its normal points away from discarded. Only drawn by the forward render path.
+    pub clip_plane: Option<Vector4<f32>>,
+
+    /// Not applied to secondary or split views, nor under MSAA where the scene's depth can't be
+    /// read
+    pub depth_of_field: Option<DepthOfField>,
+
+    /// Projected on to the opaque scene before the skybox and transparent models are drawn. Not
+    /// applied to secondary or split views, nor under MSAA where the scene's depth can't be read.
+    pub decals: Vec<FramePacketDecals>,
     pub overlay_sprites: Vec<FramePacketSprites>,
-}
\ No newline at end of file
+    pub overlay_text: Vec<TextRun>,
+    pub debug_lines: Vec<DebugLine>,
+
+    /// Drawn over the overlay sprites and under the overlay text, in order
+    pub debug_gui: Vec<GuiRect>,
+
+    /// Physical pixels to each logical pixel of the overlay sprites' layout
+    pub ui_scale: f32,
+
+    /// Cubemap drawn wherever the scene leaves the background uncovered
+    pub skybox: Option<SkyboxId>,
+
+    /// Not drawn in secondary or split views
+    pub terrain: Option<TerrainId>,
+
+    /// Drawn after the opaque scene, reflecting it and the skybox. Not drawn in secondary or
+    /// split views, nor under MSAA where the scene below's depth can't be read.
+    pub water: Option<Water>,
+
+    /// Drawn before the overlays, so that sprites can show them
+    pub views: Vec<FramePacketView>,
+
+    /// When not empty, these are drawn in their own regions of the output instead of the main
+    /// view. Debug lines and depth readback still follow the main view.
+    pub split_views: Vec<FramePacketSplitView>,
+}
+
+impl FramePacket {
+    /// The scene as seen from a secondary view, with none of the main view's overlays
+    pub fn for_view(&self, view: &FramePacketView) -> FramePacket {
+        self.with_camera(view.view, view.proj, &view.models)
+    }
+
+    /// The scene as seen from one of the split views, with none of the main view's overlays
+    pub fn for_split_view(&self, split_view: &FramePacketSplitView) -> FramePacket {
+        self.with_camera(split_view.view, split_view.proj, &split_view.models)
+    }
+
+    fn with_camera(
+        &self,
+        view: cgmath::Matrix4<f32>,
+        proj: cgmath::Matrix4<f32>,
+        models: &[FramePacketModel],
+    ) -> FramePacket {
+        FramePacket {
+            view,
+            proj,
+            models: models.to_vec(),
+            lights: self.lights.clone(),
+            directional_light: self.directional_light,
+            fog: self.fog,
+            clip_plane: None,
+            depth_of_field: None,
+            decals: Vec::new(),
+            overlay_sprites: Vec::new(),
+            overlay_text: Vec::new(),
+            debug_lines: Vec::new(),
+            debug_gui: Vec::new(),
+            ui_scale: self.ui_scale,
+            skybox: self.skybox,
+            terrain: None,
+            water: None,
+            views: Vec::new(),
+            split_views: Vec::new(),
+        }
+    }
+
+    /// The scene as seen from above or below the water, eg. mirrored about its surface, with
+    /// everything on the far side of `clip_plane` cut away
+    ///
+    /// Each instance's normal matrix is worked out again for the new view.
+    pub fn for_water_view(
+        &self,
+        view: cgmath::Matrix4<f32>,
+        proj: cgmath::Matrix4<f32>,
+        clip_plane: Vector4<f32>,
+    ) -> FramePacket {
+        let models: Vec<_> = self
+            .models
+            .iter()
+            .map(|model| FramePacketModel {
+                model_id: model.model_id,
+                material_id: model.material_id,
+                instances: model
+                    .instances
+                    .iter()
+                    .map(|instance| InstanceData {
+                        style: instance.style,
+                        ..InstanceData::new(instance.model_matrix, view)
+                    })
+                    .collect(),
+                joint_matrices: model.joint_matrices.clone(),
+                selected: model.selected.clone(),
+            })
+            .collect();
+        FramePacket {
+            clip_plane: Some(clip_plane),
+            ..self.with_camera(view, proj, &models)
+        }
+    }
+
+    /// Every instance of the models passing `filter`, as (model index, instance index) pairs
+    /// ordered from the farthest from the camera to the nearest
+    ///
+    /// Instances are compared by their origins, so this is only exact for instances that don't
+    /// overlap each other.
+    pub fn instances_back_to_front(
+        &self,
+        filter: impl Fn(&FramePacketModel) -> bool,
+    ) -> Vec<(usize, usize)> {
+        let mut instances: Vec<_> = self
+            .models
+            .iter()
+            .enumerate()
+            .filter(|(_, model)| filter(model))
+            .flat_map(|(model_index, model)| {
+                model.instances.iter().enumerate().map(move |(instance_index, instance)| {
+                    // View space looks down -Z, so the farthest instances have the lowest Z
+                    let depth = (self.view * instance.model_matrix).w.z;
+                    (depth, model_index, instance_index)
+                })
+            })
+            .collect();
+        instances.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        instances
+            .into_iter()
+            .map(|(_, model_index, instance_index)| (model_index, instance_index))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Transform, Vector2};
+
+    #[test]
+    fn test_instance_try_new() {
+        let view = Matrix4::from_translation(Vector3::new(0.0, 0.0, -5.0));
+        let instance = InstanceData::try_new(Matrix4::from_scale(2.0), view).unwrap();
+        assert_eq!(instance.model_matrix, Matrix4::from_scale(2.0));
+
+        let flattened = Matrix4::from_nonuniform_scale(1.0, 0.0, 1.0);
+        assert!(InstanceData::try_new(flattened, view).is_none());
+    }
+
+    #[test]
+    fn test_instances_back_to_front() {
+        let instance = |y: f32| InstanceData {
+            model_matrix: Matrix4::from_translation(Vector3::new(0.0, y, 0.0)),
+            normal_matrix: Matrix4::identity(),
+            style: InstanceStyle::default(),
+        };
+        let model = |model_id, instances| FramePacketModel {
+            model_id,
+            material_id: None,
+            instances,
+            joint_matrices: Vec::new(),
+            selected: Vec::new(),
+        };
+
+        let frame_packet = FramePacket {
+            view: Matrix4::look_at(
+                Point3::new(0.0, -10.0, 0.0),
+                Point3::new(0.0, 0.0, 0.0),
+                Vector3::unit_z(),
+            ),
+            proj: Matrix4::identity(),
+            models: vec![
+                model(ModelId(0), vec![instance(1.0), instance(5.0)]),
+                model(ModelId(1), vec![instance(100.0)]),
+                model(ModelId(2), vec![instance(3.0), instance(-2.0)]),
+            ],
+            lights: Vec::new(),
+            directional_light: None,
+            fog: None,
+            clip_plane: None,
+            depth_of_field: None,
+            decals: Vec::new(),
+            overlay_sprites: Vec::new(),
+            overlay_text: Vec::new(),
+            debug_lines: Vec::new(),
+            debug_gui: Vec::new(),
+            ui_scale: 1.0,
+            skybox: None,
+            terrain: None,
+            water: None,
+            views: Vec::new(),
+            split_views: Vec::new(),
+        };
+
+        let order = frame_packet.instances_back_to_front(|model| model.model_id != ModelId(1));
+        assert_eq!(order, vec![(0, 1), (2, 0), (0, 0), (2, 1)]);
+    }
+
+    #[test]
+    fn test_water_reflection() {
+        let water = Water {
+            height: 2.0,
+            ..Water::default()
+        };
+        let mirrored = water.reflection().transform_point(Point3::new(1.0, -3.0, 5.0));
+        assert_eq!(mirrored, Point3::new(1.0, -3.0, -1.0));
+        assert_eq!(water.plane().dot(Vector4::new(4.0, 4.0, 3.0, 1.0)), 1.0);
+        assert_eq!(water.plane().dot(mirrored.to_homogeneous()), -3.0);
+    }
+
+    #[test]
+    fn test_ui_sprite_to_clip_space() {
+        let sprite = |anchor, offset: [f32; 2]| UiSprite {
+            anchor,
+            offset: offset.into(),
+            size: [50.0, 25.0].into(),
+            atlas_pos: [0.0, 0.0].into(),
+            atlas_size: [1.0, 1.0].into(),
+            rotation: 0.0,
+            tint: [1.0, 1.0, 1.0, 1.0].into(),
+            nine_slice: None,
+            layer: 0,
+        };
+        let assert_near = |actual: Vector2<f32>, expected: [f32; 2]| {
+            assert!((actual - Vector2::from(expected)).magnitude() < 1e-5, "{:?}", actual);
+        };
+        let output_size = winit::dpi::PhysicalSize::new(400, 200);
+
+        let top_left = sprite(Anchor::TopLeft, [10.0, 20.0]).to_clip_space(output_size, 2.0);
+        assert_near(top_left.screen_pos, [-0.9, 0.6]);
+        assert_near(top_left.screen_size, [0.5, -0.5]);
+
+        // Sizes stay in pixels whatever the output's aspect ratio
+        let wide = winit::dpi::PhysicalSize::new(800, 200);
+        let center = sprite(Anchor::Center, [0.0, 0.0]).to_clip_space(wide, 2.0);
+        assert_near(center.screen_pos, [-0.125, 0.25]);
+        assert_near(center.screen_size, [0.25, -0.5]);
+
+        let bottom_right = sprite(Anchor::BottomRight, [-10.0, 0.0]);
+        assert_near(bottom_right.to_clip_space(output_size, 1.0).screen_pos, [0.7, -0.75]);
+    }
+
+    #[test]
+    fn test_nine_slice_insets() {
+        let panel = |size: [f32; 2]| UiSprite {
+            anchor: Anchor::TopLeft,
+            offset: [0.0, 0.0].into(),
+            size: size.into(),
+            atlas_pos: [0.0, 0.0].into(),
+            atlas_size: [1.0, 1.0].into(),
+            rotation: 0.0,
+            tint: [1.0, 1.0, 1.0, 1.0].into(),
+            nine_slice: Some(NineSlice {
+                atlas_insets: [0.25, 0.25, 0.25, 0.25].into(),
+                insets: [8.0, 4.0, 8.0, 4.0].into(),
+            }),
+            layer: 0,
+        };
+        let output_size = winit::dpi::PhysicalSize::new(400, 200);
+
+        let sprite = panel([100.0, 40.0]).to_clip_space(output_size, 2.0);
+        assert_eq!(sprite.slice_atlas, [0.25, 0.25, 0.25, 0.25].into());
+        assert_eq!(sprite.slice_screen, [0.08, 0.1, 0.08, 0.1].into());
+
+        // Too narrow for both side borders at full size
+        let narrow = panel([10.0, 40.0]).to_clip_space(output_size, 1.0);
+        assert_eq!(narrow.slice_screen, [0.5, 0.1, 0.5, 0.1].into());
+    }
+}
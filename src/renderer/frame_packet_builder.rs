@@ -0,0 +1,401 @@
+use cgmath::{Matrix4, SquareMatrix};
+
+use crate::camera::Camera;
+use crate::error::{Error, Result};
+use super::frame_packet::{
+    Decal, DebugLine, DepthOfField, DirectionalLight, Fog, FramePacket, FramePacketDecals,
+    FramePacketModel, FramePacketSprites, InstanceData, Light, SpriteInstanceData, TextRun,
+    UiSprite, Water,
+};
+use super::{AtlasId, MaterialId, ModelId, Renderer, SkyboxId, TerrainId};
+
+/// Everything a frame packet can refer to by handle, which `FramePacketBuilder::build` checks
+/// the packet against
+pub trait KnownHandles {
+    fn has_model(&self, id: ModelId) -> bool;
+    fn has_material(&self, id: MaterialId) -> bool;
+
+    /// Whether sprites can be drawn from `id`, which render targets can be as well as atlases
+    fn has_sprite_atlas(&self, id: AtlasId) -> bool;
+    fn has_decal_atlas(&self, id: AtlasId) -> bool;
+    fn has_skybox(&self, id: SkyboxId) -> bool;
+    fn has_terrain(&self, id: TerrainId) -> bool;
+}
+
+impl KnownHandles for Renderer {
+    fn has_model(&self, id: ModelId) -> bool {
+        self.models.contains_key(&id)
+    }
+
+    fn has_material(&self, id: MaterialId) -> bool {
+        self.materials.contains_key(&id)
+    }
+
+    // Atlases are still valid while they load, they're just not drawn yet
+    fn has_sprite_atlas(&self, id: AtlasId) -> bool {
+        self.has_decal_atlas(id) || self.view_targets.contains_key(&id)
+    }
+
+    fn has_decal_atlas(&self, id: AtlasId) -> bool {
+        self.atlases.contains_key(&id) || self.pending_atlases.contains(&id)
+    }
+
+    fn has_skybox(&self, id: SkyboxId) -> bool {
+        self.skyboxes.contains_key(&id)
+    }
+
+    fn has_terrain(&self, id: TerrainId) -> bool {
+        self.terrains.contains_key(&id)
+    }
+}
+
+/// Instances added with `add_instances`, whose normal matrices wait on the final view
+struct PendingModel {
+    model_id: ModelId,
+    material_id: Option<MaterialId>,
+    model_matrices: Vec<Matrix4<f32>>,
+}
+
+/// Puts a `FramePacket` together from a camera and handles, and checks that the renderer it's
+/// for knows every handle before it's drawn
+///
+/// Instances only need their model matrices, as the normal matrices are worked out from the
+/// camera when the packet is built. Anything the builder doesn't cover can still be set on the
+/// built packet directly.
+pub struct FramePacketBuilder {
+    view: Matrix4<f32>,
+    proj: Matrix4<f32>,
+    pending_models: Vec<PendingModel>,
+    models: Vec<FramePacketModel>,
+    lights: Vec<Light>,
+    directional_light: Option<DirectionalLight>,
+    fog: Option<Fog>,
+    depth_of_field: Option<DepthOfField>,
+    decals: Vec<FramePacketDecals>,
+    overlay_sprites: Vec<FramePacketSprites>,
+    overlay_text: Vec<TextRun>,
+    debug_lines: Vec<DebugLine>,
+    ui_scale: f32,
+    skybox: Option<SkyboxId>,
+    terrain: Option<TerrainId>,
+    water: Option<Water>,
+}
+
+impl Default for FramePacketBuilder {
+    fn default() -> Self {
+        Self {
+            view: Matrix4::identity(),
+            proj: Matrix4::identity(),
+            pending_models: Vec::new(),
+            models: Vec::new(),
+            lights: Vec::new(),
+            directional_light: None,
+            fog: None,
+            depth_of_field: None,
+            decals: Vec::new(),
+            overlay_sprites: Vec::new(),
+            overlay_text: Vec::new(),
+            debug_lines: Vec::new(),
+            ui_scale: 1.0,
+            skybox: None,
+            terrain: None,
+            water: None,
+        }
+    }
+}
+
+impl FramePacketBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Draw from the camera, projected for an output with the given width / height
+    pub fn camera(self, camera: &Camera, aspect_ratio: f32) -> Self {
+        self.view_proj(camera.view(), camera.proj(aspect_ratio))
+    }
+
+    /// Draw with the given view and projection matrices, for cameras other than `Camera`
+    pub fn view_proj(mut self, view: Matrix4<f32>, proj: Matrix4<f32>) -> Self {
+        self.view = view;
+        self.proj = proj;
+        self
+    }
+
+    /// Draw the model once at each of the given transforms, with the material it was uploaded
+    /// with
+    pub fn add_instances(
+        self,
+        model_id: ModelId,
+        model_matrices: impl IntoIterator<Item = Matrix4<f32>>,
+    ) -> Self {
+        self.add_instances_with_material(model_id, None, model_matrices)
+    }
+
+    /// Like `add_instances`, but drawn with another material if one is given
+    pub fn add_instances_with_material(
+        mut self,
+        model_id: ModelId,
+        material_id: Option<MaterialId>,
+        model_matrices: impl IntoIterator<Item = Matrix4<f32>>,
+    ) -> Self {
+        self.pending_models.push(PendingModel {
+            model_id,
+            material_id,
+            model_matrices: model_matrices.into_iter().collect(),
+        });
+        self
+    }
+
+    /// Add a model whose instances are already complete, eg. to style or select them
+    pub fn add_model(mut self, model: FramePacketModel) -> Self {
+        self.models.push(model);
+        self
+    }
+
+    /// Decals textured from the given atlas
+    pub fn add_decals(
+        mut self,
+        atlas_id: AtlasId,
+        decals: impl IntoIterator<Item = Decal>,
+    ) -> Self {
+        self.decals.push(FramePacketDecals {
+            atlas_id,
+            decals: decals.into_iter().collect(),
+        });
+        self
+    }
+
+    /// Overlay sprites already placed in clip space
+    pub fn add_sprites(
+        mut self,
+        atlas_id: AtlasId,
+        sprites: impl IntoIterator<Item = SpriteInstanceData>,
+    ) -> Self {
+        self.overlay_sprites.push(FramePacketSprites {
+            atlas_id,
+            sprites: sprites.into_iter().collect(),
+            ui_sprites: Vec::new(),
+        });
+        self
+    }
+
+    /// Overlay sprites laid out relative to the edges of the screen
+    pub fn add_ui_sprites(
+        mut self,
+        atlas_id: AtlasId,
+        ui_sprites: impl IntoIterator<Item = UiSprite>,
+    ) -> Self {
+        self.overlay_sprites.push(FramePacketSprites {
+            atlas_id,
+            sprites: Vec::new(),
+            ui_sprites: ui_sprites.into_iter().collect(),
+        });
+        self
+    }
+
+    pub fn add_light(mut self, light: impl Into<Light>) -> Self {
+        self.lights.push(light.into());
+        self
+    }
+
+    /// The one directional light that casts shadows
+    pub fn directional_light(mut self, light: DirectionalLight) -> Self {
+        self.directional_light = Some(light);
+        self
+    }
+
+    pub fn fog(mut self, fog: Fog) -> Self {
+        self.fog = Some(fog);
+        self
+    }
+
+    pub fn depth_of_field(mut self, depth_of_field: DepthOfField) -> Self {
+        self.depth_of_field = Some(depth_of_field);
+        self
+    }
+
+    pub fn skybox(mut self, skybox: SkyboxId) -> Self {
+        self.skybox = Some(skybox);
+        self
+    }
+
+    pub fn terrain(mut self, terrain: TerrainId) -> Self {
+        self.terrain = Some(terrain);
+        self
+    }
+
+    pub fn water(mut self, water: Water) -> Self {
+        self.water = Some(water);
+        self
+    }
+
+    pub fn add_text(mut self, text: TextRun) -> Self {
+        self.overlay_text.push(text);
+        self
+    }
+
+    pub fn add_debug_lines(mut self, lines: impl IntoIterator<Item = DebugLine>) -> Self {
+        self.debug_lines.extend(lines);
+        self
+    }
+
+    /// Physical pixels to each logical pixel of the UI sprites' layout
+    pub fn ui_scale(mut self, ui_scale: f32) -> Self {
+        self.ui_scale = ui_scale;
+        self
+    }
+
+    /// Finish the packet, failing if it refers to anything the renderer doesn't know about, has
+    /// a model with no instances, or has an instance whose normal matrix can't be worked out
+    pub fn build(self, renderer: &impl KnownHandles) -> Result<FramePacket> {
+        let view = self.view;
+        let mut models = self.models;
+        for pending in self.pending_models {
+            let instances = pending
+                .model_matrices
+                .into_iter()
+                .map(|model_matrix| InstanceData::try_new(model_matrix, view))
+                .collect::<Option<_>>()
+                .ok_or(Error::InvalidFramePacket("Instance with a non-invertible model matrix"))?;
+            models.push(FramePacketModel {
+                model_id: pending.model_id,
+                material_id: pending.material_id,
+                instances,
+                joint_matrices: Vec::new(),
+                selected: Vec::new(),
+            });
+        }
+
+        for model in &models {
+            if !renderer.has_model(model.model_id) {
+                return Err(Error::InvalidFramePacket("Model with unknown id"));
+            }
+            if model.material_id.is_some_and(|id| !renderer.has_material(id)) {
+                return Err(Error::InvalidFramePacket("Material with unknown id"));
+            }
+            if model.instances.is_empty() {
+                return Err(Error::InvalidFramePacket("Model with no instances"));
+            }
+        }
+        for sprites in &self.overlay_sprites {
+            if !renderer.has_sprite_atlas(sprites.atlas_id) {
+                return Err(Error::InvalidFramePacket("Sprite atlas with unknown id"));
+            }
+        }
+        for decals in &self.decals {
+            if !renderer.has_decal_atlas(decals.atlas_id) {
+                return Err(Error::InvalidFramePacket("Decal atlas with unknown id"));
+            }
+        }
+        if self.skybox.is_some_and(|id| !renderer.has_skybox(id)) {
+            return Err(Error::InvalidFramePacket("Skybox with unknown id"));
+        }
+        if self.terrain.is_some_and(|id| !renderer.has_terrain(id)) {
+            return Err(Error::InvalidFramePacket("Terrain with unknown id"));
+        }
+
+        Ok(FramePacket {
+            view,
+            proj: self.proj,
+            models,
+            lights: self.lights,
+            directional_light: self.directional_light,
+            fog: self.fog,
+            clip_plane: None,
+            depth_of_field: self.depth_of_field,
+            decals: self.decals,
+            overlay_sprites: self.overlay_sprites,
+            overlay_text: self.overlay_text,
+            debug_lines: self.debug_lines,
+            debug_gui: Vec::new(),
+            ui_scale: self.ui_scale,
+            skybox: self.skybox,
+            terrain: self.terrain,
+            water: self.water,
+            views: Vec::new(),
+            split_views: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Knows ids 0 of everything, and sprite atlas 1 as a render target
+    struct Handles;
+
+    impl KnownHandles for Handles {
+        fn has_model(&self, id: ModelId) -> bool {
+            id == ModelId(0)
+        }
+
+        fn has_material(&self, id: MaterialId) -> bool {
+            id == MaterialId(0)
+        }
+
+        fn has_sprite_atlas(&self, id: AtlasId) -> bool {
+            id == AtlasId(0) || id == AtlasId(1)
+        }
+
+        fn has_decal_atlas(&self, id: AtlasId) -> bool {
+            id == AtlasId(0)
+        }
+
+        fn has_skybox(&self, id: SkyboxId) -> bool {
+            id == SkyboxId(0)
+        }
+
+        fn has_terrain(&self, id: TerrainId) -> bool {
+            id == TerrainId(0)
+        }
+    }
+
+    fn error(builder: FramePacketBuilder) -> Option<&'static str> {
+        match builder.build(&Handles) {
+            Ok(_) => None,
+            Err(Error::InvalidFramePacket(reason)) => Some(reason),
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn test_build_checks_handles() {
+        let model = ModelId(0);
+        let instance = || vec![Matrix4::identity()];
+        let builder = || {
+            FramePacketBuilder::new()
+                .add_instances_with_material(model, Some(MaterialId(0)), instance())
+                .add_sprites(AtlasId(1), Vec::new())
+                .add_decals(AtlasId(0), Vec::new())
+                .skybox(SkyboxId(0))
+                .terrain(TerrainId(0))
+        };
+
+        let frame_packet = builder().build(&Handles).unwrap();
+        assert_eq!(frame_packet.models[0].instances.len(), 1);
+
+        let unknown_model = builder().add_instances(ModelId(1), instance());
+        assert_eq!(error(unknown_model), Some("Model with unknown id"));
+        let unknown_material =
+            builder().add_instances_with_material(model, Some(MaterialId(1)), instance());
+        assert_eq!(error(unknown_material), Some("Material with unknown id"));
+        let unknown_sprites = builder().add_sprites(AtlasId(2), Vec::new());
+        assert_eq!(error(unknown_sprites), Some("Sprite atlas with unknown id"));
+        // Render targets can't be projected as decals
+        let unknown_decals = builder().add_decals(AtlasId(1), Vec::new());
+        assert_eq!(error(unknown_decals), Some("Decal atlas with unknown id"));
+        assert_eq!(error(builder().skybox(SkyboxId(1))), Some("Skybox with unknown id"));
+        assert_eq!(error(builder().terrain(TerrainId(1))), Some("Terrain with unknown id"));
+    }
+
+    #[test]
+    fn test_build_checks_instances() {
+        let empty = FramePacketBuilder::new().add_instances(ModelId(0), Vec::new());
+        assert_eq!(error(empty), Some("Model with no instances"));
+
+        let flattened = FramePacketBuilder::new()
+            .add_instances(ModelId(0), vec![Matrix4::from_nonuniform_scale(1.0, 0.0, 1.0)]);
+        assert_eq!(error(flattened), Some("Instance with a non-invertible model matrix"));
+    }
+}
@@ -0,0 +1,68 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// What went in to drawing the last frame
+///
+/// wgpu doesn't expose timestamp queries yet, so stage timings are the CPU time spent recording
+/// each stage's commands rather than the time the GPU spent executing them.
+#[derive(Clone, Debug, Default)]
+pub struct FrameStats {
+    /// Time between the start of this frame and the one before it
+    pub frame_time: Duration,
+
+    /// Time spent recording each stage, in the order they ran
+    pub stage_timings: Vec<(&'static str, Duration)>,
+
+    pub draw_calls: u32,
+
+    /// Instances drawn across every draw call
+    pub instances: u32,
+}
+
+/// Tallies the draw calls recorded by the stages through the frame
+///
+/// Stages only get a shared reference to the renderer while they record, hence the cells.
+#[derive(Default)]
+pub struct DrawCounter {
+    draw_calls: Cell<u32>,
+    instances: Cell<u32>,
+}
+
+impl DrawCounter {
+    /// Count a single draw call of the given number of instances
+    pub fn record(&self, instances: u32) {
+        self.draw_calls.set(self.draw_calls.get() + 1);
+        self.instances.set(self.instances.get() + instances);
+    }
+
+    /// The counts since the last call, as (draw calls, instances)
+    pub fn take(&self) -> (u32, u32) {
+        (self.draw_calls.take(), self.instances.take())
+    }
+}
+
+/// Times consecutive stages of a frame
+pub struct StageTimer {
+    last_lap: Instant,
+    timings: Vec<(&'static str, Duration)>,
+}
+
+impl StageTimer {
+    pub fn start() -> Self {
+        Self {
+            last_lap: Instant::now(),
+            timings: Vec::new(),
+        }
+    }
+
+    /// Attribute the time since the last lap to the named stage
+    pub fn lap(&mut self, stage: &'static str) {
+        let now = Instant::now();
+        self.timings.push((stage, now - self.last_lap));
+        self.last_lap = now;
+    }
+
+    pub fn finish(self) -> Vec<(&'static str, Duration)> {
+        self.timings
+    }
+}
@@ -0,0 +1,153 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use tracing::warn;
+
+/// Most frames the CPU records before waiting for the GPU to finish the oldest of them
+pub const FRAMES_IN_FLIGHT: usize = 2;
+
+type MapFuture =
+    Pin<Box<dyn Future<Output = Result<wgpu::BufferReadMapping, wgpu::BufferAsyncErr>>>>;
+
+/// Which of the `FRAMES_IN_FLIGHT` copies of per-frame resources a frame uses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameSlot(usize);
+
+impl FrameSlot {
+    fn next(self) -> Self {
+        Self((self.0 + 1) % FRAMES_IN_FLIGHT)
+    }
+}
+
+/// One of something for each frame in flight, so that writing the current frame's copy never
+/// touches one the GPU may still be reading for an earlier frame
+pub struct PerFrame<T> {
+    frames: Vec<T>,
+}
+
+impl<T> PerFrame<T> {
+    pub fn new(create: impl FnMut(FrameSlot) -> T) -> Self {
+        Self {
+            frames: (0..FRAMES_IN_FLIGHT).map(FrameSlot).map(create).collect(),
+        }
+    }
+
+    pub fn get(&self, FrameSlot(slot): FrameSlot) -> &T {
+        &self.frames[slot]
+    }
+
+    pub fn get_mut(&mut self, FrameSlot(slot): FrameSlot) -> &mut T {
+        &mut self.frames[slot]
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.frames.iter_mut()
+    }
+}
+
+struct Fence {
+    /// Copied in to at the end of each frame using this slot, so that mapping it completes once
+    /// the GPU has finished that frame
+    buffer: wgpu::Buffer,
+
+    /// None until a frame using this slot has been submitted
+    mapping: Option<MapFuture>,
+}
+
+/// Keeps the CPU at most `FRAMES_IN_FLIGHT` frames ahead of the GPU
+///
+/// wgpu has no fences, but mapping a buffer completes once every submission using it has, so each
+/// slot has a tiny buffer that its frames copy in to and map straight after submitting.
+pub struct FrameFences {
+    /// Source of every fence's copy, whose contents don't matter
+    source: wgpu::Buffer,
+    fences: PerFrame<Fence>,
+    current: FrameSlot,
+}
+
+impl FrameFences {
+    const SIZE: wgpu::BufferAddress = 4;
+
+    pub fn new(device: &wgpu::Device) -> Self {
+        let source = device.create_buffer(&wgpu::BufferDescriptor {
+            size: Self::SIZE,
+            usage: wgpu::BufferUsage::COPY_SRC,
+            label: Some("Frame fence source"),
+        });
+        let fences = PerFrame::new(|_| Fence {
+            buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                size: Self::SIZE,
+                usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+                label: Some("Frame fence"),
+            }),
+            mapping: None,
+        });
+
+        Self {
+            source,
+            fences,
+            current: FrameSlot::default(),
+        }
+    }
+
+    /// Move on to the next slot, first blocking until the GPU has finished the frame that last
+    /// used it
+    pub fn begin_frame(&mut self, device: &wgpu::Device) -> FrameSlot {
+        self.current = self.current.next();
+        let Some(mut mapping) = self.fences.get_mut(self.current).mapping.take() else {
+            return self.current;
+        };
+
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            device.poll(wgpu::Maintain::Poll);
+            match mapping.as_mut().poll(&mut cx) {
+                Poll::Pending => std::thread::yield_now(),
+                // Dropping the mapping unmaps the buffer, ready for the next copy in to it
+                Poll::Ready(Ok(_)) => break,
+                Poll::Ready(Err(_)) => {
+                    warn!("Failed to map frame fence, not waiting on its frame");
+                    break;
+                }
+            }
+        }
+        self.current
+    }
+
+    /// The slot of the frame being recorded
+    pub fn current(&self) -> FrameSlot {
+        self.current
+    }
+
+    /// Record the current frame's fence copy, as the last thing on its encoder
+    pub fn signal(&self, encoder: &mut wgpu::CommandEncoder) {
+        let fence = self.fences.get(self.current);
+        encoder.copy_buffer_to_buffer(&self.source, 0, &fence.buffer, 0, Self::SIZE);
+    }
+
+    /// Start mapping the current frame's fence, once the encoder `signal` was recorded on has
+    /// been submitted
+    pub fn submitted(&mut self) {
+        let fence = self.fences.get_mut(self.current);
+        fence.mapping = Some(Box::pin(fence.buffer.map_read(0, Self::SIZE)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_cycle_through_every_frame() {
+        let mut frames = PerFrame::new(|_| 0);
+        let mut slot = FrameSlot::default();
+        for _ in 0..FRAMES_IN_FLIGHT * 3 {
+            *frames.get_mut(slot) += 1;
+            slot = slot.next();
+        }
+
+        assert_eq!(slot, FrameSlot::default());
+        assert!(frames.iter_mut().all(|uses| *uses == 3));
+    }
+}
@@ -0,0 +1,196 @@
+use std::rc::Rc;
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    post_process::PostProcessStage,
+    render_target::ColorTarget,
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+const FULLSCREEN_VERTEX_SHADER: &str = "./src/renderer/shaders/fullscreen.vert";
+const FXAA_FRAGMENT_SHADER: &str = "./src/renderer/shaders/fxaa.frag";
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct FxaaUniformData {
+    /// Size of the rendered region of the source in xy, and of the whole source texture in zw
+    region: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for FxaaUniformData {}
+unsafe impl bytemuck::Zeroable for FxaaUniformData {}
+
+/// Represents a render stage that smooths the edges in the tonemapped scene, for
+/// `AaMode::Fxaa`
+///
+/// This works from the finished colors alone, so it costs the same however much is drawn and
+/// catches aliasing that MSAA can't, eg. within alpha masked materials, at the cost of slightly
+/// softening texture detail. It reads and writes render graph transients, binding them afresh
+/// each frame.
+pub struct FxaaStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    sampler: Rc<wgpu::Sampler>,
+    uniform_buff: wgpu::Buffer,
+}
+
+impl FxaaStage {
+    pub async fn new(device: &wgpu::Device, resources: &mut ResourceCache) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(FULLSCREEN_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(FXAA_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<FxaaUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("FXAA stage uniform buffer"),
+        });
+
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("FXAA stage bind group layout"),
+            });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&bind_group_layout],
+        });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: PostProcessStage::OUTPUT_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+        });
+
+        // Edges are blended by sampling part way between texels
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        Ok(Self {
+            pipeline,
+            bind_group_layout,
+            sampler,
+            uniform_buff,
+        })
+    }
+
+    /// Anti-alias the top-left `region` pixels of the tonemapped `source` in to `output`, which
+    /// is described by `PostProcessStage::output_desc` like the source
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        source: &ColorTarget,
+        output: &ColorTarget,
+        region: winit::dpi::PhysicalSize<u32>,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[FxaaUniformData {
+                region: [
+                    region.width as f32,
+                    region.height as f32,
+                    source.size.width as f32,
+                    source.size.height as f32,
+                ],
+            }]),
+        );
+
+        let bind_group = renderer.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &self.uniform_buff,
+                        range: 0..std::mem::size_of::<FxaaUniformData>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+            label: Some("FXAA stage bind group"),
+        });
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &output.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        // Keeps each fragment lined up with the pixel it smooths
+        rpass.set_viewport(0.0, 0.0, region.width as f32, region.height as f32, 0.0, 1.0);
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+    }
+}
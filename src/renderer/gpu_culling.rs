@@ -0,0 +1,277 @@
+use cgmath::{Matrix, Matrix4, Vector4};
+
+use super::compute::{ComputePass, ComputePassDesc};
+use super::frame_packet::InstanceData;
+use super::frames_in_flight::FrameSlot;
+use super::instance_buffer::InstanceBuffer;
+use super::resource_cache::ResourceCache;
+use super::staging_belt::StagingBelt;
+use crate::{error::Result, picking::Aabb};
+
+/// Fewest instances of a model that are culled on the GPU, below which drawing every instance
+/// costs less than the dispatch and indirect draw
+pub const MIN_CULLED_INSTANCES: usize = 256;
+
+const CULL_SHADER: &str = "./src/renderer/shaders/cull.comp";
+
+/// Kept in sync with local_size_x in cull.comp
+const WORKGROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CullUniformData {
+    /// World space frustum planes facing inwards, as (normal, distance)
+    planes: [Vector4<f32>; 6],
+}
+
+unsafe impl bytemuck::Pod for CullUniformData {}
+unsafe impl bytemuck::Zeroable for CullUniformData {}
+
+/// Kept in sync with Batch in cull.comp
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CullBatch {
+    aabb_min: [f32; 4],
+    aabb_max: [f32; 4],
+    first_instance: u32,
+    instance_count: u32,
+    _padding: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for CullBatch {}
+unsafe impl bytemuck::Zeroable for CullBatch {}
+
+/// The arguments of `draw_indexed_indirect`, laid out as wgpu reads them
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrawIndexedIndirectArgs {
+    index_count: u32,
+    instance_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+unsafe impl bytemuck::Pod for DrawIndexedIndirectArgs {}
+unsafe impl bytemuck::Zeroable for DrawIndexedIndirectArgs {}
+
+/// One frame packet model's instances to cull, as they're laid out in the instance buffer
+pub struct CulledModel {
+    /// Model space bounds, which every instance is transformed by its own model matrix
+    pub bounds: Aabb,
+
+    /// Drawn from the start of the model's index buffer
+    pub index_count: u32,
+    pub first_instance: usize,
+    pub instance_count: usize,
+}
+
+/// Tests instances against the view frustum on the GPU, so that models with many instances are
+/// drawn without the CPU touching each one
+///
+/// Each culled model's visible instances are packed in to the same range of `culled_instances`
+/// that its instances occupy in the instance buffer, and counted in to its own indirect draw.
+pub struct GpuCulling {
+    pass: ComputePass,
+    uniform_buff: wgpu::Buffer,
+    batches: InstanceBuffer<CullBatch>,
+    culled_instances: InstanceBuffer<InstanceData>,
+    draw_args: InstanceBuffer<DrawIndexedIndirectArgs>,
+
+    /// Byte offset in to `draw_args` of each frame packet model's indirect draw, or None for
+    /// those that weren't culled
+    draw_offsets: Vec<Option<wgpu::BufferAddress>>,
+}
+
+impl GpuCulling {
+    pub async fn new(device: &wgpu::Device, resources: &mut ResourceCache) -> Result<Self> {
+        let storage = |readonly| wgpu::BindingType::StorageBuffer {
+            dynamic: false,
+            readonly,
+        };
+        let pass = ComputePass::new(device, resources, &ComputePassDesc {
+            label: "GPU culling bind group layout",
+            shader_path: CULL_SHADER,
+            defines: &[],
+            bind_groups: &[&[
+                wgpu::BindingType::UniformBuffer { dynamic: false },
+                storage(true),
+                storage(true),
+                storage(false),
+                storage(false),
+            ]],
+            workgroup_size: [WORKGROUP_SIZE, 1, 1],
+        })
+        .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<CullUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("GPU culling uniform buffer"),
+        });
+
+        Ok(Self {
+            pass,
+            uniform_buff,
+            batches: InstanceBuffer::with_usage(
+                device,
+                "GPU culling batch buffer",
+                wgpu::BufferUsage::STORAGE_READ | wgpu::BufferUsage::COPY_DST,
+            ),
+            culled_instances: InstanceBuffer::with_usage(
+                device,
+                "GPU culled instance buffer",
+                wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::STORAGE,
+            ),
+            draw_args: InstanceBuffer::with_usage(
+                device,
+                "GPU culling indirect draw buffer",
+                wgpu::BufferUsage::INDIRECT
+                    | wgpu::BufferUsage::STORAGE
+                    | wgpu::BufferUsage::COPY_DST,
+            ),
+            draw_offsets: Vec::new(),
+        })
+    }
+
+    /// Record culling this frame's instances against the view frustum of `view_proj`
+    ///
+    /// `models` has an entry for every frame packet model, None for those to draw unculled.
+    /// `instances` must already hold this frame's instance data.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: FrameSlot,
+        instances: &InstanceBuffer<InstanceData>,
+        view_proj: Matrix4<f32>,
+        models: &[Option<CulledModel>],
+    ) {
+        self.draw_offsets.clear();
+        let mut batches = Vec::new();
+        let mut draw_args = Vec::new();
+        for model in models {
+            let Some(model) = model else {
+                self.draw_offsets.push(None);
+                continue;
+            };
+
+            let offset = InstanceBuffer::<DrawIndexedIndirectArgs>::offset(draw_args.len());
+            self.draw_offsets.push(Some(offset));
+            batches.push(CullBatch {
+                aabb_min: model.bounds.min.to_homogeneous().into(),
+                aabb_max: model.bounds.max.to_homogeneous().into(),
+                first_instance: model.first_instance as u32,
+                instance_count: model.instance_count as u32,
+                _padding: [0; 2],
+            });
+            draw_args.push(DrawIndexedIndirectArgs {
+                index_count: model.index_count,
+                instance_count: 0,
+                first_index: 0,
+                base_vertex: 0,
+                first_instance: 0,
+            });
+        }
+        if batches.is_empty() {
+            return;
+        }
+
+        let instance_end = models
+            .iter()
+            .flatten()
+            .map(|model| model.first_instance + model.instance_count)
+            .max()
+            .unwrap_or(0);
+        let max_instances = models
+            .iter()
+            .flatten()
+            .map(|model| model.instance_count)
+            .max()
+            .unwrap_or(0);
+
+        self.culled_instances.reserve(device, instance_end);
+        let batch_count = batches.len();
+        self.batches.update(device, staging_belt, encoder, frame, std::iter::once(&batches[..]));
+        let draw_args = std::iter::once(&draw_args[..]);
+        self.draw_args.update(device, staging_belt, encoder, frame, draw_args);
+        staging_belt.write(
+            device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[CullUniformData {
+                planes: frustum_planes(view_proj),
+            }]),
+        );
+
+        // Any of the buffers may have been reallocated since the last frame, so this is cheaper
+        // than keeping track of which
+        let bind_group = self.pass.create_bind_group(device, 0, vec![
+            wgpu::BindingResource::Buffer {
+                buffer: &self.uniform_buff,
+                range: 0..std::mem::size_of::<CullUniformData>() as wgpu::BufferAddress,
+            },
+            self.batches.binding(),
+            instances.binding(),
+            self.culled_instances.binding(),
+            self.draw_args.binding(),
+        ]);
+        self.pass.dispatch(encoder, &[&bind_group], [
+            max_instances as u32,
+            batch_count as u32,
+            1,
+        ]);
+    }
+
+    /// The instance buffer and indirect draw to draw the `i`th frame packet model with, if it
+    /// was culled by the last `update`
+    ///
+    /// The instances are at the same offset as in the frame's instance buffer.
+    pub fn indirect_draw(
+        &self,
+        i: usize,
+    ) -> Option<(&wgpu::Buffer, &wgpu::Buffer, wgpu::BufferAddress)> {
+        let offset = self.draw_offsets.get(i).copied().flatten()?;
+        Some((self.culled_instances.buffer(), self.draw_args.buffer(), offset))
+    }
+}
+
+/// The planes bounding the clip volume of `view_proj`, with their normals facing inwards
+///
+/// The near plane is at -w in clip space, as cgmath's projections produce, so with 0..1 depth it
+/// is only a conservative bound. Reversed projections have their near plane at w instead, and
+/// put the far plane at infinity, which -w never cuts anything off of.
+pub(super) fn frustum_planes(view_proj: Matrix4<f32>) -> [Vector4<f32>; 6] {
+    let [x, y, z, w] = [0, 1, 2, 3].map(|i| view_proj.row(i));
+    [w + x, w - x, w + y, w - y, w + z, w - z]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, InnerSpace, Point3};
+
+    #[test]
+    fn test_frustum_planes() {
+        let view = Matrix4::look_at(
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(0.0, 0.0, -1.0),
+            cgmath::Vector3::unit_y(),
+        );
+        let proj = cgmath::perspective(Deg(90.0), 1.0, 0.1, 100.0);
+        let planes = frustum_planes(proj * view);
+        let inside = |point: Point3<f32>| {
+            planes.iter().all(|plane| plane.dot(point.to_homogeneous()) >= 0.0)
+        };
+
+        assert!(inside(Point3::new(0.0, 0.0, -10.0)));
+        assert!(inside(Point3::new(9.0, -9.0, -10.0)));
+        assert!(!inside(Point3::new(11.0, 0.0, -10.0)));
+        assert!(!inside(Point3::new(0.0, 0.0, 10.0)));
+        assert!(!inside(Point3::new(0.0, 0.0, -101.0)));
+        assert!(!inside(Point3::new(0.0, 0.0, -0.05)));
+    }
+}
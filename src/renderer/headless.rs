@@ -0,0 +1,517 @@
+use winit::dpi::PhysicalSize;
+
+use crate::error::{Error, Result};
+use super::{
+    depth_readback::COPY_ROW_ALIGNMENT,
+    frame_packet::FramePacket,
+    render_target::ColorTarget,
+    RenderOutput, Renderer, RendererConfig,
+};
+
+/// Matches the swapchain, so that headless frames go through exactly the same stages
+const OFFSCREEN_FORMAT: wgpu::TextureFormat = ColorTarget::LDR_FORMAT;
+
+impl Renderer {
+    /// Create a renderer that draws to an offscreen texture of the given size instead of a
+    /// window, for reading frames back with `render_to_image`
+    #[allow(unused)]
+    pub async fn new_headless(size: PhysicalSize<u32>, config: &RendererConfig) -> Result<Self> {
+        let adapter = Self::request_adapter(None, config.backend)
+            .await
+            .ok_or(Error::Gpu("Failed to create adapter for headless rendering"))?;
+        Self::with_adapter(adapter, None, size, config).await
+    }
+
+    pub(super) fn create_offscreen_target(
+        device: &wgpu::Device,
+        size: PhysicalSize<u32>,
+    ) -> ColorTarget {
+        ColorTarget::new(device, size, OFFSCREEN_FORMAT, "Offscreen output texture")
+    }
+
+    /// Draw a frame and wait for it to be read back, only for renderers made with `new_headless`
+    ///
+    /// This stalls until the GPU has finished the frame, so is meant for tests and tools rather
+    /// than anything interactive.
+    #[allow(unused)]
+    pub async fn render_to_image(
+        &mut self,
+        frame_packet: &FramePacket,
+    ) -> Result<image::RgbaImage> {
+        if let RenderOutput::Window { .. } = self.output {
+            return Err(Error::Gpu("Only headless renderers can render to an image"));
+        }
+
+        self.draw_frame(frame_packet)?;
+        let target = match &self.output {
+            RenderOutput::Offscreen(target) => target,
+            RenderOutput::Window { .. } => unreachable!(),
+        };
+
+        let PhysicalSize { width, height } = target.size;
+        let unpadded_bytes_per_row = width as wgpu::BufferAddress * 4;
+        let bytes_per_row =
+            unpadded_bytes_per_row.div_ceil(COPY_ROW_ALIGNMENT) * COPY_ROW_ALIGNMENT;
+        let buffer_size = bytes_per_row * height as wgpu::BufferAddress;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            size: buffer_size,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            label: Some("Offscreen readback buffer"),
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Offscreen readback encoder"),
+            });
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: &target.texture,
+                mip_level: 0,
+                array_layer: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::BufferCopyView {
+                buffer: &buffer,
+                offset: 0,
+                bytes_per_row: bytes_per_row as u32,
+                rows_per_image: height,
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+        );
+        self.queue.submit(&[encoder.finish()]);
+
+        let mapping = buffer.map_read(0, buffer_size);
+        self.device.poll(wgpu::Maintain::Wait);
+        let mapping = mapping
+            .await
+            .map_err(|_| Error::Gpu("Failed to map offscreen readback buffer"))?;
+
+        // Drop the row padding, and swap from the output's BGRA to RGBA
+        let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+        for row in mapping.as_slice().chunks(bytes_per_row as usize) {
+            for bgra in row[..unpadded_bytes_per_row as usize].chunks_exact(4) {
+                pixels.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]]);
+            }
+        }
+
+        Ok(image::RgbaImage::from_raw(width, height, pixels)
+            .expect("Readback buffer holds a whole image"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Matrix4, Point3, SquareMatrix, Vector3};
+
+    use super::*;
+    use crate::mesh_gen;
+    use crate::quality::QualityPreset;
+    use crate::terrain::{Heightmap, TerrainData};
+    use crate::renderer::{AaMode, FramePacketBuilder, ModelId, MotionBlurConfig, RenderPath};
+    use crate::renderer::frame_packet::{
+        Decal, DepthOfField, DirectionalLight, FramePacketDecals, FramePacketModel,
+        FramePacketSprites, InstanceData, SpriteInstanceData, Water,
+    };
+
+    const SIZE: PhysicalSize<u32> = PhysicalSize {
+        width: 64,
+        height: 64,
+    };
+
+    fn empty_frame_packet() -> FramePacket {
+        FramePacket {
+            view: Matrix4::look_at(
+                Point3::new(0.0, -3.0, 0.0),
+                Point3::new(0.0, 0.0, 0.0),
+                Vector3::unit_z(),
+            ),
+            proj: cgmath::perspective(cgmath::Deg(60.0), 1.0, 0.1, 100.0),
+            models: Vec::new(),
+            lights: Vec::new(),
+            directional_light: None,
+            fog: None,
+            clip_plane: None,
+            depth_of_field: None,
+            decals: Vec::new(),
+            overlay_sprites: Vec::new(),
+            overlay_text: Vec::new(),
+            debug_lines: Vec::new(),
+            debug_gui: Vec::new(),
+            ui_scale: 1.0,
+            skybox: None,
+            terrain: None,
+            water: None,
+            views: Vec::new(),
+            split_views: Vec::new(),
+        }
+    }
+
+    /// A model with a single unstyled instance, drawn with its own material
+    fn model_at(
+        model_id: ModelId,
+        model_matrix: Matrix4<f32>,
+        view: Matrix4<f32>,
+    ) -> FramePacketModel {
+        FramePacketModel {
+            model_id,
+            material_id: None,
+            instances: vec![InstanceData::new(model_matrix, view)],
+            joint_matrices: Vec::new(),
+            selected: Vec::new(),
+        }
+    }
+
+    /// Like `model_at`, at the origin where `empty_frame_packet` looks
+    fn cube_model(cube: ModelId, view: Matrix4<f32>) -> FramePacketModel {
+        model_at(cube, Matrix4::identity(), view)
+    }
+
+    /// A white sun from behind `empty_frame_packet`'s camera, lighting the faces it looks at
+    fn sun() -> DirectionalLight {
+        DirectionalLight {
+            direction: [0.0, 1.0, 0.0].into(),
+            color: [1.0, 1.0, 1.0].into(),
+            intensity: 2.0,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_forward_draws_model() {
+        let mut renderer = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+
+        let mut frame_packet = empty_frame_packet();
+        frame_packet.models.push(cube_model(cube, frame_packet.view));
+        frame_packet.directional_light = Some(sun());
+        let background = renderer.render_to_image(&empty_frame_packet()).await.unwrap();
+        let image = renderer.render_to_image(&frame_packet).await.unwrap();
+
+        assert_eq!(image.dimensions(), (SIZE.width, SIZE.height));
+        assert_ne!(image.get_pixel(32, 32), background.get_pixel(32, 32));
+        assert_eq!(image.get_pixel(1, 1), background.get_pixel(1, 1));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_msaa_draws_model() {
+        let config = RendererConfig {
+            msaa_samples: 4,
+            ..RendererConfig::default()
+        };
+        let mut renderer = Renderer::new_headless(SIZE, &config).await.unwrap();
+        let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+
+        let mut frame_packet = empty_frame_packet();
+        frame_packet.models.push(cube_model(cube, frame_packet.view));
+        let background = renderer.render_to_image(&empty_frame_packet()).await.unwrap();
+        let image = renderer.render_to_image(&frame_packet).await.unwrap();
+
+        assert_ne!(image.get_pixel(32, 32), background.get_pixel(32, 32));
+        assert_eq!(image.get_pixel(1, 1), background.get_pixel(1, 1));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_shadow_falls_below_caster() {
+        for render_path in [RenderPath::Forward, RenderPath::Deferred] {
+            let config = RendererConfig {
+                render_path,
+                ..RendererConfig::default()
+            };
+            let mut renderer = Renderer::new_headless(SIZE, &config).await.unwrap();
+            let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+            let plane = renderer.upload_model(mesh_gen::plane(10.0, 10.0, 1)).unwrap();
+
+            // The cube is off to one side, so that a shadow mirrored across the light's view would
+            // land on the other
+            let mut frame_packet = empty_frame_packet();
+            let view = frame_packet.view;
+            for (model_id, translation) in [(cube, [1.0, 0.0, 0.0]), (plane, [0.0, 0.0, -1.0])] {
+                let model_matrix = Matrix4::from_translation(translation.into());
+                frame_packet.models.push(model_at(model_id, model_matrix, view));
+            }
+            frame_packet.directional_light = Some(DirectionalLight {
+                direction: [0.0, 0.0, -1.0].into(),
+                ..sun()
+            });
+            let image = renderer.render_to_image(&frame_packet).await.unwrap();
+
+            // The plane straight below the cube, and the same distance away on the other side
+            let brightness =
+                |x, y| image.get_pixel(x, y).0[..3].iter().map(|&c| c as u32).sum::<u32>();
+            assert!(brightness(50, 51) < brightness(14, 51), "{:?}", render_path);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_depth_of_field() {
+        let mut renderer = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+
+        let mut frame_packet = empty_frame_packet();
+        frame_packet.models.push(cube_model(cube, frame_packet.view));
+        frame_packet.directional_light = Some(sun());
+        let sharp = renderer.render_to_image(&frame_packet).await.unwrap();
+
+        // The cube's front face is 2.5m away, leaving it in focus
+        let depth_of_field = DepthOfField {
+            focus_distance: 2.5,
+            aperture: 0.1,
+            max_blur: 0.2,
+        };
+        frame_packet.depth_of_field = Some(depth_of_field);
+        let focused = renderer.render_to_image(&frame_packet).await.unwrap();
+        assert_eq!(focused.get_pixel(32, 32), sharp.get_pixel(32, 32));
+
+        // Focused well in front of it, the cube's edge blurs in to the background
+        frame_packet.depth_of_field = Some(DepthOfField {
+            focus_distance: 0.5,
+            ..depth_of_field
+        });
+        let blurred = renderer.render_to_image(&frame_packet).await.unwrap();
+        assert_ne!(blurred.get_pixel(41, 32), sharp.get_pixel(41, 32));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_anti_aliasing_modes() {
+        for aa_mode in [AaMode::Fxaa, AaMode::Taa] {
+            let config = RendererConfig {
+                aa_mode,
+                ..RendererConfig::default()
+            };
+            let mut renderer = Renderer::new_headless(SIZE, &config).await.unwrap();
+            let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+
+            let mut frame_packet = empty_frame_packet();
+            frame_packet.models.push(cube_model(cube, frame_packet.view));
+            let background = renderer.render_to_image(&empty_frame_packet()).await.unwrap();
+
+            // TAA builds up over several frames, and flat areas stay as they are throughout
+            for _ in 0..4 {
+                let image = renderer.render_to_image(&frame_packet).await.unwrap();
+                assert_ne!(image.get_pixel(32, 32), background.get_pixel(32, 32), "{:?}", aa_mode);
+                assert_eq!(image.get_pixel(1, 1), background.get_pixel(1, 1), "{:?}", aa_mode);
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_environment_lighting() {
+        let mut renderer = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+        let white = image::RgbaImage::from_pixel(4, 4, image::Rgba([255; 4]));
+        let skybox = renderer.upload_skybox([(); 6].map(|_| white.clone())).unwrap();
+
+        let mut frame_packet = empty_frame_packet();
+        frame_packet.models.push(cube_model(cube, frame_packet.view));
+        let unlit = renderer.render_to_image(&frame_packet).await.unwrap();
+
+        // With no lights at all, the cube is only lit by the sky around it
+        frame_packet.skybox = Some(skybox);
+        let lit = renderer.render_to_image(&frame_packet).await.unwrap();
+        assert!(lit.get_pixel(32, 32)[0] > unlit.get_pixel(32, 32)[0]);
+
+        // Going back to no skybox goes back to the flat ambient light
+        frame_packet.skybox = None;
+        assert_eq!(renderer.render_to_image(&frame_packet).await.unwrap(), unlit);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_decals() {
+        let mut renderer = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+        let atlas = renderer
+            .upload_atlas(image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 0, 255])))
+            .unwrap();
+
+        let mut frame_packet = empty_frame_packet();
+        frame_packet.directional_light = Some(sun());
+        frame_packet.models.push(cube_model(cube, frame_packet.view));
+        let plain = renderer.render_to_image(&frame_packet).await.unwrap();
+
+        // Projected along y, on to the face of the cube towards the camera
+        let decal = Decal {
+            transform: Matrix4::from_angle_x(cgmath::Deg(90.0)) * Matrix4::from_scale(2.0),
+            atlas_pos: cgmath::Vector2::new(0.0, 0.0),
+            atlas_size: cgmath::Vector2::new(1.0, 1.0),
+            tint: [1.0; 4].into(),
+        };
+        frame_packet.decals.push(FramePacketDecals {
+            atlas_id: atlas,
+            decals: vec![decal],
+        });
+        let decaled = renderer.render_to_image(&frame_packet).await.unwrap();
+        let (before, after) = (plain.get_pixel(32, 32), decaled.get_pixel(32, 32));
+        assert!(after[0] >= before[0] && after[1] < before[1]);
+
+        // Nothing of the background is inside the box
+        assert_eq!(decaled.get_pixel(1, 1), plain.get_pixel(1, 1));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_terrain() {
+        let mut renderer = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        let heightmap = Heightmap::new(9, 9, vec![-1.0; 81], 1.0).unwrap();
+        let green = image::RgbaImage::from_pixel(4, 4, image::Rgba([0, 255, 0, 255]));
+        let terrain = renderer
+            .upload_terrain(TerrainData {
+                chunks: heightmap.chunks(4),
+                splat_map: image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 0, 0])),
+                layers: vec![green],
+                layer_tile_size: 1.0,
+            })
+            .unwrap();
+
+        let mut frame_packet = empty_frame_packet();
+        frame_packet.directional_light = Some(DirectionalLight {
+            direction: [0.0, 0.0, -1.0].into(),
+            ..sun()
+        });
+        let without = renderer.render_to_image(&frame_packet).await.unwrap();
+        frame_packet.terrain = Some(terrain);
+        let with = renderer.render_to_image(&frame_packet).await.unwrap();
+
+        // The camera looks along the ground, which covers the bottom of the image but not the top
+        assert!(with.get_pixel(32, 60)[1] > without.get_pixel(32, 60)[1]);
+        assert_eq!(with.get_pixel(32, 4), without.get_pixel(32, 4));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_water() {
+        let mut renderer = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+
+        let mut frame_packet = empty_frame_packet();
+        frame_packet.models.push(cube_model(cube, frame_packet.view));
+        let without = renderer.render_to_image(&frame_packet).await.unwrap();
+        frame_packet.water = Some(Water {
+            height: -1.0,
+            color: [1.0, 0.0, 0.0].into(),
+            refraction: false,
+            ..Water::default()
+        });
+        let with = renderer.render_to_image(&frame_packet).await.unwrap();
+
+        // The water covers the bottom of the image below the cube, and the sky is left as it was
+        assert!(with.get_pixel(32, 62)[0] > without.get_pixel(32, 62)[0]);
+        assert_eq!(with.get_pixel(32, 1), without.get_pixel(32, 1));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_motion_blur() {
+        let config = RendererConfig {
+            motion_blur: Some(MotionBlurConfig::default()),
+            ..RendererConfig::default()
+        };
+        let mut renderer = Renderer::new_headless(SIZE, &config).await.unwrap();
+        let mut unblurred = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+        assert!(unblurred.upload_model(mesh_gen::cube(1.0)).unwrap() == cube);
+        let frame_packet_at = |x: f32| {
+            let mut frame_packet = empty_frame_packet();
+            let model_matrix = Matrix4::from_translation(Vector3::new(x, 0.0, 0.0));
+            frame_packet.models.push(model_at(cube, model_matrix, frame_packet.view));
+            frame_packet
+        };
+
+        // Nothing has moved yet, so there's nothing to blur
+        let still = renderer.render_to_image(&frame_packet_at(0.0)).await.unwrap();
+        assert_eq!(still, unblurred.render_to_image(&frame_packet_at(0.0)).await.unwrap());
+
+        let moved = renderer.render_to_image(&frame_packet_at(0.5)).await.unwrap();
+        assert_ne!(moved, unblurred.render_to_image(&frame_packet_at(0.5)).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_sprite_overlay() {
+        let mut renderer = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        let white = image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4]));
+        let atlas_id = renderer.upload_atlas(white).unwrap();
+
+        // A red sprite over the bottom left quarter of the screen
+        let mut frame_packet = empty_frame_packet();
+        frame_packet.overlay_sprites.push(FramePacketSprites {
+            atlas_id,
+            sprites: vec![SpriteInstanceData {
+                screen_pos: [-1.0, -1.0].into(),
+                screen_size: [1.0, 1.0].into(),
+                atlas_pos: [0.0, 0.0].into(),
+                atlas_size: [1.0, 1.0].into(),
+                rotation: 0.0,
+                tint: [1.0, 0.0, 0.0, 1.0].into(),
+                slice_atlas: [0.0; 4].into(),
+                slice_screen: [0.0; 4].into(),
+                layer: 0,
+            }],
+            ui_sprites: Vec::new(),
+        });
+        let image = renderer.render_to_image(&frame_packet).await.unwrap();
+
+        let inside = image.get_pixel(8, 56);
+        assert!(inside[0] > 200 && inside[1] < 50 && inside[2] < 50);
+        let outside = image.get_pixel(56, 8);
+        assert_ne!(outside, inside);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_frame_packet_builder_checks_handles() {
+        let mut renderer = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+        let camera = crate::camera::Camera::default();
+
+        let frame_packet = FramePacketBuilder::new()
+            .camera(&camera, 1.0)
+            .add_instances(cube, vec![Matrix4::from_translation(Vector3::new(3.0, 0.0, 0.0))])
+            .build(&renderer)
+            .unwrap();
+        assert_eq!(frame_packet.models[0].instances.len(), 1);
+
+        // Handles from another renderer aren't known to this one
+        let mut other = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        other.upload_model(mesh_gen::cube(1.0)).unwrap();
+        let other_cube = other.upload_model(mesh_gen::cube(1.0)).unwrap();
+        let unknown = FramePacketBuilder::new()
+            .add_instances(other_cube, vec![Matrix4::identity()])
+            .build(&renderer);
+        assert!(matches!(unknown, Err(Error::InvalidFramePacket(_))));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a GPU adapter"]
+    async fn test_apply_quality_rebuilds_scene() {
+        let mut renderer = Renderer::new_headless(SIZE, &RendererConfig::default()).await.unwrap();
+        let cube = renderer.upload_model(mesh_gen::cube(1.0)).unwrap();
+
+        let mut frame_packet = empty_frame_packet();
+        frame_packet.models.push(cube_model(cube, frame_packet.view));
+        let background = renderer.render_to_image(&empty_frame_packet()).await.unwrap();
+
+        // Every preset changes the shadow map, AA or post effects from the one before, and the
+        // post effects it asks for are all drawn under its AA mode
+        for preset in [QualityPreset::Low, QualityPreset::Ultra, QualityPreset::High] {
+            let quality = preset.render_quality();
+            renderer.apply_quality(&quality).unwrap();
+            assert_eq!(renderer.scene_settings.aa_mode, quality.aa_mode, "{:?}", preset);
+            assert_eq!(renderer.scene_settings.ssao, quality.ssao, "{:?}", preset);
+            assert_eq!(renderer.scene_settings.motion_blur, quality.motion_blur, "{:?}", preset);
+            let image = renderer.render_to_image(&frame_packet).await.unwrap();
+            assert_ne!(image.get_pixel(32, 32), background.get_pixel(32, 32), "{:?}", preset);
+            assert_eq!(image.get_pixel(1, 1), background.get_pixel(1, 1), "{:?}", preset);
+        }
+    }
+}
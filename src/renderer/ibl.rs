@@ -0,0 +1,517 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    render_target::ColorTarget,
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    skybox::GpuSkybox,
+    SkyboxId,
+};
+
+const FULLSCREEN_VERTEX_SHADER: &str = "./src/renderer/shaders/fullscreen.vert";
+const FILTER_FRAGMENT_SHADER: &str = "./src/renderer/shaders/ibl_filter.frag";
+const BRDF_LUT_FRAGMENT_SHADER: &str = "./src/renderer/shaders/brdf_lut.frag";
+
+/// Format of every cubemap drawn from a skybox, which can hold more than the skybox's own range
+/// once filtered
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Size and mip count of the copy of the skybox that the maps are filtered from, with mips all
+/// the way down to a single texel
+const SOURCE_SIZE: u32 = 128;
+const SOURCE_MIPS: u32 = 8;
+
+/// Diffuse lighting varies slowly with the normal, so doesn't need much detail
+const IRRADIANCE_SIZE: u32 = 32;
+
+/// Each mip of the specular map is filtered for a roughness evenly spaced from 0 to 1, which the
+/// forward shader picks between with `roughness * (SPECULAR_MIPS - 1)`. The mip count has to match
+/// SPECULAR_MAX_LOD in shader.frag.
+const SPECULAR_SIZE: u32 = 128;
+const SPECULAR_MIPS: u32 = 5;
+
+const BRDF_LUT_SIZE: u32 = 128;
+const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+/// Radiance of the environment from every direction when the frame has no skybox, so that
+/// surfaces facing away from every light aren't entirely black
+const FLAT_AMBIENT: f64 = 0.02;
+
+/// Roughness the given mip of the specular map is filtered for
+fn specular_mip_roughness(mip: u32) -> f32 {
+    mip as f32 / (SPECULAR_MIPS - 1) as f32
+}
+
+fn mip_size(size: u32, mip: u32) -> u32 {
+    (size >> mip).max(1)
+}
+
+fn create_cube_texture(
+    device: &wgpu::Device,
+    size: u32,
+    mip_level_count: u32,
+    usage: wgpu::TextureUsage,
+    label: &str,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth: 1,
+        },
+        array_layer_count: 6,
+        mip_level_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: FORMAT,
+        usage,
+    })
+}
+
+/// View of the given mips of a cubemap, for sampling
+fn cube_view(texture: &wgpu::Texture, base_mip_level: u32, level_count: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        format: FORMAT,
+        dimension: wgpu::TextureViewDimension::Cube,
+        aspect: wgpu::TextureAspect::All,
+        base_mip_level,
+        level_count,
+        base_array_layer: 0,
+        array_layer_count: 6,
+    })
+}
+
+/// View of one face of one mip of a cubemap, for drawing in to
+fn face_view(texture: &wgpu::Texture, mip: u32, face: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        format: FORMAT,
+        dimension: wgpu::TextureViewDimension::D2,
+        aspect: wgpu::TextureAspect::All,
+        base_mip_level: mip,
+        level_count: 1,
+        base_array_layer: face,
+        array_layer_count: 1,
+    })
+}
+
+/// The filtered cubemaps that a surface is lit by, in place of the environment itself
+pub struct GpuEnvironment {
+    irradiance: wgpu::Texture,
+    pub irradiance_view: wgpu::TextureView,
+    specular: wgpu::Texture,
+    pub specular_view: wgpu::TextureView,
+}
+
+impl GpuEnvironment {
+    fn new(device: &wgpu::Device, usage: wgpu::TextureUsage) -> Self {
+        let irradiance = create_cube_texture(
+            device,
+            IRRADIANCE_SIZE,
+            1,
+            usage,
+            "Environment irradiance texture",
+        );
+        let specular = create_cube_texture(
+            device,
+            SPECULAR_SIZE,
+            SPECULAR_MIPS,
+            usage,
+            "Environment specular texture",
+        );
+
+        Self {
+            irradiance_view: cube_view(&irradiance, 0, 1),
+            irradiance,
+            specular_view: cube_view(&specular, 0, SPECULAR_MIPS),
+            specular,
+        }
+    }
+
+    /// Every face of every mip of both maps, with their sizes
+    fn faces(&self) -> impl Iterator<Item = (&wgpu::Texture, u32, u32, u32)> {
+        let irradiance = (0..6).map(move |face| (&self.irradiance, IRRADIANCE_SIZE, 0, face));
+        let specular = (0..SPECULAR_MIPS).flat_map(move |mip| {
+            (0..6).map(move |face| (&self.specular, mip_size(SPECULAR_SIZE, mip), mip, face))
+        });
+        irradiance.chain(specular)
+    }
+
+    /// Record copying every face of `source` over this environment
+    fn copy_from(&self, encoder: &mut wgpu::CommandEncoder, source: &GpuEnvironment) {
+        for ((texture, size, mip_level, array_layer), (source, ..)) in
+            self.faces().zip(source.faces())
+        {
+            encoder.copy_texture_to_texture(
+                wgpu::TextureCopyView {
+                    texture: source,
+                    mip_level,
+                    array_layer,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                wgpu::TextureCopyView {
+                    texture,
+                    mip_level,
+                    array_layer,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                wgpu::Extent3d {
+                    width: size,
+                    height: size,
+                    depth: 1,
+                },
+            );
+        }
+    }
+
+    /// Record filling every face with the same radiance
+    fn clear(&self, encoder: &mut wgpu::CommandEncoder, radiance: f64) {
+        for (texture, _, mip, face) in self.faces() {
+            let view = face_view(texture, mip, face);
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &view,
+                    resolve_target: None,
+                    load_op: wgpu::LoadOp::Clear,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color: wgpu::Color {
+                        r: radiance,
+                        g: radiance,
+                        b: radiance,
+                        a: 1.0,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct FilterUniformData {
+    /// Face being drawn, roughness, size of the source's largest mip and the mip of the source to
+    /// copy
+    params: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for FilterUniformData {}
+unsafe impl bytemuck::Zeroable for FilterUniformData {}
+
+/// Lights the scene from the frame packet's skybox, for the forward stage's ambient term
+///
+/// Each skybox is filtered as it's uploaded, in to an irradiance map for diffuse lighting and a
+/// specular map whose mips are blurred for increasing roughness, which together with a BRDF
+/// lookup table make up the split sum approximation of image based lighting. Only one
+/// environment is bound for shading at a time, which the frame packet's skybox is copied in to
+/// whenever it changes, so that switching skyboxes doesn't mean rebuilding every bind group that
+/// includes it. Without a skybox a flat dim environment is bound instead.
+pub struct EnvironmentLighting {
+    downsample_pipeline: Rc<wgpu::RenderPipeline>,
+    irradiance_pipeline: Rc<wgpu::RenderPipeline>,
+    prefilter_pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    pub sampler: Rc<wgpu::Sampler>,
+    pub brdf_lut: ColorTarget,
+
+    /// The environment that shading reads, copied from `environments`
+    pub bound: GpuEnvironment,
+
+    /// The skybox whose environment is in `bound`, if any
+    bound_skybox: Option<SkyboxId>,
+    environments: HashMap<SkyboxId, GpuEnvironment>,
+}
+
+impl EnvironmentLighting {
+    pub async fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        resources: &mut ResourceCache,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(FULLSCREEN_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::Cube,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Environment filter bind group layout"),
+            });
+        let filter_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&bind_group_layout],
+        });
+        let lut_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[],
+        });
+
+        let mut create_pipeline = |layout, fs_spirv: &[u32], format| {
+            resources.render_pipeline(device, &RenderPipelineDesc {
+                layout,
+                vertex_shader: &vs_spirv,
+                fragment_shader: Some(fs_spirv),
+                rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: wgpu::CullMode::None,
+                    depth_bias: 0,
+                    depth_bias_slope_scale: 0.0,
+                    depth_bias_clamp: 0.0,
+                }),
+                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+                color_states: &[wgpu::ColorStateDescriptor {
+                    format,
+                    alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                    color_blend: wgpu::BlendDescriptor::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+                depth_stencil_state: None,
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format: wgpu::IndexFormat::Uint32,
+                    vertex_buffers: &[],
+                },
+                sample_count: 1,
+            })
+        };
+        shader_cache.load_source(FILTER_FRAGMENT_SHADER).await?;
+        let mut filter_pipeline = |define| -> Result<_> {
+            let fs_spirv = shader_cache.compile(
+                FILTER_FRAGMENT_SHADER,
+                shaderc::ShaderKind::Fragment,
+                &[(define, None)],
+            )?;
+            Ok(create_pipeline(&filter_layout, &fs_spirv, FORMAT))
+        };
+        let downsample_pipeline = filter_pipeline("DOWNSAMPLE")?;
+        let irradiance_pipeline = filter_pipeline("IRRADIANCE")?;
+        let prefilter_pipeline = filter_pipeline("PREFILTER")?;
+        let lut_spirv = shader_cache
+            .get_shader(BRDF_LUT_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+        let lut_pipeline = create_pipeline(&lut_layout, &lut_spirv, BRDF_LUT_FORMAT);
+
+        // Filtering reads between texels and mips, and so does shading
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let brdf_lut = ColorTarget::new(
+            device,
+            winit::dpi::PhysicalSize::new(BRDF_LUT_SIZE, BRDF_LUT_SIZE),
+            BRDF_LUT_FORMAT,
+            "BRDF lookup table texture",
+        );
+        let bound = GpuEnvironment::new(
+            device,
+            wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::OUTPUT_ATTACHMENT
+                | wgpu::TextureUsage::COPY_DST,
+        );
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment lighting setup commands"),
+        });
+        {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &brdf_lut.view,
+                    resolve_target: None,
+                    load_op: wgpu::LoadOp::Clear,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color: wgpu::Color::BLACK,
+                }],
+                depth_stencil_attachment: None,
+            });
+            rpass.set_pipeline(&lut_pipeline);
+            rpass.draw(0..3, 0..1);
+        }
+        bound.clear(&mut encoder, FLAT_AMBIENT);
+        queue.submit(&[encoder.finish()]);
+
+        Ok(Self {
+            downsample_pipeline,
+            irradiance_pipeline,
+            prefilter_pipeline,
+            bind_group_layout,
+            sampler,
+            brdf_lut,
+            bound,
+            bound_skybox: None,
+            environments: HashMap::new(),
+        })
+    }
+
+    /// Filter a newly uploaded skybox, for lighting any frame that it's drawn behind
+    pub fn add_skybox(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        skybox_id: SkyboxId,
+        skybox: &GpuSkybox,
+    ) {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment filtering commands"),
+        });
+
+        // The skybox is resampled in to a small mip chain first, so that the filters can read
+        // from a level about as blurry as the area each of their samples stands for
+        let source = create_cube_texture(
+            device,
+            SOURCE_SIZE,
+            SOURCE_MIPS,
+            wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            "Environment source texture",
+        );
+        for mip in 0..SOURCE_MIPS {
+            let input = match mip {
+                0 => None,
+                _ => Some(cube_view(&source, mip - 1, 1)),
+            };
+            let input = input.as_ref().unwrap_or(&skybox.view);
+            for face in 0..6 {
+                let output = face_view(&source, mip, face);
+                let params = [face as f32, 0.0, SOURCE_SIZE as f32, 0.0];
+                let pipeline = &self.downsample_pipeline;
+                self.draw_face(device, &mut encoder, pipeline, input, &output, params);
+            }
+        }
+
+        let source_view = cube_view(&source, 0, SOURCE_MIPS);
+        let environment = GpuEnvironment::new(
+            device,
+            wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::OUTPUT_ATTACHMENT
+                | wgpu::TextureUsage::COPY_SRC,
+        );
+        for face in 0..6 {
+            let output = face_view(&environment.irradiance, 0, face);
+            let params = [face as f32, 0.0, SOURCE_SIZE as f32, 0.0];
+            let pipeline = &self.irradiance_pipeline;
+            self.draw_face(device, &mut encoder, pipeline, &source_view, &output, params);
+        }
+        for mip in 0..SPECULAR_MIPS {
+            // A perfect mirror reflects the environment as it is
+            let pipeline = match mip {
+                0 => &self.downsample_pipeline,
+                _ => &self.prefilter_pipeline,
+            };
+            for face in 0..6 {
+                let output = face_view(&environment.specular, mip, face);
+                let params = [face as f32, specular_mip_roughness(mip), SOURCE_SIZE as f32, 0.0];
+                self.draw_face(device, &mut encoder, pipeline, &source_view, &output, params);
+            }
+        }
+        queue.submit(&[encoder.finish()]);
+
+        self.environments.insert(skybox_id, environment);
+    }
+
+    fn draw_face(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        input: &wgpu::TextureView,
+        output: &wgpu::TextureView,
+        params: [f32; 4],
+    ) {
+        // Only ever done while loading, so each face gets its own small buffer
+        let uniform_buff = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[FilterUniformData { params }]),
+            wgpu::BufferUsage::UNIFORM,
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &uniform_buff,
+                        range: 0..std::mem::size_of::<FilterUniformData>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(input),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+            label: Some("Environment filter bind group"),
+        });
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: output,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, &bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+
+    /// Record binding the environment of the frame's skybox for shading, if it isn't already
+    pub fn update(&mut self, encoder: &mut wgpu::CommandEncoder, skybox: Option<SkyboxId>) {
+        if skybox == self.bound_skybox {
+            return;
+        }
+
+        match skybox.and_then(|skybox_id| self.environments.get(&skybox_id)) {
+            Some(environment) => self.bound.copy_from(encoder, environment),
+            None => self.bound.clear(encoder, FLAT_AMBIENT),
+        }
+        self.bound_skybox = skybox;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_specular_mips() {
+        // The shader maps roughness 0..1 on to every mip
+        assert_eq!(specular_mip_roughness(0), 0.0);
+        assert_eq!(specular_mip_roughness(SPECULAR_MIPS - 1), 1.0);
+
+        // Every mip of the source down to a single texel, and none past it
+        assert_eq!(mip_size(SOURCE_SIZE, SOURCE_MIPS - 1), 1);
+        assert_eq!(SOURCE_SIZE >> (SOURCE_MIPS - 1), 1);
+        assert!(mip_size(SPECULAR_SIZE, SPECULAR_MIPS - 1) > 1);
+    }
+}
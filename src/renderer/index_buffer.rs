@@ -0,0 +1,65 @@
+/// Every format an `IndexBuffer` can be in
+///
+/// The index format is part of a pipeline rather than the draw, so stages create a pipeline for
+/// each of these that they might draw a model with.
+pub const INDEX_FORMATS: [wgpu::IndexFormat; 2] =
+    [wgpu::IndexFormat::Uint16, wgpu::IndexFormat::Uint32];
+
+/// A model's index buffer, stored as 16 bit indices whenever the model has few enough vertices
+pub struct IndexBuffer {
+    pub buffer: wgpu::Buffer,
+    pub format: wgpu::IndexFormat,
+    pub count: u32,
+}
+
+impl IndexBuffer {
+    pub fn new(device: &wgpu::Device, indices: &[u32], vertex_count: usize) -> Self {
+        let count = indices.len() as u32;
+        match narrow_indices(indices, vertex_count) {
+            Some(narrowed) => Self {
+                buffer: device.create_buffer_with_data(
+                    bytemuck::cast_slice(&narrowed),
+                    wgpu::BufferUsage::INDEX,
+                ),
+                format: wgpu::IndexFormat::Uint16,
+                count,
+            },
+            None => Self {
+                buffer: device.create_buffer_with_data(
+                    bytemuck::cast_slice(indices),
+                    wgpu::BufferUsage::INDEX,
+                ),
+                format: wgpu::IndexFormat::Uint32,
+                count,
+            },
+        }
+    }
+}
+
+/// The indices as 16 bit values, if every vertex can be addressed by one
+///
+/// Padded to a whole number of 32 bit words, as buffer sizes have to be a multiple of 4 bytes.
+/// The padding is past the end of the indices that are drawn, so is never read.
+fn narrow_indices(indices: &[u32], vertex_count: usize) -> Option<Vec<u16>> {
+    if vertex_count > u16::MAX as usize + 1 {
+        return None;
+    }
+
+    let mut narrowed: Vec<u16> = indices.iter().map(|&index| index as u16).collect();
+    if !narrowed.len().is_multiple_of(2) {
+        narrowed.push(0);
+    }
+    Some(narrowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_narrow_indices() {
+        assert_eq!(narrow_indices(&[0, 1, 2], 3), Some(vec![0, 1, 2, 0]));
+        assert_eq!(narrow_indices(&[0, 65535, 2, 3], 65536), Some(vec![0, 65535, 2, 3]));
+        assert_eq!(narrow_indices(&[0, 65536, 2], 65537), None);
+    }
+}
@@ -0,0 +1,119 @@
+use std::marker::PhantomData;
+
+use super::frames_in_flight::{FrameSlot, PerFrame};
+use super::staging_belt::StagingBelt;
+
+/// Smallest number of instances allocated for, so the first few frames don't each regrow it
+const INITIAL_CAPACITY: usize = 256;
+
+struct Allocation {
+    buffer: wgpu::Buffer,
+
+    /// Number of instances `buffer` has room for
+    capacity: usize,
+}
+
+/// A vertex buffer of per-instance data that lives across frames
+///
+/// Every batch drawn by a stage in a frame is packed in to the one buffer, which is only ever
+/// reallocated when a frame needs more room than any before it. There's one such buffer for each
+/// frame in flight, and the stage draws from whichever was last updated.
+pub struct InstanceBuffer<T> {
+    allocations: PerFrame<Allocation>,
+    label: &'static str,
+    usage: wgpu::BufferUsage,
+
+    /// The frame last written by `update`
+    current: FrameSlot,
+
+    _instance: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> InstanceBuffer<T> {
+    pub fn new(device: &wgpu::Device, label: &'static str) -> Self {
+        Self::with_usage(device, label, wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST)
+    }
+
+    /// Like `new`, but for buffers that are also, or instead, used as something other than a
+    /// vertex buffer. `usage` has to include `COPY_DST` for `update` to write to it.
+    pub fn with_usage(
+        device: &wgpu::Device,
+        label: &'static str,
+        usage: wgpu::BufferUsage,
+    ) -> Self {
+        Self {
+            allocations: PerFrame::new(|_| Allocation {
+                buffer: Self::allocate(device, label, usage, INITIAL_CAPACITY),
+                capacity: INITIAL_CAPACITY,
+            }),
+            label,
+            usage,
+            current: FrameSlot::default(),
+            _instance: PhantomData,
+        }
+    }
+
+    fn allocate(
+        device: &wgpu::Device,
+        label: &'static str,
+        usage: wgpu::BufferUsage,
+        capacity: usize,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            size: (capacity * std::mem::size_of::<T>()) as wgpu::BufferAddress,
+            usage,
+            label: Some(label),
+        })
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.allocations.get(self.current).buffer
+    }
+
+    /// The whole buffer, for binding as a storage buffer
+    pub fn binding(&self) -> wgpu::BindingResource<'_> {
+        let allocation = self.allocations.get(self.current);
+        wgpu::BindingResource::Buffer {
+            buffer: &allocation.buffer,
+            range: 0..Self::offset(allocation.capacity),
+        }
+    }
+
+    /// Byte offset of the given instance, for binding the buffer from that instance onwards
+    pub fn offset(index: usize) -> wgpu::BufferAddress {
+        (index * std::mem::size_of::<T>()) as wgpu::BufferAddress
+    }
+
+    /// Record copying this frame's instances to the start of the frame's buffer, growing it
+    /// first if needed. Batches are laid out back to back in the order given.
+    pub fn update<'a>(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: FrameSlot,
+        batches: impl Iterator<Item = &'a [T]>,
+    ) where
+        T: 'a,
+    {
+        self.current = frame;
+        let data: Vec<T> = batches.flat_map(|batch| batch.iter().copied()).collect();
+        if data.is_empty() {
+            return;
+        }
+
+        self.reserve(device, data.len());
+        let buffer = self.buffer();
+        staging_belt.write(device, encoder, buffer, 0, bytemuck::cast_slice(&data));
+    }
+
+    /// Grow the buffer to fit at least `len` instances, for buffers that are filled in by the GPU
+    /// rather than `update`. The contents are lost if it has to grow.
+    pub fn reserve(&mut self, device: &wgpu::Device, len: usize) {
+        let allocation = self.allocations.get_mut(self.current);
+        if len > allocation.capacity {
+            allocation.capacity = len.next_power_of_two();
+            allocation.buffer = Self::allocate(device, self.label, self.usage, allocation.capacity);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::rc::Rc;
+
+use cgmath::Matrix4;
+
+use super::resource_cache::ResourceCache;
+use super::staging_belt::StagingBelt;
+
+/// Most joints a single skinned model can be deformed by, kept in sync with MAX_JOINTS in
+/// shader.vert
+pub const MAX_JOINTS: usize = 128;
+
+/// Each model's joints are bound as a fixed size block at a dynamic offset, which has to be
+/// aligned like this
+const BLOCK_SIZE: wgpu::BufferAddress = {
+    let size = (MAX_JOINTS * std::mem::size_of::<Matrix4<f32>>()) as wgpu::BufferAddress;
+    size.next_multiple_of(wgpu::BIND_BUFFER_ALIGNMENT)
+};
+
+/// Number of blocks the buffer is initially allocated for
+const INITIAL_CAPACITY: usize = 4;
+
+/// GPU side copy of the joint matrices of every skinned model drawn in a frame
+///
+/// Only the forward stage deforms vertices by these, the shadow and G-buffer passes still draw
+/// skinned models in their bind pose.
+pub struct JointBuffer {
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    bind_group: wgpu::BindGroup,
+    buffer: wgpu::Buffer,
+
+    /// Number of blocks `buffer` has room for
+    capacity: usize,
+}
+
+impl JointBuffer {
+    pub fn new(device: &wgpu::Device, resources: &mut ResourceCache) -> Self {
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::StorageBuffer {
+                        dynamic: true,
+                        readonly: true,
+                    },
+                }],
+                label: Some("Joint matrices bind group layout"),
+            });
+
+        let (buffer, bind_group) = Self::allocate(device, &bind_group_layout, INITIAL_CAPACITY);
+
+        Self {
+            bind_group_layout,
+            bind_group,
+            buffer,
+            capacity: INITIAL_CAPACITY,
+        }
+    }
+
+    fn allocate(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        capacity: usize,
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: capacity as wgpu::BufferAddress * BLOCK_SIZE,
+            usage: wgpu::BufferUsage::STORAGE_READ | wgpu::BufferUsage::COPY_DST,
+            label: Some("Joint matrices buffer"),
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[wgpu::Binding {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &buffer,
+                    range: 0..BLOCK_SIZE,
+                },
+            }],
+            label: Some("Joint matrices bind group"),
+        });
+
+        (buffer, bind_group)
+    }
+
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    /// Record copying this frame's joint matrices in to the buffer, one block per skinned model,
+    /// growing it first if needed
+    ///
+    /// Returns the dynamic offset to bind each model's block with. Unskinned models, ie. those
+    /// with no joints, get the first block as they never read it.
+    pub fn update<'a>(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        models: impl Iterator<Item = &'a [Matrix4<f32>]>,
+    ) -> Vec<wgpu::DynamicOffset> {
+        let models: Vec<_> = models.collect();
+        let skinned_count = models.iter().filter(|joints| !joints.is_empty()).count();
+        if skinned_count > self.capacity {
+            self.capacity = skinned_count.next_power_of_two();
+            let (buffer, bind_group) =
+                Self::allocate(device, &self.bind_group_layout, self.capacity);
+            self.buffer = buffer;
+            self.bind_group = bind_group;
+        }
+
+        let mut next_block = 0;
+        models
+            .into_iter()
+            .map(|joints| {
+                if joints.is_empty() {
+                    return 0;
+                }
+
+                // Any joints past the limit were rejected when the model was uploaded, so
+                // would never be read
+                let data: Vec<[[f32; 4]; 4]> = joints
+                    .iter()
+                    .take(MAX_JOINTS)
+                    .map(|&matrix| matrix.into())
+                    .collect();
+                let offset = next_block * BLOCK_SIZE;
+                let data = bytemuck::cast_slice(&data);
+                staging_belt.write(device, encoder, &self.buffer, offset, data);
+                next_block += 1;
+                offset as wgpu::DynamicOffset
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,292 @@
+use cgmath::{Angle, InnerSpace, Matrix4, Point3, Transform, Vector3};
+
+use std::rc::Rc;
+
+use super::frame_packet::Light;
+use super::resource_cache::ResourceCache;
+use super::staging_belt::StagingBelt;
+
+/// Number of lights the uniform array fallback has room for. Any beyond this are dropped.
+pub const MAX_UNIFORM_LIGHTS: usize = 64;
+
+/// Number of lights the storage buffer is initially allocated for
+const INITIAL_STORAGE_CAPACITY: usize = 16;
+
+/// How the per frame light list is handed to the shaders
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LightBufferKind {
+    /// An unbounded storage buffer, grown whenever a frame has more lights than it can hold
+    Storage,
+
+    /// A fixed size uniform array of `MAX_UNIFORM_LIGHTS`, for backends that can't read storage
+    /// buffers from fragment shaders
+    Uniform,
+}
+
+impl LightBufferKind {
+    pub fn for_backend(backend: wgpu::Backend) -> Self {
+        match backend {
+            wgpu::Backend::Vulkan | wgpu::Backend::Metal | wgpu::Backend::Dx12 => {
+                LightBufferKind::Storage
+            }
+            _ => LightBufferKind::Uniform,
+        }
+    }
+
+    /// Preprocessor definitions that select the matching light list declaration in the shaders
+    pub fn shader_defines(self) -> &'static [(&'static str, Option<&'static str>)] {
+        match self {
+            LightBufferKind::Storage => &[],
+            LightBufferKind::Uniform => &[
+                ("LIGHTS_UNIFORM_ARRAY", None),
+                // Kept in sync with MAX_UNIFORM_LIGHTS
+                ("MAX_UNIFORM_LIGHTS", Some("64")),
+            ],
+        }
+    }
+}
+
+/// Layout of the light list header, shared by the std140 and std430 declarations
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct LightListHeader {
+    count: u32,
+    _padding: [u32; 3],
+}
+
+unsafe impl bytemuck::Pod for LightListHeader {}
+unsafe impl bytemuck::Zeroable for LightListHeader {}
+
+/// Values of `GpuLight::params.x`, kept in sync with the LIGHT_* constants in shader.frag
+const LIGHT_KIND_POINT: f32 = 0.0;
+const LIGHT_KIND_SPOT: f32 = 1.0;
+const LIGHT_KIND_DIRECTIONAL: f32 = 2.0;
+
+/// A single light as laid out in the light list
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(unused)]
+struct GpuLight {
+    /// View space position, w is unused
+    position: [f32; 4],
+
+    /// View space unit direction that the light travels in, w is unused
+    direction: [f32; 4],
+
+    /// Linear RGB color in xyz, power in w
+    color: [f32; 4],
+
+    /// Kind of light in x, cosines of a spot light's inner and outer cone angles in y and z, w is
+    /// unused
+    params: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for GpuLight {}
+unsafe impl bytemuck::Zeroable for GpuLight {}
+
+impl GpuLight {
+    fn new(light: &Light, view: Matrix4<f32>) -> Self {
+        let position = |position: Point3<f32>| {
+            let position = view.transform_point(position);
+            [position.x, position.y, position.z, 1.0]
+        };
+        let direction = |direction: Vector3<f32>| {
+            let direction = view.transform_vector(direction).normalize();
+            [direction.x, direction.y, direction.z, 0.0]
+        };
+        let color = |color: Vector3<f32>, power: f32| [color.x, color.y, color.z, power];
+
+        match light {
+            Light::Point(light) => Self {
+                position: position(light.position),
+                direction: [0.0; 4],
+                color: color(light.color, light.power),
+                params: [LIGHT_KIND_POINT, 0.0, 0.0, 0.0],
+            },
+            Light::Spot(light) => Self {
+                position: position(light.position),
+                direction: direction(light.direction),
+                color: color(light.color, light.power),
+                params: [
+                    LIGHT_KIND_SPOT,
+                    light.inner_angle.cos(),
+                    light.outer_angle.cos(),
+                    0.0,
+                ],
+            },
+            Light::Directional(light) => Self {
+                position: [0.0; 4],
+                direction: direction(light.direction),
+                color: color(light.color, light.intensity),
+                params: [LIGHT_KIND_DIRECTIONAL, 0.0, 0.0, 0.0],
+            },
+        }
+    }
+}
+
+const HEADER_SIZE: usize = std::mem::size_of::<LightListHeader>();
+const LIGHT_SIZE: usize = std::mem::size_of::<GpuLight>();
+
+/// GPU side copy of the frame's light list, and the bind group that exposes it to shaders
+pub struct LightBuffer {
+    kind: LightBufferKind,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    bind_group: wgpu::BindGroup,
+    buffer: wgpu::Buffer,
+
+    /// Number of lights `buffer` has room for
+    capacity: usize,
+}
+
+impl LightBuffer {
+    pub fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        kind: LightBufferKind,
+    ) -> Self {
+        let ty = match kind {
+            LightBufferKind::Storage => wgpu::BindingType::StorageBuffer {
+                dynamic: false,
+                readonly: true,
+            },
+            LightBufferKind::Uniform => wgpu::BindingType::UniformBuffer { dynamic: false },
+        };
+
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty,
+                }],
+                label: Some("Light list bind group layout"),
+            });
+
+        let capacity = match kind {
+            LightBufferKind::Storage => INITIAL_STORAGE_CAPACITY,
+            LightBufferKind::Uniform => MAX_UNIFORM_LIGHTS,
+        };
+        let (buffer, bind_group) = Self::allocate(device, kind, &bind_group_layout, capacity);
+
+        Self {
+            kind,
+            bind_group_layout,
+            bind_group,
+            buffer,
+            capacity,
+        }
+    }
+
+    fn allocate(
+        device: &wgpu::Device,
+        kind: LightBufferKind,
+        layout: &wgpu::BindGroupLayout,
+        capacity: usize,
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let size = (HEADER_SIZE + capacity * LIGHT_SIZE) as wgpu::BufferAddress;
+        let usage = match kind {
+            LightBufferKind::Storage => wgpu::BufferUsage::STORAGE_READ,
+            LightBufferKind::Uniform => wgpu::BufferUsage::UNIFORM,
+        };
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size,
+            usage: usage | wgpu::BufferUsage::COPY_DST,
+            label: Some("Light list buffer"),
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[wgpu::Binding {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &buffer,
+                    range: 0..size,
+                },
+            }],
+            label: Some("Light list bind group"),
+        });
+
+        (buffer, bind_group)
+    }
+
+    pub fn kind(&self) -> LightBufferKind {
+        self.kind
+    }
+
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    /// Record copying this frame's lights in to the buffer, growing it first if needed
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        lights: &[Light],
+        view: Matrix4<f32>,
+    ) {
+        let lights = match self.kind {
+            LightBufferKind::Storage => lights,
+            LightBufferKind::Uniform => &lights[..lights.len().min(MAX_UNIFORM_LIGHTS)],
+        };
+
+        if lights.len() > self.capacity {
+            self.capacity = lights.len().next_power_of_two();
+            let (buffer, bind_group) =
+                Self::allocate(device, self.kind, &self.bind_group_layout, self.capacity);
+            self.buffer = buffer;
+            self.bind_group = bind_group;
+        }
+
+        let header = LightListHeader {
+            count: lights.len() as u32,
+            _padding: [0; 3],
+        };
+        let mut data = Vec::with_capacity(HEADER_SIZE + lights.len() * LIGHT_SIZE);
+        data.extend_from_slice(bytemuck::bytes_of(&header));
+        for light in lights {
+            data.extend_from_slice(bytemuck::bytes_of(&GpuLight::new(light, view)));
+        }
+
+        staging_belt.write(device, encoder, &self.buffer, 0, &data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::frame_packet::SpotLight;
+    use cgmath::Deg;
+
+    #[test]
+    fn test_spot_light_in_view_space() {
+        let view = Matrix4::look_at_dir(
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::unit_x(),
+            Vector3::unit_z(),
+        );
+        let light = Light::Spot(SpotLight {
+            position: Point3::new(2.0, 0.0, 0.0),
+            direction: Vector3::new(-2.0, 0.0, 0.0),
+            color: Vector3::new(1.0, 0.5, 0.25),
+            power: 3.0,
+            inner_angle: Deg(0.0).into(),
+            outer_angle: Deg(90.0).into(),
+        });
+
+        let gpu_light = GpuLight::new(&light, view);
+        // The camera looks down -z in view space, so a light in front of it pointing back at it
+        // ends up on -z pointing towards +z
+        assert_relative_eq!(gpu_light.position[2], -2.0, epsilon = 1e-6);
+        assert_relative_eq!(gpu_light.direction[2], 1.0, epsilon = 1e-6);
+        assert_eq!(gpu_light.color, [1.0, 0.5, 0.25, 3.0]);
+        assert_relative_eq!(gpu_light.params[1], 1.0, epsilon = 1e-6);
+        assert_relative_eq!(gpu_light.params[2], 0.0, epsilon = 1e-6);
+        assert_eq!(gpu_light.params[0], LIGHT_KIND_SPOT);
+    }
+}
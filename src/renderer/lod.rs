@@ -0,0 +1,155 @@
+use cgmath::{InnerSpace, Matrix4, Vector3};
+
+use super::ModelId;
+use crate::error::{Error, Result};
+
+/// How far past a level's threshold the screen size has to go before switching away from it, as a
+/// fraction of the threshold, so that models sitting right on one don't flicker between levels
+const HYSTERESIS: f32 = 0.15;
+
+/// One mesh of an `LodModel`
+#[derive(Clone, Copy, PartialEq)]
+pub struct LodLevel {
+    pub model_id: ModelId,
+
+    /// Smallest fraction of the screen's height the model can cover while drawn with this level
+    pub min_screen_size: f32,
+}
+
+/// Several meshes of the same model, from the most detailed to the least, drawn depending on
+/// how big the model is on screen
+#[derive(Clone, PartialEq)]
+pub struct LodModel {
+    levels: Vec<LodLevel>,
+
+    /// Radius of a sphere about the model's origin that holds every level
+    bounding_radius: f32,
+}
+
+impl LodModel {
+    /// The levels have to be in order of decreasing `min_screen_size`. The last level is drawn
+    /// however small the model gets, regardless of its own threshold.
+    pub fn new(levels: Vec<LodLevel>, bounding_radius: f32) -> Result<Self> {
+        if levels.is_empty() {
+            return Err(Error::InvalidAsset("An LOD model needs at least one level"));
+        }
+        if levels.windows(2).any(|pair| pair[0].min_screen_size <= pair[1].min_screen_size) {
+            return Err(Error::InvalidAsset("LOD screen sizes must decrease level by level"));
+        }
+        Ok(Self { levels, bounding_radius })
+    }
+
+    pub fn model_id(&self, level: usize) -> ModelId {
+        self.levels[level].model_id
+    }
+
+    /// Index of the least detailed level
+    pub fn coarsest(&self) -> usize {
+        self.levels.len() - 1
+    }
+
+    /// Fraction of the screen's height the model covers, drawn with the given model-view and
+    /// projection matrices
+    ///
+    /// Taken from the model's bounding sphere, so only depends on how far away it is and not
+    /// which way it's facing.
+    pub fn screen_size(&self, model_view: Matrix4<f32>, proj: Matrix4<f32>) -> f32 {
+        let scale = model_view.x.truncate().magnitude();
+        let radius = self.bounding_radius * scale;
+        let depth = -model_view.w.z;
+        if depth <= radius {
+            // The camera is inside the sphere
+            return f32::INFINITY;
+        }
+        radius * proj.y.y / depth
+    }
+
+    /// The level to draw at the given screen size, given the level it was last drawn with
+    pub fn select(&self, screen_size: f32, previous: Option<usize>) -> usize {
+        let ideal = self
+            .levels
+            .iter()
+            .position(|level| screen_size >= level.min_screen_size)
+            .unwrap_or_else(|| self.coarsest());
+
+        match previous {
+            Some(previous) if ideal > previous => {
+                let threshold = self.levels[previous].min_screen_size;
+                if screen_size < threshold * (1.0 - HYSTERESIS) {
+                    ideal
+                } else {
+                    previous
+                }
+            }
+            Some(previous) if ideal < previous => {
+                let threshold = self.levels[previous - 1].min_screen_size;
+                if screen_size >= threshold * (1.0 + HYSTERESIS) {
+                    ideal
+                } else {
+                    previous
+                }
+            }
+            _ => ideal,
+        }
+    }
+}
+
+/// Radius of a sphere about the origin holding every position
+pub fn bounding_radius(positions: impl Iterator<Item = [f32; 3]>) -> f32 {
+    positions
+        .map(|position| Vector3::from(position).magnitude())
+        .fold(0.0, f32::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, PerspectiveFov, Rad};
+
+    fn three_levels() -> LodModel {
+        let levels = [0.5, 0.1, 0.0]
+            .iter()
+            .enumerate()
+            .map(|(i, &min_screen_size)| LodLevel {
+                model_id: ModelId(i),
+                min_screen_size,
+            })
+            .collect();
+        LodModel::new(levels, 1.0).unwrap()
+    }
+
+    #[test]
+    fn test_select_with_hysteresis() {
+        let lods = three_levels();
+        assert_eq!(lods.select(0.6, None), 0);
+        assert_eq!(lods.select(0.2, None), 1);
+        assert_eq!(lods.select(0.01, None), 2);
+
+        // Just below the threshold isn't enough to drop to a coarser level...
+        assert_eq!(lods.select(0.48, Some(0)), 0);
+        assert_eq!(lods.select(0.4, Some(0)), 1);
+        // ...and just above isn't enough to go back up
+        assert_eq!(lods.select(0.52, Some(1)), 1);
+        assert_eq!(lods.select(0.6, Some(1)), 0);
+        assert_eq!(lods.select(0.6, Some(2)), 0);
+    }
+
+    #[test]
+    fn test_screen_size() {
+        let lods = three_levels();
+        let proj: Matrix4<f32> = PerspectiveFov {
+            fovy: Rad::from(Deg(90.0)),
+            aspect: 1.0,
+            near: 0.1,
+            far: 100.0,
+        }
+        .into();
+
+        // With a 90 degree FOV the screen is 20 units tall at a depth of 10
+        let model_view = Matrix4::from_translation(Vector3::new(0.0, 0.0, -10.0));
+        assert!((lods.screen_size(model_view, proj) - 0.1).abs() < 1e-5);
+        let scaled = model_view * Matrix4::from_scale(2.0);
+        assert!((lods.screen_size(scaled, proj) - 0.2).abs() < 1e-5);
+        assert_eq!(lods.screen_size(Matrix4::from_scale(1.0), proj), f32::INFINITY);
+    }
+}
@@ -1,55 +1,724 @@
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use crate::{model_data::ModelData, shader_cache::ShaderCache, vertex::Vertex};
+use cgmath::{InnerSpace, Matrix, SquareMatrix, Zero};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 
+use crate::{
+    error::{Error, Result},
+    model_data::{AlphaMode, MaterialData, MaterialFactors, ModelData},
+    picking::{Aabb, PickMesh},
+    scene_data::SceneData,
+    shader_cache::ShaderCache,
+    shader_watcher::ShaderWatcher, terrain::TerrainData, vertex::Vertex,
+};
+
+pub mod atlas_builder;
+mod backend;
+mod block_on;
+mod builder;
+mod capabilities;
+mod compute;
+mod debug_gui;
+mod debug_lines;
+mod decal;
+mod deferred;
+mod depth_of_field;
+mod depth_readback;
+pub mod dynamic_resolution;
 pub mod frame_packet;
+mod frame_packet_builder;
+mod frame_stats;
+mod frames_in_flight;
+mod fxaa;
+mod gpu_culling;
+mod headless;
+mod ibl;
+mod index_buffer;
+mod instance_buffer;
+mod joints;
+mod lights;
+mod lod;
+mod motion_blur;
+mod motion_vectors;
+mod oit;
+mod outline;
+mod output;
+mod pipeline_cache;
+mod post_process;
+mod profiler;
+mod render_graph;
+mod render_target;
+mod resource_cache;
+mod shader_features;
+mod shadow;
+mod skybox;
 mod sprite_overlay;
+mod ssao;
+mod staging_belt;
+mod taa;
+mod terrain;
+mod text;
+mod texture_streaming;
+mod upscale;
+mod water;
 
-use frame_packet::{FramePacket, InstanceData};
+use compute::ComputeScheduler;
+use debug_gui::DebugGuiStage;
+use debug_lines::DebugLinesStage;
+use decal::DecalRenderStage;
+use deferred::DeferredRenderStage;
+use depth_of_field::DepthOfFieldStage;
+use depth_readback::DepthReadback;
+use dynamic_resolution::DynamicResolution;
+use frame_packet::{FramePacket, FramePacketModel, InstanceData};
+use frame_stats::{DrawCounter, StageTimer};
+use frames_in_flight::{FrameFences, FrameSlot, PerFrame};
+use fxaa::FxaaStage;
+use gpu_culling::{CulledModel, GpuCulling};
+use ibl::EnvironmentLighting;
+use index_buffer::{IndexBuffer, INDEX_FORMATS};
+use instance_buffer::InstanceBuffer;
+use joints::{JointBuffer, MAX_JOINTS};
+use lights::{LightBuffer, LightBufferKind};
+use motion_blur::MotionBlurStage;
+use motion_vectors::{InstanceHistory, MotionFrame, PrevInstanceData};
+use oit::OitStage;
+use outline::OutlineStage;
+use output::OutputRenderStage;
+use pipeline_cache::{PipelineCache, PIPELINE_CACHE_PATH};
+use post_process::PostProcessStage;
+use profiler::Profiler;
+use render_graph::{RenderGraph, TransientPool};
+use render_target::{ColorTarget, DepthOrder, RenderTarget, Viewport};
+use resource_cache::{RenderPipelineDesc, ResourceCache};
+use shader_features::ShaderFeatures;
+use shadow::ShadowRenderStage;
+use skybox::{GpuSkybox, SkyboxRenderStage};
 use sprite_overlay::SpriteOverlayRenderStage;
+use ssao::SsaoStage;
+use staging_belt::StagingBelt;
+use taa::TaaStage;
+use terrain::{GpuTerrain, TerrainRenderStage};
+use text::TextRenderStage;
+use texture_streaming::{MaterialTexture, TextureStreamer};
+use upscale::UpscaleRenderStage;
+use water::WaterRenderStage;
+
+pub use backend::BackendPreference;
+pub use builder::RendererBuilder;
+pub use capabilities::AdapterCapabilities;
+#[allow(unused_imports)]
+pub use compute::{ComputePass, ComputePassDesc, ComputeWorkload};
+pub use depth_readback::DepthSample;
+pub use frame_packet_builder::{FramePacketBuilder, KnownHandles};
+pub use frame_stats::FrameStats;
+pub use lod::{LodLevel, LodModel};
+pub use motion_blur::MotionBlurConfig;
+pub use oit::TransparencyMode;
+#[allow(unused_imports)]
+pub use output::{ColorDeficiency, ColorFilter, OutputCalibration};
+pub use post_process::Tonemapper;
+pub use profiler::FrameProfile;
+pub use ssao::SsaoConfig;
+pub use text::DEFAULT_UI_FONT_PATH;
+pub use texture_streaming::TextureStreamingConfig;
+pub use upscale::UpscaleFilter;
+
+/// Watched for edits, so that shaders can be reloaded without restarting
+const SHADER_DIR: &str = "./src/renderer/shaders";
+
+/// How the 3D scene is lit, chosen when the renderer is created
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderPath {
+    /// Light each fragment as it's drawn
+    #[default]
+    Forward,
+
+    /// Draw surface attributes in to a G-buffer, then light every pixel once in a fullscreen pass
+    Deferred,
+}
+
+/// How the edges of the scene's triangles are smoothed, chosen when the renderer is created
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AaMode {
+    /// Multisampling, with as many samples as `RendererConfig::msaa_samples` asks for
+    #[default]
+    Msaa,
+
+    /// Fast approximate anti-aliasing, a post process over the tonemapped scene that blurs along
+    /// the edges it finds
+    Fxaa,
+
+    /// Temporal anti-aliasing, which jitters the camera by a fraction of a pixel each frame and
+    /// blends the tonemapped scene with the previous frames, reprojected by motion vectors.
+    /// Only supported by the forward path.
+    Taa,
+}
+
+/// How finished frames are handed to the display
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresentMode {
+    /// Wait for vertical blank, blocking once the queue of frames is full. Never tears.
+    Fifo,
+
+    /// Wait for vertical blank, replacing the queued frame rather than blocking. Never tears.
+    #[default]
+    Mailbox,
+
+    /// Present straight away, for the lowest latency at the cost of tearing
+    Immediate,
+}
+
+impl PresentMode {
+    /// Switch between waiting for vertical blank and not, for comparing latency
+    pub fn vsync_toggled(self) -> Self {
+        match self {
+            PresentMode::Fifo | PresentMode::Mailbox => PresentMode::Immediate,
+            PresentMode::Immediate => PresentMode::Fifo,
+        }
+    }
+
+    fn to_wgpu(self) -> wgpu::PresentMode {
+        match self {
+            PresentMode::Fifo => wgpu::PresentMode::Fifo,
+            PresentMode::Mailbox => wgpu::PresentMode::Mailbox,
+            PresentMode::Immediate => wgpu::PresentMode::Immediate,
+        }
+    }
+}
+
+/// Settings the renderer is created with
+#[derive(Clone, Debug, PartialEq)]
+pub struct RendererConfig {
+    /// Fixed for the lifetime of the renderer
+    pub render_path: RenderPath,
+
+    /// Can be changed later with `Renderer::apply_quality`. TAA isn't supported by the deferred
+    /// path, which uses FXAA in its place.
+    pub aa_mode: AaMode,
+
+    /// MSAA samples per pixel, 0 or 1 to disable it. Can be changed later with
+    /// `Renderer::apply_quality`, and only used by `AaMode::Msaa`.
+    ///
+    /// This is a request, the closest sample count the adapter supports is used instead.
+    pub msaa_samples: u32,
+
+    /// Can be overridden with the `WGPU_BACKEND` environment variable
+    pub backend: BackendPreference,
+
+    /// Can be changed later with `Renderer::set_present_mode`
+    pub present_mode: PresentMode,
+
+    /// Whether to draw the scene's depth before shading it, so that each pixel is only shaded
+    /// once. Fixed for the lifetime of the renderer, and only supported by the forward path.
+    pub depth_prepass: bool,
+
+    /// Screen space ambient occlusion, None to disable it. Can be changed later with
+    /// `Renderer::apply_quality`, and only supported by the forward path without MSAA.
+    ///
+    /// This works from the depth prepass, so turns it on too.
+    pub ssao: Option<SsaoConfig>,
+
+    /// Blur along each pixel's motion since last frame, None to disable it. Can be changed later
+    /// with `Renderer::apply_quality`, and only supported by the forward path without MSAA.
+    pub motion_blur: Option<MotionBlurConfig>,
+
+    /// Texels along each side of the shadow map. Can be changed later with
+    /// `Renderer::apply_quality`.
+    pub shadow_map_size: u32,
+
+    /// Whether models with many instances are frustum culled by a compute pass and drawn
+    /// indirectly, rather than every instance being drawn. Fixed for the lifetime of the renderer.
+    pub gpu_culling: bool,
+
+    /// Fixed for the lifetime of the renderer
+    pub transparency: TransparencyMode,
+
+    /// Whether depth is reversed, with the near plane at 1 and the far plane at 0, for better
+    /// precision in the distance. Fixed for the lifetime of the renderer, and cameras must
+    /// set `reverse_z` to match.
+    pub reverse_z: bool,
+
+    /// Stream the mips of material textures in as they're seen up close, keeping them under a
+    /// memory budget. None to upload every texture in full. Fixed for the lifetime of the renderer.
+    pub texture_streaming: Option<TextureStreamingConfig>,
+
+    /// TrueType font that all UI text is drawn with
+    pub ui_font: PathBuf,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        Self {
+            render_path: RenderPath::default(),
+            aa_mode: AaMode::default(),
+            msaa_samples: 0,
+            backend: BackendPreference::default(),
+            present_mode: PresentMode::default(),
+            depth_prepass: false,
+            ssao: None,
+            motion_blur: None,
+            shadow_map_size: shadow::SHADOW_MAP_SIZE,
+            gpu_culling: false,
+            transparency: TransparencyMode::default(),
+            reverse_z: false,
+            texture_streaming: None,
+            ui_font: PathBuf::from(DEFAULT_UI_FONT_PATH),
+        }
+    }
+}
+
+/// The subset of the graphics quality settings that the renderer is responsible for
+///
+/// Everything here is applied together by `Renderer::apply_quality`, so that switching between
+/// quality levels never leaves the renderer in a mix of the two.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderQuality {
+    /// Passed to `Renderer::set_render_scale`
+    pub render_scale: f32,
+
+    /// Whether dynamic resolution may drop the render scale below `render_scale`
+    pub dynamic_resolution: bool,
+
+    pub upscale_filter: UpscaleFilter,
+
+    /// Minification filter used when sampling model textures
+    pub texture_filter: wgpu::FilterMode,
+
+    /// Most taps the forward path takes along a texture's footprint when it's viewed at a
+    /// glancing angle, where 1 disables anisotropic filtering
+    ///
+    /// wgpu doesn't expose a max anisotropy on samplers, so this is done in the shader.
+    pub anisotropy: u32,
+
+    /// Texels along each side of the shadow map
+    pub shadow_map_size: u32,
+
+    /// See `RendererConfig::aa_mode`. MSAA rules out SSAO and motion blur, so anything asking
+    /// for those has to use one of the post process AA modes.
+    pub aa_mode: AaMode,
+
+    /// See `RendererConfig::msaa_samples`
+    pub msaa_samples: u32,
+
+    /// See `RendererConfig::ssao`
+    pub ssao: Option<SsaoConfig>,
+
+    /// See `RendererConfig::motion_blur`
+    pub motion_blur: Option<MotionBlurConfig>,
+}
+
+/// What the scene is drawn with, out of what the renderer config asks for, once anything the
+/// render path, AA mode or adapter can't support has been turned off
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SceneSettings {
+    aa_mode: AaMode,
+    sample_count: u32,
+    ssao: Option<SsaoConfig>,
+    motion_blur: Option<MotionBlurConfig>,
+    depth_prepass: bool,
+}
+
+impl SceneSettings {
+    /// The graphics quality asks for MSAA and post effects whatever the render path and AA mode,
+    /// so those being turned off is only worth a mention
+    fn resolve(config: &RendererConfig, capabilities: &AdapterCapabilities) -> Self {
+        let RendererConfig { render_path, aa_mode, msaa_samples, .. } = *config;
+        let aa_mode = match (render_path, aa_mode) {
+            (RenderPath::Deferred, AaMode::Taa) => {
+                info!("TAA isn't supported by the deferred render path, using FXAA instead");
+                AaMode::Fxaa
+            }
+            (_, aa_mode) => aa_mode,
+        };
+        let sample_count = match (render_path, aa_mode) {
+            (RenderPath::Forward, AaMode::Msaa) => capabilities.validate_sample_count(msaa_samples),
+            (RenderPath::Deferred, AaMode::Msaa) if msaa_samples > 1 => {
+                info!("MSAA isn't supported by the deferred render path, disabling it");
+                1
+            }
+            (_, AaMode::Fxaa | AaMode::Taa) if msaa_samples > 1 => {
+                info!("MSAA isn't used along with {:?}, disabling it", aa_mode);
+                1
+            }
+            _ => 1,
+        };
+        let ssao = match (render_path, config.ssao) {
+            (_, None) => None,
+            (RenderPath::Deferred, Some(_)) => {
+                info!("SSAO isn't supported by the deferred render path, disabling it");
+                None
+            }
+            (RenderPath::Forward, Some(_)) if sample_count > 1 => {
+                info!("SSAO isn't supported with MSAA, disabling it");
+                None
+            }
+            (RenderPath::Forward, Some(ssao)) => Some(ssao),
+        };
+        let motion_blur = match (render_path, config.motion_blur) {
+            (_, None) => None,
+            (RenderPath::Deferred, Some(_)) => {
+                info!("Motion blur isn't supported by the deferred render path, disabling it");
+                None
+            }
+            (RenderPath::Forward, Some(_)) if sample_count > 1 => {
+                info!("Motion blur isn't supported with MSAA, disabling it");
+                None
+            }
+            (RenderPath::Forward, Some(motion_blur)) => Some(motion_blur),
+        };
+        let depth_prepass = match render_path {
+            RenderPath::Forward => config.depth_prepass || ssao.is_some(),
+            RenderPath::Deferred if config.depth_prepass => {
+                warn!("The depth prepass isn't supported by the deferred render path");
+                false
+            }
+            RenderPath::Deferred => false,
+        };
+
+        Self {
+            aa_mode,
+            sample_count,
+            ssao,
+            motion_blur,
+            depth_prepass,
+        }
+    }
+}
+
+/// The stages whose pipelines or targets depend on `SceneSettings`, which are all created again
+/// when the graphics quality changes those
+struct SceneStages {
+    ssao: Option<SsaoStage>,
+    oit: Option<OitStage>,
+    outline: OutlineStage,
+    terrain: TerrainRenderStage,
+    decal: Option<DecalRenderStage>,
+    water: Option<WaterRenderStage>,
+    skybox: SkyboxRenderStage,
+    debug_lines: DebugLinesStage,
+    depth_of_field: Option<DepthOfFieldStage>,
+    motion_blur: Option<MotionBlurStage>,
+}
+
+impl SceneStages {
+    async fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        resources: &mut ResourceCache,
+        settings: &SceneSettings,
+        transparency: TransparencyMode,
+        scene_target: &RenderTarget,
+    ) -> Result<Self> {
+        // The scene's depth can't be read under MSAA
+        let resolved = settings.sample_count == 1;
+        let ssao = match settings.ssao {
+            Some(ssao) => Some(SsaoStage::new(device, resources, ssao, scene_target).await?),
+            None => None,
+        };
+        let oit = match transparency {
+            TransparencyMode::Sorted => None,
+            TransparencyMode::WeightedBlended => {
+                Some(OitStage::new(device, resources, scene_target).await?)
+            }
+        };
+        let outline = OutlineStage::new(device, resources, scene_target).await?;
+        let terrain = TerrainRenderStage::new(device, resources, scene_target).await?;
+        let decal = if resolved {
+            Some(DecalRenderStage::new(device, resources, scene_target).await?)
+        } else {
+            None
+        };
+        let water = if resolved {
+            Some(WaterRenderStage::new(device, queue, resources, scene_target).await?)
+        } else {
+            None
+        };
+        let skybox = SkyboxRenderStage::new(device, resources, scene_target).await?;
+        let debug_lines = DebugLinesStage::new(device, resources, scene_target).await?;
+        let depth_of_field = if resolved {
+            Some(DepthOfFieldStage::new(device, resources).await?)
+        } else {
+            None
+        };
+        let motion_blur = match settings.motion_blur {
+            Some(motion_blur) => Some(MotionBlurStage::new(device, resources, motion_blur).await?),
+            None => None,
+        };
+
+        Ok(Self {
+            ssao,
+            oit,
+            outline,
+            terrain,
+            decal,
+            water,
+            skybox,
+            debug_lines,
+            depth_of_field,
+            motion_blur,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct MaterialUniformData {
+    /// Linear RGBA
+    base_color_factor: [f32; 4],
+
+    /// Linear RGB, with the alpha cutoff in w. The cutoff is 0 for materials that aren't
+    /// masked, which never cuts anything out.
+    emissive_factor: [f32; 4],
+
+    /// Metalness, roughness, normal scale and occlusion strength
+    params: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for MaterialUniformData {}
+unsafe impl bytemuck::Zeroable for MaterialUniformData {}
+
+impl From<&MaterialFactors> for MaterialUniformData {
+    fn from(factors: &MaterialFactors) -> Self {
+        let [r, g, b] = factors.emissive;
+        let alpha_cutoff = match factors.alpha_mode {
+            AlphaMode::Mask => factors.alpha_cutoff,
+            AlphaMode::Opaque | AlphaMode::Blend => 0.0,
+        };
+        Self {
+            base_color_factor: factors.base_color,
+            emissive_factor: [r, g, b, alpha_cutoff],
+            params: [
+                factors.metallic,
+                factors.roughness,
+                factors.normal_scale,
+                factors.occlusion_strength,
+            ],
+        }
+    }
+}
 
 /// Represents a handle to a single model's data on the GPU
 struct GpuModel {
     vertex_buff: wgpu::Buffer,
-    index_buff: wgpu::Buffer,
-    index_count: u32,
-    base_color_texture: wgpu::Texture,
+    indices: IndexBuffer,
+
+    /// Each edge of the triangles in `indices` as a line, for drawing in wireframe. Always the
+    /// same format as `indices`.
+    wireframe_indices: IndexBuffer,
+
+    /// The material uploaded along with the model, which it's drawn with unless the frame packet
+    /// says otherwise
+    material: MaterialId,
+
+    /// Whether the vertices have joints and weights to be deformed by a skin
+    skinned: bool,
+
+    /// Model space bounds of the vertices, in their bind pose if skinned
+    bounds: Aabb,
 }
 
 impl GpuModel {
-    fn from_data(
-        data: &ModelData,
-        device: &wgpu::Device,
-        queue: &mut wgpu::Queue,
-    ) -> Self {
+    fn from_data(data: &ModelData, device: &wgpu::Device, material: MaterialId) -> Result<Self> {
+        // wgpu doesn't allow zero sized buffers
+        if data.vertices.is_empty() || data.indices.is_empty() {
+            return Err(Error::InvalidAsset("Model has no geometry"));
+        }
+
+        let max_joint = data.vertices.iter().flat_map(|vertex| vertex.joints).max();
+        if data.skinned && max_joint.is_some_and(|joint| joint as usize >= MAX_JOINTS) {
+            return Err(Error::InvalidAsset("Model is skinned with too many joints"));
+        }
+
         let vertex_buff = device.create_buffer_with_data(
             bytemuck::cast_slice(&data.vertices),
             wgpu::BufferUsage::VERTEX,
         );
-        let index_buff = device.create_buffer_with_data(
-            bytemuck::cast_slice(&data.indices),
-            wgpu::BufferUsage::INDEX,
+        let indices = IndexBuffer::new(device, &data.indices, data.vertices.len());
+        let wireframe_indices = IndexBuffer::new(
+            device,
+            &debug_lines::wireframe_indices(&data.indices),
+            data.vertices.len(),
         );
-        let index_count = data.indices.len() as u32;
 
-        let base_color_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Model base color texture"),
-            size: wgpu::Extent3d {
-                width: data.texture.width(),
-                height: data.texture.height(),
-                depth: 1,
-            },
+        let bounds = Aabb::from_points(data.vertices.iter().map(|vertex| vertex.position.into()))
+            .expect("Models with no vertices are rejected above");
+
+        Ok(Self {
+            vertex_buff,
+            indices,
+            wireframe_indices,
+            material,
+            skinned: data.skinned,
+            bounds,
+        })
+    }
+
+    /// Which permutation of the forward shader draws this model with the given material
+    fn features_with(&self, material: &GpuMaterial) -> ShaderFeatures {
+        let mut features = material.features;
+        features.set(ShaderFeatures::SKINNING, self.skinned);
+        features
+    }
+}
+
+/// Represents a handle to a single material's textures and factors on the GPU
+struct GpuMaterial {
+    // Textures that the material didn't have are filled in with a single texel that leaves the
+    // matching factor in `material_buff` unchanged
+    base_color_texture: wgpu::Texture,
+    metallic_roughness_texture: wgpu::Texture,
+    normal_texture: wgpu::Texture,
+    occlusion_texture: wgpu::Texture,
+    emissive_texture: wgpu::Texture,
+    lightmap_texture: wgpu::Texture,
+
+    /// Uniform buffer of `MaterialUniformData`
+    material_buff: wgpu::Buffer,
+
+    /// The parts of the forward shader permutation that depend on the material rather than the
+    /// model it's drawn on
+    features: ShaderFeatures,
+}
+
+impl GpuMaterial {
+    /// Textures are handed to the streamer along with the material's id when given one, other
+    /// than the defaults filled in for missing textures
+    fn from_data(
+        data: &MaterialData,
+        device: &wgpu::Device,
+        queue: &mut wgpu::Queue,
+        mut streaming: Option<(&mut TextureStreamer, MaterialId)>,
+    ) -> Result<Self> {
+        let white = image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 255, 255, 255]));
+        let flat_normal = image::RgbaImage::from_pixel(1, 1, image::Rgba([128, 128, 255, 255]));
+        let mut upload = |image: Option<&image::RgbaImage>, default, slot, format, label| {
+            match (image, streaming.as_mut()) {
+                (Some(image), Some((streamer, material_id))) => {
+                    if image.width() == 0 || image.height() == 0 {
+                        return Err(Error::InvalidAsset("Material has an empty texture"));
+                    }
+                    Ok(streamer.add(device, queue, *material_id, slot, image, format, label))
+                }
+                _ => Self::upload_texture(image.unwrap_or(default), format, label, device, queue),
+            }
+        };
+        let base_color_texture = upload(
+            Some(&data.base_color_texture),
+            &white,
+            MaterialTexture::BaseColor,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            "Material base color texture",
+        )?;
+        let metallic_roughness_texture = upload(
+            data.metallic_roughness_texture.as_ref(),
+            &white,
+            MaterialTexture::MetallicRoughness,
+            wgpu::TextureFormat::Rgba8Unorm,
+            "Material metallic-roughness texture",
+        )?;
+        let normal_texture = upload(
+            data.normal_texture.as_ref(),
+            &flat_normal,
+            MaterialTexture::Normal,
+            wgpu::TextureFormat::Rgba8Unorm,
+            "Material normal texture",
+        )?;
+        let occlusion_texture = upload(
+            data.occlusion_texture.as_ref(),
+            &white,
+            MaterialTexture::Occlusion,
+            wgpu::TextureFormat::Rgba8Unorm,
+            "Material occlusion texture",
+        )?;
+        let emissive_texture = upload(
+            data.emissive_texture.as_ref(),
+            &white,
+            MaterialTexture::Emissive,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            "Material emissive texture",
+        )?;
+        let lightmap_texture = upload(
+            data.lightmap_texture.as_ref(),
+            &white,
+            MaterialTexture::Lightmap,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            "Material lightmap texture",
+        )?;
+
+        let material_buff = device.create_buffer_with_data(
+            bytemuck::cast_slice(&[MaterialUniformData::from(&data.factors)]),
+            wgpu::BufferUsage::UNIFORM,
+        );
+
+        let mut features = ShaderFeatures::empty();
+        features.set(ShaderFeatures::NORMAL_MAP, data.normal_texture.is_some());
+        features.set(ShaderFeatures::ALPHA_BLEND, data.factors.alpha_mode == AlphaMode::Blend);
+        features.set(ShaderFeatures::ALPHA_MASK, data.factors.alpha_mode == AlphaMode::Mask);
+        features.set(ShaderFeatures::LIGHTMAP, data.lightmap_texture.is_some());
+
+        Ok(Self {
+            base_color_texture,
+            metallic_roughness_texture,
+            normal_texture,
+            occlusion_texture,
+            emissive_texture,
+            lightmap_texture,
+            material_buff,
+            features,
+        })
+    }
+
+    fn texture_mut(&mut self, slot: MaterialTexture) -> &mut wgpu::Texture {
+        match slot {
+            MaterialTexture::BaseColor => &mut self.base_color_texture,
+            MaterialTexture::MetallicRoughness => &mut self.metallic_roughness_texture,
+            MaterialTexture::Normal => &mut self.normal_texture,
+            MaterialTexture::Occlusion => &mut self.occlusion_texture,
+            MaterialTexture::Emissive => &mut self.emissive_texture,
+            MaterialTexture::Lightmap => &mut self.lightmap_texture,
+        }
+    }
+
+    fn upload_texture(
+        image: &image::RgbaImage,
+        format: wgpu::TextureFormat,
+        label: &'static str,
+        device: &wgpu::Device,
+        queue: &mut wgpu::Queue,
+    ) -> Result<wgpu::Texture> {
+        // wgpu doesn't allow zero sized textures
+        if image.width() == 0 || image.height() == 0 {
+            return Err(Error::InvalidAsset("Material has an empty texture"));
+        }
+
+        let size = wgpu::Extent3d {
+            width: image.width(),
+            height: image.height(),
+            depth: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
             array_layer_count: 1,
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            format,
             usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
         });
 
         // Actually filling the texture object with data requires this command buffer dance
         let texture_buff = device.create_buffer_with_data(
-            data.texture.as_flat_samples().as_slice(),
+            image.as_flat_samples().as_slice(),
             wgpu::BufferUsage::COPY_SRC,
         );
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -59,29 +728,20 @@ impl GpuModel {
             wgpu::BufferCopyView {
                 buffer: &texture_buff,
                 offset: 0,
-                bytes_per_row: 4 * data.texture.width(),
-                rows_per_image: data.texture.height(),
+                bytes_per_row: 4 * image.width(),
+                rows_per_image: image.height(),
             },
             wgpu::TextureCopyView {
-                texture: &base_color_texture,
+                texture: &texture,
                 mip_level: 0,
                 array_layer: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
-            wgpu::Extent3d {
-                width: data.texture.width(),
-                height: data.texture.height(),
-                depth: 1,
-            },
+            size,
         );
         queue.submit(&[encoder.finish()]);
 
-        Self {
-            vertex_buff,
-            index_buff,
-            index_count,
-            base_color_texture,
-        }
+        Ok(texture)
     }
 }
 
@@ -89,6 +749,28 @@ impl GpuModel {
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ModelId(usize);
 
+/// Exposed as a handle to a GpuMaterial
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MaterialId(usize);
+
+/// One part of an uploaded scene
+#[derive(Clone)]
+pub struct SceneModel {
+    pub model_id: ModelId,
+
+    /// The model's triangles, kept on the CPU for picking
+    pub pick_mesh: Rc<PickMesh>,
+
+    /// Index in to `SceneData::nodes` of the node the model is drawn at
+    pub node: usize,
+
+    /// Index in to `SceneData::skins` of the skin deforming the model, if any
+    pub skin: Option<usize>,
+
+    /// Transforms from this model's space in to the scene's space in its rest pose
+    pub transform: cgmath::Matrix4<f32>,
+}
+
 /// Represents a single sprite atlas on the GPU
 pub struct GpuAtlas {
     pub texture: wgpu::Texture,
@@ -148,144 +830,1060 @@ impl GpuAtlas {
     }
 }
 
+/// A render target that secondary views are drawn in to, along with the LDR copy of it that
+/// sprites show
+struct ViewTarget {
+    scene: RenderTarget,
+    post_process_bind_group: wgpu::BindGroup,
+    output: ColorTarget,
+}
+
 /// Exposed as a handle to a GpuAtlas
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct AtlasId(usize);
 
+/// Exposed as a handle to a GpuSkybox
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SkyboxId(usize);
+
+/// Exposed as a handle to a GpuTerrain
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TerrainId(usize);
+
+/// Where the renderer's finished frames end up
+enum RenderOutput {
+    /// Presented to a window
+    Window {
+        surface: wgpu::Surface,
+        swapchain: wgpu::SwapChain,
+    },
+
+    /// Left in a texture to be read back, for rendering without a window. See `new_headless`.
+    Offscreen(ColorTarget),
+}
+
 #[allow(unused)]
 pub struct Renderer {
     size: winit::dpi::PhysicalSize<u32>,
-    surface: wgpu::Surface,
+    output: RenderOutput,
     adapter: wgpu::Adapter,
+
+    /// What optional features are gated on, worked out from `adapter` up front
+    capabilities: AdapterCapabilities,
+
+    /// What the renderer was created with, with anything since changed by `apply_quality` and
+    /// the AA mode it fell back to
+    config: RendererConfig,
+
+    /// What the scene is currently drawn with, out of `config`
+    scene_settings: SceneSettings,
     device: wgpu::Device,
     queue: wgpu::Queue,
-    swapchain: wgpu::SwapChain,
-    depth_texture: wgpu::Texture,
+    present_mode: PresentMode,
+
+    /// Offscreen target that the 3D scene is rendered to before being upscaled to the swapchain
+    scene_target: RenderTarget,
+    dynamic_resolution: DynamicResolution,
+    upscale_filter: UpscaleFilter,
+    tonemapper: Tonemapper,
+    last_frame_start: Option<Instant>,
+
+    /// The last frame's unjittered projection and view, if it was drawn with motion vectors
+    last_view_proj: Option<cgmath::Matrix4<f32>>,
+
+    /// Output resolution target that the upscaled scene and UI overlay are composited in to
+    composite_target: ColorTarget,
+    color_filter: ColorFilter,
+    output_calibration: OutputCalibration,
+    high_contrast_ui: bool,
 
     next_model_id: ModelId,
     models: HashMap<ModelId, GpuModel>,
 
+    next_material_id: MaterialId,
+    materials: HashMap<MaterialId, GpuMaterial>,
+
+    /// Only created when texture streaming is enabled, otherwise textures are uploaded in full
+    texture_streamer: Option<TextureStreamer>,
+
     next_atlas_id: AtlasId,
     atlases: HashMap<AtlasId, GpuAtlas>,
 
+    /// Reserved atlas handles that are still waiting on their data
+    pending_atlases: HashSet<AtlasId>,
+
+    /// Atlases that are drawn in to by the frame packet's views, rather than uploaded
+    view_targets: HashMap<AtlasId, ViewTarget>,
+
+    next_skybox_id: SkyboxId,
+    skyboxes: HashMap<SkyboxId, GpuSkybox>,
+
+    next_terrain_id: TerrainId,
+    terrains: HashMap<TerrainId, GpuTerrain>,
+
+    /// Layouts/samplers shared between the stages
+    resource_cache: ResourceCache,
+
+    compute_scheduler: ComputeScheduler,
+    depth_readback: DepthReadback,
+
+    /// Shared by every stage for the uniforms and instances they upload each frame. Stages only
+    /// get a shared reference to the renderer, hence the RefCell.
+    staging_belt: RefCell<StagingBelt>,
+
+    draw_counter: DrawCounter,
+    frame_stats: FrameStats,
+    profiler: Profiler,
+
+    /// Limits how far ahead of the GPU frames are recorded, and which copy of per-frame resources
+    /// each one uses
+    frame_fences: FrameFences,
+
+    /// Textures for the frame graph's transients, reused from one frame to the next
+    transient_pool: TransientPool,
+
+    /// None if the platform can't watch for file changes
+    shader_watcher: Option<ShaderWatcher>,
+
+    shadow_render_stage: ShadowRenderStage,
+
+    /// Only created when SSAO is enabled, otherwise the forward stage reads `unoccluded_texture`
+    /// in place of its output
+    ssao_render_stage: Option<SsaoStage>,
+    unoccluded_texture: wgpu::Texture,
     forward_render_stage: ForwardRenderStage,
+
+    /// Only created for `RenderPath::Deferred`, in which case it draws the scene in place of the
+    /// forward stage
+    deferred_render_stage: Option<DeferredRenderStage>,
+
+    /// Only created for `TransparencyMode::WeightedBlended`
+    oit_render_stage: Option<OitStage>,
+    outline_render_stage: OutlineStage,
+    terrain_render_stage: TerrainRenderStage,
+
+    /// Not created under MSAA, where the scene's depth can't be read
+    decal_render_stage: Option<DecalRenderStage>,
+
+    /// Not created under MSAA, where the refraction's depth can't be read
+    water_render_stage: Option<WaterRenderStage>,
+    skybox_render_stage: SkyboxRenderStage,
+    debug_lines_stage: DebugLinesStage,
+
+    /// Not created under MSAA, where the scene's depth can't be read
+    depth_of_field_stage: Option<DepthOfFieldStage>,
+
+    /// Only created if enabled in the renderer config
+    motion_blur_stage: Option<MotionBlurStage>,
+    post_process_stage: PostProcessStage,
+
+    /// Only created for `AaMode::Fxaa`
+    fxaa_stage: Option<FxaaStage>,
+
+    /// Only created for `AaMode::Taa`, in which case it also keeps the jitter and history from one
+    /// frame to the next
+    taa_stage: Option<TaaStage>,
+    upscale_render_stage: UpscaleRenderStage,
     sprite_overlay_render_stage: SpriteOverlayRenderStage,
+    debug_gui_stage: DebugGuiStage,
+    text_render_stage: TextRenderStage,
+    output_render_stage: OutputRenderStage,
 }
 
 impl Renderer {
-    pub async fn new(window: &winit::window::Window) -> Self {
-        let size = window.inner_size();
+    pub async fn new(window: &winit::window::Window, config: &RendererConfig) -> Result<Self> {
         let surface = wgpu::Surface::create(window);
+        let adapter = Self::request_adapter(Some(&surface), config.backend)
+            .await
+            .ok_or(Error::Gpu("Failed to create adapter that can draw to our window"))?;
+        Self::with_adapter(adapter, Some(surface), window.inner_size(), config).await
+    }
 
-        let adapter = wgpu::Adapter::request(
-            &wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::Default,
-                compatible_surface: Some(&surface),
-            },
-            wgpu::BackendBit::VULKAN,
-        )
-        .await
-        .expect("Failed to create adapter that can draw to our window");
+    /// Create everything the renderer needs on the given adapter, drawing to the surface if
+    /// there is one and to an offscreen texture of the given size otherwise
+    async fn with_adapter(
+        adapter: wgpu::Adapter,
+        surface: Option<wgpu::Surface>,
+        size: winit::dpi::PhysicalSize<u32>,
+        config: &RendererConfig,
+    ) -> Result<Self> {
+        let RendererConfig {
+            render_path,
+            aa_mode: _,
+            msaa_samples: _,
+            backend: _,
+            present_mode,
+            depth_prepass: _,
+            ssao: _,
+            motion_blur: _,
+            shadow_map_size,
+            gpu_culling,
+            transparency,
+            reverse_z,
+            texture_streaming,
+            ref ui_font,
+        } = *config;
+        let adapter_info = adapter.get_info();
+        info!("Drawing with {} ({:?})", adapter_info.name, adapter_info.backend);
+        let capabilities = AdapterCapabilities::for_adapter(&adapter_info);
 
-        let (device, queue) = adapter
+        let (device, mut queue) = adapter
             .request_device(&wgpu::DeviceDescriptor {
                 extensions: wgpu::Extensions {
-                    anisotropic_filtering: true,
+                    anisotropic_filtering: capabilities.anisotropic_filtering,
                     ..wgpu::Extensions::default()
                 },
                 limits: wgpu::Limits::default(),
             })
             .await;
 
-        let swapchain_desc = wgpu::SwapChainDescriptor {
-            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
-            format: wgpu::TextureFormat::Bgra8Unorm,
-            width: size.width,
-            height: size.height,
-            present_mode: wgpu::PresentMode::Mailbox,
+        let output = match surface {
+            Some(surface) => RenderOutput::Window {
+                swapchain: device
+                    .create_swap_chain(&surface, &Self::swapchain_descriptor(size, present_mode)),
+                surface,
+            },
+            None => RenderOutput::Offscreen(Self::create_offscreen_target(&device, size)),
         };
 
-        let swapchain = device.create_swap_chain(&surface, &swapchain_desc);
+        let dynamic_resolution = DynamicResolution::default();
+        let settings = SceneSettings::resolve(config, &capabilities);
+        let depth_order = DepthOrder::from_reversed(reverse_z);
+        let scene_target = RenderTarget::new(
+            &device,
+            capabilities.fit_texture_size(dynamic_resolution.max_scaled_size(size)),
+            settings.sample_count,
+            depth_order,
+        );
 
-        let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Main depth texture"),
-            size: wgpu::Extent3d {
-                width: size.width,
-                height: size.height,
-                depth: 1,
+        let light_buffer_kind = LightBufferKind::for_backend(adapter_info.backend);
+        let mut resource_cache = ResourceCache::new();
+        let shadow_render_stage =
+            ShadowRenderStage::new(&device, &mut resource_cache, shadow_map_size).await?;
+        let SceneStages {
+            ssao: ssao_render_stage,
+            oit: oit_render_stage,
+            outline: outline_render_stage,
+            terrain: terrain_render_stage,
+            decal: decal_render_stage,
+            water: water_render_stage,
+            skybox: skybox_render_stage,
+            debug_lines: debug_lines_stage,
+            depth_of_field: depth_of_field_stage,
+            motion_blur: motion_blur_stage,
+        } = SceneStages::new(
+            &device,
+            &queue,
+            &mut resource_cache,
+            &settings,
+            transparency,
+            &scene_target,
+        )
+        .await?;
+        let white = image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4]));
+        let unoccluded_texture = GpuMaterial::upload_texture(
+            &white,
+            wgpu::TextureFormat::Rgba8Unorm,
+            "Unoccluded texture",
+            &device,
+            &mut queue,
+        )?;
+        let forward_render_stage = ForwardRenderStage::new(
+            &device,
+            &mut resource_cache,
+            light_buffer_kind,
+            &shadow_render_stage.view,
+            &match &ssao_render_stage {
+                Some(ssao) => ssao.output.texture.create_default_view(),
+                None => unoccluded_texture.create_default_view(),
             },
-            array_layer_count: 1,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Depth32Float,
-            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT
-                | wgpu::TextureUsage::SAMPLED
-                | wgpu::TextureUsage::COPY_SRC,
-        });
+            &unoccluded_texture.create_default_view(),
+            settings.sample_count,
+            depth_order,
+            settings.depth_prepass,
+            gpu_culling,
+            transparency == TransparencyMode::WeightedBlended,
+            settings.aa_mode == AaMode::Taa || settings.motion_blur.is_some(),
+            &queue,
+        )
+        .await?;
+        let deferred_render_stage = match render_path {
+            RenderPath::Forward => None,
+            RenderPath::Deferred => Some(
+                DeferredRenderStage::new(
+                    &device,
+                    &mut resource_cache,
+                    &forward_render_stage,
+                    &scene_target,
+                )
+                .await?,
+            ),
+        };
+        let post_process_stage =
+            PostProcessStage::new(&device, &mut resource_cache, &scene_target).await?;
+        let (fxaa_stage, taa_stage) =
+            Self::create_aa_stages(&device, &mut resource_cache, settings.aa_mode, &scene_target)
+                .await?;
+        let upscale_render_stage = UpscaleRenderStage::new(&device, &mut resource_cache).await?;
+        let sprite_overlay_render_stage =
+            SpriteOverlayRenderStage::new(&device, &mut resource_cache).await?;
+        let debug_gui_stage = DebugGuiStage::new(&device, &mut resource_cache).await?;
+        let text_render_stage =
+            TextRenderStage::new(&device, &queue, &mut resource_cache, ui_font).await?;
+
+        let composite_target = ColorTarget::new(
+            &device,
+            size,
+            ColorTarget::LDR_FORMAT,
+            "Composite color texture",
+        );
+        let output_render_stage =
+            OutputRenderStage::new(&device, &mut resource_cache, &composite_target).await?;
+        let frame_fences = FrameFences::new(&device);
 
-        let forward_render_stage = ForwardRenderStage::new(&device).await;
-        let sprite_overlay_render_stage = SpriteOverlayRenderStage::new(&device).await;
+        let shader_watcher = match ShaderWatcher::new(SHADER_DIR) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!("Shader hot reloading unavailable: {}", e);
+                None
+            }
+        };
 
-        Self {
+        Ok(Self {
             size,
-            surface,
+            output,
             adapter,
+            capabilities,
+            config: config.clone(),
+            scene_settings: settings,
             device,
             queue,
-            swapchain,
-            depth_texture,
+            present_mode,
+            scene_target,
+            dynamic_resolution,
+            upscale_filter: UpscaleFilter::default(),
+            tonemapper: Tonemapper::default(),
+            last_frame_start: None,
+            last_view_proj: None,
+            composite_target,
+            color_filter: ColorFilter::default(),
+            output_calibration: OutputCalibration::default(),
+            high_contrast_ui: false,
             next_model_id: ModelId(0),
             models: HashMap::new(),
+            next_material_id: MaterialId(0),
+            materials: HashMap::new(),
+            texture_streamer: texture_streaming.map(TextureStreamer::new),
             next_atlas_id: AtlasId(0),
             atlases: HashMap::new(),
+            pending_atlases: HashSet::new(),
+            view_targets: HashMap::new(),
+            next_skybox_id: SkyboxId(0),
+            skyboxes: HashMap::new(),
+            next_terrain_id: TerrainId(0),
+            terrains: HashMap::new(),
+            resource_cache,
+            compute_scheduler: ComputeScheduler::new(),
+            depth_readback: DepthReadback::default(),
+            staging_belt: RefCell::new(StagingBelt::default()),
+            draw_counter: DrawCounter::default(),
+            frame_stats: FrameStats::default(),
+            profiler: Profiler::default(),
+            frame_fences,
+            transient_pool: TransientPool::default(),
+            shader_watcher,
+            shadow_render_stage,
+            ssao_render_stage,
+            unoccluded_texture,
             forward_render_stage,
+            deferred_render_stage,
+            oit_render_stage,
+            outline_render_stage,
+            terrain_render_stage,
+            decal_render_stage,
+            water_render_stage,
+            skybox_render_stage,
+            debug_lines_stage,
+            depth_of_field_stage,
+            motion_blur_stage,
+            post_process_stage,
+            fxaa_stage,
+            taa_stage,
+            upscale_render_stage,
             sprite_overlay_render_stage,
+            debug_gui_stage,
+            text_render_stage,
+            output_render_stage,
+        })
+    }
+
+    /// Find an adapter that can draw to the surface, trying each of the preferred backends in
+    /// turn
+    async fn request_adapter(
+        surface: Option<&wgpu::Surface>,
+        backend: BackendPreference,
+    ) -> Option<wgpu::Adapter> {
+        let backend = BackendPreference::with_env_override(backend);
+        for (i, backends) in backend.candidates().into_iter().enumerate() {
+            let adapter = wgpu::Adapter::request(
+                &wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::Default,
+                    compatible_surface: surface,
+                },
+                backends,
+            )
+            .await;
+            match adapter {
+                Some(adapter) => return Some(adapter),
+                None if i == 0 && backend != BackendPreference::Auto => {
+                    warn!("No {:?} adapter available, falling back", backend);
+                }
+                None => (),
+            }
         }
+        None
     }
 
-    pub fn aspect_ratio(&self) -> f32 {
-        self.size.width as f32 / self.size.height as f32
+    /// Details of the GPU and graphics API being drawn with
+    #[allow(unused)]
+    pub fn adapter_info(&self) -> wgpu::AdapterInfo {
+        self.adapter.get_info()
     }
 
-    pub fn upload_model(&mut self, data: ModelData) -> ModelId {
-        let new_gpu_model = GpuModel::from_data(
-            &data,
-            &self.device,
-            &mut self.queue,
-        );
-        let new_model_id = self.next_model_id;
+    /// What the GPU being drawn with can do
+    #[allow(unused)]
+    pub fn capabilities(&self) -> &AdapterCapabilities {
+        &self.capabilities
+    }
 
-        // Create and cache any bind groups specific to this model
-        self.forward_render_stage.add_model(&self.device, new_model_id, &new_gpu_model);
+    fn swapchain_descriptor(
+        size: winit::dpi::PhysicalSize<u32>,
+        present_mode: PresentMode,
+    ) -> wgpu::SwapChainDescriptor {
+        wgpu::SwapChainDescriptor {
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            format: ColorTarget::LDR_FORMAT,
+            width: size.width,
+            height: size.height,
+            present_mode: present_mode.to_wgpu(),
+        }
+    }
 
-        self.models.insert(new_model_id, new_gpu_model);
-        self.next_model_id = ModelId(self.next_model_id.0 + 1);
+    /// Recreate the swapchain, or the offscreen target when there's no window, at the current
+    /// size and present mode
+    fn recreate_swapchain(&mut self) {
+        match &mut self.output {
+            RenderOutput::Window { surface, swapchain } => {
+                *swapchain = self.device.create_swap_chain(
+                    surface,
+                    &Self::swapchain_descriptor(self.size, self.present_mode),
+                );
+            }
+            RenderOutput::Offscreen(target) => {
+                *target = Self::create_offscreen_target(&self.device, self.size);
+            }
+        }
+    }
 
-        new_model_id
+    /// Change how frames are presented, recreating the swapchain to match
+    pub fn set_present_mode(&mut self, present_mode: PresentMode) {
+        if present_mode != self.present_mode {
+            self.present_mode = present_mode;
+            self.recreate_swapchain();
+        }
     }
 
-    pub fn upload_atlas(&mut self, data: image::RgbaImage) -> AtlasId {
-        let new_gpu_atlas = GpuAtlas::new(
-            data,
-            &self.device,
-            &mut self.queue,
-        );
-        let new_atlas_id = self.next_atlas_id;
+    pub fn aspect_ratio(&self) -> f32 {
+        self.size.width as f32 / self.size.height as f32
+    }
 
-        self.sprite_overlay_render_stage.add_atlas(&self.device, new_atlas_id, &new_gpu_atlas);
+    /// Recreate the swapchain and every output sized resource for a new output size
+    pub fn resize(&mut self, size: winit::dpi::PhysicalSize<u32>) {
+        if size == self.size || size.width == 0 || size.height == 0 {
+            return;
+        }
+        self.size = size;
 
-        self.atlases.insert(new_atlas_id, new_gpu_atlas);
-        self.next_atlas_id = AtlasId(self.next_atlas_id.0 + 1);
+        self.recreate_swapchain();
 
-        new_atlas_id
-    }
+        self.set_scene_target(RenderTarget::new(
+            &self.device,
+            self.capabilities.fit_texture_size(self.dynamic_resolution.max_scaled_size(size)),
+            self.scene_target.sample_count,
+            self.scene_target.depth_order,
+        ));
 
-    pub fn draw_frame(&mut self, frame_packet: &FramePacket) {
-        let frame = match self.swapchain.get_next_texture() {
-            Ok(frame) => frame,
-            Err(e) => panic!("Failed to get next swapchain frame: {:?}", e),
+        self.composite_target = ColorTarget::new(
+            &self.device,
+            size,
+            ColorTarget::LDR_FORMAT,
+            "Composite color texture",
+        );
+        self.output_render_stage.set_source(&self.device, &self.composite_target);
+    }
+
+    /// Controls the internal resolution the 3D scene is rendered at
+    #[allow(unused)]
+    pub fn dynamic_resolution_mut(&mut self) -> &mut DynamicResolution {
+        &mut self.dynamic_resolution
+    }
+
+    /// Set the resolution the scene is rendered at, as a fraction of the output resolution.
+    ///
+    /// Values above 1.0 supersample the scene and filter it back down to the output resolution.
+    /// When dynamic resolution is enabled this is the upper bound that it will scale up to.
+    pub fn set_render_scale(&mut self, scale: f32) {
+        let scale = scale
+            .max(self.dynamic_resolution.min_scale)
+            .min(dynamic_resolution::MAX_RENDER_SCALE);
+        self.dynamic_resolution.max_scale = scale;
+
+        let target_size =
+            self.capabilities.fit_texture_size(self.dynamic_resolution.max_scaled_size(self.size));
+        if target_size != self.scene_target.size {
+            self.set_scene_target(RenderTarget::new(
+                &self.device,
+                target_size,
+                self.scene_target.sample_count,
+                self.scene_target.depth_order,
+            ));
+        }
+    }
+
+    /// Replace the scene render target, rebinding every stage that reads or writes it along with
+    /// anything downstream of those
+    fn set_scene_target(&mut self, scene_target: RenderTarget) {
+        self.scene_target = scene_target;
+        if let Some(deferred) = &mut self.deferred_render_stage {
+            deferred.set_target(&self.device, &self.scene_target);
+        }
+        if let Some(oit) = &mut self.oit_render_stage {
+            oit.set_target(&self.device, &self.scene_target);
+        }
+        self.outline_render_stage.set_target(&self.device, &self.scene_target);
+        if let Some(water) = &mut self.water_render_stage {
+            water.set_target(&self.device, &self.scene_target);
+        }
+        if let Some(ssao) = &mut self.ssao_render_stage {
+            ssao.set_target(&self.device, &self.scene_target);
+        }
+        self.rebind_forward_inputs();
+        self.post_process_stage.set_source(&self.device, &self.scene_target);
+    }
+
+    /// Rebind the shadow map and ambient occlusion that the forward stage lights with, after
+    /// either has been reallocated or SSAO has been turned on or off
+    fn rebind_forward_inputs(&mut self) {
+        let unoccluded = self.unoccluded_texture.create_default_view();
+        let occlusion = match &self.ssao_render_stage {
+            Some(ssao) => &ssao.output.view,
+            None => &unoccluded,
+        };
+        self.forward_render_stage.set_inputs(
+            &self.device,
+            &self.shadow_render_stage.view,
+            occlusion,
+            &unoccluded,
+        );
+    }
+
+    /// Create whichever of the FXAA and TAA stages `aa_mode` draws with
+    async fn create_aa_stages(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        aa_mode: AaMode,
+        scene_target: &RenderTarget,
+    ) -> Result<(Option<FxaaStage>, Option<TaaStage>)> {
+        Ok(match aa_mode {
+            AaMode::Msaa => (None, None),
+            AaMode::Fxaa => (Some(FxaaStage::new(device, resources).await?), None),
+            AaMode::Taa => (None, Some(TaaStage::new(device, resources, scene_target).await?)),
+        })
+    }
+
+    /// Recreate the scene target, and every stage that depends on the scene settings, for new
+    /// settings
+    async fn rebuild_scene_stages(&mut self, settings: SceneSettings) -> Result<()> {
+        let scene_target = RenderTarget::new(
+            &self.device,
+            self.scene_target.size,
+            settings.sample_count,
+            self.scene_target.depth_order,
+        );
+        let stages = SceneStages::new(
+            &self.device,
+            &self.queue,
+            &mut self.resource_cache,
+            &settings,
+            self.config.transparency,
+            &scene_target,
+        )
+        .await?;
+        self.forward_render_stage.reconfigure(
+            &self.device,
+            &mut self.resource_cache,
+            settings.sample_count,
+            settings.depth_prepass,
+            settings.aa_mode == AaMode::Taa || settings.motion_blur.is_some(),
+        )?;
+        if settings.aa_mode != self.scene_settings.aa_mode {
+            let (fxaa, taa) = Self::create_aa_stages(
+                &self.device,
+                &mut self.resource_cache,
+                settings.aa_mode,
+                &scene_target,
+            )
+            .await?;
+            self.fxaa_stage = fxaa;
+            self.taa_stage = taa;
+        }
+
+        self.ssao_render_stage = stages.ssao;
+        self.oit_render_stage = stages.oit;
+        self.outline_render_stage = stages.outline;
+        self.terrain_render_stage = stages.terrain;
+        self.decal_render_stage = stages.decal;
+        self.water_render_stage = stages.water;
+        self.skybox_render_stage = stages.skybox;
+        self.debug_lines_stage = stages.debug_lines;
+        self.depth_of_field_stage = stages.depth_of_field;
+        self.motion_blur_stage = stages.motion_blur;
+        self.scene_settings = settings;
+
+        // The new stages know nothing of what was uploaded to the old ones
+        for (&skybox_id, skybox) in &self.skyboxes {
+            self.skybox_render_stage.add_skybox(&self.device, skybox_id, skybox);
+        }
+        if let Some(decals) = &mut self.decal_render_stage {
+            for (&atlas_id, atlas) in &self.atlases {
+                decals.add_atlas(&self.device, atlas_id, &atlas.view);
+            }
+        }
+        for view_target in self.view_targets.values_mut() {
+            view_target.scene = RenderTarget::new(
+                &self.device,
+                view_target.scene.size,
+                settings.sample_count,
+                view_target.scene.depth_order,
+            );
+            view_target.post_process_bind_group =
+                self.post_process_stage.bind_other(&self.device, &view_target.scene);
+        }
+
+        // Motion vectors may not have been drawn last frame
+        self.last_view_proj = None;
+        self.set_scene_target(scene_target);
+        Ok(())
+    }
+
+    /// Set how the HDR scene is mapped to the displayable range
+    pub fn set_tonemapper(&mut self, tonemapper: Tonemapper) {
+        self.tonemapper = tonemapper;
+    }
+
+    /// Set how the scene is upscaled when rendered at a reduced internal resolution
+    pub fn set_upscale_filter(&mut self, filter: UpscaleFilter) {
+        self.upscale_filter = filter;
+    }
+
+    /// Set the accessibility color filter applied to the final image
+    pub fn set_color_filter(&mut self, filter: ColorFilter) {
+        self.color_filter = filter;
+    }
+
+    /// Set the exposure/brightness/gamma adjustments applied to the final image
+    pub fn set_output_calibration(&mut self, calibration: OutputCalibration) {
+        self.output_calibration = calibration;
+    }
+
+    /// Physical pixel width of a single line of UI text drawn at a pixel height of `size`
+    pub fn text_width(&self, text: &str, size: f32) -> f32 {
+        self.text_render_stage.text_width(text, size)
+    }
+
+    /// Enable or disable the high contrast mode for the UI overlay
+    pub fn set_high_contrast_ui(&mut self, enabled: bool) {
+        self.high_contrast_ui = enabled;
+    }
+
+    /// Draw every model as the edges of its triangles rather than filled in
+    ///
+    /// Only the forward render path supports this, as wgpu has no polygon fill modes to switch
+    /// the G-buffer pass over with.
+    pub fn set_wireframe(&mut self, enabled: bool) -> Result<()> {
+        if enabled && self.deferred_render_stage.is_some() {
+            warn!("Wireframe isn't supported by the deferred render path");
+        }
+        self.forward_render_stage.set_wireframe(&self.device, &mut self.resource_cache, enabled)
+    }
+
+    /// Persist which shader permutations were used, so they can be prepared up front next run
+    pub fn save_pipeline_cache(&mut self) {
+        self.forward_render_stage.pipeline_cache.save();
+    }
+
+    /// Apply every renderer side setting of a graphics quality level in one go
+    ///
+    /// Changing the MSAA samples or post effects recreates the scene target along with every
+    /// stage drawing to it, and changing the shadow map size reallocates the shadow map.
+    pub fn apply_quality(&mut self, quality: &RenderQuality) -> Result<()> {
+        self.dynamic_resolution.enabled = quality.dynamic_resolution;
+        self.set_render_scale(quality.render_scale);
+        self.set_upscale_filter(quality.upscale_filter);
+        self.forward_render_stage.set_texture_filter(
+            &self.device,
+            &mut self.resource_cache,
+            &self.materials,
+            quality.texture_filter,
+        );
+        self.forward_render_stage.set_anisotropy(quality.anisotropy);
+
+        if quality.shadow_map_size != self.shadow_render_stage.size {
+            self.shadow_render_stage.set_size(&self.device, quality.shadow_map_size);
+            self.rebind_forward_inputs();
+        }
+
+        let config = RendererConfig {
+            aa_mode: quality.aa_mode,
+            msaa_samples: quality.msaa_samples,
+            ssao: quality.ssao,
+            motion_blur: quality.motion_blur,
+            shadow_map_size: quality.shadow_map_size,
+            ..self.config.clone()
+        };
+        let settings = SceneSettings::resolve(&config, &self.capabilities);
+        self.config = config;
+        if settings != self.scene_settings {
+            // Only the shaders are loaded asynchronously, from files that are already there
+            block_on::block_on(self.rebuild_scene_stages(settings))?;
+        }
+        Ok(())
+    }
+
+    /// Register simulation work to be dispatched at the start of every frame
+    #[allow(unused)]
+    pub fn add_compute_workload(&mut self, workload: Box<dyn ComputeWorkload>) {
+        self.compute_scheduler.add(workload);
+    }
+
+    /// Compile a compute shader and create the layouts it binds, for use in a `ComputeWorkload`
+    ///
+    /// Pipelines are shared through the renderer's resource cache, so passes created with the
+    /// same shader and bindings are cheap.
+    #[allow(unused)]
+    pub async fn create_compute_pass(&mut self, desc: &ComputePassDesc<'_>) -> Result<ComputePass> {
+        ComputePass::new(&self.device, &mut self.resource_cache, desc).await
+    }
+
+    /// For creating the buffers and bind groups that compute workloads use
+    #[allow(unused)]
+    pub fn device(&self) -> &wgpu::Device {
+        &self.device
+    }
+
+    /// Read back the scene depth at the given output pixel once the next frame has been drawn
+    ///
+    /// This never stalls the GPU, so the result takes a frame or two to become available. The
+    /// multisampled depth buffer can't be read back, so with MSAA enabled it never does.
+    #[allow(unused)]
+    pub fn sample_depth(&mut self, x: u32, y: u32) -> DepthSample {
+        self.depth_readback.request(winit::dpi::PhysicalPosition { x, y })
+    }
+
+    /// Upload a model along with its material, which it's drawn with by default
+    pub fn upload_model(&mut self, data: ModelData) -> Result<ModelId> {
+        let material_id = self.upload_material(&data.material)?;
+        let new_gpu_model = GpuModel::from_data(&data, &self.device, material_id)?;
+        let new_model_id = self.next_model_id;
+
+        // Prepare the pipeline for the model's own material up front, rather than on first draw
+        let features = new_gpu_model.features_with(&self.materials[&material_id]);
+        self.forward_render_stage.ensure_pipeline(
+            &self.device,
+            &mut self.resource_cache,
+            (features, new_gpu_model.indices.format),
+        )?;
+
+        self.models.insert(new_model_id, new_gpu_model);
+        self.next_model_id = ModelId(self.next_model_id.0 + 1);
+
+        Ok(new_model_id)
+    }
+
+    /// Upload a material that any model can be drawn with, by giving its id along with the
+    /// model's in the frame packet
+    pub fn upload_material(&mut self, data: &MaterialData) -> Result<MaterialId> {
+        let new_material_id = self.next_material_id;
+        let streaming = self.texture_streamer.as_mut().map(|s| (s, new_material_id));
+        let new_gpu_material =
+            GpuMaterial::from_data(data, &self.device, &mut self.queue, streaming)?;
+
+        // Create and cache any bind groups specific to this material
+        self.forward_render_stage.add_material(&self.device, new_material_id, &new_gpu_material);
+
+        self.materials.insert(new_material_id, new_gpu_material);
+        self.next_material_id = MaterialId(self.next_material_id.0 + 1);
+
+        Ok(new_material_id)
+    }
+
+    /// Upload several meshes of the same model, each paired with the smallest fraction of the
+    /// screen's height it's drawn at, from the most detailed down
+    pub fn upload_model_lods(&mut self, lods: Vec<(ModelData, f32)>) -> Result<LodModel> {
+        let bounding_radius = lod::bounding_radius(
+            lods.iter()
+                .flat_map(|(data, _)| data.vertices.iter().map(|vertex| vertex.position)),
+        );
+        let levels = lods
+            .into_iter()
+            .map(|(data, min_screen_size)| {
+                Ok(LodLevel {
+                    model_id: self.upload_model(data)?,
+                    min_screen_size,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        LodModel::new(levels, bounding_radius)
+    }
+
+    /// Upload every primitive in a scene, returning where each should be drawn relative to the
+    /// scene's origin
+    ///
+    /// The meshes are taken out of the scene, leaving the nodes, skins and animations for
+    /// posing the returned models.
+    pub fn upload_scene(&mut self, scene: &mut SceneData) -> Result<Vec<SceneModel>> {
+        let instances = scene.mesh_instances();
+
+        let mesh_models: Vec<Vec<(ModelId, Rc<PickMesh>)>> = std::mem::take(&mut scene.meshes)
+            .into_iter()
+            .map(|mesh| {
+                mesh.primitives
+                    .into_iter()
+                    .map(|primitive| {
+                        let pick_mesh = Rc::new(PickMesh::new(&primitive));
+                        Ok((self.upload_model(primitive)?, pick_mesh))
+                    })
+                    .collect()
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(instances
+            .into_iter()
+            .flat_map(|instance| {
+                let skin = scene.nodes[instance.node].skin;
+                mesh_models[instance.mesh].iter().map(move |(model_id, pick_mesh)| SceneModel {
+                    model_id: *model_id,
+                    pick_mesh: pick_mesh.clone(),
+                    node: instance.node,
+                    skin,
+                    transform: instance.transform,
+                })
+            })
+            .collect())
+    }
+
+    pub fn upload_atlas(&mut self, data: image::RgbaImage) -> Result<AtlasId> {
+        let new_atlas_id = self.reserve_atlas_id();
+        self.fill_atlas(new_atlas_id, data)?;
+        Ok(new_atlas_id)
+    }
+
+    /// Create a handle for an atlas whose data isn't available yet
+    ///
+    /// Sprites using the atlas can be put in frame packets straight away, they're just skipped
+    /// until the data is given to `fill_atlas`.
+    pub fn reserve_atlas_id(&mut self) -> AtlasId {
+        let new_atlas_id = self.next_atlas_id;
+        self.next_atlas_id = AtlasId(self.next_atlas_id.0 + 1);
+        self.pending_atlases.insert(new_atlas_id);
+        new_atlas_id
+    }
+
+    /// Upload the data for an atlas reserved with `reserve_atlas_id`
+    pub fn fill_atlas(&mut self, atlas_id: AtlasId, data: image::RgbaImage) -> Result<()> {
+        if !self.pending_atlases.contains(&atlas_id) {
+            return Err(Error::InvalidAsset("Atlas was never reserved, or is already filled"));
+        }
+        if data.width() == 0 || data.height() == 0 {
+            return Err(Error::InvalidAsset("Sprite atlas is empty"));
+        }
+
+        let new_gpu_atlas = GpuAtlas::new(
+            data,
+            &self.device,
+            &mut self.queue,
+        );
+
+        self.sprite_overlay_render_stage.add_atlas(&self.device, atlas_id, &new_gpu_atlas.view);
+        if let Some(decals) = &mut self.decal_render_stage {
+            decals.add_atlas(&self.device, atlas_id, &new_gpu_atlas.view);
+        }
+
+        self.pending_atlases.remove(&atlas_id);
+        self.atlases.insert(atlas_id, new_gpu_atlas);
+
+        Ok(())
+    }
+
+    /// Create a target for a `FramePacketView` to be drawn in to, which sprites can then show
+    /// like any other atlas
+    pub fn create_render_target(
+        &mut self,
+        size: winit::dpi::PhysicalSize<u32>,
+    ) -> Result<AtlasId> {
+        if size.width == 0 || size.height == 0 {
+            return Err(Error::InvalidAsset("Render target is empty"));
+        }
+
+        let new_atlas_id = self.next_atlas_id;
+        self.next_atlas_id = AtlasId(self.next_atlas_id.0 + 1);
+
+        let scene = RenderTarget::new(
+            &self.device,
+            size,
+            self.scene_target.sample_count,
+            self.scene_target.depth_order,
+        );
+        let post_process_bind_group = self.post_process_stage.bind_other(&self.device, &scene);
+        let output = ColorTarget::new(
+            &self.device,
+            size,
+            PostProcessStage::OUTPUT_FORMAT,
+            "View target color texture",
+        );
+        self.sprite_overlay_render_stage.add_atlas(&self.device, new_atlas_id, &output.view);
+
+        self.view_targets.insert(new_atlas_id, ViewTarget {
+            scene,
+            post_process_bind_group,
+            output,
+        });
+
+        Ok(new_atlas_id)
+    }
+
+    /// Upload the six faces of a cubemap to draw behind the scene, ordered +X, -X, +Y, -Y, +Z, -Z
+    ///
+    /// The scene's ambient lighting is also worked out from it, for frames that it's drawn in.
+    pub fn upload_skybox(&mut self, faces: [image::RgbaImage; 6]) -> Result<SkyboxId> {
+        let new_gpu_skybox = GpuSkybox::new(&faces, &self.device, &self.queue)?;
+        let new_skybox_id = self.next_skybox_id;
+
+        self.skybox_render_stage.add_skybox(&self.device, new_skybox_id, &new_gpu_skybox);
+        self.forward_render_stage.environment.add_skybox(
+            &self.device,
+            &self.queue,
+            new_skybox_id,
+            &new_gpu_skybox,
+        );
+
+        self.skyboxes.insert(new_skybox_id, new_gpu_skybox);
+        self.next_skybox_id = SkyboxId(self.next_skybox_id.0 + 1);
+
+        Ok(new_skybox_id)
+    }
+
+    /// Upload a terrain built from a `terrain::Heightmap`, to draw in frames that refer to it
+    pub fn upload_terrain(&mut self, data: TerrainData) -> Result<TerrainId> {
+        let new_gpu_terrain = self.terrain_render_stage.upload(&self.device, &self.queue, &data)?;
+        let new_terrain_id = self.next_terrain_id;
+
+        self.terrains.insert(new_terrain_id, new_gpu_terrain);
+        self.next_terrain_id = TerrainId(self.next_terrain_id.0 + 1);
+
+        Ok(new_terrain_id)
+    }
+
+    /// Rebuild the pipelines of any shaders that have been edited since the last frame
+    ///
+    /// A shader that fails to compile is reported and the stage keeps drawing with its previous
+    /// pipeline, so that a typo doesn't take down the whole app.
+    fn reload_changed_shaders(&mut self) {
+        let changed = match &self.shader_watcher {
+            Some(watcher) => watcher.changed_files(),
+            None => return,
         };
+        if changed.is_empty() {
+            return;
+        }
+
+        let forward = &mut self.forward_render_stage;
+        if let Err(e) = forward.reload_shaders(&self.device, &mut self.resource_cache, &changed) {
+            warn!("Failed to reload forward shaders: {}", e);
+        }
+        let sprite_overlay = &mut self.sprite_overlay_render_stage;
+        if let Err(e) =
+            sprite_overlay.reload_shaders(&self.device, &mut self.resource_cache, &changed)
+        {
+            warn!("Failed to reload sprite shaders: {}", e);
+        }
+
+        // Pipelines of the shaders' previous versions won't be asked for again
+        self.resource_cache.release_unused_pipelines();
+    }
+
+    /// Statistics about the last frame drawn
+    pub fn frame_stats(&self) -> &FrameStats {
+        &self.frame_stats
+    }
+
+    /// The time spent in each part of the last frame drawn, or None before the first
+    #[allow(unused)]
+    pub fn last_frame_profile(&self) -> Option<&FrameProfile> {
+        self.profiler.last_frame()
+    }
+
+    /// The time spent in each part of a frame, averaged over the last couple of seconds
+    pub fn rolling_frame_profile(&self) -> FrameProfile {
+        self.profiler.rolling_average()
+    }
+
+    /// Time some work done outside of the renderer, eg. ticking the app, along with the next
+    /// frame
+    pub fn record_cpu_scope(&mut self, name: &'static str, time: Duration) {
+        self.profiler.record_cpu(name, time);
+    }
+
+    pub fn draw_frame(&mut self, frame_packet: &FramePacket) -> Result<()> {
+        self.reload_changed_shaders();
+
+        let now = Instant::now();
+        let frame_time = match self.last_frame_start {
+            Some(last_frame_start) => now - last_frame_start,
+            None => Duration::ZERO,
+        };
+        if self.last_frame_start.is_some() {
+            self.dynamic_resolution.record_frame_time(frame_time);
+        }
+        self.last_frame_start = Some(now);
+        let mut timer = StageTimer::start();
+
+        // Nothing for this frame can be written until the GPU is done with the earlier frame that
+        // used the same copies of the per-frame resources
+        let frame_slot = self.frame_fences.begin_frame(&self.device);
+        timer.lap("frame wait");
+
+        self.depth_readback.poll(&self.device);
+        self.staging_belt.get_mut().poll(&self.device);
+
+        // Get the simulation work queued before potentially blocking on the swapchain
+        self.compute_scheduler.submit(&self.device, &self.queue);
+        timer.lap("compute");
+
+        // None when drawing offscreen, in which case there's nothing to acquire
+        let frame = match &mut self.output {
+            RenderOutput::Window { swapchain, .. } => match swapchain.get_next_texture() {
+                Ok(frame) => Some(frame),
+                Err(_) => {
+                    // Most likely the swapchain no longer matches the window, eg. because of a
+                    // resize that hasn't been reported yet. Recreate it and try again next frame.
+                    self.recreate_swapchain();
+                    return Ok(());
+                }
+            },
+            RenderOutput::Offscreen(_) => None,
+        };
+        timer.lap("acquire");
 
         let mut encoder = self
             .device
@@ -293,22 +1891,765 @@ impl Renderer {
                 label: Some("Per frame encoder"),
             });
 
-        self.forward_render_stage.draw_frame(
-            self,
+        self.forward_render_stage.prepare_pipelines(
+            &self.device,
+            &mut self.resource_cache,
+            &self.models,
+            &self.materials,
             frame_packet,
-            &mut encoder,
-            &frame.view,
-            &self.depth_texture.create_default_view(),
+        )?;
+        let motion_frame = self.begin_motion_frame(frame_packet);
+        if let Some(streamer) = &mut self.texture_streamer {
+            let changed = streamer.update(
+                &self.device,
+                &mut encoder,
+                frame_packet,
+                &self.models,
+                &mut self.materials,
+                self.size.height,
+            );
+            for material_id in changed {
+                let material = &self.materials[&material_id];
+                self.forward_render_stage.add_material(&self.device, material_id, material);
+            }
+        }
+        {
+            let staging_belt = self.staging_belt.get_mut();
+            self.forward_render_stage.update(
+                &self.device,
+                staging_belt,
+                &mut encoder,
+                frame_slot,
+                &self.models,
+                frame_packet,
+                frame_packet.view,
+                motion_frame,
+            );
+            if let Some(decals) = &mut self.decal_render_stage {
+                let decal_sets = &frame_packet.decals;
+                decals.update(&self.device, staging_belt, &mut encoder, frame_slot, decal_sets);
+            }
+            self.debug_lines_stage.update(
+                &self.device,
+                staging_belt,
+                &mut encoder,
+                frame_slot,
+                frame_packet,
+            );
+            self.sprite_overlay_render_stage.update(
+                &self.device,
+                staging_belt,
+                &mut encoder,
+                frame_slot,
+                &frame_packet.overlay_sprites,
+                self.size,
+                frame_packet.ui_scale,
+            );
+            self.debug_gui_stage.update(
+                &self.device,
+                staging_belt,
+                &mut encoder,
+                frame_slot,
+                &frame_packet.debug_gui,
+                self.size,
+                frame_packet.ui_scale,
+            );
+            self.text_render_stage.update(
+                &self.device,
+                staging_belt,
+                &mut encoder,
+                frame_slot,
+                &frame_packet.overlay_text,
+                self.size,
+            );
+        }
+        timer.lap("upload");
+
+        let prepassed = Cell::new(false);
+        let depth_copies = Cell::new(Vec::new());
+        let mut transient_pool = std::mem::take(&mut self.transient_pool);
+        let graph = self
+            .frame_graph(frame_packet, motion_frame, frame.as_ref(), &prepassed, &depth_copies)
+            .compile(&self.device, &mut transient_pool);
+        let result = graph.execute(self, &transient_pool, &mut encoder, |pass| timer.lap(pass));
+        self.transient_pool = transient_pool;
+        result?;
+
+        self.frame_fences.signal(&mut encoder);
+        self.staging_belt.get_mut().finish();
+        self.queue.submit(&[encoder.finish()]);
+        self.frame_fences.submitted();
+        self.staging_belt.get_mut().recall();
+        self.depth_readback.map_copies(depth_copies.take());
+        timer.lap("submit");
+
+        let (draw_calls, instances) = self.draw_counter.take();
+        let stage_timings = timer.finish();
+        self.profiler.finish_frame(&stage_timings);
+        self.frame_stats = FrameStats {
+            frame_time,
+            stage_timings,
+            draw_calls,
+            instances,
+        };
+
+        Ok(())
+    }
+
+    /// Set up the frame about to be drawn for motion vectors, if TAA or motion blur need them
+    ///
+    /// Split views are drawn without them, as their regions of the scene target don't share the
+    /// one camera that motion is measured against. The frame after one of those starts over.
+    fn begin_motion_frame(&mut self, frame_packet: &FramePacket) -> Option<MotionFrame> {
+        let wanted = self.taa_stage.is_some() || self.motion_blur_stage.is_some();
+        if !wanted || !frame_packet.split_views.is_empty() {
+            if let Some(taa) = &mut self.taa_stage {
+                taa.skip_frame();
+            }
+            self.last_view_proj = None;
+            self.forward_render_stage.instance_history.clear();
+            return None;
+        }
+
+        let scene_size = self.scene_size();
+        let jitter = match &mut self.taa_stage {
+            Some(taa) => taa.begin_frame(&self.device, &self.scene_target, scene_size),
+            None => cgmath::Vector2::zero(),
+        };
+        let view_proj = frame_packet.proj * frame_packet.view;
+        let prev_view_proj = self.last_view_proj.replace(view_proj).unwrap_or(view_proj);
+        Some(MotionFrame {
+            jitter,
+            view_proj,
+            prev_view_proj,
+        })
+    }
+
+    /// The region of the scene target that the scene is drawn in to this frame
+    fn scene_size(&self) -> winit::dpi::PhysicalSize<u32> {
+        // Clamp in case the scale bounds were changed without reallocating the render target
+        let scene_size = self.dynamic_resolution.scaled_size(self.size);
+        winit::dpi::PhysicalSize {
+            width: scene_size.width.min(self.scene_target.size.width),
+            height: scene_size.height.min(self.scene_target.size.height),
+        }
+    }
+
+    /// Every pass of a frame, in order
+    ///
+    /// `motion_frame` is from `begin_motion_frame`. `prepassed` is set by the depth prepass for
+    /// the passes after it, and `depth_copies` ends up with the depth readback copies to map once
+    /// the frame has been submitted.
+    fn frame_graph<'a>(
+        &self,
+        frame_packet: &'a FramePacket,
+        motion_frame: Option<MotionFrame>,
+        frame: Option<&'a wgpu::SwapChainOutput>,
+        prepassed: &'a Cell<bool>,
+        depth_copies: &'a Cell<Vec<(wgpu::Buffer, DepthSample)>>,
+    ) -> RenderGraph<'a, Renderer> {
+        let scene_size = self.scene_size();
+        let viewport = Viewport::from_size(scene_size);
+
+        let mut graph = RenderGraph::<Renderer>::new();
+        let shadow_map = graph.import("Shadow map");
+        let scene = graph.import("Scene");
+        let occlusion = graph.import("Occlusion");
+        let view_targets = graph.import("View targets");
+        let water_targets = graph.import("Water targets");
+        let depth_readback = graph.import("Depth readback");
+        let tonemapped = graph.create(
+            "Tonemapped scene",
+            PostProcessStage::output_desc(&self.scene_target),
         );
+        let composite = graph.import("Composite");
+        let output = graph.import("Output");
 
-        self.sprite_overlay_render_stage.draw_frame(
-            self,
+        let motion_vectors =
+            graph.create("Motion vectors", motion_vectors::transient_desc(&self.scene_target));
+
+        graph.add_pass("shadow", &[], &[shadow_map], move |renderer, encoder, _| {
+            let instances = &renderer.forward_render_stage.instances;
+            renderer.shadow_render_stage.draw_frame(renderer, frame_packet, instances, encoder)
+        });
+
+        if frame_packet.split_views.is_empty() {
+            let water = frame_packet.water.filter(|_| self.water_render_stage.is_some());
+            if let Some(water) = water {
+                let outputs = [water_targets];
+                let inputs = [shadow_map];
+                graph.add_pass("water views", &inputs, &outputs, move |renderer, encoder, _| {
+                    let motion = motion_frame;
+                    renderer.draw_water_views(frame_packet, &water, motion, encoder, scene_size)
+                });
+            }
+            graph.add_pass("depth prepass", &[], &[scene], move |renderer, encoder, _| {
+                prepassed.set(renderer.forward_render_stage.draw_depth_prepass(
+                    renderer,
+                    frame_packet,
+                    encoder,
+                    &renderer.scene_target,
+                    scene_size,
+                )?);
+                Ok(())
+            });
+            if self.ssao_render_stage.is_some() {
+                graph.add_pass("ssao", &[scene], &[occlusion], move |renderer, encoder, _| {
+                    if let Some(ssao) = &renderer.ssao_render_stage {
+                        let prepassed = prepassed.get();
+                        ssao.draw_frame(renderer, frame_packet, scene_size, prepassed, encoder);
+                    }
+                    Ok(())
+                });
+            }
+            let lit_inputs = [scene, shadow_map, occlusion];
+            graph.add_pass("scene", &lit_inputs, &[scene], move |renderer, encoder, _| {
+                match &renderer.deferred_render_stage {
+                    Some(deferred) => {
+                        deferred.draw_frame(renderer, frame_packet, encoder, scene_size)
+                    }
+                    None => renderer.forward_render_stage.draw_frame(
+                        renderer,
+                        frame_packet,
+                        encoder,
+                        &renderer.scene_target,
+                        viewport,
+                        wgpu::LoadOp::Clear,
+                        if prepassed.get() { wgpu::LoadOp::Load } else { wgpu::LoadOp::Clear },
+                    ),
+                }
+            });
+            // Before the motion vectors, so that models hidden behind the terrain don't draw any
+            if let Some(terrain_id) = frame_packet.terrain {
+                graph.add_pass("terrain", &[scene], &[scene], move |renderer, encoder, _| {
+                    let terrain = renderer
+                        .terrains
+                        .get(&terrain_id)
+                        .ok_or(Error::InvalidFramePacket("Terrain with unknown id"))?;
+                    let proj = match motion_frame {
+                        Some(motion_frame) => motion_frame.jittered_proj(frame_packet.proj),
+                        None => frame_packet.proj,
+                    };
+                    renderer.terrain_render_stage.draw_frame(
+                        renderer,
+                        frame_packet,
+                        proj,
+                        terrain,
+                        encoder,
+                        &renderer.scene_target,
+                        viewport,
+                    );
+                    Ok(())
+                });
+            }
+            if motion_frame.is_some() {
+                let outputs = [motion_vectors];
+                graph.add_pass("motion vectors", &[scene], &outputs, move |renderer, encoder, t| {
+                    renderer.forward_render_stage.draw_motion_vectors(
+                        renderer,
+                        frame_packet,
+                        encoder,
+                        t.get(motion_vectors),
+                        viewport,
+                    )
+                });
+            }
+            if self.decal_render_stage.is_some() && !frame_packet.decals.is_empty() {
+                graph.add_pass("decals", &[scene], &[scene], move |renderer, encoder, _| {
+                    let stage =
+                        renderer.decal_render_stage.as_ref().expect("Only used when created");
+                    let jitter = motion_frame.map_or(cgmath::Vector2::zero(), |frame| frame.jitter);
+                    stage.draw_frame(renderer, frame_packet, jitter, encoder, scene_size)
+                });
+            }
+            graph.add_pass("skybox", &[scene], &[scene], move |renderer, encoder, _| {
+                let (skybox, target) = (&renderer.skybox_render_stage, &renderer.scene_target);
+                skybox.draw_frame(renderer, frame_packet, encoder, target, viewport)
+            });
+            if let Some(water) = water {
+                let inputs = [scene, water_targets];
+                graph.add_pass("water", &inputs, &[scene], move |renderer, encoder, _| {
+                    let stage =
+                        renderer.water_render_stage.as_ref().expect("Only used when created");
+                    let proj = match motion_frame {
+                        Some(motion_frame) => motion_frame.jittered_proj(frame_packet.proj),
+                        None => frame_packet.proj,
+                    };
+                    stage.draw_frame(renderer, frame_packet, &water, proj, encoder, scene_size)
+                });
+            }
+            graph.add_pass("transparent", &lit_inputs, &[scene], move |renderer, encoder, _| {
+                renderer.forward_render_stage.draw_transparent(
+                    renderer,
+                    frame_packet,
+                    encoder,
+                    &renderer.scene_target,
+                    viewport,
+                )
+            });
+            graph.add_pass("outlines", &[scene], &[scene], move |renderer, encoder, _| {
+                renderer.forward_render_stage.draw_outlines(
+                    renderer,
+                    &renderer.outline_render_stage,
+                    frame_packet,
+                    encoder,
+                    &renderer.scene_target,
+                    viewport,
+                )
+            });
+        } else {
+            // The split views aren't drawn in to the prepass, so there's no depth for SSAO either
+            if self.ssao_render_stage.is_some() {
+                graph.add_pass("ssao", &[], &[occlusion], move |renderer, encoder, _| {
+                    if let Some(ssao) = &renderer.ssao_render_stage {
+                        ssao.draw_frame(renderer, frame_packet, scene_size, false, encoder);
+                    }
+                    Ok(())
+                });
+            }
+            let inputs = [shadow_map, occlusion];
+            graph.add_pass("split views", &inputs, &[scene], move |renderer, encoder, _| {
+                renderer.draw_split_views(frame_packet, encoder, scene_size)
+            });
+        }
+
+        graph.add_pass("debug lines", &[scene], &[scene], move |renderer, encoder, _| {
+            let target = &renderer.scene_target;
+            renderer.debug_lines_stage.draw_frame(renderer, encoder, target, scene_size);
+            Ok(())
+        });
+
+        graph.add_pass("views", &[shadow_map], &[view_targets], move |renderer, encoder, _| {
+            renderer.draw_views(frame_packet, encoder)
+        });
+
+        graph.add_pass("depth readback", &[scene], &[depth_readback], move |renderer, encoder, _| {
+            if renderer.scene_target.sample_count == 1 {
+                depth_copies.set(renderer.depth_readback.record_copies(
+                    &renderer.device,
+                    encoder,
+                    &renderer.scene_target.depth_texture,
+                    scene_size,
+                    renderer.size,
+                ));
+            } else {
+                renderer.depth_readback.discard_pending();
+            }
+            Ok(())
+        });
+
+        // Depth of field is drawn over a copy of the scene, which is tonemapped in its place
+        let depth_of_field = frame_packet
+            .depth_of_field
+            .filter(|_| self.depth_of_field_stage.is_some() && frame_packet.split_views.is_empty());
+        let hdr_scene = match depth_of_field {
+            Some(depth_of_field) => {
+                let desc = DepthOfFieldStage::transient_desc(&self.scene_target);
+                let sharp = graph.create("Depth of field sharp", desc);
+                let horizontal = graph.create("Depth of field horizontal blur", desc);
+                let blurred = graph.create("Depth of field blurred", desc);
+                let focused = graph.create("Depth of field output", desc);
+                fn stage(renderer: &Renderer) -> &DepthOfFieldStage {
+                    renderer.depth_of_field_stage.as_ref().expect("Only used when created")
+                }
+
+                graph.add_pass("dof coc", &[scene], &[sharp], move |renderer, encoder, textures| {
+                    stage(renderer).draw_coc(
+                        renderer,
+                        &depth_of_field,
+                        frame_packet.proj,
+                        &renderer.scene_target,
+                        textures.get(sharp),
+                        scene_size,
+                        encoder,
+                    );
+                    Ok(())
+                });
+                let blur = [(false, sharp, horizontal), (true, horizontal, blurred)];
+                for (vertical, source, output) in blur {
+                    let name = if vertical { "dof vertical blur" } else { "dof horizontal blur" };
+                    graph.add_pass(name, &[source], &[output], move |renderer, encoder, textures| {
+                        let (source, output) = (textures.get(source), textures.get(output));
+                        let stage = stage(renderer);
+                        stage.draw_blur(renderer, vertical, source, output, scene_size, encoder);
+                        Ok(())
+                    });
+                }
+                let inputs = [sharp, blurred];
+                graph.add_pass("dof composite", &inputs, &[focused], move |renderer, encoder, t| {
+                    let (sharp, blurred, output) = (t.get(sharp), t.get(blurred), t.get(focused));
+                    let stage = stage(renderer);
+                    stage.draw_composite(renderer, sharp, blurred, output, scene_size, encoder);
+                    Ok(())
+                });
+                focused
+            }
+            None => scene,
+        };
+
+        let hdr_scene = match (motion_frame, &self.motion_blur_stage) {
+            (Some(motion_frame), Some(_)) => {
+                let blurred = graph.create(
+                    "Motion blurred scene",
+                    MotionBlurStage::transient_desc(&self.scene_target),
+                );
+                let inputs = [hdr_scene, motion_vectors, scene];
+                graph.add_pass("motion blur", &inputs, &[blurred], move |renderer, encoder, t| {
+                    let stage =
+                        renderer.motion_blur_stage.as_ref().expect("Only used when created");
+                    let source = if hdr_scene == scene {
+                        &renderer.scene_target.color_view
+                    } else {
+                        &t.get(hdr_scene).view
+                    };
+                    let (motion_vectors, output) = (t.get(motion_vectors), t.get(blurred));
+                    stage.draw_frame(
+                        renderer,
+                        &motion_frame,
+                        source,
+                        motion_vectors,
+                        output,
+                        scene_size,
+                        encoder,
+                    );
+                    Ok(())
+                });
+                blurred
+            }
+            _ => hdr_scene,
+        };
+
+        graph.add_pass("post process", &[hdr_scene], &[tonemapped], move |renderer, encoder, t| {
+            let (post_process, tonemapper) = (&renderer.post_process_stage, renderer.tonemapper);
+            let output = t.get(tonemapped);
+            if hdr_scene == scene {
+                post_process.draw_frame(renderer, tonemapper, output, scene_size, encoder);
+            } else {
+                let bind_group = post_process.bind_view(&renderer.device, &t.get(hdr_scene).view);
+                let region = scene_size;
+                post_process.draw_other(renderer, tonemapper, &bind_group, output, region, encoder);
+            }
+            Ok(())
+        });
+
+        // Anti-aliasing smooths the tonemapped scene in to whatever the upscale then reads
+        let taa_history = graph.import("TAA history");
+        let ldr_scene = if let (Some(motion_frame), Some(_)) = (motion_frame, &self.taa_stage) {
+            let inputs = [tonemapped, motion_vectors, scene];
+            graph.add_pass("taa", &inputs, &[taa_history], move |renderer, encoder, t| {
+                // Taken out for the duration, as resolving moves the stage on to the next history
+                let mut taa = renderer.taa_stage.take().expect("Only used when created");
+                let (current, motion_vectors) = (t.get(tonemapped), t.get(motion_vectors));
+                taa.draw_frame(renderer, &motion_frame, current, motion_vectors, encoder);
+                renderer.taa_stage = Some(taa);
+                Ok(())
+            });
+            taa_history
+        } else if self.fxaa_stage.is_some() {
+            let antialiased = graph.create(
+                "Anti-aliased scene",
+                PostProcessStage::output_desc(&self.scene_target),
+            );
+            graph.add_pass("fxaa", &[tonemapped], &[antialiased], move |renderer, encoder, t| {
+                if let Some(fxaa) = &renderer.fxaa_stage {
+                    let (source, output) = (t.get(tonemapped), t.get(antialiased));
+                    fxaa.draw_frame(renderer, source, output, scene_size, encoder);
+                }
+                Ok(())
+            });
+            antialiased
+        } else {
+            tonemapped
+        };
+
+        graph.add_pass("upscale", &[ldr_scene], &[composite], move |renderer, encoder, textures| {
+            let source = match &renderer.taa_stage {
+                Some(taa) if ldr_scene == taa_history => taa.output(),
+                _ => textures.get(ldr_scene),
+            };
+            renderer.upscale_render_stage.draw_frame(
+                renderer,
+                &source.view,
+                renderer.scene_target.size,
+                scene_size,
+                renderer.size,
+                renderer.upscale_filter,
+                encoder,
+                &renderer.composite_target.view,
+            );
+            Ok(())
+        });
+
+        // Sprites can be drawn from view targets
+        let overlay_inputs = [composite, view_targets];
+        graph.add_pass("overlay", &overlay_inputs, &[composite], move |renderer, encoder, _| {
+            let target = &renderer.composite_target.view;
+            renderer.sprite_overlay_render_stage.draw_frame(
+                renderer,
+                renderer.high_contrast_ui,
+                encoder,
+                target,
+            )?;
+            renderer.debug_gui_stage.draw_frame(renderer, encoder, target);
+            renderer.text_render_stage.draw_frame(renderer, encoder, target);
+            Ok(())
+        });
+
+        graph.add_pass("output", &[composite], &[output], move |renderer, encoder, _| {
+            let output_view = match (frame, &renderer.output) {
+                (Some(frame), _) => &frame.view,
+                (None, RenderOutput::Offscreen(target)) => &target.view,
+                (None, RenderOutput::Window { .. }) => {
+                    unreachable!("Window frames are acquired before drawing")
+                }
+            };
+            renderer.output_render_stage.draw_frame(
+                renderer,
+                renderer.color_filter,
+                renderer.output_calibration,
+                encoder,
+                output_view,
+            );
+            Ok(())
+        });
+
+        graph
+    }
+
+    /// Draw each of the frame packet's secondary views in to its target with the forward stage
+    ///
+    /// This comes after the main view in the same encoder, so the forward stage's buffers are
+    /// re-uploaded for each view without disturbing what the main view was drawn with. The shadow
+    /// map is reused as is, so the uniforms keep the main view's light transform.
+    fn draw_views(
+        &mut self,
+        frame_packet: &FramePacket,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Result<()> {
+        for view in &frame_packet.views {
+            let target = self
+                .view_targets
+                .get(&view.target)
+                .ok_or(Error::InvalidFramePacket("View with unknown target"))?;
+            let packet = frame_packet.for_view(view);
+
+            self.forward_render_stage.prepare_pipelines(
+                &self.device,
+                &mut self.resource_cache,
+                &self.models,
+                &self.materials,
+                &packet,
+            )?;
+            self.forward_render_stage.update(
+                &self.device,
+                self.staging_belt.get_mut(),
+                encoder,
+                self.frame_fences.current(),
+                &self.models,
+                &packet,
+                frame_packet.view,
+                None,
+            );
+
+            let size = target.scene.size;
+            let prepassed = self.forward_render_stage.draw_depth_prepass(
+                self,
+                &packet,
+                encoder,
+                &target.scene,
+                size,
+            )?;
+            self.forward_render_stage.draw_frame(
+                self,
+                &packet,
+                encoder,
+                &target.scene,
+                Viewport::from_size(size),
+                wgpu::LoadOp::Clear,
+                if prepassed { wgpu::LoadOp::Load } else { wgpu::LoadOp::Clear },
+            )?;
+            let viewport = Viewport::from_size(size);
+            self.skybox_render_stage.draw_frame(self, &packet, encoder, &target.scene, viewport)?;
+            self.forward_render_stage.draw_transparent(
+                self,
+                &packet,
+                encoder,
+                &target.scene,
+                viewport,
+            )?;
+
+            self.post_process_stage.draw_other(
+                self,
+                self.tonemapper,
+                &target.post_process_bind_group,
+                &target.output,
+                size,
+                encoder,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Draw the scene in to the water stage's reflection and refraction targets, before the main
+    /// view is drawn
+    ///
+    /// As with `draw_views`, the forward stage's buffers are re-uploaded for each of them, after
+    /// which the main view's are put back for the rest of the frame. The terrain is drawn in to
+    /// both as well, cut off at the surface like the models.
+    fn draw_water_views(
+        &mut self,
+        frame_packet: &FramePacket,
+        water: &frame_packet::Water,
+        motion_frame: Option<MotionFrame>,
+        encoder: &mut wgpu::CommandEncoder,
+        scene_size: winit::dpi::PhysicalSize<u32>,
+    ) -> Result<()> {
+        let (view, proj) = water::reflection_camera(water, frame_packet.view, frame_packet.proj);
+        let clip_plane = water::reflection_clip_plane(water);
+        let reflection = frame_packet.for_water_view(view, proj, clip_plane);
+        self.draw_water_view(frame_packet, &reflection, false, encoder, scene_size)?;
+        if water.refraction {
+            let clip_plane = water::refraction_clip_plane(water);
+            let (view, proj) = (frame_packet.view, frame_packet.proj);
+            let refraction = frame_packet.for_water_view(view, proj, clip_plane);
+            self.draw_water_view(frame_packet, &refraction, true, encoder, scene_size)?;
+        }
+
+        self.forward_render_stage.restore(
+            &self.device,
+            self.staging_belt.get_mut(),
+            encoder,
+            self.frame_fences.current(),
+            &self.models,
             frame_packet,
-            &mut encoder,
-            &frame.view
+            motion_frame,
         );
+        Ok(())
+    }
 
-        self.queue.submit(&[encoder.finish()]);
+    /// Draw one of the water's views, `packet`, in to its reflection or refraction target
+    fn draw_water_view(
+        &mut self,
+        frame_packet: &FramePacket,
+        packet: &FramePacket,
+        refraction: bool,
+        encoder: &mut wgpu::CommandEncoder,
+        scene_size: winit::dpi::PhysicalSize<u32>,
+    ) -> Result<()> {
+        self.forward_render_stage.prepare_pipelines(
+            &self.device,
+            &mut self.resource_cache,
+            &self.models,
+            &self.materials,
+            packet,
+        )?;
+        self.forward_render_stage.update(
+            &self.device,
+            self.staging_belt.get_mut(),
+            encoder,
+            self.frame_fences.current(),
+            &self.models,
+            packet,
+            frame_packet.view,
+            None,
+        );
+
+        let stage = self.water_render_stage.as_ref().expect("Only used when created");
+        let target = if refraction { &stage.refraction } else { &stage.reflection };
+        let viewport = water::view_region(scene_size);
+        let size = winit::dpi::PhysicalSize::new(viewport.width, viewport.height);
+        let prepassed =
+            self.forward_render_stage.draw_depth_prepass(self, packet, encoder, target, size)?;
+        self.forward_render_stage.draw_frame(
+            self,
+            packet,
+            encoder,
+            target,
+            viewport,
+            wgpu::LoadOp::Clear,
+            if prepassed { wgpu::LoadOp::Load } else { wgpu::LoadOp::Clear },
+        )?;
+        if let Some(terrain_id) = frame_packet.terrain {
+            let terrain = self
+                .terrains
+                .get(&terrain_id)
+                .ok_or(Error::InvalidFramePacket("Terrain with unknown id"))?;
+            let terrain_stage = &self.terrain_render_stage;
+            terrain_stage.draw_frame(self, packet, packet.proj, terrain, encoder, target, viewport);
+        }
+        self.skybox_render_stage.draw_frame(self, packet, encoder, target, viewport)?;
+        self.forward_render_stage.draw_transparent(self, packet, encoder, target, viewport)
+    }
+
+    /// Draw each of the frame packet's split views in to its own region of the scene target, in
+    /// place of the main view
+    ///
+    /// As with `draw_views`, the forward stage's buffers are re-uploaded for each view in turn.
+    /// Every view shares the target's depth buffer, with the scissor rect keeping them apart.
+    fn draw_split_views(
+        &mut self,
+        frame_packet: &FramePacket,
+        encoder: &mut wgpu::CommandEncoder,
+        scene_size: winit::dpi::PhysicalSize<u32>,
+    ) -> Result<()> {
+        for (i, split_view) in frame_packet.split_views.iter().enumerate() {
+            let packet = frame_packet.for_split_view(split_view);
+            self.forward_render_stage.prepare_pipelines(
+                &self.device,
+                &mut self.resource_cache,
+                &self.models,
+                &self.materials,
+                &packet,
+            )?;
+            self.forward_render_stage.update(
+                &self.device,
+                self.staging_belt.get_mut(),
+                encoder,
+                self.frame_fences.current(),
+                &self.models,
+                &packet,
+                frame_packet.view,
+                None,
+            );
+
+            // Clears ignore the viewport and scissor rect, so only the first view can clear
+            let load_op = if i == 0 { wgpu::LoadOp::Clear } else { wgpu::LoadOp::Load };
+            let viewport = Viewport::within(split_view.viewport, scene_size);
+            self.forward_render_stage.draw_frame(
+                self,
+                &packet,
+                encoder,
+                &self.scene_target,
+                viewport,
+                load_op,
+                load_op,
+            )?;
+            self.skybox_render_stage.draw_frame(
+                self,
+                &packet,
+                encoder,
+                &self.scene_target,
+                viewport,
+            )?;
+            self.forward_render_stage.draw_transparent(
+                self,
+                &packet,
+                encoder,
+                &self.scene_target,
+                viewport,
+            )?;
+            self.forward_render_stage.draw_outlines(
+                self,
+                &self.outline_render_stage,
+                &packet,
+                encoder,
+                &self.scene_target,
+                viewport,
+            )?;
+        }
+
+        Ok(())
     }
 }
 
@@ -317,250 +2658,1643 @@ impl Renderer {
 struct ForwardUniformData {
     view: cgmath::Matrix4<f32>,
     proj: cgmath::Matrix4<f32>,
+
+    /// Transforms world space in to the shadow map's clip space
+    light_view_proj: cgmath::Matrix4<f32>,
+
+    /// View space direction towards the directional light, w is unused
+    sun_direction: cgmath::Vector4<f32>,
+
+    /// Linear RGB color of the directional light premultiplied by its intensity, w is unused
+    sun_color: cgmath::Vector4<f32>,
+
+    /// Linear RGB, w is unused
+    fog_color: cgmath::Vector4<f32>,
+
+    /// See `Fog::params`
+    fog_params: cgmath::Vector4<f32>,
+
+    /// Last frame's unjittered projection and view, for motion vectors
+    prev_view_proj: cgmath::Matrix4<f32>,
+
+    /// Clip space offset that `proj` is jittered by for TAA in xy, zw are unused
+    jitter: cgmath::Vector4<f32>,
+
+    /// View space plane that fragments behind are discarded at, see `FramePacket::clip_plane`
+    clip_plane: cgmath::Vector4<f32>,
+
+    /// See `RenderQuality::anisotropy` in x, yzw are unused
+    texture_params: cgmath::Vector4<f32>,
 }
 
 unsafe impl bytemuck::Pod for ForwardUniformData {}
 unsafe impl bytemuck::Zeroable for ForwardUniformData {}
 
+const FORWARD_VERTEX_SHADER: &str = "./src/renderer/shaders/shader.vert";
+const FORWARD_FRAGMENT_SHADER: &str = "./src/renderer/shaders/shader.frag";
+
+/// The ways the forward stage draws each shader permutation
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ForwardPipelineKind {
+    /// Shaded triangles
+    Filled,
+
+    /// Shaded lines along the triangles' edges
+    Wireframe,
+
+    /// Triangles writing only depth, for the depth prepass
+    DepthOnly,
+
+    /// Transparent triangles summed in to the OIT stage's accumulation targets
+    WeightedBlended,
+
+    /// Triangles covering the outline stage's selection mask
+    SelectionMask,
+
+    /// Opaque triangles writing how far they've moved on screen since last frame, for TAA and
+    /// motion blur
+    MotionVectors,
+}
+
+/// Identifies one of the forward stage's pipelines, as the shader permutation it was compiled
+/// with and the index format of the models it draws
+type ForwardPipelineKey = (ShaderFeatures, wgpu::IndexFormat);
+
+/// A frame's copy of the forward stage's uniforms
+struct ForwardUniforms {
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+
+    /// Like `bind_group`, but with nothing occluded, for drawing in to targets other than the
+    /// scene target that SSAO's output lines up with
+    unoccluded_bind_group: wgpu::BindGroup,
+}
+
 /// Represents a render stage that renders instanced 3d geometry to a texture view
 struct ForwardRenderStage {
-    uniform_bind_group: wgpu::BindGroup,
-    uniform_buff: wgpu::Buffer,
-    texture_bind_group_layout: wgpu::BindGroupLayout,
-    pipeline: wgpu::RenderPipeline,
-    texture_bind_groups: HashMap<ModelId, wgpu::BindGroup>,
-    texture_sampler: wgpu::Sampler,
+    uniform_bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    uniforms: PerFrame<ForwardUniforms>,
+
+    /// The frame last written by `update`, whose uniforms are drawn with
+    frame: FrameSlot,
+    shadow_sampler: Rc<wgpu::Sampler>,
+    occlusion_sampler: Rc<wgpu::Sampler>,
+    texture_bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    pipeline_layout: Rc<wgpu::PipelineLayout>,
+    texture_bind_groups: HashMap<MaterialId, wgpu::BindGroup>,
+    texture_sampler: Rc<wgpu::Sampler>,
+    lights: LightBuffer,
+
+    /// Bound alongside the uniforms, for the ambient lighting
+    environment: EnvironmentLighting,
+    instances: InstanceBuffer<InstanceData>,
+    joints: JointBuffer,
+
+    /// None unless enabled in the renderer config
+    culling: Option<GpuCulling>,
+
+    /// Dynamic offset of each of this frame's models in to `joints`
+    joint_offsets: Vec<wgpu::DynamicOffset>,
+
+    shader_cache: ShaderCache,
+
+    /// A pipeline for every shader permutation and index format in use
+    pipelines: HashMap<ForwardPipelineKey, Rc<wgpu::RenderPipeline>>,
+    pipeline_cache: PipelineCache,
+
+    /// Whether models are drawn as lines along their triangles' edges
+    wireframe: bool,
+
+    /// Like `pipelines`, but drawing line lists. Only filled in while `wireframe` is set.
+    wireframe_pipelines: HashMap<ForwardPipelineKey, Rc<wgpu::RenderPipeline>>,
+
+    /// Whether the scene's depth is drawn before it's shaded, in which case `pipelines` only
+    /// shade the pixels whose depth matches
+    depth_prepass: bool,
+
+    /// Like `pipelines`, but only writing depth. Only filled in if `depth_prepass` is set.
+    prepass_pipelines: HashMap<ForwardPipelineKey, Rc<wgpu::RenderPipeline>>,
+
+    /// Whether transparent surfaces are drawn with order-independent transparency when drawing
+    /// to the scene target
+    weighted_blended: bool,
+
+    /// Like `pipelines`, but drawing in to the OIT stage's accumulation targets. Only filled in
+    /// for transparent permutations, and only if `weighted_blended` is set.
+    oit_pipelines: HashMap<ForwardPipelineKey, Rc<wgpu::RenderPipeline>>,
+
+    /// Like `pipelines`, but drawing in to the outline stage's selection mask. Only filled in for
+    /// the permutations of selected instances.
+    selection_pipelines: HashMap<ForwardPipelineKey, Rc<wgpu::RenderPipeline>>,
+
+    /// Whether the scene's motion vectors are drawn, for TAA or motion blur
+    motion_vectors: bool,
+
+    /// Last frame's model matrix for each instance in `instances`, drawn with motion vectors.
+    /// Only written while `motion_vectors` is set.
+    prev_instances: InstanceBuffer<PrevInstanceData>,
+    instance_history: InstanceHistory,
+
+    /// Like `pipelines`, but drawing motion vectors. Only filled in for opaque permutations, and
+    /// only if `motion_vectors` is set.
+    motion_vector_pipelines: HashMap<ForwardPipelineKey, Rc<wgpu::RenderPipeline>>,
+
+    /// MSAA samples per pixel of the render target this stage draws to
+    sample_count: u32,
+
+    /// Depth order of every render target this stage draws to
+    depth_order: DepthOrder,
+
+    /// Whether the frame being drawn has fog, which every permutation drawn includes
+    fog: bool,
+
+    /// Likewise for a clip plane
+    clip_plane: bool,
+
+    /// See `RenderQuality::anisotropy`
+    anisotropy: u32,
 }
 
 impl ForwardRenderStage {
-    pub async fn new(device: &wgpu::Device) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        light_buffer_kind: LightBufferKind,
+        shadow_map: &wgpu::TextureView,
+        occlusion: &wgpu::TextureView,
+        unoccluded: &wgpu::TextureView,
+        sample_count: u32,
+        depth_order: DepthOrder,
+        depth_prepass: bool,
+        gpu_culling: bool,
+        weighted_blended: bool,
+        motion_vectors: bool,
+        queue: &wgpu::Queue,
+    ) -> Result<Self> {
         let mut shader_cache = ShaderCache::new();
-        let vs_spirv = shader_cache
-            .get_shader(
-                "./src/renderer/shaders/shader.vert",
-                shaderc::ShaderKind::Vertex,
-            )
-            .await;
-        let fs_spirv = shader_cache
-            .get_shader(
-                "./src/renderer/shaders/shader.frag",
-                shaderc::ShaderKind::Fragment,
-            )
-            .await;
-
-        let vs_module = device.create_shader_module(&vs_spirv);
-        let fs_module = device.create_shader_module(&fs_spirv);
+        shader_cache.load_source(FORWARD_VERTEX_SHADER).await?;
+        shader_cache.load_source(FORWARD_FRAGMENT_SHADER).await?;
 
-        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
-            size: std::mem::size_of::<ForwardUniformData>() as wgpu::BufferAddress,
-            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-            label: Some("Render stage uniform buffer"),
-        });
+        let lights = LightBuffer::new(device, resources, light_buffer_kind);
+        let joints = JointBuffer::new(device, resources);
+        let environment = EnvironmentLighting::new(device, queue, resources).await?;
 
         let uniform_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                bindings: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
-                }],
-                label: Some("Render stage uniform buffer layout"),
-            });
-
-        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &uniform_bind_group_layout,
-            bindings: &[wgpu::Binding {
-                binding: 0,
-                resource: wgpu::BindingResource::Buffer {
-                    buffer: &uniform_buff,
-                    range: 0..std::mem::size_of::<ForwardUniformData>() as wgpu::BufferAddress,
-                },
-            }],
-            label: Some("Render stage uniform bind group"),
-        });
-
-        let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
                 bindings: &[
                     wgpu::BindGroupLayoutEntry {
                         binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: true },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
                         visibility: wgpu::ShaderStage::FRAGMENT,
                         ty: wgpu::BindingType::SampledTexture {
                             multisampled: false,
                             dimension: wgpu::TextureViewDimension::D2,
-                            component_type: wgpu::TextureComponentType::Uint,
+                            component_type: wgpu::TextureComponentType::Float,
                         },
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                    // The environment's irradiance and specular maps, and the BRDF lookup table
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::Cube,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::Cube,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 8,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Render stage uniform buffer layout"),
+            });
+
+        // Linear filtering of a comparison sampler blends the results of the 4 nearest depth
+        // tests, which smooths the shadow edges further on top of the PCF done in the shader
+        let shadow_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::LessEqual,
+        });
+
+        // Occlusion is at a lower resolution than the scene, so is filtered to smooth it back out
+        let occlusion_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let uniforms = PerFrame::new(|_| {
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<ForwardUniformData>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                label: Some("Render stage uniform buffer"),
+            });
+            let create_bind_group = |occlusion| {
+                Self::create_uniform_bind_group(
+                    device,
+                    &uniform_bind_group_layout,
+                    &buffer,
+                    shadow_map,
+                    &shadow_sampler,
+                    occlusion,
+                    &occlusion_sampler,
+                    &environment,
+                )
+            };
+            ForwardUniforms {
+                bind_group: create_bind_group(occlusion),
+                unoccluded_bind_group: create_bind_group(unoccluded),
+                buffer,
+            }
+        });
+
+        // Every material texture is sampled with the same sampler, in binding 1 for the sake of
+        // the shaders that only need the base color
+        let material_texture = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+            },
+        };
+        let texture_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    material_texture(0),
                     wgpu::BindGroupLayoutEntry {
                         binding: 1,
                         visibility: wgpu::ShaderStage::FRAGMENT,
                         ty: wgpu::BindingType::Sampler { comparison: false },
                     },
+                    material_texture(2),
+                    material_texture(3),
+                    material_texture(4),
+                    material_texture(5),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    material_texture(7),
                 ],
                 label: Some("texture_bind_group_layout"),
             });
 
         let render_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                bind_group_layouts: &[&uniform_bind_group_layout, &texture_bind_group_layout],
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[
+                    &uniform_bind_group_layout,
+                    &texture_bind_group_layout,
+                    lights.bind_group_layout(),
+                    joints.bind_group_layout(),
+                ],
             });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            layout: &render_pipeline_layout,
-            vertex_stage: wgpu::ProgrammableStageDescriptor {
-                module: &vs_module,
-                entry_point: "main",
-            },
-            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
-                module: &fs_module,
-                entry_point: "main",
-            }),
-            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: wgpu::CullMode::Back,
-                depth_bias: 0,
-                depth_bias_slope_scale: 0.0,
-                depth_bias_clamp: 0.0,
-            }),
-            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
-            color_states: &[wgpu::ColorStateDescriptor {
-                format: wgpu::TextureFormat::Bgra8Unorm,
+        let texture_sampler =
+            Self::create_texture_sampler(device, resources, wgpu::FilterMode::Nearest);
+
+        let culling = if gpu_culling {
+            Some(GpuCulling::new(device, resources).await?)
+        } else {
+            None
+        };
+
+        let mut stage = Self {
+            uniform_bind_group_layout,
+            uniforms,
+            frame: FrameSlot::default(),
+            shadow_sampler,
+            occlusion_sampler,
+            texture_bind_group_layout,
+            pipeline_layout: render_pipeline_layout,
+            texture_sampler,
+            texture_bind_groups: HashMap::new(),
+            lights,
+            environment,
+            // Read by the culling pass as well as drawn from
+            instances: InstanceBuffer::with_usage(
+                device,
+                "Forward render stage instance buffer",
+                wgpu::BufferUsage::VERTEX
+                    | wgpu::BufferUsage::STORAGE_READ
+                    | wgpu::BufferUsage::COPY_DST,
+            ),
+            joints,
+            culling,
+            joint_offsets: Vec::new(),
+            shader_cache,
+            pipelines: HashMap::new(),
+            pipeline_cache: PipelineCache::load(PIPELINE_CACHE_PATH).await,
+            wireframe: false,
+            wireframe_pipelines: HashMap::new(),
+            depth_prepass,
+            prepass_pipelines: HashMap::new(),
+            weighted_blended,
+            oit_pipelines: HashMap::new(),
+            selection_pipelines: HashMap::new(),
+            motion_vectors,
+            prev_instances: InstanceBuffer::new(
+                device,
+                "Forward render stage previous instance buffer",
+            ),
+            instance_history: InstanceHistory::default(),
+            motion_vector_pipelines: HashMap::new(),
+            sample_count,
+            depth_order,
+            fog: false,
+            clip_plane: false,
+            anisotropy: 1,
+        };
+
+        // Create everything used in previous runs now, rather than hitching when it's first drawn
+        let warm_permutations: Vec<_> = stage.pipeline_cache.permutations().collect();
+        for features in warm_permutations {
+            for &index_format in &INDEX_FORMATS {
+                stage.ensure_pipeline(device, resources, (features, index_format))?;
+            }
+        }
+
+        Ok(stage)
+    }
+
+    /// Compile the shader permutation for the given features and create its pipeline for the
+    /// index format, if that hasn't been done already
+    fn ensure_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        key: ForwardPipelineKey,
+    ) -> Result<()> {
+        if self.wireframe && !self.wireframe_pipelines.contains_key(&key) {
+            let kind = ForwardPipelineKind::Wireframe;
+            let pipeline = self.create_pipeline(device, resources, key, kind)?;
+            self.wireframe_pipelines.insert(key, pipeline);
+        }
+
+        if self.pipelines.contains_key(&key) {
+            return Ok(());
+        }
+
+        if self.depth_prepass {
+            let kind = ForwardPipelineKind::DepthOnly;
+            let pipeline = self.create_pipeline(device, resources, key, kind)?;
+            self.prepass_pipelines.insert(key, pipeline);
+        }
+        if self.weighted_blended && key.0.contains(ShaderFeatures::ALPHA_BLEND) {
+            let kind = ForwardPipelineKind::WeightedBlended;
+            let pipeline = self.create_pipeline(device, resources, key, kind)?;
+            self.oit_pipelines.insert(key, pipeline);
+        }
+        if self.motion_vectors && !key.0.contains(ShaderFeatures::ALPHA_BLEND) {
+            let kind = ForwardPipelineKind::MotionVectors;
+            let pipeline = self.create_pipeline(device, resources, key, kind)?;
+            self.motion_vector_pipelines.insert(key, pipeline);
+        }
+        let pipeline = self.create_pipeline(device, resources, key, ForwardPipelineKind::Filled)?;
+        self.pipelines.insert(key, pipeline);
+        self.pipeline_cache.record(key.0);
+        Ok(())
+    }
+
+    /// Switch between drawing filled triangles and wireframes, creating the wireframe pipelines
+    /// for every permutation in use when first needed
+    pub fn set_wireframe(
+        &mut self,
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        enabled: bool,
+    ) -> Result<()> {
+        self.wireframe = enabled;
+        if enabled {
+            let permutations: Vec<_> = self.pipelines.keys().copied().collect();
+            for key in permutations {
+                self.ensure_pipeline(device, resources, key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Recompile the forward shaders if either is in the given set of changed files, replacing
+    /// every pipeline only if all of their permutations compile
+    pub fn reload_shaders(
+        &mut self,
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        changed: &HashSet<PathBuf>,
+    ) -> Result<()> {
+        let mut any_changed = false;
+        for path in &[FORWARD_VERTEX_SHADER, FORWARD_FRAGMENT_SHADER] {
+            if changed.contains(Path::new(path)) {
+                self.shader_cache.reload_source(path)?;
+                any_changed = true;
+            }
+        }
+        if !any_changed {
+            return Ok(());
+        }
+
+        let permutations: Vec<_> = self.pipelines.keys().copied().collect();
+        let wireframe_permutations: Vec<_> = self.wireframe_pipelines.keys().copied().collect();
+        let prepass_permutations: Vec<_> = self.prepass_pipelines.keys().copied().collect();
+        let oit_permutations: Vec<_> = self.oit_pipelines.keys().copied().collect();
+        let selection_permutations: Vec<_> = self.selection_pipelines.keys().copied().collect();
+        let motion_vector_permutations: Vec<_> =
+            self.motion_vector_pipelines.keys().copied().collect();
+        let mut recreate = |permutations: Vec<ForwardPipelineKey>, kind| {
+            permutations
+                .into_iter()
+                .map(|key| Ok((key, self.create_pipeline(device, resources, key, kind)?)))
+                .collect::<Result<HashMap<_, _>>>()
+        };
+        let pipelines = recreate(permutations, ForwardPipelineKind::Filled)?;
+        let wireframe_pipelines = recreate(wireframe_permutations, ForwardPipelineKind::Wireframe)?;
+        let prepass_pipelines = recreate(prepass_permutations, ForwardPipelineKind::DepthOnly)?;
+        let oit_pipelines = recreate(oit_permutations, ForwardPipelineKind::WeightedBlended)?;
+        let selection_pipelines =
+            recreate(selection_permutations, ForwardPipelineKind::SelectionMask)?;
+        let motion_vector_pipelines =
+            recreate(motion_vector_permutations, ForwardPipelineKind::MotionVectors)?;
+        self.pipelines = pipelines;
+        self.wireframe_pipelines = wireframe_pipelines;
+        self.prepass_pipelines = prepass_pipelines;
+        self.oit_pipelines = oit_pipelines;
+        self.selection_pipelines = selection_pipelines;
+        self.motion_vector_pipelines = motion_vector_pipelines;
+        Ok(())
+    }
+
+    fn create_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        (features, index_format): ForwardPipelineKey,
+        kind: ForwardPipelineKind,
+    ) -> Result<Rc<wgpu::RenderPipeline>> {
+        let mut defines = features.shader_defines();
+        defines.extend_from_slice(self.lights.kind().shader_defines());
+        defines.extend_from_slice(self.depth_order.shader_defines());
+        match kind {
+            ForwardPipelineKind::WeightedBlended => defines.push(("WEIGHTED_BLENDED", None)),
+            ForwardPipelineKind::SelectionMask => defines.push(("SELECTION_MASK", None)),
+            ForwardPipelineKind::MotionVectors => defines.push(("MOTION_VECTORS", None)),
+            _ => {}
+        }
+
+        // The depth only pipelines compile the vertex shader with the same defines as the
+        // shaded ones, so that they produce exactly the same depth
+        let vs_spirv = self.shader_cache.compile(
+            FORWARD_VERTEX_SHADER,
+            shaderc::ShaderKind::Vertex,
+            &defines,
+        )?;
+        // Depth only pipelines can do without a fragment shader, unless it has to discard
+        // whatever is under a masked material's alpha cutoff
+        let fs_spirv = match kind {
+            ForwardPipelineKind::DepthOnly if !features.contains(ShaderFeatures::ALPHA_MASK) => {
+                None
+            }
+            ForwardPipelineKind::DepthOnly => {
+                let mut defines = defines.clone();
+                defines.push(("DEPTH_ONLY", None));
+                Some(self.shader_cache.compile(
+                    FORWARD_FRAGMENT_SHADER,
+                    shaderc::ShaderKind::Fragment,
+                    &defines,
+                )?)
+            }
+            _ => Some(self.shader_cache.compile(
+                FORWARD_FRAGMENT_SHADER,
+                shaderc::ShaderKind::Fragment,
+                &defines,
+            )?),
+        };
+
+        let topology = match kind {
+            ForwardPipelineKind::Wireframe => wgpu::PrimitiveTopology::LineList,
+            _ => wgpu::PrimitiveTopology::TriangleList,
+        };
+        let blended = kind == ForwardPipelineKind::Filled
+            && features.contains(ShaderFeatures::ALPHA_BLEND);
+        let (color_blend, alpha_blend) = if blended {
+            (
+                wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            )
+        } else {
+            (wgpu::BlendDescriptor::REPLACE, wgpu::BlendDescriptor::REPLACE)
+        };
+        // Colors are summed in to the accumulation target, while the revealage target is
+        // multiplied by one minus each alpha
+        let additive = wgpu::BlendDescriptor {
+            src_factor: wgpu::BlendFactor::One,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let revealage_blend = wgpu::BlendDescriptor {
+            src_factor: wgpu::BlendFactor::Zero,
+            dst_factor: wgpu::BlendFactor::OneMinusSrcColor,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let color_states: &[_] = match kind {
+            ForwardPipelineKind::DepthOnly => &[],
+            ForwardPipelineKind::WeightedBlended => &[
+                wgpu::ColorStateDescriptor {
+                    format: OitStage::ACCUM_FORMAT,
+                    alpha_blend: additive.clone(),
+                    color_blend: additive,
+                    write_mask: wgpu::ColorWrite::ALL,
+                },
+                wgpu::ColorStateDescriptor {
+                    format: OitStage::REVEALAGE_FORMAT,
+                    alpha_blend: revealage_blend.clone(),
+                    color_blend: revealage_blend,
+                    write_mask: wgpu::ColorWrite::ALL,
+                },
+            ],
+            ForwardPipelineKind::SelectionMask => &[wgpu::ColorStateDescriptor {
+                format: OutlineStage::MASK_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            ForwardPipelineKind::MotionVectors => &[wgpu::ColorStateDescriptor {
+                format: motion_vectors::FORMAT,
                 alpha_blend: wgpu::BlendDescriptor::REPLACE,
                 color_blend: wgpu::BlendDescriptor::REPLACE,
                 write_mask: wgpu::ColorWrite::ALL,
             }],
-            depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+            _ => &[wgpu::ColorStateDescriptor {
+                format: RenderTarget::COLOR_FORMAT,
+                alpha_blend,
+                color_blend,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+        };
+
+        // After a prepass the depth buffer already holds the nearest surface, so shading only
+        // needs to happen where a triangle matches it. Blended surfaces are left out of the
+        // prepass, and don't write depth so that everything behind them still gets drawn.
+        let (depth_write_enabled, depth_compare) = match kind {
+            ForwardPipelineKind::Filled if blended => (false, wgpu::CompareFunction::Less),
+            ForwardPipelineKind::WeightedBlended => (false, wgpu::CompareFunction::Less),
+            ForwardPipelineKind::Filled if self.depth_prepass => {
+                (false, wgpu::CompareFunction::Equal)
+            }
+            // Drawn over the finished depth buffer, only where each triangle is the nearest
+            ForwardPipelineKind::MotionVectors => (false, wgpu::CompareFunction::LessEqual),
+            _ => (true, wgpu::CompareFunction::Less),
+        };
+
+        // The selection mask covers the whole of each selected instance, hidden or not, and is
+        // single sampled as only its edges are of interest
+        let (depth_stencil_state, sample_count) = if kind == ForwardPipelineKind::SelectionMask {
+            (None, 1)
+        } else {
+            let depth_stencil_state = wgpu::DepthStencilStateDescriptor {
                 format: wgpu::TextureFormat::Depth32Float,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
+                depth_write_enabled,
+                depth_compare: self.depth_order.compare(depth_compare),
                 stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
                 stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
                 stencil_read_mask: 0,
                 stencil_write_mask: 0,
+            };
+            (Some(depth_stencil_state), self.sample_count)
+        };
+
+        // Motion vectors take last frame's model matrices in place of the normal matrices
+        let vertex_buffers: &[_] = match kind {
+            ForwardPipelineKind::MotionVectors => &[
+                Vertex::vertex_buffer_descriptor(),
+                InstanceData::motion_vector_buffer_descriptor(),
+                PrevInstanceData::vertex_buffer_descriptor(),
+            ],
+            _ => &[Vertex::vertex_buffer_descriptor(), InstanceData::vertex_buffer_descriptor()],
+        };
+
+        Ok(resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &self.pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: fs_spirv.as_deref(),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::Back,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
             }),
+            primitive_topology: topology,
+            color_states,
+            depth_stencil_state,
             vertex_state: wgpu::VertexStateDescriptor {
-                index_format: wgpu::IndexFormat::Uint32,
-                vertex_buffers: &[
-                    Vertex::vertex_buffer_descriptor(),
-                    InstanceData::vertex_buffer_descriptor(),
-                ],
+                index_format,
+                vertex_buffers,
             },
-            sample_count: 1,
-            sample_mask: 0,
-            alpha_to_coverage_enabled: false,
-        });
+            sample_count,
+        }))
+    }
 
-        let texture_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+    fn create_texture_sampler(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        min_filter: wgpu::FilterMode,
+    ) -> Rc<wgpu::Sampler> {
+        resources.sampler(device, &wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
             mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
+            min_filter,
+            mipmap_filter: min_filter,
             lod_min_clamp: -100.0,
             lod_max_clamp: 100.0,
             compare: wgpu::CompareFunction::Always,
-        });
+        })
+    }
 
-        Self {
-            uniform_buff,
-            uniform_bind_group,
-            pipeline,
-            texture_bind_group_layout,
-            texture_sampler,
-            texture_bind_groups: HashMap::new(),
+    /// Swap the sampler used for material textures, rebuilding the bind groups that reference it
+    pub fn set_texture_filter(
+        &mut self,
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        materials: &HashMap<MaterialId, GpuMaterial>,
+        min_filter: wgpu::FilterMode,
+    ) {
+        self.texture_sampler = Self::create_texture_sampler(device, resources, min_filter);
+        for (&material_id, material) in materials {
+            self.add_material(device, material_id, material);
+        }
+    }
+
+    /// Set the most taps material textures are sampled with, from the next frame on
+    pub fn set_anisotropy(&mut self, anisotropy: u32) {
+        self.anisotropy = anisotropy.max(1);
+    }
+
+    /// Switch to drawing in to targets with a new sample count, or with the depth prepass or
+    /// motion vectors turned on or off, recreating the pipelines of every permutation in use
+    pub fn reconfigure(
+        &mut self,
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        sample_count: u32,
+        depth_prepass: bool,
+        motion_vectors: bool,
+    ) -> Result<()> {
+        let permutations: Vec<_> = self.pipelines.keys().copied().collect();
+        self.sample_count = sample_count;
+        self.depth_prepass = depth_prepass;
+        self.motion_vectors = motion_vectors;
+        self.pipelines.clear();
+        self.wireframe_pipelines.clear();
+        self.prepass_pipelines.clear();
+        self.oit_pipelines.clear();
+        self.selection_pipelines.clear();
+        self.motion_vector_pipelines.clear();
+        self.instance_history = InstanceHistory::default();
+        for key in permutations {
+            self.ensure_pipeline(device, resources, key)?;
         }
+        Ok(())
     }
 
-    pub fn add_model(&mut self, device: &wgpu::Device, model_id: ModelId, model: &GpuModel) {
+    /// Create the pipeline for every combination of model and material in the frame packet that
+    /// hasn't been drawn before
+    pub fn prepare_pipelines(
+        &mut self,
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        models: &HashMap<ModelId, GpuModel>,
+        materials: &HashMap<MaterialId, GpuMaterial>,
+        frame_packet: &FramePacket,
+    ) -> Result<()> {
+        for model in &frame_packet.models {
+            let model_data = models
+                .get(&model.model_id)
+                .ok_or(Error::InvalidFramePacket("Model with unknown id"))?;
+            let material = materials
+                .get(&model.material_id.unwrap_or(model_data.material))
+                .ok_or(Error::InvalidFramePacket("Material with unknown id"))?;
+            let mut features = model_data.features_with(material);
+            features.set(ShaderFeatures::FOG, frame_packet.fog.is_some());
+            features.set(ShaderFeatures::CLIP_PLANE, frame_packet.clip_plane.is_some());
+            let key = (features, model_data.indices.format);
+            self.ensure_pipeline(device, resources, key)?;
+            if !model.selected.is_empty() && !self.selection_pipelines.contains_key(&key) {
+                let kind = ForwardPipelineKind::SelectionMask;
+                let pipeline = self.create_pipeline(device, resources, key, kind)?;
+                self.selection_pipelines.insert(key, pipeline);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_material(
+        &mut self,
+        device: &wgpu::Device,
+        material_id: MaterialId,
+        material: &GpuMaterial,
+    ) {
+        let base_color_view = material.base_color_texture.create_default_view();
+        let metallic_roughness_view = material.metallic_roughness_texture.create_default_view();
+        let normal_view = material.normal_texture.create_default_view();
+        let occlusion_view = material.occlusion_texture.create_default_view();
+        let emissive_view = material.emissive_texture.create_default_view();
+        let lightmap_view = material.lightmap_texture.create_default_view();
         let texture_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &self.texture_bind_group_layout,
             bindings: &[
                 wgpu::Binding {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&model.base_color_texture.create_default_view()),
+                    resource: wgpu::BindingResource::TextureView(&base_color_view),
                 },
                 wgpu::Binding {
                     binding: 1,
                     resource: wgpu::BindingResource::Sampler(&self.texture_sampler),
                 },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&metallic_roughness_view),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&normal_view),
+                },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&occlusion_view),
+                },
+                wgpu::Binding {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&emissive_view),
+                },
+                wgpu::Binding {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &material.material_buff,
+                        range: 0..std::mem::size_of::<MaterialUniformData>()
+                            as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 7,
+                    resource: wgpu::BindingResource::TextureView(&lightmap_view),
+                },
             ],
-            label: Some("diffuse_bind_group"),
+            label: Some("Material bind group"),
         });
 
-        self.texture_bind_groups.insert(model_id, texture_bind_group);
+        self.texture_bind_groups.insert(material_id, texture_bind_group);
     }
 
-    pub fn draw_frame(
-        &self,
-        renderer: &Renderer,
+    /// Record copying this frame's camera and sun in to the uniform buffer, which the deferred
+    /// stage also draws with
+    #[allow(clippy::too_many_arguments)]
+    fn create_uniform_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buff: &wgpu::Buffer,
+        shadow_map: &wgpu::TextureView,
+        shadow_sampler: &wgpu::Sampler,
+        occlusion: &wgpu::TextureView,
+        occlusion_sampler: &wgpu::Sampler,
+        environment: &EnvironmentLighting,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: uniform_buff,
+                        range: 0..std::mem::size_of::<ForwardUniformData>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(shadow_map),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(shadow_sampler),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(occlusion),
+                },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(occlusion_sampler),
+                },
+                wgpu::Binding {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(
+                        &environment.bound.irradiance_view,
+                    ),
+                },
+                wgpu::Binding {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&environment.bound.specular_view),
+                },
+                wgpu::Binding {
+                    binding: 7,
+                    resource: wgpu::BindingResource::TextureView(&environment.brdf_lut.view),
+                },
+                wgpu::Binding {
+                    binding: 8,
+                    resource: wgpu::BindingResource::Sampler(&environment.sampler),
+                },
+            ],
+            label: Some("Render stage uniform bind group"),
+        })
+    }
+
+    /// Rebind the shadow map and the ambient occlusion that lighting is darkened by, eg. after
+    /// either has been reallocated
+    pub fn set_inputs(
+        &mut self,
+        device: &wgpu::Device,
+        shadow_map: &wgpu::TextureView,
+        occlusion: &wgpu::TextureView,
+        unoccluded: &wgpu::TextureView,
+    ) {
+        let Self {
+            uniforms,
+            uniform_bind_group_layout,
+            shadow_sampler,
+            occlusion_sampler,
+            environment,
+            ..
+        } = self;
+        for uniforms in uniforms.iter_mut() {
+            let create_bind_group = |occlusion| {
+                Self::create_uniform_bind_group(
+                    device,
+                    uniform_bind_group_layout,
+                    &uniforms.buffer,
+                    shadow_map,
+                    shadow_sampler,
+                    occlusion,
+                    occlusion_sampler,
+                    environment,
+                )
+            };
+            let (bind_group, unoccluded_bind_group) =
+                (create_bind_group(occlusion), create_bind_group(unoccluded));
+            uniforms.bind_group = bind_group;
+            uniforms.unoccluded_bind_group = unoccluded_bind_group;
+        }
+    }
+
+    /// Upload everything this stage draws the frame packet's models with, and cull their
+    /// instances if GPU culling is enabled
+    ///
+    /// `shadow_view` is the view the shadow map was fitted to, which is only different from the
+    /// frame packet's own when drawing a secondary view. `motion` is only given for the main view
+    /// when motion vectors are drawn, and jitters the projection for TAA.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: FrameSlot,
+        models: &HashMap<ModelId, GpuModel>,
+        frame_packet: &FramePacket,
+        shadow_view: cgmath::Matrix4<f32>,
+        motion: Option<MotionFrame>,
+    ) {
+        self.frame = frame;
+        self.fog = frame_packet.fog.is_some();
+        self.clip_plane = frame_packet.clip_plane.is_some();
+        self.lights.update(device, staging_belt, encoder, &frame_packet.lights, frame_packet.view);
+        self.environment.update(encoder, frame_packet.skybox);
+        self.instances.update(
+            device,
+            staging_belt,
+            encoder,
+            frame,
+            frame_packet.models.iter().map(|model| &model.instances[..]),
+        );
+        if motion.is_some() && self.motion_vectors {
+            let prev_instances = self.instance_history.advance(
+                frame_packet.models.iter().map(|model| (model.model_id, &model.instances[..])),
+            );
+            self.prev_instances.update(
+                device,
+                staging_belt,
+                encoder,
+                frame,
+                prev_instances.iter().map(|model| &model[..]),
+            );
+        }
+        if let Some(culling) = &mut self.culling {
+            let culled_models = Self::culled_models(models, frame_packet, self.wireframe);
+            culling.update(
+                device,
+                staging_belt,
+                encoder,
+                frame,
+                &self.instances,
+                frame_packet.proj * frame_packet.view,
+                &culled_models,
+            );
+        }
+        self.joint_offsets = self.joints.update(
+            device,
+            staging_belt,
+            encoder,
+            frame_packet.models.iter().map(|model| &model.joint_matrices[..]),
+        );
+        self.update_uniforms(device, staging_belt, encoder, frame_packet, shadow_view, motion);
+    }
+
+    /// Upload the frame packet's buffers again after other views have been drawn with them,
+    /// without moving the instance history that motion vectors are drawn from on a second time
+    #[allow(clippy::too_many_arguments)]
+    pub fn restore(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: FrameSlot,
+        models: &HashMap<ModelId, GpuModel>,
+        frame_packet: &FramePacket,
+        motion: Option<MotionFrame>,
+    ) {
+        // Without motion, `update` leaves last frame's instances as they were
+        let shadow_view = frame_packet.view;
+        self.update(device, staging_belt, encoder, frame, models, frame_packet, shadow_view, None);
+        self.update_uniforms(device, staging_belt, encoder, frame_packet, shadow_view, motion);
+    }
+
+    /// Which of the frame packet's models are worth culling on the GPU
+    ///
+    /// Skinned models are left out, as their bind pose bounds don't follow the animation, and so
+    /// is everything while drawing wireframes, whose index counts the culling doesn't know.
+    fn culled_models(
+        models: &HashMap<ModelId, GpuModel>,
         frame_packet: &FramePacket,
+        wireframe: bool,
+    ) -> Vec<Option<CulledModel>> {
+        let mut first_instance = 0;
+        frame_packet
+            .models
+            .iter()
+            .map(|model| {
+                let start = first_instance;
+                first_instance += model.instances.len();
+                let model_data = models.get(&model.model_id)?;
+                if wireframe
+                    || model_data.skinned
+                    || model.instances.len() < gpu_culling::MIN_CULLED_INSTANCES
+                {
+                    return None;
+                }
+
+                Some(CulledModel {
+                    bounds: model_data.bounds,
+                    index_count: model_data.indices.count,
+                    first_instance: start,
+                    instance_count: model.instances.len(),
+                })
+            })
+            .collect()
+    }
+
+    fn update_uniforms(
+        &self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
         encoder: &mut wgpu::CommandEncoder,
-        color_output: &wgpu::TextureView,
-        depth_output: &wgpu::TextureView,
+        frame_packet: &FramePacket,
+        shadow_view: cgmath::Matrix4<f32>,
+        motion: Option<MotionFrame>,
     ) {
-        let uniform_staging = renderer.device.create_buffer_with_data(
+        // Without a directional light the shadow map is never drawn, but a black sun means it
+        // doesn't matter what the shader reads from it
+        let (fog_color, fog_params) = match frame_packet.fog {
+            Some(fog) => (fog.color.extend(0.0), fog.params()),
+            None => (cgmath::Vector4::zero(), cgmath::Vector4::zero()),
+        };
+        let (light_view_proj, sun_direction, sun_color) = match frame_packet.directional_light {
+            Some(light) => (
+                shadow::light_view_proj(&light, shadow_view),
+                frame_packet.view * -light.direction.normalize().extend(0.0),
+                (light.color * light.intensity).extend(0.0),
+            ),
+            None => (
+                cgmath::Matrix4::identity(),
+                cgmath::Vector4::unit_z(),
+                cgmath::Vector4::zero(),
+            ),
+        };
+        let (proj, prev_view_proj, jitter) = match motion {
+            Some(motion) => (
+                motion.jittered_proj(frame_packet.proj),
+                motion.prev_view_proj,
+                motion.jitter.extend(0.0).extend(0.0),
+            ),
+            None => (
+                frame_packet.proj,
+                frame_packet.proj * frame_packet.view,
+                cgmath::Vector4::zero(),
+            ),
+        };
+        // Planes transform by the inverse transpose of what their points transform by
+        let clip_plane = match (frame_packet.clip_plane, frame_packet.view.invert()) {
+            (Some(plane), Some(inv_view)) => inv_view.transpose() * plane,
+            _ => cgmath::Vector4::zero(),
+        };
+
+        staging_belt.write(
+            device,
+            encoder,
+            &self.uniforms.get(self.frame).buffer,
+            0,
             bytemuck::cast_slice(&[ForwardUniformData {
                 view: frame_packet.view,
-                proj: frame_packet.proj,
+                proj,
+                light_view_proj,
+                sun_direction,
+                sun_color,
+                fog_color,
+                fog_params,
+                prev_view_proj,
+                jitter,
+                clip_plane,
+                texture_params: cgmath::vec4(self.anisotropy as f32, 0.0, 0.0, 0.0),
             }]),
-            wgpu::BufferUsage::COPY_SRC,
         );
+    }
 
-        encoder.copy_buffer_to_buffer(
-            &uniform_staging,
-            0,
-            &self.uniform_buff,
-            0,
-            std::mem::size_of::<ForwardUniformData>() as wgpu::BufferAddress,
+    /// SSAO is only drawn for the scene target, so anything else is drawn unoccluded
+    fn uniform_bind_group_for(
+        &self,
+        renderer: &Renderer,
+        target: &RenderTarget,
+    ) -> &wgpu::BindGroup {
+        let uniforms = self.uniforms.get(self.frame);
+        if std::ptr::eq(target, &renderer.scene_target) {
+            &uniforms.bind_group
+        } else {
+            &uniforms.unoccluded_bind_group
+        }
+    }
+
+    /// This frame's uniforms, bound with the scene's occlusion
+    fn uniform_bind_group(&self) -> &wgpu::BindGroup {
+        &self.uniforms.get(self.frame).bind_group
+    }
+
+    /// Whether `draw_depth_prepass` draws anything this frame, which wireframes skip as their
+    /// lines don't cover the triangles they're shaded with
+    fn uses_depth_prepass(&self, frame_packet: &FramePacket) -> bool {
+        self.depth_prepass && !self.wireframe && !frame_packet.models.is_empty()
+    }
+
+    /// Fill the target's depth buffer with the nearest surface of every model, if the prepass is
+    /// enabled, so that `draw_frame` only shades the visible pixels
+    ///
+    /// Returns whether the depth buffer was filled in, in which case `draw_frame` has to keep it.
+    pub fn draw_depth_prepass(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &RenderTarget,
+        viewport_size: winit::dpi::PhysicalSize<u32>,
+    ) -> Result<bool> {
+        if !self.uses_depth_prepass(frame_packet) {
+            return Ok(false);
+        }
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[],
+            depth_stencil_attachment: Some(target.depth_attachment(wgpu::LoadOp::Clear)),
+        });
+
+        rpass.set_viewport(
+            0.0,
+            0.0,
+            viewport_size.width as f32,
+            viewport_size.height as f32,
+            0.0,
+            1.0,
         );
+        rpass.set_bind_group(0, self.uniform_bind_group_for(renderer, target), &[]);
+        rpass.set_bind_group(2, self.lights.bind_group(), &[]);
 
-        for model in &frame_packet.models {
-            let model_data = renderer
-                .models
-                .get(&model.model_id)
-                .expect("Frame packet references model with unknown id");
+        let mut first_instance = 0;
+        for (i, model) in frame_packet.models.iter().enumerate() {
+            // The texture bind group is unused by the vertex shader, but the pipeline layout
+            // still needs it bound
+            let (model_data, texture_bind_group, features) = self.resolve(renderer, model)?;
 
-            let texture_bind_group = self.texture_bind_groups
-                .get(&model.model_id)
-                .expect("Frame packet references model with no texture information");
+            if self.is_transparent(features) {
+                first_instance += model.instances.len();
+                continue;
+            }
 
-            let instance_data_buff = renderer.device.create_buffer_with_data(
-                bytemuck::cast_slice(&model.instances[..]),
-                wgpu::BufferUsage::VERTEX,
+            let pipeline = self
+                .prepass_pipelines
+                .get(&(features, model_data.indices.format))
+                .expect("Pipeline wasn't prepared for the model and material");
+            rpass.set_pipeline(pipeline);
+            rpass.set_bind_group(1, texture_bind_group, &[]);
+            rpass.set_bind_group(3, self.joints.bind_group(), &[self.joint_offsets[i]]);
+
+            rpass.set_vertex_buffer(0, &model_data.vertex_buff, 0, 0);
+            rpass.set_index_buffer(&model_data.indices.buffer, 0, 0);
+            self.draw_instances(
+                renderer,
+                &mut rpass,
+                i,
+                first_instance,
+                model.instances.len(),
+                model_data.indices.count,
             );
+            first_instance += model.instances.len();
+        }
 
-            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &color_output,
-                    resolve_target: None,
-                    load_op: wgpu::LoadOp::Clear,
-                    store_op: wgpu::StoreOp::Store,
-                    clear_color: wgpu::Color::BLACK,
-                }],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                    attachment: depth_output,
-                    depth_load_op: wgpu::LoadOp::Clear,
-                    depth_store_op: wgpu::StoreOp::Store,
-                    clear_depth: 1.0,
-                    stencil_load_op: wgpu::LoadOp::Clear,
-                    stencil_store_op: wgpu::StoreOp::Store,
-                    clear_stencil: 0,
-                }),
-            });
+        Ok(true)
+    }
+
+    /// The uploaded model a frame packet model draws, the bind group of the material it's drawn
+    /// with, and the permutation of the forward shader drawing that combination
+    fn resolve<'a>(
+        &'a self,
+        renderer: &'a Renderer,
+        model: &FramePacketModel,
+    ) -> Result<(&'a GpuModel, &'a wgpu::BindGroup, ShaderFeatures)> {
+        let model_data = renderer
+            .models
+            .get(&model.model_id)
+            .ok_or(Error::InvalidFramePacket("Model with unknown id"))?;
+        let material_id = model.material_id.unwrap_or(model_data.material);
+        let material = renderer
+            .materials
+            .get(&material_id)
+            .ok_or(Error::InvalidFramePacket("Material with unknown id"))?;
+        let texture_bind_group = self
+            .texture_bind_groups
+            .get(&material_id)
+            .ok_or(Error::InvalidFramePacket("Material with no texture information"))?;
+        let mut features = model_data.features_with(material);
+        features.set(ShaderFeatures::FOG, self.fog);
+        features.set(ShaderFeatures::CLIP_PLANE, self.clip_plane);
+        Ok((model_data, texture_bind_group, features))
+    }
+
+    /// Bind the `i`th frame packet model's instances, which start at `first_instance`, and draw
+    /// them with the first `index_count` indices of the bound index buffer
+    ///
+    /// Models culled on the GPU are drawn indirectly from their culled instances. The draw counter
+    /// still counts every instance of those, as only the GPU knows how many survived.
+    fn draw_instances<'a>(
+        &'a self,
+        renderer: &Renderer,
+        rpass: &mut wgpu::RenderPass<'a>,
+        i: usize,
+        first_instance: usize,
+        instance_count: usize,
+        index_count: u32,
+    ) {
+        let offset = InstanceBuffer::<InstanceData>::offset(first_instance);
+        match self.culling.as_ref().and_then(|culling| culling.indirect_draw(i)) {
+            Some((culled_instances, draw_args, draw_offset)) => {
+                rpass.set_vertex_buffer(1, culled_instances, offset, 0);
+                rpass.draw_indexed_indirect(draw_args, draw_offset);
+            }
+            None => {
+                rpass.set_vertex_buffer(1, self.instances.buffer(), offset, 0);
+                rpass.draw_indexed(0..index_count, 0, 0..instance_count as u32);
+            }
+        }
+        renderer.draw_counter.record(instance_count as u32);
+    }
+
+    /// Whether models drawn with the given features are blended over the scene by
+    /// `draw_transparent`, rather than drawn by `draw_frame` along with everything else
+    ///
+    /// Wireframes are never blended, as their lines have nothing to be seen through.
+    fn is_transparent(&self, features: ShaderFeatures) -> bool {
+        !self.wireframe && features.contains(ShaderFeatures::ALPHA_BLEND)
+    }
+
+    /// Draw every opaque model in a single render pass
+    ///
+    /// The caller decides whether the pass starts by clearing the target's color and depth, or
+    /// draws on top of what's already there, eg. depth from `draw_depth_prepass`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &RenderTarget,
+        viewport: Viewport,
+        color_load_op: wgpu::LoadOp,
+        depth_load_op: wgpu::LoadOp,
+    ) -> Result<()> {
+        // Begun even without any models, eg. while they're still loading, so that the clear
+        // still happens
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[target.color_attachment(color_load_op)],
+            depth_stencil_attachment: Some(target.depth_attachment(depth_load_op)),
+        });
+
+        viewport.apply(&mut rpass);
+        rpass.set_bind_group(0, self.uniform_bind_group_for(renderer, target), &[]);
+        rpass.set_bind_group(2, self.lights.bind_group(), &[]);
+
+        let pipelines = if self.wireframe {
+            &self.wireframe_pipelines
+        } else {
+            &self.pipelines
+        };
+
+        let mut first_instance = 0;
+        for (i, model) in frame_packet.models.iter().enumerate() {
+            let (model_data, texture_bind_group, features) = self.resolve(renderer, model)?;
+
+            if self.is_transparent(features) {
+                first_instance += model.instances.len();
+                continue;
+            }
+
+            let indices = if self.wireframe {
+                &model_data.wireframe_indices
+            } else {
+                &model_data.indices
+            };
+            let pipeline = pipelines
+                .get(&(features, indices.format))
+                .expect("Pipeline wasn't prepared for the model and material");
+            rpass.set_pipeline(pipeline);
+            rpass.set_bind_group(1, texture_bind_group, &[]);
+            rpass.set_bind_group(3, self.joints.bind_group(), &[self.joint_offsets[i]]);
+
+            rpass.set_vertex_buffer(0, &model_data.vertex_buff, 0, 0);
+            rpass.set_index_buffer(&indices.buffer, 0, 0);
+            self.draw_instances(
+                renderer,
+                &mut rpass,
+                i,
+                first_instance,
+                model.instances.len(),
+                indices.count,
+            );
+            first_instance += model.instances.len();
+        }
+
+        Ok(())
+    }
+
+    /// Draw how far every opaque model has moved since last frame in to `output`, for the parts
+    /// of them left visible in the scene target's depth
+    ///
+    /// Anything not drawn here, eg. the skybox, is left with a w of zero. Only the triangles are
+    /// drawn, even while drawing wireframes, and every instance is drawn rather than those left
+    /// by GPU culling, as the culled instances don't keep their previous transforms alongside.
+    pub fn draw_motion_vectors(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &ColorTarget,
+        viewport: Viewport,
+    ) -> Result<()> {
+        let target = &renderer.scene_target;
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &output.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::TRANSPARENT,
+            }],
+            depth_stencil_attachment: Some(target.depth_attachment(wgpu::LoadOp::Load)),
+        });
+
+        viewport.apply(&mut rpass);
+        rpass.set_bind_group(0, self.uniform_bind_group_for(renderer, target), &[]);
+        rpass.set_bind_group(2, self.lights.bind_group(), &[]);
+
+        let mut first_instance = 0;
+        for (i, model) in frame_packet.models.iter().enumerate() {
+            let (model_data, texture_bind_group, features) = self.resolve(renderer, model)?;
+            let key = (features, model_data.indices.format);
+            let pipeline = match self.motion_vector_pipelines.get(&key) {
+                Some(pipeline) => pipeline,
+                // Transparent surfaces don't write depth, so the history is left to follow
+                // whatever is behind them
+                None => {
+                    first_instance += model.instances.len();
+                    continue;
+                }
+            };
+            rpass.set_pipeline(pipeline);
+            rpass.set_bind_group(1, texture_bind_group, &[]);
+            rpass.set_bind_group(3, self.joints.bind_group(), &[self.joint_offsets[i]]);
+
+            let instance_count = model.instances.len();
+            let offset = InstanceBuffer::<InstanceData>::offset(first_instance);
+            let prev_offset = InstanceBuffer::<PrevInstanceData>::offset(first_instance);
+            rpass.set_vertex_buffer(0, &model_data.vertex_buff, 0, 0);
+            rpass.set_vertex_buffer(1, self.instances.buffer(), offset, 0);
+            rpass.set_vertex_buffer(2, self.prev_instances.buffer(), prev_offset, 0);
+            rpass.set_index_buffer(&model_data.indices.buffer, 0, 0);
+            rpass.draw_indexed(0..model_data.indices.count, 0, 0..instance_count as u32);
+            renderer.draw_counter.record(instance_count as u32);
+            first_instance += instance_count;
+        }
+
+        Ok(())
+    }
+
+    /// Blend every transparent model over the target in a second pass, once everything opaque
+    /// including the skybox has been drawn
+    ///
+    /// Transparent instances don't write depth, so they're drawn from back to front for each one
+    /// to blend over those behind it. Consecutive instances of the same model are still drawn
+    /// together. With weighted blended transparency the scene target skips the sort, see
+    /// `draw_weighted_blended`.
+    pub fn draw_transparent(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &RenderTarget,
+        viewport: Viewport,
+    ) -> Result<()> {
+        if let Some(oit) = &renderer.oit_render_stage {
+            if std::ptr::eq(target, &renderer.scene_target) {
+                return self.draw_weighted_blended(
+                    renderer,
+                    oit,
+                    frame_packet,
+                    encoder,
+                    target,
+                    viewport,
+                );
+            }
+        }
+
+        let models = frame_packet
+            .models
+            .iter()
+            .map(|model| self.resolve(renderer, model))
+            .collect::<Result<Vec<_>>>()?;
+
+        let order = frame_packet.instances_back_to_front(|model| {
+            self.resolve(renderer, model)
+                .is_ok_and(|(_, _, features)| self.is_transparent(features))
+        });
+        if order.is_empty() {
+            return Ok(());
+        }
+
+        // Where each model's instances start in the instance buffer
+        let first_instances: Vec<_> = frame_packet
+            .models
+            .iter()
+            .scan(0, |first_instance, model| {
+                let start = *first_instance;
+                *first_instance += model.instances.len();
+                Some(start)
+            })
+            .collect();
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[target.color_attachment(wgpu::LoadOp::Load)],
+            depth_stencil_attachment: Some(target.depth_attachment(wgpu::LoadOp::Load)),
+        });
+
+        viewport.apply(&mut rpass);
+        rpass.set_bind_group(0, self.uniform_bind_group_for(renderer, target), &[]);
+        rpass.set_bind_group(2, self.lights.bind_group(), &[]);
 
-            rpass.set_pipeline(&self.pipeline);
-            rpass.set_bind_group(0, &self.uniform_bind_group, &[]);
-            rpass.set_bind_group(1, &texture_bind_group, &[]);
+        let mut remaining = &order[..];
+        while let Some(&(i, first)) = remaining.first() {
+            // The run of the same model's instances in a row, eg. all of them if only one
+            // transparent model is in view
+            let run_len = remaining
+                .iter()
+                .enumerate()
+                .take_while(|(offset, &(model_index, instance_index))| {
+                    model_index == i && instance_index == first + offset
+                })
+                .count();
+            remaining = &remaining[run_len..];
+
+            let (model_data, texture_bind_group, features) = models[i];
+            let pipeline = self
+                .pipelines
+                .get(&(features, model_data.indices.format))
+                .expect("Pipeline wasn't prepared for the model and material");
+            rpass.set_pipeline(pipeline);
+            rpass.set_bind_group(1, texture_bind_group, &[]);
+            rpass.set_bind_group(3, self.joints.bind_group(), &[self.joint_offsets[i]]);
 
             rpass.set_vertex_buffer(0, &model_data.vertex_buff, 0, 0);
-            rpass.set_vertex_buffer(1, &instance_data_buff, 0, 0);
-            rpass.set_index_buffer(&model_data.index_buff, 0, 0);
+            rpass.set_vertex_buffer(
+                1,
+                self.instances.buffer(),
+                InstanceBuffer::<InstanceData>::offset(first_instances[i]),
+                0,
+            );
+            rpass.set_index_buffer(&model_data.indices.buffer, 0, 0);
             rpass.draw_indexed(
-                0..model_data.index_count,
+                0..model_data.indices.count,
                 0,
-                0..model.instances.len() as u32,
+                first as u32..(first + run_len) as u32,
             );
+            renderer.draw_counter.record(run_len as u32);
+        }
+
+        Ok(())
+    }
+
+    /// Draw every transparent model in to the OIT stage's accumulation targets in whatever order
+    /// they come, then composite them over the target
+    fn draw_weighted_blended(
+        &self,
+        renderer: &Renderer,
+        oit: &OitStage,
+        frame_packet: &FramePacket,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &RenderTarget,
+        viewport: Viewport,
+    ) -> Result<()> {
+        let models = frame_packet
+            .models
+            .iter()
+            .map(|model| self.resolve(renderer, model))
+            .collect::<Result<Vec<_>>>()?;
+        if !models.iter().any(|&(_, _, features)| self.is_transparent(features)) {
+            return Ok(());
+        }
+
+        {
+            let mut rpass = oit.begin_accumulation(encoder, target);
+            viewport.apply(&mut rpass);
+            rpass.set_bind_group(0, self.uniform_bind_group_for(renderer, target), &[]);
+            rpass.set_bind_group(2, self.lights.bind_group(), &[]);
+
+            let mut first_instance = 0;
+            for (i, (model, &(model_data, texture_bind_group, features))) in
+                frame_packet.models.iter().zip(&models).enumerate()
+            {
+                if !self.is_transparent(features) {
+                    first_instance += model.instances.len();
+                    continue;
+                }
+
+                let pipeline = self
+                    .oit_pipelines
+                    .get(&(features, model_data.indices.format))
+                    .expect("Pipeline wasn't prepared for the model and material");
+                rpass.set_pipeline(pipeline);
+                rpass.set_bind_group(1, texture_bind_group, &[]);
+                rpass.set_bind_group(3, self.joints.bind_group(), &[self.joint_offsets[i]]);
+
+                rpass.set_vertex_buffer(0, &model_data.vertex_buff, 0, 0);
+                rpass.set_index_buffer(&model_data.indices.buffer, 0, 0);
+                self.draw_instances(
+                    renderer,
+                    &mut rpass,
+                    i,
+                    first_instance,
+                    model.instances.len(),
+                    model_data.indices.count,
+                );
+                first_instance += model.instances.len();
+            }
+        }
+
+        oit.composite(renderer, encoder, target, viewport);
+        Ok(())
+    }
+
+    /// Outline the frame packet's selected instances over the target, if there are any
+    ///
+    /// Only the triangles are drawn in to the mask, even while drawing wireframes.
+    pub fn draw_outlines(
+        &self,
+        renderer: &Renderer,
+        outline: &OutlineStage,
+        frame_packet: &FramePacket,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &RenderTarget,
+        viewport: Viewport,
+    ) -> Result<()> {
+        if frame_packet.models.iter().all(|model| model.selected.is_empty()) {
+            return Ok(());
+        }
+
+        {
+            let mut rpass = outline.begin_mask(encoder);
+            viewport.apply(&mut rpass);
+            rpass.set_bind_group(0, self.uniform_bind_group_for(renderer, target), &[]);
+            rpass.set_bind_group(2, self.lights.bind_group(), &[]);
+            rpass.set_vertex_buffer(1, self.instances.buffer(), 0, 0);
+
+            let mut first_instance = 0;
+            for (i, model) in frame_packet.models.iter().enumerate() {
+                if model.selected.is_empty() {
+                    first_instance += model.instances.len();
+                    continue;
+                }
+
+                let (model_data, texture_bind_group, features) = self.resolve(renderer, model)?;
+                let pipeline = self
+                    .selection_pipelines
+                    .get(&(features, model_data.indices.format))
+                    .expect("Pipeline wasn't prepared for the model and material");
+                rpass.set_pipeline(pipeline);
+                rpass.set_bind_group(1, texture_bind_group, &[]);
+                rpass.set_bind_group(3, self.joints.bind_group(), &[self.joint_offsets[i]]);
+
+                rpass.set_vertex_buffer(0, &model_data.vertex_buff, 0, 0);
+                rpass.set_index_buffer(&model_data.indices.buffer, 0, 0);
+                for &selected in &model.selected {
+                    let instance = (first_instance + selected) as u32;
+                    rpass.draw_indexed(0..model_data.indices.count, 0, instance..instance + 1);
+                    renderer.draw_counter.record(1);
+                }
+                first_instance += model.instances.len();
+            }
         }
+
+        outline.composite(renderer, encoder, target, viewport);
+        Ok(())
     }
 }
\ No newline at end of file
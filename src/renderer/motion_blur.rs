@@ -0,0 +1,319 @@
+use std::rc::Rc;
+
+use cgmath::Matrix4;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    motion_vectors::MotionFrame,
+    render_graph::TransientDesc,
+    render_target::{ColorTarget, RenderTarget},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+const FULLSCREEN_VERTEX_SHADER: &str = "./src/renderer/shaders/fullscreen.vert";
+const MOTION_BLUR_FRAGMENT_SHADER: &str = "./src/renderer/shaders/motion_blur.frag";
+
+/// Most samples taken along each pixel's motion, which has to match MAX_SAMPLES in
+/// motion_blur.frag
+const MAX_SAMPLES: u32 = 32;
+
+/// Tunables for motion blur
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MotionBlurConfig {
+    /// Fraction of the time between frames that the shutter is open for, ie. how much of each
+    /// pixel's motion since last frame it's blurred along. 0.5 is a typical film camera's.
+    pub shutter: f32,
+
+    /// Samples taken along each pixel's motion, up to 32. More gives smoother blur where things
+    /// move quickly.
+    pub samples: u32,
+}
+
+impl Default for MotionBlurConfig {
+    fn default() -> Self {
+        Self {
+            shutter: 0.5,
+            samples: 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct MotionBlurUniformData {
+    /// Transforms this frame's unjittered clip space in to last frame's
+    reproject: Matrix4<f32>,
+
+    /// Clip space offset the scene was drawn with in xy, zw are unused
+    jitter: [f32; 4],
+
+    /// Size of the rendered region in xy, and of the whole source texture in zw
+    region: [f32; 4],
+
+    /// Shutter and sample count from `MotionBlurConfig`, zw are unused
+    params: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for MotionBlurUniformData {}
+unsafe impl bytemuck::Zeroable for MotionBlurUniformData {}
+
+impl MotionBlurUniformData {
+    fn new(
+        config: &MotionBlurConfig,
+        frame: &MotionFrame,
+        region: winit::dpi::PhysicalSize<u32>,
+        source_size: winit::dpi::PhysicalSize<u32>,
+    ) -> Self {
+        let MotionBlurConfig { shutter, samples } = *config;
+        Self {
+            reproject: frame.reprojection(),
+            jitter: [frame.jitter.x, frame.jitter.y, 0.0, 0.0],
+            region: [
+                region.width as f32,
+                region.height as f32,
+                source_size.width as f32,
+                source_size.height as f32,
+            ],
+            params: [shutter.max(0.0), samples.clamp(1, MAX_SAMPLES) as f32, 0.0, 0.0],
+        }
+    }
+}
+
+/// Represents a render stage that smears the HDR scene along each pixel's motion since last
+/// frame, before it's tonemapped
+///
+/// Motion comes from the forward stage's motion vectors, which account for both the camera and
+/// each instance moving, and anything the forward stage didn't draw is reprojected from its
+/// depth instead. Each pixel is only blurred along its own motion, so the edges of a moving
+/// model blur over the background but not the other way around. It reads and writes render
+/// graph transients, binding them afresh each frame.
+///
+/// The scene's depth can't be read under MSAA, so the stage isn't created then.
+pub struct MotionBlurStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    sampler: Rc<wgpu::Sampler>,
+    uniform_buff: wgpu::Buffer,
+    config: MotionBlurConfig,
+}
+
+impl MotionBlurStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        config: MotionBlurConfig,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(FULLSCREEN_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(MOTION_BLUR_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<MotionBlurUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Motion blur stage uniform buffer"),
+        });
+
+        // The scene, motion vectors and the scene's depth
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+            },
+        };
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    texture_entry(1),
+                    texture_entry(2),
+                    texture_entry(3),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Motion blur stage bind group layout"),
+            });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&bind_group_layout],
+        });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: RenderTarget::COLOR_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+        });
+
+        // Samples along the motion land anywhere between texels
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        Ok(Self {
+            pipeline,
+            bind_group_layout,
+            sampler,
+            uniform_buff,
+            config,
+        })
+    }
+
+    /// Description of the texture this stage blurs the scene in to, for the given scene render
+    /// target
+    pub fn transient_desc(scene: &RenderTarget) -> TransientDesc {
+        TransientDesc {
+            size: scene.size,
+            format: RenderTarget::COLOR_FORMAT,
+        }
+    }
+
+    /// Blur the top-left `region` pixels of the HDR `source` in to `output` along the forward
+    /// stage's `motion_vectors`, for the given frame
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        frame: &MotionFrame,
+        source: &wgpu::TextureView,
+        motion_vectors: &ColorTarget,
+        output: &ColorTarget,
+        region: winit::dpi::PhysicalSize<u32>,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let uniform_data = MotionBlurUniformData::new(&self.config, frame, region, output.size);
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[uniform_data]),
+        );
+
+        let bind_group = renderer.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &self.uniform_buff,
+                        range: 0..std::mem::size_of::<MotionBlurUniformData>()
+                            as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&motion_vectors.view),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(
+                        &renderer.scene_target.depth_view,
+                    ),
+                },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+            label: Some("Motion blur stage bind group"),
+        });
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &output.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        // Keeps each fragment lined up with the texels it reads
+        rpass.set_viewport(0.0, 0.0, region.width as f32, region.height as f32, 0.0, 1.0);
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{SquareMatrix, Vector2, Vector3};
+    use winit::dpi::PhysicalSize;
+
+    use super::*;
+
+    #[test]
+    fn test_uniform_data() {
+        let frame = MotionFrame {
+            jitter: Vector2::new(0.25, -0.5),
+            view_proj: Matrix4::identity(),
+            prev_view_proj: Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0)),
+        };
+        let data = |shutter, samples| {
+            let config = MotionBlurConfig { shutter, samples };
+            let (region, source_size) = (PhysicalSize::new(640, 360), PhysicalSize::new(1280, 720));
+            MotionBlurUniformData::new(&config, &frame, region, source_size)
+        };
+
+        let packed = data(0.5, 8);
+        assert_eq!(packed.reproject, frame.prev_view_proj);
+        assert_eq!(packed.jitter, [0.25, -0.5, 0.0, 0.0]);
+        assert_eq!(packed.region, [640.0, 360.0, 1280.0, 720.0]);
+        assert_eq!(packed.params, [0.5, 8.0, 0.0, 0.0]);
+
+        // Out of range settings are clamped to what the shader can handle
+        assert_eq!(data(-1.0, 0).params, [0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(data(0.5, 1000).params, [0.5, MAX_SAMPLES as f32, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,175 @@
+use cgmath::{Matrix4, SquareMatrix, Vector2};
+
+use super::{
+    frame_packet::InstanceData,
+    render_graph::TransientDesc,
+    render_target::RenderTarget,
+    ModelId,
+};
+
+/// Format of the motion vectors the forward stage draws, in to targets of `transient_desc`
+///
+/// Each pixel holds the change in its texture coordinates since last frame in xy, and whether
+/// the forward stage drew anything there at all in w.
+pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Description of the motion vector target for the given scene render target
+pub fn transient_desc(scene: &RenderTarget) -> TransientDesc {
+    TransientDesc {
+        size: scene.size,
+        format: FORMAT,
+    }
+}
+
+/// The camera of a frame drawn with motion vectors, and where it was last frame
+#[derive(Clone, Copy, Debug)]
+pub struct MotionFrame {
+    /// Clip space offset that the projection is jittered by, which is only non-zero for TAA
+    pub jitter: Vector2<f32>,
+
+    /// This frame's unjittered projection and view
+    pub view_proj: Matrix4<f32>,
+
+    /// Last frame's unjittered projection and view, or this frame's if there was no last frame
+    pub prev_view_proj: Matrix4<f32>,
+}
+
+impl MotionFrame {
+    /// The frame's projection with the jitter applied
+    pub fn jittered_proj(&self, proj: Matrix4<f32>) -> Matrix4<f32> {
+        Matrix4::from_translation(self.jitter.extend(0.0)) * proj
+    }
+
+    /// Transforms this frame's unjittered clip space in to last frame's
+    pub fn reprojection(&self) -> Matrix4<f32> {
+        self.prev_view_proj * self.view_proj.invert().unwrap_or_else(Matrix4::identity)
+    }
+}
+
+/// Where an instance was last frame, drawn alongside its `InstanceData` for motion vectors
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PrevInstanceData {
+    /// Last frame's model matrix
+    pub model_matrix: Matrix4<f32>,
+}
+
+unsafe impl bytemuck::Pod for PrevInstanceData {}
+unsafe impl bytemuck::Zeroable for PrevInstanceData {}
+
+impl PrevInstanceData {
+    /// Takes the place of the normal matrix, which motion vectors don't need, as there aren't
+    /// enough vertex attributes for both. See `InstanceData::motion_vector_buffer_descriptor`.
+    pub fn vertex_buffer_descriptor<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        const FLOAT_SIZE: wgpu::BufferAddress = 4;
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: 0,
+                    shader_location: 8,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4,
+                    shader_location: 9,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 2,
+                    shader_location: 10,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: FLOAT_SIZE * 4 * 3,
+                    shader_location: 11,
+                },
+            ],
+        }
+    }
+}
+
+/// Last frame's model matrices for each of the frame packet's models, which instances are
+/// matched up with by their position in the frame packet
+///
+/// Instances have no identity of their own across frames, so a model is only taken to be the
+/// same as last frame's if it's at the same index with the same id and number of instances.
+/// Anything else is treated as not having moved.
+#[derive(Default)]
+pub struct InstanceHistory {
+    last_frame: Vec<(ModelId, Vec<Matrix4<f32>>)>,
+}
+
+impl InstanceHistory {
+    /// Where each instance of the frame packet's models was last frame, remembering where they
+    /// are now for the next
+    pub fn advance<'a>(
+        &mut self,
+        models: impl Iterator<Item = (ModelId, &'a [InstanceData])>,
+    ) -> Vec<Vec<PrevInstanceData>> {
+        let current: Vec<(ModelId, Vec<_>)> = models
+            .map(|(model_id, instances)| {
+                (model_id, instances.iter().map(|instance| instance.model_matrix).collect())
+            })
+            .collect();
+        let prev = current
+            .iter()
+            .enumerate()
+            .map(|(i, (model_id, matrices))| {
+                let last = self.last_frame.get(i).filter(|(last_id, last)| {
+                    last_id == model_id && last.len() == matrices.len()
+                });
+                let matrices = last.map_or(matrices, |(_, last)| last);
+                matrices.iter().map(|&model_matrix| PrevInstanceData { model_matrix }).collect()
+            })
+            .collect();
+        self.last_frame = current;
+        prev
+    }
+
+    /// Forget last frame, eg. after one drawn without motion vectors
+    pub fn clear(&mut self) {
+        self.last_frame.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+
+    use super::*;
+
+    fn instance(x: f32) -> InstanceData {
+        InstanceData::new(Matrix4::from_translation(Vector3::new(x, 0.0, 0.0)), Matrix4::identity())
+    }
+
+    #[test]
+    fn test_instance_history() {
+        let mut history = InstanceHistory::default();
+        let (a, b) = (ModelId(0), ModelId(1));
+        let prev_x = |prev: &Vec<Vec<PrevInstanceData>>| -> Vec<Vec<f32>> {
+            prev.iter().map(|model| model.iter().map(|p| p.model_matrix.w.x).collect()).collect()
+        };
+
+        // Nothing to go on for the first frame, so everything is where it is now
+        let first = [instance(1.0), instance(2.0)];
+        let prev = history.advance(vec![(a, &first[..])].into_iter());
+        assert_eq!(prev_x(&prev), vec![vec![1.0, 2.0]]);
+
+        let second = [instance(3.0), instance(4.0)];
+        let added = [instance(5.0)];
+        let prev = history.advance(vec![(a, &second[..]), (b, &added[..])].into_iter());
+        assert_eq!(prev_x(&prev), vec![vec![1.0, 2.0], vec![5.0]]);
+
+        // A model whose instances changed in number can't be matched up
+        let third = [instance(6.0)];
+        let prev = history.advance(vec![(a, &third[..]), (b, &added[..])].into_iter());
+        assert_eq!(prev_x(&prev), vec![vec![6.0], vec![5.0]]);
+
+        history.clear();
+        let prev = history.advance(vec![(b, &added[..])].into_iter());
+        assert_eq!(prev_x(&prev), vec![vec![5.0]]);
+    }
+}
@@ -0,0 +1,303 @@
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    render_target::{RenderTarget, Viewport},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+const FULLSCREEN_VERTEX_SHADER: &str = "./src/renderer/shaders/fullscreen.vert";
+const COMPOSITE_FRAGMENT_SHADER: &str = "./src/renderer/shaders/oit_composite.frag";
+
+/// How transparent surfaces are blended over the opaque scene
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransparencyMode {
+    /// Blend each instance over the scene in order, from back to front. Correct between
+    /// instances, but not between the triangles of a single one, and sorting costs CPU time.
+    #[default]
+    Sorted,
+
+    /// Weighted blended order-independent transparency, after McGuire and Bavoil. Nothing is
+    /// sorted, so this copes with many overlapping surfaces, but where they overlap their colors
+    /// are averaged by a depth based weight rather than layered exactly.
+    WeightedBlended,
+}
+
+/// A color texture that transparent surfaces are accumulated in to, which is resolved to a
+/// single sample under MSAA
+struct OitTexture {
+    view: wgpu::TextureView,
+    msaa_view: Option<wgpu::TextureView>,
+}
+
+impl OitTexture {
+    fn new(
+        device: &wgpu::Device,
+        target: &RenderTarget,
+        format: wgpu::TextureFormat,
+        label: &str,
+    ) -> Self {
+        let create = |sample_count, usage| {
+            device
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: Some(label),
+                    size: wgpu::Extent3d {
+                        width: target.size.width,
+                        height: target.size.height,
+                        depth: 1,
+                    },
+                    array_layer_count: 1,
+                    mip_level_count: 1,
+                    sample_count,
+                    dimension: wgpu::TextureDimension::D2,
+                    format,
+                    usage,
+                })
+                .create_default_view()
+        };
+
+        let view = create(
+            1,
+            wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        );
+        let msaa_view = if target.sample_count > 1 {
+            Some(create(target.sample_count, wgpu::TextureUsage::OUTPUT_ATTACHMENT))
+        } else {
+            None
+        };
+
+        Self { view, msaa_view }
+    }
+
+    fn attachment(
+        &self,
+        clear_color: wgpu::Color,
+    ) -> wgpu::RenderPassColorAttachmentDescriptor<'_> {
+        let (attachment, resolve_target) = match &self.msaa_view {
+            Some(msaa_view) => (msaa_view, Some(&self.view)),
+            None => (&self.view, None),
+        };
+
+        wgpu::RenderPassColorAttachmentDescriptor {
+            attachment,
+            resolve_target,
+            load_op: wgpu::LoadOp::Clear,
+            store_op: wgpu::StoreOp::Store,
+            clear_color,
+        }
+    }
+}
+
+/// Represents a render stage for weighted blended order-independent transparency
+///
+/// The forward stage draws every transparent surface, in any order, in to the accumulation
+/// targets started by `begin_accumulation`, which `composite` then blends over the scene.
+/// Only the scene target is drawn this way, the forward stage still sorts the transparent
+/// instances of anything else.
+pub struct OitStage {
+    composite_pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    sampler: Rc<wgpu::Sampler>,
+
+    /// Sum of each surface's premultiplied color and alpha, scaled by its weight
+    accum: OitTexture,
+
+    /// Product of one minus each surface's alpha, ie. how much of the scene still shows through
+    revealage: OitTexture,
+    bind_group: wgpu::BindGroup,
+}
+
+impl OitStage {
+    pub const ACCUM_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    pub const REVEALAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        target: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(FULLSCREEN_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(COMPOSITE_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+            },
+        };
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    texture_entry(0),
+                    texture_entry(1),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("OIT composite bind group layout"),
+            });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&bind_group_layout],
+        });
+
+        // The composite outputs the average transparent color with one minus the revealage as
+        // its alpha, so this covers the scene by however much of it the surfaces hide
+        let composite_pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: RenderTarget::COLOR_FORMAT,
+                color_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: target.sample_count,
+        });
+
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let (accum, revealage) = Self::create_textures(device, target);
+        let bind_group =
+            Self::create_bind_group(device, &bind_group_layout, &sampler, &accum, &revealage);
+
+        Ok(Self {
+            composite_pipeline,
+            bind_group_layout,
+            sampler,
+            accum,
+            revealage,
+            bind_group,
+        })
+    }
+
+    fn create_textures(device: &wgpu::Device, target: &RenderTarget) -> (OitTexture, OitTexture) {
+        (
+            OitTexture::new(device, target, Self::ACCUM_FORMAT, "OIT accumulation texture"),
+            OitTexture::new(device, target, Self::REVEALAGE_FORMAT, "OIT revealage texture"),
+        )
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        accum: &OitTexture,
+        revealage: &OitTexture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&accum.view),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&revealage.view),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("OIT composite bind group"),
+        })
+    }
+
+    /// Reallocate the accumulation targets to match a new scene target
+    pub fn set_target(&mut self, device: &wgpu::Device, target: &RenderTarget) {
+        let (accum, revealage) = Self::create_textures(device, target);
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.sampler,
+            &accum,
+            &revealage,
+        );
+        self.accum = accum;
+        self.revealage = revealage;
+    }
+
+    /// Begin a pass that draws transparent surfaces in to the cleared accumulation targets,
+    /// depth tested against, but not writing to, the target's opaque depth
+    pub fn begin_accumulation<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        target: &'a RenderTarget,
+    ) -> wgpu::RenderPass<'a> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                self.accum.attachment(wgpu::Color::TRANSPARENT),
+                self.revealage.attachment(wgpu::Color::WHITE),
+            ],
+            depth_stencil_attachment: Some(target.depth_attachment(wgpu::LoadOp::Load)),
+        })
+    }
+
+    /// Blend the accumulated surfaces over the viewport of the target's color
+    pub fn composite(
+        &self,
+        renderer: &Renderer,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &RenderTarget,
+        viewport: Viewport,
+    ) {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[target.color_attachment(wgpu::LoadOp::Load)],
+            depth_stencil_attachment: None,
+        });
+
+        viewport.apply(&mut rpass);
+        rpass.set_pipeline(&self.composite_pipeline);
+        rpass.set_bind_group(0, &self.bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+    }
+}
@@ -0,0 +1,194 @@
+use std::rc::Rc;
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    render_target::{ColorTarget, RenderTarget, Viewport},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+const FULLSCREEN_VERTEX_SHADER: &str = "./src/renderer/shaders/fullscreen.vert";
+const OUTLINE_FRAGMENT_SHADER: &str = "./src/renderer/shaders/outline.frag";
+
+/// Represents a render stage that outlines the frame packet's selected instances
+///
+/// The forward stage draws the selected instances in to the mask started by `begin_mask`, without
+/// depth testing so that they're outlined even when something is in front of them. `composite`
+/// then draws the outline around the edges of the mask over the scene. Like OIT, only the scene
+/// target is drawn this way.
+pub struct OutlineStage {
+    composite_pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    sampler: Rc<wgpu::Sampler>,
+
+    /// Non-zero wherever a selected instance covers the scene target
+    mask: ColorTarget,
+    bind_group: wgpu::BindGroup,
+}
+
+impl OutlineStage {
+    pub const MASK_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        target: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(FULLSCREEN_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(OUTLINE_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Outline bind group layout"),
+            });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&bind_group_layout],
+        });
+
+        let composite_pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: RenderTarget::COLOR_FORMAT,
+                color_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: target.sample_count,
+        });
+
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let mask = Self::create_mask(device, target);
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &sampler, &mask);
+
+        Ok(Self {
+            composite_pipeline,
+            bind_group_layout,
+            sampler,
+            mask,
+            bind_group,
+        })
+    }
+
+    fn create_mask(device: &wgpu::Device, target: &RenderTarget) -> ColorTarget {
+        ColorTarget::new(device, target.size, Self::MASK_FORMAT, "Selection mask texture")
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        mask: &ColorTarget,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&mask.view),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("Outline bind group"),
+        })
+    }
+
+    /// Reallocate the mask to match a new scene target
+    pub fn set_target(&mut self, device: &wgpu::Device, target: &RenderTarget) {
+        self.mask = Self::create_mask(device, target);
+        self.bind_group =
+            Self::create_bind_group(device, &self.bind_group_layout, &self.sampler, &self.mask);
+    }
+
+    /// Begin a pass that draws the selected instances in to the cleared mask
+    pub fn begin_mask<'a>(&'a self, encoder: &'a mut wgpu::CommandEncoder) -> wgpu::RenderPass<'a> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &self.mask.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::TRANSPARENT,
+            }],
+            depth_stencil_attachment: None,
+        })
+    }
+
+    /// Draw the outline around the mask over the viewport of the target's color
+    pub fn composite(
+        &self,
+        renderer: &Renderer,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &RenderTarget,
+        viewport: Viewport,
+    ) {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[target.color_attachment(wgpu::LoadOp::Load)],
+            depth_stencil_attachment: None,
+        });
+
+        viewport.apply(&mut rpass);
+        rpass.set_pipeline(&self.composite_pipeline);
+        rpass.set_bind_group(0, &self.bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+    }
+}
@@ -0,0 +1,287 @@
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    render_target::ColorTarget,
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+/// A type of color vision deficiency
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorDeficiency {
+    /// Missing long wavelength (red) cones
+    Protanopia,
+
+    /// Missing medium wavelength (green) cones
+    Deuteranopia,
+
+    /// Missing short wavelength (blue) cones
+    Tritanopia,
+}
+
+/// An accessibility filter applied to the final image, including the UI overlay
+///
+/// Serialized as a table of the `mode` and the `deficiency` it's for, as TOML has nothing like
+/// Rust's enums with data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "mode", content = "deficiency")]
+pub enum ColorFilter {
+    #[default]
+    None,
+
+    /// Show the image as it would be seen with the given deficiency
+    Simulate(ColorDeficiency),
+
+    /// Shift colors so that detail that would be lost to the given deficiency is still visible
+    Daltonize(ColorDeficiency),
+}
+
+/// User adjustable display calibration, applied to the final image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputCalibration {
+    /// Multiplier applied to linear color values
+    pub exposure: f32,
+
+    /// Offset added to linear color values, lifting (or crushing) the black level
+    pub brightness: f32,
+
+    /// Gamma of the display, used to encode the final linear color values
+    pub gamma: f32,
+}
+
+impl Default for OutputCalibration {
+    fn default() -> Self {
+        Self {
+            exposure: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct OutputUniformData {
+    color_filter: u32,
+    deficiency: u32,
+    exposure: f32,
+    brightness: f32,
+    gamma: f32,
+}
+
+unsafe impl bytemuck::Pod for OutputUniformData {}
+unsafe impl bytemuck::Zeroable for OutputUniformData {}
+
+/// Represents the final render stage, which copies the composited frame to the swapchain while
+/// applying any whole-screen color adjustments
+pub struct OutputRenderStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    bind_group: wgpu::BindGroup,
+    uniform_buff: wgpu::Buffer,
+    sampler: Rc<wgpu::Sampler>,
+}
+
+impl OutputRenderStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        source: &ColorTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/fullscreen.vert",
+                shaderc::ShaderKind::Vertex,
+            )
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/output.frag",
+                shaderc::ShaderKind::Fragment,
+            )
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<OutputUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Output stage uniform buffer"),
+        });
+
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Output stage bind group layout"),
+            });
+
+        let render_pipeline_layout =
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&bind_group_layout],
+            });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &render_pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: ColorTarget::LDR_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+        });
+
+        // The source is the same size as the output, so there's no filtering to be done
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let bind_group =
+            Self::create_bind_group(device, &bind_group_layout, &uniform_buff, &sampler, source);
+
+        Ok(Self {
+            pipeline,
+            bind_group_layout,
+            bind_group,
+            uniform_buff,
+            sampler,
+        })
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buff: &wgpu::Buffer,
+        sampler: &wgpu::Sampler,
+        source: &ColorTarget,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: uniform_buff,
+                        range: 0..std::mem::size_of::<OutputUniformData>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("Output stage bind group"),
+        })
+    }
+
+    /// Rebind this stage to a new source target, eg. after it has been reallocated
+    pub fn set_source(&mut self, device: &wgpu::Device, source: &ColorTarget) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniform_buff,
+            &self.sampler,
+            source,
+        );
+    }
+
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        color_filter: ColorFilter,
+        calibration: OutputCalibration,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+    ) {
+        let deficiency_index = |deficiency| match deficiency {
+            ColorDeficiency::Protanopia => 0,
+            ColorDeficiency::Deuteranopia => 1,
+            ColorDeficiency::Tritanopia => 2,
+        };
+        let (color_filter, deficiency) = match color_filter {
+            ColorFilter::None => (0, 0),
+            ColorFilter::Simulate(deficiency) => (1, deficiency_index(deficiency)),
+            ColorFilter::Daltonize(deficiency) => (2, deficiency_index(deficiency)),
+        };
+
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[OutputUniformData {
+                color_filter,
+                deficiency,
+                exposure: calibration.exposure,
+                brightness: calibration.brightness,
+                gamma: calibration.gamma,
+            }]),
+        );
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: output,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &self.bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use super::shader_features::ShaderFeatures;
+
+/// Where the set of used shader permutations is persisted between runs
+pub const PIPELINE_CACHE_PATH: &str = "./pipeline_cache.toml";
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct PipelineCacheFile {
+    /// `ShaderFeatures` bits of every permutation that has been used
+    permutations: BTreeSet<u32>,
+}
+
+/// Remembers which shader permutations were used in previous runs, so that their shaders can be
+/// compiled and pipelines created at startup instead of the first time something is drawn with
+/// them
+pub struct PipelineCache {
+    path: PathBuf,
+    file: PipelineCacheFile,
+
+    /// Whether a permutation has been recorded since the file was last written
+    dirty: bool,
+}
+
+impl PipelineCache {
+    /// Load the cache from the given path. A missing or unreadable file is treated as empty, as
+    /// the only cost of that is compiling permutations on first use.
+    pub async fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let file = match tokio::fs::read_to_string(path).await {
+            Ok(text) => toml::from_str(&text).unwrap_or_else(|e| {
+                warn!("Ignoring malformed pipeline cache {}: {}", path.display(), e);
+                PipelineCacheFile::default()
+            }),
+            Err(_) => PipelineCacheFile::default(),
+        };
+
+        Self {
+            path: path.to_owned(),
+            file,
+            dirty: false,
+        }
+    }
+
+    /// Every permutation that has been recorded, skipping any with bits this build doesn't know
+    pub fn permutations(&self) -> impl Iterator<Item = ShaderFeatures> + '_ {
+        self.file
+            .permutations
+            .iter()
+            .filter_map(|&bits| ShaderFeatures::from_bits(bits))
+    }
+
+    pub fn record(&mut self, features: ShaderFeatures) {
+        if self.file.permutations.insert(features.bits()) {
+            self.dirty = true;
+        }
+    }
+
+    /// Write the cache back to disk if anything new has been recorded
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        let text = match toml::to_string(&self.file) {
+            Ok(text) => text,
+            Err(e) => {
+                error!("Failed to serialize pipeline cache: {}", e);
+                return;
+            }
+        };
+        match std::fs::write(&self.path, text) {
+            Ok(()) => self.dirty = false,
+            Err(e) => warn!("Failed to write pipeline cache {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pipeline_cache_round_trip() {
+        let path = std::env::temp_dir().join("wgpu-test-pipeline-cache-round-trip.toml");
+        let _ = std::fs::remove_file(&path);
+
+        let mut cache = PipelineCache::load(&path).await;
+        assert_eq!(cache.permutations().count(), 0);
+
+        cache.record(ShaderFeatures::empty());
+        cache.record(ShaderFeatures::FOG | ShaderFeatures::SHADOWS);
+        cache.record(ShaderFeatures::empty());
+        cache.save();
+
+        let reloaded = PipelineCache::load(&path).await;
+        assert_eq!(
+            reloaded.permutations().collect::<Vec<_>>(),
+            vec![ShaderFeatures::empty(), ShaderFeatures::FOG | ShaderFeatures::SHADOWS]
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    render_graph::TransientDesc,
+    render_target::{ColorTarget, RenderTarget},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+/// How the HDR scene is mapped down to displayable colors
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tonemapper {
+    /// Filmic curve fitted to the ACES reference rendering transform, which keeps more contrast
+    /// and desaturates very bright colors
+    #[default]
+    Aces,
+
+    /// `c / (1 + c)`, which never clips but flattens out highlights
+    Reinhard,
+}
+
+impl Tonemapper {
+    /// Value of u_Tonemapper in tonemap.frag
+    fn shader_value(self) -> u32 {
+        match self {
+            Tonemapper::Aces => 0,
+            Tonemapper::Reinhard => 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct PostProcessUniformData {
+    tonemapper: u32,
+}
+
+unsafe impl bytemuck::Pod for PostProcessUniformData {}
+unsafe impl bytemuck::Zeroable for PostProcessUniformData {}
+
+/// Represents a render stage that turns the HDR scene in to a displayable image
+///
+/// The output is an LDR copy of the source with the same size and layout, so that only the
+/// rendered region of the source needs processing and later stages can treat it the same way.
+/// It's a render graph transient, so this stage only owns the source's bind group.
+pub struct PostProcessStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    bind_group: wgpu::BindGroup,
+    uniform_buff: wgpu::Buffer,
+    sampler: Rc<wgpu::Sampler>,
+}
+
+impl PostProcessStage {
+    pub const OUTPUT_FORMAT: wgpu::TextureFormat = ColorTarget::LDR_FORMAT;
+
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        source: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/fullscreen.vert",
+                shaderc::ShaderKind::Vertex,
+            )
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/tonemap.frag",
+                shaderc::ShaderKind::Fragment,
+            )
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<PostProcessUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Post process stage uniform buffer"),
+        });
+
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Post process stage bind group layout"),
+            });
+
+        let render_pipeline_layout =
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&bind_group_layout],
+            });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &render_pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: Self::OUTPUT_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+        });
+
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let bind_group = Self::create_bind_group(
+            device,
+            &bind_group_layout,
+            &uniform_buff,
+            &sampler,
+            &source.color_view,
+        );
+
+        Ok(Self {
+            pipeline,
+            bind_group_layout,
+            bind_group,
+            uniform_buff,
+            sampler,
+        })
+    }
+
+    /// The output's description as a render graph transient, for the given source
+    pub fn output_desc(source: &RenderTarget) -> TransientDesc {
+        TransientDesc {
+            size: source.size,
+            format: Self::OUTPUT_FORMAT,
+        }
+    }
+
+    /// Rebind this stage to a new source render target, eg. after it has been reallocated
+    pub fn set_source(&mut self, device: &wgpu::Device, source: &RenderTarget) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniform_buff,
+            &self.sampler,
+            &source.color_view,
+        );
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buff: &wgpu::Buffer,
+        sampler: &wgpu::Sampler,
+        source: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: uniform_buff,
+                        range: 0..std::mem::size_of::<PostProcessUniformData>()
+                            as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("Post process stage bind group"),
+        })
+    }
+
+    /// A bind group for processing some other render target with `draw_other`
+    pub fn bind_other(&self, device: &wgpu::Device, source: &RenderTarget) -> wgpu::BindGroup {
+        self.bind_view(device, &source.color_view)
+    }
+
+    /// Like `bind_other`, for an HDR texture laid out like the scene that isn't a render target,
+    /// eg. the output of an earlier pass over the scene
+    pub fn bind_view(&self, device: &wgpu::Device, source: &wgpu::TextureView) -> wgpu::BindGroup {
+        Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniform_buff,
+            &self.sampler,
+            source,
+        )
+    }
+
+    /// Process the top-left `region` pixels of the source in to the same region of `output`,
+    /// which is described by `output_desc`
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        tonemapper: Tonemapper,
+        output: &ColorTarget,
+        region: winit::dpi::PhysicalSize<u32>,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        self.draw_other(renderer, tonemapper, &self.bind_group, output, region, encoder);
+    }
+
+    /// Like `draw_frame`, but from the source bound by `bind_other` in to an output of the same
+    /// size as it
+    pub fn draw_other(
+        &self,
+        renderer: &Renderer,
+        tonemapper: Tonemapper,
+        bind_group: &wgpu::BindGroup,
+        output: &ColorTarget,
+        region: winit::dpi::PhysicalSize<u32>,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[PostProcessUniformData {
+                tonemapper: tonemapper.shader_value(),
+            }]),
+        );
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &output.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        // Keeps each fragment lined up with the source texel it reads
+        rpass.set_viewport(0.0, 0.0, region.width as f32, region.height as f32, 0.0, 1.0);
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+    }
+}
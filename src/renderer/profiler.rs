@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+/// Number of frames the rolling averages are taken over
+const ROLLING_WINDOW: usize = 120;
+
+/// Time spent in one named part of a frame
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileScope {
+    pub name: &'static str,
+
+    /// Time the CPU spent in the scope
+    pub cpu: Duration,
+
+    /// Time the GPU spent executing the commands recorded in the scope
+    ///
+    /// wgpu doesn't expose timestamp queries yet, so this is always None until it does.
+    pub gpu: Option<Duration>,
+}
+
+/// Every scope timed over a frame, in the order they ran
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameProfile {
+    pub scopes: Vec<ProfileScope>,
+}
+
+impl FrameProfile {
+    #[allow(unused)]
+    pub fn scope(&self, name: &str) -> Option<&ProfileScope> {
+        self.scopes.iter().find(|scope| scope.name == name)
+    }
+
+    /// Add `cpu` to the named scope, adding the scope on the end if it isn't there yet
+    fn add_cpu(&mut self, name: &'static str, cpu: Duration) {
+        match self.scopes.iter_mut().find(|scope| scope.name == name) {
+            Some(scope) => scope.cpu += cpu,
+            None => self.scopes.push(ProfileScope {
+                name,
+                cpu,
+                gpu: None,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for FrameProfile {
+    /// One scope per line
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, scope) in self.scopes.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {:.2} ms", scope.name, scope.cpu.as_secs_f64() * 1000.0)?;
+            if let Some(gpu) = scope.gpu {
+                write!(f, " ({:.2} ms GPU)", gpu.as_secs_f64() * 1000.0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Collects the scopes timed through each frame, keeping the last `ROLLING_WINDOW` frames
+#[derive(Default)]
+pub struct Profiler {
+    /// Scopes timed outside of the renderer since the last frame finished, eg. app ticks
+    pending: FrameProfile,
+
+    /// Most recent last
+    history: VecDeque<FrameProfile>,
+}
+
+impl Profiler {
+    /// Add CPU time spent outside of the renderer to the next frame's profile
+    ///
+    /// Scopes recorded more than once before the frame finishes, eg. several ticks between two
+    /// frames, are added together.
+    pub fn record_cpu(&mut self, name: &'static str, time: Duration) {
+        self.pending.add_cpu(name, time);
+    }
+
+    /// Complete a frame's profile from the renderer's stage timings, after anything recorded
+    /// since the last frame
+    pub fn finish_frame(&mut self, stage_timings: &[(&'static str, Duration)]) {
+        let mut profile = std::mem::take(&mut self.pending);
+        for &(name, time) in stage_timings {
+            profile.add_cpu(name, time);
+        }
+
+        if self.history.len() == ROLLING_WINDOW {
+            self.history.pop_front();
+        }
+        self.history.push_back(profile);
+    }
+
+    pub fn last_frame(&self) -> Option<&FrameProfile> {
+        self.history.back()
+    }
+
+    /// Each scope's average over the recent frames it ran in
+    pub fn rolling_average(&self) -> FrameProfile {
+        let mut totals = FrameProfile::default();
+        // Number of frames each of the totals' scopes ran in
+        let mut counts: Vec<u32> = Vec::new();
+        for profile in &self.history {
+            for scope in &profile.scopes {
+                totals.add_cpu(scope.name, scope.cpu);
+                counts.resize(totals.scopes.len(), 0);
+                if let Some(i) = totals.scopes.iter().position(|total| total.name == scope.name) {
+                    counts[i] += 1;
+                }
+            }
+        }
+
+        for (scope, count) in totals.scopes.iter_mut().zip(counts) {
+            scope.cpu /= count;
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_frame_profiles() {
+        let mut profiler = Profiler::default();
+        profiler.record_cpu("tick", millis(1));
+        profiler.record_cpu("tick", millis(2));
+        profiler.finish_frame(&[("shadow", millis(4)), ("scene", millis(6))]);
+
+        let last = profiler.last_frame().unwrap();
+        let names: Vec<_> = last.scopes.iter().map(|scope| scope.name).collect();
+        assert_eq!(names, vec!["tick", "shadow", "scene"]);
+        assert_eq!(last.scope("tick").unwrap().cpu, millis(3));
+
+        // The second frame has no ticks, which shouldn't drag the tick average down
+        profiler.finish_frame(&[("shadow", millis(8)), ("scene", millis(2))]);
+        let average = profiler.rolling_average();
+        assert_eq!(average.scope("tick").unwrap().cpu, millis(3));
+        assert_eq!(average.scope("shadow").unwrap().cpu, millis(6));
+        assert_eq!(average.scope("scene").unwrap().cpu, millis(4));
+    }
+
+    #[test]
+    fn test_rolling_window() {
+        let mut profiler = Profiler::default();
+        profiler.finish_frame(&[("scene", millis(100))]);
+        for _ in 0..ROLLING_WINDOW {
+            profiler.finish_frame(&[("scene", millis(1))]);
+        }
+        assert_eq!(profiler.rolling_average().scope("scene").unwrap().cpu, millis(1));
+    }
+}
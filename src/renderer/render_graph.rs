@@ -0,0 +1,333 @@
+use crate::error::Result;
+use super::render_target::ColorTarget;
+
+/// Handle to a resource in a `RenderGraph`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GraphResource(usize);
+
+/// Everything needed to allocate a transient texture, which textures are only shared between
+/// transients with equal descriptions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransientDesc {
+    pub size: winit::dpi::PhysicalSize<u32>,
+    pub format: wgpu::TextureFormat,
+}
+
+struct ResourceNode {
+    name: &'static str,
+
+    /// None for imported resources
+    transient: Option<TransientDesc>,
+}
+
+type RecordPass<'a, C> =
+    Box<dyn FnOnce(&mut C, &mut wgpu::CommandEncoder, &GraphTextures) -> Result<()> + 'a>;
+
+struct PassNode<'a, C> {
+    name: &'static str,
+    reads: Vec<GraphResource>,
+    writes: Vec<GraphResource>,
+    record: RecordPass<'a, C>,
+}
+
+/// The passes of a frame and the resources they read and write, from which it works out which
+/// passes need running, and allocates the transient textures passed between them
+///
+/// Resources are versioned by the order passes are added in, which is the order they run in. A
+/// pass reading a resource sees what the last pass added before it wrote there. Passes that both
+/// read and write a resource, eg. to draw over it, list it in both.
+///
+/// Imported resources outlive the frame, so passes writing them always run. Any other pass only
+/// runs if something that runs reads what it writes.
+pub struct RenderGraph<'a, C> {
+    resources: Vec<ResourceNode>,
+    passes: Vec<PassNode<'a, C>>,
+}
+
+/// The passes that run in a `RenderGraph`, and which textures its transients end up in
+#[derive(Debug, Default, PartialEq)]
+struct Schedule {
+    /// Indices of the passes to run, in the order to run them
+    order: Vec<usize>,
+
+    /// Index in to `slots` of the texture each resource is in, for the transients that are used
+    resource_slots: Vec<Option<usize>>,
+
+    /// Each texture that the transients are spread across, along with the name of the first
+    /// transient to use it
+    slots: Vec<(TransientDesc, &'static str)>,
+}
+
+impl<'a, C> RenderGraph<'a, C> {
+    pub fn new() -> Self {
+        Self {
+            resources: Vec::new(),
+            passes: Vec::new(),
+        }
+    }
+
+    /// A resource that lives on beyond the frame, eg. a stage's own render target or the
+    /// swapchain image, which passes access themselves
+    pub fn import(&mut self, name: &'static str) -> GraphResource {
+        self.resources.push(ResourceNode {
+            name,
+            transient: None,
+        });
+        GraphResource(self.resources.len() - 1)
+    }
+
+    /// A texture that only lives from the first pass using it to the last, which passes find in
+    /// the `GraphTextures` they're given
+    ///
+    /// Transients whose passes don't overlap may share the same texture.
+    pub fn create(&mut self, name: &'static str, desc: TransientDesc) -> GraphResource {
+        self.resources.push(ResourceNode {
+            name,
+            transient: Some(desc),
+        });
+        GraphResource(self.resources.len() - 1)
+    }
+
+    pub fn add_pass(
+        &mut self,
+        name: &'static str,
+        reads: &[GraphResource],
+        writes: &[GraphResource],
+        record: impl FnOnce(&mut C, &mut wgpu::CommandEncoder, &GraphTextures) -> Result<()> + 'a,
+    ) {
+        self.passes.push(PassNode {
+            name,
+            reads: reads.to_vec(),
+            writes: writes.to_vec(),
+            record: Box::new(record),
+        });
+    }
+
+    /// Work out which passes run, and which texture each transient goes in
+    fn schedule(&self) -> Schedule {
+        // The passes whose output each pass reads
+        let mut inputs = vec![Vec::new(); self.passes.len()];
+        let mut last_writer: Vec<Option<usize>> = vec![None; self.resources.len()];
+        for (i, pass) in self.passes.iter().enumerate() {
+            for &GraphResource(resource) in &pass.reads {
+                inputs[i].extend(last_writer[resource]);
+            }
+            for &GraphResource(resource) in &pass.writes {
+                last_writer[resource] = Some(i);
+            }
+        }
+
+        let mut live = vec![false; self.passes.len()];
+        let mut stack: Vec<usize> = (0..self.passes.len())
+            .filter(|&i| {
+                let imported = |&GraphResource(r): &_| self.resources[r].transient.is_none();
+                self.passes[i].writes.iter().any(imported)
+            })
+            .collect();
+        while let Some(pass) = stack.pop() {
+            if !live[pass] {
+                live[pass] = true;
+                stack.extend(&inputs[pass]);
+            }
+        }
+
+        let order: Vec<usize> = (0..self.passes.len()).filter(|&i| live[i]).collect();
+
+        // Each transient is live from the first pass using it to the last, and gets a texture
+        // that isn't in use over that span
+        let mut first_use = vec![None; self.resources.len()];
+        let mut last_use = vec![0; self.resources.len()];
+        for (position, &pass) in order.iter().enumerate() {
+            let pass = &self.passes[pass];
+            for &GraphResource(resource) in pass.reads.iter().chain(&pass.writes) {
+                first_use[resource].get_or_insert(position);
+                last_use[resource] = position;
+            }
+        }
+        let mut resource_slots = vec![None; self.resources.len()];
+        let mut slots: Vec<(TransientDesc, &'static str)> = Vec::new();
+        let mut free_slots: Vec<usize> = Vec::new();
+        for position in 0..order.len() {
+            for (resource, node) in self.resources.iter().enumerate() {
+                let desc = match node.transient {
+                    Some(desc) if first_use[resource] == Some(position) => desc,
+                    _ => continue,
+                };
+                let free = free_slots.iter().position(|&slot| slots[slot].0 == desc);
+                resource_slots[resource] = Some(match free {
+                    Some(free) => free_slots.swap_remove(free),
+                    None => {
+                        slots.push((desc, node.name));
+                        slots.len() - 1
+                    }
+                });
+            }
+
+            // Only freed once every transient the pass uses has a texture, so that its inputs
+            // and outputs never share one
+            for resource in 0..self.resources.len() {
+                if first_use[resource].is_some() && last_use[resource] == position {
+                    free_slots.extend(resource_slots[resource]);
+                }
+            }
+        }
+
+        Schedule {
+            order,
+            resource_slots,
+            slots,
+        }
+    }
+
+    /// Schedule the passes, and get the pool's textures ready for the transients
+    pub fn compile(self, device: &wgpu::Device, pool: &mut TransientPool) -> CompiledGraph<'a, C> {
+        let schedule = self.schedule();
+        pool.allocate(device, &schedule.slots);
+        CompiledGraph {
+            passes: self.passes,
+            schedule,
+        }
+    }
+}
+
+/// A `RenderGraph` ready to record, with the textures for its transients in a `TransientPool`
+pub struct CompiledGraph<'a, C> {
+    passes: Vec<PassNode<'a, C>>,
+    schedule: Schedule,
+}
+
+impl<C> CompiledGraph<'_, C> {
+    /// Record every pass that needs running in to the encoder, calling `on_pass` with the name
+    /// of each one after it's recorded
+    ///
+    /// The pool must be the one the graph was compiled with.
+    pub fn execute(
+        self,
+        context: &mut C,
+        pool: &TransientPool,
+        encoder: &mut wgpu::CommandEncoder,
+        mut on_pass: impl FnMut(&'static str),
+    ) -> Result<()> {
+        let textures = GraphTextures {
+            textures: self
+                .schedule
+                .resource_slots
+                .iter()
+                .map(|slot| slot.map(|slot| &pool.textures[slot].1))
+                .collect(),
+        };
+
+        let mut passes: Vec<_> = self.passes.into_iter().map(Some).collect();
+        for i in self.schedule.order {
+            let pass = passes[i].take().expect("Passes are only scheduled once");
+            (pass.record)(context, encoder, &textures)?;
+            on_pass(pass.name);
+        }
+        Ok(())
+    }
+}
+
+/// The textures of a `RenderGraph`'s transients, for passes to look up while recording
+pub struct GraphTextures<'p> {
+    textures: Vec<Option<&'p ColorTarget>>,
+}
+
+impl GraphTextures<'_> {
+    /// The texture of a transient used by the pass
+    pub fn get(&self, GraphResource(resource): GraphResource) -> &ColorTarget {
+        self.textures[resource].expect("Only transients used by a pass have graph textures")
+    }
+}
+
+/// The textures backing a `RenderGraph`'s transients, kept between frames so that they're only
+/// reallocated when the frame needs something different
+#[derive(Default)]
+pub struct TransientPool {
+    textures: Vec<(TransientDesc, ColorTarget)>,
+}
+
+impl TransientPool {
+    /// Make the pool exactly the given textures, in order, keeping whichever of the existing ones
+    /// it can
+    fn allocate(&mut self, device: &wgpu::Device, slots: &[(TransientDesc, &'static str)]) {
+        let mut existing = std::mem::take(&mut self.textures);
+        for &(desc, label) in slots {
+            let texture = match existing.iter().position(|(existing, _)| *existing == desc) {
+                Some(i) => existing.swap_remove(i).1,
+                None => ColorTarget::new(device, desc.size, desc.format, label),
+            };
+            self.textures.push((desc, texture));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(width: u32) -> TransientDesc {
+        TransientDesc {
+            size: winit::dpi::PhysicalSize { width, height: 1 },
+            format: wgpu::TextureFormat::Rgba8Unorm,
+        }
+    }
+
+    fn pass(graph: &mut RenderGraph<()>, reads: &[GraphResource], writes: &[GraphResource]) {
+        graph.add_pass("Test pass", reads, writes, |_, _, _| Ok(()));
+    }
+
+    #[test]
+    fn test_culls_unused_passes() {
+        let mut graph = RenderGraph::new();
+        let output = graph.import("Output");
+        let used = graph.create("Used", desc(1));
+        let unused = graph.create("Unused", desc(1));
+
+        pass(&mut graph, &[], &[used]);
+        pass(&mut graph, &[], &[unused]);
+        pass(&mut graph, &[unused], &[unused]);
+        pass(&mut graph, &[used], &[output]);
+        pass(&mut graph, &[output], &[output]);
+
+        let schedule = graph.schedule();
+        assert_eq!(schedule.order, vec![0, 3, 4]);
+        assert_eq!(schedule.resource_slots, vec![None, Some(0), None]);
+    }
+
+    #[test]
+    fn test_reads_see_earlier_writes() {
+        let mut graph = RenderGraph::new();
+        let output = graph.import("Output");
+        let transient = graph.create("Transient", desc(1));
+
+        // Only the first write is ever read, the later ones are overwritten or left unread
+        pass(&mut graph, &[], &[transient]);
+        pass(&mut graph, &[transient], &[output]);
+        pass(&mut graph, &[], &[transient]);
+        pass(&mut graph, &[], &[transient]);
+        pass(&mut graph, &[output], &[output]);
+
+        assert_eq!(graph.schedule().order, vec![0, 1, 4]);
+    }
+
+    #[test]
+    fn test_transients_share_textures() {
+        let mut graph = RenderGraph::new();
+        let output = graph.import("Output");
+        let a = graph.create("A", desc(1));
+        let b = graph.create("B", desc(1));
+        let c = graph.create("C", desc(1));
+        let d = graph.create("D", desc(2));
+
+        // A is finished with by the time C is written, but B overlaps both
+        pass(&mut graph, &[], &[a]);
+        pass(&mut graph, &[a], &[b]);
+        pass(&mut graph, &[b], &[c]);
+        pass(&mut graph, &[c], &[d]);
+        pass(&mut graph, &[d], &[output]);
+
+        let schedule = graph.schedule();
+        assert_eq!(schedule.resource_slots, vec![None, Some(0), Some(1), Some(0), Some(2)]);
+        assert_eq!(schedule.slots, vec![(desc(1), "A"), (desc(1), "B"), (desc(2), "D")]);
+    }
+}
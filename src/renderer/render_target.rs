@@ -0,0 +1,329 @@
+use super::frame_packet::ViewportRect;
+
+/// Which end of the depth range is nearest the camera
+///
+/// Reversing it puts the near plane at 1 and the far plane at 0, where floating point depth has
+/// the most precision to spare, which all but removes z-fighting far from the camera. The
+/// camera's projection must be reversed to match, see `Camera::reverse_z`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepthOrder {
+    Standard,
+    Reversed,
+}
+
+impl DepthOrder {
+    pub fn from_reversed(reversed: bool) -> Self {
+        if reversed {
+            Self::Reversed
+        } else {
+            Self::Standard
+        }
+    }
+
+    /// The depth of the far plane, which the depth buffer is cleared to
+    pub fn clear_depth(self) -> f32 {
+        match self {
+            Self::Standard => 1.0,
+            Self::Reversed => 0.0,
+        }
+    }
+
+    /// Adapt a depth comparison written for standard depth to this order
+    pub fn compare(self, compare: wgpu::CompareFunction) -> wgpu::CompareFunction {
+        use wgpu::CompareFunction::*;
+        match (self, compare) {
+            (Self::Standard, compare) => compare,
+            (Self::Reversed, Less) => Greater,
+            (Self::Reversed, LessEqual) => GreaterEqual,
+            (Self::Reversed, Greater) => Less,
+            (Self::Reversed, GreaterEqual) => LessEqual,
+            (Self::Reversed, compare) => compare,
+        }
+    }
+
+    /// Defines for shaders that read depth or place anything on the far plane
+    pub fn shader_defines(self) -> &'static [(&'static str, Option<&'static str>)] {
+        match self {
+            Self::Standard => &[],
+            Self::Reversed => &[("REVERSE_Z", None)],
+        }
+    }
+}
+
+/// An offscreen color + depth target that the 3D scene is rendered in to
+///
+/// Color is stored as linear HDR values, which are tonemapped by the post process stage.
+///
+/// The textures are allocated at the full output size, and the scene is rendered to a sub-region
+/// of them when rendering at a reduced resolution. This means changing the internal resolution
+/// never requires reallocating anything.
+///
+/// With MSAA the scene is drawn in to a multisampled color texture which is resolved in to
+/// `color_texture`, and the depth texture is multisampled to match.
+#[allow(unused)]
+pub struct RenderTarget {
+    pub size: winit::dpi::PhysicalSize<u32>,
+    pub sample_count: u32,
+    pub depth_order: DepthOrder,
+    pub color_texture: wgpu::Texture,
+    pub color_view: wgpu::TextureView,
+    pub msaa_color_texture: Option<wgpu::Texture>,
+    pub msaa_color_view: Option<wgpu::TextureView>,
+    pub depth_texture: wgpu::Texture,
+    pub depth_view: wgpu::TextureView,
+}
+
+impl RenderTarget {
+    pub const COLOR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+    pub fn new(
+        device: &wgpu::Device,
+        size: winit::dpi::PhysicalSize<u32>,
+        sample_count: u32,
+        depth_order: DepthOrder,
+    ) -> Self {
+        let extent = wgpu::Extent3d {
+            width: size.width,
+            height: size.height,
+            depth: 1,
+        };
+
+        let color_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Scene color texture"),
+            size: extent,
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::COLOR_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        });
+
+        let msaa_color_texture = if sample_count > 1 {
+            Some(device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Scene multisampled color texture"),
+                size: extent,
+                array_layer_count: 1,
+                mip_level_count: 1,
+                sample_count,
+                dimension: wgpu::TextureDimension::D2,
+                format: Self::COLOR_FORMAT,
+                usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            }))
+        } else {
+            None
+        };
+
+        // Multisampled textures can't be copied from, which rules out depth readback with MSAA
+        let depth_usage = if sample_count > 1 {
+            wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED
+        } else {
+            wgpu::TextureUsage::OUTPUT_ATTACHMENT
+                | wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::COPY_SRC
+        };
+        let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Scene depth texture"),
+            size: extent,
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::DEPTH_FORMAT,
+            usage: depth_usage,
+        });
+
+        let color_view = color_texture.create_default_view();
+        let msaa_color_view = msaa_color_texture
+            .as_ref()
+            .map(|texture| texture.create_default_view());
+        let depth_view = depth_texture.create_default_view();
+
+        Self {
+            size,
+            sample_count,
+            depth_order,
+            color_texture,
+            color_view,
+            msaa_color_texture,
+            msaa_color_view,
+            depth_texture,
+            depth_view,
+        }
+    }
+
+    /// The attachment to draw the scene's color in to, resolving to `color_view` under MSAA
+    pub fn color_attachment(
+        &self,
+        load_op: wgpu::LoadOp,
+    ) -> wgpu::RenderPassColorAttachmentDescriptor<'_> {
+        let (attachment, resolve_target) = match &self.msaa_color_view {
+            Some(msaa_color_view) => (msaa_color_view, Some(&self.color_view)),
+            None => (&self.color_view, None),
+        };
+
+        wgpu::RenderPassColorAttachmentDescriptor {
+            attachment,
+            resolve_target,
+            load_op,
+            store_op: wgpu::StoreOp::Store,
+            clear_color: wgpu::Color::BLACK,
+        }
+    }
+
+    /// The attachment to depth test against, cleared to the far plane if `load_op` is Clear
+    pub fn depth_attachment(
+        &self,
+        load_op: wgpu::LoadOp,
+    ) -> wgpu::RenderPassDepthStencilAttachmentDescriptor<'_> {
+        wgpu::RenderPassDepthStencilAttachmentDescriptor {
+            attachment: &self.depth_view,
+            depth_load_op: load_op,
+            depth_store_op: wgpu::StoreOp::Store,
+            clear_depth: self.depth_order.clear_depth(),
+            stencil_load_op: wgpu::LoadOp::Clear,
+            stencil_store_op: wgpu::StoreOp::Store,
+            clear_stencil: 0,
+        }
+    }
+}
+
+/// A rectangle of a render target in whole pixels, measured from its top left corner, that a
+/// pass draws in to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Viewport {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Viewport {
+    /// The top left `size` of the target, eg. the region the scene is drawn to at a reduced
+    /// internal resolution
+    pub fn from_size(size: winit::dpi::PhysicalSize<u32>) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: size.width,
+            height: size.height,
+        }
+    }
+
+    /// The pixels covered by `rect` within the top left `size` of the target
+    ///
+    /// Edges are rounded to the nearest pixel, so rects that share an edge share it exactly here
+    /// too. Each is at least a pixel wide and tall.
+    pub fn within(rect: ViewportRect, size: winit::dpi::PhysicalSize<u32>) -> Self {
+        let edge =
+            |fraction: f32, length: u32| ((fraction * length as f32).round() as u32).min(length);
+        let x = edge(rect.x, size.width).min(size.width.saturating_sub(1));
+        let y = edge(rect.y, size.height).min(size.height.saturating_sub(1));
+        let right = edge(rect.x + rect.width, size.width).max(x + 1);
+        let bottom = edge(rect.y + rect.height, size.height).max(y + 1);
+        Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+
+    /// Map clip space on to this rectangle, and discard anything drawn outside of it
+    pub fn apply(self, rpass: &mut wgpu::RenderPass) {
+        rpass.set_viewport(
+            self.x as f32,
+            self.y as f32,
+            self.width as f32,
+            self.height as f32,
+            0.0,
+            1.0,
+        );
+        rpass.set_scissor_rect(self.x, self.y, self.width, self.height);
+    }
+}
+
+/// An offscreen color-only target, used for intermediate results between full screen passes
+#[allow(unused)]
+pub struct ColorTarget {
+    pub size: winit::dpi::PhysicalSize<u32>,
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+}
+
+impl ColorTarget {
+    /// Format of the tonemapped targets that the UI is composited over, and of the swapchain
+    ///
+    /// Being sRGB, these are stored gamma encoded but read, written and blended as linear color,
+    /// so every stage works in linear space without encoding anything itself.
+    pub const LDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Bgra8UnormSrgb;
+
+    pub fn new(
+        device: &wgpu::Device,
+        size: winit::dpi::PhysicalSize<u32>,
+        format: wgpu::TextureFormat,
+        label: &str,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: size.width,
+                height: size.height,
+                depth: 1,
+            },
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            // Copyable so that the headless renderer can read its output back
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT
+                | wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::COPY_SRC,
+        });
+        let view = texture.create_default_view();
+
+        Self {
+            size,
+            texture,
+            view,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reversed_depth_order() {
+        let reversed = DepthOrder::Reversed;
+        assert_eq!(reversed.clear_depth(), 0.0);
+        assert_eq!(reversed.compare(wgpu::CompareFunction::Less), wgpu::CompareFunction::Greater);
+        assert_eq!(
+            reversed.compare(wgpu::CompareFunction::LessEqual),
+            wgpu::CompareFunction::GreaterEqual
+        );
+        assert_eq!(reversed.compare(wgpu::CompareFunction::Equal), wgpu::CompareFunction::Equal);
+        assert_eq!(
+            DepthOrder::Standard.compare(wgpu::CompareFunction::Less),
+            wgpu::CompareFunction::Less
+        );
+    }
+
+    #[test]
+    fn test_viewports_tile_columns() {
+        let size = winit::dpi::PhysicalSize::new(100, 50);
+        let viewports: Vec<_> = ViewportRect::columns(3)
+            .into_iter()
+            .map(|rect| Viewport::within(rect, size))
+            .collect();
+        assert_eq!(viewports[0], Viewport { x: 0, y: 0, width: 33, height: 50 });
+        assert_eq!(viewports[1], Viewport { x: 33, y: 0, width: 34, height: 50 });
+        assert_eq!(viewports[2], Viewport { x: 67, y: 0, width: 33, height: 50 });
+
+        let sliver = ViewportRect { x: 1.0, y: 0.0, width: 0.0, height: 1.0 };
+        assert_eq!(Viewport::within(sliver, size), Viewport { x: 99, y: 0, width: 1, height: 50 });
+    }
+}
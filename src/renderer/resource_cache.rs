@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+type BindGroupLayoutKey = Vec<(u32, wgpu::ShaderStage, wgpu::BindingType)>;
+type VertexBufferKey =
+    (wgpu::BufferAddress, wgpu::InputStepMode, Vec<wgpu::VertexAttributeDescriptor>);
+
+/// The parts of a `wgpu::SamplerDescriptor` that affect the sampler, in a hashable form
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct SamplerKey {
+    address_modes: [wgpu::AddressMode; 3],
+    mag_filter: wgpu::FilterMode,
+    min_filter: wgpu::FilterMode,
+    mipmap_filter: wgpu::FilterMode,
+    lod_min_clamp: u32,
+    lod_max_clamp: u32,
+    compare: wgpu::CompareFunction,
+}
+
+impl From<&wgpu::SamplerDescriptor> for SamplerKey {
+    fn from(desc: &wgpu::SamplerDescriptor) -> Self {
+        Self {
+            address_modes: [desc.address_mode_u, desc.address_mode_v, desc.address_mode_w],
+            mag_filter: desc.mag_filter,
+            min_filter: desc.min_filter,
+            mipmap_filter: desc.mipmap_filter,
+            lod_min_clamp: desc.lod_min_clamp.to_bits(),
+            lod_max_clamp: desc.lod_max_clamp.to_bits(),
+            compare: desc.compare,
+        }
+    }
+}
+
+/// Like `wgpu::RenderPipelineDescriptor`, but with the shaders given as SPIR-V so that pipelines
+/// can be told apart by what they run. Both shaders' entry points are `main`.
+pub struct RenderPipelineDesc<'a> {
+    /// Must have come from the same `ResourceCache`
+    pub layout: &'a wgpu::PipelineLayout,
+    pub vertex_shader: &'a [u32],
+    pub fragment_shader: Option<&'a [u32]>,
+    pub rasterization_state: Option<wgpu::RasterizationStateDescriptor>,
+    pub primitive_topology: wgpu::PrimitiveTopology,
+    pub color_states: &'a [wgpu::ColorStateDescriptor],
+    pub depth_stencil_state: Option<wgpu::DepthStencilStateDescriptor>,
+    pub vertex_state: wgpu::VertexStateDescriptor<'a>,
+    pub sample_count: u32,
+}
+
+/// Everything in a `RenderPipelineDesc` that affects the pipeline, in a hashable form
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct RenderPipelineKey {
+    /// Address of the layout, which is stable as this cache keeps every layout alive
+    layout: usize,
+    vertex_shader: Vec<u32>,
+    fragment_shader: Option<Vec<u32>>,
+    rasterization_state: Option<(wgpu::FrontFace, wgpu::CullMode, i32, u32, u32)>,
+    primitive_topology: wgpu::PrimitiveTopology,
+    color_states: Vec<wgpu::ColorStateDescriptor>,
+    depth_stencil_state: Option<wgpu::DepthStencilStateDescriptor>,
+    index_format: wgpu::IndexFormat,
+    vertex_buffers: Vec<VertexBufferKey>,
+    sample_count: u32,
+}
+
+impl From<&RenderPipelineDesc<'_>> for RenderPipelineKey {
+    fn from(desc: &RenderPipelineDesc) -> Self {
+        Self {
+            layout: desc.layout as *const wgpu::PipelineLayout as usize,
+            vertex_shader: desc.vertex_shader.to_vec(),
+            fragment_shader: desc.fragment_shader.map(<[u32]>::to_vec),
+            rasterization_state: desc.rasterization_state.as_ref().map(|state| {
+                (
+                    state.front_face,
+                    state.cull_mode,
+                    state.depth_bias,
+                    state.depth_bias_slope_scale.to_bits(),
+                    state.depth_bias_clamp.to_bits(),
+                )
+            }),
+            primitive_topology: desc.primitive_topology,
+            color_states: desc.color_states.to_vec(),
+            depth_stencil_state: desc.depth_stencil_state.clone(),
+            index_format: desc.vertex_state.index_format,
+            vertex_buffers: desc
+                .vertex_state
+                .vertex_buffers
+                .iter()
+                .map(|buffer| (buffer.stride, buffer.step_mode, buffer.attributes.to_vec()))
+                .collect(),
+            sample_count: desc.sample_count,
+        }
+    }
+}
+
+/// Like `wgpu::ComputePipelineDescriptor`, but with the shader given as SPIR-V so that pipelines
+/// can be told apart by what they run. The entry point is `main`.
+pub struct ComputePipelineDesc<'a> {
+    /// Must have come from the same `ResourceCache`
+    pub layout: &'a wgpu::PipelineLayout,
+    pub compute_shader: &'a [u32],
+}
+
+/// Hands out shared instances of GPU objects that are fully described by their descriptor, so
+/// that stages asking for identical layouts/samplers don't each create their own copy.
+///
+/// Objects are created with the label of whichever caller asked for them first.
+#[derive(Default)]
+pub struct ResourceCache {
+    bind_group_layouts: HashMap<BindGroupLayoutKey, Rc<wgpu::BindGroupLayout>>,
+    samplers: HashMap<SamplerKey, Rc<wgpu::Sampler>>,
+
+    /// Keyed by the addresses of the bind group layouts, which are stable as this cache keeps
+    /// every layout alive
+    pipeline_layouts: HashMap<Vec<usize>, Rc<wgpu::PipelineLayout>>,
+
+    render_pipelines: HashMap<RenderPipelineKey, Rc<wgpu::RenderPipeline>>,
+
+    /// Keyed by the address of the layout along with the shader
+    compute_pipelines: HashMap<(usize, Vec<u32>), Rc<wgpu::ComputePipeline>>,
+}
+
+impl ResourceCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind_group_layout(
+        &mut self,
+        device: &wgpu::Device,
+        desc: &wgpu::BindGroupLayoutDescriptor,
+    ) -> Rc<wgpu::BindGroupLayout> {
+        let key = desc
+            .bindings
+            .iter()
+            .map(|entry| (entry.binding, entry.visibility, entry.ty))
+            .collect();
+
+        self.bind_group_layouts
+            .entry(key)
+            .or_insert_with(|| Rc::new(device.create_bind_group_layout(desc)))
+            .clone()
+    }
+
+    pub fn sampler(
+        &mut self,
+        device: &wgpu::Device,
+        desc: &wgpu::SamplerDescriptor,
+    ) -> Rc<wgpu::Sampler> {
+        self.samplers
+            .entry(desc.into())
+            .or_insert_with(|| Rc::new(device.create_sampler(desc)))
+            .clone()
+    }
+
+    /// Every bind group layout in the descriptor must have come from this cache
+    pub fn pipeline_layout(
+        &mut self,
+        device: &wgpu::Device,
+        desc: &wgpu::PipelineLayoutDescriptor,
+    ) -> Rc<wgpu::PipelineLayout> {
+        let key = desc
+            .bind_group_layouts
+            .iter()
+            .map(|&layout| layout as *const wgpu::BindGroupLayout as usize)
+            .collect();
+
+        self.pipeline_layouts
+            .entry(key)
+            .or_insert_with(|| Rc::new(device.create_pipeline_layout(desc)))
+            .clone()
+    }
+
+    /// Create a render pipeline, or share an identical one that's already been created
+    ///
+    /// Pipelines stay in the cache after every stage has dropped them, so that switching back to
+    /// a setting doesn't rebuild them. Use `release_unused_pipelines` once they're known to be
+    /// stale, eg. after their shaders have been reloaded.
+    pub fn render_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        desc: &RenderPipelineDesc,
+    ) -> Rc<wgpu::RenderPipeline> {
+        self.render_pipelines
+            .entry(desc.into())
+            .or_insert_with(|| {
+                let vs_module = device.create_shader_module(desc.vertex_shader);
+                let fs_module =
+                    desc.fragment_shader.map(|spirv| device.create_shader_module(spirv));
+                Rc::new(device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: desc.layout,
+                    vertex_stage: wgpu::ProgrammableStageDescriptor {
+                        module: &vs_module,
+                        entry_point: "main",
+                    },
+                    fragment_stage: fs_module.as_ref().map(|module| {
+                        wgpu::ProgrammableStageDescriptor {
+                            module,
+                            entry_point: "main",
+                        }
+                    }),
+                    rasterization_state: desc.rasterization_state.clone(),
+                    primitive_topology: desc.primitive_topology,
+                    color_states: desc.color_states,
+                    depth_stencil_state: desc.depth_stencil_state.clone(),
+                    vertex_state: desc.vertex_state.clone(),
+                    sample_count: desc.sample_count,
+                    // Every sample is written, a mask of 0 would leave MSAA targets untouched
+                    sample_mask: !0,
+                    alpha_to_coverage_enabled: false,
+                }))
+            })
+            .clone()
+    }
+
+    /// Create a compute pipeline, or share an identical one that's already been created
+    ///
+    /// Like render pipelines, these stay in the cache until `release_unused_pipelines`.
+    pub fn compute_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        desc: &ComputePipelineDesc,
+    ) -> Rc<wgpu::ComputePipeline> {
+        let key = (
+            desc.layout as *const wgpu::PipelineLayout as usize,
+            desc.compute_shader.to_vec(),
+        );
+        self.compute_pipelines
+            .entry(key)
+            .or_insert_with(|| {
+                let module = device.create_shader_module(desc.compute_shader);
+                Rc::new(device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    layout: desc.layout,
+                    compute_stage: wgpu::ProgrammableStageDescriptor {
+                        module: &module,
+                        entry_point: "main",
+                    },
+                }))
+            })
+            .clone()
+    }
+
+    /// Drop every pipeline that nothing outside the cache is using any more
+    pub fn release_unused_pipelines(&mut self) {
+        self.render_pipelines.retain(|_, pipeline| Rc::strong_count(pipeline) > 1);
+        self.compute_pipelines.retain(|_, pipeline| Rc::strong_count(pipeline) > 1);
+    }
+}
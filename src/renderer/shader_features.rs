@@ -0,0 +1,78 @@
+bitflags::bitflags! {
+    /// Optional parts of the forward shader, each compiled in only when needed
+    ///
+    /// Every distinct combination is a separate permutation of the shader with its own pipeline,
+    /// so a model only pays for the features its material actually uses. Features that aren't
+    /// implemented yet have their bit reserved here so that the permutation keys stay stable as
+    /// they land.
+    pub struct ShaderFeatures: u32 {
+        /// Perturb the interpolated normal with a tangent space normal map
+        const NORMAL_MAP = 1 << 0;
+
+        /// Deform vertices by a weighted set of joint matrices
+        const SKINNING = 1 << 1;
+
+        /// Blend the shaded color toward a fog color with distance. Set for every model while
+        /// the frame packet has fog, rather than by the material.
+        const FOG = 1 << 2;
+
+        /// Attenuate lighting by sampling a shadow map
+        const SHADOWS = 1 << 3;
+
+        /// Output the material's alpha, for blending over what's behind the surface
+        const ALPHA_BLEND = 1 << 4;
+
+        /// Discard fragments whose alpha is below the material's cutoff
+        const ALPHA_MASK = 1 << 5;
+
+        /// Light the surface's ambient term from the material's lightmap, sampled with the second
+        /// texcoord set
+        const LIGHTMAP = 1 << 6;
+
+        /// Discard fragments on the far side of the frame packet's clip plane. Set for every
+        /// model while the frame packet has one, rather than by the material.
+        const CLIP_PLANE = 1 << 7;
+    }
+}
+
+impl Default for ShaderFeatures {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl ShaderFeatures {
+    /// Preprocessor definitions that enable these features in the shader source
+    pub fn shader_defines(self) -> Vec<(&'static str, Option<&'static str>)> {
+        const DEFINES: [(ShaderFeatures, &str); 8] = [
+            (ShaderFeatures::NORMAL_MAP, "FEATURE_NORMAL_MAP"),
+            (ShaderFeatures::SKINNING, "FEATURE_SKINNING"),
+            (ShaderFeatures::FOG, "FEATURE_FOG"),
+            (ShaderFeatures::SHADOWS, "FEATURE_SHADOWS"),
+            (ShaderFeatures::ALPHA_BLEND, "FEATURE_ALPHA_BLEND"),
+            (ShaderFeatures::ALPHA_MASK, "FEATURE_ALPHA_MASK"),
+            (ShaderFeatures::LIGHTMAP, "FEATURE_LIGHTMAP"),
+            (ShaderFeatures::CLIP_PLANE, "FEATURE_CLIP_PLANE"),
+        ];
+
+        DEFINES
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| (*name, None))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shader_defines() {
+        assert!(ShaderFeatures::empty().shader_defines().is_empty());
+        assert_eq!(
+            (ShaderFeatures::FOG | ShaderFeatures::NORMAL_MAP).shader_defines(),
+            vec![("FEATURE_NORMAL_MAP", None), ("FEATURE_FOG", None)]
+        );
+    }
+}
@@ -0,0 +1,63 @@
+#version 450
+
+// Integrates the GGX specular BRDF over the hemisphere for each view angle and roughness, as a
+// scale and bias to apply to the surface's reflectance at normal incidence. Split from the
+// prefiltered environment this way, image based specular lighting is two texture lookups.
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec2 o_color;
+
+const float PI = 3.14159265359;
+
+const uint SAMPLE_COUNT = 512;
+
+// The i'th of n points spread evenly over the unit square
+vec2 hammersley(uint i, uint n) {
+    return vec2(float(i) / float(n), float(bitfieldReverse(i)) * 2.3283064365386963e-10);
+}
+
+// Half vector around +Z, distributed by GGX with the given roughness
+vec3 importance_sample_ggx(vec2 xi, float roughness) {
+    float a = roughness * roughness;
+    float phi = 2.0 * PI * xi.x;
+    float cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
+
+// Smith's method with the Schlick-GGX approximation, remapped for image based lighting
+float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+    float a = roughness * roughness;
+    float k = a / 2.0;
+    float ggx_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    float ggx_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return ggx_v * ggx_l;
+}
+
+void main() {
+    // Kept off exactly zero, where the view is edge on and the integral blows up
+    float n_dot_v = max(v_TexCoord.x, 1e-3);
+    float roughness = v_TexCoord.y;
+    vec3 view_dir = vec3(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+
+    float scale = 0.0;
+    float bias = 0.0;
+    for (uint i = 0; i < SAMPLE_COUNT; i++) {
+        vec3 half_dir = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), roughness);
+        vec3 light_dir = normalize(2.0 * dot(view_dir, half_dir) * half_dir - view_dir);
+        float n_dot_l = max(light_dir.z, 0.0);
+        if (n_dot_l <= 0.0) {
+            continue;
+        }
+
+        float n_dot_h = max(half_dir.z, 0.0);
+        float v_dot_h = max(dot(view_dir, half_dir), 0.0);
+        float visibility = geometry_smith(n_dot_v, n_dot_l, roughness) * v_dot_h
+            / max(n_dot_h * n_dot_v, 1e-4);
+        float fresnel = pow(1.0 - v_dot_h, 5.0);
+        scale += (1.0 - fresnel) * visibility;
+        bias += fresnel * visibility;
+    }
+    o_color = vec2(scale, bias) / float(SAMPLE_COUNT);
+}
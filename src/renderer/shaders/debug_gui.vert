@@ -0,0 +1,14 @@
+#version 450
+
+layout(location = 0) in vec2 a_ScreenTopLeft;
+layout(location = 1) in vec2 a_ScreenSize;
+layout(location = 2) in vec4 a_Color;
+
+layout(location = 0) out vec4 v_Color;
+
+void main() {
+    vec2 corner = vec2(gl_VertexIndex & 1, gl_VertexIndex >> 1);
+    // Given gamma encoded, but blended in linear space
+    v_Color = vec4(pow(a_Color.rgb, vec3(2.2)), a_Color.a);
+    gl_Position = vec4(a_ScreenTopLeft + corner * a_ScreenSize, 0.0, 1.0);
+}
@@ -0,0 +1,64 @@
+#version 450
+
+// Projects a decal's texture on to the opaque scene inside its box, finding the surface behind
+// each pixel from the scene's depth.
+
+layout(location = 0) flat in vec4 v_InvTransform0;
+layout(location = 1) flat in vec4 v_InvTransform1;
+layout(location = 2) flat in vec4 v_InvTransform2;
+layout(location = 3) flat in vec4 v_InvTransform3;
+layout(location = 4) flat in vec4 v_AtlasRect;
+layout(location = 5) flat in vec4 v_Tint;
+layout(location = 6) flat in vec3 v_Axis;
+
+layout(location = 0) out vec4 o_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_ViewProj;
+    mat4 u_InvViewProj;
+    // Size of the rendered region in xy, zw are unused
+    vec4 u_Region;
+    // Clip space offset the scene was drawn with this frame in xy, zw are unused
+    vec4 u_Jitter;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_Depth;
+layout(set = 0, binding = 2) uniform sampler s_Depth;
+
+layout(set = 1, binding = 0) uniform texture2D t_Atlas;
+layout(set = 1, binding = 1) uniform sampler s_Atlas;
+
+// Surfaces facing further than this from the projection axis, as the cosine of the angle between
+// them, fade out rather than having the texture smeared along them
+const float FADE_START = 0.5;
+const float FADE_END = 0.2;
+
+void main() {
+    // The viewport covers exactly the rendered region, so fragments line up with its texels
+    vec2 uv = gl_FragCoord.xy / u_Region.xy;
+    float depth = texelFetch(sampler2D(t_Depth, s_Depth), ivec2(gl_FragCoord.xy), 0).r;
+    vec2 ndc = vec2(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0) - u_Jitter.xy;
+    vec4 world = u_InvViewProj * vec4(ndc, depth, 1.0);
+    vec3 position = world.xyz / world.w;
+
+    // Taken before anything is discarded, while every fragment of the quad is still running
+    vec3 normal = normalize(cross(dFdx(position), dFdy(position)));
+    float facing = smoothstep(FADE_END, FADE_START, abs(dot(normal, v_Axis)));
+
+#ifdef REVERSE_Z
+    bool background = depth == 0.0;
+#else
+    bool background = depth == 1.0;
+#endif
+    mat4 inv_transform = mat4(v_InvTransform0, v_InvTransform1, v_InvTransform2, v_InvTransform3);
+    vec3 local = (inv_transform * vec4(position, 1.0)).xyz;
+    if (background || any(greaterThan(abs(local), vec3(0.5)))) {
+        discard;
+    }
+
+    vec2 decal_uv = vec2(local.x + 0.5, 0.5 - local.y);
+    vec2 atlas_coord = v_AtlasRect.xy + decal_uv * v_AtlasRect.zw;
+    // Atlases have no mips, so there's no need for derivatives that discarding left undefined
+    o_color = textureLod(sampler2D(t_Atlas, s_Atlas), atlas_coord, 0.0) * v_Tint;
+    o_color.a *= facing;
+}
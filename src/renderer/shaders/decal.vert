@@ -0,0 +1,56 @@
+#version 450
+
+layout(location = 0) in vec4 a_Transform0;
+layout(location = 1) in vec4 a_Transform1;
+layout(location = 2) in vec4 a_Transform2;
+layout(location = 3) in vec4 a_Transform3;
+layout(location = 4) in vec4 a_InvTransform0;
+layout(location = 5) in vec4 a_InvTransform1;
+layout(location = 6) in vec4 a_InvTransform2;
+layout(location = 7) in vec4 a_InvTransform3;
+layout(location = 8) in vec4 a_AtlasRect;
+layout(location = 9) in vec4 a_Tint;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_ViewProj;
+    mat4 u_InvViewProj;
+    // Size of the rendered region in xy, zw are unused
+    vec4 u_Region;
+    // Clip space offset the scene was drawn with this frame in xy, zw are unused
+    vec4 u_Jitter;
+};
+
+layout(location = 0) flat out vec4 v_InvTransform0;
+layout(location = 1) flat out vec4 v_InvTransform1;
+layout(location = 2) flat out vec4 v_InvTransform2;
+layout(location = 3) flat out vec4 v_InvTransform3;
+layout(location = 4) flat out vec4 v_AtlasRect;
+layout(location = 5) flat out vec4 v_Tint;
+layout(location = 6) flat out vec3 v_Axis;
+
+// Corners of each face of the unit cube, counter-clockwise seen from outside it. Each index's
+// bits select the corner's x, y and z, from least to most significant.
+const int INDICES[36] = int[](
+    0, 4, 6, 0, 6, 2,
+    1, 7, 5, 1, 3, 7,
+    0, 1, 5, 0, 5, 4,
+    2, 7, 3, 2, 6, 7,
+    0, 2, 3, 0, 3, 1,
+    4, 7, 6, 4, 5, 7
+);
+
+void main() {
+    int corner = INDICES[gl_VertexIndex];
+    vec3 position = vec3(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1) - 0.5;
+
+    mat4 transform = mat4(a_Transform0, a_Transform1, a_Transform2, a_Transform3);
+    v_InvTransform0 = a_InvTransform0;
+    v_InvTransform1 = a_InvTransform1;
+    v_InvTransform2 = a_InvTransform2;
+    v_InvTransform3 = a_InvTransform3;
+    v_AtlasRect = a_AtlasRect;
+    v_Tint = a_Tint;
+    v_Axis = normalize(mat3(transform)[2]);
+
+    gl_Position = u_ViewProj * transform * vec4(position, 1.0);
+}
@@ -0,0 +1,164 @@
+#version 450
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_View;
+    mat4 u_Proj;
+    mat4 u_LightViewProj;
+    // View space direction towards the directional light, w is unused
+    vec4 u_SunDirection;
+    // Linear RGB color premultiplied by intensity, w is unused
+    vec4 u_SunColor;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_ShadowMap;
+layout(set = 0, binding = 2) uniform samplerShadow s_ShadowMap;
+
+layout(set = 1, binding = 0) uniform DeferredLocals {
+    mat4 u_InvProj;
+    mat4 u_ViewToLight;
+};
+
+layout(set = 1, binding = 1) uniform texture2D t_Albedo;
+layout(set = 1, binding = 2) uniform texture2D t_Normal;
+layout(set = 1, binding = 3) uniform texture2D t_Material;
+layout(set = 1, binding = 4) uniform texture2D t_Depth;
+layout(set = 1, binding = 5) uniform sampler s_GBuffer;
+layout(set = 1, binding = 6) uniform texture2D t_Emissive;
+
+// Values of Light.params.x
+const uint LIGHT_POINT = 0;
+const uint LIGHT_SPOT = 1;
+const uint LIGHT_DIRECTIONAL = 2;
+
+struct Light {
+    // View space position, w is unused
+    vec4 position;
+    // View space unit direction the light travels in, w is unused
+    vec4 direction;
+    // Linear RGB color in xyz, power in w
+    vec4 color;
+    // Kind of light in x, cosines of a spot light's inner and outer cone angles in y and z
+    vec4 params;
+};
+
+// The header is padded out to 16 bytes so that this is laid out the same under std140 and std430
+#ifdef LIGHTS_UNIFORM_ARRAY
+layout(set = 2, binding = 0) uniform Lights {
+    uint u_LightCount;
+    Light u_Lights[MAX_UNIFORM_LIGHTS];
+};
+#else
+layout(set = 2, binding = 0) readonly buffer Lights {
+    uint u_LightCount;
+    Light u_Lights[];
+};
+#endif
+
+// Must match the value in gbuffer.frag
+const float MAX_SHININESS = 128.0;
+
+// Fraction of the directional light reaching a point, from a 3x3 PCF of the shadow map
+float sun_visibility(vec4 shadow_coord) {
+    vec3 coord = shadow_coord.xyz / shadow_coord.w;
+    // Clip space y is up, texture v is down
+    vec2 uv = vec2(coord.x * 0.5 + 0.5, 0.5 - coord.y * 0.5);
+
+    // Anything outside of the shadow map's coverage is lit
+    if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))) || coord.z > 1.0) {
+        return 1.0;
+    }
+
+    vec2 texel_size = 1.0 / vec2(textureSize(sampler2DShadow(t_ShadowMap, s_ShadowMap), 0));
+    float visibility = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            vec3 coord_offset = vec3(vec2(x, y) * texel_size, 0.0);
+            visibility += texture(
+                sampler2DShadow(t_ShadowMap, s_ShadowMap),
+                vec3(uv, coord.z) + coord_offset
+            );
+        }
+    }
+    return visibility / 9.0;
+}
+
+void main() {
+    // The G-buffer is at least as large as the viewport and drawn from its top-left corner, so
+    // fragment coordinates address it directly
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+
+    float depth = texelFetch(sampler2D(t_Depth, s_GBuffer), texel, 0).r;
+#ifdef REVERSE_Z
+    if (depth <= 0.0) {
+#else
+    if (depth >= 1.0) {
+#endif
+        // Nothing was drawn here, match the forward path's clear color
+        o_color = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+
+    vec4 clip_position = vec4(v_TexCoord.x * 2.0 - 1.0, 1.0 - v_TexCoord.y * 2.0, depth, 1.0);
+    vec4 view_position = u_InvProj * clip_position;
+    vec3 position = view_position.xyz / view_position.w;
+
+    vec3 base_color = texelFetch(sampler2D(t_Albedo, s_GBuffer), texel, 0).rgb;
+    vec3 normal = normalize(texelFetch(sampler2D(t_Normal, s_GBuffer), texel, 0).xyz);
+    vec4 material = texelFetch(sampler2D(t_Material, s_GBuffer), texel, 0);
+    float specular_strength = material.r;
+    float shininess = material.g * MAX_SHININESS;
+
+    vec3 view_dir = normalize(-position);
+
+    // Emitted light isn't affected by any of the lighting below, and can be well over 1.0
+    vec3 emissive = texelFetch(sampler2D(t_Emissive, s_GBuffer), texel, 0).rgb;
+    vec3 colorLinear = base_color * 0.02 + emissive;
+
+    vec3 sun_dir = normalize(u_SunDirection.xyz);
+    float sun_lambertian = max(dot(sun_dir, normal), 0.0);
+    float sun_specular = specular_strength
+        * pow(max(dot(normalize(sun_dir + view_dir), normal), 0.0), shininess);
+    float sun_light = sun_visibility(u_ViewToLight * vec4(position, 1.0));
+    colorLinear += base_color * (sun_lambertian + sun_specular) * u_SunColor.rgb * sun_light;
+
+    for (uint i = 0; i < u_LightCount; i++) {
+        Light light = u_Lights[i];
+        uint kind = uint(light.params.x);
+        vec3 light_color = light.color.rgb;
+        float light_power = light.color.w;
+
+        vec3 light_dir;
+        float attenuation;
+        if (kind == LIGHT_DIRECTIONAL) {
+            light_dir = -light.direction.xyz;
+            attenuation = 1.0;
+        } else {
+            vec3 to_light = light.position.xyz - position;
+            float light_distance = length(to_light);
+            light_dir = to_light / light_distance;
+            attenuation = 1.0 / light_distance;
+
+            if (kind == LIGHT_SPOT) {
+                // Fade out between the inner and outer cone angles
+                float cos_angle = dot(-light_dir, light.direction.xyz);
+                attenuation *= smoothstep(light.params.z, light.params.y, cos_angle);
+            }
+        }
+
+        vec3 half_dir = normalize(light_dir + view_dir);
+
+        float lambertian = max(dot(light_dir, normal), 0.0);
+
+        float spec_angle = max(dot(half_dir, normal), 0.0);
+        float specular = specular_strength * pow(spec_angle, shininess);
+
+        colorLinear += base_color * (lambertian + specular) * light_color * light_power * attenuation;
+    }
+
+    // Left linear and unbounded, tonemapping happens in a later pass
+    o_color = vec4(colorLinear, 1.0);
+}
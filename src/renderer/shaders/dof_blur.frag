@@ -0,0 +1,54 @@
+#version 450
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+// Taps either side of the center
+const int TAPS = 8;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_InvProj;
+    // Focus distance in meters, then aperture and max blur radius in pixels, w is unused
+    vec4 u_Params;
+    // Size of the rendered region of the scene in xy, zw are unused
+    vec4 u_Region;
+};
+
+// Color with the circle of confusion in alpha, from dof_coc.frag or the other blur direction
+layout(set = 0, binding = 1) uniform texture2D t_Source;
+layout(set = 0, binding = 3) uniform sampler s_Texel;
+
+void main() {
+#ifdef VERTICAL
+    ivec2 axis = ivec2(0, 1);
+#else
+    ivec2 axis = ivec2(1, 0);
+#endif
+
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    ivec2 max_texel = ivec2(u_Region.xy) - 1;
+    vec4 center = texelFetch(sampler2D(t_Source, s_Texel), texel, 0);
+
+    // Taps spread over the largest possible blur, and each only counts where both its own circle
+    // of confusion and this pixel's reach across the gap between them. That way in focus pixels
+    // neither blur nor bleed in to the blurred areas around them.
+    float spacing = max(u_Params.z / float(TAPS), 1.0);
+    vec3 sum = center.rgb;
+    float total_weight = 1.0;
+    for (int i = -TAPS; i <= TAPS; i++) {
+        if (i == 0) {
+            continue;
+        }
+        float offset = float(i) * spacing;
+        ivec2 tap_texel = clamp(texel + axis * int(round(offset)), ivec2(0), max_texel);
+        vec4 tap = texelFetch(sampler2D(t_Source, s_Texel), tap_texel, 0);
+
+        float reach = min(tap.a, center.a);
+        float weight = clamp(reach - abs(offset) + 1.0, 0.0, 1.0);
+        sum += tap.rgb * weight;
+        total_weight += weight;
+    }
+
+    o_color = vec4(sum / total_weight, center.a);
+}
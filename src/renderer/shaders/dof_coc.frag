@@ -0,0 +1,35 @@
+#version 450
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    // Transforms clip space back in to view space, to find each pixel's distance from depth
+    mat4 u_InvProj;
+    // Focus distance in meters, then aperture and max blur radius in pixels, w is unused
+    vec4 u_Params;
+    // Size of the rendered region of the scene in xy, zw are unused
+    vec4 u_Region;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_Scene;
+layout(set = 0, binding = 2) uniform texture2D t_Depth;
+layout(set = 0, binding = 3) uniform sampler s_Texel;
+
+void main() {
+    // The viewport covers exactly the rendered region of the scene, so fragments line up with its
+    // texels
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    vec3 color = texelFetch(sampler2D(t_Scene, s_Texel), texel, 0).rgb;
+    float depth = texelFetch(sampler2D(t_Depth, s_Texel), texel, 0).r;
+
+    // One over the distance along the view direction, which stays finite for the far plane of an
+    // infinite projection where the distance itself wouldn't
+    vec4 position = u_InvProj * vec4(0.0, 0.0, depth, 1.0);
+    float inv_distance = max(-position.w / position.z, 0.0);
+
+    // Radius of the circle of confusion in pixels, kept in alpha for the blur and composite
+    float coc = u_Params.y * abs(1.0 - u_Params.x * inv_distance);
+    o_color = vec4(color, min(coc, u_Params.z));
+}
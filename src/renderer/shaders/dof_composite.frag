@@ -0,0 +1,27 @@
+#version 450
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_InvProj;
+    vec4 u_Params;
+    vec4 u_Region;
+};
+
+// The unblurred scene with the circle of confusion in alpha, from dof_coc.frag
+layout(set = 0, binding = 1) uniform texture2D t_Sharp;
+layout(set = 0, binding = 2) uniform texture2D t_Blurred;
+layout(set = 0, binding = 3) uniform sampler s_Texel;
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    vec4 sharp = texelFetch(sampler2D(t_Sharp, s_Texel), texel, 0);
+    vec3 blurred = texelFetch(sampler2D(t_Blurred, s_Texel), texel, 0).rgb;
+
+    // Anything blurred by under half a pixel is left as it was, fading in to the blurred copy
+    // over the next pixel of blur
+    float t = smoothstep(0.5, 1.5, sharp.a);
+    o_color = vec4(mix(sharp.rgb, blurred, t), 1.0);
+}
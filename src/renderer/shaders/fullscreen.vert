@@ -0,0 +1,10 @@
+#version 450
+
+layout(location = 0) out vec2 v_TexCoord;
+
+void main() {
+    // Single triangle that covers the whole of clip space, with texture coordinates that run 0..1
+    // across the visible region.
+    v_TexCoord = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(v_TexCoord.x * 2.0 - 1.0, 1.0 - v_TexCoord.y * 2.0, 0.0, 1.0);
+}
\ No newline at end of file
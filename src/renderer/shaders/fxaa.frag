@@ -0,0 +1,136 @@
+#version 450
+
+// Fast approximate anti-aliasing, after FXAA 3.11. Edges are found from the contrast in luma
+// around each pixel, then followed along their length to work out how far towards the other side
+// of the edge the pixel should be blended.
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    // Size of the rendered region of the source in xy, and of the whole source texture in zw
+    vec4 u_Region;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_Source;
+layout(set = 0, binding = 2) uniform sampler s_Source;
+
+// Contrast below which a pixel isn't treated as part of an edge, relative to its brightest
+// neighbour, and absolute for dark areas
+const float EDGE_THRESHOLD = 0.125;
+const float EDGE_THRESHOLD_MIN = 0.0312;
+
+// How strongly single pixel details are blended with their neighbours
+const float SUBPIXEL_QUALITY = 0.75;
+
+// How many pixels edges are followed for in each direction
+const int SEARCH_STEPS = 12;
+
+vec3 sample_source(vec2 position) {
+    // Keep to the rendered region, rather than filtering in whatever is left beyond it
+    vec2 clamped = clamp(position, vec2(0.5), u_Region.xy - 0.5);
+    return textureLod(sampler2D(t_Source, s_Source), clamped / u_Region.zw, 0.0).rgb;
+}
+
+// The source is sRGB, so it reads back as linear color. Edges are judged closer to how they're
+// seen, in roughly gamma encoded luma.
+float luma(vec3 color) {
+    return sqrt(dot(color, vec3(0.299, 0.587, 0.114)));
+}
+
+float luma_at(vec2 position) {
+    return luma(sample_source(position));
+}
+
+void main() {
+    // The viewport covers exactly the rendered region, so this is the pixel's center
+    vec2 position = gl_FragCoord.xy;
+    vec3 color = sample_source(position);
+
+    float luma_m = luma(color);
+    float luma_n = luma_at(position + vec2(0.0, -1.0));
+    float luma_s = luma_at(position + vec2(0.0, 1.0));
+    float luma_w = luma_at(position + vec2(-1.0, 0.0));
+    float luma_e = luma_at(position + vec2(1.0, 0.0));
+
+    float luma_min = min(luma_m, min(min(luma_n, luma_s), min(luma_w, luma_e)));
+    float luma_max = max(luma_m, max(max(luma_n, luma_s), max(luma_w, luma_e)));
+    float luma_range = luma_max - luma_min;
+    if (luma_range < max(EDGE_THRESHOLD_MIN, luma_max * EDGE_THRESHOLD)) {
+        o_color = vec4(color, 1.0);
+        return;
+    }
+
+    float luma_nw = luma_at(position + vec2(-1.0, -1.0));
+    float luma_ne = luma_at(position + vec2(1.0, -1.0));
+    float luma_sw = luma_at(position + vec2(-1.0, 1.0));
+    float luma_se = luma_at(position + vec2(1.0, 1.0));
+
+    // Horizontal edges change the most going up and down, and vertical ones going across
+    float horizontal_change = abs(luma_nw + luma_ne - 2.0 * luma_n)
+        + 2.0 * abs(luma_w + luma_e - 2.0 * luma_m)
+        + abs(luma_sw + luma_se - 2.0 * luma_s);
+    float vertical_change = abs(luma_nw + luma_sw - 2.0 * luma_w)
+        + 2.0 * abs(luma_n + luma_s - 2.0 * luma_m)
+        + abs(luma_ne + luma_se - 2.0 * luma_e);
+    bool horizontal = horizontal_change >= vertical_change;
+
+    // Work out which side of the pixel the edge is on, from whichever neighbour across it differs
+    // the most
+    float luma_before = horizontal ? luma_n : luma_w;
+    float luma_after = horizontal ? luma_s : luma_e;
+    float gradient_before = abs(luma_before - luma_m);
+    float gradient_after = abs(luma_after - luma_m);
+    bool edge_before = gradient_before >= gradient_after;
+    float gradient_threshold = 0.25 * max(gradient_before, gradient_after);
+    float edge_luma = 0.5 * (luma_m + (edge_before ? luma_before : luma_after));
+
+    vec2 across = horizontal ? vec2(0.0, 1.0) : vec2(1.0, 0.0);
+    vec2 along = horizontal ? vec2(1.0, 0.0) : vec2(0.0, 1.0);
+    float across_sign = edge_before ? -1.0 : 1.0;
+
+    // Follow the edge both ways from halfway across to the neighbour, until the luma along it
+    // stops matching
+    vec2 edge = position + across * (0.5 * across_sign);
+    vec2 end_neg = edge - along;
+    vec2 end_pos = edge + along;
+    float delta_neg = luma_at(end_neg) - edge_luma;
+    float delta_pos = luma_at(end_pos) - edge_luma;
+    bool done_neg = abs(delta_neg) >= gradient_threshold;
+    bool done_pos = abs(delta_pos) >= gradient_threshold;
+    for (int i = 1; i < SEARCH_STEPS && !(done_neg && done_pos); i++) {
+        if (!done_neg) {
+            end_neg -= along;
+            delta_neg = luma_at(end_neg) - edge_luma;
+            done_neg = abs(delta_neg) >= gradient_threshold;
+        }
+        if (!done_pos) {
+            end_pos += along;
+            delta_pos = luma_at(end_pos) - edge_luma;
+            done_pos = abs(delta_pos) >= gradient_threshold;
+        }
+    }
+
+    float distance_neg = dot(position - end_neg, along);
+    float distance_pos = dot(end_pos - position, along);
+    bool nearer_neg = distance_neg < distance_pos;
+    float edge_length = distance_neg + distance_pos;
+
+    // Pixels are only pulled across the edge if the nearer end of it goes the opposite way from
+    // them, otherwise they're on the wrong side of the step to be blended
+    bool darker_than_edge = luma_m < edge_luma;
+    bool end_darker = (nearer_neg ? delta_neg : delta_pos) < 0.0;
+    float offset = end_darker != darker_than_edge
+        ? 0.5 - min(distance_neg, distance_pos) / edge_length
+        : 0.0;
+
+    // Details smaller than a pixel, eg. thin lines, are blended by how much they stand out from
+    // the average of their neighbours
+    float luma_average = (2.0 * (luma_n + luma_s + luma_w + luma_e)
+        + luma_nw + luma_ne + luma_sw + luma_se) / 12.0;
+    float subpixel = smoothstep(0.0, 1.0, clamp(abs(luma_average - luma_m) / luma_range, 0.0, 1.0));
+    offset = max(offset, subpixel * subpixel * SUBPIXEL_QUALITY);
+
+    o_color = vec4(sample_source(position + across * (offset * across_sign)), 1.0);
+}
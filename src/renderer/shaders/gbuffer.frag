@@ -0,0 +1,64 @@
+#version 450
+
+layout(location = 0) in vec4 v_Color;
+layout(location = 1) in vec3 v_Position;
+layout(location = 2) in vec3 v_Normal;
+layout(location = 3) in vec2 v_TexCoord;
+// Per-instance base color multiplier, and emissive, metalness, roughness and occlusion strength
+// multipliers
+layout(location = 5) in vec4 v_Tint;
+layout(location = 6) flat in vec4 v_MaterialScale;
+
+layout(location = 0) out vec4 o_Albedo;
+layout(location = 1) out vec4 o_Normal;
+layout(location = 2) out vec4 o_Material;
+layout(location = 3) out vec4 o_Emissive;
+
+layout(set = 1, binding = 0) uniform texture2D t_base_color;
+layout(set = 1, binding = 1) uniform sampler s_base_color;
+layout(set = 1, binding = 2) uniform texture2D t_metallic_roughness;
+layout(set = 1, binding = 5) uniform texture2D t_emissive;
+
+layout(set = 1, binding = 6) uniform Material {
+    // Linear RGBA
+    vec4 u_BaseColorFactor;
+    // Linear RGB, alpha cutoff in w
+    vec4 u_EmissiveFactor;
+    // Metalness, roughness, normal scale and occlusion strength
+    vec4 u_MaterialParams;
+};
+
+// The deferred lighting pass is still Blinn-Phong, so the material's roughness is approximated
+// as a shininess exponent with the usual 2 / roughness^4 - 2 mapping
+const float SPECULAR_STRENGTH = 1.0;
+
+// Must match the value in deferred_lighting.frag
+const float MAX_SHININESS = 128.0;
+
+void main() {
+    vec4 base_color_alpha = texture(sampler2D(t_base_color, s_base_color), v_TexCoord)
+        * v_Color
+        * v_Tint;
+
+    // The cutoff is 0 for materials that aren't masked, so this only ever cuts out masked ones
+    if (base_color_alpha.a * u_BaseColorFactor.a < u_EmissiveFactor.w) {
+        discard;
+    }
+
+    vec3 base_color = base_color_alpha.rgb;
+    float roughness = texture(sampler2D(t_metallic_roughness, s_base_color), v_TexCoord).g
+        * u_MaterialParams.y
+        * v_MaterialScale.z;
+    roughness = clamp(roughness, 0.2, 1.0);
+    float shininess = clamp(2.0 / pow(roughness, 4.0) - 2.0, 1.0, MAX_SHININESS);
+
+    o_Albedo = vec4(base_color * u_BaseColorFactor.rgb, 1.0);
+    o_Normal = vec4(normalize(v_Normal), 0.0);
+    o_Material = vec4(SPECULAR_STRENGTH, shininess / MAX_SHININESS, 0.0, 0.0);
+    o_Emissive = vec4(
+        texture(sampler2D(t_emissive, s_base_color), v_TexCoord).rgb
+            * u_EmissiveFactor.rgb
+            * v_MaterialScale.x,
+        0.0
+    );
+}
@@ -0,0 +1,143 @@
+#version 450
+
+// Draws one face of one mip of an environment cubemap from the source cubemap, in one of three
+// ways picked by a define:
+//  - DOWNSAMPLE copies the source, for building its mip chain
+//  - IRRADIANCE convolves the source with a cosine lobe, for diffuse lighting
+//  - PREFILTER convolves the source with a GGX lobe of the given roughness, for specular lighting
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    // Face being drawn in x, roughness in y, size of the source's largest mip in z, and the mip
+    // of the source to copy in w
+    vec4 u_Params;
+};
+
+layout(set = 0, binding = 1) uniform textureCube t_Source;
+layout(set = 0, binding = 2) uniform sampler s_Source;
+
+const float PI = 3.14159265359;
+
+// Direction through the given point on a face, following the cubemap convention of each face's
+// texture coordinates starting from its top-left corner
+vec3 face_direction(int face, vec2 uv) {
+    vec2 p = uv * 2.0 - 1.0;
+    if (face == 0) {
+        return vec3(1.0, -p.y, -p.x);
+    } else if (face == 1) {
+        return vec3(-1.0, -p.y, p.x);
+    } else if (face == 2) {
+        return vec3(p.x, 1.0, p.y);
+    } else if (face == 3) {
+        return vec3(p.x, -1.0, -p.y);
+    } else if (face == 4) {
+        return vec3(p.x, -p.y, 1.0);
+    }
+    return vec3(-p.x, -p.y, -1.0);
+}
+
+// Two vectors perpendicular to the normal and each other
+mat3 tangent_frame(vec3 normal) {
+    vec3 up = abs(normal.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(up, normal));
+    return mat3(tangent, cross(normal, tangent), normal);
+}
+
+#ifdef IRRADIANCE
+// Steps around and away from the normal that the hemisphere is integrated over
+const int PHI_STEPS = 64;
+const int THETA_STEPS = 16;
+
+// Mip of the source sampled, coarse enough that the steps don't skip over any of it
+const float IRRADIANCE_LOD = 3.0;
+
+// Cosine weighted average of the light arriving over the hemisphere around the normal, which a
+// white Lambertian surface reflects in full
+vec3 irradiance(vec3 normal) {
+    mat3 frame = tangent_frame(normal);
+    vec3 total = vec3(0.0);
+    for (int i = 0; i < PHI_STEPS; i++) {
+        float phi = (float(i) + 0.5) * 2.0 * PI / float(PHI_STEPS);
+        for (int j = 0; j < THETA_STEPS; j++) {
+            float theta = (float(j) + 0.5) * 0.5 * PI / float(THETA_STEPS);
+            vec3 direction = frame * vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            vec3 radiance =
+                textureLod(samplerCube(t_Source, s_Source), direction, IRRADIANCE_LOD).rgb;
+            total += radiance * cos(theta) * sin(theta);
+        }
+    }
+    return PI * total / float(PHI_STEPS * THETA_STEPS);
+}
+#endif
+
+#ifdef PREFILTER
+const uint SAMPLE_COUNT = 256;
+
+// The i'th of n points spread evenly over the unit square
+vec2 hammersley(uint i, uint n) {
+    return vec2(float(i) / float(n), float(bitfieldReverse(i)) * 2.3283064365386963e-10);
+}
+
+// Half vector around the normal, distributed by GGX with the given roughness
+vec3 importance_sample_ggx(vec2 xi, mat3 frame, float roughness) {
+    float a = roughness * roughness;
+    float phi = 2.0 * PI * xi.x;
+    float cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return frame * vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
+
+float distribution_ggx(float n_dot_h, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float denom = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denom * denom);
+}
+
+// Light reflected towards the normal by a GGX lobe, taking the view to be along the normal as
+// there's no knowing it ahead of time
+//
+// Samples where the lobe is sparse are taken from blurrier mips of the source, so that a few
+// hundred of them don't leave bright spots.
+vec3 prefilter(vec3 normal, float roughness) {
+    mat3 frame = tangent_frame(normal);
+    float source_size = u_Params.z;
+    float texel_solid_angle = 4.0 * PI / (6.0 * source_size * source_size);
+
+    vec3 total = vec3(0.0);
+    float total_weight = 0.0;
+    for (uint i = 0; i < SAMPLE_COUNT; i++) {
+        vec3 half_dir = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), frame, roughness);
+        vec3 light_dir = normalize(2.0 * dot(normal, half_dir) * half_dir - normal);
+        float n_dot_l = dot(normal, light_dir);
+        if (n_dot_l <= 0.0) {
+            continue;
+        }
+
+        // With the view along the normal, n.h and h.v are the same
+        float n_dot_h = max(dot(normal, half_dir), 0.0);
+        float pdf = distribution_ggx(n_dot_h, roughness) / 4.0 + 1e-4;
+        float sample_solid_angle = 1.0 / (float(SAMPLE_COUNT) * pdf + 1e-4);
+        float lod = 0.5 * log2(sample_solid_angle / texel_solid_angle);
+
+        vec3 radiance = textureLod(samplerCube(t_Source, s_Source), light_dir, max(lod, 0.0)).rgb;
+        total += radiance * n_dot_l;
+        total_weight += n_dot_l;
+    }
+    return total / max(total_weight, 1e-4);
+}
+#endif
+
+void main() {
+    vec3 direction = normalize(face_direction(int(u_Params.x), v_TexCoord));
+#if defined(IRRADIANCE)
+    o_color = vec4(irradiance(direction), 1.0);
+#elif defined(PREFILTER)
+    o_color = vec4(prefilter(direction, u_Params.y), 1.0);
+#else
+    o_color = textureLod(samplerCube(t_Source, s_Source), direction, u_Params.w);
+#endif
+}
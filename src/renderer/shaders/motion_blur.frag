@@ -0,0 +1,66 @@
+#version 450
+
+// Averages the HDR scene along each pixel's motion since last frame, centred on the pixel, as
+// if the shutter were open for part of the time between frames.
+
+#define MAX_SAMPLES 32
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    // Transforms this frame's unjittered clip space in to last frame's
+    mat4 u_Reproject;
+    // Clip space offset the scene was drawn with this frame in xy, zw are unused
+    vec4 u_Jitter;
+    // Size of the rendered region in xy, and of the whole source texture in zw
+    vec4 u_Region;
+    // Shutter in x, sample count in y, zw are unused
+    vec4 u_Params;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_Scene;
+// Change in texture coordinates since last frame in xy, and whether the forward stage drew
+// anything there in w
+layout(set = 0, binding = 2) uniform texture2D t_Motion;
+layout(set = 0, binding = 3) uniform texture2D t_Depth;
+layout(set = 0, binding = 4) uniform sampler s_Linear;
+
+void main() {
+    // The viewport covers exactly the rendered region, so fragments line up with its texels
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    vec2 uv = gl_FragCoord.xy / u_Region.xy;
+    vec4 motion = texelFetch(sampler2D(t_Motion, s_Linear), texel, 0);
+    vec2 velocity = motion.xy;
+    if (motion.w == 0.0) {
+        // Nothing was drawn here by the forward stage, eg. the skybox, so the pixel is reprojected
+        // from its depth
+        float depth = texelFetch(sampler2D(t_Depth, s_Linear), texel, 0).r;
+        vec2 ndc = vec2(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0) - u_Jitter.xy;
+        vec4 previous = u_Reproject * vec4(ndc, depth, 1.0);
+        velocity = (ndc - previous.xy / previous.w) * vec2(0.5, -0.5);
+    }
+
+    // In pixels of the rendered region
+    vec2 blur = velocity * u_Params.x * u_Region.xy;
+    int samples = int(u_Params.y);
+    if (dot(blur, blur) < 0.25 || samples <= 1) {
+        o_color = texelFetch(sampler2D(t_Scene, s_Linear), texel, 0);
+        return;
+    }
+
+    vec3 total = vec3(0.0);
+    for (int i = 0; i < MAX_SAMPLES; i++) {
+        if (i >= samples) {
+            break;
+        }
+
+        float t = float(i) / float(samples - 1) - 0.5;
+        // Keep to the rendered region, rather than filtering in whatever is left beyond it
+        vec2 position = clamp(gl_FragCoord.xy + blur * t, vec2(0.5), u_Region.xy - 0.5);
+        total += textureLod(sampler2D(t_Scene, s_Linear), position / u_Region.zw, 0.0).rgb;
+    }
+
+    o_color = vec4(total / float(samples), 1.0);
+}
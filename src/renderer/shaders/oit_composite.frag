@@ -0,0 +1,24 @@
+#version 450
+
+layout(location = 0) out vec4 o_color;
+
+// Accumulated and resolved at the scene target's size, so they line up with it pixel for pixel
+layout(set = 0, binding = 0) uniform texture2D t_Accum;
+layout(set = 0, binding = 1) uniform texture2D t_Revealage;
+layout(set = 0, binding = 2) uniform sampler s_Oit;
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    float revealage = texelFetch(sampler2D(t_Revealage, s_Oit), texel, 0).r;
+
+    // Nothing transparent covers this pixel
+    if (revealage >= 1.0) {
+        discard;
+    }
+
+    vec4 accum = texelFetch(sampler2D(t_Accum, s_Oit), texel, 0);
+
+    // The weighted average of every surface's color, kept finite where the sums overflowed
+    vec3 average = accum.rgb / clamp(accum.a, 1e-4, 5e4);
+    o_color = vec4(average, 1.0 - revealage);
+}
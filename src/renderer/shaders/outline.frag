@@ -0,0 +1,38 @@
+#version 450
+
+layout(location = 0) out vec4 o_color;
+
+// Drawn at the scene target's size, so it lines up with it pixel for pixel
+layout(set = 0, binding = 0) uniform texture2D t_Mask;
+layout(set = 0, binding = 1) uniform sampler s_Mask;
+
+// In pixels of the scene target, so the outline thins out at a reduced render scale
+const int OUTLINE_WIDTH = 2;
+const vec3 OUTLINE_COLOR = vec3(1.0, 0.6, 0.0);
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    ivec2 last_texel = textureSize(sampler2D(t_Mask, s_Mask), 0) - 1;
+
+    // The selected surfaces themselves are left as they are
+    if (texelFetch(sampler2D(t_Mask, s_Mask), texel, 0).r > 0.0) {
+        discard;
+    }
+
+    // Anything within the outline's width of a selected pixel is part of the outline
+    float coverage = 0.0;
+    for (int y = -OUTLINE_WIDTH; y <= OUTLINE_WIDTH; y++) {
+        for (int x = -OUTLINE_WIDTH; x <= OUTLINE_WIDTH; x++) {
+            if (x * x + y * y > OUTLINE_WIDTH * OUTLINE_WIDTH) {
+                continue;
+            }
+            ivec2 neighbor = clamp(texel + ivec2(x, y), ivec2(0), last_texel);
+            coverage = max(coverage, texelFetch(sampler2D(t_Mask, s_Mask), neighbor, 0).r);
+        }
+    }
+
+    if (coverage <= 0.0) {
+        discard;
+    }
+    o_color = vec4(OUTLINE_COLOR, coverage);
+}
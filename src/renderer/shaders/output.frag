@@ -0,0 +1,90 @@
+#version 450
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+// Values for u_ColorFilter
+const uint COLOR_FILTER_NONE = 0u;
+const uint COLOR_FILTER_SIMULATE = 1u;
+const uint COLOR_FILTER_DALTONIZE = 2u;
+
+// Values for u_Deficiency
+const uint DEFICIENCY_PROTANOPIA = 0u;
+const uint DEFICIENCY_DEUTERANOPIA = 1u;
+const uint DEFICIENCY_TRITANOPIA = 2u;
+
+layout(set = 0, binding = 0) uniform Locals {
+    uint u_ColorFilter;
+    uint u_Deficiency;
+
+    // User display calibration, see OutputCalibration
+    float u_Exposure;
+    float u_Brightness;
+    float u_Gamma;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_source;
+layout(set = 0, binding = 2) uniform sampler s_source;
+
+// The sRGB swapchain encodes with roughly this gamma on write, which the user's display gamma is
+// applied on top of
+const float screenGamma = 2.2;
+
+// Full severity dichromacy simulation matrices from Machado, Oliveira & Fernandes (2009).
+// GLSL matrices are column major, so these read as the transpose of the published ones.
+const mat3 PROTANOPIA = mat3(
+     0.152286,  0.114503, -0.003882,
+     1.052583,  0.786281, -0.048116,
+    -0.204868,  0.099216,  1.051998
+);
+const mat3 DEUTERANOPIA = mat3(
+     0.367322,  0.280085, -0.011820,
+     0.860646,  0.672501,  0.042940,
+    -0.227968,  0.047413,  0.968881
+);
+const mat3 TRITANOPIA = mat3(
+     1.255528, -0.078411,  0.004733,
+    -0.076749,  0.930809,  0.691367,
+    -0.178779,  0.147602,  0.303900
+);
+
+vec3 simulate(vec3 color) {
+    if (u_Deficiency == DEFICIENCY_PROTANOPIA) {
+        return PROTANOPIA * color;
+    } else if (u_Deficiency == DEFICIENCY_DEUTERANOPIA) {
+        return DEUTERANOPIA * color;
+    } else {
+        return TRITANOPIA * color;
+    }
+}
+
+// Shift the information lost to the deficiency in to the channels that can still be seen
+vec3 daltonize(vec3 color) {
+    vec3 error = color - simulate(color);
+    vec3 correction = vec3(
+        0.0,
+        0.7 * error.r + error.g,
+        0.7 * error.r + error.b
+    );
+    return color + correction;
+}
+
+void main() {
+    // The source is sRGB, so this is already linear as the deficiency models expect
+    vec3 color_linear = texture(sampler2D(t_source, s_source), v_TexCoord).rgb;
+
+    if (u_ColorFilter == COLOR_FILTER_SIMULATE) {
+        color_linear = simulate(color_linear);
+    } else if (u_ColorFilter == COLOR_FILTER_DALTONIZE) {
+        color_linear = daltonize(color_linear);
+    }
+
+    color_linear = color_linear * u_Exposure + u_Brightness;
+
+    // Encode for the user's actual display gamma rather than the assumed one, undoing the part of
+    // it that the swapchain applies
+    vec3 color = pow(clamp(color_linear, 0.0, 1.0), vec3(screenGamma / u_Gamma));
+
+    o_color = vec4(color, 1.0);
+}
\ No newline at end of file
@@ -4,44 +4,420 @@ layout(location = 0) in vec4 v_Color;
 layout(location = 1) in vec3 v_Position;
 layout(location = 2) in vec3 v_Normal;
 layout(location = 3) in vec2 v_TexCoord;
+layout(location = 4) in vec4 v_ShadowCoord;
+// Per-instance base color multiplier, and emissive, metalness, roughness and occlusion strength
+// multipliers
+layout(location = 5) in vec4 v_Tint;
+layout(location = 6) flat in vec4 v_MaterialScale;
+layout(location = 7) in vec2 v_LightmapCoord;
+
+#ifdef MOTION_VECTORS
+layout(location = 8) in vec4 v_ClipPosition;
+layout(location = 9) in vec4 v_PrevClipPosition;
+#endif
 
 layout(location = 0) out vec4 o_color;
 
+#ifdef WEIGHTED_BLENDED
+// Multiplied in to the revealage target, while o_color is summed in to the accumulation target
+layout(location = 1) out float o_revealage;
+#endif
+
 layout(set = 0, binding = 0) uniform Locals {
     mat4 u_View;
     mat4 u_Proj;
+    mat4 u_LightViewProj;
+    // View space direction towards the directional light, w is unused
+    vec4 u_SunDirection;
+    // Linear RGB color premultiplied by intensity, w is unused
+    vec4 u_SunColor;
+    // Linear RGB, w is unused
+    vec4 u_FogColor;
+    // Linear start and end distances, exponential density, and the FOG_* mode
+    vec4 u_FogParams;
+    // Last frame's unjittered projection and view, for motion vectors
+    mat4 u_PrevViewProj;
+    // Clip space offset u_Proj is jittered by for TAA in xy, zw are unused
+    vec4 u_Jitter;
+    // View space plane that FEATURE_CLIP_PLANE discards everything behind
+    vec4 u_ClipPlane;
+    // Most taps sample_material takes along a texture's footprint in x, yzw are unused
+    vec4 u_TextureParams;
 };
 
+layout(set = 0, binding = 1) uniform texture2D t_ShadowMap;
+layout(set = 0, binding = 2) uniform samplerShadow s_ShadowMap;
+
+// Screen space ambient occlusion at half the scene target's resolution, or a single unoccluded
+// texel when it's disabled
+layout(set = 0, binding = 3) uniform texture2D t_Occlusion;
+layout(set = 0, binding = 4) uniform sampler s_Occlusion;
+
+// The skybox filtered for diffuse and specular lighting, the specular map's mips being for
+// increasing roughness, and a scale and bias to the reflectance at normal incidence by n.v and
+// roughness
+layout(set = 0, binding = 5) uniform textureCube t_Irradiance;
+layout(set = 0, binding = 6) uniform textureCube t_Specular;
+layout(set = 0, binding = 7) uniform texture2D t_BrdfLut;
+layout(set = 0, binding = 8) uniform sampler s_Environment;
+
 layout(set = 1, binding = 0) uniform texture2D t_base_color;
 layout(set = 1, binding = 1) uniform sampler s_base_color;
+layout(set = 1, binding = 2) uniform texture2D t_metallic_roughness;
+layout(set = 1, binding = 3) uniform texture2D t_normal;
+layout(set = 1, binding = 4) uniform texture2D t_occlusion;
+layout(set = 1, binding = 5) uniform texture2D t_emissive;
+
+layout(set = 1, binding = 6) uniform Material {
+    // Linear RGBA
+    vec4 u_BaseColorFactor;
+    // Linear RGB, alpha cutoff in w
+    vec4 u_EmissiveFactor;
+    // Metalness, roughness, normal scale and occlusion strength
+    vec4 u_MaterialParams;
+};
+
+layout(set = 1, binding = 7) uniform texture2D t_lightmap;
+
+// Values of Light.params.x
+const uint LIGHT_POINT = 0;
+const uint LIGHT_SPOT = 1;
+const uint LIGHT_DIRECTIONAL = 2;
+
+struct Light {
+    // View space position, w is unused
+    vec4 position;
+    // View space unit direction the light travels in, w is unused
+    vec4 direction;
+    // Linear RGB color in xyz, power in w
+    vec4 color;
+    // Kind of light in x, cosines of a spot light's inner and outer cone angles in y and z
+    vec4 params;
+};
+
+// The header is padded out to 16 bytes so that this is laid out the same under std140 and std430
+#ifdef LIGHTS_UNIFORM_ARRAY
+layout(set = 2, binding = 0) uniform Lights {
+    uint u_LightCount;
+    Light u_Lights[MAX_UNIFORM_LIGHTS];
+};
+#else
+layout(set = 2, binding = 0) readonly buffer Lights {
+    uint u_LightCount;
+    Light u_Lights[];
+};
+#endif
+
+// Fraction of the directional light reaching this fragment, from a 3x3 PCF of the shadow map
+float sun_visibility() {
+    vec3 coord = v_ShadowCoord.xyz / v_ShadowCoord.w;
+    // Clip space y is up, texture v is down
+    vec2 uv = vec2(coord.x * 0.5 + 0.5, 0.5 - coord.y * 0.5);
+
+    // Anything outside of the shadow map's coverage is lit
+    if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))) || coord.z > 1.0) {
+        return 1.0;
+    }
+
+    vec2 texel_size = 1.0 / vec2(textureSize(sampler2DShadow(t_ShadowMap, s_ShadowMap), 0));
+    float visibility = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            vec3 coord_offset = vec3(vec2(x, y) * texel_size, 0.0);
+            visibility += texture(
+                sampler2DShadow(t_ShadowMap, s_ShadowMap),
+                vec3(uv, coord.z) + coord_offset
+            );
+        }
+    }
+    return visibility / 9.0;
+}
+
+// Fraction of the ambient light reaching this fragment past nearby geometry
+float ambient_visibility() {
+    vec2 size = vec2(textureSize(sampler2D(t_Occlusion, s_Occlusion), 0));
+    return texture(sampler2D(t_Occlusion, s_Occlusion), gl_FragCoord.xy / (2.0 * size)).r;
+}
+
+const float PI = 3.14159265359;
+
+// Reflectance at normal incidence of every dielectric, per the glTF spec
+const vec3 DIELECTRIC_F0 = vec3(0.04);
+
+// Last mip of t_Specular, which is filtered for a roughness of 1. This has to match SPECULAR_MIPS
+// in ibl.rs.
+const float SPECULAR_MAX_LOD = 4.0;
+
+// Anisotropic filtering, as wgpu doesn't expose it on samplers. The footprint's major axis is
+// covered by up to u_TextureParams.x taps, each sampled at the mip of its minor axis.
+vec4 sample_material(texture2D t) {
+    vec2 dx = dFdx(v_TexCoord);
+    vec2 dy = dFdy(v_TexCoord);
+    vec2 size = vec2(textureSize(sampler2D(t, s_base_color), 0));
+    float len_x = length(dx * size);
+    float len_y = length(dy * size);
+    vec2 major = len_x > len_y ? dx : dy;
+    float ratio = max(len_x, len_y) / max(min(len_x, len_y), 1e-6);
+    int taps = int(clamp(ceil(ratio), 1.0, max(u_TextureParams.x, 1.0)));
+    if (taps == 1) {
+        return texture(sampler2D(t, s_base_color), v_TexCoord);
+    }
+
+    vec2 minor_dx = dx / float(taps);
+    vec2 minor_dy = dy / float(taps);
+    vec4 sum = vec4(0.0);
+    for (int i = 0; i < taps; i++) {
+        vec2 offset = major * ((float(i) + 0.5) / float(taps) - 0.5);
+        sum += textureGrad(sampler2D(t, s_base_color), v_TexCoord + offset, minor_dx, minor_dy);
+    }
+    return sum / float(taps);
+}
 
-// Assume the monitor is calibrated to the sRGB color space
-const float screenGamma = 2.2;
+#ifdef FEATURE_NORMAL_MAP
+// Perturb the interpolated normal with the normal map, building the tangent frame from screen
+// space derivatives as the vertices don't carry tangents
+vec3 perturb_normal(vec3 normal) {
+    vec3 dp1 = dFdx(v_Position);
+    vec3 dp2 = dFdy(v_Position);
+    vec2 duv1 = dFdx(v_TexCoord);
+    vec2 duv2 = dFdy(v_TexCoord);
+
+    vec3 dp2perp = cross(dp2, normal);
+    vec3 dp1perp = cross(normal, dp1);
+    vec3 tangent = dp2perp * duv1.x + dp1perp * duv2.x;
+    vec3 bitangent = dp2perp * duv1.y + dp1perp * duv2.y;
+    float inv_max = inversesqrt(max(dot(tangent, tangent), dot(bitangent, bitangent)));
+    mat3 tbn = mat3(tangent * inv_max, bitangent * inv_max, normal);
+
+    vec3 tangent_normal = sample_material(t_normal).xyz * 2.0 - 1.0;
+    tangent_normal.xy *= u_MaterialParams.z;
+    return normalize(tbn * tangent_normal);
+}
+#endif
+
+// Trowbridge-Reitz GGX normal distribution
+float distribution_ggx(float n_dot_h, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float denom = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denom * denom);
+}
+
+// Smith's method with the Schlick-GGX approximation for each of the light and view directions
+float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+    float r = roughness + 1.0;
+    float k = r * r / 8.0;
+    float ggx_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    float ggx_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return ggx_v * ggx_l;
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Like fresnel_schlick, averaged over the spread of microfacet normals of a rough surface
+vec3 fresnel_schlick_roughness(float cos_theta, vec3 f0, float roughness) {
+    return f0 + (max(vec3(1.0 - roughness), f0) - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Direction to look up in the environment's cubemaps for a view space direction
+vec3 environment_dir(vec3 view_dir) {
+    // The view matrix is a rotation and translation, so its transpose undoes the rotation. The
+    // world is Z up, whereas cubemap faces are laid out with Y up.
+    vec3 world_dir = transpose(mat3(u_View)) * view_dir;
+    return vec3(world_dir.x, world_dir.z, -world_dir.y);
+}
+
+// Light reflected from the environment, with the given irradiance for the diffuse part
+vec3 environment_lighting(
+    vec3 normal,
+    vec3 view_dir,
+    vec3 irradiance,
+    vec3 albedo,
+    float metallic,
+    float roughness
+) {
+    float n_dot_v = max(dot(normal, view_dir), 1e-4);
+    vec3 f0 = mix(DIELECTRIC_F0, albedo, metallic);
+    vec3 fresnel = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    vec3 diffuse = (1.0 - fresnel) * (1.0 - metallic) * albedo * irradiance;
+
+    vec3 prefiltered = textureLod(
+        samplerCube(t_Specular, s_Environment),
+        environment_dir(reflect(-view_dir, normal)),
+        roughness * SPECULAR_MAX_LOD
+    ).rgb;
+    vec2 brdf = texture(sampler2D(t_BrdfLut, s_Environment), vec2(n_dot_v, roughness)).rg;
+    vec3 specular = prefiltered * (f0 * brdf.x + brdf.y);
+
+    return diffuse + specular;
+}
+
+// Cook-Torrance reflectance of light arriving from light_dir, multiplied by the cosine term
+//
+// Lights are specified such that a white diffuse surface facing one reflects its full color, so
+// this is scaled by PI to cancel out the Lambertian diffuse's normalization.
+vec3 cook_torrance(
+    vec3 normal,
+    vec3 view_dir,
+    vec3 light_dir,
+    vec3 albedo,
+    float metallic,
+    float roughness
+) {
+    float n_dot_l = max(dot(normal, light_dir), 0.0);
+    if (n_dot_l <= 0.0) {
+        return vec3(0.0);
+    }
+    float n_dot_v = max(dot(normal, view_dir), 1e-4);
+    vec3 half_dir = normalize(light_dir + view_dir);
+
+    vec3 f0 = mix(DIELECTRIC_F0, albedo, metallic);
+    vec3 fresnel = fresnel_schlick(max(dot(half_dir, view_dir), 0.0), f0);
+    float ndf = distribution_ggx(max(dot(normal, half_dir), 0.0), roughness);
+    float geometry = geometry_smith(n_dot_v, n_dot_l, roughness);
+    vec3 specular = ndf * geometry * fresnel / (4.0 * n_dot_v * n_dot_l);
+
+    // Metals have no diffuse reflection, and whatever is reflected specularly can't be diffused
+    vec3 diffuse = (1.0 - fresnel) * (1.0 - metallic) * albedo / PI;
+
+    return (diffuse + specular) * n_dot_l * PI;
+}
+
+#ifdef FEATURE_FOG
+// Values of u_FogParams.w
+const uint FOG_LINEAR = 0;
+const uint FOG_EXPONENTIAL = 1;
+const uint FOG_EXPONENTIAL_SQUARED = 2;
+
+// Fraction of the surface's color that makes it through the fog to the camera
+float fog_visibility(float distance) {
+    uint mode = uint(u_FogParams.w);
+    if (mode == FOG_LINEAR) {
+        float range = max(u_FogParams.y - u_FogParams.x, 1e-4);
+        return clamp((u_FogParams.y - distance) / range, 0.0, 1.0);
+    }
+
+    float optical_depth = u_FogParams.z * distance;
+    if (mode == FOG_EXPONENTIAL_SQUARED) {
+        optical_depth *= optical_depth;
+    }
+    return exp(-optical_depth);
+}
+#endif
 
 void main() {
-    // Hard code a fixed light position, and transform it into the current view space
-    vec3 light_pos = (u_View * vec4(1.0, 4.0, 3.0, 1.0)).xyz;
-    float light_power = 5.0;
+#ifdef FEATURE_CLIP_PLANE
+    // wgpu has no user clip distances, so the plane is emulated by discarding
+    if (dot(vec4(v_Position, 1.0), u_ClipPlane) < 0.0) {
+        discard;
+    }
+#endif
+    vec4 base_color_alpha = sample_material(t_base_color) * u_BaseColorFactor * v_Color * v_Tint;
+#ifdef FEATURE_ALPHA_MASK
+    if (base_color_alpha.a < u_EmissiveFactor.w) {
+        discard;
+    }
+#endif
+#ifdef DEPTH_ONLY
+    // The depth prepass only needs to know which fragments survive the alpha test and clip plane
+    return;
+#endif
+#ifdef SELECTION_MASK
+    // The outline stage only needs to know which pixels are covered
+    o_color = vec4(1.0);
+    return;
+#endif
+#ifdef MOTION_VECTORS
+    // Change in texture coordinates since last frame, with w marking the pixel as drawn
+    vec2 velocity = v_ClipPosition.xy / v_ClipPosition.w
+        - v_PrevClipPosition.xy / v_PrevClipPosition.w;
+    o_color = vec4(velocity * vec2(0.5, -0.5), 0.0, 1.0);
+    return;
+#endif
 
     vec3 normal = normalize(v_Normal);
-
-    vec3 light_dir = normalize(light_pos - v_Position);
-    float light_distance = length(light_pos - v_Position);
+#ifdef FEATURE_NORMAL_MAP
+    normal = perturb_normal(normal);
+#endif
     vec3 view_dir = normalize(-v_Position);
-    vec3 half_dir = normalize(light_dir + view_dir);
 
-    float lambertian = max(dot(light_dir, normal), 0.0);
+    vec3 base_color = base_color_alpha.rgb;
+    vec4 metallic_roughness = sample_material(t_metallic_roughness);
+    vec4 params = u_MaterialParams * vec4(v_MaterialScale.yz, 1.0, v_MaterialScale.w);
+    float metallic = clamp(metallic_roughness.b * params.x, 0.0, 1.0);
+    // Perfectly smooth surfaces make the specular highlight infinitely small and bright
+    float roughness = clamp(metallic_roughness.g * params.y, 0.04, 1.0);
+    float occlusion = mix(1.0, sample_material(t_occlusion).r, clamp(params.w, 0.0, 1.0));
+    vec3 emissive = sample_material(t_emissive).rgb * u_EmissiveFactor.rgb * v_MaterialScale.x;
+
+#ifdef FEATURE_LIGHTMAP
+    // Light baked in to the lightmap stands in for the environment's diffuse lighting
+    vec3 irradiance = texture(sampler2D(t_lightmap, s_base_color), v_LightmapCoord).rgb;
+#else
+    vec3 irradiance =
+        texture(samplerCube(t_Irradiance, s_Environment), environment_dir(normal)).rgb;
+#endif
+    vec3 ambient =
+        environment_lighting(normal, view_dir, irradiance, base_color, metallic, roughness);
+    vec3 colorLinear = ambient * occlusion * ambient_visibility() + emissive;
+
+    vec3 sun_dir = normalize(u_SunDirection.xyz);
+    colorLinear += cook_torrance(normal, view_dir, sun_dir, base_color, metallic, roughness)
+        * u_SunColor.rgb
+        * sun_visibility();
+
+    for (uint i = 0; i < u_LightCount; i++) {
+        Light light = u_Lights[i];
+        uint kind = uint(light.params.x);
+        vec3 light_color = light.color.rgb;
+        float light_power = light.color.w;
 
-    float spec_angle = max(dot(half_dir, normal), 0.0);
-    float specular = pow(spec_angle, 15.0);
+        vec3 light_dir;
+        float attenuation;
+        if (kind == LIGHT_DIRECTIONAL) {
+            light_dir = -light.direction.xyz;
+            attenuation = 1.0;
+        } else {
+            vec3 to_light = light.position.xyz - v_Position;
+            float light_distance = length(to_light);
+            light_dir = to_light / light_distance;
+            attenuation = 1.0 / light_distance;
 
-    vec3 base_color = texture(sampler2D(t_base_color, s_base_color), v_TexCoord).rgb;
+            if (kind == LIGHT_SPOT) {
+                // Fade out between the inner and outer cone angles
+                float cos_angle = dot(-light_dir, light.direction.xyz);
+                attenuation *= smoothstep(light.params.z, light.params.y, cos_angle);
+            }
+        }
 
-    vec3 colorLinear = (base_color * 0.02)
-                     + base_color * lambertian * vec3(1.0, 1.0, 1.0) * light_power / light_distance
-                     + base_color * specular * vec3(1.0, 1.0, 1.0) * light_power / light_distance;
+        colorLinear += cook_torrance(normal, view_dir, light_dir, base_color, metallic, roughness)
+            * light_color
+            * light_power
+            * attenuation;
+    }
 
-    vec3 colorGammaCorrected = pow(colorLinear, vec3(1.0 / screenGamma));
+#ifdef FEATURE_FOG
+    colorLinear = mix(u_FogColor.rgb, colorLinear, fog_visibility(length(v_Position)));
+#endif
 
-    o_color = vec4(colorGammaCorrected, 1.0);
-}
\ No newline at end of file
+    // Left linear and unbounded, tonemapping happens in a later pass
+#if defined(FEATURE_ALPHA_BLEND) && defined(WEIGHTED_BLENDED)
+    // Nearer surfaces count for more in the weighted average, per equation 10 of McGuire and
+    // Bavoil's paper
+    float alpha = base_color_alpha.a;
+#ifdef REVERSE_Z
+    float nearness = gl_FragCoord.z;
+#else
+    float nearness = 1.0 - gl_FragCoord.z;
+#endif
+    float weight = clamp(alpha * max(1e-2, 3e3 * pow(nearness, 3.0)), 1e-2, 3e3);
+    o_color = vec4(colorLinear * alpha, alpha) * weight;
+    o_revealage = alpha;
+#elif defined(FEATURE_ALPHA_BLEND)
+    o_color = vec4(colorLinear, base_color_alpha.a);
+#else
+    o_color = vec4(colorLinear, 1.0);
+#endif
+}
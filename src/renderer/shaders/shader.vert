@@ -2,26 +2,99 @@
 
 layout(location = 0) in vec3 a_Position;
 layout(location = 1) in vec3 a_Normal;
-layout(location = 2) in vec2 a_TexCoord;
+// The first texcoord set in xy, and the second that lightmaps are sampled with in zw
+layout(location = 2) in vec4 a_TexCoords;
 layout(location = 3) in vec4 a_Color;
 layout(location = 4) in mat4 a_ModelMatrix;
+#ifdef MOTION_VECTORS
+// Motion vectors don't need normals, which leaves room for where the instance was last frame
+layout(location = 8) in mat4 a_PrevModelMatrix;
+#else
 layout(location = 8) in mat4 a_NormalMatrix;
+#endif
+layout(location = 12) in uvec4 a_Joints;
+layout(location = 13) in vec4 a_Weights;
+layout(location = 14) in vec4 a_Tint;
+layout(location = 15) in vec4 a_MaterialScale;
 
 layout(location = 0) out vec4 v_Color;
 layout(location = 1) out vec3 v_Position;
 layout(location = 2) out vec3 v_Normal;
 layout(location = 3) out vec2 v_TexCoord;
+layout(location = 4) out vec4 v_ShadowCoord;
+layout(location = 5) out vec4 v_Tint;
+layout(location = 6) flat out vec4 v_MaterialScale;
+layout(location = 7) out vec2 v_LightmapCoord;
+
+#ifdef MOTION_VECTORS
+// This frame's unjittered clip space position, and last frame's
+layout(location = 8) out vec4 v_ClipPosition;
+layout(location = 9) out vec4 v_PrevClipPosition;
+#endif
+
+// The depth prepass runs this same shader, and shading only happens where the depth matches it
+// exactly
+invariant gl_Position;
 
 layout(set = 0, binding = 0) uniform Locals {
     mat4 u_View;
     mat4 u_Proj;
+    mat4 u_LightViewProj;
+    // View space direction towards the directional light, w is unused
+    vec4 u_SunDirection;
+    // Linear RGB color premultiplied by intensity, w is unused
+    vec4 u_SunColor;
+    vec4 u_FogColor;
+    vec4 u_FogParams;
+    // Last frame's unjittered projection and view, for motion vectors
+    mat4 u_PrevViewProj;
+    // Clip space offset u_Proj is jittered by for TAA in xy, zw are unused
+    vec4 u_Jitter;
+    // View space plane that FEATURE_CLIP_PLANE discards everything behind
+    vec4 u_ClipPlane;
+    // See shader.frag
+    vec4 u_TextureParams;
 };
 
+#ifdef FEATURE_SKINNING
+// Kept in sync with MAX_JOINTS in joints.rs
+#define MAX_JOINTS 128
+
+layout(set = 3, binding = 0) readonly buffer Joints {
+    mat4 u_Joints[MAX_JOINTS];
+};
+#endif
+
 void main() {
+    vec4 position = vec4(a_Position, 1.0);
+    vec4 normal = vec4(a_Normal, 1.0);
+#ifdef FEATURE_SKINNING
+    mat4 skin = a_Weights.x * u_Joints[a_Joints.x]
+        + a_Weights.y * u_Joints[a_Joints.y]
+        + a_Weights.z * u_Joints[a_Joints.z]
+        + a_Weights.w * u_Joints[a_Joints.w];
+    position = skin * position;
+    normal = vec4(mat3(skin) * a_Normal, 1.0);
+#endif
+
     v_Color = a_Color;
-    v_Position = (u_View * a_ModelMatrix * vec4(a_Position, 1.0)).xyz;
-    v_Normal = normalize(a_NormalMatrix * vec4(a_Normal, 1.0)).xyz;
-    v_TexCoord = a_TexCoord;
+    v_Position = (u_View * a_ModelMatrix * position).xyz;
+#ifdef MOTION_VECTORS
+    v_Normal = vec3(0.0, 0.0, 1.0);
+#else
+    v_Normal = normalize(a_NormalMatrix * normal).xyz;
+#endif
+    v_TexCoord = a_TexCoords.xy;
+    v_LightmapCoord = a_TexCoords.zw;
+    v_ShadowCoord = u_LightViewProj * a_ModelMatrix * position;
+    v_Tint = a_Tint;
+    v_MaterialScale = a_MaterialScale;
 
     gl_Position = u_Proj * vec4(v_Position, 1.0);
+
+#ifdef MOTION_VECTORS
+    // Skinned models are taken to have held the same pose, as last frame's joints aren't kept
+    v_ClipPosition = vec4(gl_Position.xy - u_Jitter.xy * gl_Position.w, gl_Position.zw);
+    v_PrevClipPosition = u_PrevViewProj * a_PrevModelMatrix * position;
+#endif
 }
\ No newline at end of file
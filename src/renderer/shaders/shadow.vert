@@ -0,0 +1,12 @@
+#version 450
+
+layout(location = 0) in vec3 a_Position;
+layout(location = 4) in mat4 a_ModelMatrix;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_LightViewProj;
+};
+
+void main() {
+    gl_Position = u_LightViewProj * a_ModelMatrix * vec4(a_Position, 1.0);
+}
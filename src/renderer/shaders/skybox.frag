@@ -0,0 +1,21 @@
+#version 450
+
+layout(location = 0) in vec4 v_WorldDir;
+
+layout(location = 0) out vec4 f_Color;
+
+layout(set = 1, binding = 0) uniform textureCube t_Sky;
+layout(set = 1, binding = 1) uniform sampler s_Sky;
+
+void main() {
+#ifdef REVERSE_Z
+    // The reversed projection's far plane is at infinity, where w is zero and xyz is already a
+    // direction
+    vec3 dir = v_WorldDir.xyz;
+#else
+    vec3 dir = v_WorldDir.xyz / v_WorldDir.w;
+#endif
+
+    // The world is Z up, whereas cubemap faces are laid out with Y up
+    f_Color = texture(samplerCube(t_Sky, s_Sky), vec3(dir.x, dir.z, -dir.y));
+}
@@ -0,0 +1,21 @@
+#version 450
+
+layout(location = 0) out vec4 v_WorldDir;
+
+layout(set = 0, binding = 0) uniform Uniforms {
+    mat4 u_InvViewProj;
+};
+
+void main() {
+    // Fullscreen triangle sitting on the far plane, so that it only shows where nothing else was
+    // drawn
+    vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+#ifdef REVERSE_Z
+    gl_Position = vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+#else
+    gl_Position = vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 1.0, 1.0);
+#endif
+
+    // Left homogeneous so that the divide happens per fragment after interpolation
+    v_WorldDir = u_InvViewProj * gl_Position;
+}
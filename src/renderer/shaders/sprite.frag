@@ -1,12 +1,48 @@
 #version 450
 
-layout(location = 0) in vec2 v_AtlasCoord;
+layout(location = 0) in vec2 v_Corner;
+layout(location = 1) in vec4 v_Tint;
+layout(location = 2) flat in vec4 v_AtlasRect;
+layout(location = 3) flat in vec4 v_SliceAtlas;
+layout(location = 4) flat in vec4 v_SliceScreen;
 
 layout(set = 0, binding = 0) uniform texture2D t_atlas;
 layout(set = 0, binding = 1) uniform sampler s_atlas;
 
+layout(set = 1, binding = 0) uniform Locals {
+    uint u_HighContrast;
+    float u_AspectRatio;
+};
+
 layout(location = 0) out vec4 o_color;
 
+// Map a coordinate across the sprite on screen to one across its atlas rect, with the borders at
+// either end kept at their atlas size and the middle stretched to fill the rest
+float slice(float t, float screenStart, float screenEnd, float atlasStart, float atlasEnd) {
+    if (t < screenStart) {
+        return t / screenStart * atlasStart;
+    }
+    if (t > 1.0 - screenEnd) {
+        return 1.0 - (1.0 - t) / screenEnd * atlasEnd;
+    }
+    float middle = max(1.0 - screenStart - screenEnd, 1e-6);
+    return atlasStart + (t - screenStart) / middle * (1.0 - atlasStart - atlasEnd);
+}
+
 void main() {
-    o_color = texture(sampler2D(t_atlas, s_atlas), v_AtlasCoord);
+    vec2 sliced = vec2(
+        slice(v_Corner.x, v_SliceScreen.x, v_SliceScreen.z, v_SliceAtlas.x, v_SliceAtlas.z),
+        slice(v_Corner.y, v_SliceScreen.y, v_SliceScreen.w, v_SliceAtlas.y, v_SliceAtlas.w));
+    vec2 atlasCoord = v_AtlasRect.xy + sliced * v_AtlasRect.zw;
+    o_color = texture(sampler2D(t_atlas, s_atlas), atlasCoord) * v_Tint;
+
+    if (u_HighContrast != 0u) {
+        // Remove any translucency and push colors away from mid grey, so that the UI stands out
+        // clearly against whatever is behind it. Done in gamma space, where mid grey is
+        // perceptually in the middle.
+        o_color.a = o_color.a > 0.1 ? 1.0 : 0.0;
+        vec3 encoded = pow(o_color.rgb, vec3(1.0 / 2.2));
+        encoded = clamp((encoded - 0.5) * 2.0 + 0.5, 0.0, 1.0);
+        o_color.rgb = pow(encoded, vec3(2.2));
+    }
 }
\ No newline at end of file
@@ -4,32 +4,57 @@ layout(location = 0) in vec2 a_ScreenTopLeft;
 layout(location = 1) in vec2 a_ScreenSize;
 layout(location = 2) in vec2 a_AtlasTopLeft;
 layout(location = 3) in vec2 a_AtlasSize;
+layout(location = 4) in float a_Rotation;
+layout(location = 5) in vec4 a_Tint;
+layout(location = 6) in vec4 a_SliceAtlas;
+layout(location = 7) in vec4 a_SliceScreen;
 
-layout(location = 0) out vec2 v_AtlasCoord;
+layout(set = 1, binding = 0) uniform Locals {
+    uint u_HighContrast;
+    float u_AspectRatio;
+};
+
+layout(location = 0) out vec2 v_Corner;
+layout(location = 1) out vec4 v_Tint;
+layout(location = 2) flat out vec4 v_AtlasRect;
+layout(location = 3) flat out vec4 v_SliceAtlas;
+layout(location = 4) flat out vec4 v_SliceScreen;
 
 void main() {
-    vec2 screenCoord;
+    vec2 corner;
     switch (gl_VertexIndex) {
         case 0:
-            screenCoord = a_ScreenTopLeft;
-            v_AtlasCoord = a_AtlasTopLeft;
+            corner = vec2(0.0, 0.0);
             break;
         case 1:
-            screenCoord = a_ScreenTopLeft + vec2(a_ScreenSize.x, 0);
-            v_AtlasCoord = a_AtlasTopLeft + vec2(a_AtlasSize.x, 0);
+            corner = vec2(1.0, 0.0);
             break;
         case 2:
-            screenCoord = a_ScreenTopLeft + vec2(0, a_ScreenSize.y);
-            v_AtlasCoord = a_AtlasTopLeft + vec2(0, a_AtlasSize.y);
+            corner = vec2(0.0, 1.0);
             break;
         case 3:
-            screenCoord = a_ScreenTopLeft + a_ScreenSize;
-            v_AtlasCoord = a_AtlasTopLeft + a_AtlasSize;
+            corner = vec2(1.0, 1.0);
             break;
         default:
             // Write outside of clip space to discard the vertex
             gl_Position = vec4(10.0, 10.0, 10.0, 1.0);
+            return;
     }
 
+    // Rotate about the center with x scaled to match y, so that the sprite isn't sheared on a
+    // non-square output
+    vec2 offset = (corner - 0.5) * a_ScreenSize * vec2(u_AspectRatio, 1.0);
+    float s = sin(a_Rotation);
+    float c = cos(a_Rotation);
+    offset = mat2(c, s, -s, c) * offset / vec2(u_AspectRatio, 1.0);
+
+    vec2 screenCoord = a_ScreenTopLeft + 0.5 * a_ScreenSize + offset;
+    v_Corner = corner;
+    // Tints are given gamma encoded like any other UI color, but blended in linear space
+    v_Tint = vec4(pow(a_Tint.rgb, vec3(2.2)), a_Tint.a);
+    v_AtlasRect = vec4(a_AtlasTopLeft, a_AtlasSize);
+    v_SliceAtlas = a_SliceAtlas;
+    v_SliceScreen = a_SliceScreen;
+
     gl_Position = vec4(screenCoord, 0.0, 1.0);
-}
\ No newline at end of file
+}
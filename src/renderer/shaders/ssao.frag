@@ -0,0 +1,98 @@
+#version 450
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_occlusion;
+
+// Has to match KERNEL_SIZE in ssao.rs
+const int KERNEL_SIZE = 16;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_Proj;
+    mat4 u_InvProj;
+    // View space sample offsets in the +Z hemisphere, w is unused
+    vec4 u_Kernel[KERNEL_SIZE];
+    // Radius, bias and intensity, w is unused
+    vec4 u_Params;
+    // Size of the rendered region of the scene in xy, and of the occlusion texture's in zw
+    vec4 u_Region;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_Depth;
+layout(set = 0, binding = 2) uniform sampler s_Depth;
+
+// View space position of the surface at the given scene pixel, clamped to the rendered region
+vec3 view_position(ivec2 texel) {
+    texel = clamp(texel, ivec2(0), ivec2(u_Region.xy) - 1);
+    float depth = texelFetch(sampler2D(t_Depth, s_Depth), texel, 0).r;
+    vec2 uv = (vec2(texel) + 0.5) / u_Region.xy;
+    vec4 position = u_InvProj * vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    return position.xyz / position.w;
+}
+
+// Normal of the surface at the given scene pixel, from whichever neighbour along each axis is
+// closest in depth so that silhouette edges don't bend it towards the background
+vec3 view_normal(ivec2 texel, vec3 position) {
+    vec3 left = position - view_position(texel - ivec2(1, 0));
+    vec3 right = view_position(texel + ivec2(1, 0)) - position;
+    vec3 up = position - view_position(texel - ivec2(0, 1));
+    vec3 down = view_position(texel + ivec2(0, 1)) - position;
+
+    vec3 dx = abs(left.z) < abs(right.z) ? left : right;
+    vec3 dy = abs(up.z) < abs(down.z) ? up : down;
+
+    // Texel rows run down the screen, the opposite way to view space Y
+    return normalize(cross(dy, dx));
+}
+
+// Rotation of the sample kernel about the normal, repeating every 4x4 pixels so that the blur
+// pass evens it out exactly
+vec3 kernel_rotation(ivec2 pixel) {
+    ivec2 cell = pixel & 3;
+    float angle = float(cell.x * 4 + cell.y) * 2.39996323;
+    return vec3(cos(angle), sin(angle), 0.0);
+}
+
+void main() {
+    vec2 pixel = v_TexCoord * u_Region.zw;
+    ivec2 texel = ivec2(pixel * 2.0);
+
+    float depth = texelFetch(sampler2D(t_Depth, s_Depth), texel, 0).r;
+#ifdef REVERSE_Z
+    if (depth <= 0.0) {
+#else
+    if (depth >= 1.0) {
+#endif
+        // Nothing was drawn here to be occluded
+        o_occlusion = vec4(1.0);
+        return;
+    }
+
+    float radius = u_Params.x;
+    float bias = u_Params.y;
+    float intensity = u_Params.z;
+
+    vec3 position = view_position(texel);
+    vec3 normal = view_normal(texel, position);
+
+    vec3 rotation = kernel_rotation(ivec2(pixel));
+    vec3 tangent = normalize(rotation - normal * dot(rotation, normal));
+    vec3 bitangent = cross(normal, tangent);
+    mat3 tbn = mat3(tangent, bitangent, normal);
+
+    float occlusion = 0.0;
+    for (int i = 0; i < KERNEL_SIZE; i++) {
+        vec3 sample_position = position + tbn * u_Kernel[i].xyz * radius;
+
+        vec4 clip = u_Proj * vec4(sample_position, 1.0);
+        vec2 uv = vec2(clip.x, -clip.y) / clip.w * 0.5 + 0.5;
+        float scene_z = view_position(ivec2(uv * u_Region.xy)).z;
+
+        // Fade out occluders far in front of the surface, which are most likely separate objects
+        float range = smoothstep(0.0, 1.0, radius / abs(position.z - scene_z));
+        occlusion += (scene_z >= sample_position.z + bias ? 1.0 : 0.0) * range;
+    }
+
+    float unoccluded = 1.0 - occlusion / float(KERNEL_SIZE);
+    o_occlusion = vec4(pow(unoccluded, intensity));
+}
@@ -0,0 +1,33 @@
+#version 450
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_occlusion;
+
+const int KERNEL_SIZE = 16;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_Proj;
+    mat4 u_InvProj;
+    vec4 u_Kernel[KERNEL_SIZE];
+    vec4 u_Params;
+    // Size of the rendered region of the scene in xy, and of the occlusion texture's in zw
+    vec4 u_Region;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_Occlusion;
+layout(set = 0, binding = 2) uniform sampler s_Occlusion;
+
+void main() {
+    // Averages over the 4x4 block that the kernel rotations repeat across
+    ivec2 texel = ivec2(v_TexCoord * u_Region.zw);
+    ivec2 max_texel = ivec2(u_Region.zw) - 1;
+    float occlusion = 0.0;
+    for (int x = -2; x < 2; x++) {
+        for (int y = -2; y < 2; y++) {
+            ivec2 offset_texel = clamp(texel + ivec2(x, y), ivec2(0), max_texel);
+            occlusion += texelFetch(sampler2D(t_Occlusion, s_Occlusion), offset_texel, 0).r;
+        }
+    }
+    o_occlusion = vec4(occlusion / 16.0);
+}
@@ -0,0 +1,76 @@
+#version 450
+
+// Blends the tonemapped scene in to the history of previous frames, fetched from wherever each
+// pixel was last frame. The history is clamped to the range of colors around the pixel this
+// frame, so that whatever was disoccluded or changed doesn't leave a trail behind.
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    // Transforms this frame's unjittered clip space in to last frame's
+    mat4 u_Reproject;
+    // Clip space offset the scene was drawn with this frame in xy, zw are unused
+    vec4 u_Jitter;
+    // Size of the rendered region in xy, and of the whole history texture in zw
+    vec4 u_Region;
+    // How much of this frame is blended in to the history in x, yzw are unused
+    vec4 u_Params;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_Current;
+layout(set = 0, binding = 2) uniform texture2D t_History;
+// Change in texture coordinates since last frame in xy, and whether the forward stage drew
+// anything there in w
+layout(set = 0, binding = 3) uniform texture2D t_Motion;
+layout(set = 0, binding = 4) uniform texture2D t_Depth;
+layout(set = 0, binding = 5) uniform sampler s_Linear;
+
+void main() {
+    // The viewport covers exactly the rendered region, so fragments line up with its texels
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    ivec2 last_texel = ivec2(u_Region.xy) - 1;
+    vec3 current = texelFetch(sampler2D(t_Current, s_Linear), texel, 0).rgb;
+
+    vec3 neighbourhood_min = current;
+    vec3 neighbourhood_max = current;
+    for (int y = -1; y <= 1; y++) {
+        for (int x = -1; x <= 1; x++) {
+            ivec2 neighbour_texel = clamp(texel + ivec2(x, y), ivec2(0), last_texel);
+            vec3 neighbour = texelFetch(sampler2D(t_Current, s_Linear), neighbour_texel, 0).rgb;
+            neighbourhood_min = min(neighbourhood_min, neighbour);
+            neighbourhood_max = max(neighbourhood_max, neighbour);
+        }
+    }
+
+    vec2 uv = gl_FragCoord.xy / u_Region.xy;
+    vec4 motion = texelFetch(sampler2D(t_Motion, s_Linear), texel, 0);
+    vec2 velocity = motion.xy;
+    if (motion.w == 0.0) {
+        // Nothing was drawn here by the forward stage, eg. the skybox, so the pixel is reprojected
+        // from its depth. This is exact for anything that only moves with the camera.
+        float depth = texelFetch(sampler2D(t_Depth, s_Linear), texel, 0).r;
+        vec2 ndc = vec2(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0) - u_Jitter.xy;
+        vec4 previous = u_Reproject * vec4(ndc, depth, 1.0);
+        velocity = (ndc - previous.xy / previous.w) * vec2(0.5, -0.5);
+    }
+
+    vec2 previous_uv = uv - velocity;
+    float weight = u_Params.x;
+    if (any(lessThan(previous_uv, vec2(0.0))) || any(greaterThan(previous_uv, vec2(1.0)))) {
+        // Just came on screen, so there's no history for it
+        weight = 1.0;
+    }
+
+    // Keep to the rendered region, rather than filtering in whatever is left beyond it
+    vec2 history_position = clamp(previous_uv * u_Region.xy, vec2(0.5), u_Region.xy - 0.5);
+    vec3 history = textureLod(
+        sampler2D(t_History, s_Linear),
+        history_position / u_Region.zw,
+        0.0
+    ).rgb;
+    history = clamp(history, neighbourhood_min, neighbourhood_max);
+
+    o_color = vec4(mix(history, current, weight), 1.0);
+}
@@ -0,0 +1,62 @@
+#version 450
+
+// Blends the terrain's tiled layers by its splat map, lit by the sun and a flat ambient term.
+
+#define MAX_LAYERS 4
+
+layout(location = 0) in vec3 v_Position;
+layout(location = 1) in vec3 v_Normal;
+layout(location = 2) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_ViewProj;
+    // World space direction towards the sun in xyz, w is unused
+    vec4 u_SunDirection;
+    // Linear color of the sun scaled by its intensity in rgb, a is unused
+    vec4 u_SunColor;
+    // World units covered by each repeat of the layers in x, layer count in y, zw are unused
+    vec4 u_Params;
+    // World space plane that everything behind is discarded at, or zero to keep everything
+    vec4 u_ClipPlane;
+};
+
+layout(set = 1, binding = 0) uniform texture2D t_SplatMap;
+layout(set = 1, binding = 1) uniform sampler s_SplatMap;
+layout(set = 1, binding = 2) uniform texture2DArray t_Layers;
+layout(set = 1, binding = 3) uniform sampler s_Layers;
+
+// Matches the forward stage's lighting when the frame has no skybox
+const float AMBIENT = 0.02;
+
+void main() {
+    // As with the forward shader, there are no user clip distances to use instead
+    if (dot(vec4(v_Position, 1.0), u_ClipPlane) < 0.0) {
+        discard;
+    }
+
+    int layer_count = int(u_Params.y);
+    vec4 weights = texture(sampler2D(t_SplatMap, s_SplatMap), v_TexCoord);
+    vec2 tiled = v_Position.xy / u_Params.x;
+
+    vec3 albedo = vec3(0.0);
+    float total = 0.0;
+    for (int i = 0; i < MAX_LAYERS; i++) {
+        // Channels past the last layer are ignored
+        if (i >= layer_count) {
+            break;
+        }
+        albedo += texture(sampler2DArray(t_Layers, s_Layers), vec3(tiled, i)).rgb * weights[i];
+        total += weights[i];
+    }
+    if (total > 0.0) {
+        albedo /= total;
+    } else {
+        // Anywhere the splat map leaves bare shows the first layer
+        albedo = texture(sampler2DArray(t_Layers, s_Layers), vec3(tiled, 0.0)).rgb;
+    }
+
+    float n_dot_l = max(dot(normalize(v_Normal), u_SunDirection.xyz), 0.0);
+    o_color = vec4(albedo * (u_SunColor.rgb * n_dot_l + AMBIENT), 1.0);
+}
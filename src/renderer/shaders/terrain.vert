@@ -0,0 +1,29 @@
+#version 450
+
+layout(location = 0) in vec3 a_Pos;
+layout(location = 1) in vec3 a_Normal;
+layout(location = 2) in vec2 a_TexCoord;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_ViewProj;
+    // World space direction towards the sun in xyz, w is unused
+    vec4 u_SunDirection;
+    // Linear color of the sun scaled by its intensity in rgb, a is unused
+    vec4 u_SunColor;
+    // World units covered by each repeat of the layers in x, layer count in y, zw are unused
+    vec4 u_Params;
+    // World space plane that everything behind is discarded at, or zero to keep everything
+    vec4 u_ClipPlane;
+};
+
+layout(location = 0) out vec3 v_Position;
+layout(location = 1) out vec3 v_Normal;
+layout(location = 2) out vec2 v_TexCoord;
+
+void main() {
+    // Terrain is built in world space, so there's no model matrix
+    v_Position = a_Pos;
+    v_Normal = a_Normal;
+    v_TexCoord = a_TexCoord;
+    gl_Position = u_ViewProj * vec4(a_Pos, 1.0);
+}
@@ -0,0 +1,15 @@
+#version 450
+
+layout(location = 0) in vec2 v_AtlasCoord;
+layout(location = 1) in vec4 v_Color;
+
+layout(set = 0, binding = 0) uniform texture2D t_glyphs;
+layout(set = 0, binding = 1) uniform sampler s_glyphs;
+
+layout(location = 0) out vec4 o_color;
+
+void main() {
+    // The glyph atlas only stores coverage, in the red channel
+    float coverage = texture(sampler2D(t_glyphs, s_glyphs), v_AtlasCoord).r;
+    o_color = vec4(v_Color.rgb, v_Color.a * coverage);
+}
@@ -0,0 +1,46 @@
+#version 450
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+// Values for u_Tonemapper
+const uint TONEMAPPER_ACES = 0u;
+const uint TONEMAPPER_REINHARD = 1u;
+
+layout(set = 0, binding = 0) uniform Locals {
+    uint u_Tonemapper;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_Scene;
+layout(set = 0, binding = 2) uniform sampler s_Scene;
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 color) {
+    const float a = 2.51;
+    const float b = 0.03;
+    const float c = 2.43;
+    const float d = 0.59;
+    const float e = 0.14;
+    return clamp((color * (a * color + b)) / (color * (c * color + d) + e), 0.0, 1.0);
+}
+
+vec3 reinhard(vec3 color) {
+    return color / (1.0 + color);
+}
+
+void main() {
+    // The viewport covers exactly the rendered region of the scene, so fragments line up with its
+    // texels
+    vec3 hdr = texelFetch(sampler2D(t_Scene, s_Scene), ivec2(gl_FragCoord.xy), 0).rgb;
+
+    vec3 ldr;
+    if (u_Tonemapper == TONEMAPPER_REINHARD) {
+        ldr = reinhard(hdr);
+    } else {
+        ldr = aces(hdr);
+    }
+
+    // Left linear, the sRGB target encodes it on write
+    o_color = vec4(ldr, 1.0);
+}
@@ -0,0 +1,93 @@
+#version 450
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_color;
+
+// Values for u_Filter
+const uint FILTER_BILINEAR = 0u;
+const uint FILTER_CAS = 1u;
+const uint FILTER_DOWNSAMPLE = 2u;
+
+// Maximum number of taps along each axis when downsampling, enough for a 4x render scale
+const int MAX_DOWNSAMPLE_TAPS = 4;
+
+layout(set = 0, binding = 0) uniform Locals {
+    // Maps 0..1 texture coordinates on to the region of the source that was rendered to
+    vec2 u_UvScale;
+
+    // Texture coordinate of the center of the last rendered texel, to prevent bilinear filtering
+    // pulling in stale data from outside the rendered region
+    vec2 u_UvMax;
+
+    // Size of a single source texel in texture coordinates
+    vec2 u_TexelSize;
+
+    // Size of a single output pixel in source texture coordinates
+    vec2 u_Footprint;
+
+    // 0..1, how strongly the contrast adaptive sharpening filter sharpens
+    float u_Sharpness;
+
+    uint u_Filter;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_source;
+layout(set = 0, binding = 2) uniform sampler s_source;
+
+vec3 sample_source(vec2 uv) {
+    uv = clamp(uv, 0.5 * u_TexelSize, u_UvMax);
+    return texture(sampler2D(t_source, s_source), uv).rgb;
+}
+
+// Contrast adaptive sharpening, loosely following AMD's FidelityFX CAS.
+//
+// Sharpens using a cross shaped kernel whose negative lobe weight is scaled down in areas that
+// already have a lot of contrast, which avoids the ringing a fixed unsharp mask would produce.
+vec3 cas(vec2 uv) {
+    vec3 c = sample_source(uv);
+    vec3 n = sample_source(uv + vec2(0.0, -u_TexelSize.y));
+    vec3 s = sample_source(uv + vec2(0.0, u_TexelSize.y));
+    vec3 e = sample_source(uv + vec2(u_TexelSize.x, 0.0));
+    vec3 w = sample_source(uv + vec2(-u_TexelSize.x, 0.0));
+
+    vec3 min_rgb = min(c, min(min(n, s), min(e, w)));
+    vec3 max_rgb = max(c, max(max(n, s), max(e, w)));
+
+    // How much headroom there is before the result would clip, relative to the local contrast
+    vec3 amplitude = sqrt(clamp(min(min_rgb, 1.0 - max_rgb) / max(max_rgb, 1e-5), 0.0, 1.0));
+    vec3 weight = -amplitude * mix(1.0 / 8.0, 1.0 / 5.0, u_Sharpness);
+
+    return (c + weight * (n + s + e + w)) / (1.0 + 4.0 * weight);
+}
+
+// Box filter over every source texel covered by the output pixel
+vec3 downsample(vec2 uv) {
+    ivec2 taps = clamp(ivec2(ceil(u_Footprint / u_TexelSize)), ivec2(1), ivec2(MAX_DOWNSAMPLE_TAPS));
+    vec2 step_size = u_Footprint / vec2(taps);
+    vec2 origin = uv - 0.5 * u_Footprint + 0.5 * step_size;
+
+    vec3 total = vec3(0.0);
+    for (int y = 0; y < taps.y; y++) {
+        for (int x = 0; x < taps.x; x++) {
+            total += sample_source(origin + vec2(x, y) * step_size);
+        }
+    }
+
+    return total / float(taps.x * taps.y);
+}
+
+void main() {
+    vec2 uv = v_TexCoord * u_UvScale;
+
+    vec3 color;
+    if (u_Filter == FILTER_CAS) {
+        color = cas(uv);
+    } else if (u_Filter == FILTER_DOWNSAMPLE) {
+        color = downsample(uv);
+    } else {
+        color = sample_source(uv);
+    }
+
+    o_color = vec4(color, 1.0);
+}
\ No newline at end of file
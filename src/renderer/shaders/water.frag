@@ -0,0 +1,109 @@
+#version 450
+
+// Blends the water's reflection and refraction by the Fresnel term, both bent by the waves.
+
+layout(location = 0) in vec3 v_Position;
+layout(location = 1) in vec3 v_ViewPosition;
+
+layout(location = 0) out vec4 o_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_View;
+    mat4 u_Proj;
+    // Inverse of the unjittered projection the refraction was drawn with
+    mat4 u_InvProj;
+    // World space position of the camera in xyz, w is unused
+    vec4 u_CameraPosition;
+    // World space direction towards the sun in xyz, w is unused
+    vec4 u_SunDirection;
+    // Linear color of the sun scaled by its intensity in rgb, a is unused
+    vec4 u_SunColor;
+    // Minimum corner of the surface in xy, maximum in zw
+    vec4 u_Rect;
+    // Height, wave scale, distortion and time
+    vec4 u_Params;
+    // Linear color of the water in rgb, and its density in a
+    vec4 u_Color;
+    // Size of the rendered region in xy, and the fraction of the reflection and refraction
+    // targets their views cover in zw
+    vec4 u_Region;
+    // Whether the refraction was drawn in x, yzw are unused
+    vec4 u_Flags;
+};
+
+layout(set = 0, binding = 1) uniform texture2D t_Reflection;
+layout(set = 0, binding = 2) uniform texture2D t_Refraction;
+layout(set = 0, binding = 3) uniform texture2D t_RefractionDepth;
+layout(set = 0, binding = 4) uniform sampler s_Target;
+layout(set = 0, binding = 5) uniform sampler s_Depth;
+layout(set = 0, binding = 6) uniform texture2D t_Waves;
+layout(set = 0, binding = 7) uniform sampler s_Waves;
+
+// Reflectance of water looking straight down at it
+const float F0 = 0.02;
+const float SHININESS = 512.0;
+
+// Distance seen through the water where nothing was drawn below it
+const float OPEN_WATER = 1e6;
+
+vec3 wave_normal(vec2 coord) {
+    return texture(sampler2D(t_Waves, s_Waves), coord).xyz * 2.0 - 1.0;
+}
+
+// Distance from the camera to whatever the refraction shows at the given screen position
+float refraction_distance(vec2 uv) {
+    float depth = textureLod(sampler2D(t_RefractionDepth, s_Depth), uv * u_Region.zw, 0.0).r;
+#ifdef REVERSE_Z
+    if (depth == 0.0) {
+        return OPEN_WATER;
+    }
+#else
+    if (depth == 1.0) {
+        return OPEN_WATER;
+    }
+#endif
+    vec4 view = u_InvProj * vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    return length(view.xyz / view.w);
+}
+
+void main() {
+    // Two copies of the waves scrolling across each other at different scales
+    float time = u_Params.w;
+    vec2 coord = v_Position.xy / u_Params.y;
+    vec3 wave0 = wave_normal(coord + time * vec2(0.031, 0.019));
+    vec3 wave1 = wave_normal(coord * 1.7 + time * vec2(-0.023, 0.037));
+    vec3 normal = normalize(vec3(wave0.xy + wave1.xy, wave0.z * wave1.z));
+
+    vec3 to_camera = normalize(u_CameraPosition.xyz - v_Position);
+    vec2 uv = gl_FragCoord.xy / u_Region.xy;
+    vec2 offset = normal.xy * u_Params.z;
+
+    // The reflection was drawn flipped horizontally
+    vec2 reflection_uv = clamp(vec2(1.0 - uv.x, uv.y) + offset, 0.0, 1.0);
+    vec3 reflection = texture(sampler2D(t_Reflection, s_Target), reflection_uv * u_Region.zw).rgb;
+
+    vec3 refraction = u_Color.rgb;
+    if (u_Flags.x > 0.0) {
+        float surface_distance = length(v_ViewPosition);
+        vec2 refraction_uv = clamp(uv + offset, 0.0, 1.0);
+        float scene_distance = refraction_distance(refraction_uv);
+        // Bent on to something in front of the water, which would pull it through the surface
+        if (scene_distance < surface_distance) {
+            refraction_uv = uv;
+            scene_distance = refraction_distance(uv);
+        }
+        vec3 below =
+            textureLod(sampler2D(t_Refraction, s_Target), refraction_uv * u_Region.zw, 0.0).rgb;
+        float thickness = max(scene_distance - surface_distance, 0.0);
+        refraction = mix(u_Color.rgb, below, exp(-u_Color.a * thickness));
+    }
+
+    float cos_theta = abs(dot(normal, to_camera));
+    float fresnel = F0 + (1.0 - F0) * pow(1.0 - cos_theta, 5.0);
+
+    vec3 half_vector = normalize(u_SunDirection.xyz + to_camera);
+    float specular = pow(max(dot(normal, half_vector), 0.0), SHININESS);
+
+    vec3 color = mix(refraction, reflection, fresnel) + u_SunColor.rgb * specular * fresnel;
+    o_color = vec4(color, 1.0);
+}
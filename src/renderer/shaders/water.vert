@@ -0,0 +1,34 @@
+#version 450
+
+layout(location = 0) out vec3 v_Position;
+layout(location = 1) out vec3 v_ViewPosition;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 u_View;
+    mat4 u_Proj;
+    mat4 u_InvProj;
+    vec4 u_CameraPosition;
+    vec4 u_SunDirection;
+    vec4 u_SunColor;
+    // Minimum corner of the surface in xy, maximum in zw
+    vec4 u_Rect;
+    // Height, wave scale, distortion and time
+    vec4 u_Params;
+    vec4 u_Color;
+    vec4 u_Region;
+    vec4 u_Flags;
+};
+
+// Two triangles covering the surface, counter-clockwise seen from above
+const vec2 CORNERS[6] = vec2[](
+    vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(1.0, 1.0),
+    vec2(0.0, 0.0), vec2(1.0, 1.0), vec2(0.0, 1.0)
+);
+
+void main() {
+    vec2 corner = CORNERS[gl_VertexIndex];
+    vec4 position = vec4(mix(u_Rect.xy, u_Rect.zw, corner), u_Params.x, 1.0);
+    v_Position = position.xyz;
+    v_ViewPosition = (u_View * position).xyz;
+    gl_Position = u_Proj * vec4(v_ViewPosition, 1.0);
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, SquareMatrix, Transform, Vector3};
+
+use crate::{
+    error::{Error, Result},
+    shader_cache::ShaderCache,
+    vertex::Vertex,
+};
+use super::{
+    frame_packet::{DirectionalLight, FramePacket, InstanceData},
+    index_buffer::INDEX_FORMATS,
+    instance_buffer::InstanceBuffer,
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+pub const SHADOW_MAP_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// Texels along each side of the shadow map, unless the graphics quality asks for otherwise
+pub const SHADOW_MAP_SIZE: u32 = 2048;
+
+/// Half the width of the square region around the camera that casts and receives shadows
+const SHADOW_DISTANCE: f32 = 20.0;
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct ShadowUniformData {
+    light_view_proj: Matrix4<f32>,
+}
+
+unsafe impl bytemuck::Pod for ShadowUniformData {}
+unsafe impl bytemuck::Zeroable for ShadowUniformData {}
+
+/// Build the matrix that transforms world space in to the directional light's clip space, for a
+/// camera with the given view matrix
+///
+/// The light's view is an orthographic box centred on the camera, with depth in the 0..1 range
+/// that wgpu expects.
+pub fn light_view_proj(light: &DirectionalLight, view: Matrix4<f32>) -> Matrix4<f32> {
+    let camera_position = view
+        .invert()
+        .map(|inverse| inverse.transform_point(Point3::origin()))
+        .unwrap_or_else(Point3::origin);
+
+    let direction = light.direction.normalize();
+    let up = if direction.z.abs() > 0.99 {
+        Vector3::unit_x()
+    } else {
+        Vector3::unit_z()
+    };
+
+    let eye = camera_position - direction * SHADOW_DISTANCE * 2.0;
+    let light_view = Matrix4::look_at_dir(eye, direction, up);
+    let light_proj = cgmath::ortho(
+        -SHADOW_DISTANCE,
+        SHADOW_DISTANCE,
+        -SHADOW_DISTANCE,
+        SHADOW_DISTANCE,
+        0.0,
+        SHADOW_DISTANCE * 4.0,
+    );
+
+    // cgmath produces OpenGL style -1..1 depth
+    let depth_correction = Matrix4::from_translation([0.0, 0.0, 0.5].into())
+        * Matrix4::from_nonuniform_scale(1.0, 1.0, 0.5);
+
+    depth_correction * light_proj * light_view
+}
+
+/// Represents a render stage that renders the depth of the scene as seen from the directional
+/// light in to a shadow map, for the forward stage to sample
+pub struct ShadowRenderStage {
+    /// One for each index format models can have
+    pipelines: HashMap<wgpu::IndexFormat, Rc<wgpu::RenderPipeline>>,
+    uniform_bind_group: wgpu::BindGroup,
+    uniform_buff: wgpu::Buffer,
+
+    #[allow(unused)]
+    texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+
+    /// Texels along each side of the shadow map
+    pub size: u32,
+}
+
+impl ShadowRenderStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        size: u32,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/shadow.vert",
+                shaderc::ShaderKind::Vertex,
+            )
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<ShadowUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Shadow stage uniform buffer"),
+        });
+
+        let uniform_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                }],
+                label: Some("Shadow stage uniform buffer layout"),
+            });
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            bindings: &[wgpu::Binding {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &uniform_buff,
+                    range: 0..std::mem::size_of::<ShadowUniformData>() as wgpu::BufferAddress,
+                },
+            }],
+            label: Some("Shadow stage uniform bind group"),
+        });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&uniform_bind_group_layout],
+        });
+
+        let mut create_pipeline = |index_format| {
+            resources.render_pipeline(device, &RenderPipelineDesc {
+                layout: &pipeline_layout,
+                vertex_shader: &vs_spirv,
+                // Only depth is written
+                fragment_shader: None,
+                rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: wgpu::CullMode::Back,
+                    // Push the stored depth back a little to avoid surfaces shadowing themselves
+                    depth_bias: 2,
+                    depth_bias_slope_scale: 2.0,
+                    depth_bias_clamp: 0.0,
+                }),
+                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+                color_states: &[],
+                depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                    format: SHADOW_MAP_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::LessEqual,
+                    stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
+                    stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
+                    stencil_read_mask: 0,
+                    stencil_write_mask: 0,
+                }),
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format,
+                    vertex_buffers: &[
+                        Vertex::vertex_buffer_descriptor(),
+                        InstanceData::vertex_buffer_descriptor(),
+                    ],
+                },
+                sample_count: 1,
+            })
+        };
+        let pipelines = INDEX_FORMATS
+            .iter()
+            .map(|&index_format| (index_format, create_pipeline(index_format)))
+            .collect();
+
+        let (texture, view) = Self::create_texture(device, size);
+
+        Ok(Self {
+            pipelines,
+            uniform_bind_group,
+            uniform_buff,
+            texture,
+            view,
+            size,
+        })
+    }
+
+    fn create_texture(device: &wgpu::Device, size: u32) -> (wgpu::Texture, wgpu::TextureView) {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Shadow map texture"),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth: 1,
+            },
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: SHADOW_MAP_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        });
+        let view = texture.create_default_view();
+        (texture, view)
+    }
+
+    /// Reallocate the shadow map at a new resolution, after which anything sampling it has to be
+    /// rebound to the new `view`
+    pub fn set_size(&mut self, device: &wgpu::Device, size: u32) {
+        let (texture, view) = Self::create_texture(device, size);
+        self.texture = texture;
+        self.view = view;
+        self.size = size;
+    }
+
+    /// Draw every model in the frame packet in to the shadow map, if it has a directional light
+    ///
+    /// `instances` must already hold this frame's instance data, laid out in frame packet order.
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        instances: &InstanceBuffer<InstanceData>,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Result<()> {
+        let light_view_proj = match frame_packet.directional_light {
+            Some(light) => light_view_proj(&light, frame_packet.view),
+            None => return Ok(()),
+        };
+
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[ShadowUniformData { light_view_proj }]),
+        );
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &self.view,
+                depth_load_op: wgpu::LoadOp::Clear,
+                depth_store_op: wgpu::StoreOp::Store,
+                clear_depth: 1.0,
+                stencil_load_op: wgpu::LoadOp::Clear,
+                stencil_store_op: wgpu::StoreOp::Store,
+                clear_stencil: 0,
+            }),
+        });
+
+        rpass.set_bind_group(0, &self.uniform_bind_group, &[]);
+
+        let mut first_instance = 0;
+        for model in &frame_packet.models {
+            let model_data = renderer
+                .models
+                .get(&model.model_id)
+                .ok_or(Error::InvalidFramePacket("Model with unknown id"))?;
+
+            rpass.set_pipeline(&self.pipelines[&model_data.indices.format]);
+            rpass.set_vertex_buffer(0, &model_data.vertex_buff, 0, 0);
+            rpass.set_vertex_buffer(
+                1,
+                instances.buffer(),
+                InstanceBuffer::<InstanceData>::offset(first_instance),
+                0,
+            );
+            rpass.set_index_buffer(&model_data.indices.buffer, 0, 0);
+            rpass.draw_indexed(
+                0..model_data.indices.count,
+                0,
+                0..model.instances.len() as u32,
+            );
+            renderer.draw_counter.record(model.instances.len() as u32);
+
+            first_instance += model.instances.len();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_light_view_proj_centres_on_camera() {
+        let light = DirectionalLight {
+            direction: [-0.3, -0.5, -1.0].into(),
+            color: [1.0, 1.0, 1.0].into(),
+            intensity: 1.0,
+        };
+        let camera_position = Point3::new(3.0, -2.0, 1.0);
+        let view = Matrix4::look_at_dir(camera_position, Vector3::unit_x(), Vector3::unit_z());
+
+        let clip = light_view_proj(&light, view).transform_point(camera_position);
+        assert_relative_eq!(clip, Point3::new(0.0, 0.0, 0.5), epsilon = 1e-5);
+    }
+}
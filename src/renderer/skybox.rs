@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use cgmath::{Matrix4, SquareMatrix};
+
+use crate::{
+    error::{Error, Result},
+    shader_cache::ShaderCache,
+};
+use super::{
+    frame_packet::FramePacket,
+    render_target::{RenderTarget, Viewport},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer, SkyboxId,
+};
+
+/// Represents a single environment cubemap on the GPU
+pub struct GpuSkybox {
+    #[allow(unused)]
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+}
+
+impl GpuSkybox {
+    /// Upload six square faces of equal size, in the order +X, -X, +Y, -Y, +Z, -Z
+    ///
+    /// The faces follow the usual cubemap convention of +Y being up, which the skybox shader
+    /// maps on to the world's +Z.
+    pub fn new(
+        faces: &[image::RgbaImage; 6],
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<Self> {
+        let size = faces[0].width();
+        if size == 0 {
+            return Err(Error::InvalidAsset("Skybox faces are empty"));
+        }
+        if faces.iter().any(|face| face.width() != size || face.height() != size) {
+            return Err(Error::InvalidAsset("Skybox faces must be square and all the same size"));
+        }
+
+        let face_extent = wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Skybox cubemap texture"),
+            size: face_extent,
+            array_layer_count: 6,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Skybox upload commands"),
+        });
+        for (layer, face) in faces.iter().enumerate() {
+            let face_buff = device.create_buffer_with_data(
+                face.as_flat_samples().as_slice(),
+                wgpu::BufferUsage::COPY_SRC,
+            );
+            encoder.copy_buffer_to_texture(
+                wgpu::BufferCopyView {
+                    buffer: &face_buff,
+                    offset: 0,
+                    bytes_per_row: 4 * size,
+                    rows_per_image: size,
+                },
+                wgpu::TextureCopyView {
+                    texture: &texture,
+                    mip_level: 0,
+                    array_layer: layer as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                face_extent,
+            );
+        }
+        queue.submit(&[encoder.finish()]);
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            dimension: wgpu::TextureViewDimension::Cube,
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            array_layer_count: 6,
+        });
+
+        Ok(Self { texture, view })
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct SkyboxUniformData {
+    /// Transforms clip space in to a world space direction, ignoring the camera's position
+    inv_view_proj: Matrix4<f32>,
+}
+
+unsafe impl bytemuck::Pod for SkyboxUniformData {}
+unsafe impl bytemuck::Zeroable for SkyboxUniformData {}
+
+/// Represents a render stage that fills every pixel the scene didn't draw to with a cubemap, as
+/// seen from the camera's rotation alone
+pub struct SkyboxRenderStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    uniform_bind_group: wgpu::BindGroup,
+    uniform_buff: wgpu::Buffer,
+    texture_bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    texture_bind_groups: HashMap<SkyboxId, wgpu::BindGroup>,
+    texture_sampler: Rc<wgpu::Sampler>,
+}
+
+impl SkyboxRenderStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        target: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader_with_defines(
+                "./src/renderer/shaders/skybox.vert",
+                shaderc::ShaderKind::Vertex,
+                target.depth_order.shader_defines(),
+            )
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader_with_defines(
+                "./src/renderer/shaders/skybox.frag",
+                shaderc::ShaderKind::Fragment,
+                target.depth_order.shader_defines(),
+            )
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<SkyboxUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Skybox stage uniform buffer"),
+        });
+
+        let uniform_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                }],
+                label: Some("Skybox stage uniform buffer layout"),
+            });
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            bindings: &[wgpu::Binding {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &uniform_buff,
+                    range: 0..std::mem::size_of::<SkyboxUniformData>() as wgpu::BufferAddress,
+                },
+            }],
+            label: Some("Skybox stage uniform bind group"),
+        });
+
+        let texture_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::Cube,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Skybox stage texture bind group layout"),
+            });
+
+        let render_pipeline_layout =
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&uniform_bind_group_layout, &texture_bind_group_layout],
+            });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &render_pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: RenderTarget::COLOR_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            // The sky is drawn on the far plane, so only shows through where the depth buffer
+            // still has its clear value
+            depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                format: RenderTarget::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: target.depth_order.compare(wgpu::CompareFunction::LessEqual),
+                stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
+                stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
+                stencil_read_mask: 0,
+                stencil_write_mask: 0,
+            }),
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: target.sample_count,
+        });
+
+        let texture_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        Ok(Self {
+            pipeline,
+            uniform_bind_group,
+            uniform_buff,
+            texture_bind_group_layout,
+            texture_bind_groups: HashMap::new(),
+            texture_sampler,
+        })
+    }
+
+    pub fn add_skybox(&mut self, device: &wgpu::Device, skybox_id: SkyboxId, skybox: &GpuSkybox) {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.texture_bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&skybox.view),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.texture_sampler),
+                },
+            ],
+            label: Some("Skybox bind group"),
+        });
+
+        self.texture_bind_groups.insert(skybox_id, bind_group);
+    }
+
+    /// Draws on top of the scene that's already in the target, so has to come after it
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &RenderTarget,
+        viewport: Viewport,
+    ) -> Result<()> {
+        let skybox_id = match frame_packet.skybox {
+            Some(skybox_id) => skybox_id,
+            None => return Ok(()),
+        };
+        let bind_group = self
+            .texture_bind_groups
+            .get(&skybox_id)
+            .ok_or(Error::InvalidFramePacket("Skybox with unknown id"))?;
+
+        // Drop the translation so that the sky stays infinitely far away
+        let mut view_rotation = frame_packet.view;
+        view_rotation.w = cgmath::Vector4::unit_w();
+        let inv_view_proj = (frame_packet.proj * view_rotation)
+            .invert()
+            .unwrap_or_else(Matrix4::identity);
+
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[SkyboxUniformData { inv_view_proj }]),
+        );
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[target.color_attachment(wgpu::LoadOp::Load)],
+            depth_stencil_attachment: Some(target.depth_attachment(wgpu::LoadOp::Load)),
+        });
+
+        viewport.apply(&mut rpass);
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        rpass.set_bind_group(1, bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+
+        Ok(())
+    }
+}
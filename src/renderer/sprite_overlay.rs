@@ -1,36 +1,104 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use crate::shader_cache::ShaderCache;
-use super::{frame_packet::{FramePacket, SpriteInstanceData}, Renderer, AtlasId, GpuAtlas};
+use crate::{
+    error::{Error, Result},
+    shader_cache::ShaderCache,
+};
+use super::{
+    frame_packet::{FramePacketSprites, SpriteInstanceData},
+    frames_in_flight::FrameSlot,
+    instance_buffer::InstanceBuffer,
+    render_target::ColorTarget,
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    staging_belt::StagingBelt,
+    Renderer, AtlasId,
+};
+
+const SPRITE_VERTEX_SHADER: &str = "./src/renderer/shaders/sprite.vert";
+const SPRITE_FRAGMENT_SHADER: &str = "./src/renderer/shaders/sprite.frag";
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct SpriteUniformData {
+    high_contrast: u32,
+    aspect_ratio: f32,
+}
+
+unsafe impl bytemuck::Pod for SpriteUniformData {}
+unsafe impl bytemuck::Zeroable for SpriteUniformData {}
+
+/// A run of consecutive instances drawn from the same atlas
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SpriteBatch {
+    atlas_id: AtlasId,
+    count: usize,
+}
+
+/// Order every sprite in the frame by layer, then split them in to as few batches as that allows
+///
+/// UI sprites are placed in clip space for an output of `output_size` along the way.
+fn sort_into_batches(
+    sprite_sets: &[FramePacketSprites],
+    output_size: winit::dpi::PhysicalSize<u32>,
+    ui_scale: f32,
+) -> (Vec<SpriteInstanceData>, Vec<SpriteBatch>) {
+    let mut sprites: Vec<_> = sprite_sets
+        .iter()
+        .flat_map(|set| {
+            let laid_out = set
+                .ui_sprites
+                .iter()
+                .map(move |&sprite| sprite.to_clip_space(output_size, ui_scale));
+            set.sprites
+                .iter()
+                .copied()
+                .chain(laid_out)
+                .map(move |sprite| (set.atlas_id, sprite))
+        })
+        .collect();
+
+    // Stable, so sprites on the same layer keep the order they were given in
+    sprites.sort_by_key(|(_, sprite)| sprite.layer);
+
+    let mut batches: Vec<SpriteBatch> = Vec::new();
+    for (atlas_id, _) in &sprites {
+        match batches.last_mut() {
+            Some(batch) if batch.atlas_id == *atlas_id => batch.count += 1,
+            _ => batches.push(SpriteBatch {
+                atlas_id: *atlas_id,
+                count: 1,
+            }),
+        }
+    }
+
+    (sprites.into_iter().map(|(_, sprite)| sprite).collect(), batches)
+}
 
 pub struct SpriteOverlayRenderStage {
-    pipeline: wgpu::RenderPipeline,
-    texture_bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: Rc<wgpu::RenderPipeline>,
+    pipeline_layout: Rc<wgpu::PipelineLayout>,
+    shader_cache: ShaderCache,
+    uniform_bind_group: wgpu::BindGroup,
+    uniform_buff: wgpu::Buffer,
+    texture_bind_group_layout: Rc<wgpu::BindGroupLayout>,
     texture_bind_groups: HashMap<AtlasId, wgpu::BindGroup>,
-    texture_sampler: wgpu::Sampler,
+    texture_sampler: Rc<wgpu::Sampler>,
+    instances: InstanceBuffer<SpriteInstanceData>,
+
+    /// How this frame's instances are split between atlases, in draw order
+    batches: Vec<SpriteBatch>,
 }
 
 impl SpriteOverlayRenderStage {
-    pub async fn new(device: &wgpu::Device) -> Self {
+    pub async fn new(device: &wgpu::Device, resources: &mut ResourceCache) -> Result<Self> {
         let mut shader_cache = ShaderCache::new();
-        let vs_spirv = shader_cache
-            .get_shader(
-                "./src/renderer/shaders/sprite.vert",
-                shaderc::ShaderKind::Vertex,
-            )
-            .await;
-        let fs_spirv = shader_cache
-            .get_shader(
-                "./src/renderer/shaders/sprite.frag",
-                shaderc::ShaderKind::Fragment,
-            )
-            .await;
-
-        let vs_module = device.create_shader_module(&vs_spirv);
-        let fs_module = device.create_shader_module(&fs_spirv);
+        shader_cache.load_source(SPRITE_VERTEX_SHADER).await?;
+        shader_cache.load_source(SPRITE_FRAGMENT_SHADER).await?;
 
         let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
                 bindings: &[
                     wgpu::BindGroupLayoutEntry {
                         binding: 0,
@@ -50,21 +118,83 @@ impl SpriteOverlayRenderStage {
                 label: Some("UI render stage bind group layout"),
             });
 
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<SpriteUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("UI render stage uniform buffer"),
+        });
+
+        let uniform_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                }],
+                label: Some("UI render stage uniform buffer layout"),
+            });
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            bindings: &[wgpu::Binding {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &uniform_buff,
+                    range: 0..std::mem::size_of::<SpriteUniformData>() as wgpu::BufferAddress,
+                },
+            }],
+            label: Some("UI render stage uniform bind group"),
+        });
+
         let render_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                bind_group_layouts: &[&texture_bind_group_layout],
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&texture_bind_group_layout, &uniform_bind_group_layout],
             });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            layout: &render_pipeline_layout,
-            vertex_stage: wgpu::ProgrammableStageDescriptor {
-                module: &vs_module,
-                entry_point: "main",
-            },
-            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
-                module: &fs_module,
-                entry_point: "main",
-            }),
+        let pipeline =
+            Self::create_pipeline(device, resources, &render_pipeline_layout, &mut shader_cache)?;
+
+        let texture_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        Ok(Self {
+            pipeline,
+            pipeline_layout: render_pipeline_layout,
+            shader_cache,
+            uniform_bind_group,
+            uniform_buff,
+            texture_sampler,
+            texture_bind_group_layout,
+            texture_bind_groups: HashMap::new(),
+            instances: InstanceBuffer::new(device, "UI render stage instance buffer"),
+            batches: Vec::new(),
+        })
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        layout: &wgpu::PipelineLayout,
+        shader_cache: &mut ShaderCache,
+    ) -> Result<Rc<wgpu::RenderPipeline>> {
+        let vs_spirv =
+            shader_cache.compile(SPRITE_VERTEX_SHADER, shaderc::ShaderKind::Vertex, &[])?;
+        let fs_spirv =
+            shader_cache.compile(SPRITE_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment, &[])?;
+
+        Ok(resources.render_pipeline(device, &RenderPipelineDesc {
+            layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
             rasterization_state: Some(wgpu::RasterizationStateDescriptor {
                 front_face: wgpu::FrontFace::Ccw,
                 cull_mode: wgpu::CullMode::Back,
@@ -74,7 +204,7 @@ impl SpriteOverlayRenderStage {
             }),
             primitive_topology: wgpu::PrimitiveTopology::TriangleStrip,
             color_states: &[wgpu::ColorStateDescriptor {
-                format: wgpu::TextureFormat::Bgra8Unorm,
+                format: ColorTarget::LDR_FORMAT,
                 alpha_blend: wgpu::BlendDescriptor::REPLACE,
                 color_blend: wgpu::BlendDescriptor {
                     src_factor: wgpu::BlendFactor::SrcAlpha,
@@ -91,37 +221,49 @@ impl SpriteOverlayRenderStage {
                 ],
             },
             sample_count: 1,
-            sample_mask: 0,
-            alpha_to_coverage_enabled: false,
-        });
+        }))
+    }
 
-        let texture_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            lod_min_clamp: -100.0,
-            lod_max_clamp: 100.0,
-            compare: wgpu::CompareFunction::Always,
-        });
+    /// Recompile the sprite shaders if either is in the given set of changed files, only
+    /// replacing the pipeline if that succeeds
+    pub fn reload_shaders(
+        &mut self,
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        changed: &HashSet<PathBuf>,
+    ) -> Result<()> {
+        let mut any_changed = false;
+        for path in &[SPRITE_VERTEX_SHADER, SPRITE_FRAGMENT_SHADER] {
+            if changed.contains(Path::new(path)) {
+                self.shader_cache.reload_source(path)?;
+                any_changed = true;
+            }
+        }
 
-        Self {
-            pipeline,
-            texture_sampler,
-            texture_bind_group_layout,
-            texture_bind_groups: HashMap::new(),
+        if any_changed {
+            self.pipeline = Self::create_pipeline(
+                device,
+                resources,
+                &self.pipeline_layout,
+                &mut self.shader_cache,
+            )?;
         }
+        Ok(())
     }
 
-    pub fn add_atlas(&mut self, device: &wgpu::Device, atlas_id: AtlasId, atlas: &GpuAtlas) {
+    /// Make sprites with the given atlas id sample the given texture, eg. a `GpuAtlas`'s
+    pub fn add_atlas(
+        &mut self,
+        device: &wgpu::Device,
+        atlas_id: AtlasId,
+        texture: &wgpu::TextureView,
+    ) {
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &self.texture_bind_group_layout,
             bindings: &[
                 wgpu::Binding {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&atlas.view),
+                    resource: wgpu::BindingResource::TextureView(texture),
                 },
                 wgpu::Binding {
                     binding: 1,
@@ -134,27 +276,59 @@ impl SpriteOverlayRenderStage {
         self.texture_bind_groups.insert(atlas_id, bind_group);
     }
 
+    /// Record uploading this frame's sprites, sorted by layer and laid out for an output of
+    /// `output_size`
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: FrameSlot,
+        sprite_sets: &[FramePacketSprites],
+        output_size: winit::dpi::PhysicalSize<u32>,
+        ui_scale: f32,
+    ) {
+        let (sprites, batches) = sort_into_batches(sprite_sets, output_size, ui_scale);
+        let sprites = std::iter::once(&sprites[..]);
+        self.instances.update(device, staging_belt, encoder, frame, sprites);
+        self.batches = batches;
+    }
+
     pub fn draw_frame(
         &self,
         renderer: &Renderer,
-        frame_packet: &FramePacket,
+        high_contrast: bool,
         encoder: &mut wgpu::CommandEncoder,
         output: &wgpu::TextureView,
-    ) {
-        for sprite_set in &frame_packet.overlay_sprites {
+    ) -> Result<()> {
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[SpriteUniformData {
+                high_contrast: high_contrast as u32,
+                aspect_ratio: renderer.aspect_ratio(),
+            }]),
+        );
+
+        let mut first_instance = 0;
+        for batch in &self.batches {
+            if renderer.pending_atlases.contains(&batch.atlas_id) {
+                // Still loading, so there's nothing to draw these sprites with yet
+                first_instance += batch.count;
+                continue;
+            }
+
             let bind_group = self
                 .texture_bind_groups
-                .get(&sprite_set.atlas_id)
-                .expect("Frame packet references sprite atlas with unknown id");
-
-            let instance_data_buff = renderer.device.create_buffer_with_data(
-                bytemuck::cast_slice(&sprite_set.sprites[..]),
-                wgpu::BufferUsage::VERTEX,
-            );
+                .get(&batch.atlas_id)
+                .ok_or(Error::InvalidFramePacket("Sprite atlas with unknown id"))?;
 
             let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &output,
+                    attachment: output,
                     resolve_target: None,
                     load_op: wgpu::LoadOp::Load,
                     store_op: wgpu::StoreOp::Store,
@@ -164,12 +338,67 @@ impl SpriteOverlayRenderStage {
             });
 
             rpass.set_pipeline(&self.pipeline);
-            rpass.set_bind_group(0, &bind_group, &[]);
-            rpass.set_vertex_buffer(0, &instance_data_buff, 0, 0);
+            rpass.set_bind_group(0, bind_group, &[]);
+            rpass.set_bind_group(1, &self.uniform_bind_group, &[]);
+            rpass.set_vertex_buffer(
+                0,
+                self.instances.buffer(),
+                InstanceBuffer::<SpriteInstanceData>::offset(first_instance),
+                0,
+            );
             rpass.draw(
                 0..4,
-                0..(sprite_set.sprites.len() as u32)
+                0..(batch.count as u32)
             );
+            renderer.draw_counter.record(batch.count as u32);
+            first_instance += batch.count;
         }
+
+        Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sprite(layer: i32) -> SpriteInstanceData {
+        SpriteInstanceData {
+            screen_pos: [0.0, 0.0].into(),
+            screen_size: [1.0, 1.0].into(),
+            atlas_pos: [0.0, 0.0].into(),
+            atlas_size: [1.0, 1.0].into(),
+            rotation: 0.0,
+            tint: [1.0, 1.0, 1.0, 1.0].into(),
+            slice_atlas: [0.0; 4].into(),
+            slice_screen: [0.0; 4].into(),
+            layer,
+        }
+    }
+
+    #[test]
+    fn test_sort_into_batches() {
+        let sets = [
+            FramePacketSprites {
+                atlas_id: AtlasId(0),
+                sprites: vec![sprite(1), sprite(0)],
+                ui_sprites: Vec::new(),
+            },
+            FramePacketSprites {
+                atlas_id: AtlasId(1),
+                sprites: vec![sprite(0), sprite(2)],
+                ui_sprites: Vec::new(),
+            },
+        ];
+
+        let output_size = winit::dpi::PhysicalSize::new(64, 64);
+        let (sprites, batches) = sort_into_batches(&sets, output_size, 1.0);
+        let layers: Vec<_> = sprites.iter().map(|sprite| sprite.layer).collect();
+        assert_eq!(layers, vec![0, 0, 1, 2]);
+        assert_eq!(batches, vec![
+            SpriteBatch { atlas_id: AtlasId(0), count: 1 },
+            SpriteBatch { atlas_id: AtlasId(1), count: 1 },
+            SpriteBatch { atlas_id: AtlasId(0), count: 1 },
+            SpriteBatch { atlas_id: AtlasId(1), count: 1 },
+        ]);
+    }
+}
@@ -0,0 +1,421 @@
+use std::rc::Rc;
+
+use cgmath::{InnerSpace, Matrix4, SquareMatrix, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    frame_packet::FramePacket,
+    render_target::{ColorTarget, RenderTarget},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+const FULLSCREEN_VERTEX_SHADER: &str = "./src/renderer/shaders/fullscreen.vert";
+const OCCLUSION_FRAGMENT_SHADER: &str = "./src/renderer/shaders/ssao.frag";
+const BLUR_FRAGMENT_SHADER: &str = "./src/renderer/shaders/ssao_blur.frag";
+
+/// Number of samples taken around each pixel, which has to match KERNEL_SIZE in ssao.frag
+const KERNEL_SIZE: usize = 16;
+
+/// Tunables for screen space ambient occlusion
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SsaoConfig {
+    /// World space distance around each point that's searched for occluders
+    pub radius: f32,
+
+    /// View space depth difference ignored when testing for occlusion, which stops flat surfaces
+    /// from occluding themselves
+    pub bias: f32,
+
+    /// Exponent applied to the unoccluded fraction, higher darkens occluded areas more
+    pub intensity: f32,
+}
+
+impl Default for SsaoConfig {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            bias: 0.025,
+            intensity: 1.5,
+        }
+    }
+}
+
+/// Points in the +Z hemisphere for sampling around a surface, with more of them near the center
+///
+/// They're laid out with a Halton sequence rather than randomly, so every run looks the same.
+fn sample_kernel() -> [[f32; 4]; KERNEL_SIZE] {
+    fn halton(mut index: usize, base: usize) -> f32 {
+        let mut fraction = 1.0;
+        let mut result = 0.0;
+        while index > 0 {
+            fraction /= base as f32;
+            result += fraction * (index % base) as f32;
+            index /= base;
+        }
+        result
+    }
+
+    let mut kernel = [[0.0; 4]; KERNEL_SIZE];
+    for (i, sample) in kernel.iter_mut().enumerate() {
+        let direction = Vector3::new(
+            halton(i + 1, 2) * 2.0 - 1.0,
+            halton(i + 1, 3) * 2.0 - 1.0,
+            0.1 + 0.9 * halton(i + 1, 5),
+        )
+        .normalize();
+        let t = i as f32 / KERNEL_SIZE as f32;
+        let scale = 0.1 + 0.9 * t * t;
+        *sample = (direction * scale).extend(0.0).into();
+    }
+    kernel
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct SsaoUniformData {
+    proj: Matrix4<f32>,
+
+    /// Transforms clip space back in to view space, to reconstruct positions from depth
+    inv_proj: Matrix4<f32>,
+
+    /// View space offsets from `sample_kernel`, w is unused
+    kernel: [[f32; 4]; KERNEL_SIZE],
+
+    /// Radius, bias and intensity from `SsaoConfig`, w is unused
+    params: [f32; 4],
+
+    /// Size of the rendered region of the scene in xy, and of the rendered region of the
+    /// occlusion textures in zw
+    region: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for SsaoUniformData {}
+unsafe impl bytemuck::Zeroable for SsaoUniformData {}
+
+/// Represents a render stage that estimates how much ambient light reaches each pixel from the
+/// scene's depth, for the forward stage to darken its ambient lighting by
+///
+/// Occlusion is computed at half the scene's resolution with normals reconstructed from depth,
+/// then blurred to hide the noise from rotating the sample kernel per pixel. This needs the
+/// depth from the forward stage's depth prepass, as nothing else has been drawn by then.
+pub struct SsaoStage {
+    config: SsaoConfig,
+    kernel: [[f32; 4]; KERNEL_SIZE],
+
+    occlusion_pipeline: Rc<wgpu::RenderPipeline>,
+    blur_pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    sampler: Rc<wgpu::Sampler>,
+    uniform_buff: wgpu::Buffer,
+
+    /// Noisy occlusion straight from the depth buffer
+    raw: ColorTarget,
+    occlusion_bind_group: wgpu::BindGroup,
+
+    /// Blurred occlusion, 1.0 where nothing blocks the ambient light
+    pub output: ColorTarget,
+    blur_bind_group: wgpu::BindGroup,
+}
+
+impl SsaoStage {
+    pub const OUTPUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        config: SsaoConfig,
+        target: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(FULLSCREEN_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let occlusion_fs_spirv = shader_cache
+            .get_shader_with_defines(
+                OCCLUSION_FRAGMENT_SHADER,
+                shaderc::ShaderKind::Fragment,
+                target.depth_order.shader_defines(),
+            )
+            .await?;
+        let blur_fs_spirv = shader_cache
+            .get_shader(BLUR_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<SsaoUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("SSAO stage uniform buffer"),
+        });
+
+        // Both passes read a single texture, scene depth for the first and raw occlusion for the
+        // blur, so they share a layout
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("SSAO stage bind group layout"),
+            });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&bind_group_layout],
+        });
+
+        let mut create_pipeline = |fs_spirv: &[u32]| {
+            resources.render_pipeline(device, &RenderPipelineDesc {
+                layout: &pipeline_layout,
+                vertex_shader: &vs_spirv,
+                fragment_shader: Some(fs_spirv),
+                rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: wgpu::CullMode::None,
+                    depth_bias: 0,
+                    depth_bias_slope_scale: 0.0,
+                    depth_bias_clamp: 0.0,
+                }),
+                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+                color_states: &[wgpu::ColorStateDescriptor {
+                    format: Self::OUTPUT_FORMAT,
+                    alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                    color_blend: wgpu::BlendDescriptor::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+                depth_stencil_state: None,
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format: wgpu::IndexFormat::Uint32,
+                    vertex_buffers: &[],
+                },
+                sample_count: 1,
+            })
+        };
+        let occlusion_pipeline = create_pipeline(&occlusion_fs_spirv);
+        let blur_pipeline = create_pipeline(&blur_fs_spirv);
+
+        // Both passes read exact texels, so the filtering here never comes in to play
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let raw = Self::create_occlusion_target(device, target, "SSAO raw occlusion texture");
+        let output = Self::create_occlusion_target(device, target, "SSAO occlusion texture");
+        let occlusion_bind_group = Self::create_bind_group(
+            device,
+            &bind_group_layout,
+            &uniform_buff,
+            &sampler,
+            &target.depth_view,
+        );
+        let blur_bind_group =
+            Self::create_bind_group(device, &bind_group_layout, &uniform_buff, &sampler, &raw.view);
+
+        Ok(Self {
+            config,
+            kernel: sample_kernel(),
+            occlusion_pipeline,
+            blur_pipeline,
+            bind_group_layout,
+            sampler,
+            uniform_buff,
+            raw,
+            occlusion_bind_group,
+            output,
+            blur_bind_group,
+        })
+    }
+
+    /// Half the size of the scene target, rounded up so that every scene pixel is covered
+    fn create_occlusion_target(
+        device: &wgpu::Device,
+        target: &RenderTarget,
+        label: &str,
+    ) -> ColorTarget {
+        let size = winit::dpi::PhysicalSize {
+            width: target.size.width.div_ceil(2),
+            height: target.size.height.div_ceil(2),
+        };
+        ColorTarget::new(device, size, Self::OUTPUT_FORMAT, label)
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buff: &wgpu::Buffer,
+        sampler: &wgpu::Sampler,
+        source: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: uniform_buff,
+                        range: 0..std::mem::size_of::<SsaoUniformData>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("SSAO stage bind group"),
+        })
+    }
+
+    /// Reallocate the occlusion textures to match a new scene render target
+    ///
+    /// Anything reading `output` has to be rebound too.
+    pub fn set_target(&mut self, device: &wgpu::Device, target: &RenderTarget) {
+        self.raw = Self::create_occlusion_target(device, target, "SSAO raw occlusion texture");
+        self.output = Self::create_occlusion_target(device, target, "SSAO occlusion texture");
+        self.occlusion_bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniform_buff,
+            &self.sampler,
+            &target.depth_view,
+        );
+        self.blur_bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniform_buff,
+            &self.sampler,
+            &self.raw.view,
+        );
+    }
+
+    /// Clears the target to unoccluded, only drawing to its top-left `region` pixels
+    fn begin_pass<'a>(
+        encoder: &'a mut wgpu::CommandEncoder,
+        target: &'a ColorTarget,
+        region: winit::dpi::PhysicalSize<u32>,
+    ) -> wgpu::RenderPass<'a> {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &target.view,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::WHITE,
+            }],
+            depth_stencil_attachment: None,
+        });
+        rpass.set_viewport(0.0, 0.0, region.width as f32, region.height as f32, 0.0, 1.0);
+        rpass
+    }
+
+    /// Compute occlusion for the top-left `region` pixels of the scene target from its depth
+    ///
+    /// Without depth to work from, eg. when the prepass was skipped, the output is cleared to be
+    /// fully unoccluded instead.
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        region: winit::dpi::PhysicalSize<u32>,
+        depth_ready: bool,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let occlusion_region = winit::dpi::PhysicalSize {
+            width: region.width.div_ceil(2),
+            height: region.height.div_ceil(2),
+        };
+
+        if !depth_ready {
+            Self::begin_pass(encoder, &self.output, occlusion_region);
+            return;
+        }
+
+        let SsaoConfig {
+            radius,
+            bias,
+            intensity,
+        } = self.config;
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[SsaoUniformData {
+                proj: frame_packet.proj,
+                inv_proj: frame_packet.proj.invert().unwrap_or_else(Matrix4::identity),
+                kernel: self.kernel,
+                params: [radius, bias, intensity, 0.0],
+                region: [
+                    region.width as f32,
+                    region.height as f32,
+                    occlusion_region.width as f32,
+                    occlusion_region.height as f32,
+                ],
+            }]),
+        );
+
+        {
+            let mut rpass = Self::begin_pass(encoder, &self.raw, occlusion_region);
+            rpass.set_pipeline(&self.occlusion_pipeline);
+            rpass.set_bind_group(0, &self.occlusion_bind_group, &[]);
+            rpass.draw(0..3, 0..1);
+            renderer.draw_counter.record(1);
+        }
+
+        let mut rpass = Self::begin_pass(encoder, &self.output, occlusion_region);
+        rpass.set_pipeline(&self.blur_pipeline);
+        rpass.set_bind_group(0, &self.blur_bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_kernel() {
+        let kernel = sample_kernel();
+        let mut last_length = 0.0;
+        for sample in kernel.iter() {
+            let offset = Vector3::new(sample[0], sample[1], sample[2]);
+            assert!(offset.z > 0.0);
+
+            // Spreading out from the center of the hemisphere to its edge
+            let length = offset.magnitude();
+            assert!(length > last_length && length <= 1.0);
+            last_length = length;
+        }
+        assert!(last_length > 0.8);
+    }
+}
@@ -0,0 +1,164 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use tracing::warn;
+
+/// Size of each staging buffer, unless a single write needs more than this
+const CHUNK_SIZE: wgpu::BufferAddress = 64 * 1024;
+
+/// Offsets and sizes of buffer to buffer copies have to be a multiple of this
+const COPY_ALIGNMENT: wgpu::BufferAddress = 4;
+
+type MapFuture =
+    Pin<Box<dyn Future<Output = Result<wgpu::BufferWriteMapping, wgpu::BufferAsyncErr>>>>;
+
+enum ChunkState {
+    /// Mapped and ready to be written from `used` onwards
+    Mapped {
+        mapping: wgpu::BufferWriteMapping,
+        used: wgpu::BufferAddress,
+    },
+
+    /// Unmapped and waiting on the submission that copies out of it
+    Submitted,
+
+    /// Waiting on the GPU to finish with it so it can be written again
+    Mapping(MapFuture),
+}
+
+struct Chunk {
+    buffer: wgpu::Buffer,
+    size: wgpu::BufferAddress,
+    state: ChunkState,
+}
+
+/// Persistent pool of mapped staging buffers for the data stages upload every frame
+///
+/// Writes are copied in to whichever chunk has room and the copy out of it is recorded on the
+/// frame's encoder. Once that encoder has been submitted the used chunks are mapped again in the
+/// background, so new buffers are only allocated while the pool grows to fit a frame's uploads.
+#[derive(Default)]
+pub struct StagingBelt {
+    chunks: Vec<Chunk>,
+}
+
+impl StagingBelt {
+    /// Record copying `data` in to `target` at `offset`
+    pub fn write(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::Buffer,
+        offset: wgpu::BufferAddress,
+        data: &[u8],
+    ) {
+        if data.is_empty() {
+            return;
+        }
+
+        let size = data.len() as wgpu::BufferAddress;
+        let aligned_size = size.next_multiple_of(COPY_ALIGNMENT);
+
+        let chunk = self.chunks.iter_mut().find(|chunk| match &chunk.state {
+            ChunkState::Mapped { used, .. } => used + aligned_size <= chunk.size,
+            _ => false,
+        });
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => {
+                self.chunks.push(Self::create_chunk(device, aligned_size.max(CHUNK_SIZE)));
+                self.chunks.last_mut().unwrap()
+            }
+        };
+
+        let chunk_offset = match &mut chunk.state {
+            ChunkState::Mapped { mapping, used } => {
+                let chunk_offset = *used;
+                let start = chunk_offset as usize;
+                mapping.as_slice()[start..start + data.len()].copy_from_slice(data);
+                *used += aligned_size;
+                chunk_offset
+            }
+            _ => unreachable!("Only mapped chunks are written to"),
+        };
+
+        encoder.copy_buffer_to_buffer(&chunk.buffer, chunk_offset, target, offset, size);
+    }
+
+    fn create_chunk(device: &wgpu::Device, size: wgpu::BufferAddress) -> Chunk {
+        // Mapped with map_write rather than created mapped, as the slice create_buffer_mapped
+        // hands back can't be held on to between writes
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size,
+            usage: wgpu::BufferUsage::MAP_WRITE | wgpu::BufferUsage::COPY_SRC,
+            label: Some("Staging belt chunk"),
+        });
+        let mut mapping = Box::pin(buffer.map_write(0, size));
+
+        // A fresh buffer isn't in use by the GPU, so this resolves on the next poll
+        device.poll(wgpu::Maintain::Poll);
+        let mapping = match mapping
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+        {
+            Poll::Ready(Ok(mapping)) => mapping,
+            _ => panic!("Failed to map a newly created staging buffer"),
+        };
+
+        Chunk {
+            buffer,
+            size,
+            state: ChunkState::Mapped { mapping, used: 0 },
+        }
+    }
+
+    /// Unmap every chunk written to this frame, ready for the encoder to be submitted
+    pub fn finish(&mut self) {
+        for chunk in &mut self.chunks {
+            if let ChunkState::Mapped { used, .. } = chunk.state {
+                if used > 0 {
+                    // Dropping the mapping unmaps the buffer
+                    chunk.state = ChunkState::Submitted;
+                }
+            }
+        }
+    }
+
+    /// Start mapping the chunks unmapped by `finish`, which must have been submitted
+    pub fn recall(&mut self) {
+        for chunk in &mut self.chunks {
+            if let ChunkState::Submitted = chunk.state {
+                chunk.state = ChunkState::Mapping(Box::pin(chunk.buffer.map_write(0, chunk.size)));
+            }
+        }
+    }
+
+    /// Pick up any chunks that have finished mapping, without blocking
+    pub fn poll(&mut self, device: &wgpu::Device) {
+        if !self.chunks.iter().any(|chunk| matches!(chunk.state, ChunkState::Mapping(_))) {
+            return;
+        }
+
+        device.poll(wgpu::Maintain::Poll);
+
+        let mut cx = Context::from_waker(Waker::noop());
+        self.chunks.retain_mut(|chunk| {
+            let future = match &mut chunk.state {
+                ChunkState::Mapping(future) => future,
+                _ => return true,
+            };
+            match future.as_mut().poll(&mut cx) {
+                Poll::Pending => true,
+                Poll::Ready(Ok(mapping)) => {
+                    chunk.state = ChunkState::Mapped { mapping, used: 0 };
+                    true
+                }
+                Poll::Ready(Err(_)) => {
+                    warn!("Failed to map staging buffer, dropping it");
+                    false
+                }
+            }
+        });
+    }
+}
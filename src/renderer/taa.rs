@@ -0,0 +1,372 @@
+use std::rc::Rc;
+
+use cgmath::{Matrix4, Vector2};
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    motion_vectors::MotionFrame,
+    post_process::PostProcessStage,
+    render_target::{ColorTarget, RenderTarget},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+const FULLSCREEN_VERTEX_SHADER: &str = "./src/renderer/shaders/fullscreen.vert";
+const TAA_FRAGMENT_SHADER: &str = "./src/renderer/shaders/taa.frag";
+
+/// How many frames the jitter takes to cover the pixel before repeating
+const JITTER_PHASES: u32 = 8;
+
+/// How much of each new frame is blended in to the history. Lower is smoother but slower to
+/// catch up with changes.
+const CURRENT_FRAME_WEIGHT: f32 = 0.1;
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct TaaUniformData {
+    /// Transforms this frame's unjittered clip space in to last frame's
+    reproject: Matrix4<f32>,
+
+    /// Clip space offset the scene was drawn with in xy, zw are unused
+    jitter: [f32; 4],
+
+    /// Size of the rendered region in xy, and of the whole history texture in zw
+    region: [f32; 4],
+
+    /// How much of this frame is blended in to the history in x, yzw are unused
+    params: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for TaaUniformData {}
+unsafe impl bytemuck::Zeroable for TaaUniformData {}
+
+/// The `i`th number of the Halton sequence in the given base, which spreads points evenly over
+/// 0..1 however many of them are taken
+fn halton(mut i: u32, base: u32) -> f32 {
+    let mut fraction = 1.0;
+    let mut result = 0.0;
+    while i > 0 {
+        fraction /= base as f32;
+        result += fraction * (i % base) as f32;
+        i /= base;
+    }
+    result
+}
+
+/// Offset of the camera within a pixel for the given frame, in pixels from its center
+fn jitter_offset(frame_index: u32) -> Vector2<f32> {
+    // The sequences start from 1, as 0 would put every phase's first sample in the corner
+    let i = frame_index % JITTER_PHASES + 1;
+    Vector2::new(halton(i, 2) - 0.5, halton(i, 3) - 0.5)
+}
+
+/// Represents a render stage that resolves the tonemapped scene against the history of previous
+/// frames, for `AaMode::Taa`
+///
+/// The camera is jittered by a different fraction of a pixel each frame, so that blending the
+/// frames together samples each pixel at many points over time. The forward stage draws motion
+/// vectors for the resolve to find where each pixel was last frame, and anything it didn't draw
+/// is reprojected from its depth instead. The resolved frame is kept as the next frame's
+/// history, so this stage owns its own targets rather than using render graph transients.
+pub struct TaaStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    sampler: Rc<wgpu::Sampler>,
+    uniform_buff: wgpu::Buffer,
+
+    /// Each frame is resolved in to one while the other is read as the previous frame's
+    history: [ColorTarget; 2],
+
+    /// Index in to `history` of the last frame resolved
+    latest: usize,
+
+    /// Whether `history[latest]` can be blended with, which it can't after it's reallocated, the
+    /// rendered region changes, or a frame is drawn without TAA
+    history_valid: bool,
+
+    /// Rendered region of the scene that the history was resolved at
+    region: winit::dpi::PhysicalSize<u32>,
+
+    frame_index: u32,
+}
+
+impl TaaStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        scene: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(FULLSCREEN_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(TAA_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<TaaUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("TAA stage uniform buffer"),
+        });
+
+        // This frame, the history, motion vectors and the scene's depth
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+            },
+        };
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    texture_entry(1),
+                    texture_entry(2),
+                    texture_entry(3),
+                    texture_entry(4),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("TAA stage bind group layout"),
+            });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&bind_group_layout],
+        });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: PostProcessStage::OUTPUT_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+        });
+
+        // The history is reprojected to anywhere between its texels
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        Ok(Self {
+            pipeline,
+            bind_group_layout,
+            sampler,
+            uniform_buff,
+            history: Self::create_history(device, scene),
+            latest: 0,
+            history_valid: false,
+            region: scene.size,
+            frame_index: 0,
+        })
+    }
+
+    fn create_history(device: &wgpu::Device, scene: &RenderTarget) -> [ColorTarget; 2] {
+        let format = PostProcessStage::OUTPUT_FORMAT;
+        [
+            ColorTarget::new(device, scene.size, format, "TAA history texture"),
+            ColorTarget::new(device, scene.size, format, "TAA history texture"),
+        ]
+    }
+
+    /// Pick the clip space jitter for a frame about to be drawn over the top-left `region` pixels
+    /// of the scene target
+    ///
+    /// The history is reallocated to match the scene target if that has been, which starts it
+    /// over.
+    pub fn begin_frame(
+        &mut self,
+        device: &wgpu::Device,
+        scene: &RenderTarget,
+        region: winit::dpi::PhysicalSize<u32>,
+    ) -> Vector2<f32> {
+        if self.history[0].size != scene.size {
+            self.history = Self::create_history(device, scene);
+            self.history_valid = false;
+        }
+        if region != self.region {
+            self.region = region;
+            self.history_valid = false;
+        }
+
+        self.frame_index = self.frame_index.wrapping_add(1);
+        let offset = jitter_offset(self.frame_index);
+        Vector2::new(
+            2.0 * offset.x / region.width.max(1) as f32,
+            2.0 * offset.y / region.height.max(1) as f32,
+        )
+    }
+
+    /// Note that a frame is being drawn without TAA, eg. one of split views, after which the
+    /// history no longer follows on
+    pub fn skip_frame(&mut self) {
+        self.history_valid = false;
+    }
+
+    /// Blend the tonemapped `current` frame in to the history, reprojected with the forward
+    /// stage's `motion_vectors`, for the frame set up by `begin_frame`
+    ///
+    /// The result is left in `output`, which stays valid until the next frame's resolve.
+    pub fn draw_frame(
+        &mut self,
+        renderer: &Renderer,
+        frame: &MotionFrame,
+        current: &ColorTarget,
+        motion_vectors: &ColorTarget,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let region = self.region;
+        let history_size = self.history[0].size;
+
+        // Without any history this frame is taken as it is
+        let weight = if self.history_valid { CURRENT_FRAME_WEIGHT } else { 1.0 };
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[TaaUniformData {
+                reproject: frame.reprojection(),
+                jitter: [frame.jitter.x, frame.jitter.y, 0.0, 0.0],
+                region: [
+                    region.width as f32,
+                    region.height as f32,
+                    history_size.width as f32,
+                    history_size.height as f32,
+                ],
+                params: [weight, 0.0, 0.0, 0.0],
+            }]),
+        );
+
+        let (history, output) = (&self.history[self.latest], &self.history[1 - self.latest]);
+        let bind_group = renderer.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &self.uniform_buff,
+                        range: 0..std::mem::size_of::<TaaUniformData>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&current.view),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&history.view),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&motion_vectors.view),
+                },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(
+                        &renderer.scene_target.depth_view,
+                    ),
+                },
+                wgpu::Binding {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+            label: Some("TAA stage bind group"),
+        });
+
+        {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &output.view,
+                    resolve_target: None,
+                    load_op: wgpu::LoadOp::Clear,
+                    store_op: wgpu::StoreOp::Store,
+                    clear_color: wgpu::Color::BLACK,
+                }],
+                depth_stencil_attachment: None,
+            });
+
+            // Keeps each fragment lined up with the texels it reads
+            rpass.set_viewport(0.0, 0.0, region.width as f32, region.height as f32, 0.0, 1.0);
+            rpass.set_pipeline(&self.pipeline);
+            rpass.set_bind_group(0, &bind_group, &[]);
+            rpass.draw(0..3, 0..1);
+            renderer.draw_counter.record(1);
+        }
+
+        self.latest = 1 - self.latest;
+        self.history_valid = true;
+    }
+
+    /// The last frame resolved, over the top-left pixels of the region it was drawn at
+    pub fn output(&self) -> &ColorTarget {
+        &self.history[self.latest]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jitter_covers_pixel() {
+        let offsets: Vec<_> = (0..JITTER_PHASES).map(jitter_offset).collect();
+        for offset in &offsets {
+            assert!(offset.x.abs() < 0.5 && offset.y.abs() < 0.5);
+        }
+        for (i, a) in offsets.iter().enumerate() {
+            assert!(offsets[i + 1..].iter().all(|b| a != b));
+        }
+
+        // Averages out to the pixel's center, and repeats once every phase has been used
+        let mean = offsets.iter().fold(Vector2::new(0.0, 0.0), |sum, &offset| sum + offset)
+            / JITTER_PHASES as f32;
+        assert!(mean.x.abs() < 0.1 && mean.y.abs() < 0.1);
+        assert_eq!(jitter_offset(JITTER_PHASES + 3), jitter_offset(3));
+    }
+
+    #[test]
+    fn test_halton() {
+        assert_eq!(halton(1, 2), 0.5);
+        assert_eq!(halton(2, 2), 0.25);
+        assert_eq!(halton(3, 2), 0.75);
+        assert!((halton(2, 3) - 2.0 / 3.0).abs() < 1e-6);
+    }
+}
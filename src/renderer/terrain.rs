@@ -0,0 +1,485 @@
+use std::ops::Range;
+use std::rc::Rc;
+
+use cgmath::{InnerSpace, Matrix4, Vector3, Vector4, Zero};
+
+use crate::{
+    error::{Error, Result},
+    picking::Aabb,
+    shader_cache::ShaderCache,
+    terrain::{TerrainData, TerrainVertex},
+};
+use super::{
+    frame_packet::FramePacket,
+    gpu_culling::frustum_planes,
+    render_target::{RenderTarget, Viewport},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    texture_streaming::mip_chain,
+    Renderer,
+};
+
+const TERRAIN_VERTEX_SHADER: &str = "./src/renderer/shaders/terrain.vert";
+const TERRAIN_FRAGMENT_SHADER: &str = "./src/renderer/shaders/terrain.frag";
+
+/// Most layers a terrain can blend between, one for each channel of the splat map. Has to match
+/// MAX_LAYERS in terrain.frag.
+const MAX_LAYERS: usize = 4;
+
+/// One of a terrain's chunks, within its shared buffers
+struct GpuTerrainChunk {
+    indices: Range<u32>,
+    base_vertex: i32,
+    bounds: Aabb,
+}
+
+/// Represents a single terrain on the GPU, with every chunk's vertices and indices back to back
+/// in one buffer each
+pub struct GpuTerrain {
+    vertex_buff: wgpu::Buffer,
+    index_buff: wgpu::Buffer,
+    chunks: Vec<GpuTerrainChunk>,
+    layer_count: usize,
+    layer_tile_size: f32,
+    bind_group: wgpu::BindGroup,
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct TerrainUniformData {
+    view_proj: Matrix4<f32>,
+
+    /// World space direction towards the sun in xyz, w is unused
+    sun_direction: Vector4<f32>,
+
+    /// Linear color of the sun scaled by its intensity in rgb, a is unused
+    sun_color: Vector4<f32>,
+
+    /// Layer tile size and count, zw are unused
+    params: [f32; 4],
+
+    /// World space plane that fragments behind are discarded at, or zero to keep everything
+    clip_plane: Vector4<f32>,
+}
+
+unsafe impl bytemuck::Pod for TerrainUniformData {}
+unsafe impl bytemuck::Zeroable for TerrainUniformData {}
+
+/// Whether any of the box is on the inside of every one of the planes
+fn in_frustum(planes: &[Vector4<f32>; 6], bounds: &Aabb) -> bool {
+    planes.iter().all(|plane| {
+        // The corner furthest along the plane's normal
+        let corner = Vector4::new(
+            if plane.x >= 0.0 { bounds.max.x } else { bounds.min.x },
+            if plane.y >= 0.0 { bounds.max.y } else { bounds.min.y },
+            if plane.z >= 0.0 { bounds.max.z } else { bounds.min.z },
+            1.0,
+        );
+        plane.dot(corner) >= 0.0
+    })
+}
+
+/// Represents a render stage that draws a heightmap terrain in to the scene, after the models
+///
+/// Up to four tiled textures are blended by the terrain's splat map, and lit by the frame's
+/// shadow casting directional light and a flat ambient term. There are no shadows, fog or other
+/// lights. Chunks entirely outside the view frustum are culled on the CPU each frame.
+pub struct TerrainRenderStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    uniform_bind_group: wgpu::BindGroup,
+    uniform_buff: wgpu::Buffer,
+    texture_bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    splat_sampler: Rc<wgpu::Sampler>,
+    layer_sampler: Rc<wgpu::Sampler>,
+}
+
+impl TerrainRenderStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+        target: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(TERRAIN_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(TERRAIN_FRAGMENT_SHADER, shaderc::ShaderKind::Fragment)
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<TerrainUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Terrain stage uniform buffer"),
+        });
+
+        let uniform_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                }],
+                label: Some("Terrain stage uniform buffer layout"),
+            });
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            bindings: &[wgpu::Binding {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &uniform_buff,
+                    range: 0..std::mem::size_of::<TerrainUniformData>() as wgpu::BufferAddress,
+                },
+            }],
+            label: Some("Terrain stage uniform bind group"),
+        });
+
+        let texture_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2Array,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Terrain stage texture bind group layout"),
+            });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&uniform_bind_group_layout, &texture_bind_group_layout],
+        });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::Back,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: RenderTarget::COLOR_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                format: RenderTarget::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: target.depth_order.compare(wgpu::CompareFunction::Less),
+                stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
+                stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
+                stencil_read_mask: 0,
+                stencil_write_mask: 0,
+            }),
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[TerrainVertex::vertex_buffer_descriptor()],
+            },
+            sample_count: target.sample_count,
+        });
+
+        let splat_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        let layer_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        Ok(Self {
+            pipeline,
+            uniform_bind_group,
+            uniform_buff,
+            texture_bind_group_layout,
+            splat_sampler,
+            layer_sampler,
+        })
+    }
+
+    /// Upload a terrain's chunks and textures, with a full mip chain for each layer so that they
+    /// don't shimmer in the distance
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: &TerrainData,
+    ) -> Result<GpuTerrain> {
+        if data.chunks.is_empty() {
+            return Err(Error::InvalidAsset("Terrain has no chunks"));
+        }
+        if data.layers.is_empty() || data.layers.len() > MAX_LAYERS {
+            return Err(Error::InvalidAsset("Terrain needs between one and four layers"));
+        }
+        let size = data.layers[0].dimensions();
+        if size.0 == 0 || size.1 == 0 || data.layers.iter().any(|layer| layer.dimensions() != size) {
+            return Err(Error::InvalidAsset("Terrain layers must all be the same, non-zero size"));
+        }
+        if data.splat_map.width() == 0 || data.splat_map.height() == 0 {
+            return Err(Error::InvalidAsset("Terrain splat map is empty"));
+        }
+
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let chunks = data
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let first_index = indices.len() as u32;
+                let base_vertex = vertices.len() as i32;
+                vertices.extend_from_slice(&chunk.vertices);
+                indices.extend_from_slice(&chunk.indices);
+                GpuTerrainChunk {
+                    indices: first_index..indices.len() as u32,
+                    base_vertex,
+                    bounds: chunk.bounds,
+                }
+            })
+            .collect();
+        let vertex_buff = device
+            .create_buffer_with_data(bytemuck::cast_slice(&vertices), wgpu::BufferUsage::VERTEX);
+        let index_buff = device
+            .create_buffer_with_data(bytemuck::cast_slice(&indices), wgpu::BufferUsage::INDEX);
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Terrain upload commands"),
+        });
+        let splat_map = upload_layers(
+            device,
+            &mut encoder,
+            std::slice::from_ref(&data.splat_map),
+            1,
+            wgpu::TextureFormat::Rgba8Unorm,
+            "Terrain splat map texture",
+        );
+        let mip_count = mip_chain(&data.layers[0]).len() as u32;
+        let layers = upload_layers(
+            device,
+            &mut encoder,
+            &data.layers,
+            mip_count,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            "Terrain layers texture",
+        );
+        queue.submit(&[encoder.finish()]);
+
+        let layers_view = layers.create_view(&wgpu::TextureViewDescriptor {
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            dimension: wgpu::TextureViewDimension::D2Array,
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: 0,
+            level_count: mip_count,
+            base_array_layer: 0,
+            array_layer_count: data.layers.len() as u32,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.texture_bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&splat_map.create_default_view()),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.splat_sampler),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&layers_view),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&self.layer_sampler),
+                },
+            ],
+            label: Some("Terrain texture bind group"),
+        });
+
+        Ok(GpuTerrain {
+            vertex_buff,
+            index_buff,
+            chunks,
+            layer_count: data.layers.len(),
+            layer_tile_size: data.layer_tile_size,
+            bind_group,
+        })
+    }
+
+    /// Draw the chunks of the frame's terrain that are in view, on top of the scene that's
+    /// already in the target and with `proj` in place of the frame's own projection, eg. jittered
+    /// for TAA
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        proj: Matrix4<f32>,
+        terrain: &GpuTerrain,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &RenderTarget,
+        viewport: Viewport,
+    ) {
+        let (sun_direction, sun_color) = match frame_packet.directional_light {
+            Some(light) => (-light.direction.normalize(), light.color * light.intensity),
+            None => (Vector3::unit_z(), Vector3::new(0.0, 0.0, 0.0)),
+        };
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[TerrainUniformData {
+                view_proj: proj * frame_packet.view,
+                sun_direction: sun_direction.extend(0.0),
+                sun_color: sun_color.extend(0.0),
+                params: [terrain.layer_tile_size, terrain.layer_count as f32, 0.0, 0.0],
+                clip_plane: frame_packet.clip_plane.unwrap_or_else(Vector4::zero),
+            }]),
+        );
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[target.color_attachment(wgpu::LoadOp::Load)],
+            depth_stencil_attachment: Some(target.depth_attachment(wgpu::LoadOp::Load)),
+        });
+
+        viewport.apply(&mut rpass);
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        rpass.set_bind_group(1, &terrain.bind_group, &[]);
+        rpass.set_vertex_buffer(0, &terrain.vertex_buff, 0, 0);
+        rpass.set_index_buffer(&terrain.index_buff, 0, 0);
+
+        let planes = frustum_planes(frame_packet.proj * frame_packet.view);
+        for chunk in terrain.chunks.iter().filter(|chunk| in_frustum(&planes, &chunk.bounds)) {
+            rpass.draw_indexed(chunk.indices.clone(), chunk.base_vertex, 0..1);
+            renderer.draw_counter.record(1);
+        }
+    }
+}
+
+/// Create a 2D texture with an array layer for each of the images, which all have to be the same
+/// size, and upload the first `mip_count` levels of each
+pub(super) fn upload_layers(
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    images: &[image::RgbaImage],
+    mip_count: u32,
+    format: wgpu::TextureFormat,
+    label: &'static str,
+) -> wgpu::Texture {
+    let extent = |image: &image::RgbaImage| wgpu::Extent3d {
+        width: image.width(),
+        height: image.height(),
+        depth: 1,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: extent(&images[0]),
+        array_layer_count: images.len() as u32,
+        mip_level_count: mip_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+    });
+
+    for (layer, image) in images.iter().enumerate() {
+        let mips = mip_chain(image);
+        for (level, mip) in mips.iter().enumerate().take(mip_count as usize) {
+            let buffer = device.create_buffer_with_data(
+                mip.as_flat_samples().as_slice(),
+                wgpu::BufferUsage::COPY_SRC,
+            );
+            encoder.copy_buffer_to_texture(
+                wgpu::BufferCopyView {
+                    buffer: &buffer,
+                    offset: 0,
+                    bytes_per_row: 4 * mip.width(),
+                    rows_per_image: mip.height(),
+                },
+                wgpu::TextureCopyView {
+                    texture: &texture,
+                    mip_level: level as u32,
+                    array_layer: layer as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                extent(mip),
+            );
+        }
+    }
+
+    texture
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Point3};
+
+    #[test]
+    fn test_in_frustum() {
+        let view = Matrix4::look_at(
+            Point3::new(0.0, 0.0, 10.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::unit_y(),
+        );
+        let proj = cgmath::perspective(Deg(90.0), 1.0, 0.1, 100.0);
+        let planes = frustum_planes(proj * view);
+        let chunk = |x: f32| Aabb {
+            min: Point3::new(x, -1.0, 0.0),
+            max: Point3::new(x + 2.0, 1.0, 1.0),
+        };
+
+        assert!(in_frustum(&planes, &chunk(-1.0)));
+        // Straddling the edge of the view, with none of its corners inside
+        assert!(in_frustum(&planes, &Aabb {
+            min: Point3::new(-50.0, -1.0, 0.0),
+            max: Point3::new(50.0, 1.0, 1.0),
+        }));
+        assert!(!in_frustum(&planes, &chunk(12.0)));
+        assert!(!in_frustum(&planes, &chunk(-20.0)));
+    }
+}
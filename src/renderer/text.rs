@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use cgmath::Vector2;
+use rusttype::{point, Font, Scale};
+use winit::dpi::PhysicalSize;
+
+use crate::{
+    error::{Error, Result},
+    shader_cache::ShaderCache,
+};
+use super::{
+    frame_packet::{GlyphInstanceData, TextRun},
+    frames_in_flight::FrameSlot,
+    instance_buffer::InstanceBuffer,
+    render_target::ColorTarget,
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    staging_belt::StagingBelt,
+    Renderer,
+};
+
+/// The font UI text is drawn with, unless `RendererConfig::ui_font` says otherwise
+pub const DEFAULT_UI_FONT_PATH: &str = "./DejaVuSans.ttf";
+
+/// Pixel height glyphs are rasterized at. Text is scaled from this, so it stays sharpest at
+/// around this size and below.
+const ATLAS_FONT_SIZE: f32 = 48.0;
+
+/// Width of the glyph atlas. 1 byte per texel, so this keeps rows aligned as wgpu requires.
+const ATLAS_WIDTH: u32 = 512;
+
+/// Empty texels left around each glyph, so that filtering doesn't bleed between neighbours
+const GLYPH_PADDING: u32 = 1;
+
+/// Drawn in place of any character that isn't in the atlas
+const FALLBACK_CHAR: char = '?';
+
+/// Where a single glyph is in the atlas, and how to place it relative to the pen position
+#[derive(Clone, Copy, Debug)]
+struct GlyphInfo {
+    /// Normalized atlas coordinates of the glyph's bitmap
+    atlas_pos: Vector2<f32>,
+    atlas_size: Vector2<f32>,
+
+    /// Pixel offset from the pen position on the baseline to the top-left of the bitmap
+    offset: Vector2<f32>,
+
+    /// Pixel size of the bitmap
+    size: Vector2<f32>,
+
+    /// How far the pen moves along after drawing this glyph
+    advance: f32,
+}
+
+/// Every printable ASCII glyph of a font, rasterized at `ATLAS_FONT_SIZE` and packed in to a
+/// single coverage texture
+struct GlyphAtlas {
+    glyphs: HashMap<char, GlyphInfo>,
+
+    /// Distance from the top of a line to its baseline, in pixels
+    ascent: f32,
+
+    /// Distance between consecutive baselines, in pixels
+    line_height: f32,
+}
+
+impl GlyphAtlas {
+    fn build(font: &Font) -> (Self, image::GrayImage) {
+        let scale = Scale::uniform(ATLAS_FONT_SIZE);
+        let v_metrics = font.v_metrics(scale);
+
+        // Simple shelf packing, placing glyphs left to right and starting a new row whenever the
+        // current one is full
+        let mut placements = Vec::new();
+        let (mut x, mut y, mut row_height) = (GLYPH_PADDING, GLYPH_PADDING, 0);
+        for c in (32u8..=126).map(char::from) {
+            let glyph = font.glyph(c).scaled(scale).positioned(point(0.0, 0.0));
+            let advance = glyph.unpositioned().h_metrics().advance_width;
+            let bounds = glyph.pixel_bounding_box();
+            let (width, height) = bounds
+                .map(|bounds| (bounds.width() as u32, bounds.height() as u32))
+                .unwrap_or((0, 0));
+
+            if x + width + GLYPH_PADDING > ATLAS_WIDTH {
+                x = GLYPH_PADDING;
+                y += row_height + GLYPH_PADDING;
+                row_height = 0;
+            }
+
+            placements.push((c, glyph, bounds, x, y, advance));
+            x += width + GLYPH_PADDING;
+            row_height = row_height.max(height);
+        }
+        let atlas_height = y + row_height + GLYPH_PADDING;
+
+        let mut image = image::GrayImage::new(ATLAS_WIDTH, atlas_height);
+        let mut glyphs = HashMap::new();
+        for (c, glyph, bounds, x, y, advance) in placements {
+            let (offset, size) = match bounds {
+                Some(bounds) => {
+                    glyph.draw(|gx, gy, coverage| {
+                        image.put_pixel(x + gx, y + gy, image::Luma([(coverage * 255.0) as u8]));
+                    });
+                    (
+                        Vector2::new(bounds.min.x as f32, bounds.min.y as f32),
+                        Vector2::new(bounds.width() as f32, bounds.height() as f32),
+                    )
+                }
+                // Whitespace has nothing to draw, only an advance
+                None => (Vector2::new(0.0, 0.0), Vector2::new(0.0, 0.0)),
+            };
+
+            glyphs.insert(
+                c,
+                GlyphInfo {
+                    atlas_pos: Vector2::new(
+                        x as f32 / ATLAS_WIDTH as f32,
+                        y as f32 / atlas_height as f32,
+                    ),
+                    atlas_size: Vector2::new(
+                        size.x / ATLAS_WIDTH as f32,
+                        size.y / atlas_height as f32,
+                    ),
+                    offset,
+                    size,
+                    advance,
+                },
+            );
+        }
+
+        let atlas = Self {
+            glyphs,
+            ascent: v_metrics.ascent,
+            line_height: v_metrics.ascent - v_metrics.descent + v_metrics.line_gap,
+        };
+        (atlas, image)
+    }
+
+    fn glyph(&self, c: char) -> &GlyphInfo {
+        match self.glyphs.get(&c) {
+            Some(glyph) => glyph,
+            None => &self.glyphs[&FALLBACK_CHAR],
+        }
+    }
+
+    /// How far the pen moves along drawing a single line of text at a pixel height of `size`
+    fn width(&self, text: &str, size: f32) -> f32 {
+        let scale = size / ATLAS_FONT_SIZE;
+        text.chars().map(|c| self.glyph(c).advance * scale).sum()
+    }
+
+    /// Lay out a run of text as one quad per visible glyph
+    fn layout(&self, run: &TextRun, screen_size: PhysicalSize<u32>) -> Vec<GlyphInstanceData> {
+        let scale = run.size / ATLAS_FONT_SIZE;
+        let pixel_to_clip = Vector2::new(
+            2.0 / screen_size.width as f32,
+            2.0 / screen_size.height as f32,
+        );
+        // Pixels go down the screen where clip space goes up
+        let to_clip = |pixels: Vector2<f32>| {
+            Vector2::new(pixels.x * pixel_to_clip.x, -pixels.y * pixel_to_clip.y)
+        };
+
+        let mut instances = Vec::new();
+        let mut pen = Vector2::new(0.0, self.ascent * scale);
+        for c in run.text.chars() {
+            if c == '\n' {
+                pen = Vector2::new(0.0, pen.y + self.line_height * scale);
+                continue;
+            }
+
+            let glyph = self.glyph(c);
+            if glyph.size.x > 0.0 && glyph.size.y > 0.0 {
+                instances.push(GlyphInstanceData {
+                    screen_pos: run.screen_pos + to_clip(pen + glyph.offset * scale),
+                    screen_size: to_clip(glyph.size * scale),
+                    atlas_pos: glyph.atlas_pos,
+                    atlas_size: glyph.atlas_size,
+                    color: run.color,
+                });
+            }
+            pen.x += glyph.advance * scale;
+        }
+
+        instances
+    }
+}
+
+/// Represents a render stage that draws the frame packet's text runs on top of the UI overlay
+pub struct TextRenderStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group: wgpu::BindGroup,
+    glyph_atlas: GlyphAtlas,
+
+    #[allow(unused)]
+    atlas_texture: wgpu::Texture,
+
+    instances: InstanceBuffer<GlyphInstanceData>,
+
+    /// Number of glyphs laid out by the last call to `update`
+    glyph_count: u32,
+}
+
+impl TextRenderStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        resources: &mut ResourceCache,
+        font_path: &Path,
+    ) -> Result<Self> {
+        let font_data = tokio::fs::read(font_path).await.map_err(Error::io(font_path))?;
+        let font = Font::from_bytes(font_data)
+            .map_err(|_| Error::InvalidAsset("Failed to parse UI font"))?;
+        let (glyph_atlas, atlas_image) = GlyphAtlas::build(&font);
+
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/text.vert",
+                shaderc::ShaderKind::Vertex,
+            )
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/text.frag",
+                shaderc::ShaderKind::Fragment,
+            )
+            .await?;
+
+        let atlas_size = wgpu::Extent3d {
+            width: atlas_image.width(),
+            height: atlas_image.height(),
+            depth: 1,
+        };
+        let atlas_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Glyph atlas texture"),
+            size: atlas_size,
+            array_layer_count: 1,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+
+        let atlas_buff = device.create_buffer_with_data(
+            &atlas_image,
+            wgpu::BufferUsage::COPY_SRC,
+        );
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Glyph atlas upload commands"),
+        });
+        encoder.copy_buffer_to_texture(
+            wgpu::BufferCopyView {
+                buffer: &atlas_buff,
+                offset: 0,
+                bytes_per_row: atlas_image.width(),
+                rows_per_image: atlas_image.height(),
+            },
+            wgpu::TextureCopyView {
+                texture: &atlas_texture,
+                mip_level: 0,
+                array_layer: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            atlas_size,
+        );
+        queue.submit(&[encoder.finish()]);
+
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Text render stage bind group layout"),
+            });
+
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(
+                        &atlas_texture.create_default_view(),
+                    ),
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("Glyph atlas bind group"),
+        });
+
+        let render_pipeline_layout =
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&bind_group_layout],
+            });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &render_pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleStrip,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: ColorTarget::LDR_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[GlyphInstanceData::vertex_buffer_descriptor()],
+            },
+            sample_count: 1,
+        });
+
+        Ok(Self {
+            pipeline,
+            bind_group,
+            glyph_atlas,
+            atlas_texture,
+            instances: InstanceBuffer::new(device, "Text render stage instance buffer"),
+            glyph_count: 0,
+        })
+    }
+
+    /// Lay out this frame's text and record copying the glyphs in to the instance buffer
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        staging_belt: &mut StagingBelt,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: FrameSlot,
+        text: &[TextRun],
+        screen_size: PhysicalSize<u32>,
+    ) {
+        let glyphs: Vec<_> = text
+            .iter()
+            .flat_map(|run| self.glyph_atlas.layout(run, screen_size))
+            .collect();
+
+        self.glyph_count = glyphs.len() as u32;
+        let glyphs = std::iter::once(&glyphs[..]);
+        self.instances.update(device, staging_belt, encoder, frame, glyphs);
+    }
+
+    /// Pixel width of a single line of text drawn at a pixel height of `size`
+    pub fn text_width(&self, text: &str, size: f32) -> f32 {
+        self.glyph_atlas.width(text, size)
+    }
+
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+    ) {
+        if self.glyph_count == 0 {
+            return;
+        }
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: output,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Load,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &self.bind_group, &[]);
+        rpass.set_vertex_buffer(0, self.instances.buffer(), 0, 0);
+        rpass.draw(0..4, 0..self.glyph_count);
+        renderer.draw_counter.record(self.glyph_count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_advances_along_and_down_lines() {
+        let font_data = std::fs::read(DEFAULT_UI_FONT_PATH).unwrap();
+        let font = Font::from_bytes(font_data).unwrap();
+        let (atlas, _) = GlyphAtlas::build(&font);
+
+        let run = TextRun {
+            text: "ab c\nd".to_string(),
+            screen_pos: [-1.0, 1.0].into(),
+            size: 24.0,
+            color: [1.0, 1.0, 1.0, 1.0].into(),
+        };
+        let glyphs = atlas.layout(&run, PhysicalSize::new(800, 600));
+
+        // The space takes up room but isn't drawn
+        assert_eq!(glyphs.len(), 4);
+        assert!(glyphs[1].screen_pos.x > glyphs[0].screen_pos.x);
+        assert!(glyphs[2].screen_pos.x > glyphs[1].screen_pos.x);
+        assert!(glyphs[3].screen_pos.x < glyphs[2].screen_pos.x);
+        assert!(glyphs[3].screen_pos.y < glyphs[2].screen_pos.y);
+        assert!(glyphs.iter().all(|glyph| glyph.screen_size.y < 0.0));
+    }
+
+    #[test]
+    fn test_width_matches_layout() {
+        let font_data = std::fs::read(DEFAULT_UI_FONT_PATH).unwrap();
+        let font = Font::from_bytes(font_data).unwrap();
+        let (atlas, _) = GlyphAtlas::build(&font);
+
+        // The pen starts the second glyph where the first one's width ends
+        let run = TextRun {
+            text: "ab".to_string(),
+            screen_pos: [-1.0, 1.0].into(),
+            size: 24.0,
+            color: [1.0, 1.0, 1.0, 1.0].into(),
+        };
+        let glyphs = atlas.layout(&run, PhysicalSize::new(800, 600));
+        let b_offset = atlas.glyphs[&'b'].offset.x * 24.0 / ATLAS_FONT_SIZE;
+        let pen_x = (glyphs[1].screen_pos.x + 1.0) * 400.0 - b_offset;
+        assert!((pen_x - atlas.width("a", 24.0)).abs() < 1e-3);
+
+        assert_eq!(atlas.width("", 24.0), 0.0);
+        assert_eq!(atlas.width("aa", 48.0), 4.0 * atlas.width("a", 24.0));
+        assert_eq!(atlas.width("\u{2603}", 24.0), atlas.width("?", 24.0));
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Transform};
+use serde::{Deserialize, Serialize};
+
+use super::frame_packet::FramePacket;
+use super::{GpuMaterial, GpuModel, MaterialId, ModelId};
+use crate::picking::Aabb;
+
+/// Levels no larger than this along either side are uploaded along with the material, so there's
+/// always something to draw it with
+const MIN_RESIDENT_SIZE: u32 = 64;
+
+/// Tunables for streaming material textures
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TextureStreamingConfig {
+    /// Most memory that material textures may take up, in megabytes
+    pub budget_mb: u32,
+
+    /// Most textures given a finer level each frame, which bounds how long the uploads take
+    pub uploads_per_frame: usize,
+}
+
+impl Default for TextureStreamingConfig {
+    fn default() -> Self {
+        Self {
+            budget_mb: 512,
+            uploads_per_frame: 4,
+        }
+    }
+}
+
+/// Which of a material's textures is being streamed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MaterialTexture {
+    BaseColor,
+    MetallicRoughness,
+    Normal,
+    Occlusion,
+    Emissive,
+    Lightmap,
+}
+
+struct StreamedTexture {
+    material_id: MaterialId,
+    slot: MaterialTexture,
+    format: wgpu::TextureFormat,
+    label: &'static str,
+
+    /// Every level of the texture, from the full size image down to a single texel
+    mips: Vec<image::RgbaImage>,
+
+    /// How many of the coarsest levels are on the GPU
+    resident: usize,
+
+    /// Fewest levels that are ever on the GPU, see `MIN_RESIDENT_SIZE`
+    min_resident: usize,
+
+    /// Frame this was last drawn in
+    last_used: u64,
+
+    /// Fraction of the screen's height covered by the largest thing drawn with this, in the last
+    /// frame it was drawn in
+    importance: f32,
+}
+
+impl StreamedTexture {
+    fn level_bytes(&self, level: usize) -> u64 {
+        let mip = &self.mips[level];
+        4 * mip.width() as u64 * mip.height() as u64
+    }
+
+    /// Index in to `mips` of the finest level on the GPU with `resident` levels
+    fn first_level(&self, resident: usize) -> usize {
+        self.mips.len() - resident
+    }
+
+    /// How many levels it takes to draw this at its importance without losing detail, assuming
+    /// it's stretched across what it's drawn on once
+    fn wanted(&self, screen_height: u32) -> usize {
+        let texels = self.importance * screen_height as f32;
+        let level = self
+            .mips
+            .iter()
+            .rposition(|mip| mip.width().max(mip.height()) as f32 >= texels)
+            .unwrap_or(0);
+        (self.mips.len() - level).max(self.min_resident)
+    }
+}
+
+/// Uploads material textures a level at a time, coarsest first, as they're seen up close
+///
+/// Each texture is allocated with only its resident levels, and reallocated whenever a level is
+/// added or evicted, copying across the levels it keeps. The finest levels of the textures drawn
+/// least recently are evicted first whenever they'd take up more than the budget.
+pub struct TextureStreamer {
+    config: TextureStreamingConfig,
+    textures: Vec<StreamedTexture>,
+    frame: u64,
+}
+
+impl TextureStreamer {
+    pub fn new(config: TextureStreamingConfig) -> Self {
+        Self {
+            config,
+            textures: Vec::new(),
+            frame: 0,
+        }
+    }
+
+    /// Start streaming one of a material's textures, returning it with only its coarsest levels
+    #[allow(clippy::too_many_arguments)]
+    pub fn add(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &mut wgpu::Queue,
+        material_id: MaterialId,
+        slot: MaterialTexture,
+        image: &image::RgbaImage,
+        format: wgpu::TextureFormat,
+        label: &'static str,
+    ) -> wgpu::Texture {
+        let mips = mip_chain(image);
+        let min_resident = mips
+            .iter()
+            .filter(|mip| mip.width().max(mip.height()) <= MIN_RESIDENT_SIZE)
+            .count()
+            .max(1);
+        let texture = StreamedTexture {
+            material_id,
+            slot,
+            format,
+            label,
+            mips,
+            resident: min_resident,
+            min_resident,
+            last_used: self.frame,
+            importance: 0.0,
+        };
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Texture upload commands"),
+        });
+        let gpu_texture = create_texture(device, &mut encoder, &texture, min_resident, None);
+        queue.submit(&[encoder.finish()]);
+
+        self.textures.push(texture);
+        gpu_texture
+    }
+
+    /// Record adding and evicting levels of the textures drawn by this frame's models, returning
+    /// the materials whose textures were reallocated
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        frame_packet: &FramePacket,
+        models: &HashMap<ModelId, GpuModel>,
+        materials: &mut HashMap<MaterialId, GpuMaterial>,
+        screen_height: u32,
+    ) -> Vec<MaterialId> {
+        self.frame += 1;
+        let importance = material_importance(frame_packet, models);
+        for texture in &mut self.textures {
+            if let Some(&importance) = importance.get(&texture.material_id) {
+                texture.last_used = self.frame;
+                texture.importance = importance;
+            }
+        }
+
+        let mut changed = Vec::new();
+        for (i, resident) in self.plan(screen_height) {
+            let texture = &mut self.textures[i];
+            let Some(material) = materials.get_mut(&texture.material_id) else {
+                continue;
+            };
+            let gpu_texture = material.texture_mut(texture.slot);
+            let previous = Some((&*gpu_texture, texture.resident));
+            *gpu_texture = create_texture(device, encoder, texture, resident, previous);
+            texture.resident = resident;
+
+            if !changed.contains(&texture.material_id) {
+                changed.push(texture.material_id);
+            }
+        }
+        changed
+    }
+
+    /// The number of levels each texture that changes should have this frame
+    fn plan(&self, screen_height: u32) -> Vec<(usize, usize)> {
+        let budget = self.config.budget_mb as u64 * 1024 * 1024;
+        let wanted: Vec<_> = self.textures.iter().map(|t| t.wanted(screen_height)).collect();
+        let mut resident: Vec<_> = self.textures.iter().map(|t| t.resident).collect();
+        let mut total: u64 = self
+            .textures
+            .iter()
+            .flat_map(|t| (t.first_level(t.resident)..t.mips.len()).map(move |l| t.level_bytes(l)))
+            .sum();
+
+        // Add a level to each of the most important textures that want more
+        let mut uploads: Vec<_> = (0..self.textures.len())
+            .filter(|&i| self.textures[i].last_used == self.frame && resident[i] < wanted[i])
+            .collect();
+        uploads.sort_by(|&a, &b| {
+            self.textures[b].importance.total_cmp(&self.textures[a].importance)
+        });
+        uploads.truncate(self.config.uploads_per_frame);
+        let upload_bytes = |i: usize, resident: &[usize]| {
+            let texture = &self.textures[i];
+            texture.level_bytes(texture.first_level(resident[i] + 1))
+        };
+        let needed: u64 = uploads.iter().map(|&i| upload_bytes(i, &resident)).sum();
+
+        // Make room for them by evicting from whatever was drawn longest ago first, and the least
+        // important of those drawn as recently. Those drawn this frame keep what they want.
+        let mut by_recency: Vec<_> = (0..self.textures.len()).collect();
+        by_recency.sort_by(|&a, &b| {
+            let (a, b) = (&self.textures[a], &self.textures[b]);
+            a.last_used.cmp(&b.last_used).then(a.importance.total_cmp(&b.importance))
+        });
+        for i in by_recency {
+            let texture = &self.textures[i];
+            let keep = if texture.last_used == self.frame {
+                wanted[i]
+            } else {
+                texture.min_resident
+            };
+            while total + needed > budget && resident[i] > keep {
+                total -= texture.level_bytes(texture.first_level(resident[i]));
+                resident[i] -= 1;
+            }
+        }
+
+        for i in uploads {
+            let bytes = upload_bytes(i, &resident);
+            if total + bytes <= budget {
+                total += bytes;
+                resident[i] += 1;
+            }
+        }
+
+        (0..self.textures.len())
+            .filter(|&i| resident[i] != self.textures[i].resident)
+            .map(|i| (i, resident[i]))
+            .collect()
+    }
+}
+
+/// Halve the image down to a single texel
+pub(super) fn mip_chain(image: &image::RgbaImage) -> Vec<image::RgbaImage> {
+    let mut mips = vec![image.clone()];
+    loop {
+        let last = &mips[mips.len() - 1];
+        if last.width() == 1 && last.height() == 1 {
+            return mips;
+        }
+        let (width, height) = ((last.width() / 2).max(1), (last.height() / 2).max(1));
+        let next = image::imageops::resize(last, width, height, image::imageops::Triangle);
+        mips.push(next);
+    }
+}
+
+/// Allocate a texture with the coarsest `resident` levels, copying across any that were already
+/// in the `previous` texture, along with how many levels it had
+fn create_texture(
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    texture: &StreamedTexture,
+    resident: usize,
+    previous: Option<(&wgpu::Texture, usize)>,
+) -> wgpu::Texture {
+    let first_level = texture.first_level(resident);
+    let extent = |mip: &image::RgbaImage| wgpu::Extent3d {
+        width: mip.width(),
+        height: mip.height(),
+        depth: 1,
+    };
+    let gpu_texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(texture.label),
+        size: extent(&texture.mips[first_level]),
+        array_layer_count: 1,
+        mip_level_count: resident as u32,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: texture.format,
+        usage: wgpu::TextureUsage::SAMPLED
+            | wgpu::TextureUsage::COPY_DST
+            | wgpu::TextureUsage::COPY_SRC,
+    });
+
+    for (level, mip) in texture.mips.iter().enumerate().skip(first_level) {
+        let destination = wgpu::TextureCopyView {
+            texture: &gpu_texture,
+            mip_level: (level - first_level) as u32,
+            array_layer: 0,
+            origin: wgpu::Origin3d::ZERO,
+        };
+        match previous {
+            Some((previous, previous_resident))
+                if level >= texture.first_level(previous_resident) =>
+            {
+                let source = wgpu::TextureCopyView {
+                    texture: previous,
+                    mip_level: (level - texture.first_level(previous_resident)) as u32,
+                    array_layer: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                };
+                encoder.copy_texture_to_texture(source, destination, extent(mip));
+            }
+            _ => {
+                let buffer = device.create_buffer_with_data(
+                    mip.as_flat_samples().as_slice(),
+                    wgpu::BufferUsage::COPY_SRC,
+                );
+                let source = wgpu::BufferCopyView {
+                    buffer: &buffer,
+                    offset: 0,
+                    bytes_per_row: 4 * mip.width(),
+                    rows_per_image: mip.height(),
+                };
+                encoder.copy_buffer_to_texture(source, destination, extent(mip));
+            }
+        }
+    }
+
+    gpu_texture
+}
+
+/// The largest fraction of the screen's height covered by any instance drawn with each material
+fn material_importance(
+    frame_packet: &FramePacket,
+    models: &HashMap<ModelId, GpuModel>,
+) -> HashMap<MaterialId, f32> {
+    let mut importance = HashMap::new();
+    for model in &frame_packet.models {
+        let Some(model_data) = models.get(&model.model_id) else {
+            continue;
+        };
+        let size = model
+            .instances
+            .iter()
+            .map(|instance| {
+                let model_view = frame_packet.view * instance.model_matrix;
+                screen_size(&model_data.bounds, model_view, frame_packet.proj)
+            })
+            .fold(0.0, f32::max);
+        let material_id = model.material_id.unwrap_or(model_data.material);
+        let entry = importance.entry(material_id).or_insert(0.0f32);
+        *entry = entry.max(size);
+    }
+    importance
+}
+
+/// Fraction of the screen's height covered by the bounding sphere of `bounds`
+fn screen_size(bounds: &Aabb, model_view: Matrix4<f32>, proj: Matrix4<f32>) -> f32 {
+    let center = model_view.transform_point(bounds.min.midpoint(bounds.max));
+    let scale = model_view.x.truncate().magnitude();
+    let radius = (bounds.max - bounds.min).magnitude() / 2.0 * scale;
+    let depth = -center.z;
+    if depth <= radius {
+        // The camera is inside the sphere
+        return f32::INFINITY;
+    }
+    radius * proj.y.y / depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn streamed(size: u32, importance: f32, last_used: u64) -> StreamedTexture {
+        let mips = mip_chain(&image::RgbaImage::new(size, size));
+        let min_resident = mips.iter().filter(|mip| mip.width() <= MIN_RESIDENT_SIZE).count();
+        StreamedTexture {
+            material_id: MaterialId(0),
+            slot: MaterialTexture::BaseColor,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            label: "Test texture",
+            mips,
+            resident: min_resident,
+            min_resident,
+            last_used,
+            importance,
+        }
+    }
+
+    #[test]
+    fn test_mip_chain() {
+        let sizes: Vec<_> = mip_chain(&image::RgbaImage::new(8, 2))
+            .iter()
+            .map(|mip| (mip.width(), mip.height()))
+            .collect();
+        assert_eq!(sizes, vec![(8, 2), (4, 1), (2, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn test_wanted_levels() {
+        // 1024, 512, ..., 1 texels
+        let texture = streamed(1024, 0.25, 0);
+        assert_eq!(texture.mips.len(), 11);
+        assert_eq!(texture.wanted(1000), 9);
+        assert_eq!(streamed(1024, 0.0, 0).wanted(1000), texture.min_resident);
+        assert_eq!(streamed(1024, f32::INFINITY, 0).wanted(1000), 11);
+    }
+
+    #[test]
+    fn test_plan_evicts_least_recently_used() {
+        let mut streamer = TextureStreamer::new(TextureStreamingConfig {
+            budget_mb: 6,
+            uploads_per_frame: 4,
+        });
+        streamer.frame = 2;
+
+        // Every level of a 1024² texture is about 5.3MB, 4MB of which is the finest. Making room
+        // for that in the fresh texture takes evicting the two finest levels of the stale one.
+        let mut stale = streamed(1024, 1.0, 1);
+        stale.resident = 11;
+        let mut fresh = streamed(1024, 1.0, 2);
+        fresh.resident = 10;
+        streamer.textures = vec![stale, fresh];
+
+        assert_eq!(streamer.plan(1000), vec![(0, 9), (1, 11)]);
+    }
+}
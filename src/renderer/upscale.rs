@@ -0,0 +1,266 @@
+use std::rc::Rc;
+
+use crate::{error::Result, shader_cache::ShaderCache};
+use super::{
+    render_target::ColorTarget,
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    Renderer,
+};
+
+/// How the scene is resampled when it is rendered at a reduced internal resolution
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(unused)]
+pub enum UpscaleFilter {
+    Bilinear,
+
+    /// Bilinear upscaling followed by contrast adaptive sharpening, to recover some of the detail
+    /// lost by rendering at a lower resolution
+    ContrastAdaptiveSharpening {
+        /// 0..1, how strongly to sharpen
+        sharpness: f32,
+    },
+}
+
+impl Default for UpscaleFilter {
+    fn default() -> Self {
+        UpscaleFilter::ContrastAdaptiveSharpening { sharpness: 0.5 }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct UpscaleUniformData {
+    uv_scale: cgmath::Vector2<f32>,
+    uv_max: cgmath::Vector2<f32>,
+    texel_size: cgmath::Vector2<f32>,
+    footprint: cgmath::Vector2<f32>,
+    sharpness: f32,
+    filter: u32,
+}
+
+unsafe impl bytemuck::Pod for UpscaleUniformData {}
+unsafe impl bytemuck::Zeroable for UpscaleUniformData {}
+
+/// Represents a render stage that stretches the rendered region of the post processed scene over
+/// the whole of an output texture view
+///
+/// Despite the name this also handles downsampling when the scene has been supersampled.
+///
+/// The source is a render graph transient that can be a different texture each frame, so it's
+/// bound afresh every time it's drawn.
+pub struct UpscaleRenderStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    uniform_buff: wgpu::Buffer,
+    sampler: Rc<wgpu::Sampler>,
+}
+
+impl UpscaleRenderStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        resources: &mut ResourceCache,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/fullscreen.vert",
+                shaderc::ShaderKind::Vertex,
+            )
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader(
+                "./src/renderer/shaders/upscale.frag",
+                shaderc::ShaderKind::Fragment,
+            )
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<UpscaleUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Upscale stage uniform buffer"),
+        });
+
+        let bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler { comparison: false },
+                    },
+                ],
+                label: Some("Upscale stage bind group layout"),
+            });
+
+        let render_pipeline_layout =
+            resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&bind_group_layout],
+            });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &render_pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: ColorTarget::LDR_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+        });
+
+        let sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        Ok(Self {
+            pipeline,
+            bind_group_layout,
+            uniform_buff,
+            sampler,
+        })
+    }
+
+    fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        source: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &self.uniform_buff,
+                        range: 0..std::mem::size_of::<UpscaleUniformData>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+            label: Some("Upscale stage bind group"),
+        })
+    }
+
+    /// Stretch the top-left `source_region` pixels of `source` over `output`
+    ///
+    /// The given filter is only applied when the region is smaller than the output. A region the
+    /// same size as the output is copied through untouched, and a larger one is box filtered down.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        source: &wgpu::TextureView,
+        source_size: winit::dpi::PhysicalSize<u32>,
+        source_region: winit::dpi::PhysicalSize<u32>,
+        output_size: winit::dpi::PhysicalSize<u32>,
+        filter: UpscaleFilter,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+    ) {
+        let source_width = source_size.width as f32;
+        let source_height = source_size.height as f32;
+
+        let (filter, sharpness) = if source_region.width > output_size.width
+            || source_region.height > output_size.height
+        {
+            (2, 0.0)
+        } else if source_region == output_size {
+            (0, 0.0)
+        } else {
+            match filter {
+                UpscaleFilter::Bilinear => (0, 0.0),
+                UpscaleFilter::ContrastAdaptiveSharpening { sharpness } => (1, sharpness),
+            }
+        };
+
+        let uniform_data = UpscaleUniformData {
+            uv_scale: [
+                source_region.width as f32 / source_width,
+                source_region.height as f32 / source_height,
+            ]
+            .into(),
+            uv_max: [
+                (source_region.width as f32 - 0.5) / source_width,
+                (source_region.height as f32 - 0.5) / source_height,
+            ]
+            .into(),
+            texel_size: [1.0 / source_width, 1.0 / source_height].into(),
+            footprint: [
+                source_region.width as f32 / (output_size.width as f32 * source_width),
+                source_region.height as f32 / (output_size.height as f32 * source_height),
+            ]
+            .into(),
+            sharpness,
+            filter,
+        };
+
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[uniform_data]),
+        );
+        let bind_group = self.create_bind_group(&renderer.device, source);
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: output,
+                resolve_target: None,
+                load_op: wgpu::LoadOp::Clear,
+                store_op: wgpu::StoreOp::Store,
+                clear_color: wgpu::Color::BLACK,
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+        renderer.draw_counter.record(1);
+    }
+}
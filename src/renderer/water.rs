@@ -0,0 +1,527 @@
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+use cgmath::{InnerSpace, Matrix4, SquareMatrix, Vector3, Vector4};
+
+use crate::{
+    error::{Error, Result},
+    shader_cache::ShaderCache,
+};
+use super::{
+    frame_packet::{FramePacket, Water},
+    render_target::{RenderTarget, Viewport},
+    resource_cache::{RenderPipelineDesc, ResourceCache},
+    terrain::upload_layers,
+    texture_streaming::mip_chain,
+    Renderer,
+};
+
+const WATER_VERTEX_SHADER: &str = "./src/renderer/shaders/water.vert";
+const WATER_FRAGMENT_SHADER: &str = "./src/renderer/shaders/water.frag";
+
+/// Texels along each side of the waves' normal map
+const NORMAL_MAP_SIZE: u32 = 128;
+
+/// How far past the surface the reflection and refraction are clipped, in world units, so that
+/// the waves' distortion doesn't pull in the gap where models cross the surface
+const CLIP_BIAS: f32 = 0.05;
+
+/// Waves summed in to the normal map, as whole repeats across it in x and y, which keeps it
+/// seamless, and their amplitudes in the same units as the repeats
+const WAVES: [(f32, f32, f32); 5] = [
+    (1.0, 2.0, 0.02),
+    (3.0, -1.0, 0.012),
+    (-2.0, 5.0, 0.008),
+    (7.0, 4.0, 0.004),
+    (-9.0, -11.0, 0.002),
+];
+
+#[derive(Clone, Copy)]
+#[allow(unused)]
+struct WaterUniformData {
+    view: Matrix4<f32>,
+
+    /// Projection the surface is drawn with, jittered for TAA if the scene was
+    proj: Matrix4<f32>,
+
+    /// Inverse of the unjittered projection that the refraction was drawn with
+    inv_proj: Matrix4<f32>,
+
+    /// World space position of the camera, w is unused
+    camera_position: Vector4<f32>,
+
+    /// World space direction towards the sun, w is unused
+    sun_direction: Vector4<f32>,
+
+    /// Linear color of the sun scaled by its intensity, w is unused
+    sun_color: Vector4<f32>,
+
+    /// The surface's rectangle, from its minimum corner in xy to its maximum in zw
+    rect: [f32; 4],
+
+    /// Height, wave scale, distortion and time
+    params: [f32; 4],
+
+    /// Linear RGB color of the water, and its density in w
+    color: [f32; 4],
+
+    /// Size of the region of the scene target being drawn to in xy, and the fraction of the
+    /// reflection and refraction targets that their views are drawn in to in zw
+    region: [f32; 4],
+
+    /// Whether the refraction was drawn in x, yzw are unused
+    flags: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for WaterUniformData {}
+unsafe impl bytemuck::Zeroable for WaterUniformData {}
+
+/// Surface normal of the waves at the given point of the normal map, with +Z out of the water
+fn wave_normal(u: f32, v: f32) -> Vector3<f32> {
+    let (dx, dy) = WAVES.iter().fold((0.0, 0.0), |(dx, dy), &(kx, ky, amplitude)| {
+        let slope = 2.0 * PI * amplitude * (2.0 * PI * (kx * u + ky * v)).cos();
+        (dx + slope * kx, dy + slope * ky)
+    });
+    Vector3::new(-dx, -dy, 1.0).normalize()
+}
+
+/// The waves' normals packed in to the 0-1 range, with texel rows running along +v
+fn wave_normal_map(size: u32) -> image::RgbaImage {
+    image::RgbaImage::from_fn(size, size, |x, y| {
+        let normal = wave_normal(x as f32 / size as f32, y as f32 / size as f32);
+        let pack = |n: f32| ((n * 0.5 + 0.5) * 255.0).round() as u8;
+        image::Rgba([pack(normal.x), pack(normal.y), pack(normal.z), 255])
+    })
+}
+
+/// The camera that the reflection is drawn from, as a view and projection
+///
+/// The view is mirrored about the surface, and the projection is flipped horizontally to put
+/// triangles' winding back the way it was, so the reflection has to be sampled flipped too.
+pub(super) fn reflection_camera(
+    water: &Water,
+    view: Matrix4<f32>,
+    proj: Matrix4<f32>,
+) -> (Matrix4<f32>, Matrix4<f32>) {
+    let flip = Matrix4::from_nonuniform_scale(-1.0, 1.0, 1.0);
+    (view * water.reflection(), flip * proj)
+}
+
+/// World space plane that the reflection is clipped to, keeping what's above the surface
+pub(super) fn reflection_clip_plane(water: &Water) -> Vector4<f32> {
+    water.plane() + Vector4::new(0.0, 0.0, 0.0, CLIP_BIAS)
+}
+
+/// World space plane that the refraction is clipped to, keeping what's below the surface
+pub(super) fn refraction_clip_plane(water: &Water) -> Vector4<f32> {
+    -water.plane() + Vector4::new(0.0, 0.0, 0.0, CLIP_BIAS)
+}
+
+/// The region of the reflection and refraction targets that their views are drawn in to, for
+/// the given region of the scene target
+pub(super) fn view_region(scene_size: winit::dpi::PhysicalSize<u32>) -> Viewport {
+    Viewport::from_size(half_size(scene_size))
+}
+
+fn half_size(size: winit::dpi::PhysicalSize<u32>) -> winit::dpi::PhysicalSize<u32> {
+    winit::dpi::PhysicalSize::new((size.width / 2).max(1), (size.height / 2).max(1))
+}
+
+/// Represents a render stage that draws a plane of water in to the scene, reflecting it
+///
+/// Before the main view's scene is drawn, the scene is drawn again in to half resolution targets:
+/// mirrored about the water's surface with everything below it cut away for the reflection, and
+/// from the main camera with everything above it cut away for the refraction. The surface is
+/// drawn after the opaque scene, bending both by a scrolling normal map and blending between
+/// them by the Fresnel term. The refraction fades to the water's color with the depth of water
+/// it's seen through, which is measured from the refraction's depth.
+///
+/// That depth can't be read under MSAA, so the stage isn't created then. The reflection is only
+/// right when the camera is above the water.
+pub struct WaterRenderStage {
+    pipeline: Rc<wgpu::RenderPipeline>,
+    uniform_bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    uniform_buff: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    target_sampler: Rc<wgpu::Sampler>,
+    depth_sampler: Rc<wgpu::Sampler>,
+    normal_map: wgpu::TextureView,
+    normal_sampler: Rc<wgpu::Sampler>,
+
+    /// The scene mirrored about the surface, at half the scene target's resolution
+    pub reflection: RenderTarget,
+
+    /// The scene below the surface, at half the scene target's resolution
+    pub refraction: RenderTarget,
+}
+
+impl WaterRenderStage {
+    pub async fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        resources: &mut ResourceCache,
+        target: &RenderTarget,
+    ) -> Result<Self> {
+        let mut shader_cache = ShaderCache::new();
+        let vs_spirv = shader_cache
+            .get_shader(WATER_VERTEX_SHADER, shaderc::ShaderKind::Vertex)
+            .await?;
+        let fs_spirv = shader_cache
+            .get_shader_with_defines(
+                WATER_FRAGMENT_SHADER,
+                shaderc::ShaderKind::Fragment,
+                target.depth_order.shader_defines(),
+            )
+            .await?;
+
+        let uniform_buff = device.create_buffer(&wgpu::BufferDescriptor {
+            size: std::mem::size_of::<WaterUniformData>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            label: Some("Water stage uniform buffer"),
+        });
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+            },
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Sampler { comparison: false },
+        };
+        let uniform_bind_group_layout =
+            resources.bind_group_layout(device, &wgpu::BindGroupLayoutDescriptor {
+                bindings: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer { dynamic: false },
+                    },
+                    texture_entry(1),
+                    texture_entry(2),
+                    texture_entry(3),
+                    sampler_entry(4),
+                    sampler_entry(5),
+                    texture_entry(6),
+                    sampler_entry(7),
+                ],
+                label: Some("Water stage uniform bind group layout"),
+            });
+
+        let pipeline_layout = resources.pipeline_layout(device, &wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&uniform_bind_group_layout],
+        });
+
+        let pipeline = resources.render_pipeline(device, &RenderPipelineDesc {
+            layout: &pipeline_layout,
+            vertex_shader: &vs_spirv,
+            fragment_shader: Some(&fs_spirv),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: RenderTarget::COLOR_FORMAT,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                format: RenderTarget::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: target.depth_order.compare(wgpu::CompareFunction::Less),
+                stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
+                stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
+                stencil_read_mask: 0,
+                stencil_write_mask: 0,
+            }),
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: target.sample_count,
+        });
+
+        let target_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        let depth_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+        let normal_sampler = resources.sampler(device, &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: wgpu::CompareFunction::Always,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Water normal map upload commands"),
+        });
+        let normal_map = wave_normal_map(NORMAL_MAP_SIZE);
+        let normal_map = upload_layers(
+            device,
+            &mut encoder,
+            std::slice::from_ref(&normal_map),
+            mip_chain(&normal_map).len() as u32,
+            wgpu::TextureFormat::Rgba8Unorm,
+            "Water normal map texture",
+        );
+        queue.submit(&[encoder.finish()]);
+        let normal_map = normal_map.create_default_view();
+
+        let size = half_size(target.size);
+        let reflection = RenderTarget::new(device, size, 1, target.depth_order);
+        let refraction = RenderTarget::new(device, size, 1, target.depth_order);
+        let bind_group = Self::create_bind_group(
+            device,
+            &uniform_bind_group_layout,
+            &uniform_buff,
+            &reflection,
+            &refraction,
+            &target_sampler,
+            &depth_sampler,
+            &normal_map,
+            &normal_sampler,
+        );
+
+        Ok(Self {
+            pipeline,
+            uniform_bind_group_layout,
+            uniform_buff,
+            bind_group,
+            target_sampler,
+            depth_sampler,
+            normal_map,
+            normal_sampler,
+            reflection,
+            refraction,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buff: &wgpu::Buffer,
+        reflection: &RenderTarget,
+        refraction: &RenderTarget,
+        target_sampler: &wgpu::Sampler,
+        depth_sampler: &wgpu::Sampler,
+        normal_map: &wgpu::TextureView,
+        normal_sampler: &wgpu::Sampler,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            bindings: &[
+                wgpu::Binding {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: uniform_buff,
+                        range: 0..std::mem::size_of::<WaterUniformData>() as wgpu::BufferAddress,
+                    },
+                },
+                wgpu::Binding {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&reflection.color_view),
+                },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&refraction.color_view),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&refraction.depth_view),
+                },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(target_sampler),
+                },
+                wgpu::Binding {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(depth_sampler),
+                },
+                wgpu::Binding {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(normal_map),
+                },
+                wgpu::Binding {
+                    binding: 7,
+                    resource: wgpu::BindingResource::Sampler(normal_sampler),
+                },
+            ],
+            label: Some("Water stage bind group"),
+        })
+    }
+
+    /// Reallocate the reflection and refraction targets to follow the scene target's size
+    pub fn set_target(&mut self, device: &wgpu::Device, target: &RenderTarget) {
+        let size = half_size(target.size);
+        self.reflection = RenderTarget::new(device, size, 1, target.depth_order);
+        self.refraction = RenderTarget::new(device, size, 1, target.depth_order);
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.uniform_bind_group_layout,
+            &self.uniform_buff,
+            &self.reflection,
+            &self.refraction,
+            &self.target_sampler,
+            &self.depth_sampler,
+            &self.normal_map,
+            &self.normal_sampler,
+        );
+    }
+
+    /// Draw the water's surface over the top-left `region` pixels of the scene target, once its
+    /// reflection and refraction have been drawn, with `proj` in place of the frame's own
+    /// projection, eg. jittered for TAA
+    pub fn draw_frame(
+        &self,
+        renderer: &Renderer,
+        frame_packet: &FramePacket,
+        water: &Water,
+        proj: Matrix4<f32>,
+        encoder: &mut wgpu::CommandEncoder,
+        region: winit::dpi::PhysicalSize<u32>,
+    ) -> Result<()> {
+        let inv_proj = frame_packet
+            .proj
+            .invert()
+            .ok_or(Error::InvalidFramePacket("Projection matrix had a zero determinant"))?;
+        let camera_position = frame_packet
+            .view
+            .invert()
+            .ok_or(Error::InvalidFramePacket("View matrix had a zero determinant"))?
+            .w;
+        let (sun_direction, sun_color) = match frame_packet.directional_light {
+            Some(light) => (-light.direction.normalize(), light.color * light.intensity),
+            None => (Vector3::unit_z(), Vector3::new(0.0, 0.0, 0.0)),
+        };
+        let view_size = half_size(region);
+        let target_size = self.reflection.size;
+        renderer.staging_belt.borrow_mut().write(
+            &renderer.device,
+            encoder,
+            &self.uniform_buff,
+            0,
+            bytemuck::cast_slice(&[WaterUniformData {
+                view: frame_packet.view,
+                proj,
+                inv_proj,
+                camera_position,
+                sun_direction: sun_direction.extend(0.0),
+                sun_color: sun_color.extend(0.0),
+                rect: [water.min.x, water.min.y, water.max.x, water.max.y],
+                params: [water.height, water.wave_scale, water.distortion, water.time],
+                color: water.color.extend(water.density).into(),
+                region: [
+                    region.width as f32,
+                    region.height as f32,
+                    view_size.width as f32 / target_size.width as f32,
+                    view_size.height as f32 / target_size.height as f32,
+                ],
+                flags: [if water.refraction { 1.0 } else { 0.0 }, 0.0, 0.0, 0.0],
+            }]),
+        );
+
+        let target = &renderer.scene_target;
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[target.color_attachment(wgpu::LoadOp::Load)],
+            depth_stencil_attachment: Some(target.depth_attachment(wgpu::LoadOp::Load)),
+        });
+
+        Viewport::from_size(region).apply(&mut rpass);
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &self.bind_group, &[]);
+        rpass.draw(0..6, 0..1);
+        renderer.draw_counter.record(1);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Point3};
+
+    /// Height of the waves that `wave_normal` is the normal of
+    fn wave_height(u: f32, v: f32) -> f32 {
+        WAVES
+            .iter()
+            .map(|&(kx, ky, amplitude)| amplitude * (2.0 * PI * (kx * u + ky * v)).sin())
+            .sum()
+    }
+
+    #[test]
+    fn test_wave_normals() {
+        // The normal map repeats seamlessly in both directions
+        let normal = wave_normal(0.3, 0.7);
+        assert!((normal - wave_normal(1.3, -0.3)).magnitude() < 1e-4);
+        assert!(normal.z > 0.0);
+
+        // Normals lean away from uphill
+        let (u, v, step) = (0.1, 0.4, 1e-3);
+        let slope = (wave_height(u + step, v) - wave_height(u - step, v)) / (2.0 * step);
+        assert!(wave_normal(u, v).x * slope < 0.0);
+    }
+
+    #[test]
+    fn test_reflection_camera() {
+        let water = Water {
+            height: 1.0,
+            ..Water::default()
+        };
+        let view = Matrix4::look_at(
+            Point3::new(2.0, -6.0, 4.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::unit_z(),
+        );
+        let proj = cgmath::perspective(Deg(60.0), 1.5, 0.1, 100.0);
+        let (mirrored_view, mirrored_proj) = reflection_camera(&water, view, proj);
+
+        // Points on the surface land on the same pixel, flipped horizontally
+        let on_surface = Vector4::new(3.0, 1.0, 1.0, 1.0);
+        let clip = proj * view * on_surface;
+        let mirrored = mirrored_proj * mirrored_view * on_surface;
+        assert!((mirrored - Vector4::new(-clip.x, clip.y, clip.z, clip.w)).magnitude() < 1e-4);
+
+        // Models sticking through the surface are cut off a little past it on each side
+        let above = Vector4::new(0.0, 0.0, 1.01, 1.0);
+        let below = Vector4::new(0.0, 0.0, 0.99, 1.0);
+        assert!(reflection_clip_plane(&water).dot(below) > 0.0);
+        assert!(refraction_clip_plane(&water).dot(above) > 0.0);
+        assert!(reflection_clip_plane(&water).dot(Vector4::new(0.0, 0.0, 0.9, 1.0)) < 0.0);
+        assert!(refraction_clip_plane(&water).dot(Vector4::new(0.0, 0.0, 1.1, 1.0)) < 0.0);
+    }
+}
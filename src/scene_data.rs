@@ -0,0 +1,370 @@
+use std::path::Path;
+
+use cgmath::{Matrix4, Quaternion, SquareMatrix, Vector3};
+use tracing::{info, warn};
+
+use crate::animation::AnimationClip;
+use crate::error::{Error, Result};
+use crate::mesh_opt::MeshStats;
+use crate::model_data::ModelData;
+
+/// A single GLTF mesh, with each of its primitives split out as a separate model as they may
+/// each have their own material
+pub struct MeshData {
+    pub primitives: Vec<ModelData>,
+}
+
+/// A node's transform split in to the parts that animations target
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeTransform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl NodeTransform {
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    /// Somewhere between this transform and another, where `t` is 0 for this one and 1 for the
+    /// other
+    pub fn lerp(&self, other: &NodeTransform, t: f32) -> NodeTransform {
+        NodeTransform {
+            translation: self.translation + (other.translation - self.translation) * t,
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale + (other.scale - self.scale) * t,
+        }
+    }
+}
+
+impl Default for NodeTransform {
+    fn default() -> Self {
+        Self {
+            translation: Vector3::new(0.0, 0.0, 0.0),
+            rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+pub struct SceneNode {
+    /// Transform from this node's space to its parent's space, when not being animated
+    pub local_transform: NodeTransform,
+
+    /// Index in to `SceneData::meshes` of the mesh drawn at this node, if any
+    pub mesh: Option<usize>,
+
+    /// Index in to `SceneData::skins` of the skin deforming this node's mesh, if any
+    pub skin: Option<usize>,
+
+    /// Indices in to `SceneData::nodes`
+    pub children: Vec<usize>,
+}
+
+/// The joints that deform a skinned mesh
+pub struct SkinData {
+    /// Indices in to `SceneData::nodes`, in the order the mesh's joint indices refer to them
+    pub joints: Vec<usize>,
+
+    /// Transforms from the mesh's space to each joint's space in the bind pose
+    pub inverse_bind_matrices: Vec<Matrix4<f32>>,
+}
+
+impl SkinData {
+    /// The matrix each joint deforms the mesh's vertices by, given the scene space transform of
+    /// every node
+    ///
+    /// The results are relative to `mesh_transform`, the scene space transform the mesh is drawn
+    /// with, as glTF places skinned meshes purely by their joints.
+    pub fn joint_matrices(
+        &self,
+        node_transforms: &[Matrix4<f32>],
+        mesh_transform: Matrix4<f32>,
+    ) -> Vec<Matrix4<f32>> {
+        let inverse_mesh_transform = mesh_transform.invert().unwrap_or_else(Matrix4::identity);
+        self.joints
+            .iter()
+            .zip(&self.inverse_bind_matrices)
+            .map(|(&joint, inverse_bind)| {
+                inverse_mesh_transform * node_transforms[joint] * inverse_bind
+            })
+            .collect()
+    }
+}
+
+/// Somewhere a mesh is drawn in a scene
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeshInstance {
+    /// Index in to `SceneData::meshes`
+    pub mesh: usize,
+
+    /// Index in to `SceneData::nodes` of the node drawing the mesh
+    pub node: usize,
+
+    /// Transforms from the mesh's space to scene space
+    pub transform: Matrix4<f32>,
+}
+
+/// Represents a whole GLTF scene on the CPU, ie. every mesh along with the node hierarchy
+/// placing them
+pub struct SceneData {
+    pub meshes: Vec<MeshData>,
+    pub nodes: Vec<SceneNode>,
+    pub skins: Vec<SkinData>,
+    pub animations: Vec<AnimationClip>,
+
+    /// Indices in to `nodes` of the nodes with no parent
+    pub roots: Vec<usize>,
+}
+
+/// File extensions that `SceneData::load` can load, in lowercase
+const SUPPORTED_EXTENSIONS: [&str; 3] = ["glb", "gltf", "obj"];
+
+/// The lowercase extension of a path, if it has one
+fn extension(path: &Path) -> Option<String> {
+    Some(path.extension()?.to_str()?.to_lowercase())
+}
+
+impl SceneData {
+    /// Whether `load` can load the given file, going by its extension
+    pub fn is_supported(path: &Path) -> bool {
+        extension(path).is_some_and(|extension| SUPPORTED_EXTENSIONS.contains(&&*extension))
+    }
+
+    /// Load a GLTF or OBJ file, picking which by the file's extension
+    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        match extension(path).as_deref() {
+            Some("glb") | Some("gltf") => Self::load_gltf(path).await,
+            Some("obj") => Ok(Self::from_model(ModelData::load_obj(path).await?)),
+            _ => Err(Error::InvalidAsset("Unsupported model format, expected .glb, .gltf or .obj")),
+        }
+    }
+
+    /// A scene with a single root node drawing the given model
+    pub fn from_model(model: ModelData) -> Self {
+        Self {
+            meshes: vec![MeshData {
+                primitives: vec![model],
+            }],
+            nodes: vec![SceneNode {
+                local_transform: NodeTransform::default(),
+                mesh: Some(0),
+                skin: None,
+                children: Vec::new(),
+            }],
+            skins: Vec::new(),
+            animations: Vec::new(),
+            roots: vec![0],
+        }
+    }
+
+    /// Load the default scene from a GLTF file, or the first scene if there isn't a default
+    ///
+    /// Binary `.glb` files are read asynchronously. JSON `.gltf` files can refer to buffers and
+    /// images in other files next to them, which only `gltf::import` resolves, so those block
+    /// the worker thread while they're read.
+    pub async fn load_gltf<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let (doc, buffers, images) = if extension(path).as_deref() == Some("gltf") {
+            tokio::task::block_in_place(|| gltf::import(path))?
+        } else {
+            let file_content = tokio::fs::read(path).await.map_err(Error::io(path))?;
+            gltf::import_slice(&file_content)?
+        };
+        for extension in doc.extensions_used() {
+            warn!("Ignoring unsupported GLTF extension {}", extension);
+        }
+
+        let mut meshes: Vec<MeshData> = doc
+            .meshes()
+            .map(|mesh| {
+                let primitives = mesh
+                    .primitives()
+                    .map(|primitive| ModelData::from_gltf_primitive(&primitive, &buffers, &images))
+                    .collect::<Result<_, _>>()?;
+                Ok(MeshData { primitives })
+            })
+            .collect::<Result<_>>()?;
+
+        let mut stats = MeshStats::default();
+        for mesh in &mut meshes {
+            for primitive in &mut mesh.primitives {
+                stats += primitive.optimize(false);
+            }
+        }
+        info!("Optimized {}: {}", path.display(), stats);
+
+        let nodes = doc
+            .nodes()
+            .map(|node| {
+                let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+                SceneNode {
+                    local_transform: NodeTransform {
+                        translation: translation.into(),
+                        rotation: Quaternion::new(w, x, y, z),
+                        scale: scale.into(),
+                    },
+                    mesh: node.mesh().map(|mesh| mesh.index()),
+                    skin: node.skin().map(|skin| skin.index()),
+                    children: node.children().map(|child| child.index()).collect(),
+                }
+            })
+            .collect();
+
+        let skins = doc
+            .skins()
+            .map(|skin| {
+                let joints: Vec<usize> = skin.joints().map(|joint| joint.index()).collect();
+                // Without explicit matrices every joint's bind pose is the identity
+                let inverse_bind_matrices = match skin
+                    .reader(|buff| Some(&buffers[buff.index()]))
+                    .read_inverse_bind_matrices()
+                {
+                    Some(matrices) => matrices.map(Matrix4::from).collect(),
+                    None => vec![Matrix4::identity(); joints.len()],
+                };
+                if inverse_bind_matrices.len() != joints.len() {
+                    return Err(Error::InvalidAsset(
+                        "Skin has a different number of joints and inverse bind matrices",
+                    ));
+                }
+                Ok(SkinData {
+                    joints,
+                    inverse_bind_matrices,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let animations = doc
+            .animations()
+            .map(|animation| AnimationClip::from_gltf(&animation, &buffers))
+            .collect::<Result<_>>()?;
+
+        let scene = doc
+            .default_scene()
+            .or_else(|| doc.scenes().next())
+            .ok_or(Error::InvalidAsset("Expected a GLTF file with at least one scene"))?;
+        let roots = scene.nodes().map(|node| node.index()).collect();
+
+        Ok(Self {
+            meshes,
+            nodes,
+            skins,
+            animations,
+            roots,
+        })
+    }
+
+    /// The scene space transform of every node, given the local transform of each
+    pub fn node_transforms(&self, local_transforms: &[NodeTransform]) -> Vec<Matrix4<f32>> {
+        let mut transforms = vec![Matrix4::identity(); self.nodes.len()];
+        let mut stack: Vec<(usize, Matrix4<f32>)> = self
+            .roots
+            .iter()
+            .map(|&root| (root, Matrix4::identity()))
+            .collect();
+
+        while let Some((node_index, parent_transform)) = stack.pop() {
+            let transform = parent_transform * local_transforms[node_index].matrix();
+            transforms[node_index] = transform;
+            stack.extend(self.nodes[node_index].children.iter().map(|&child| (child, transform)));
+        }
+
+        transforms
+    }
+
+    /// The local transform of every node when nothing is animating it
+    pub fn rest_pose(&self) -> Vec<NodeTransform> {
+        self.nodes.iter().map(|node| node.local_transform).collect()
+    }
+
+    /// Every mesh drawn by the scene in its rest pose
+    ///
+    /// A mesh appears once for every node that references it.
+    pub fn mesh_instances(&self) -> Vec<MeshInstance> {
+        let transforms = self.node_transforms(&self.rest_pose());
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(node, scene_node)| {
+                Some(MeshInstance {
+                    mesh: scene_node.mesh?,
+                    node,
+                    transform: transforms[node],
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translated_node(x: f32, mesh: Option<usize>, children: Vec<usize>) -> SceneNode {
+        SceneNode {
+            local_transform: NodeTransform {
+                translation: Vector3::new(x, 0.0, 0.0),
+                ..NodeTransform::default()
+            },
+            mesh,
+            skin: None,
+            children,
+        }
+    }
+
+    #[test]
+    fn test_mesh_instances_compose_transforms() {
+        let translation = |x: f32| Matrix4::from_translation(Vector3::new(x, 0.0, 0.0));
+        let scene = SceneData {
+            meshes: Vec::new(),
+            nodes: vec![
+                translated_node(1.0, None, vec![1, 2]),
+                translated_node(2.0, Some(0), vec![]),
+                translated_node(3.0, Some(0), vec![]),
+            ],
+            skins: Vec::new(),
+            animations: Vec::new(),
+            roots: vec![0],
+        };
+
+        let instances: Vec<_> = scene
+            .mesh_instances()
+            .into_iter()
+            .map(|instance| (instance.mesh, instance.node, instance.transform))
+            .collect();
+        assert_eq!(instances, vec![(0, 1, translation(3.0)), (0, 2, translation(4.0))]);
+    }
+
+    #[test]
+    fn test_joint_matrices_are_relative_to_bind_pose() {
+        let translation = |x: f32| Matrix4::from_translation(Vector3::new(x, 0.0, 0.0));
+        let skin = SkinData {
+            joints: vec![0],
+            inverse_bind_matrices: vec![translation(-1.0)],
+        };
+
+        // A joint still in its bind pose leaves the mesh where it is
+        assert_eq!(skin.joint_matrices(&[translation(1.0)], Matrix4::identity()), vec![
+            Matrix4::identity()
+        ]);
+
+        // Moving the joint moves the mesh with it, wherever the mesh is drawn
+        let mesh_transform = translation(5.0);
+        let joints = skin.joint_matrices(&[translation(3.0)], mesh_transform);
+        assert_eq!(mesh_transform * joints[0], translation(2.0));
+    }
+
+    #[test]
+    fn test_supported_extensions() {
+        assert!(SceneData::is_supported(Path::new("models/thing.glb")));
+        assert!(SceneData::is_supported(Path::new("THING.GLTF")));
+        assert!(SceneData::is_supported(Path::new("thing.obj")));
+        assert!(!SceneData::is_supported(Path::new("thing.fbx")));
+        assert!(!SceneData::is_supported(Path::new("thing")));
+    }
+}
@@ -1,15 +1,87 @@
-use std::path::Path;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::fs::File;
 use tokio::prelude::*;
+use tracing::warn;
+
+use crate::error::{Error, Result};
+
+/// Where compiled SPIR-V is kept between runs
+pub const SHADER_CACHE_DIR: &str = "./.shader_cache";
+
+/// The first word of every SPIR-V module
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+/// Identifies one compiled permutation of a shader's source in the on-disk cache
+///
+/// `DefaultHasher` isn't guaranteed to be stable across Rust releases, but the worst that can
+/// happen when it changes is that every entry misses and gets compiled again.
+fn cache_key(
+    source_text: &str,
+    shader_kind: shaderc::ShaderKind,
+    defines: &[(&str, Option<&str>)],
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    source_text.hash(&mut hasher);
+    defines.hash(&mut hasher);
+    format!("{:016x}.{:?}.spv", hasher.finish(), shader_kind).to_lowercase()
+}
+
+/// Reinterpret a cache file as SPIR-V words, or None if it doesn't look like a SPIR-V module
+fn spirv_from_bytes(bytes: &[u8]) -> Option<Vec<u32>> {
+    let chunks = bytes.chunks_exact(4);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+
+    let words: Vec<u32> = chunks
+        .map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+    if words.first() != Some(&SPIRV_MAGIC) {
+        return None;
+    }
+    Some(words)
+}
+
+/// Write a file by writing a temporary one alongside it and renaming it in to place, so that
+/// anything reading the path at the same time, eg. another process sharing the cache, never sees
+/// it half written
+fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    // Unique within the process as well as between processes, for tests compiling in parallel
+    static NEXT_TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+    let temp_id = NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed);
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(format!(".{}-{}.tmp", std::process::id(), temp_id));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = std::fs::write(&temp_path, contents)
+        .and_then(|_| std::fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
 
 pub struct ShaderCache {
     compiler: shaderc::Compiler,
+
+    /// Source text of every shader loaded so far, so that further permutations of them can be
+    /// compiled without going back to disk
+    sources: HashMap<PathBuf, String>,
+
+    /// Directory compiled SPIR-V is read from and written to
+    cache_dir: PathBuf,
 }
 
 impl ShaderCache {
     pub fn new() -> Self {
         Self {
             compiler: shaderc::Compiler::new().unwrap(),
+            sources: HashMap::new(),
+            cache_dir: PathBuf::from(SHADER_CACHE_DIR),
         }
     }
 
@@ -17,36 +89,181 @@ impl ShaderCache {
         &mut self,
         path: P,
         shader_kind: shaderc::ShaderKind,
-    ) -> Vec<u32> {
+    ) -> Result<Vec<u32>> {
+        self.get_shader_with_defines(path, shader_kind, &[]).await
+    }
+
+    /// Compile a shader with the given preprocessor macros defined, as `(name, value)` pairs
+    pub async fn get_shader_with_defines<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        shader_kind: shaderc::ShaderKind,
+        defines: &[(&str, Option<&str>)],
+    ) -> Result<Vec<u32>> {
+        self.load_source(path.as_ref()).await?;
+        self.compile(path, shader_kind, defines)
+    }
+
+    /// Read a shader's source from disk, if it hasn't been already
+    pub async fn load_source<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if self.sources.contains_key(path) {
+            return Ok(());
+        }
+
+        let mut source_text = Vec::new();
+        let mut file = File::open(path).await.map_err(Error::io(path))?;
+        file.read_to_end(&mut source_text)
+            .await
+            .map_err(Error::io(path))?;
+        let source_text = String::from_utf8(source_text).map_err(|e| Error::Io {
+            path: path.to_owned(),
+            source: std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        })?;
+
+        self.sources.insert(path.to_owned(), source_text);
+        Ok(())
+    }
+
+    /// Read a shader's source from disk again, eg. after it has been edited
+    ///
+    /// Unlike `load_source` this blocks, which is fine for the occasional reload while developing.
+    pub fn reload_source<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let source_text = std::fs::read_to_string(path).map_err(Error::io(path))?;
+        self.sources.insert(path.to_owned(), source_text);
+        Ok(())
+    }
+
+    /// Compile a shader whose source has already been loaded with `load_source`
+    ///
+    /// If this exact source has been compiled with the same kind and defines before, the SPIR-V
+    /// is read back from the disk cache instead. That's only a small read, so this is still fine
+    /// to call in the middle of a frame.
+    pub fn compile<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        shader_kind: shaderc::ShaderKind,
+        defines: &[(&str, Option<&str>)],
+    ) -> Result<Vec<u32>> {
         let path = path.as_ref();
         let input_file_name = path
             .file_name()
             .expect("Expected path to have a filename")
             .to_str()
             .expect("Expected filename to be valid unicode");
+        let source_text = self
+            .sources
+            .get(path)
+            .expect("Shader source must be loaded before it is compiled");
 
-        let mut source_text = Vec::new();
-        let mut file = File::open(path)
-            .await
-            .expect("Failed to open shader source file");
-        file.read_to_end(&mut source_text)
-            .await
-            .expect("Failed to read shader source file");
-        let source_text =
-            std::str::from_utf8(&source_text).expect("Expected shader source to be valid utf8");
+        let cache_path = self.cache_dir.join(cache_key(source_text, shader_kind, defines));
+        if let Some(spirv) = std::fs::read(&cache_path)
+            .ok()
+            .and_then(|bytes| spirv_from_bytes(&bytes))
+        {
+            return Ok(spirv);
+        }
 
         let entry_point_name = "main";
-        let additional_options = None;
-        self.compiler
+        let mut options = shaderc::CompileOptions::new().expect("Failed to create compile options");
+        for (name, value) in defines {
+            options.add_macro_definition(name, *value);
+        }
+
+        let artifact = self
+            .compiler
             .compile_into_spirv(
-                &source_text,
+                source_text,
                 shader_kind,
                 input_file_name,
                 entry_point_name,
-                additional_options,
+                Some(&options),
             )
-            .expect("Failed to compile shader source")
-            .as_binary()
-            .to_vec()
+            .map_err(|source| Error::ShaderCompilation {
+                path: path.to_owned(),
+                source,
+            })?;
+
+        // Failing to cache only costs another compile next time, so isn't worth failing over
+        let write_result = std::fs::create_dir_all(&self.cache_dir)
+            .and_then(|_| write_atomically(&cache_path, artifact.as_binary_u8()));
+        if let Err(e) = write_result {
+            warn!("Failed to cache shader {}: {}", cache_path.display(), e);
+        }
+
+        Ok(artifact.as_binary().to_vec())
+    }
+
+    /// Remove the cached SPIR-V for one permutation of a loaded shader, so that it's compiled
+    /// from source next time
+    #[allow(unused)]
+    pub fn invalidate<P: AsRef<Path>>(
+        &self,
+        path: P,
+        shader_kind: shaderc::ShaderKind,
+        defines: &[(&str, Option<&str>)],
+    ) -> Result<()> {
+        let source_text = self
+            .sources
+            .get(path.as_ref())
+            .expect("Shader source must be loaded before it is invalidated");
+        let cache_path = self.cache_dir.join(cache_key(source_text, shader_kind, defines));
+
+        match std::fs::remove_file(&cache_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::io(cache_path)(e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove every cached shader, eg. after upgrading shaderc
+    #[allow(unused)]
+    pub fn clear_disk_cache(&self) -> Result<()> {
+        match std::fs::remove_dir_all(&self.cache_dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(Error::io(&self.cache_dir)(e))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key_covers_kind_and_defines() {
+        let source = "#version 450\nvoid main() {}";
+        let key = cache_key(source, shaderc::ShaderKind::Vertex, &[]);
+        assert!(key.ends_with(".vertex.spv"));
+        assert_eq!(key, cache_key(source, shaderc::ShaderKind::Vertex, &[]));
+        assert_ne!(key, cache_key(source, shaderc::ShaderKind::Fragment, &[]));
+        assert_ne!(key, cache_key(source, shaderc::ShaderKind::Vertex, &[("FOO", None)]));
+        assert_ne!(key, cache_key("#version 450", shaderc::ShaderKind::Vertex, &[]));
+    }
+
+    #[test]
+    fn test_spirv_from_bytes() {
+        let words = [SPIRV_MAGIC, 0x0001_0000, 1234];
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_ne_bytes().to_vec()).collect();
+        assert_eq!(spirv_from_bytes(&bytes), Some(words.to_vec()));
+        assert_eq!(spirv_from_bytes(&bytes[..7]), None);
+        assert_eq!(spirv_from_bytes(&[0; 8]), None);
+    }
+
+    #[test]
+    fn test_write_atomically_leaves_only_the_file() {
+        let dir = std::env::temp_dir().join(format!("shader_cache_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("0123456789abcdef.vertex.spv");
+
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(files, vec![path]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
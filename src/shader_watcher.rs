@@ -0,0 +1,59 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// How long a file has to go without changing before it's reported, so that an editor saving in
+/// several steps only causes a single reload
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(100);
+
+/// Watches a directory of shader sources for edits
+pub struct ShaderWatcher {
+    /// Stops watching when dropped
+    _watcher: RecommendedWatcher,
+    events: Receiver<DebouncedEvent>,
+
+    /// The directory as it was passed to `new`, which changed files are reported relative to
+    dir: PathBuf,
+
+    /// notify reports absolute paths, which have this prefix
+    canonical_dir: PathBuf,
+}
+
+impl ShaderWatcher {
+    pub fn new(dir: impl AsRef<Path>) -> notify::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        let canonical_dir = dir.canonicalize()?;
+
+        let (sender, events) = channel();
+        let mut watcher = notify::watcher(sender, DEBOUNCE_DELAY)?;
+        watcher.watch(&canonical_dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            dir,
+            canonical_dir,
+        })
+    }
+
+    /// Every file that has been written since this was last called, with paths in the same form
+    /// as the directory passed to `new`. Never blocks.
+    pub fn changed_files(&self) -> HashSet<PathBuf> {
+        self.events
+            .try_iter()
+            .filter_map(|event| match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Rename(_, path) => Some(path),
+                _ => None,
+            })
+            .filter_map(|path| {
+                let relative = path.strip_prefix(&self.canonical_dir).ok()?;
+                Some(self.dir.join(relative))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,78 @@
+use cgmath::{InnerSpace, Vector3};
+
+/// Color straight overhead
+const ZENITH_COLOR: [f32; 3] = [0.18, 0.36, 0.72];
+
+/// Color at the horizon, which the sky fades to from the zenith
+const HORIZON_COLOR: [f32; 3] = [0.70, 0.80, 0.92];
+
+/// Color of everything below the horizon
+const GROUND_COLOR: [f32; 3] = [0.22, 0.20, 0.18];
+
+/// Direction through the given point on a cubemap face, with `s` and `t` running -1..1 from the
+/// left/top of the face
+///
+/// Faces are in the usual +X, -X, +Y, -Y, +Z, -Z order with Y up.
+fn face_direction(face: usize, s: f32, t: f32) -> Vector3<f32> {
+    let dir = match face {
+        0 => Vector3::new(1.0, -t, -s),
+        1 => Vector3::new(-1.0, -t, s),
+        2 => Vector3::new(s, 1.0, t),
+        3 => Vector3::new(s, -1.0, -t),
+        4 => Vector3::new(s, -t, 1.0),
+        5 => Vector3::new(-s, -t, -1.0),
+        _ => unreachable!("A cubemap only has six faces"),
+    };
+    dir.normalize()
+}
+
+fn sky_color(dir: Vector3<f32>) -> image::Rgba<u8> {
+    let elevation = dir.y;
+    let color = if elevation < 0.0 {
+        GROUND_COLOR
+    } else {
+        // Most of the change happens close to the horizon
+        let t = elevation.sqrt();
+        let mut color = [0.0; 3];
+        for (i, channel) in color.iter_mut().enumerate() {
+            *channel = HORIZON_COLOR[i] + (ZENITH_COLOR[i] - HORIZON_COLOR[i]) * t;
+        }
+        color
+    };
+
+    // The skybox is sampled as sRGB, so store the gamma encoded values
+    let encode = |linear: f32| (linear.powf(1.0 / 2.2) * 255.0).round() as u8;
+    image::Rgba([encode(color[0]), encode(color[1]), encode(color[2]), 255])
+}
+
+/// Generates the six faces of a simple gradient sky, for `Renderer::upload_skybox`
+pub fn gradient_faces(size: u32) -> [image::RgbaImage; 6] {
+    let face = |face| {
+        image::RgbaImage::from_fn(size, size, |x, y| {
+            let s = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+            let t = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+            sky_color(face_direction(face, s, t))
+        })
+    };
+    [face(0), face(1), face(2), face(3), face(4), face(5)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gradient_faces() {
+        let faces = gradient_faces(16);
+        assert!(faces.iter().all(|face| face.dimensions() == (16, 16)));
+
+        // Overhead is a deeper blue than the horizon, and the bottom face is all ground
+        let zenith = faces[2].get_pixel(8, 8);
+        let horizon = faces[0].get_pixel(8, 7);
+        assert!(zenith[0] < horizon[0]);
+        assert!(faces[3].pixels().all(|p| *p == sky_color(-Vector3::unit_y())));
+
+        // The side faces show the horizon half way down
+        assert_ne!(faces[0].get_pixel(8, 0), faces[0].get_pixel(8, 15));
+    }
+}
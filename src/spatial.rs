@@ -0,0 +1,305 @@
+use crate::picking::{Aabb, Ray};
+
+/// Most items in a leaf before it's split
+const LEAF_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+enum NodeContents {
+    /// Range of `Bvh::items`
+    Leaf { first: usize, count: usize },
+
+    /// Indices in to `Bvh::nodes`, which are always after this node's
+    Branch { left: usize, right: usize },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Node {
+    bounds: Aabb,
+    contents: NodeContents,
+}
+
+/// Bounding volume hierarchy over some boxes, identified by their index in the slice it's built
+/// from
+///
+/// Built by splitting each node at the median along its longest axis. `refit` keeps the tree
+/// but moves the boxes, which is much cheaper than building it again but gets slower to query
+/// the further the boxes have moved from where it was built.
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+    /// The root first, then every node before its children
+    nodes: Vec<Node>,
+
+    /// Index of each box, grouped by leaf
+    items: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::new(),
+            items: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            bvh.build_node(bounds, 0, bounds.len());
+        }
+        bvh
+    }
+
+    /// Push the node over `items[first..first + count]`, returning its index
+    fn build_node(&mut self, bounds: &[Aabb], first: usize, count: usize) -> usize {
+        let items = &mut self.items[first..first + count];
+        let node_bounds = union(items.iter().map(|&i| bounds[i]));
+        let centers = Aabb::from_points(items.iter().map(|&i| bounds[i].center())).unwrap();
+        let extent = centers.max - centers.min;
+        let axis = (0..3).max_by(|&a, &b| extent[a].total_cmp(&extent[b])).unwrap();
+
+        let index = self.nodes.len();
+        if count <= LEAF_SIZE || extent[axis] == 0.0 {
+            self.nodes.push(Node {
+                bounds: node_bounds,
+                contents: NodeContents::Leaf { first, count },
+            });
+            return index;
+        }
+
+        items.sort_by(|&a, &b| bounds[a].center()[axis].total_cmp(&bounds[b].center()[axis]));
+        self.nodes.push(Node {
+            bounds: node_bounds,
+            contents: NodeContents::Branch { left: 0, right: 0 },
+        });
+        let half = count / 2;
+        let left = self.build_node(bounds, first, half);
+        let right = self.build_node(bounds, first + half, count - half);
+        self.nodes[index].contents = NodeContents::Branch { left, right };
+        index
+    }
+
+    /// Move the boxes the tree was built from, which must be the same number of them
+    pub fn refit(&mut self, bounds: &[Aabb]) {
+        assert_eq!(bounds.len(), self.items.len(), "Refit a BVH with a different box count");
+        for i in (0..self.nodes.len()).rev() {
+            self.nodes[i].bounds = match self.nodes[i].contents {
+                NodeContents::Leaf { first, count } => {
+                    union(self.items[first..first + count].iter().map(|&item| bounds[item]))
+                }
+                NodeContents::Branch { left, right } => {
+                    union([self.nodes[left].bounds, self.nodes[right].bounds])
+                }
+            };
+        }
+    }
+
+    /// Box around every box in the tree, or None if it's empty
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.nodes.first().map(|root| root.bounds)
+    }
+
+    /// Find the nearest box that a ray hits something in, given the distance along the ray to
+    /// whatever it hits in each box, if anything
+    ///
+    /// Nodes are visited nearest first, and skipped once they're further away than the nearest
+    /// hit so far, so `hit` is only called for a few boxes along the ray.
+    pub fn raycast(
+        &self,
+        ray: &Ray,
+        mut hit: impl FnMut(usize) -> Option<f32>,
+    ) -> Option<(usize, f32)> {
+        let mut nearest: Option<(usize, f32)> = None;
+        let mut stack = Vec::new();
+        if let Some(distance) = self.bounds().and_then(|bounds| ray.intersect_aabb(&bounds)) {
+            stack.push((0, distance));
+        }
+
+        while let Some((node, entry)) = stack.pop() {
+            if nearest.is_some_and(|(_, nearest)| entry >= nearest) {
+                continue;
+            }
+            match self.nodes[node].contents {
+                NodeContents::Leaf { first, count } => {
+                    for &item in &self.items[first..first + count] {
+                        let Some(distance) = hit(item) else {
+                            continue;
+                        };
+                        if nearest.is_none_or(|(_, nearest)| distance < nearest) {
+                            nearest = Some((item, distance));
+                        }
+                    }
+                }
+                NodeContents::Branch { left, right } => {
+                    let entry = |child: usize| {
+                        Some((child, ray.intersect_aabb(&self.nodes[child].bounds)?))
+                    };
+                    let mut children = [entry(left), entry(right)];
+                    children.sort_by(|a, b| match (a, b) {
+                        (Some((_, a)), Some((_, b))) => b.total_cmp(a),
+                        _ => a.is_some().cmp(&b.is_some()),
+                    });
+
+                    // Furthest first, so the nearest is popped next
+                    stack.extend(children.iter().flatten());
+                }
+            }
+        }
+        nearest
+    }
+
+    /// Every box in a leaf that overlaps the given box, which includes every box that overlaps
+    /// it along with some that are only near it
+    pub fn overlapping(&self, aabb: &Aabb) -> Vec<usize> {
+        let mut overlapping = Vec::new();
+        let mut stack = Vec::new();
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if !node.bounds.overlaps(aabb) {
+                continue;
+            }
+            match node.contents {
+                NodeContents::Leaf { first, count } => {
+                    overlapping.extend_from_slice(&self.items[first..first + count])
+                }
+                NodeContents::Branch { left, right } => stack.extend([left, right]),
+            }
+        }
+        overlapping
+    }
+}
+
+/// A `Bvh` over objects that move and come and go, identified by keys
+///
+/// Each update refits the tree if the same objects are in it as before, and rebuilds it
+/// otherwise.
+#[derive(Clone, Debug)]
+pub struct ObjectBvh<K> {
+    keys: Vec<K>,
+    bounds: Vec<Aabb>,
+    bvh: Bvh,
+}
+
+impl<K> Default for ObjectBvh<K> {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            bounds: Vec::new(),
+            bvh: Bvh::default(),
+        }
+    }
+}
+
+impl<K: PartialEq> ObjectBvh<K> {
+    /// Replace every object with the given ones, each with its world space bounds
+    pub fn update(&mut self, objects: impl IntoIterator<Item = (K, Aabb)>) {
+        let (keys, bounds): (Vec<_>, Vec<_>) = objects.into_iter().unzip();
+        if keys == self.keys {
+            self.bvh.refit(&bounds);
+        } else {
+            self.bvh = Bvh::build(&bounds);
+            self.keys = keys;
+        }
+        self.bounds = bounds;
+    }
+
+    /// See `Bvh::raycast`
+    pub fn raycast(
+        &self,
+        ray: &Ray,
+        mut hit: impl FnMut(&K) -> Option<f32>,
+    ) -> Option<(&K, f32)> {
+        self.bvh
+            .raycast(ray, |i| hit(&self.keys[i]))
+            .map(|(i, distance)| (&self.keys[i], distance))
+    }
+
+    /// Every object whose bounds overlap the given box, in no particular order
+    pub fn overlapping(&self, aabb: &Aabb) -> Vec<&K> {
+        let overlapping = self.bvh.overlapping(aabb).into_iter();
+        overlapping.filter(|&i| self.bounds[i].overlaps(aabb)).map(|i| &self.keys[i]).collect()
+    }
+
+    /// Every object along with its bounds, in the order they were last given to `update`
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Aabb)> {
+        self.keys.iter().zip(&self.bounds)
+    }
+}
+
+/// Box around every given box, which mustn't be empty
+fn union(boxes: impl IntoIterator<Item = Aabb>) -> Aabb {
+    let mut boxes = boxes.into_iter();
+    let first = boxes.next().expect("Took the union of no boxes");
+    boxes.fold(first, |a, b| a.union(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Point3, Vector3};
+
+    /// A unit cube at each of a line of positions along the X axis, from 0 to `count - 1`
+    fn cubes(count: usize, offset: Vector3<f32>) -> Vec<Aabb> {
+        (0..count)
+            .map(|i| {
+                let min = Point3::new(i as f32, 0.0, 0.0) + offset;
+                Aabb {
+                    min,
+                    max: min + Vector3::new(0.5, 0.5, 0.5),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_raycast_finds_nearest() {
+        let bounds = cubes(100, Vector3::new(0.0, 0.0, 0.0));
+        let bvh = Bvh::build(&bounds);
+        assert_eq!(bvh.bounds().unwrap().max, Point3::new(99.5, 0.5, 0.5));
+
+        // Coming from beyond the far end, and hitting the near face of every box
+        let ray = Ray {
+            origin: Point3::new(200.0, 0.25, 0.25),
+            direction: Vector3::new(-1.0, 0.0, 0.0),
+        };
+        let mut visited = 0;
+        let hit = bvh.raycast(&ray, |i| {
+            visited += 1;
+            ray.intersect_aabb(&bounds[i])
+        });
+        assert_eq!(hit, Some((99, 100.5)));
+        assert!(visited <= 2 * LEAF_SIZE);
+
+        let miss = Ray {
+            origin: Point3::new(0.0, 5.0, 0.0),
+            direction: Vector3::new(1.0, 0.0, 0.0),
+        };
+        assert_eq!(bvh.raycast(&miss, |i| miss.intersect_aabb(&bounds[i])), None);
+        assert_eq!(Bvh::build(&[]).raycast(&ray, |_| Some(0.0)), None);
+    }
+
+    #[test]
+    fn test_refit_moves_objects() {
+        let mut objects = ObjectBvh::default();
+        objects.update(cubes(10, Vector3::new(0.0, 0.0, 0.0)).into_iter().enumerate());
+        let ray = Ray {
+            origin: Point3::new(3.25, 10.0, 0.25),
+            direction: Vector3::new(0.0, -1.0, 0.0),
+        };
+        let hit = |objects: &ObjectBvh<usize>, bounds: &[Aabb]| {
+            objects.raycast(&ray, |&i| ray.intersect_aabb(&bounds[i])).map(|(&i, _)| i)
+        };
+        assert_eq!(hit(&objects, &cubes(10, Vector3::new(0.0, 0.0, 0.0))), Some(3));
+
+        // Every box moves along by one, so the ray now goes through the one that was beside it
+        let moved = cubes(10, Vector3::new(1.0, 0.0, 0.0));
+        objects.update(moved.iter().copied().enumerate());
+        assert_eq!(hit(&objects, &moved), Some(2));
+
+        let query = Aabb {
+            min: Point3::new(0.0, 0.0, 0.0),
+            max: Point3::new(2.25, 1.0, 1.0),
+        };
+        let mut overlapping = objects.overlapping(&query);
+        overlapping.sort();
+        assert_eq!(overlapping, vec![&0, &1]);
+    }
+}
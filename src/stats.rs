@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use crate::renderer::FrameStats;
+
+/// Weight given to each new sample, so the overlay doesn't flicker too fast to read
+const SMOOTHING: f32 = 0.1;
+
+/// Exponential moving average of a time in milliseconds
+#[derive(Clone, Copy, Debug, Default)]
+struct Smoothed(Option<f32>);
+
+impl Smoothed {
+    fn record(&mut self, sample: Duration) {
+        let sample = sample.as_secs_f32() * 1000.0;
+        self.0 = Some(match self.0 {
+            Some(average) => average + (sample - average) * SMOOTHING,
+            None => sample,
+        });
+    }
+
+    fn millis(self) -> f32 {
+        self.0.unwrap_or(0.0)
+    }
+}
+
+/// Running figures on how long frames take, shown on top of the scene when visible
+#[derive(Default)]
+pub struct Stats {
+    pub visible: bool,
+
+    frame_time: Smoothed,
+    tick_time: Smoothed,
+
+    /// Smoothed recording time of each of the renderer's stages, in the order they ran
+    stage_times: Vec<(&'static str, Smoothed)>,
+
+    /// Counts from the last frame, which don't need smoothing as they only change with the scene
+    draw_calls: u32,
+    instances: u32,
+}
+
+impl Stats {
+    /// Record the time the app spent in a single tick
+    pub fn record_tick(&mut self, tick_time: Duration) {
+        self.tick_time.record(tick_time);
+    }
+
+    pub fn record_frame(&mut self, frame: &FrameStats) {
+        if frame.frame_time > Duration::ZERO {
+            self.frame_time.record(frame.frame_time);
+        }
+
+        // Frames that bail out early, eg. on losing the swapchain, skip later stages entirely
+        for &(stage, time) in &frame.stage_timings {
+            match self.stage_times.iter_mut().find(|(name, _)| *name == stage) {
+                Some((_, smoothed)) => smoothed.record(time),
+                None => {
+                    let mut smoothed = Smoothed::default();
+                    smoothed.record(time);
+                    self.stage_times.push((stage, smoothed));
+                }
+            }
+        }
+
+        self.draw_calls = frame.draw_calls;
+        self.instances = frame.instances;
+    }
+
+    /// The overlay text, one figure per line
+    pub fn text(&self) -> String {
+        let frame_millis = self.frame_time.millis();
+        let fps = if frame_millis > 0.0 { 1000.0 / frame_millis } else { 0.0 };
+
+        let mut text = format!("Frame: {:.2} ms ({:.0} fps)", frame_millis, fps);
+        text.push_str(&format!("\nTick: {:.2} ms", self.tick_time.millis()));
+        text.push_str(&format!("\nDraws: {} ({} instances)", self.draw_calls, self.instances));
+        for (stage, time) in &self.stage_times {
+            text.push_str(&format!("\n  {}: {:.2} ms", stage, time.millis()));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smoothing() {
+        let mut smoothed = Smoothed::default();
+        smoothed.record(Duration::from_millis(10));
+        assert_eq!(smoothed.millis(), 10.0);
+
+        // A single slow frame only nudges the average
+        smoothed.record(Duration::from_millis(110));
+        assert!((smoothed.millis() - 20.0).abs() < 1e-3);
+
+        for _ in 0..200 {
+            smoothed.record(Duration::from_millis(110));
+        }
+        assert!((smoothed.millis() - 110.0).abs() < 1e-3);
+    }
+}
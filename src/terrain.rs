@@ -0,0 +1,254 @@
+use bytemuck::{Pod, Zeroable};
+use cgmath::{InnerSpace, Point3, Vector3};
+
+use crate::error::{Error, Result};
+use crate::picking::Aabb;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct TerrainVertex {
+    pub position: [f32; 3],
+
+    pub normal: [f32; 3],
+
+    /// Where the vertex is across the whole terrain, from the top-left corner of the heightmap,
+    /// which the splat map is sampled with
+    pub texcoord: [f32; 2],
+}
+
+unsafe impl Pod for TerrainVertex {}
+unsafe impl Zeroable for TerrainVertex {}
+
+impl TerrainVertex {
+    pub fn vertex_buffer_descriptor<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float3,
+                    offset: 0,
+                    shader_location: 0,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float3,
+                    offset: 3 * 4,
+                    shader_location: 1,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float2,
+                    offset: 6 * 4,
+                    shader_location: 2,
+                },
+            ],
+        }
+    }
+}
+
+/// A square patch of a terrain's grid, drawn or culled as a whole
+pub struct TerrainChunk {
+    pub vertices: Vec<TerrainVertex>,
+    pub indices: Vec<u32>,
+    pub bounds: Aabb,
+}
+
+/// Everything a terrain is drawn with, see `Renderer::upload_terrain`
+pub struct TerrainData {
+    pub chunks: Vec<TerrainChunk>,
+
+    /// How much of each of `layers` shows across the whole terrain, one layer to each of the
+    /// RGBA channels. Weights are normalized, so they needn't sum to one.
+    pub splat_map: image::RgbaImage,
+
+    /// Up to four textures all of the same size, tiled across the terrain and blended together
+    /// by the splat map
+    pub layers: Vec<image::RgbaImage>,
+
+    /// World units covered by each repeat of the layers
+    pub layer_tile_size: f32,
+}
+
+/// Heights sampled on a regular grid in the XY plane, centered on the origin with +Z up like
+/// the rest of the world
+///
+/// The first row of samples is along the far (+Y) edge, so that a heightmap image reads like a
+/// map with north at the top.
+pub struct Heightmap {
+    width: u32,
+    depth: u32,
+    heights: Vec<f32>,
+
+    /// World units between neighbouring samples
+    spacing: f32,
+}
+
+impl Heightmap {
+    /// `heights` is given row by row, and needs at least two rows of at least two samples
+    pub fn new(width: u32, depth: u32, heights: Vec<f32>, spacing: f32) -> Result<Self> {
+        if width < 2 || depth < 2 {
+            return Err(Error::InvalidAsset("Heightmap needs at least 2x2 samples"));
+        }
+        if heights.len() != (width * depth) as usize {
+            return Err(Error::InvalidAsset("Heightmap has the wrong number of samples"));
+        }
+        Ok(Self {
+            width,
+            depth,
+            heights,
+            spacing,
+        })
+    }
+
+    /// A sample for each pixel, from 0 for black up to `max_height` for white. 16 bit greyscale
+    /// images keep their full precision.
+    pub fn from_image(image: &image::DynamicImage, spacing: f32, max_height: f32) -> Result<Self> {
+        let (width, depth, heights) = match image {
+            image::DynamicImage::ImageLuma16(image) => {
+                let scale = max_height / u16::MAX as f32;
+                let heights = image.pixels().map(|pixel| pixel[0] as f32 * scale).collect();
+                (image.width(), image.height(), heights)
+            }
+            image => {
+                let image = image.to_luma8();
+                let scale = max_height / u8::MAX as f32;
+                let heights = image.pixels().map(|pixel| pixel[0] as f32 * scale).collect();
+                (image.width(), image.height(), heights)
+            }
+        };
+        Self::new(width, depth, heights, spacing)
+    }
+
+    /// The height of the given sample, clamped to the edges of the grid
+    pub fn height(&self, x: i64, y: i64) -> f32 {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let y = y.clamp(0, self.depth as i64 - 1) as usize;
+        self.heights[y * self.width as usize + x]
+    }
+
+    /// World space position of the given sample
+    pub fn position(&self, x: u32, y: u32) -> Point3<f32> {
+        Point3::new(
+            (x as f32 - (self.width - 1) as f32 / 2.0) * self.spacing,
+            ((self.depth - 1) as f32 / 2.0 - y as f32) * self.spacing,
+            self.height(x as i64, y as i64),
+        )
+    }
+
+    /// Surface normal at the given sample, from the slope between its neighbours
+    pub fn normal(&self, x: u32, y: u32) -> Vector3<f32> {
+        let (x, y) = (x as i64, y as i64);
+        let dx = (self.height(x + 1, y) - self.height(x - 1, y)) / (2.0 * self.spacing);
+        // Rows run towards -Y
+        let dy = (self.height(x, y - 1) - self.height(x, y + 1)) / (2.0 * self.spacing);
+        Vector3::new(-dx, -dy, 1.0).normalize()
+    }
+
+    /// Split the grid in to chunks of up to `chunk_quads` squares along each side
+    ///
+    /// Neighbouring chunks each have their own copy of the samples along their shared edge, with
+    /// the same position and normal, so that there are no seams between them.
+    pub fn chunks(&self, chunk_quads: u32) -> Vec<TerrainChunk> {
+        let chunk_quads = chunk_quads.max(1);
+        let mut chunks = Vec::new();
+        for y0 in (0..self.depth - 1).step_by(chunk_quads as usize) {
+            for x0 in (0..self.width - 1).step_by(chunk_quads as usize) {
+                let x1 = (x0 + chunk_quads).min(self.width - 1);
+                let y1 = (y0 + chunk_quads).min(self.depth - 1);
+                chunks.push(self.chunk(x0..=x1, y0..=y1));
+            }
+        }
+        chunks
+    }
+
+    fn chunk(
+        &self,
+        xs: std::ops::RangeInclusive<u32>,
+        ys: std::ops::RangeInclusive<u32>,
+    ) -> TerrainChunk {
+        let mut vertices = Vec::new();
+        for y in ys.clone() {
+            for x in xs.clone() {
+                vertices.push(TerrainVertex {
+                    position: self.position(x, y).into(),
+                    normal: self.normal(x, y).into(),
+                    texcoord: [
+                        x as f32 / (self.width - 1) as f32,
+                        y as f32 / (self.depth - 1) as f32,
+                    ],
+                });
+            }
+        }
+
+        // Counter-clockwise seen from above, with each row further towards -Y than the last
+        let row_len = xs.end() - xs.start() + 1;
+        let rows = ys.end() - ys.start() + 1;
+        let mut indices = Vec::new();
+        for j in 0..rows - 1 {
+            for i in 0..row_len - 1 {
+                let top_left = j * row_len + i;
+                let bottom_left = top_left + row_len;
+                indices.extend_from_slice(&[
+                    bottom_left,
+                    bottom_left + 1,
+                    top_left + 1,
+                    bottom_left,
+                    top_left + 1,
+                    top_left,
+                ]);
+            }
+        }
+
+        let bounds = Aabb::from_points(vertices.iter().map(|vertex| vertex.position.into()))
+            .expect("Chunks have at least one square");
+        TerrainChunk {
+            vertices,
+            indices,
+            bounds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(width: u32, depth: u32) -> Heightmap {
+        // Rising by one unit per sample towards +X
+        let heights = (0..width * depth).map(|i| (i % width) as f32).collect();
+        Heightmap::new(width, depth, heights, 1.0).unwrap()
+    }
+
+    #[test]
+    fn test_heightmap_normals() {
+        let heightmap = ramp(4, 3);
+        let expected = Vector3::new(-1.0, 0.0, 1.0).normalize();
+        assert!((heightmap.normal(1, 1) - expected).magnitude() < 1e-6);
+
+        let flat = Heightmap::new(2, 2, vec![3.0; 4], 2.0).unwrap();
+        assert_eq!(flat.normal(0, 1), Vector3::unit_z());
+        assert_eq!(flat.position(0, 0), Point3::new(-1.0, 1.0, 3.0));
+
+        assert!(Heightmap::new(1, 4, vec![0.0; 4], 1.0).is_err());
+        assert!(Heightmap::new(2, 2, vec![0.0; 3], 1.0).is_err());
+    }
+
+    #[test]
+    fn test_heightmap_chunks() {
+        // 4x3 squares, split in to a 2x2 grid of chunks with the last row short
+        let heightmap = ramp(5, 4);
+        let chunks = heightmap.chunks(2);
+        let sizes: Vec<_> = chunks.iter().map(|chunk| chunk.vertices.len()).collect();
+        assert_eq!(sizes, vec![9, 9, 6, 6]);
+        assert_eq!(chunks[0].indices.len(), 2 * 2 * 6);
+        assert_eq!(chunks[3].indices.len(), 2 * 6);
+
+        // The first chunk's right edge is the second chunk's left edge
+        let edge = |chunk: &TerrainChunk, column: usize| -> Vec<TerrainVertex> {
+            chunk.vertices.iter().skip(column).step_by(3).copied().collect()
+        };
+        assert_eq!(edge(&chunks[0], 2), edge(&chunks[1], 0));
+
+        assert_eq!(chunks[1].bounds.min, Point3::new(0.0, -0.5, 2.0));
+        assert_eq!(chunks[1].bounds.max, Point3::new(2.0, 1.5, 4.0));
+    }
+}
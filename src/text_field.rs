@@ -0,0 +1,172 @@
+use tracing::warn;
+
+use crate::input_manager::TextInputEvent;
+
+/// Thin wrapper around the system clipboard that degrades to doing nothing when the clipboard
+/// isn't available (eg. no X11 display)
+pub struct Clipboard {
+    inner: Option<arboard::Clipboard>,
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        let inner = match arboard::Clipboard::new() {
+            Ok(clipboard) => Some(clipboard),
+            Err(e) => {
+                warn!("Failed to open the system clipboard: {}", e);
+                None
+            }
+        };
+
+        Self { inner }
+    }
+
+    pub fn get_text(&mut self) -> Option<String> {
+        self.inner.as_mut()?.get_text().ok()
+    }
+
+    pub fn set_text(&mut self, text: String) {
+        if let Some(clipboard) = self.inner.as_mut() {
+            if let Err(e) = clipboard.set_text(text) {
+                warn!("Failed to write to the system clipboard: {}", e);
+            }
+        }
+    }
+}
+
+/// A single line of editable text with a cursor
+///
+/// Text arrives already composed, so CJK input through an IME is handled the same as typing
+/// directly. winit doesn't report the in-progress composition, so that is left to the IME's own
+/// candidate window.
+#[derive(Default)]
+pub struct TextField {
+    text: String,
+
+    /// Byte offset of the cursor in to `text`, always on a char boundary
+    cursor: usize,
+}
+
+impl TextField {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        // Pasted text may span multiple lines, only the first is meaningful for a single line
+        // field
+        let text = text.lines().next().unwrap_or("");
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    fn prev_boundary(&self) -> usize {
+        self.text[..self.cursor]
+            .char_indices()
+            .next_back()
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.text[self.cursor..]
+            .chars()
+            .next()
+            .map(|c| self.cursor + c.len_utf8())
+            .unwrap_or(self.cursor)
+    }
+
+    /// Apply an edit, returning the submitted text if the event was a submission
+    pub fn apply(&mut self, event: TextInputEvent, clipboard: &mut Clipboard) -> Option<String> {
+        match event {
+            TextInputEvent::Insert(c) => {
+                let mut buf = [0; 4];
+                self.insert(c.encode_utf8(&mut buf));
+            }
+            TextInputEvent::Backspace => {
+                let start = self.prev_boundary();
+                self.text.replace_range(start..self.cursor, "");
+                self.cursor = start;
+            }
+            TextInputEvent::Delete => {
+                let end = self.next_boundary();
+                self.text.replace_range(self.cursor..end, "");
+            }
+            TextInputEvent::CursorLeft => self.cursor = self.prev_boundary(),
+            TextInputEvent::CursorRight => self.cursor = self.next_boundary(),
+            TextInputEvent::Home => self.cursor = 0,
+            TextInputEvent::End => self.cursor = self.text.len(),
+            TextInputEvent::Copy => clipboard.set_text(self.text.clone()),
+            TextInputEvent::Cut => {
+                clipboard.set_text(std::mem::take(&mut self.text));
+                self.cursor = 0;
+            }
+            TextInputEvent::Paste => {
+                if let Some(text) = clipboard.get_text() {
+                    self.insert(&text);
+                }
+            }
+            TextInputEvent::Submit => {
+                self.cursor = 0;
+                return Some(std::mem::take(&mut self.text));
+            }
+            TextInputEvent::Cancel => (),
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_clipboard() -> Clipboard {
+        Clipboard { inner: None }
+    }
+
+    #[test]
+    fn test_text_field_editing() {
+        let mut clipboard = no_clipboard();
+        let mut field = TextField::new();
+
+        for c in "ab日本".chars() {
+            field.apply(TextInputEvent::Insert(c), &mut clipboard);
+        }
+        assert_eq!(field.text(), "ab日本");
+
+        field.apply(TextInputEvent::CursorLeft, &mut clipboard);
+        field.apply(TextInputEvent::Backspace, &mut clipboard);
+        assert_eq!(field.text(), "ab本");
+        assert_eq!(field.cursor(), 2);
+
+        field.apply(TextInputEvent::Delete, &mut clipboard);
+        assert_eq!(field.text(), "ab");
+
+        field.apply(TextInputEvent::Home, &mut clipboard);
+        field.apply(TextInputEvent::Insert('x'), &mut clipboard);
+        assert_eq!(field.text(), "xab");
+
+        field.apply(TextInputEvent::End, &mut clipboard);
+        assert_eq!(
+            field.apply(TextInputEvent::Submit, &mut clipboard),
+            Some("xab".to_string())
+        );
+        assert_eq!(field.text(), "");
+    }
+
+    #[test]
+    fn test_text_field_insert_multiline() {
+        let mut field = TextField::new();
+        field.insert("./models/a.glb\n./models/b.glb");
+        assert_eq!(field.text(), "./models/a.glb");
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::VecDeque;
+
+use cgmath::Vector2;
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::MouseButton;
+
+use crate::input_manager::{KeyState, LogicalEvent};
+use crate::renderer::frame_packet::{SpriteInstanceData, UiSprite};
+
+/// Identifies one of the overlay's interactive regions, chosen by whoever registers it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UiRegionId(pub u32);
+
+/// Something the cursor did to an interactive region
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UiEvent {
+    /// The cursor moved on to the region, or the region appeared under it
+    HoverStart(UiRegionId),
+    HoverEnd(UiRegionId),
+
+    /// The left button was pressed and released over the region
+    Clicked(UiRegionId),
+}
+
+/// An overlay sprite that reacts to the cursor, as it was placed in clip space
+struct Region {
+    id: UiRegionId,
+    sprite: SpriteInstanceData,
+}
+
+impl Region {
+    /// Whether the clip space point is inside the sprite as the overlay draws it, including its
+    /// rotation
+    fn contains(&self, point: Vector2<f32>, aspect_ratio: f32) -> bool {
+        let sprite = &self.sprite;
+        let center = sprite.screen_pos + sprite.screen_size * 0.5;
+
+        // Undo the rotation the same way the sprite shader applies it, with x scaled to match y
+        let offset = point - center;
+        let offset = Vector2::new(offset.x * aspect_ratio, offset.y);
+        let (s, c) = (-sprite.rotation).sin_cos();
+        let offset = Vector2::new(c * offset.x - s * offset.y, s * offset.x + c * offset.y);
+        let offset = Vector2::new(offset.x / aspect_ratio, offset.y);
+
+        // Clip space sizes are negative for sprites laid out downwards, which this still holds for
+        let corner = Vector2::new(
+            offset.x / sprite.screen_size.x + 0.5,
+            offset.y / sprite.screen_size.y + 0.5,
+        );
+        (0.0..=1.0).contains(&corner.x) && (0.0..=1.0).contains(&corner.y)
+    }
+}
+
+/// Hit tests the cursor against overlay sprites registered as interactive, and turns the mouse's
+/// movement and left button in to `UiEvent`s for them
+///
+/// Regions are registered afresh each tick between `begin_regions` and `end_regions`, with the
+/// same sprites the overlay is drawn with. Where regions overlap, the one drawn on top gets the
+/// cursor: the highest layer, then the last one registered.
+pub struct UiInteraction {
+    regions: Vec<Region>,
+    screen_size: PhysicalSize<u32>,
+
+    /// Physical pixels per logical pixel, for placing UI sprites
+    ui_scale: f32,
+
+    /// In clip space, or None while the cursor is outside the window or grabbed
+    cursor: Option<Vector2<f32>>,
+    hovered: Option<UiRegionId>,
+
+    /// Region the left button was pressed over, while it's held
+    pressed: Option<UiRegionId>,
+    events: VecDeque<UiEvent>,
+}
+
+impl UiInteraction {
+    pub fn new(screen_size: PhysicalSize<u32>, scale_factor: f64) -> Self {
+        Self {
+            regions: Vec::new(),
+            screen_size,
+            ui_scale: scale_factor as f32,
+            cursor: None,
+            hovered: None,
+            pressed: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Update the size/DPI of the window the overlay is drawn in
+    pub fn set_screen_metrics(&mut self, screen_size: PhysicalSize<u32>, scale_factor: f64) {
+        self.screen_size = screen_size;
+        self.ui_scale = scale_factor as f32;
+    }
+
+    fn aspect_ratio(&self) -> f32 {
+        self.screen_size.width as f32 / self.screen_size.height.max(1) as f32
+    }
+
+    fn to_clip_space(&self, position: PhysicalPosition<f64>) -> Vector2<f32> {
+        let width = self.screen_size.width.max(1) as f32;
+        let height = self.screen_size.height.max(1) as f32;
+        Vector2::new(
+            2.0 * position.x as f32 / width - 1.0,
+            1.0 - 2.0 * position.y as f32 / height,
+        )
+    }
+
+    /// Forget last tick's regions, ready to register this tick's
+    pub fn begin_regions(&mut self) {
+        self.regions.clear();
+    }
+
+    /// Register a sprite already placed in clip space
+    pub fn add_sprite(&mut self, id: UiRegionId, sprite: SpriteInstanceData) {
+        self.regions.push(Region { id, sprite });
+    }
+
+    /// Register a sprite laid out relative to the edges of the screen
+    pub fn add_ui_sprite(&mut self, id: UiRegionId, sprite: UiSprite) {
+        let sprite = sprite.to_clip_space(self.screen_size, self.ui_scale);
+        self.add_sprite(id, sprite);
+    }
+
+    /// Finish registering regions, updating what's hovered in case they moved under the cursor
+    pub fn end_regions(&mut self) {
+        self.update_hover();
+    }
+
+    /// The region drawn on top at the clip space point, if any
+    pub fn hit_test(&self, point: Vector2<f32>) -> Option<UiRegionId> {
+        let aspect_ratio = self.aspect_ratio();
+        self.regions
+            .iter()
+            .enumerate()
+            .filter(|(_, region)| region.contains(point, aspect_ratio))
+            .max_by_key(|(i, region)| (region.sprite.layer, *i))
+            .map(|(_, region)| region.id)
+    }
+
+    /// The region under the cursor as of the last registered regions or cursor movement
+    pub fn hovered(&self) -> Option<UiRegionId> {
+        self.hovered
+    }
+
+    fn update_hover(&mut self) {
+        let hovered = self.cursor.and_then(|cursor| self.hit_test(cursor));
+        if hovered != self.hovered {
+            self.events.extend(self.hovered.map(UiEvent::HoverEnd));
+            self.events.extend(hovered.map(UiEvent::HoverStart));
+            self.hovered = hovered;
+        }
+    }
+
+    /// Pick up the mouse's movement and buttons, returning whether the event was meant for a
+    /// region and so shouldn't also act on the scene
+    pub fn handle_event(&mut self, event: &LogicalEvent) -> bool {
+        match event {
+            LogicalEvent::CursorMoved { position } => {
+                self.cursor = position.map(|position| self.to_clip_space(position));
+                self.update_hover();
+                false
+            }
+            LogicalEvent::MouseButton {
+                button: MouseButton::Left,
+                new_state: KeyState::Down,
+            } if self.hovered.is_some() => {
+                self.pressed = self.hovered;
+                true
+            }
+            // Only a release over the region that was pressed counts as a click, so a press can be
+            // backed out of by moving away before letting go
+            LogicalEvent::MouseButton {
+                button: MouseButton::Left,
+                new_state: KeyState::Up,
+            } => match self.pressed.take() {
+                Some(pressed) => {
+                    if self.hovered == Some(pressed) {
+                        self.events.push_back(UiEvent::Clicked(pressed));
+                    }
+                    true
+                }
+                None => false,
+            },
+            LogicalEvent::Click {
+                position: Some(position),
+            } => self.hit_test(self.to_clip_space(*position)).is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn poll_event(&mut self) -> Option<UiEvent> {
+        self.events.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::frame_packet::Anchor;
+
+    const BUTTON: UiRegionId = UiRegionId(1);
+    const COVER: UiRegionId = UiRegionId(2);
+
+    fn sprite(screen_pos: [f32; 2], screen_size: [f32; 2], layer: i32) -> SpriteInstanceData {
+        SpriteInstanceData {
+            screen_pos: screen_pos.into(),
+            screen_size: screen_size.into(),
+            atlas_pos: [0.0, 0.0].into(),
+            atlas_size: [1.0, 1.0].into(),
+            rotation: 0.0,
+            tint: [1.0, 1.0, 1.0, 1.0].into(),
+            slice_atlas: [0.0; 4].into(),
+            slice_screen: [0.0; 4].into(),
+            layer,
+        }
+    }
+
+    fn move_cursor(ui: &mut UiInteraction, x: f64, y: f64) {
+        ui.handle_event(&LogicalEvent::CursorMoved {
+            position: Some(PhysicalPosition::new(x, y)),
+        });
+    }
+
+    fn left_button(ui: &mut UiInteraction, new_state: KeyState) -> bool {
+        ui.handle_event(&LogicalEvent::MouseButton {
+            button: MouseButton::Left,
+            new_state,
+        })
+    }
+
+    #[test]
+    fn test_hit_test_respects_layers() {
+        let mut ui = UiInteraction::new(PhysicalSize::new(200, 100), 1.0);
+        ui.begin_regions();
+        ui.add_sprite(COVER, sprite([-1.0, -1.0], [1.0, 2.0], 1));
+        ui.add_sprite(BUTTON, sprite([-1.0, -1.0], [2.0, 2.0], 0));
+        ui.end_regions();
+
+        assert_eq!(ui.hit_test([-0.5, 0.0].into()), Some(COVER));
+        assert_eq!(ui.hit_test([0.5, 0.0].into()), Some(BUTTON));
+        assert_eq!(ui.hit_test([1.5, 0.0].into()), None);
+
+        // On the same layer, the one registered last is drawn on top
+        ui.begin_regions();
+        ui.add_sprite(COVER, sprite([-1.0, -1.0], [1.0, 2.0], 0));
+        ui.add_sprite(BUTTON, sprite([-1.0, -1.0], [2.0, 2.0], 0));
+        ui.end_regions();
+        assert_eq!(ui.hit_test([-0.5, 0.0].into()), Some(BUTTON));
+    }
+
+    #[test]
+    fn test_hit_test_rotated_sprite() {
+        let mut ui = UiInteraction::new(PhysicalSize::new(100, 100), 1.0);
+        ui.begin_regions();
+        ui.add_sprite(BUTTON, SpriteInstanceData {
+            rotation: std::f32::consts::FRAC_PI_4,
+            ..sprite([-0.5, -0.5], [1.0, 1.0], 0)
+        });
+        ui.end_regions();
+
+        // The corners are rotated away, and the diamond's points reach further out
+        assert_eq!(ui.hit_test([0.45, 0.45].into()), None);
+        assert_eq!(ui.hit_test([0.65, 0.0].into()), Some(BUTTON));
+    }
+
+    #[test]
+    fn test_hover_and_click_events() {
+        let mut ui = UiInteraction::new(PhysicalSize::new(200, 100), 2.0);
+        ui.begin_regions();
+        ui.add_ui_sprite(BUTTON, UiSprite {
+            anchor: Anchor::TopLeft,
+            offset: [10.0, 10.0].into(),
+            size: [20.0, 10.0].into(),
+            atlas_pos: [0.0, 0.0].into(),
+            atlas_size: [1.0, 1.0].into(),
+            rotation: 0.0,
+            tint: [1.0, 1.0, 1.0, 1.0].into(),
+            nine_slice: None,
+            layer: 0,
+        });
+        ui.end_regions();
+
+        // Laid out at twice the size for the scale factor, so (20, 20) to (60, 40)
+        move_cursor(&mut ui, 50.0, 30.0);
+        assert_eq!(ui.poll_event(), Some(UiEvent::HoverStart(BUTTON)));
+        assert!(left_button(&mut ui, KeyState::Down));
+        assert!(left_button(&mut ui, KeyState::Up));
+        assert_eq!(ui.poll_event(), Some(UiEvent::Clicked(BUTTON)));
+
+        // Moving off before releasing doesn't click
+        assert!(left_button(&mut ui, KeyState::Down));
+        move_cursor(&mut ui, 70.0, 30.0);
+        assert!(left_button(&mut ui, KeyState::Up));
+        assert_eq!(ui.poll_event(), Some(UiEvent::HoverEnd(BUTTON)));
+        assert_eq!(ui.poll_event(), None);
+        assert!(!left_button(&mut ui, KeyState::Down));
+    }
+}
@@ -9,11 +9,25 @@ pub struct Vertex {
 
     pub texcoord: [f32; 2],
 
-    /// RGBA color
+    /// Second texture coordinate set, which lightmaps are sampled with. Packed in to the same
+    /// attribute as `texcoord`, as every other attribute location is already taken.
+    pub lightmap_texcoord: [f32; 2],
+
+    /// Linear RGBA multiplier of the material's base color
     pub color: [f32; 4],
+
+    /// Indices of the joints in the model's skin that deform this vertex
+    pub joints: [u32; 4],
+
+    /// How much each of `joints` deforms this vertex, summing to 1 on skinned models. Unused
+    /// otherwise.
+    pub weights: [f32; 4],
 }
 
 impl Vertex {
+    /// Color of vertices that don't have one of their own, which leaves the material unchanged
+    pub const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
     pub fn vertex_buffer_descriptor<'a>() -> wgpu::VertexBufferDescriptor<'a> {
         wgpu::VertexBufferDescriptor {
             stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
@@ -29,16 +43,28 @@ impl Vertex {
                     offset: 3 * 4,
                     shader_location: 1,
                 },
+                // Both texture coordinate sets
                 wgpu::VertexAttributeDescriptor {
-                    format: wgpu::VertexFormat::Float2,
+                    format: wgpu::VertexFormat::Float4,
                     offset: 6 * 4,
                     shader_location: 2,
                 },
                 wgpu::VertexAttributeDescriptor {
                     format: wgpu::VertexFormat::Float4,
-                    offset: 8 * 4,
+                    offset: 10 * 4,
                     shader_location: 3,
                 },
+                // Locations 4-11 are taken by the per instance matrices
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Uint4,
+                    offset: 14 * 4,
+                    shader_location: 12,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    format: wgpu::VertexFormat::Float4,
+                    offset: 18 * 4,
+                    shader_location: 13,
+                },
             ],
         }
     }
@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use cgmath::{Angle, InnerSpace, Matrix4, Point3, Quaternion, Rad, Vector3};
+
+use crate::animation::AnimationPlayer;
+use crate::asset_loader::{LoadedScene, SceneHandle};
+use crate::collision::{self, Collider, Contact};
+use crate::picking::{self, Aabb, Ray};
+use crate::renderer::{
+    frame_packet::{FramePacketModel, InstanceData, InstanceStyle},
+    LodModel, MaterialId, ModelId, SceneModel,
+};
+use crate::scene_data::SceneData;
+use crate::spatial::ObjectBvh;
+
+/// Handle to an entity in a `World`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Entity(usize);
+
+/// Where an entity is in the world
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub position: Point3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: f32,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            position: Point3::new(0.0, 0.0, 0.0),
+            rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: 1.0,
+        }
+    }
+}
+
+impl Transform {
+    pub fn at(position: Point3<f32>) -> Self {
+        Self {
+            position,
+            ..Self::default()
+        }
+    }
+
+    /// Rotate about a world space axis through the entity's position
+    pub fn rotate(&mut self, angle: impl Into<Rad<f32>>, axis: Vector3<f32>) {
+        let angle = angle.into() / 2.0;
+        let s = angle.sin();
+        let c = angle.cos();
+        let rotation = Quaternion::new(c, axis.x * s, axis.y * s, axis.z * s);
+
+        self.rotation = (rotation * self.rotation).normalize();
+    }
+
+    /// Somewhere between this transform and another, where `t` is 0 for this one and 1 for the
+    /// other
+    pub fn lerp(&self, other: &Transform, t: f32) -> Transform {
+        Transform {
+            position: self.position + (other.position - self.position) * t,
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale + (other.scale - self.scale) * t,
+        }
+    }
+
+    /// Generates a matrix that transforms the entity's model space into world space
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(Vector3::new(self.position.x, self.position.y, self.position.z))
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_scale(self.scale)
+    }
+}
+
+/// Draws a single model at the entity's transform
+///
+/// Every entity drawing the same model is drawn as one instanced draw call.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ModelRef(pub ModelId);
+
+/// Draws everything the entity draws with this material, rather than the ones its models were
+/// uploaded with
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MaterialRef(pub MaterialId);
+
+/// Draws whichever level of an `LodModel` suits how big the entity is on screen
+///
+/// Entities share instanced draws with every other entity drawing the same level, including
+/// `ModelRef`s of it.
+pub struct LodRef {
+    pub model: Rc<LodModel>,
+
+    /// Level picked by the last `World::select_lods`, or None to draw the coarsest until then
+    level: Option<usize>,
+}
+
+impl LodRef {
+    pub fn new(model: Rc<LodModel>) -> Self {
+        Self { model, level: None }
+    }
+
+    pub fn model_id(&self) -> ModelId {
+        self.model.model_id(self.level.unwrap_or_else(|| self.model.coarsest()))
+    }
+}
+
+/// Draws every part of a glTF scene relative to the entity's transform, posed by the scene's
+/// first animation if it has one
+pub struct SceneInstance {
+    /// The scene that the parts come from, which has no parts until it's loaded
+    pub handle: SceneHandle,
+
+    /// The models making up this scene, relative to the entity's own transform
+    pub parts: Vec<SceneModel>,
+
+    /// Nodes, skins and animations of the scene that `parts` came from, shared between every
+    /// instance of it
+    scene: Option<Rc<SceneData>>,
+    animation: Option<AnimationPlayer>,
+
+    /// Scene space transform of every node in `scene`, in its current pose
+    node_transforms: Vec<Matrix4<f32>>,
+}
+
+impl SceneInstance {
+    /// Stands in for the scene until it's loaded and passed to `set_scene`
+    pub fn new(handle: SceneHandle) -> Self {
+        Self {
+            handle,
+            parts: Vec::new(),
+            scene: None,
+            animation: None,
+            node_transforms: Vec::new(),
+        }
+    }
+
+    /// Play the scene's first animation, if it has any
+    pub fn set_scene(&mut self, parts: Vec<SceneModel>, scene: Rc<SceneData>) {
+        self.animation = if scene.animations.is_empty() {
+            None
+        } else {
+            Some(AnimationPlayer::new(&scene, 0))
+        };
+        self.node_transforms = scene.node_transforms(&scene.rest_pose());
+        self.parts = parts;
+        self.scene = Some(scene);
+    }
+
+    fn tick_animation(&mut self, dt: f32) {
+        if let (Some(scene), Some(animation)) = (&self.scene, &mut self.animation) {
+            animation.tick(scene, dt);
+            self.node_transforms = animation.node_transforms().to_vec();
+        }
+    }
+
+    /// Where the given part is in the current pose, relative to the entity
+    pub fn part_transform(&self, part: &SceneModel) -> Matrix4<f32> {
+        self.node_transforms.get(part.node).copied().unwrap_or(part.transform)
+    }
+
+    /// Scene space transform of every node `alpha` of the way from the pose before the last tick
+    /// to the current one, see `AnimationPlayer::interpolated_node_transforms`
+    pub fn interpolated_pose(&self, alpha: f32) -> Vec<Matrix4<f32>> {
+        match (&self.scene, &self.animation) {
+            (Some(scene), Some(animation)) => animation.interpolated_node_transforms(scene, alpha),
+            _ => self.node_transforms.clone(),
+        }
+    }
+
+    /// Where the given part is in `pose`, from `interpolated_pose`, relative to the entity, along
+    /// with its joint matrices if it's skinned
+    pub fn posed_part(
+        &self,
+        part: &SceneModel,
+        pose: &[Matrix4<f32>],
+    ) -> (Matrix4<f32>, Vec<Matrix4<f32>>) {
+        let transform = pose.get(part.node).copied().unwrap_or(part.transform);
+        let joint_matrices = match (&self.scene, part.skin) {
+            (Some(scene), Some(skin)) => scene.skins[skin].joint_matrices(pose, transform),
+            _ => Vec::new(),
+        };
+        (transform, joint_matrices)
+    }
+}
+
+/// Moves the entity in world units per second
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(unused)]
+pub struct Velocity(pub Vector3<f32>);
+
+/// Outlines everything the entity draws, or one part of its scene, as selected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Selected {
+    /// Index in to the entity's scene parts of the only one to outline, or None for all of them
+    pub part: Option<usize>,
+}
+
+impl Selected {
+    fn includes_part(&self, part: usize) -> bool {
+        self.part.is_none_or(|selected| selected == part)
+    }
+}
+
+/// Turns the entity about a world space axis at a constant rate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Spinner {
+    pub axis: Vector3<f32>,
+
+    /// Per second
+    pub speed: Rad<f32>,
+}
+
+/// One kind of component, indexed by entity
+#[derive(Clone)]
+pub struct Components<T> {
+    items: Vec<Option<T>>,
+}
+
+impl<T> Default for Components<T> {
+    fn default() -> Self {
+        Self { items: Vec::new() }
+    }
+}
+
+impl<T> Components<T> {
+    /// Give an entity this component, replacing any it already had
+    pub fn insert(&mut self, entity: Entity, component: T) {
+        if self.items.len() <= entity.0 {
+            self.items.resize_with(entity.0 + 1, || None);
+        }
+        self.items[entity.0] = Some(component);
+    }
+
+    pub fn remove(&mut self, entity: Entity) -> Option<T> {
+        self.items.get_mut(entity.0)?.take()
+    }
+
+    pub fn get(&self, entity: Entity) -> Option<&T> {
+        self.items.get(entity.0)?.as_ref()
+    }
+
+    #[allow(unused)]
+    pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        self.items.get_mut(entity.0)?.as_mut()
+    }
+
+    /// Every entity with this component, in the order they were spawned
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)> {
+        self.items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, item)| Some((Entity(i), item.as_ref()?)))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut T)> {
+        self.items
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, item)| Some((Entity(i), item.as_mut()?)))
+    }
+}
+
+/// Every entity in the app and their components
+///
+/// Entities are just indices, and each kind of component is stored separately so that systems
+/// only visit the entities that have what they need.
+#[derive(Default)]
+pub struct World {
+    next_entity: usize,
+
+    pub transforms: Components<Transform>,
+    pub models: Components<ModelRef>,
+    pub lod_models: Components<LodRef>,
+    pub materials: Components<MaterialRef>,
+    pub scenes: Components<SceneInstance>,
+    pub velocities: Components<Velocity>,
+    pub spinners: Components<Spinner>,
+    pub selections: Components<Selected>,
+
+    /// How each of the entity's instances are shaded, on top of their materials
+    pub styles: Components<InstanceStyle>,
+
+    /// Every entity's transform as of the start of the last tick, for drawing frames that fall
+    /// between ticks
+    previous_transforms: Components<Transform>,
+
+    /// Every scene part, by entity and index in to its `SceneInstance::parts`, where it was at
+    /// the end of the last tick
+    parts_bvh: ObjectBvh<(Entity, usize)>,
+}
+
+impl World {
+    /// Create an entity with no components, to be given them with `insert`
+    pub fn spawn(&mut self) -> Entity {
+        let entity = Entity(self.next_entity);
+        self.next_entity += 1;
+        entity
+    }
+
+    /// Remove every component of an entity
+    ///
+    /// Entity handles aren't reused, so stale ones just find no components.
+    #[allow(unused)]
+    pub fn despawn(&mut self, entity: Entity) {
+        self.transforms.remove(entity);
+        self.models.remove(entity);
+        self.lod_models.remove(entity);
+        self.materials.remove(entity);
+        self.scenes.remove(entity);
+        self.velocities.remove(entity);
+        self.spinners.remove(entity);
+        self.selections.remove(entity);
+        self.styles.remove(entity);
+        self.previous_transforms.remove(entity);
+    }
+
+    /// Run every system once
+    pub fn tick(&mut self, dt: f32) {
+        self.previous_transforms = self.transforms.clone();
+        self.apply_velocities(dt);
+        self.spin(dt);
+        self.tick_animations(dt);
+        self.update_parts_bvh();
+    }
+
+    fn apply_velocities(&mut self, dt: f32) {
+        for (entity, velocity) in self.velocities.iter() {
+            if let Some(transform) = self.transforms.items[entity.0].as_mut() {
+                transform.position += velocity.0 * dt;
+            }
+        }
+    }
+
+    fn spin(&mut self, dt: f32) {
+        for (entity, spinner) in self.spinners.iter() {
+            if let Some(transform) = self.transforms.items[entity.0].as_mut() {
+                transform.rotate(spinner.speed * dt, spinner.axis);
+            }
+        }
+    }
+
+    fn tick_animations(&mut self, dt: f32) {
+        for (_, scene) in self.scenes.iter_mut() {
+            scene.tick_animation(dt);
+        }
+    }
+
+    /// Refit the BVH to where the parts have moved, or rebuild it if parts have come or gone
+    fn update_parts_bvh(&mut self) {
+        let mut parts = Vec::new();
+        for (entity, scene) in self.scenes.iter() {
+            let Some(transform) = self.transforms.get(entity) else {
+                continue;
+            };
+            let entity_matrix = transform.matrix();
+            for (i, part) in scene.parts.iter().enumerate() {
+                let matrix = entity_matrix * scene.part_transform(part);
+                parts.push(((entity, i), part.pick_mesh.bounds.transformed(matrix)));
+            }
+        }
+        self.parts_bvh.update(parts);
+    }
+
+    /// One of an entity's scene parts, along with its model matrix as of the end of the last tick
+    fn posed_part(&self, entity: Entity, i: usize) -> Option<(&SceneModel, Matrix4<f32>)> {
+        let scene = self.scenes.get(entity)?;
+        let part = scene.parts.get(i)?;
+        Some((part, self.transforms.get(entity)?.matrix() * scene.part_transform(part)))
+    }
+
+    /// Find the nearest scene part that a world space ray hits
+    ///
+    /// Returns the entity, the index of the part in its `SceneInstance::parts` and the distance
+    /// to the hit. Parts are where they were at the end of the last tick.
+    pub fn raycast_parts(&self, ray: &Ray) -> Option<(Entity, usize, f32)> {
+        let hit = picking::pick(ray, &self.parts_bvh, |&(entity, i)| {
+            let (part, matrix) = self.posed_part(entity, i)?;
+            Some((&*part.pick_mesh, matrix))
+        });
+        hit.map(|(&(entity, part), distance)| (entity, part, distance))
+    }
+
+    /// Every contact between a world space shape and the scene parts, where they were at the end
+    /// of the last tick
+    pub fn part_contacts(&self, collider: &impl Collider) -> Vec<Contact> {
+        let mut contacts = Vec::new();
+        for &(entity, i) in self.parts_bvh.overlapping(&collider.bounds()) {
+            if let Some((part, matrix)) = self.posed_part(entity, i) {
+                contacts.extend(collision::mesh_contacts(collider, &part.pick_mesh, matrix));
+            }
+        }
+        contacts
+    }
+
+    /// Move a shape out of every scene part it overlaps, see `collision::push_out`
+    pub fn push_out<C: Collider>(&self, collider: C) -> C {
+        collision::push_out(collider, |collider| self.part_contacts(collider))
+    }
+
+    /// Every scene part's world space bounds, as of the end of the last tick
+    pub fn part_bounds(&self) -> impl Iterator<Item = (Entity, &Aabb)> {
+        self.parts_bvh.iter().map(|(&(entity, _), bounds)| (entity, bounds))
+    }
+
+    /// Every pair of entities with scene parts whose bounds overlap each other's, as of the end
+    /// of the last tick
+    ///
+    /// Each pair is only given once, with the entity that was spawned first on the left.
+    pub fn overlapping_entities(&self) -> Vec<(Entity, Entity)> {
+        let mut pairs = Vec::new();
+        for (&(entity, _), bounds) in self.parts_bvh.iter() {
+            for &(other, _) in self.parts_bvh.overlapping(bounds) {
+                if entity.0 < other.0 && !pairs.contains(&(entity, other)) {
+                    pairs.push((entity, other));
+                }
+            }
+        }
+        pairs
+    }
+
+    /// Pick the level each `LodRef` is drawn with, from how big it is seen through the given
+    /// camera
+    ///
+    /// Each entity only switches level once it's well past the threshold between them, so ones
+    /// hovering around it don't keep switching back and forth. Every view draws the levels picked
+    /// here, which should be for the main camera.
+    pub fn select_lods(&mut self, view: Matrix4<f32>, proj: Matrix4<f32>) {
+        for (entity, lod) in self.lod_models.iter_mut() {
+            let transform = match self.transforms.get(entity) {
+                Some(transform) => transform,
+                None => continue,
+            };
+            let screen_size = lod.model.screen_size(view * transform.matrix(), proj);
+            lod.level = Some(lod.model.select(screen_size, lod.level));
+        }
+    }
+
+    /// Hand a loaded scene to every entity waiting on it
+    ///
+    /// Returns whether any entity was waiting on it.
+    pub fn scene_loaded(&mut self, loaded: LoadedScene) -> bool {
+        let scene = Rc::new(loaded.scene);
+        let mut any_waiting = false;
+        for (_, instance) in self.scenes.iter_mut() {
+            if instance.handle == loaded.handle {
+                instance.set_scene(loaded.models.clone(), scene.clone());
+                any_waiting = true;
+            }
+        }
+        any_waiting
+    }
+
+    /// Where an entity is `alpha` of the way from the start of the last tick to the end of it
+    ///
+    /// Entities spawned since the last tick are just where they are now.
+    pub fn interpolated_transform(&self, entity: Entity, alpha: f32) -> Option<Transform> {
+        let current = self.transforms.get(entity)?;
+        Some(match self.previous_transforms.get(entity) {
+            Some(previous) => previous.lerp(current, alpha),
+            None => *current,
+        })
+    }
+
+    /// Everything to draw this frame, one instanced draw per model and material shared by
+    /// `ModelRef`s and `LodRef`s, and one per scene part
+    ///
+    /// Transforms and animated poses are interpolated by `alpha`, see `interpolated_transform`
+    /// and `SceneInstance::interpolated_pose`.
+    pub fn frame_packet_models(&self, view: Matrix4<f32>, alpha: f32) -> Vec<FramePacketModel> {
+        let instance = |entity, model_matrix| InstanceData {
+            style: self.styles.get(entity).copied().unwrap_or_default(),
+            ..InstanceData::new(model_matrix, view)
+        };
+
+        let material_id =
+            |entity| self.materials.get(entity).map(|&MaterialRef(material_id)| material_id);
+
+        let mut models = Vec::new();
+        for (entity, scene) in self.scenes.iter() {
+            let entity_matrix = match self.interpolated_transform(entity, alpha) {
+                Some(transform) => transform.matrix(),
+                None => continue,
+            };
+            let selected = self.selections.get(entity);
+            let pose = scene.interpolated_pose(alpha);
+            for (i, part) in scene.parts.iter().enumerate() {
+                let (part_transform, joint_matrices) = scene.posed_part(part, &pose);
+                models.push(FramePacketModel {
+                    model_id: part.model_id,
+                    material_id: material_id(entity),
+                    instances: vec![instance(entity, entity_matrix * part_transform)],
+                    joint_matrices,
+                    selected: match selected {
+                        Some(selected) if selected.includes_part(i) => vec![0],
+                        _ => Vec::new(),
+                    },
+                });
+            }
+        }
+
+        // Index in to `models` of each model and material's batch, so that they keep the order
+        // entities were spawned in
+        let mut batches: HashMap<(ModelId, Option<MaterialId>), usize> = HashMap::new();
+        let model_refs = self.models.iter().map(|(entity, &ModelRef(model_id))| (entity, model_id));
+        let lod_refs = self.lod_models.iter().map(|(entity, lod)| (entity, lod.model_id()));
+        for (entity, model_id) in model_refs.chain(lod_refs) {
+            let transform = match self.interpolated_transform(entity, alpha) {
+                Some(transform) => transform,
+                None => continue,
+            };
+            let material_id = material_id(entity);
+            let batch = *batches.entry((model_id, material_id)).or_insert_with(|| {
+                models.push(FramePacketModel {
+                    model_id,
+                    material_id,
+                    instances: Vec::new(),
+                    joint_matrices: Vec::new(),
+                    selected: Vec::new(),
+                });
+                models.len() - 1
+            });
+            let batch = &mut models[batch];
+            if self.selections.get(entity).is_some() {
+                batch.selected.push(batch.instances.len());
+            }
+            batch.instances.push(instance(entity, transform.matrix()));
+        }
+
+        models
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_components_by_entity() {
+        let mut world = World::default();
+        let a = world.spawn();
+        let b = world.spawn();
+        let c = world.spawn();
+        world.transforms.insert(c, Transform::at(Point3::new(1.0, 0.0, 0.0)));
+        world.transforms.insert(a, Transform::default());
+        world.velocities.insert(c, Velocity(Vector3::new(0.0, 2.0, 0.0)));
+
+        assert!(world.transforms.get(b).is_none());
+        let with_transforms: Vec<_> = world.transforms.iter().map(|(entity, _)| entity).collect();
+        assert_eq!(with_transforms, vec![a, c]);
+
+        world.tick(0.5);
+        assert_eq!(world.transforms.get(c).unwrap().position, Point3::new(1.0, 1.0, 0.0));
+
+        world.despawn(c);
+        assert!(world.transforms.get(c).is_none());
+        assert!(world.velocities.get(c).is_none());
+    }
+
+    #[test]
+    fn test_spinner() {
+        let mut world = World::default();
+        let entity = world.spawn();
+        world.transforms.insert(entity, Transform::default());
+        world.spinners.insert(entity, Spinner {
+            axis: Vector3::unit_z(),
+            speed: Rad(std::f32::consts::PI),
+        });
+
+        world.tick(0.5);
+        let x = world.transforms.get(entity).unwrap().matrix() * Vector3::unit_x().extend(0.0);
+        assert!((x.truncate() - Vector3::unit_y()).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn test_interpolated_transform() {
+        let mut world = World::default();
+        let entity = world.spawn();
+        world.transforms.insert(entity, Transform::default());
+        world.velocities.insert(entity, Velocity(Vector3::new(4.0, 0.0, 0.0)));
+
+        // Nothing to interpolate from before the first tick
+        let transform = world.interpolated_transform(entity, 0.5).unwrap();
+        assert_eq!(transform.position, Point3::new(0.0, 0.0, 0.0));
+
+        world.tick(0.5);
+        let transform = world.interpolated_transform(entity, 0.25).unwrap();
+        assert_eq!(transform.position, Point3::new(0.5, 0.0, 0.0));
+        let transform = world.interpolated_transform(entity, 1.0).unwrap();
+        assert_eq!(transform.position, Point3::new(2.0, 0.0, 0.0));
+    }
+}